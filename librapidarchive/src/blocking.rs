@@ -6,6 +6,7 @@ use std::io::Write;
 
 use crate::spanning::{RecoverableWrite, DataZone, DataZoneStream};
 use crate::fs::ArchivalSink;
+use crate::tape::TapeDevice;
 
 /// Write implementation that ensures all data written to it is passed along to
 /// it's interior writer in identically-sized buffers of 512 * factor bytes.
@@ -13,7 +14,8 @@ pub struct BlockingWriter<W, P = u64> where P: Clone + PartialEq {
     blocking_factor: usize,
     inner: W,
     block: Vec<u8>,
-    datazone_stream: DataZoneStream<P>
+    datazone_stream: DataZoneStream<P>,
+    written: u64
 }
 
 impl<W: Write, P> BlockingWriter<W, P> where P: Clone + PartialEq {
@@ -22,16 +24,27 @@ impl<W: Write, P> BlockingWriter<W, P> where P: Clone + PartialEq {
             inner: inner,
             blocking_factor: 20 * 512,
             block: Vec::with_capacity(20 * 512),
-            datazone_stream: DataZoneStream::new()
+            datazone_stream: DataZoneStream::new(),
+            written: 0
         }
     }
     
     pub fn new_with_factor(inner: W, factor: usize) -> BlockingWriter<W, P> {
+        BlockingWriter::new_with_record_size(inner, factor * 512)
+    }
+
+    /// Create a writer passing along records of exactly `record_size` bytes,
+    /// which need not be a multiple of the tar block size.
+    ///
+    /// This suits tape drives in fixed block mode, which only accept writes
+    /// of their block size.
+    pub fn new_with_record_size(inner: W, record_size: usize) -> BlockingWriter<W, P> {
         BlockingWriter {
             inner: inner,
-            blocking_factor: factor * 512,
-            block: Vec::with_capacity(factor * 512),
-            datazone_stream: DataZoneStream::new()
+            blocking_factor: record_size,
+            block: Vec::with_capacity(record_size),
+            datazone_stream: DataZoneStream::new(),
+            written: 0
         }
     }
     
@@ -77,6 +90,7 @@ impl<W: Write, P> BlockingWriter<W, P> where P: Clone + PartialEq {
         if self.block.len() >= self.blocking_factor {
             self.inner.write_all(&self.block[..self.blocking_factor])?;
             self.datazone_stream.write_committed(self.blocking_factor as u64);
+            self.written += self.blocking_factor as u64;
 
             //This is actually safe, because this always acts to shrink
             //the array, failing to drop values properly is safe (though
@@ -110,8 +124,31 @@ impl<W:Write, P> RecoverableWrite<P> for BlockingWriter<W, P> where P: Clone + P
     }
 }
 
-impl<W:Write, P> ArchivalSink<P> for BlockingWriter<W, P> where W: Send + RecoverableWrite<P>, P: Send + Clone + PartialEq {
-    
+/// Downcasts on a `BlockingWriter` reach through to the inner writer.
+/// 
+/// Flushing a `BlockingWriter` pads the current block out with zeroes, so
+/// seeking or issuing tape commands mid-block will leave padding in the
+/// stream. Syncing does not flush, so the partial block is not synced.
+impl<W:Write, P> ArchivalSink<P> for BlockingWriter<W, P> where W: ArchivalSink<P>, P: Send + Clone + PartialEq {
+    fn downcast_seek(&mut self) -> Option<&mut dyn io::Seek> {
+        self.inner.downcast_seek()
+    }
+
+    fn downcast_tapedevice(&mut self) -> Option<&mut dyn TapeDevice> {
+        self.inner.downcast_tapedevice()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    /// Records count once they've been handed to the inner writer, less
+    /// whatever it still reports as uncommitted.
+    fn committed_bytes(&self) -> Option<u64> {
+        let uncommitted : u64 = self.inner.uncommitted_writes().iter().map(|zone| zone.uncommitted_length).sum();
+
+        Some(self.written.saturating_sub(uncommitted))
+    }
 }
 
 impl<W:Write, P> Write for BlockingWriter<W, P> where P: Clone + PartialEq, W: RecoverableWrite<P> {
@@ -134,6 +171,7 @@ impl<W:Write, P> Write for BlockingWriter<W, P> where P: Clone + PartialEq, W: R
                     Ok(blk_write) => {
                         shortcircuit_writes += blk_write;
                         self.datazone_stream.write_through(blk_write as u64);
+                        self.written += blk_write as u64;
                     }
                     Err(x) => return Err(x)
                 }
@@ -161,11 +199,12 @@ impl<W:Write, P> Write for BlockingWriter<W, P> where P: Clone + PartialEq, W: R
     /// to be inserted into the resulting stream. The alternative was to not
     /// flush intermediary contents, which would result in some data getting
     /// lost if the client failed to write a correctly divisible number of bytes
-    /// instead.
+    /// instead. A block with nothing in it is not padded, so flushing on a
+    /// block boundary writes nothing.
     fn flush(&mut self) -> io::Result<()> {
         self.end_data_zone();
 
-        if self.block.len() < self.blocking_factor {
+        if self.block.len() > 0 && self.block.len() < self.blocking_factor {
             self.block.resize(self.blocking_factor, 0);
         }
         
@@ -270,4 +309,30 @@ mod tests {
         assert_eq!(&blk.as_inner_writer().as_inner_writer().get_ref()[512..1024], vec![1 as u8; 512].as_slice());
         assert_eq!(&blk.as_inner_writer().as_inner_writer().get_ref()[1024..], vec![0 as u8; 1024].as_slice());
     }
+    
+    #[test]
+    fn blocking_exact_record_size() {
+        let mut blk : BlockingWriter<_, u64> = BlockingWriter::new_with_record_size(Cursor::new(vec![]), 800);
+
+        blk.write_all(&vec![1; 1024]).unwrap();
+        assert_eq!(blk.as_inner_writer().get_ref().len(), 800);
+
+        blk.flush().unwrap();
+        assert_eq!(blk.as_inner_writer().get_ref().len(), 1600);
+        assert_eq!(&blk.as_inner_writer().get_ref()[1024..], vec![0 as u8; 576].as_slice());
+    }
+
+    #[test]
+    fn blocking_flush_padding() {
+        let mut blk : BlockingWriter<_, u64> = BlockingWriter::new_with_factor(Cursor::new(vec![]), 2);
+        
+        blk.write_all(&vec![1; 1024]).unwrap();
+        blk.flush().unwrap();
+        assert_eq!(blk.as_inner_writer().get_ref().len(), 1024);
+        
+        blk.write_all(&vec![1; 10]).unwrap();
+        blk.flush().unwrap();
+        assert_eq!(blk.as_inner_writer().get_ref().len(), 2048);
+        assert_eq!(&blk.as_inner_writer().get_ref()[1034..], vec![0 as u8; 1014].as_slice());
+    }
 }
@@ -0,0 +1,119 @@
+//! Cooperative cancellation of archival operations.
+//!
+//! A `CancellationToken` is shared between everything taking part in an
+//! operation. Once any holder cancels it, traversal stops descending, header
+//! generation stops reading ahead, serialization stops copying file data, and
+//! buffered writers discard whatever they haven't written yet. Work already in
+//! progress (say, a single write to a slow tape) is allowed to finish.
+//!
+//! Cancelled operations fail with an I/O error for which `is_cancellation`
+//! holds. We can't use `ErrorKind::Interrupted` for this, as the standard
+//! library's copy loops retry interrupted operations.
+
+use std::{io, fmt, error};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// A shared flag indicating that an operation should stop.
+#[derive(Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>
+}
+
+impl CancellationToken {
+    pub fn new() -> CancellationToken {
+        CancellationToken::default()
+    }
+
+    /// Ask everything holding this token to stop.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// Yield a cancellation error if the token has been cancelled.
+    pub fn check(&self) -> io::Result<()> {
+        match self.is_cancelled() {
+            true => Err(cancelled_error()),
+            false => Ok(())
+        }
+    }
+}
+
+/// The error payload of a cancelled operation.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Operation was cancelled")
+    }
+}
+
+impl error::Error for Cancelled {}
+
+/// Construct the error yielded by cancelled operations.
+pub fn cancelled_error() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, Cancelled)
+}
+
+/// Determine if an error was caused by cancellation.
+pub fn is_cancellation(error: &io::Error) -> bool {
+    match error.get_ref() {
+        Some(inner) => inner.is::<Cancelled>(),
+        None => false
+    }
+}
+
+/// A reader which stops with a cancellation error once it's token is
+/// cancelled.
+pub struct CancellableRead<R> {
+    inner: R,
+    cancel: CancellationToken
+}
+
+impl<R> CancellableRead<R> {
+    pub fn new(inner: R, cancel: CancellationToken) -> CancellableRead<R> {
+        CancellableRead {
+            inner: inner,
+            cancel: cancel
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: io::Read> io::Read for CancellableRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.cancel.check()?;
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Read;
+    use super::{CancellationToken, CancellableRead, is_cancellation};
+
+    #[test]
+    fn cancellable_copy() {
+        let cancel = CancellationToken::new();
+        let mut source = CancellableRead::new(io::repeat(0).take(4096), cancel.clone());
+        let mut out = vec![];
+
+        assert_eq!(io::copy(&mut source, &mut out).unwrap(), 4096);
+
+        let other_handle = cancel.clone();
+        other_handle.cancel();
+
+        let error = io::copy(&mut CancellableRead::new(io::repeat(0), cancel), &mut io::sink()).err().unwrap();
+        assert!(is_cancellation(&error));
+        assert!(!is_cancellation(&io::Error::new(io::ErrorKind::Other, "something else")));
+    }
+}
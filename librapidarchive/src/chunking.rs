@@ -0,0 +1,322 @@
+//! Content-defined chunking of archive data, for estimating deduplication.
+//!
+//! Deduplicating storage splits incoming data into chunks wherever a rolling
+//! hash of the last few bytes hits a particular value, so that identical runs
+//! of data produce identical chunks regardless of where they sit in the
+//! stream. Running the same process over an archive as it's written tells us
+//! how well a deduplicating store would do with it, without having to have
+//! one on hand.
+//!
+//! We use a gear hash, as in FastCDC, with chunks between `MIN_CHUNK_SIZE` and
+//! `MAX_CHUNK_SIZE` bytes long and averaging around `AVERAGE_CHUNK_SIZE`.
+//! Chunks are identified by their SHA-256 digest.
+
+use std::{io, mem};
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use crate::digest::{Sha256, Sha256Digest};
+use crate::filter::WriteFilter;
+use crate::hashing::{HasherPool, HashStream};
+
+/// The smallest chunk that will be cut, other than at the end of the stream.
+pub const MIN_CHUNK_SIZE: u64 = 2 * 1024;
+
+/// The typical size of a chunk.
+pub const AVERAGE_CHUNK_SIZE: u64 = 8 * 1024;
+
+/// The largest chunk that will be cut.
+pub const MAX_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// How far to shift the hash to leave the bits that decide a chunk boundary.
+///
+/// A boundary occurs when all of the top log2(AVERAGE_CHUNK_SIZE) bits of the
+/// hash are zero.
+const BOUNDARY_SHIFT: u32 = 64 - 13;
+
+/// A chunk of the archive stream.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Chunk {
+    /// Where the chunk starts, counting from the first byte chunked.
+    pub offset: u64,
+    pub length: u64,
+    pub digest: Sha256Digest,
+}
+
+/// Totals describing how well a stream would deduplicate.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ChunkStats {
+    pub total_bytes: u64,
+    pub chunks: u64,
+    pub unique_chunks: u64,
+    pub unique_bytes: u64,
+}
+
+impl ChunkStats {
+    /// How many times smaller the stream would be if every duplicate chunk
+    /// were stored only once.
+    pub fn dedup_ratio(&self) -> f64 {
+        match self.unique_bytes {
+            0 => 1.0,
+            unique => self.total_bytes as f64 / unique as f64
+        }
+    }
+}
+
+/// Generate the table of random values the gear hash mixes in for each byte.
+///
+/// The table has to be the same every time, or chunk boundaries wouldn't be
+/// reproducible, so it's generated with a fixed-seed SplitMix64.
+fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut seed : u64 = 0x7261_7069_6474_6172;
+
+    for entry in table.iter_mut() {
+        seed = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = seed;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        *entry = z ^ (z >> 31);
+    }
+
+    table
+}
+
+/// Splits a stream into chunks and tallies how many are duplicates.
+///
+/// Every chunk's digest is remembered for the life of the chunker, which
+/// costs a little over 32 bytes of memory per `AVERAGE_CHUNK_SIZE` bytes
+/// chunked.
+pub struct Chunker {
+    gear: [u64; 256],
+    hash: u64,
+    chunk_offset: u64,
+    chunk_length: u64,
+    hasher: Sha256,
+    seen: HashSet<Sha256Digest>,
+    stats: ChunkStats,
+    observer: Option<Box<dyn FnMut(&Chunk) -> io::Result<()> + Send>>,
+}
+
+impl Default for Chunker {
+    fn default() -> Self {
+        Chunker::new()
+    }
+}
+
+impl Chunker {
+    pub fn new() -> Chunker {
+        Chunker {
+            gear: gear_table(),
+            hash: 0,
+            chunk_offset: 0,
+            chunk_length: 0,
+            hasher: Sha256::new(),
+            seen: HashSet::new(),
+            stats: ChunkStats::default(),
+            observer: None
+        }
+    }
+
+    /// Construct a chunker which calls a function with every chunk it cuts.
+    pub fn with_observer<F>(observer: F) -> Chunker where F: 'static + FnMut(&Chunk) -> io::Result<()> + Send {
+        let mut chunker = Chunker::new();
+
+        chunker.observer = Some(Box::new(observer));
+
+        chunker
+    }
+
+    pub fn stats(&self) -> ChunkStats {
+        self.stats
+    }
+
+    /// Chunk more of the stream.
+    pub fn update(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut start = 0;
+
+        for (i, byte) in data.iter().enumerate() {
+            self.hash = (self.hash << 1).wrapping_add(self.gear[*byte as usize]);
+            self.chunk_length += 1;
+
+            if (self.chunk_length >= MIN_CHUNK_SIZE && self.hash >> BOUNDARY_SHIFT == 0) || self.chunk_length >= MAX_CHUNK_SIZE {
+                self.hasher.update(&data[start..i + 1]);
+                self.end_chunk()?;
+                start = i + 1;
+            }
+        }
+
+        self.hasher.update(&data[start..]);
+
+        Ok(())
+    }
+
+    /// End the stream, cutting whatever remains into a final chunk.
+    pub fn finish(&mut self) -> io::Result<()> {
+        match self.chunk_length {
+            0 => Ok(()),
+            _ => self.end_chunk()
+        }
+    }
+
+    fn end_chunk(&mut self) -> io::Result<()> {
+        let chunk = Chunk {
+            offset: self.chunk_offset,
+            length: self.chunk_length,
+            digest: mem::replace(&mut self.hasher, Sha256::new()).finish()
+        };
+
+        self.stats.total_bytes += chunk.length;
+        self.stats.chunks += 1;
+
+        if self.seen.insert(chunk.digest) {
+            self.stats.unique_chunks += 1;
+            self.stats.unique_bytes += chunk.length;
+        }
+
+        self.chunk_offset += chunk.length;
+        self.chunk_length = 0;
+        self.hash = 0;
+
+        match self.observer {
+            Some(ref mut observer) => observer(&chunk),
+            None => Ok(())
+        }
+    }
+}
+
+/// A filter which chunks everything written through it.
+///
+/// The chunker is shared, so that it can outlive the sink and keep chunking
+/// across several volumes. Data rewritten onto a new volume after a torn write
+/// is chunked again.
+///
+/// Chunking can be offloaded onto a `HasherPool`, so that it happens alongside
+/// writing instead of holding it up.
+pub struct ChunkFilter {
+    chunker: Arc<Mutex<Chunker>>,
+    stream: Option<HashStream<Chunker>>
+}
+
+impl ChunkFilter {
+    pub fn new(chunker: Arc<Mutex<Chunker>>) -> ChunkFilter {
+        ChunkFilter {
+            chunker: chunker,
+            stream: None
+        }
+    }
+
+    /// Create a filter which chunks on one of the threads of a pool.
+    ///
+    /// The chunker is only up to date once the filter has been flushed or
+    /// dropped.
+    pub fn offloaded(chunker: Arc<Mutex<Chunker>>, pool: &HasherPool) -> ChunkFilter {
+        ChunkFilter {
+            stream: Some(pool.stream(chunker.clone())),
+            chunker: chunker
+        }
+    }
+}
+
+impl WriteFilter for ChunkFilter {
+    fn write_filtered(&mut self, buf: &[u8], next: &mut dyn io::Write) -> io::Result<usize> {
+        let written = next.write(buf)?;
+
+        match self.stream {
+            Some(ref stream) => stream.update(&buf[..written])?,
+            None => self.chunker.lock().unwrap().update(&buf[..written])?
+        }
+
+        Ok(written)
+    }
+
+    fn flush_filtered(&mut self, next: &mut dyn io::Write) -> io::Result<()> {
+        if let Some(ref stream) = self.stream {
+            stream.wait()?;
+        }
+
+        next.flush()
+    }
+
+    fn preserves_offsets(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use crate::filter::WriteFilter;
+    use crate::hashing::HasherPool;
+    use super::{Chunker, ChunkFilter, MIN_CHUNK_SIZE, MAX_CHUNK_SIZE};
+
+    /// Deterministic, incompressible test data.
+    fn noise(len: usize, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 24) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn chunker_dedup() {
+        let chunks = Arc::new(Mutex::new(vec![]));
+        let observed = chunks.clone();
+        let mut chunker = Chunker::with_observer(move |chunk| {
+            observed.lock().unwrap().push(*chunk);
+            Ok(())
+        });
+
+        let data = noise(256 * 1024, 1);
+
+        //Write the same data twice, in oddly sized pieces, so that chunking
+        //has to carry state across writes.
+        for piece in data.chunks(1000).chain(data.chunks(777)) {
+            chunker.update(piece).unwrap();
+        }
+
+        chunker.finish().unwrap();
+
+        let stats = chunker.stats();
+        let chunks = chunks.lock().unwrap();
+
+        assert_eq!(stats.total_bytes, 512 * 1024);
+        assert_eq!(stats.chunks, chunks.len() as u64);
+        assert!(stats.dedup_ratio() > 1.8);
+
+        let mut expected_offset = 0;
+
+        for chunk in chunks.iter() {
+            assert_eq!(chunk.offset, expected_offset);
+            assert!(chunk.length <= MAX_CHUNK_SIZE);
+            expected_offset += chunk.length;
+        }
+
+        assert!(chunks[..chunks.len() - 1].iter().all(|c| c.length >= MIN_CHUNK_SIZE));
+    }
+
+    #[test]
+    fn offloaded_chunking() {
+        let data = noise(256 * 1024, 2);
+        let pool = HasherPool::new(1);
+        let inline = Arc::new(Mutex::new(Chunker::new()));
+        let offloaded = Arc::new(Mutex::new(Chunker::new()));
+        let mut inline_filter = ChunkFilter::new(inline.clone());
+        let mut offloaded_filter = ChunkFilter::offloaded(offloaded.clone(), &pool);
+        let mut sink = vec![];
+
+        for piece in data.chunks(1000) {
+            inline_filter.write_filtered(piece, &mut sink).unwrap();
+            offloaded_filter.write_filtered(piece, &mut sink).unwrap();
+        }
+
+        offloaded_filter.flush_filtered(&mut sink).unwrap();
+        sink.flush().unwrap();
+
+        assert!(offloaded.lock().unwrap().stats() == inline.lock().unwrap().stats());
+        assert_eq!(pool.stats().bytes(), 256 * 1024);
+    }
+}
@@ -0,0 +1,720 @@
+//! Compression of archive data on it's way to the sink.
+//!
+//! `GzipFilter` is a `WriteFilter` which compresses everything written through
+//! it into the gzip format, as `tar -z` does, using flate2.
+//!
+//! `ParallelGzipFilter` produces the same format on rayon's thread pool, in
+//! the manner of pigz. Input is split into chunks which are compressed
+//! separately and reassembled in order. Every chunk ends on a byte boundary
+//! with a sync flush, so their output can simply be concatenated into one
+//! DEFLATE stream.
+//!
+//! `ZstdFilter` compresses into the Zstandard format instead, using the
+//! reference library, which can spread the work across several threads. It's
+//! only available when built with the `zstd` feature.
+//!
+//! `XzFilter` does the same for the xz format, using liblzma, when built with
+//! the `xz` feature.
+//!
+//! # Flushing
+//!
+//! None of these formats can be flushed partway through and still be a
+//! complete file, so flushing a filter finishes the current gzip member, zstd
+//! frame, or xz stream. Anything written afterwards starts a new one.
+//! Decompressors treat concatenated members (or frames, or streams) as one, so
+//! this is only a (small) loss of compression. Filters must be flushed once
+//! the archive is complete, or their last member is lost.
+//!
+//! # Data zones
+//!
+//! Every compressor changes the length of the data passing through it, so the
+//! `FilteredWriter` it sits in measures data zones in uncompressed bytes, and
+//! finishes the current member at the end of each zone. A zone only counts as
+//! committed once all of it's compressed data has been. When a volume fills
+//! up, the torn member is rewritten onto the next volume from where the zone
+//! began, in a member of it's own, so each volume of a compressed archive can
+//! be decompressed by itself.
+//!
+//! Zones are only tracked when spanning volumes or writing to tape, so this
+//! costs nothing otherwise.
+
+use std::{io, cmp, mem, panic};
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::mpsc;
+use flate2::Compression;
+use flate2::write::{DeflateEncoder, GzEncoder};
+use crate::filter::WriteFilter;
+use crate::tuning::Configuration;
+
+/// How much input each job of a `ParallelGzipFilter` compresses.
+const PARALLEL_CHUNK_SIZE: usize = 128 * 1024;
+
+/// The compression level used by default, as with gzip.
+pub const DEFAULT_LEVEL: u32 = 6;
+
+/// The gzip header written by `ParallelGzipFilter`: no file name or
+/// modification time, and an unknown OS, so that the same archive always
+/// compresses the same way.
+const GZIP_HEADER: [u8; 10] = [0x1F, 0x8B, 8, 0, 0, 0, 0, 0, 0, 255];
+
+/// A write filter which compresses data into the gzip format.
+///
+/// See the module documentation for how flushing and data zones behave.
+pub struct GzipFilter {
+    level: u32,
+    member: Option<GzEncoder<Vec<u8>>>,
+}
+
+impl GzipFilter {
+    /// Create a filter compressing at the given level, from 1 (fastest) to 9
+    /// (smallest), as with gzip.
+    pub fn new(level: u32) -> GzipFilter {
+        GzipFilter {
+            level,
+            member: None
+        }
+    }
+}
+
+impl Default for GzipFilter {
+    fn default() -> Self {
+        GzipFilter::new(DEFAULT_LEVEL)
+    }
+}
+
+impl WriteFilter for GzipFilter {
+    fn write_filtered(&mut self, buf: &[u8], next: &mut dyn io::Write) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let level = self.level;
+        let encoder = self.member.get_or_insert_with(|| GzEncoder::new(Vec::new(), Compression::new(level)));
+
+        encoder.write_all(buf)?;
+
+        let output = mem::take(encoder.get_mut());
+        if !output.is_empty() {
+            next.write_all(&output)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn finish_filtered(&mut self, next: &mut dyn io::Write) -> io::Result<()> {
+        if let Some(encoder) = self.member.take() {
+            next.write_all(&encoder.finish()?)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush_filtered(&mut self, next: &mut dyn io::Write) -> io::Result<()> {
+        self.finish_filtered(next)?;
+        next.flush()
+    }
+
+    fn changes_length(&self) -> bool {
+        true
+    }
+}
+
+/// Compress one chunk of a gzip member into DEFLATE blocks which end on a byte
+/// boundary, ready to be followed by the next chunk's.
+fn compress_chunk(level: u32, chunk: &[u8]) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::new(level));
+
+    encoder.write_all(chunk)?;
+    encoder.flush()?;
+
+    Ok(mem::take(encoder.get_mut()))
+}
+
+/// The error for a compression job which ended without handing back it's
+/// output.
+fn lost_job() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "Compressor thread stopped without finishing it's chunk")
+}
+
+/// A write filter which compresses data into the gzip format on rayon's
+/// global thread pool.
+///
+/// Compressed chunks are written out in order as they complete. Only as many
+/// chunks as the filter was given threads are compressed at once; writes
+/// block once that many are outstanding, so a slow pool holds up the archive
+/// rather than filling up memory.
+pub struct ParallelGzipFilter {
+    level: u32,
+    max_jobs: usize,
+
+    /// Indicates a member has been started and not yet finished.
+    started: bool,
+    crc: flate2::Crc,
+
+    /// Input not yet handed off to the pool.
+    pending: Vec<u8>,
+
+    /// The chunks handed off to the pool, in order, each with a channel it's
+    /// output is sent back on.
+    jobs: VecDeque<mpsc::Receiver<io::Result<Vec<u8>>>>,
+}
+
+impl ParallelGzipFilter {
+    /// Create a filter compressing at the given level, from 1 (fastest) to 9
+    /// (smallest), on up to the given number of threads at once.
+    pub fn new(level: u32, threads: usize) -> ParallelGzipFilter {
+        ParallelGzipFilter {
+            level,
+            max_jobs: cmp::max(threads, 1),
+            started: false,
+            crc: flate2::Crc::new(),
+            pending: Vec::with_capacity(PARALLEL_CHUNK_SIZE),
+            jobs: VecDeque::new()
+        }
+    }
+
+    /// Hand the pending input off to the pool.
+    ///
+    /// A job which panics sends back an error instead of it's output, as
+    /// rayon would otherwise abort the process.
+    fn submit(&mut self) {
+        let chunk = mem::replace(&mut self.pending, Vec::with_capacity(PARALLEL_CHUNK_SIZE));
+        let (sender, receiver) = mpsc::channel();
+        let level = self.level;
+
+        self.jobs.push_back(receiver);
+        rayon::spawn(move || {
+            let output = panic::catch_unwind(|| compress_chunk(level, &chunk)).unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "Compressor thread panicked")));
+
+            //The filter may have been dropped while we were working.
+            let _ = sender.send(output);
+        });
+    }
+
+    /// Write out every compressed chunk that's ready, in order, and wait until
+    /// no more than `outstanding` chunks are left.
+    ///
+    /// Jobs which failed, or were dropped without sending anything back, are
+    /// reported as errors.
+    fn collect(&mut self, next: &mut dyn io::Write, outstanding: usize) -> io::Result<()> {
+        while let Some(job) = self.jobs.front() {
+            let output = match self.jobs.len() > outstanding {
+                true => job.recv().map_err(|_| lost_job())?,
+                false => match job.try_recv() {
+                    Ok(output) => output,
+                    Err(mpsc::TryRecvError::Empty) => break,
+                    Err(mpsc::TryRecvError::Disconnected) => return Err(lost_job())
+                }
+            };
+
+            self.jobs.pop_front();
+            next.write_all(&output?)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl WriteFilter for ParallelGzipFilter {
+    fn write_filtered(&mut self, buf: &[u8], next: &mut dyn io::Write) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if !self.started {
+            next.write_all(&GZIP_HEADER)?;
+            self.started = true;
+            self.crc.reset();
+        }
+
+        self.crc.update(buf);
+
+        let mut remaining = buf;
+
+        while !remaining.is_empty() {
+            let take = cmp::min(PARALLEL_CHUNK_SIZE - self.pending.len(), remaining.len());
+
+            self.pending.extend_from_slice(&remaining[..take]);
+            remaining = &remaining[take..];
+
+            if self.pending.len() == PARALLEL_CHUNK_SIZE {
+                self.submit();
+                self.collect(next, self.max_jobs)?;
+            }
+        }
+
+        Ok(buf.len())
+    }
+
+    fn finish_filtered(&mut self, next: &mut dyn io::Write) -> io::Result<()> {
+        if self.started {
+            if !self.pending.is_empty() {
+                self.submit();
+            }
+
+            self.collect(next, 0)?;
+
+            //An empty, final block with fixed Huffman codes ends the stream.
+            let mut trailer = vec![0x03, 0x00];
+
+            trailer.extend_from_slice(&self.crc.sum().to_le_bytes());
+            trailer.extend_from_slice(&self.crc.amount().to_le_bytes());
+            next.write_all(&trailer)?;
+
+            self.started = false;
+        }
+
+        Ok(())
+    }
+
+    fn flush_filtered(&mut self, next: &mut dyn io::Write) -> io::Result<()> {
+        self.finish_filtered(next)?;
+        next.flush()
+    }
+
+    fn changes_length(&self) -> bool {
+        true
+    }
+}
+
+/// A write filter which compresses data into the Zstandard format.
+///
+/// See the module documentation for how flushing and data zones behave.
+#[cfg(feature = "zstd")]
+pub struct ZstdFilter {
+    level: i32,
+    threads: u32,
+    frame: Option<zstd::stream::write::Encoder<'static, Vec<u8>>>,
+}
+
+#[cfg(feature = "zstd")]
+impl ZstdFilter {
+    /// Create a filter compressing at the given level, or zstd's default level
+    /// if zero, on the given number of worker threads.
+    ///
+    /// With no worker threads, data is compressed on whichever thread writes
+    /// to the filter.
+    pub fn new(level: i32, threads: u32) -> ZstdFilter {
+        ZstdFilter {
+            level,
+            threads,
+            frame: None
+        }
+    }
+}
+
+#[cfg(feature = "zstd")]
+impl WriteFilter for ZstdFilter {
+    fn write_filtered(&mut self, buf: &[u8], next: &mut dyn io::Write) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.frame.is_none() {
+            let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), self.level)?;
+
+            encoder.include_checksum(true)?;
+
+            if self.threads > 0 {
+                encoder.multithread(self.threads)?;
+            }
+
+            self.frame = Some(encoder);
+        }
+
+        let encoder = self.frame.as_mut().unwrap();
+
+        encoder.write_all(buf)?;
+
+        let output = mem::take(encoder.get_mut());
+        if !output.is_empty() {
+            next.write_all(&output)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn finish_filtered(&mut self, next: &mut dyn io::Write) -> io::Result<()> {
+        if let Some(encoder) = self.frame.take() {
+            next.write_all(&encoder.finish()?)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush_filtered(&mut self, next: &mut dyn io::Write) -> io::Result<()> {
+        self.finish_filtered(next)?;
+        next.flush()
+    }
+
+    fn changes_length(&self) -> bool {
+        true
+    }
+}
+
+/// Compresses data into the xz format, using liblzma.
+///
+/// See the module documentation for how flushing and data zones behave.
+#[cfg(feature = "xz")]
+pub struct XzFilter {
+    level: u32,
+    threads: u32,
+    stream: Option<xz2::write::XzEncoder<Vec<u8>>>,
+}
+
+#[cfg(feature = "xz")]
+impl XzFilter {
+    /// Create a filter compressing at the given preset level, from 0 to 9, on
+    /// the given number of worker threads.
+    ///
+    /// With no worker threads, data is compressed on whichever thread writes
+    /// to the filter.
+    pub fn new(level: u32, threads: u32) -> XzFilter {
+        XzFilter {
+            level,
+            threads,
+            stream: None
+        }
+    }
+}
+
+#[cfg(feature = "xz")]
+impl WriteFilter for XzFilter {
+    fn write_filtered(&mut self, buf: &[u8], next: &mut dyn io::Write) -> io::Result<usize> {
+        use xz2::stream::{Check, MtStreamBuilder, Stream};
+
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.stream.is_none() {
+            let stream = match self.threads {
+                0 => Stream::new_easy_encoder(self.level, Check::Crc64),
+                threads => MtStreamBuilder::new().threads(threads).preset(self.level).check(Check::Crc64).encoder()
+            };
+
+            self.stream = Some(xz2::write::XzEncoder::new_stream(Vec::new(), stream?));
+        }
+
+        let encoder = self.stream.as_mut().unwrap();
+
+        encoder.write_all(buf)?;
+
+        let output = mem::take(encoder.get_mut());
+        if !output.is_empty() {
+            next.write_all(&output)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn finish_filtered(&mut self, next: &mut dyn io::Write) -> io::Result<()> {
+        if let Some(encoder) = self.stream.take() {
+            next.write_all(&encoder.finish()?)?;
+        }
+
+        Ok(())
+    }
+
+    fn flush_filtered(&mut self, next: &mut dyn io::Write) -> io::Result<()> {
+        self.finish_filtered(next)?;
+        next.flush()
+    }
+
+    fn changes_length(&self) -> bool {
+        true
+    }
+}
+
+/// The compression formats an archive can be written or read in.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Codec {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Codec {
+    /// Create a filter compressing into this format, as tuned by the given
+    /// configuration.
+    pub fn filter(self, tuning: &Configuration) -> io::Result<Box<dyn WriteFilter>> {
+        match self {
+            Codec::Gzip => {
+                let level = match tuning.compression_level {
+                    Some(level) => level.clamp(1, 9) as u32,
+                    None => DEFAULT_LEVEL
+                };
+
+                match tuning.compression_threads {
+                    0 => Ok(Box::new(GzipFilter::new(level))),
+                    threads => Ok(Box::new(ParallelGzipFilter::new(level, threads)))
+                }
+            },
+            #[cfg(feature = "zstd")]
+            Codec::Zstd => Ok(Box::new(ZstdFilter::new(tuning.compression_level.unwrap_or(0), tuning.compression_threads as u32))),
+            #[cfg(not(feature = "zstd"))]
+            Codec::Zstd => Err(io::Error::new(io::ErrorKind::InvalidInput, "Zstandard compression is not supported by this build")),
+            #[cfg(feature = "xz")]
+            Codec::Xz => Ok(Box::new(XzFilter::new(tuning.compression_level.map(|level| level.clamp(0, 9) as u32).unwrap_or(DEFAULT_LEVEL), tuning.compression_threads as u32))),
+            #[cfg(not(feature = "xz"))]
+            Codec::Xz => Err(io::Error::new(io::ErrorKind::InvalidInput, "xz compression is not supported by this build"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+    use std::sync::mpsc;
+    use crate::filter::{WriteFilter, FilterChain};
+    use crate::fs::ArchivalSink;
+    use crate::tuning::Configuration;
+    use super::{GzipFilter, ParallelGzipFilter, Codec};
+
+    /// Compress some data in one member.
+    fn gzip(data: &[u8], level: u32) -> Vec<u8> {
+        let mut filter = GzipFilter::new(level);
+        let mut out = vec![];
+
+        for piece in data.chunks(10_000) {
+            filter.write_filtered(piece, &mut out).unwrap();
+        }
+
+        filter.flush_filtered(&mut out).unwrap();
+        out
+    }
+
+    /// Decompress with the system's gzip, if there is one.
+    fn gunzip(data: &[u8]) -> Option<Vec<u8>> {
+        let mut child = Command::new("gzip").arg("-dc").stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().ok()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let data = data.to_vec();
+        let writer = std::thread::spawn(move || stdin.write_all(&data));
+        let output = child.wait_with_output().unwrap();
+
+        writer.join().unwrap().unwrap();
+        assert!(output.status.success());
+
+        Some(output.stdout)
+    }
+
+    /// Compressible test data: words, repeated with variations.
+    fn text(len: usize) -> Vec<u8> {
+        let words = ["archive ", "tape ", "volume ", "block ", "record ", "header ", "member ", "\n"];
+        let mut state = 1u32;
+        let mut text = vec![];
+
+        while text.len() < len {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            text.extend_from_slice(words[(state >> 29) as usize].as_bytes());
+        }
+
+        text.truncate(len);
+        text
+    }
+
+    /// Deterministic, incompressible test data.
+    fn noise(len: usize) -> Vec<u8> {
+        let mut state = 7u32;
+
+        (0..len).map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 24) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn gzip_roundtrip() {
+        for data in [vec![], b"a".to_vec(), text(300_000), noise(200_000), vec![0; 1_000_000]].iter() {
+            for level in [1, 6, 9].iter() {
+                let compressed = gzip(data, *level);
+
+                //Nothing written, so no member was started.
+                if data.is_empty() {
+                    assert!(compressed.is_empty());
+                    continue;
+                }
+
+                assert_eq!(&compressed[..3], &[0x1F, 0x8B, 8]);
+
+                match gunzip(&compressed) {
+                    Some(decompressed) => assert!(decompressed == *data, "level {} of {} bytes", level, data.len()),
+                    None => return
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn gzip_ratio() {
+        let data = text(300_000);
+
+        assert!(gzip(&data, 6).len() < data.len() / 3);
+        assert!(gzip(&vec![0; 1_000_000], 6).len() < 2000);
+        assert!(gzip(&noise(200_000), 6).len() < 200_000 + 100);
+    }
+
+    #[test]
+    fn gzip_filter_members() {
+        let mut filter = GzipFilter::default();
+        let mut out = vec![];
+
+        //Flushing ends a member; flushing again without writing adds nothing.
+        filter.write_filtered(b"first member, ", &mut out).unwrap();
+        filter.flush_filtered(&mut out).unwrap();
+        let first_len = out.len();
+        filter.flush_filtered(&mut out).unwrap();
+        assert_eq!(out.len(), first_len);
+        filter.write_filtered(b"second member", &mut out).unwrap();
+        filter.flush_filtered(&mut out).unwrap();
+        assert_eq!(&out[first_len..first_len + 3], &[0x1F, 0x8B, 8]);
+
+        if let Some(decompressed) = gunzip(&out) {
+            assert_eq!(decompressed, b"first member, second member");
+        }
+    }
+
+    #[test]
+    fn gzip_filter_sink() {
+        let mut chain = FilterChain::new();
+
+        chain.push(GzipFilter::default());
+
+        let mut sink : Box<dyn ArchivalSink<u64>> = chain.apply(Box::new(std::io::Cursor::new(vec![])));
+
+        sink.write_all(b"archive").unwrap();
+        sink.flush().unwrap();
+        assert!(sink.downcast_seek().is_none());
+        assert!(sink.downcast_tapedevice().is_none());
+    }
+
+    #[test]
+    fn gzip_parallel() {
+        let data = text(1_000_000);
+        let single = gzip(&data, 6).len();
+
+        for threads in [1, 4].iter() {
+            let mut filter = ParallelGzipFilter::new(6, *threads);
+            let mut out = vec![];
+
+            for piece in data.chunks(50_000) {
+                filter.write_filtered(piece, &mut out).unwrap();
+            }
+
+            filter.flush_filtered(&mut out).unwrap();
+            let first_len = out.len();
+            filter.flush_filtered(&mut out).unwrap();
+            assert_eq!(out.len(), first_len);
+
+            filter.write_filtered(b"second member", &mut out).unwrap();
+            filter.flush_filtered(&mut out).unwrap();
+
+            //Chunks continue from each other, so this is as good as compressing
+            //the whole thing at once.
+            assert!(first_len < single * 11 / 10);
+
+            if let Some(decompressed) = gunzip(&out) {
+                let mut expected = data.clone();
+
+                expected.extend_from_slice(b"second member");
+                assert!(decompressed == expected);
+            }
+        }
+    }
+
+    #[test]
+    fn gzip_parallel_lost_job() {
+        let mut filter = ParallelGzipFilter::new(6, 2);
+        let (sender, receiver) = mpsc::channel();
+        let mut out = vec![];
+
+        //A job that stopped without sending anything back, as if it panicked.
+        drop(sender);
+        filter.jobs.push_back(receiver);
+
+        assert!(filter.collect(&mut out, 0).is_err());
+    }
+
+    #[test]
+    fn codec_levels() {
+        let mut tuning = Configuration::default();
+        let mut fast = vec![];
+        let mut small = vec![];
+        let data = text(300_000);
+
+        tuning.compression_level = Some(1);
+        let mut filter = Codec::Gzip.filter(&tuning).unwrap();
+        filter.write_filtered(&data, &mut fast).unwrap();
+        filter.flush_filtered(&mut fast).unwrap();
+
+        //Levels beyond gzip's range are clamped to it.
+        tuning.compression_level = Some(19);
+        let mut filter = Codec::Gzip.filter(&tuning).unwrap();
+        filter.write_filtered(&data, &mut small).unwrap();
+        filter.flush_filtered(&mut small).unwrap();
+
+        assert!(small.len() <= fast.len());
+        assert_eq!(Codec::Zstd.filter(&tuning).is_ok(), cfg!(feature = "zstd"));
+        assert_eq!(Codec::Xz.filter(&tuning).is_ok(), cfg!(feature = "xz"));
+    }
+
+    #[cfg(feature = "zstd")]
+    #[test]
+    fn zstd_roundtrip() {
+        use super::ZstdFilter;
+
+        for threads in [0, 2].iter() {
+            let data = text(3_000_000);
+            let mut filter = ZstdFilter::new(3, *threads);
+            let mut out = vec![];
+
+            for piece in data.chunks(10_000) {
+                filter.write_filtered(piece, &mut out).unwrap();
+            }
+
+            filter.flush_filtered(&mut out).unwrap();
+            filter.flush_filtered(&mut out).unwrap();
+            filter.write_filtered(b"second frame", &mut out).unwrap();
+            filter.flush_filtered(&mut out).unwrap();
+
+            let mut expected = data.clone();
+            expected.extend_from_slice(b"second frame");
+
+            assert!(out.len() < data.len() / 3);
+            assert!(zstd::decode_all(&out[..]).unwrap() == expected);
+        }
+    }
+
+    #[cfg(feature = "xz")]
+    #[test]
+    fn xz_roundtrip() {
+        use std::io::Read;
+        use super::XzFilter;
+
+        for threads in [0, 2].iter() {
+            let data = text(1_000_000);
+            let mut filter = XzFilter::new(3, *threads);
+            let mut out = vec![];
+
+            for piece in data.chunks(10_000) {
+                filter.write_filtered(piece, &mut out).unwrap();
+            }
+
+            filter.flush_filtered(&mut out).unwrap();
+            filter.flush_filtered(&mut out).unwrap();
+            filter.write_filtered(b"second stream", &mut out).unwrap();
+            filter.flush_filtered(&mut out).unwrap();
+
+            let mut expected = data.clone();
+            expected.extend_from_slice(b"second stream");
+
+            let mut decoded = vec![];
+            xz2::read::XzDecoder::new_multi_decoder(&out[..]).read_to_end(&mut decoded).unwrap();
+
+            assert!(out.len() < data.len() / 3);
+            assert!(decoded == expected);
+        }
+    }
+}
@@ -1,16 +1,26 @@
 //! Parallel I/O buffers
 
-use std::{io, thread};
+use std::{io, fs, path, process, thread};
+use std::io::{Read, Seek, Write};
+use std::collections::VecDeque;
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::{channel, Sender, Receiver};
 use crate::fs::ArchivalSink;
 use crate::spanning::{DataZone, DataZoneStream, RecoverableWrite};
+use crate::tape::{TapeDevice, FilemarkPolicy};
+use crate::cancel::{CancellationToken, cancelled_error};
 
-enum ConcurrentCommand<I> where I: Send + Clone {
+/// Makes an inner writer's data durable, for writers which can be synced.
+type SyncFn<T> = fn(&mut T) -> io::Result<()>;
+
+enum ConcurrentCommand<T, I> where I: Send + Clone {
     #[allow(dead_code)]
     DoRead(u64),
     DoWriteAll(Vec<u8>),
     DoFlush,
+    DoSync(SyncFn<T>),
+    DoSyncEvery(u64, SyncFn<T>),
     DoBeginDataZone(I),
     DoResumeDataZone(I, u64),
     DoEndDataZone,
@@ -21,6 +31,7 @@ enum ConcurrentResponse {
     DidRead(io::Result<Vec<u8>>),
     DidWriteAll(io::Result<usize>),
     DidFlush(io::Result<()>),
+    DidSync(io::Result<()>),
     DidBeginDataZone,
     DidResumeDataZone,
     DidEndDataZone,
@@ -36,8 +47,20 @@ use self::ConcurrentResponse::*;
 /// This is the version of the command task designed to handle instances of
 /// [`io::Write`]. Due to Rust specialization not being ready yet, you can only
 /// prebuffer an [`io::Read`] *or* an [`io::Write`], but not both.
+/// 
+/// Once the given `CancellationToken` is cancelled, buffered data is no longer
+/// written, and each outstanding write, flush, or sync command fails instead.
+/// 
+/// Once asked to sync every so many bytes, the task does so after whichever
+/// write crosses the interval. Errors from those syncs can't be reported by
+/// the write, which did succeed, so they are reported by the next flush or
+/// sync instead.
 #[allow(unused_must_use)]
-fn command_task_write<T, P>(inner_mtx: Arc<Mutex<T>>, cmd_recv: Receiver<ConcurrentCommand<P>>, cmd_send: Sender<ConcurrentResponse>) where T: io::Write + Send + RecoverableWrite<P>, P: Send + Clone {
+fn command_task_write<T, P>(inner_mtx: Arc<Mutex<T>>, cmd_recv: Receiver<ConcurrentCommand<T, P>>, cmd_send: Sender<ConcurrentResponse>, cancel: CancellationToken) where T: io::Write + Send + RecoverableWrite<P>, P: Send + Clone {
+    let mut periodic_sync : Option<(u64, SyncFn<T>)> = None;
+    let mut unsynced = 0;
+    let mut sync_error = None;
+
     while let Ok(cmd) = cmd_recv.recv() {
         {
             let mut inner = inner_mtx.lock().unwrap();
@@ -49,19 +72,68 @@ fn command_task_write<T, P>(inner_mtx: Arc<Mutex<T>>, cmd_recv: Receiver<Concurr
                         break;
                     }
                 },
+                DoWriteAll(_) if cancel.is_cancelled() => {
+                    if let Err(_) = cmd_send.send(DidWriteAll(Err(cancelled_error()))) {
+                        break;
+                    }
+                },
                 DoWriteAll(data) => {
-                    if let Err(_) = cmd_send.send(DidWriteAll(match inner.write_all(&data) {
+                    let result = inner.write_all(&data);
+
+                    if let (Ok(_), Some((interval, sync))) = (&result, periodic_sync) {
+                        unsynced += data.len() as u64;
+
+                        if unsynced >= interval {
+                            unsynced = 0;
+
+                            if let Err(e) = sync(&mut inner) {
+                                sync_error.get_or_insert(e);
+                            }
+                        }
+                    }
+
+                    if let Err(_) = cmd_send.send(DidWriteAll(match result {
                         Ok(_) => Ok(data.len()),
                         Err(e) => Err(e)
                     })) {
                         break;
                     }
                 },
+                DoFlush if cancel.is_cancelled() => {
+                    if let Err(_) = cmd_send.send(DidFlush(Err(cancelled_error()))) {
+                        break;
+                    }
+                },
                 DoFlush => {
-                    if let Err(_) = cmd_send.send(DidFlush(inner.flush())) {
+                    let result = match sync_error.take() {
+                        Some(e) => Err(e),
+                        None => inner.flush()
+                    };
+
+                    if let Err(_) = cmd_send.send(DidFlush(result)) {
                         break;
                     }
                 },
+                DoSync(_) if cancel.is_cancelled() => {
+                    if let Err(_) = cmd_send.send(DidSync(Err(cancelled_error()))) {
+                        break;
+                    }
+                },
+                DoSync(sync) => {
+                    let result = match sync_error.take() {
+                        Some(e) => Err(e),
+                        None => sync(&mut inner)
+                    };
+
+                    unsynced = 0;
+
+                    if let Err(_) = cmd_send.send(DidSync(result)) {
+                        break;
+                    }
+                },
+                DoSyncEvery(interval, sync) => {
+                    periodic_sync = Some((interval, sync));
+                },
                 DoBeginDataZone(ident) => {
                     inner.begin_data_zone(ident);
                     
@@ -93,6 +165,85 @@ fn command_task_write<T, P>(inner_mtx: Arc<Mutex<T>>, cmd_recv: Receiver<Concurr
     cmd_send.send(Terminated);
 }
 
+static SPILL_FILE_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Temporary file holding records which didn't fit in a write buffer.
+/// 
+/// Records are stored back-to-back in the file, with their lengths kept in
+/// memory so that they can be handed back in their original sizes. The file is
+/// truncated whenever it empties out and deleted when dropped.
+struct SpillFile {
+    path: path::PathBuf,
+    file: fs::File,
+    read_pos: u64,
+    write_pos: u64,
+    lengths: VecDeque<usize>
+}
+
+impl SpillFile {
+    fn create(dir: &path::Path) -> io::Result<SpillFile> {
+        let name = format!("rapidtar-spill-{}-{}.tmp", process::id(), SPILL_FILE_COUNT.fetch_add(1, Ordering::SeqCst));
+        let path = dir.join(name);
+        let file = fs::OpenOptions::new().read(true).write(true).create_new(true).open(&path)?;
+
+        Ok(SpillFile {
+            path: path,
+            file: file,
+            read_pos: 0,
+            write_pos: 0,
+            lengths: VecDeque::new()
+        })
+    }
+
+    fn is_empty(&self) -> bool {
+        self.lengths.is_empty()
+    }
+
+    fn front_len(&self) -> Option<usize> {
+        self.lengths.front().cloned()
+    }
+
+    fn push(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.file.seek(io::SeekFrom::Start(self.write_pos))?;
+        self.file.write_all(buf)?;
+
+        self.write_pos += buf.len() as u64;
+        self.lengths.push_back(buf.len());
+
+        Ok(())
+    }
+
+    fn pop(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let len = match self.lengths.front() {
+            Some(len) => *len,
+            None => return Ok(None)
+        };
+
+        let mut data = vec![0; len];
+
+        self.file.seek(io::SeekFrom::Start(self.read_pos))?;
+        self.file.read_exact(&mut data)?;
+
+        self.read_pos += len as u64;
+        self.lengths.pop_front();
+
+        if self.lengths.is_empty() {
+            self.read_pos = 0;
+            self.write_pos = 0;
+            self.file.set_len(0)?;
+        }
+
+        Ok(Some(data))
+    }
+}
+
+impl Drop for SpillFile {
+    #[allow(unused_must_use)]
+    fn drop(&mut self) {
+        fs::remove_file(&self.path);
+    }
+}
+
 /// Write buffer that does all of it's buffered I/O concurrently.
 /// 
 /// By doing buffered I/O on a separate thread and storing the results in memory
@@ -113,25 +264,51 @@ fn command_task_write<T, P>(inner_mtx: Arc<Mutex<T>>, cmd_recv: Receiver<Concurr
 /// [`BlockingWriter`] depending on your needs. Such writers may be
 /// used in concert with this one.
 /// 
+/// # Spilling
+/// 
+/// Normally, once the buffer limit is reached, writes block until the device
+/// catches up. If the device is paused for a long time (say, waiting for a
+/// tape to load) this stalls everything feeding the buffer. A buffer created
+/// with `new_with_spill` instead writes overflowing records to a temporary
+/// file, and moves them back into the buffer as the device drains it. Spilled
+/// records are moved back on later writes and flushes, so callers must `flush`
+/// before dropping the buffer or the spilled data will be lost.
+/// 
+/// # Cancellation
+/// 
+/// A buffer created with `new_cancellable` stops writing once it's token is
+/// cancelled: buffered and spilled data is discarded, and further writes and
+/// flushes fail with a cancellation error. A write already underway on the
+/// inner writer is allowed to finish.
+/// 
 /// [`BlockingWriter`]: ../blocking/struct.BlockingWriter.html
 pub struct ConcurrentWriteBuffer<T: io::Write + Send, P: Send + Clone> {
-    cmd_send: Sender<ConcurrentCommand<P>>,
+    cmd_send: Sender<ConcurrentCommand<T, P>>,
     resp_recv: Receiver<ConcurrentResponse>,
     inner: Arc<Mutex<T>>,
     buffered_size: u64,
     buffered_limit: u64,
-    datazone_stream: DataZoneStream<P>
+    datazone_stream: DataZoneStream<P>,
+    spill: Option<SpillFile>,
+    cancel: CancellationToken,
 }
 
 impl<T, P> ConcurrentWriteBuffer<T, P> where T: 'static + io::Write + Send + RecoverableWrite<P>, P: 'static + Send + Clone + PartialEq {
     pub fn new(inner: T, limit: u64) -> ConcurrentWriteBuffer<T, P> {
+        Self::new_cancellable(inner, limit, CancellationToken::new())
+    }
+
+    /// Construct a buffer which stops writing when the given token is
+    /// cancelled.
+    pub fn new_cancellable(inner: T, limit: u64, cancel: CancellationToken) -> ConcurrentWriteBuffer<T, P> {
         let (cmd_send, cmd_recv) = channel();
         let (resp_send, resp_recv) = channel();
         let self_inner_mtx = Arc::new(Mutex::new(inner));
         let cmd_inner_mtx = self_inner_mtx.clone();
+        let cmd_cancel = cancel.clone();
         
         thread::Builder::new().name("Async Write Thread".into()).stack_size(64*1024).spawn(move || {
-            command_task_write(cmd_inner_mtx, cmd_recv, resp_send)
+            command_task_write(cmd_inner_mtx, cmd_recv, resp_send, cmd_cancel)
         }).unwrap();
         
         ConcurrentWriteBuffer {
@@ -140,9 +317,52 @@ impl<T, P> ConcurrentWriteBuffer<T, P> where T: 'static + io::Write + Send + Rec
             inner: self_inner_mtx,
             buffered_size: 0,
             buffered_limit: limit,
-            datazone_stream: DataZoneStream::new()
+            datazone_stream: DataZoneStream::new(),
+            spill: None,
+            cancel: cancel
         }
     }
+
+    /// Construct a buffer which spills records that exceed it's limit to a
+    /// temporary file in the given directory, rather than blocking.
+    pub fn new_with_spill(inner: T, limit: u64, spill_dir: &path::Path) -> io::Result<ConcurrentWriteBuffer<T, P>> {
+        let mut buf = Self::new(inner, limit);
+
+        buf.spill_to(spill_dir)?;
+
+        Ok(buf)
+    }
+
+    /// Spill records that exceed the buffer's limit to a temporary file in the
+    /// given directory, rather than blocking.
+    pub(crate) fn spill_to(&mut self, spill_dir: &path::Path) -> io::Result<()> {
+        self.spill = Some(SpillFile::create(spill_dir)?);
+
+        Ok(())
+    }
+
+    /// Determine if a record of a given size must wait for buffer space.
+    fn needs_space(&self, size: u64) -> bool {
+        (size < self.buffered_limit) && ((self.buffered_size + size) > self.buffered_limit)
+    }
+
+    /// Move as many spilled records back into the buffer as will fit.
+    fn unspill(&mut self) -> io::Result<()> {
+        while let Some(len) = self.spill.as_ref().and_then(|spill| spill.front_len()) {
+            if self.needs_space(len as u64) {
+                break;
+            }
+
+            if let Some(data) = self.spill.as_mut().unwrap().pop()? {
+                //Spilled data was already counted as buffered in the data zone
+                //stream when it was written to us.
+                self.buffered_size = self.buffered_size + data.len() as u64;
+                self.cmd_send.send(DoWriteAll(data)).unwrap();
+            }
+        }
+
+        Ok(())
+    }
     
     /// Mark some amount of data as committed.
     /// 
@@ -166,7 +386,7 @@ impl<T, P> ConcurrentWriteBuffer<T, P> where T: 'static + io::Write + Send + Rec
     fn drain_buf_until_space(&mut self, needed_space: u64) -> io::Result<()> {
         //TODO: If the buffer thread terminated somehow, we need to have some
         //kind of recovery for it
-        while (needed_space < self.buffered_limit) && ((self.buffered_size + needed_space) > self.buffered_limit) {
+        while self.needs_space(needed_space) {
             match self.resp_recv.recv() {
                 Ok(DidWriteAll(Ok(size))) => self.mark_data_committed(size as u64),
                 Ok(DidWriteAll(Err(e))) => return Err(e),
@@ -199,6 +419,22 @@ impl<T, P> ConcurrentWriteBuffer<T, P> where T: 'static + io::Write + Send + Rec
         }
     }
 
+    /// Wait for a given sync to complete.
+    /// 
+    /// If a sync has not been requested this function will deadlock.
+    fn drain_buf_until_sync(&mut self) -> io::Result<()> {
+        loop {
+            match self.resp_recv.recv() {
+                Ok(DidWriteAll(Ok(size))) => self.mark_data_committed(size as u64),
+                Ok(DidWriteAll(Err(e))) => return Err(e),
+                Ok(DidRead(Err(e))) => return Err(e), //this shouldn't happen but w/e
+                Ok(DidSync(result)) => return result,
+                Err(_) => return Err(io::Error::new(io::ErrorKind::Other, "Buffer thread unexpectedly terminated")),
+                _ => continue
+            }
+        }
+    }
+
     /// Drain all the data 'currently' in the buffer.
     /// 
     /// This function achieves that by using `try_recv`, which errors out if the
@@ -221,6 +457,22 @@ impl<T, P> ConcurrentWriteBuffer<T, P> where T: 'static + io::Write + Send + Rec
 
 impl<T, P> io::Write for ConcurrentWriteBuffer<T, P> where T: 'static + io::Write + Send + RecoverableWrite<P>, P: 'static + Send + Clone + PartialEq {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.cancel.check()?;
+
+        if self.spill.is_some() {
+            self.drain_buf_until_empty()?;
+            self.unspill()?;
+
+            //Once anything has spilled, everything after it has to spill too,
+            //or records would reach the device out of order.
+            if self.needs_space(buf.len() as u64) || !self.spill.as_ref().unwrap().is_empty() {
+                self.spill.as_mut().unwrap().push(buf)?;
+                self.datazone_stream.write_buffered(buf.len() as u64);
+
+                return Ok(buf.len());
+            }
+        }
+
         self.drain_buf_until_space(buf.len() as u64)?;
         
         self.mark_data_buffered(buf.len() as u64);
@@ -230,6 +482,13 @@ impl<T, P> io::Write for ConcurrentWriteBuffer<T, P> where T: 'static + io::Writ
     }
     
     fn flush(&mut self) -> io::Result<()> {
+        self.cancel.check()?;
+
+        while let Some(len) = self.spill.as_ref().and_then(|spill| spill.front_len()) {
+            self.drain_buf_until_space(len as u64)?;
+            self.unspill()?;
+        }
+
         self.cmd_send.send(DoFlush).unwrap();
         
         self.drain_buf_until_flush()?;
@@ -268,5 +527,272 @@ impl<T, P> Drop for ConcurrentWriteBuffer<T, P> where T: io::Write + Send, P: Se
     }
 }
 
-impl<T, P> ArchivalSink<P> for ConcurrentWriteBuffer<T, P> where T: 'static + io::Write + Send + RecoverableWrite<P>, P: 'static + Send + Clone + PartialEq {
-}
\ No newline at end of file
+impl<T, P> ConcurrentWriteBuffer<T, P> where T: 'static + ArchivalSink<P>, P: 'static + Send + Clone + PartialEq {
+    /// Sync the inner writer every time the given number of bytes have been
+    /// written to it.
+    /// 
+    /// Syncs are done by the buffer's thread, so writers aren't held up by
+    /// them. Errors are reported by the next `flush` or `sync`.
+    pub fn sync_every(&mut self, interval: u64) {
+        self.cmd_send.send(DoSyncEvery(interval, <T as ArchivalSink<P>>::sync)).unwrap();
+    }
+
+    /// Drain the buffer and run a tape command on the inner writer.
+    /// 
+    /// Tape commands are positional, so all buffered data must reach the
+    /// device before we can issue one.
+    fn with_inner_tape<R, F>(&mut self, cmd: F) -> io::Result<R> where F: FnOnce(&mut dyn TapeDevice) -> io::Result<R> {
+        io::Write::flush(self)?;
+
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.downcast_tapedevice() {
+            Some(tape) => cmd(tape),
+            None => Err(io::Error::new(io::ErrorKind::Other, "Buffered writer is not a tape device"))
+        }
+    }
+}
+
+/// Seeking a concurrent buffer flushes it before seeking the inner writer.
+impl<T, P> io::Seek for ConcurrentWriteBuffer<T, P> where T: 'static + ArchivalSink<P>, P: 'static + Send + Clone + PartialEq {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        io::Write::flush(self)?;
+
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.downcast_seek() {
+            Some(seekable) => seekable.seek(pos),
+            None => Err(io::Error::new(io::ErrorKind::Other, "Buffered writer is not seekable"))
+        }
+    }
+}
+
+/// Reading through a concurrent buffer is only supported for tape devices, and
+/// flushes any buffered writes first.
+impl<T, P> io::Read for ConcurrentWriteBuffer<T, P> where T: 'static + ArchivalSink<P>, P: 'static + Send + Clone + PartialEq {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.with_inner_tape(|tape| tape.read(buf))
+    }
+}
+
+impl<T, P> TapeDevice for ConcurrentWriteBuffer<T, P> where T: 'static + ArchivalSink<P>, P: 'static + Send + Clone + PartialEq {
+    fn read_block(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.with_inner_tape(|tape| tape.read_block(buf))
+    }
+
+    fn write_filemark(&mut self, blocking: bool) -> io::Result<()> {
+        self.with_inner_tape(|tape| tape.write_filemark(blocking))
+    }
+
+    fn write_setmark(&mut self, blocking: bool) -> io::Result<()> {
+        self.with_inner_tape(|tape| tape.write_setmark(blocking))
+    }
+
+    fn set_filemark_policy(&mut self, policy: FilemarkPolicy) -> io::Result<()> {
+        self.with_inner_tape(|tape| tape.set_filemark_policy(policy))
+    }
+
+    fn set_compression(&mut self, enabled: bool) -> io::Result<()> {
+        self.with_inner_tape(|tape| tape.set_compression(enabled))
+    }
+
+    /// The block size doesn't change with writes, so this doesn't wait for
+    /// buffered writes to finish.
+    fn fixed_block_size(&self) -> Option<u32> {
+        self.inner.lock().unwrap().downcast_tapedevice().and_then(|tape| tape.fixed_block_size())
+    }
+
+    fn seek_blocks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        self.with_inner_tape(|tape| tape.seek_blocks(pos))
+    }
+
+    fn tell_blocks(&mut self) -> io::Result<u64> {
+        self.with_inner_tape(|tape| tape.tell_blocks())
+    }
+
+    fn seek_filemarks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        self.with_inner_tape(|tape| tape.seek_filemarks(pos))
+    }
+
+    fn seek_setmarks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        self.with_inner_tape(|tape| tape.seek_setmarks(pos))
+    }
+
+    fn seek_partition(&mut self, id: u32) -> io::Result<()> {
+        self.with_inner_tape(|tape| tape.seek_partition(id))
+    }
+
+    fn current_partition(&mut self) -> io::Result<u32> {
+        self.with_inner_tape(|tape| tape.current_partition())
+    }
+
+    fn partition_count(&mut self) -> io::Result<u32> {
+        self.with_inner_tape(|tape| tape.partition_count())
+    }
+}
+
+impl<T, P> ArchivalSink<P> for ConcurrentWriteBuffer<T, P> where T: 'static + ArchivalSink<P>, P: 'static + Send + Clone + PartialEq {
+    fn downcast_seek(&mut self) -> Option<&mut dyn io::Seek> {
+        if self.inner.lock().unwrap().downcast_seek().is_some() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn downcast_tapedevice(&mut self) -> Option<&mut dyn TapeDevice> {
+        if self.inner.lock().unwrap().downcast_tapedevice().is_some() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    /// Syncing a concurrent buffer syncs everything written to it, once the
+    /// buffer's thread has written it to the inner writer.
+    fn sync(&mut self) -> io::Result<()> {
+        self.cancel.check()?;
+
+        while let Some(len) = self.spill.as_ref().and_then(|spill| spill.front_len()) {
+            self.drain_buf_until_space(len as u64)?;
+            self.unspill()?;
+        }
+
+        self.cmd_send.send(DoSync(<T as ArchivalSink<P>>::sync)).unwrap();
+
+        self.drain_buf_until_sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, io};
+    use std::io::{Cursor, Seek, SeekFrom, Write};
+    use std::sync::{Arc, Mutex, Condvar};
+    use crate::concurrentbuf::ConcurrentWriteBuffer;
+    use crate::cancel::{CancellationToken, is_cancellation};
+    use crate::fs::ArchivalSink;
+    use crate::spanning::RecoverableWrite;
+    use crate::testing::{RecordingSink, SinkEvent};
+
+    /// Writer which refuses to make progress until it's gate is opened.
+    struct GatedWriter {
+        gate: Arc<(Mutex<bool>, Condvar)>,
+        data: Arc<Mutex<Vec<u8>>>
+    }
+
+    impl io::Write for GatedWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let &(ref lock, ref cvar) = &*self.gate;
+            let mut open = lock.lock().unwrap();
+
+            while !*open {
+                open = cvar.wait(open).unwrap();
+            }
+
+            self.data.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl RecoverableWrite<u64> for GatedWriter {
+    }
+
+    #[test]
+    fn concurrent_spill() {
+        let gate = Arc::new((Mutex::new(false), Condvar::new()));
+        let data = Arc::new(Mutex::new(vec![]));
+        let inner = GatedWriter { gate: gate.clone(), data: data.clone() };
+        let mut buf : ConcurrentWriteBuffer<_, u64> = ConcurrentWriteBuffer::new_with_spill(inner, 1024, &env::temp_dir()).unwrap();
+
+        //Without spilling, the third write would block forever.
+        for i in 0..8 {
+            buf.write_all(&vec![i as u8; 512]).unwrap();
+        }
+
+        {
+            let &(ref lock, ref cvar) = &*gate;
+            *lock.lock().unwrap() = true;
+            cvar.notify_all();
+        }
+
+        buf.flush().unwrap();
+
+        let data = data.lock().unwrap();
+        assert_eq!(data.len(), 8 * 512);
+
+        for i in 0..8 {
+            assert_eq!(&data[i * 512..(i + 1) * 512], vec![i as u8; 512].as_slice());
+        }
+    }
+
+    #[test]
+    fn concurrent_cancel() {
+        let gate = Arc::new((Mutex::new(false), Condvar::new()));
+        let data = Arc::new(Mutex::new(vec![]));
+        let inner = GatedWriter { gate: gate.clone(), data: data.clone() };
+        let cancel = CancellationToken::new();
+        let mut buf : ConcurrentWriteBuffer<_, u64> = ConcurrentWriteBuffer::new_cancellable(inner, 4096, cancel.clone());
+
+        for i in 0..4 {
+            buf.write_all(&vec![i as u8; 512]).unwrap();
+        }
+
+        cancel.cancel();
+
+        {
+            let &(ref lock, ref cvar) = &*gate;
+            *lock.lock().unwrap() = true;
+            cvar.notify_all();
+        }
+
+        assert!(is_cancellation(&buf.write_all(&[0; 512]).err().unwrap()));
+        assert!(is_cancellation(&buf.flush().err().unwrap()));
+
+        //At most the write in progress when we cancelled made it through.
+        assert!(data.lock().unwrap().len() <= 512);
+    }
+
+    #[test]
+    fn concurrent_seek_passthrough() {
+        let mut buf : ConcurrentWriteBuffer<_, u64> = ConcurrentWriteBuffer::new(Cursor::new(vec![]), 1024);
+
+        buf.write_all(&vec![1; 512]).unwrap();
+        buf.write_all(&vec![2; 512]).unwrap();
+
+        let caps = buf.capabilities();
+        assert!(caps.seekable);
+        assert!(!caps.tape);
+
+        assert_eq!(buf.seek(SeekFrom::Start(256)).unwrap(), 256);
+        buf.write_all(&vec![3; 512]).unwrap();
+        buf.flush().unwrap();
+
+        let inner = buf.inner.lock().unwrap();
+        assert_eq!(inner.get_ref().len(), 1024);
+        assert_eq!(&inner.get_ref()[0..256], vec![1 as u8; 256].as_slice());
+        assert_eq!(&inner.get_ref()[256..768], vec![3 as u8; 512].as_slice());
+        assert_eq!(&inner.get_ref()[768..], vec![2 as u8; 256].as_slice());
+    }
+
+    #[test]
+    fn concurrent_sync() {
+        let sink = RecordingSink::<u64>::new();
+        let log = sink.log();
+        let mut buf = ConcurrentWriteBuffer::new(sink, 1024 * 1024);
+
+        buf.sync_every(1024);
+
+        for _ in 0..3 {
+            buf.write_all(&[1; 512]).unwrap();
+        }
+
+        buf.sync().unwrap();
+
+        let record = || SinkEvent::Record(vec![1; 512]);
+        assert_eq!(log.events(), vec![record(), record(), SinkEvent::Sync, record(), SinkEvent::Sync]);
+    }
+}
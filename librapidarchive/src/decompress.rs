@@ -0,0 +1,405 @@
+//! Transparent decompression of archives being read.
+//!
+//! Archives are recognized as compressed by the magic number at the start of
+//! their stream, as GNU tar does when reading, so that listing or extracting a
+//! `.tar.gz` needs no flag. `decompress_source` peeks at those bytes and wraps
+//! the source in the matching decoder, or hands it back untouched (with the
+//! peeked bytes put back) if it isn't compressed at all.
+//!
+//! gzip is decoded with flate2. Zstandard and xz are decoded by their
+//! reference libraries when built with the `zstd` or `xz` features.
+//! Otherwise, the archive is piped through the `zstd` or `xz` command, which
+//! must then be installed.
+//!
+//! Decompressed sources can't hand out their tape device, since reading by
+//! whole tape blocks would skip the decoder.
+
+use std::{io, process, thread};
+use std::io::{BufRead, Read, Write};
+use crate::compress::Codec;
+use crate::fs::ArchivalSource;
+use crate::tape::TapeDevice;
+
+/// The longest magic number we recognize.
+const MAGIC_LENGTH: usize = 6;
+
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const XZ_MAGIC: [u8; 6] = [0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00];
+
+/// Recognize the compression format of a stream from it's first few bytes.
+pub fn detect(magic: &[u8]) -> Option<Codec> {
+    if magic.starts_with(&GZIP_MAGIC) {
+        Some(Codec::Gzip)
+    } else if magic.starts_with(&ZSTD_MAGIC) {
+        Some(Codec::Zstd)
+    } else if magic.starts_with(&XZ_MAGIC) {
+        Some(Codec::Xz)
+    } else {
+        None
+    }
+}
+
+/// Read as much of the start of a stream as a magic number could occupy.
+///
+/// Fewer bytes are only returned if the stream ends first.
+pub fn read_magic<R: Read + ?Sized>(source: &mut R) -> io::Result<Vec<u8>> {
+    let mut magic = vec![0; MAGIC_LENGTH];
+    let mut filled = 0;
+
+    while filled < MAGIC_LENGTH {
+        match source.read(&mut magic[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+
+    magic.truncate(filled);
+    Ok(magic)
+}
+
+/// Wrap a source in a decoder for whatever it's compressed with, if anything.
+pub fn decompress_source(mut source: Box<dyn ArchivalSource>) -> io::Result<Box<dyn ArchivalSource>> {
+    let magic = read_magic(&mut source)?;
+    let codec = detect(&magic);
+    let source = Prefixed { prefix: magic, consumed: 0, inner: source };
+
+    match codec {
+        None => Ok(Box::new(source)),
+        Some(Codec::Gzip) => {
+            info!("Archive is gzip compressed");
+            Ok(Box::new(GzipDecoder::new(source)))
+        },
+        #[cfg(feature = "zstd")]
+        Some(Codec::Zstd) => {
+            info!("Archive is Zstandard compressed");
+            Ok(Box::new(zstd::stream::read::Decoder::new(source)?))
+        },
+        #[cfg(not(feature = "zstd"))]
+        Some(Codec::Zstd) => {
+            info!("Archive is Zstandard compressed");
+            Ok(Box::new(CommandDecoder::spawn("zstd", Box::new(source))?))
+        },
+        #[cfg(feature = "xz")]
+        Some(Codec::Xz) => {
+            info!("Archive is xz compressed");
+            Ok(Box::new(xz2::read::XzDecoder::new_multi_decoder(source)))
+        },
+        #[cfg(not(feature = "xz"))]
+        Some(Codec::Xz) => {
+            info!("Archive is xz compressed");
+            Ok(Box::new(CommandDecoder::spawn("xz", Box::new(source))?))
+        }
+    }
+}
+
+/// A source with some bytes already read from it put back in front.
+struct Prefixed {
+    prefix: Vec<u8>,
+    consumed: usize,
+    inner: Box<dyn ArchivalSource>
+}
+
+impl Read for Prefixed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.consumed < self.prefix.len() {
+            let remaining = &self.prefix[self.consumed..];
+            let n = remaining.len().min(buf.len());
+
+            buf[..n].copy_from_slice(&remaining[..n]);
+            self.consumed += n;
+
+            return Ok(n);
+        }
+
+        self.inner.read(buf)
+    }
+}
+
+impl ArchivalSource for Prefixed {
+    /// Tapes are only handed out once the put-back bytes have been read, as
+    /// the tape itself is already past them.
+    fn downcast_tapedevice(&mut self) -> Option<&mut dyn TapeDevice> {
+        if self.consumed < self.prefix.len() {
+            return None;
+        }
+
+        self.inner.downcast_tapedevice()
+    }
+}
+
+/// Decompresses a gzip stream as it's read.
+///
+/// Concatenated members are read as one stream. Anything which follows the
+/// last member that isn't another one, such as the zeros padding a blocked
+/// archive out to it's record size, is ignored.
+pub struct GzipDecoder<R: Read> {
+    member: Option<flate2::bufread::GzDecoder<io::BufReader<R>>>,
+    members: u64
+}
+
+impl<R: Read> GzipDecoder<R> {
+    pub fn new(inner: R) -> Self {
+        GzipDecoder {
+            member: Some(flate2::bufread::GzDecoder::new(io::BufReader::new(inner))),
+            members: 0
+        }
+    }
+
+    /// Start decoding the next member, if another one follows the member we
+    /// just finished.
+    fn next_member(&mut self) -> io::Result<()> {
+        let mut input = match self.member.take() {
+            Some(member) => member.into_inner(),
+            None => return Ok(())
+        };
+
+        self.members += 1;
+
+        //A member split across the buffer's end can only be recognized by
+        //it's first byte; the header is checked properly once it's decoded.
+        let available = input.fill_buf()?;
+
+        if available.is_empty() || !GZIP_MAGIC.starts_with(&available[..available.len().min(2)]) {
+            if !available.is_empty() {
+                debug!("Ignoring data after gzip member {}", self.members);
+            }
+
+            return Ok(());
+        }
+
+        self.member = Some(flate2::bufread::GzDecoder::new(input));
+
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for GzipDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        while let Some(ref mut member) = self.member {
+            match member.read(buf)? {
+                0 => self.next_member()?,
+                n => return Ok(n)
+            }
+        }
+
+        Ok(0)
+    }
+}
+
+impl<R: Read + Send> ArchivalSource for GzipDecoder<R> {
+}
+
+#[cfg(feature = "zstd")]
+impl<R: io::BufRead + Send> ArchivalSource for zstd::stream::read::Decoder<'static, R> {
+}
+
+#[cfg(feature = "xz")]
+impl<R: Read + Send> ArchivalSource for xz2::read::XzDecoder<R> {
+}
+
+/// Decompresses a stream by piping it through an external program.
+///
+/// The compressed stream is fed to the program's standard input from a thread
+/// of it's own, and the decompressed stream read back from it's output. Once
+/// that ends, the program has to have exited successfully for the stream to
+/// be considered complete.
+pub struct CommandDecoder {
+    program: &'static str,
+    child: process::Child,
+    output: process::ChildStdout,
+    feeder: Option<thread::JoinHandle<io::Result<()>>>
+}
+
+impl CommandDecoder {
+    pub fn spawn(program: &'static str, mut source: Box<dyn ArchivalSource>) -> io::Result<Self> {
+        let mut child = process::Command::new(program)
+            .arg("-dc")
+            .stdin(process::Stdio::piped())
+            .stdout(process::Stdio::piped())
+            .spawn()
+            .map_err(|e| io::Error::new(e.kind(), format!("Could not run {} to decompress the archive: {}", program, e)))?;
+        let mut input = child.stdin.take().expect("child should have a standard input");
+        let output = child.stdout.take().expect("child should have a standard output");
+        let feeder = thread::Builder::new().name(format!("{} Feeder Thread", program)).spawn(move || {
+            match io::copy(&mut source, &mut input) {
+                Ok(_) => input.flush(),
+                //The program stopped reading, which it'll tell us about.
+                Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(()),
+                Err(e) => Err(e)
+            }
+        })?;
+
+        Ok(CommandDecoder { program, child, output, feeder: Some(feeder) })
+    }
+
+    /// Wait for the program to finish, reporting whatever went wrong.
+    fn finish(&mut self) -> io::Result<()> {
+        let feeder = match self.feeder.take() {
+            Some(feeder) => feeder,
+            None => return Ok(())
+        };
+        let status = self.child.wait()?;
+
+        match feeder.join() {
+            Ok(result) => result?,
+            Err(_) => return Err(io::Error::new(io::ErrorKind::Other, format!("{} feeder thread panicked", self.program)))
+        }
+
+        if !status.success() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} failed to decompress the archive ({})", self.program, status)));
+        }
+
+        Ok(())
+    }
+}
+
+impl Read for CommandDecoder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.output.read(buf)?;
+
+        if n == 0 && !buf.is_empty() {
+            self.finish()?;
+        }
+
+        Ok(n)
+    }
+}
+
+impl ArchivalSource for CommandDecoder {
+}
+
+impl Drop for CommandDecoder {
+    /// Stop the program if the archive wasn't read to the end.
+    fn drop(&mut self) {
+        if self.feeder.is_some() {
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read, Write};
+    use std::process::{Command, Stdio};
+    use crate::compress::{Codec, GzipFilter, ParallelGzipFilter};
+    use crate::filter::WriteFilter;
+    use crate::fs::ArchivalSource;
+    use super::{GzipDecoder, detect, decompress_source};
+
+    fn compress<F: WriteFilter>(mut filter: F, data: &[u8], member_size: usize) -> Vec<u8> {
+        let mut out = vec![];
+
+        for member in data.chunks(member_size) {
+            for piece in member.chunks(10_000) {
+                filter.write_filtered(piece, &mut out).unwrap();
+            }
+
+            filter.flush_filtered(&mut out).unwrap();
+        }
+
+        out
+    }
+
+    fn inflate(data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut out = vec![];
+
+        GzipDecoder::new(data).read_to_end(&mut out)?;
+        Ok(out)
+    }
+
+    fn sample() -> Vec<u8> {
+        let mut data = vec![];
+
+        for i in 0..15_000u32 {
+            data.extend_from_slice(format!("line {} of {}\n", i, i.wrapping_mul(2_654_435_761) % 977).as_bytes());
+        }
+
+        let mut seed = 1u32;
+
+        for _ in 0..30_000 {
+            seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            data.push((seed >> 16) as u8);
+        }
+
+        data
+    }
+
+    #[test]
+    fn gunzip_roundtrip() {
+        let data = sample();
+
+        for &level in &[1, 6, 9] {
+            assert!(inflate(&compress(GzipFilter::new(level), &data, data.len())).unwrap() == data);
+        }
+
+        assert!(inflate(&compress(GzipFilter::new(6), &data, 100_000)).unwrap() == data);
+        assert!(inflate(&compress(ParallelGzipFilter::new(6, 3), &data, data.len())).unwrap() == data);
+    }
+
+    #[test]
+    fn gunzip_system_gzip() {
+        let data = sample();
+        let mut child = match Command::new("gzip").args(&["-c", "-n", "-9"]).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn() {
+            Ok(child) => child,
+            Err(_) => return
+        };
+        let mut stdin = child.stdin.take().unwrap();
+        let input = data.clone();
+        let writer = std::thread::spawn(move || stdin.write_all(&input));
+        let compressed = child.wait_with_output().unwrap().stdout;
+
+        writer.join().unwrap().unwrap();
+        assert!(inflate(&compressed).unwrap() == data);
+    }
+
+    #[test]
+    fn gunzip_trailing_data() {
+        let data = sample();
+        let mut compressed = compress(GzipFilter::new(6), &data, data.len());
+        let length = compressed.len();
+
+        compressed.extend_from_slice(&[0; 10240]);
+        assert!(inflate(&compressed).unwrap() == data);
+
+        assert_eq!(inflate(&compressed[..length - 1]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+
+        compressed[length - 6] ^= 1;
+        assert!(inflate(&compressed).is_err());
+    }
+
+    #[test]
+    fn decompress_detection() {
+        assert_eq!(detect(&[0x1F, 0x8B, 8, 0, 0, 0]), Some(Codec::Gzip));
+        assert_eq!(detect(&[0x28, 0xB5, 0x2F, 0xFD, 0, 0]), Some(Codec::Zstd));
+        assert_eq!(detect(&[0xFD, 0x37, 0x7A, 0x58, 0x5A, 0x00]), Some(Codec::Xz));
+        assert_eq!(detect(b"file.t"), None);
+        assert_eq!(detect(&[0x1F]), None);
+
+        let data = sample();
+        let plain: Box<dyn ArchivalSource> = Box::new(io::Cursor::new(data.clone()));
+        let mut out = vec![];
+
+        decompress_source(plain).unwrap().read_to_end(&mut out).unwrap();
+        assert!(out == data);
+
+        let compressed: Box<dyn ArchivalSource> = Box::new(io::Cursor::new(compress(GzipFilter::new(6), &data, data.len())));
+        let mut out = vec![];
+
+        decompress_source(compressed).unwrap().read_to_end(&mut out).unwrap();
+        assert!(out == data);
+
+        let short: Box<dyn ArchivalSource> = Box::new(io::Cursor::new(vec![0x1F]));
+        let mut out = vec![];
+
+        decompress_source(short).unwrap().read_to_end(&mut out).unwrap();
+        assert_eq!(out, vec![0x1F]);
+    }
+}
@@ -0,0 +1,163 @@
+//! Lists of files to archive, as given to `-T`.
+//!
+//! A file list names one path per line. As with GNU tar, lines starting with
+//! a dash are options instead, which apply to every path listed after them:
+//!
+//! ```text
+//! -C /home
+//! alice
+//! --exclude=*.tmp
+//! bob
+//! --directory=/etc
+//! passwd
+//! --add-file=-odd-name
+//! ```
+//!
+//! The supported options are:
+//!
+//!  * `-C DIR` or `--directory=DIR`: read the following paths from within
+//!    `DIR`, which is itself relative to the previous directory, if any. Paths
+//!    are still archived under the names they were listed with.
+//!  * `--exclude=PATTERN`: leave out anything matching the given shell
+//!    wildcard pattern (see `tar::filter::ExcludePattern`) from the following
+//!    paths.
+//!  * `--add-file=NAME`: list a path which starts with a dash.
+//!
+//! Empty lines are ignored. Any other option is an error, so that a list
+//! written for another tar doesn't silently archive the wrong things.
+
+use std::{io, path};
+use std::io::BufRead;
+
+/// A path named in a file list, along with the options in effect for it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ListedPath {
+    /// The path, as it should be archived.
+    pub path: path::PathBuf,
+
+    /// The directory to read the path from, if the list changed directory.
+    pub directory: Option<path::PathBuf>,
+
+    /// Wildcard patterns excluding entries within the path.
+    pub excludes: Vec<String>,
+}
+
+impl ListedPath {
+    /// The path to read this entry from.
+    pub fn source_path(&self) -> path::PathBuf {
+        match self.directory {
+            Some(ref directory) => directory.join(&self.path),
+            None => self.path.clone()
+        }
+    }
+}
+
+/// Split an option line into it's name and value.
+///
+/// Values may be attached with `=` (long options), attached directly (short
+/// options), or separated with whitespace.
+fn split_option(line: &str) -> (&str, Option<&str>) {
+    if line.starts_with("--") {
+        if let Some(equals) = line.find('=') {
+            return (&line[..equals], Some(&line[equals + 1..]));
+        }
+    } else if let Some(rest) = line.get(2..) {
+        if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+            return (&line[..2], Some(rest));
+        }
+    }
+
+    match line.find(char::is_whitespace) {
+        Some(space) => (&line[..space], Some(line[space..].trim_start())),
+        None => (line, None)
+    }
+}
+
+/// The options a file list may contain.
+const SUPPORTED_OPTIONS: [&str; 4] = ["-C", "--directory", "--exclude", "--add-file"];
+
+/// Parse a file list.
+pub fn parse_file_list<R: BufRead>(reader: R) -> io::Result<Vec<ListedPath>> {
+    let mut listed = Vec::new();
+    let mut directory : Option<path::PathBuf> = None;
+    let mut excludes = Vec::new();
+
+    for (number, line) in reader.lines().enumerate() {
+        let line = line?;
+        let line = line.trim_end_matches('\r');
+
+        if line.is_empty() {
+            continue;
+        }
+
+        if !line.starts_with('-') {
+            listed.push(ListedPath {
+                path: path::PathBuf::from(line),
+                directory: directory.clone(),
+                excludes: excludes.clone()
+            });
+
+            continue;
+        }
+
+        let (option, value) = split_option(line);
+
+        if !SUPPORTED_OPTIONS.contains(&option) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Line {} of file list: unsupported option {}", number + 1, option)));
+        }
+
+        let value = match value {
+            Some(value) if !value.is_empty() => value,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Line {} of file list: {} needs a value", number + 1, option)))
+        };
+
+        match option {
+            "-C" | "--directory" => directory = Some(match directory {
+                Some(ref current) => current.join(value),
+                None => path::PathBuf::from(value)
+            }),
+            "--exclude" => excludes.push(value.to_string()),
+            _ => listed.push(ListedPath {
+                path: path::PathBuf::from(value),
+                directory: directory.clone(),
+                excludes: excludes.clone()
+            })
+        }
+    }
+
+    Ok(listed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+    use super::parse_file_list;
+
+    #[test]
+    fn file_list_options() {
+        let list = "a\n\n-C /home\nalice\n--exclude=*.tmp\n--directory=bob\nnotes\n--add-file=-odd\n-Cdocs\nb c\r\n";
+        let listed = parse_file_list(list.as_bytes()).unwrap();
+
+        let summary : Vec<(String, Option<String>, Vec<String>)> = listed.iter().map(|listed| (
+            listed.path.to_string_lossy().into_owned(),
+            listed.directory.as_ref().map(|d| d.to_string_lossy().into_owned()),
+            listed.excludes.clone()
+        )).collect();
+
+        assert_eq!(summary, vec![
+            ("a".to_string(), None, vec![]),
+            ("alice".to_string(), Some("/home".to_string()), vec![]),
+            ("notes".to_string(), Some("/home/bob".to_string()), vec!["*.tmp".to_string()]),
+            ("-odd".to_string(), Some("/home/bob".to_string()), vec!["*.tmp".to_string()]),
+            ("b c".to_string(), Some("/home/bob/docs".to_string()), vec!["*.tmp".to_string()]),
+        ]);
+
+        assert_eq!(listed[1].source_path(), path::Path::new("/home/alice"));
+        assert_eq!(listed[0].source_path(), path::Path::new("a"));
+
+        assert!(parse_file_list("--null\n".as_bytes()).is_err());
+        assert!(parse_file_list("-é\n".as_bytes()).is_err());
+        assert!(parse_file_list("-C\n".as_bytes()).is_err());
+        assert!(parse_file_list("--exclude pattern\nx\n".as_bytes()).unwrap()[0].excludes == vec!["pattern".to_string()]);
+    }
+}
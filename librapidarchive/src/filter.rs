@@ -0,0 +1,504 @@
+//! Transformations applied to archive data on it's way to the sink.
+//!
+//! A `WriteFilter` sees every byte the archive serializer produces and decides
+//! what actually gets passed on to the next stage: compressors shrink it,
+//! encryptors scramble it, throttles delay it, and hashers or tees merely
+//! observe it. Filters are composed into a `FilterChain`, which wraps an
+//! opened sink such that data passes through each filter in the order they
+//! were added.
+
+use std::io;
+use std::collections::VecDeque;
+use crate::{spanning, tape};
+use crate::fs::ArchivalSink;
+
+/// How many data zones a `FilteredWriter` remembers the lengths of before it
+/// checks which of them have been committed and forgets those.
+const ZONE_HISTORY: usize = 4096;
+
+/// A transformation on the data written to an archive sink.
+///
+/// Filters don't need to know anything about data zones or downcasts; the
+/// `FilteredWriter` they are placed in takes care of forwarding those onto the
+/// next stage. Filters which change the length of their data need only say so
+/// and be able to finish their output at a zone boundary.
+pub trait WriteFilter: Send {
+    /// Filter some data and write the result to the next stage.
+    ///
+    /// Like `io::Write::write`, this returns how much of `buf` was consumed,
+    /// which need not be the amount of data written to `next`.
+    fn write_filtered(&mut self, buf: &[u8], next: &mut dyn io::Write) -> io::Result<usize>;
+
+    /// Push any data held within the filter onto the next stage, then flush
+    /// it.
+    fn flush_filtered(&mut self, next: &mut dyn io::Write) -> io::Result<()> {
+        next.flush()
+    }
+
+    /// Push out everything the filter holds such that what's been written to
+    /// the next stage can be decoded without anything that follows it, as a
+    /// compressor does by finishing it's current member. Unlike
+    /// `flush_filtered`, the next stage isn't flushed.
+    fn finish_filtered(&mut self, _next: &mut dyn io::Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Indicates if every byte written to the filter reaches the next stage
+    /// unchanged and at the same offset.
+    ///
+    /// Seeking and tape commands are only allowed through filters which
+    /// preserve offsets.
+    fn preserves_offsets(&self) -> bool {
+        false
+    }
+
+    /// Indicates if the filter changes how much data reaches the next stage,
+    /// as compressors do.
+    ///
+    /// Data zones are normally measured after filtering. Behind a filter which
+    /// changes the length of it's data, they are measured before filtering
+    /// instead, and the filter is finished at the end of every zone.
+    fn changes_length(&self) -> bool {
+        false
+    }
+}
+
+impl<F: WriteFilter + ?Sized> WriteFilter for Box<F> {
+    fn write_filtered(&mut self, buf: &[u8], next: &mut dyn io::Write) -> io::Result<usize> {
+        (**self).write_filtered(buf, next)
+    }
+
+    fn flush_filtered(&mut self, next: &mut dyn io::Write) -> io::Result<()> {
+        (**self).flush_filtered(next)
+    }
+
+    fn finish_filtered(&mut self, next: &mut dyn io::Write) -> io::Result<()> {
+        (**self).finish_filtered(next)
+    }
+
+    fn preserves_offsets(&self) -> bool {
+        (**self).preserves_offsets()
+    }
+
+    fn changes_length(&self) -> bool {
+        (**self).changes_length()
+    }
+}
+
+/// The unfiltered length of a data zone written through a filter which
+/// changes the length of it's data.
+struct ZoneLength {
+    /// How much of the zone was committed before it was resumed.
+    resumed_from: u64,
+
+    /// How much was written into the zone.
+    written: u64,
+
+    /// Indicates the filter was finished at the end of the zone, so that all
+    /// of it's data reached the next stage.
+    finished: bool,
+}
+
+/// A writer which passes everything written to it through a `WriteFilter`.
+///
+/// Data zones are forwarded onto the next stage as they are. If the filter
+/// changes the length of it's data, they're still begun and ended on the next
+/// stage, but only to learn which of them have been committed; their lengths
+/// are reported as they were before filtering. A zone behind such a filter is
+/// either committed in full or not at all.
+pub struct FilteredWriter<F: WriteFilter, W: io::Write> {
+    filter: F,
+    inner: W,
+
+    /// The lengths of the most recent zones, if the filter changes them.
+    zones: VecDeque<ZoneLength>,
+    in_zone: bool,
+
+    /// An error finishing the filter at the end of a zone, which couldn't be
+    /// returned at the time.
+    deferred_error: Option<io::Error>,
+}
+
+impl<F: WriteFilter, W: io::Write> FilteredWriter<F, W> {
+    pub fn wrap(filter: F, inner: W) -> FilteredWriter<F, W> {
+        FilteredWriter {
+            filter: filter,
+            inner: inner,
+            zones: VecDeque::new(),
+            in_zone: false,
+            deferred_error: None
+        }
+    }
+
+    pub fn as_inner_writer(&self) -> &W {
+        &self.inner
+    }
+
+    /// Finish the filter at the end of a zone, or before the first one.
+    fn finish_zone(&mut self) {
+        let finished = match self.filter.finish_filtered(&mut self.inner) {
+            Ok(()) => true,
+            Err(e) => {
+                self.deferred_error.get_or_insert(e);
+                false
+            }
+        };
+
+        if self.in_zone {
+            if let Some(zone) = self.zones.back_mut() {
+                zone.finished = finished;
+            }
+        }
+
+        self.in_zone = false;
+    }
+}
+
+impl<F: WriteFilter, W: io::Write> io::Write for FilteredWriter<F, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if let Some(e) = self.deferred_error.take() {
+            return Err(e);
+        }
+
+        let written = self.filter.write_filtered(buf, &mut self.inner)?;
+
+        if self.in_zone {
+            if let Some(zone) = self.zones.back_mut() {
+                zone.written += written as u64;
+            }
+        }
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if let Some(e) = self.deferred_error.take() {
+            return Err(e);
+        }
+
+        self.filter.flush_filtered(&mut self.inner)
+    }
+}
+
+impl<F: WriteFilter, W: io::Write> FilteredWriter<F, W> {
+    /// Start measuring a new zone, which begins `resumed_from` bytes in.
+    fn start_zone<I>(&mut self, resumed_from: u64) where W: spanning::RecoverableWrite<I> {
+        self.finish_zone();
+
+        //Zones are committed in order, so only as many as the next stage
+        //still has outstanding need to be remembered.
+        if self.zones.len() >= ZONE_HISTORY {
+            let outstanding = self.inner.uncommitted_writes().iter().filter(|zone| zone.ident.is_some()).count();
+
+            self.zones.drain(..self.zones.len().saturating_sub(outstanding));
+        }
+
+        self.zones.push_back(ZoneLength {
+            resumed_from: resumed_from,
+            written: 0,
+            finished: false
+        });
+        self.in_zone = true;
+    }
+}
+
+impl<F, W, I> spanning::RecoverableWrite<I> for FilteredWriter<F, W> where F: WriteFilter, W: spanning::RecoverableWrite<I> {
+    fn begin_data_zone(&mut self, ident: I) {
+        if self.filter.changes_length() {
+            self.start_zone::<I>(0);
+        }
+
+        self.inner.begin_data_zone(ident);
+    }
+
+    fn resume_data_zone(&mut self, ident: I, committed: u64) {
+        if self.filter.changes_length() {
+            self.start_zone::<I>(committed);
+            self.inner.begin_data_zone(ident);
+        } else {
+            self.inner.resume_data_zone(ident, committed);
+        }
+    }
+
+    fn end_data_zone(&mut self) {
+        if self.filter.changes_length() {
+            self.finish_zone();
+        }
+
+        self.inner.end_data_zone();
+    }
+
+    fn uncommitted_writes(&self) -> Vec<spanning::DataZone<I>> {
+        let mut zones = self.inner.uncommitted_writes();
+
+        if !self.filter.changes_length() {
+            return zones;
+        }
+
+        //The next stage's zones are the most recent ones we began, in the
+        //same order.
+        let mut lengths = self.zones.iter().rev();
+
+        for zone in zones.iter_mut().rev().filter(|zone| zone.ident.is_some()) {
+            let length = match lengths.next() {
+                Some(length) => length,
+                None => break
+            };
+            let committed = match length.finished && zone.uncommitted_length == 0 {
+                true => length.written,
+                false => 0
+            };
+
+            zone.length = length.resumed_from + length.written;
+            zone.committed_length = length.resumed_from + committed;
+            zone.uncommitted_length = length.written - committed;
+        }
+
+        zones
+    }
+}
+
+impl<F, W, I> ArchivalSink<I> for FilteredWriter<F, W> where F: WriteFilter, W: ArchivalSink<I> {
+    fn downcast_seek(&mut self) -> Option<&mut dyn io::Seek> {
+        if self.filter.preserves_offsets() {
+            self.inner.downcast_seek()
+        } else {
+            None
+        }
+    }
+
+    fn downcast_tapedevice(&mut self) -> Option<&mut dyn tape::TapeDevice> {
+        if self.filter.preserves_offsets() {
+            self.inner.downcast_tapedevice()
+        } else {
+            None
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn committed_bytes(&self) -> Option<u64> {
+        self.inner.committed_bytes()
+    }
+}
+
+/// An ordered list of filters to apply to an archive sink.
+///
+/// The first filter added is the first to see data written to the sink; the
+/// last filter added writes directly to the sink itself.
+pub struct FilterChain {
+    filters: Vec<Box<dyn WriteFilter>>
+}
+
+impl FilterChain {
+    pub fn new() -> FilterChain {
+        FilterChain {
+            filters: Vec::new()
+        }
+    }
+
+    /// Add a filter onto the end of the chain.
+    pub fn push<F: 'static + WriteFilter>(&mut self, filter: F) {
+        self.filters.push(Box::new(filter));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Wrap a sink such that all data written to it passes through every
+    /// filter in the chain, in order.
+    pub fn apply<I>(self, sink: Box<dyn ArchivalSink<I>>) -> Box<dyn ArchivalSink<I>> where I: 'static {
+        let mut sink = sink;
+
+        for filter in self.filters.into_iter().rev() {
+            sink = Box::new(FilteredWriter::wrap(filter, sink));
+        }
+
+        sink
+    }
+}
+
+impl Default for FilterChain {
+    fn default() -> Self {
+        FilterChain::new()
+    }
+}
+
+/// A filter which copies everything written through it to another writer.
+///
+/// The copy is made before data is passed onto the next stage, so the second
+/// writer may see data that the sink later rejects.
+pub struct TeeFilter<T: io::Write + Send> {
+    tee: T
+}
+
+impl<T: io::Write + Send> TeeFilter<T> {
+    pub fn new(tee: T) -> TeeFilter<T> {
+        TeeFilter {
+            tee: tee
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.tee
+    }
+}
+
+impl<T: io::Write + Send> WriteFilter for TeeFilter<T> {
+    fn write_filtered(&mut self, buf: &[u8], next: &mut dyn io::Write) -> io::Result<usize> {
+        let written = next.write(buf)?;
+
+        self.tee.write_all(&buf[..written])?;
+
+        Ok(written)
+    }
+
+    fn flush_filtered(&mut self, next: &mut dyn io::Write) -> io::Result<()> {
+        self.tee.flush()?;
+        next.flush()
+    }
+
+    fn preserves_offsets(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::Write;
+    use std::sync::{Arc, Mutex};
+    use crate::fs::ArchivalSink;
+    use super::{WriteFilter, FilterChain, TeeFilter};
+
+    /// Filter which adds a constant to every byte.
+    struct AddFilter(u8);
+
+    impl WriteFilter for AddFilter {
+        fn write_filtered(&mut self, buf: &[u8], next: &mut dyn io::Write) -> io::Result<usize> {
+            let shifted : Vec<u8> = buf.iter().map(|b| b.wrapping_add(self.0)).collect();
+
+            next.write_all(&shifted)?;
+
+            Ok(buf.len())
+        }
+    }
+
+    /// Shared writer, so that the test can inspect what was teed.
+    #[derive(Clone)]
+    struct SharedVec(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedVec {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn filter_chain_order() {
+        let teed = SharedVec(Arc::new(Mutex::new(vec![])));
+        let mut chain = FilterChain::new();
+
+        chain.push(AddFilter(1));
+        chain.push(TeeFilter::new(teed.clone()));
+        chain.push(AddFilter(2));
+
+        let mut sink : Box<dyn ArchivalSink<u64>> = chain.apply(Box::new(io::Cursor::new(vec![])));
+
+        sink.write_all(&[0, 1, 2]).unwrap();
+        sink.flush().unwrap();
+
+        assert_eq!(*teed.0.lock().unwrap(), vec![1, 2, 3]);
+        assert!(!sink.capabilities().seekable);
+    }
+
+    /// Device which fails writes once it's full, like a tape at end of media.
+    #[cfg(feature = "compression")]
+    struct FullDevice(Vec<u8>, usize);
+
+    #[cfg(feature = "compression")]
+    impl io::Write for FullDevice {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.0.len() + buf.len() > self.1 {
+                return Err(io::Error::new(io::ErrorKind::WriteZero, "volume is full"));
+            }
+
+            self.0.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "compression")]
+    impl<P> crate::spanning::RecoverableWrite<P> for FullDevice {
+    }
+
+    #[cfg(feature = "compression")]
+    #[test]
+    fn filter_zones_before_compression() {
+        use std::io::Read;
+        use crate::blocking::BlockingWriter;
+        use crate::compress::GzipFilter;
+        use crate::decompress::GzipDecoder;
+        use crate::spanning::RecoverableWrite;
+        use super::FilteredWriter;
+
+        //Incompressible, so the second zone won't fit on the volume.
+        let mut state = 1u32;
+        let data : Vec<u8> = (0..200_000).map(|_| {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            (state >> 24) as u8
+        }).collect();
+        let volume = BlockingWriter::<_, u64>::new_with_record_size(FullDevice(vec![], 8192), 512);
+        let mut sink = FilteredWriter::wrap(GzipFilter::default(), volume);
+
+        sink.begin_data_zone(1);
+        sink.write_all(&data[..3000]).unwrap();
+        sink.begin_data_zone(2);
+
+        let written = data[3000..].chunks(1000).take_while(|piece| sink.write_all(piece).is_ok()).count() * 1000;
+        assert!(written < 197_000);
+
+        //Zones are measured before compression, and the torn one is lost in
+        //full.
+        let zones = sink.uncommitted_writes();
+        let torn = zones.iter().find(|zone| zone.ident == Some(2)).unwrap();
+        assert_eq!((torn.length, torn.committed_length, torn.uncommitted_length), (written as u64, 0, written as u64));
+
+        if let Some(first) = zones.iter().find(|zone| zone.ident == Some(1)) {
+            assert_eq!((first.length, first.committed_length, first.uncommitted_length), (3000, 3000, 0));
+        }
+
+        //The first zone ended its member, so it can be read back from the
+        //torn volume.
+        let mut recovered = vec![];
+        let volume = &sink.as_inner_writer().as_inner_writer().0;
+        let _ = GzipDecoder::new(&volume[..]).read_to_end(&mut recovered);
+        assert!(recovered.len() >= 3000 && recovered[..] == data[..recovered.len()]);
+    }
+
+    #[test]
+    fn filter_chain_transparent() {
+        let teed = SharedVec(Arc::new(Mutex::new(vec![])));
+        let mut chain = FilterChain::new();
+
+        chain.push(TeeFilter::new(teed.clone()));
+
+        let mut sink : Box<dyn ArchivalSink<u64>> = chain.apply(Box::new(io::Cursor::new(vec![])));
+
+        sink.write_all(&[5, 6, 7]).unwrap();
+
+        assert_eq!(*teed.0.lock().unwrap(), vec![5, 6, 7]);
+        assert!(sink.capabilities().seekable);
+    }
+}
@@ -2,17 +2,168 @@
 //! special. Fallback intended for use when a platform does not provide
 //! enhanced functionality.
 
-use std::{io, fs, path, ffi};
+use std::{io, fs, path, ffi, env, time};
+use std::str::FromStr;
+use std::io::Seek;
 use std::cmp::PartialEq;
-use crate::{tar, tape, spanning};
-use crate::tuning::Configuration;
+use std::sync::{Arc, Mutex};
+use crate::{tar, tape, spanning, handles};
+use crate::handles::HandleGuard;
+use crate::tuning::{Configuration, FsyncPolicy};
+use crate::concurrentbuf::ConcurrentWriteBuffer;
+use crate::cancel::CancellationToken;
+use crate::blocking::BlockingWriter;
+use crate::retry::RetryingWriter;
+
+/// Describes the optional capabilities of a particular `ArchivalSink`.
+///
+/// Operations which can work in more than one way (say, appending to an
+/// archive by seeking backwards over the terminator on a file vs. spacing
+/// over filemarks on a tape) should consult this to pick a strategy.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct SinkCapabilities {
+    /// The sink can be repositioned with `io::Seek` via `downcast_seek`.
+    pub seekable: bool,
+
+    /// The sink is backed by a tape device reachable via
+    /// `downcast_tapedevice`.
+    pub tape: bool,
+}
+
+/// How to treat the access times of files read for archival.
+///
+/// Backups which bump the access time of every file they read make access
+/// times useless to anything else that relies on them (e.g. mail readers,
+/// tiered storage, or cleanup jobs).
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AtimePolicy {
+    /// Read files normally, letting the OS update access times as it sees fit.
+    Ignore,
+
+    /// Restore each file's original access time after reading it.
+    ///
+    /// This requires permission to change the file's timestamps, and changes
+    /// it's ctime in the process.
+    Replace,
+
+    /// Ask the OS not to update access times in the first place.
+    ///
+    /// Files for which the OS refuses this request are read normally.
+    System
+}
+
+impl Default for AtimePolicy {
+    fn default() -> Self {
+        AtimePolicy::Ignore
+    }
+}
+
+impl FromStr for AtimePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(AtimePolicy::Ignore),
+            "replace" => Ok(AtimePolicy::Replace),
+            "system" => Ok(AtimePolicy::System),
+            _ => Err(())
+        }
+    }
+}
+
+/// A file opened for reading it's contents into an archive.
+///
+/// Reads honor an `AtimePolicy`. If the policy is `Replace`, the file's access
+/// time is restored when this is dropped.
+///
+/// Opening a file takes a slot in the process-wide `handles::global` budget,
+/// waiting for one if necessary, which is held until the file is dropped.
+pub struct ArchivalFile {
+    file: fs::File,
+    restore_atime: Option<time::SystemTime>,
+    _handle: HandleGuard<'static>
+}
+
+impl ArchivalFile {
+    /// Open a file for archival.
+    ///
+    /// `atime` is the access time the file had before archival started, which
+    /// is what will be restored under the `Replace` policy.
+    pub fn open<P: AsRef<path::Path>>(path: P, atime: Option<time::SystemTime>, policy: AtimePolicy) -> io::Result<ArchivalFile> {
+        let handle = handles::global().acquire();
+
+        Ok(ArchivalFile {
+            file: crate::fs::open_source_file(path.as_ref(), policy)?,
+            restore_atime: match policy {
+                AtimePolicy::Replace => atime,
+                _ => None
+            },
+            _handle: handle
+        })
+    }
+
+    /// Open a file for archival without following a symbolic link in it's
+    /// place, and read it's metadata from the open file.
+    ///
+    /// The metadata describes the very file whose contents will be read, even
+    /// if it's path is replaced in the meantime. Under the `Replace` policy,
+    /// the access time it reports is the one restored.
+    pub fn open_nofollow<P: AsRef<path::Path>>(path: P, policy: AtimePolicy) -> io::Result<(ArchivalFile, fs::Metadata)> {
+        let handle = handles::global().acquire();
+        let file = crate::fs::open_source_file_nofollow(path.as_ref(), policy)?;
+        let metadata = file.metadata()?;
+
+        Ok((ArchivalFile {
+            restore_atime: match policy {
+                AtimePolicy::Replace => metadata.accessed().ok(),
+                _ => None
+            },
+            file: file,
+            _handle: handle
+        }, metadata))
+    }
+
+    /// The underlying file, for reading more of it's metadata.
+    pub fn file(&self) -> &fs::File {
+        &self.file
+    }
+}
+
+impl io::Read for ArchivalFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.file.read(buf)
+    }
+}
+
+impl io::Seek for ArchivalFile {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.file.seek(pos)
+    }
+}
+
+impl Drop for ArchivalFile {
+    fn drop(&mut self) {
+        if let Some(atime) = self.restore_atime {
+            if let Err(e) = crate::fs::set_file_atime(&self.file, atime) {
+                debug!("Could not restore access time: {}", e);
+            }
+        }
+    }
+}
 
 /// Supertrait that represents all the things a good archive sink needs to be.
-/// 
+///
 /// TODO: The **moment** Rust gets the ability to handle multiple traits in a
 /// single trait object, delete this arbitrary supertrait immediately.
-/// 
+///
 /// TODO: wait no now this supertrait does downcasts because Box won't
+///
+/// # Downcasts and buffering
+///
+/// Sinks which buffer writes are allowed to hand out downcasts to their inner
+/// device. Callers must `flush` the sink before seeking or issuing tape
+/// commands through a downcast, otherwise buffered data will land wherever the
+/// device happens to be positioned when the buffer drains.
 pub trait ArchivalSink<I>: Send + io::Write + spanning::RecoverableWrite<I> {
     fn downcast_seek(&mut self) -> Option<&mut dyn io::Seek> {
         None
@@ -21,12 +172,202 @@ pub trait ArchivalSink<I>: Send + io::Write + spanning::RecoverableWrite<I> {
     fn downcast_tapedevice(&mut self) -> Option<&mut dyn tape::TapeDevice> {
         None
     }
+
+    /// Query what this sink is capable of.
+    ///
+    /// The default implementation derives capabilities from the downcasts
+    /// available on this sink, so implementors generally only need to provide
+    /// the downcasts themselves.
+    fn capabilities(&mut self) -> SinkCapabilities {
+        SinkCapabilities {
+            seekable: self.downcast_seek().is_some(),
+            tape: self.downcast_tapedevice().is_some(),
+        }
+    }
+
+    /// Make everything this sink has passed on to it's device durable.
+    ///
+    /// Data held back by a sink which only writes whole records, such as a
+    /// `BlockingWriter`, has not been passed on yet; callers wanting it synced
+    /// too should `flush` first. The default implementation does nothing,
+    /// which suits devices that don't cache writes, such as tapes.
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Count how many bytes this sink has committed to it's device.
+    ///
+    /// Only sinks which know what their device has accepted, such as a
+    /// `BlockingWriter`, keep count; the default implementation yields `None`.
+    /// Data still held back by this sink or any buffer behind it is not
+    /// counted.
+    fn committed_bytes(&self) -> Option<u64> {
+        None
+    }
 }
 
 impl<I> ArchivalSink<I> for fs::File {
     fn downcast_seek(&mut self) -> Option<&mut dyn io::Seek> {
         Some(self)
     }
+
+    /// Files are synced with `fsync` or `FlushFileBuffers`. Files which can't
+    /// be synced, such as pipes or `/dev/null`, are left alone.
+    fn sync(&mut self) -> io::Result<()> {
+        match self.sync_all() {
+            Err(ref e) if e.kind() == io::ErrorKind::InvalidInput => Ok(()),
+            result => result
+        }
+    }
+}
+
+impl<I> ArchivalSink<I> for io::Cursor<Vec<u8>> {
+    fn downcast_seek(&mut self) -> Option<&mut dyn io::Seek> {
+        Some(self)
+    }
+}
+
+/// Boxed sinks are sinks too, which allows them to be wrapped further.
+impl<I> ArchivalSink<I> for Box<dyn ArchivalSink<I>> {
+    fn downcast_seek(&mut self) -> Option<&mut dyn io::Seek> {
+        (**self).downcast_seek()
+    }
+
+    fn downcast_tapedevice(&mut self) -> Option<&mut dyn tape::TapeDevice> {
+        (**self).downcast_tapedevice()
+    }
+
+    fn capabilities(&mut self) -> SinkCapabilities {
+        (**self).capabilities()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        (**self).sync()
+    }
+
+    fn committed_bytes(&self) -> Option<u64> {
+        (**self).committed_bytes()
+    }
+}
+
+/// Supertrait for anything an archive can be read back out of.
+///
+/// This is the read-side counterpart of `ArchivalSink`. Sources are plain
+/// byte streams, but tape devices also hand out a downcast so that readers
+/// can work in whole tape blocks via `TapeDevice::read_block`.
+pub trait ArchivalSource: Send + io::Read {
+    fn downcast_tapedevice(&mut self) -> Option<&mut dyn tape::TapeDevice> {
+        None
+    }
+}
+
+impl ArchivalSource for fs::File {
+}
+
+impl ArchivalSource for io::Stdin {
+}
+
+impl ArchivalSource for io::Cursor<Vec<u8>> {
+}
+
+impl ArchivalSource for Box<dyn ArchivalSource> {
+    fn downcast_tapedevice(&mut self) -> Option<&mut dyn tape::TapeDevice> {
+        (**self).downcast_tapedevice()
+    }
+}
+
+/// Wrap a device in a `ConcurrentWriteBuffer` configured according to the
+/// given tuning parameters, which stops writing once the given token is
+/// cancelled.
+///
+/// This is the buffering stage of the stack built by `assemble_sink`.
+pub(crate) fn buffer_sink<W, I>(inner: W, tuning: &Configuration, cancel: &CancellationToken) -> io::Result<ConcurrentWriteBuffer<W, I>> where W: 'static + Send + io::Write + spanning::RecoverableWrite<I>, I: 'static + Send + Clone + PartialEq {
+    let mut buffered = ConcurrentWriteBuffer::new_cancellable(inner, tuning.serial_buffer_limit, cancel.clone());
+
+    if tuning.spill_to_disk {
+        buffered.spill_to(&env::temp_dir())?;
+    }
+
+    Ok(buffered)
+}
+
+/// Build the standard stack of writers on top of a device that isn't
+/// record-oriented, such as a file, without boxing any of it.
+///
+/// Data written to the returned sink passes through a `ConcurrentWriteBuffer`
+/// (see `buffer_sink`) and a `RetryingWriter` before reaching the device. If
+/// the tuning parameters call for syncing every so many bytes, the buffer
+/// does so as it writes.
+/// This is what `open_sink` builds for files, less any volume size limit,
+/// which can be added by wrapping the result in a `LimitingWriter`.
+///
+/// Library users who know what they're writing to at compile time can use
+/// this, or `build_tape_pipeline`, to avoid dynamic dispatch on every write,
+/// and to add layers of their own on either side of the stack.
+pub fn build_pipeline<D, I>(device: D, tuning: &Configuration, cancel: &CancellationToken) -> io::Result<impl ArchivalSink<I>> where D: 'static + ArchivalSink<I>, I: 'static + Send + Clone + PartialEq {
+    let mut buffered = buffer_sink(RetryingWriter::wrap(device, tuning.write_retry), tuning, cancel)?;
+
+    if let FsyncPolicy::PerBytes(interval) = tuning.fsync {
+        buffered.sync_every(interval);
+    }
+
+    Ok(buffered)
+}
+
+/// Build the standard stack of writers on top of a tape device, without
+/// boxing any of it.
+///
+/// This is `build_pipeline`, with a `BlockingWriter` on top so that the tape
+/// is written in whole records (see `tape_record_size`).
+pub fn build_tape_pipeline<T, I>(tape: T, tuning: &Configuration, cancel: &CancellationToken) -> io::Result<impl ArchivalSink<I>> where T: 'static + tape::TapeDevice + ArchivalSink<I>, I: 'static + Send + Clone + PartialEq {
+    let record_size = tape_record_size(&tape, tuning);
+
+    Ok(BlockingWriter::new_with_record_size(build_pipeline(tape, tuning, cancel)?, record_size))
+}
+
+/// The size of the records a tape device should be written in.
+///
+/// Drives in fixed block mode get records of exactly their block size. Any
+/// other drive gets records of the tuning parameters' blocking factor.
+pub fn tape_record_size<T: tape::TapeDevice + ?Sized>(tape: &T, tuning: &Configuration) -> usize {
+    match tape.fixed_block_size() {
+        Some(block_size) => block_size as usize,
+        None => tuning.blocking_factor * 512
+    }
+}
+
+/// Assemble the standard stack of writers on top of an opened device.
+///
+/// Data written to the returned sink passes through, in order:
+///
+///  1. A `LimitingWriter`, if a volume size limit was given
+///  2. A `BlockingWriter`, if the device is record-oriented (in which case
+///     `record_size` gives it's record size) or blocking is forced by the
+///     tuning parameters
+///  3. A `ConcurrentWriteBuffer` (see `buffer_sink`)
+///  4. A `RetryingWriter`
+///
+/// before reaching the device itself. This is the stack `build_pipeline`
+/// builds, boxed so that the layers can be chosen at runtime. Callers that want to transform archive
+/// data should apply a `filter::FilterChain` to the result, so that their
+/// filters see data before it is limited or blocked.
+pub(crate) fn assemble_sink<D, I>(device: D, record_size: Option<usize>, tuning: &Configuration, limit: Option<u64>, cancel: &CancellationToken) -> io::Result<Box<ArchivalSink<I>>> where D: 'static + ArchivalSink<I>, I: 'static + Send + Clone + PartialEq {
+    let buffered = build_pipeline(device, tuning, cancel)?;
+    let record_size = match (record_size, tuning.force_blocking) {
+        (Some(record_size), _) => Some(record_size),
+        (None, true) => Some(tuning.blocking_factor * 512),
+        (None, false) => None
+    };
+    let mut sink : Box<ArchivalSink<I>> = match record_size {
+        Some(record_size) => Box::new(BlockingWriter::new_with_record_size(buffered, record_size)),
+        None => Box::new(buffered)
+    };
+
+    if let Some(limit) = limit {
+        sink = Box::new(spanning::LimitingWriter::wrap(sink, limit));
+    }
+
+    Ok(sink)
 }
 
 /// Open a sink object for writing an archive (aka "tape").
@@ -82,7 +423,7 @@ impl<I> ArchivalSink<I> for fs::File {
 /// only. Platform-specific sink functions may support opening other kinds of
 /// writers.
 #[allow(unused_variables)]
-pub fn open_sink<P: AsRef<path::Path>, I>(outfile: P, tuning: &Configuration, limit: Option<u64>) -> io::Result<Box<ArchivalSink<I>>> where ffi::OsString: From<P>, P: Clone, I: 'static + Send + Clone + PartialEq {
+pub fn open_sink<P: AsRef<path::Path>, I>(outfile: P, tuning: &Configuration, limit: Option<u64>, cancel: &CancellationToken) -> io::Result<Box<ArchivalSink<I>>> where ffi::OsString: From<P>, P: Clone, I: 'static + Send + Clone + PartialEq {
     let file = fs::File::create(outfile.as_ref())?;
 
     if let Some(limit) = limit {
@@ -92,6 +433,81 @@ pub fn open_sink<P: AsRef<path::Path>, I>(outfile: P, tuning: &Configuration, li
     }
 }
 
+/// Open a sink object for writing over the end of an existing archive file.
+///
+/// The file is cut short at `offset`, usually where it's end-of-archive
+/// marker begins, and writing continues from there. Files that don't exist yet
+/// are created. Otherwise this works like `open_sink`.
+///
+/// # Platform considerations
+///
+/// This function supports files only, on every platform. Tapes can't be cut
+/// short; open them with `open_sink` and space them to where writing should
+/// continue instead.
+pub fn open_sink_at<P: AsRef<path::Path>, I>(outfile: P, offset: u64, tuning: &Configuration, limit: Option<u64>, cancel: &CancellationToken) -> io::Result<Box<ArchivalSink<I>>> where I: 'static + Send + Clone + PartialEq {
+    let mut file = fs::OpenOptions::new().write(true).create(true).open(outfile.as_ref())?;
+
+    file.set_len(offset)?;
+    file.seek(io::SeekFrom::Start(offset))?;
+
+    assemble_sink(file, None, tuning, limit, cancel)
+}
+
+/// Determine if an archive name refers to standard input or output.
+///
+/// Like most tar implementations, we treat an archive named `-` as the
+/// process's standard streams rather than a file of that name.
+pub fn is_standard_stream<P: AsRef<path::Path>>(name: P) -> bool {
+    name.as_ref() == path::Path::new("-")
+}
+
+/// Open a source object for reading an archive back.
+///
+/// # Parameters
+///
+/// This function accepts the name of an input device, interpreted in the same
+/// namespace as `open_sink`. The name `-` opens standard input.
+///
+/// # Returns
+///
+/// If the path given in infile names a valid object that can be read from, it
+/// will be opened and returned. Otherwise yields an error.
+///
+/// Sources opened on tape devices read with the same block semantics the
+/// device was written with: a read never spans a filemark, and a read that
+/// hits one returns short. Callers that care about the block structure of the
+/// tape can use `downcast_tapedevice` to read whole blocks at a time.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. It supports reads from files
+/// and standard input only.
+pub fn open_source<P: AsRef<path::Path>>(infile: P) -> io::Result<Box<ArchivalSource>> where ffi::OsString: From<P>, P: Clone {
+    if is_standard_stream(infile.as_ref()) {
+        return Ok(Box::new(io::stdin()));
+    }
+
+    Ok(Box::new(fs::File::open(infile.as_ref())?))
+}
+
+/// Position a source at the start of a particular archive within it.
+///
+/// Tapes may hold several archives, one per tape file, separated by
+/// filemarks. `file_number` counts tape files from the beginning of the
+/// current partition, starting at zero, matching the file numbers reported by
+/// `mt status`. The tape is rewound before spacing forward, so this works no
+/// matter where the tape was left.
+///
+/// Files, pipes, and other non-tape sources only ever hold one archive, so
+/// asking for any file other than the first is an error.
+pub fn seek_archive_file(source: &mut ArchivalSource, file_number: u64) -> io::Result<()> {
+    match source.downcast_tapedevice() {
+        Some(tape) => tape.seek_filemarks(io::SeekFrom::Start(file_number)),
+        None if file_number == 0 => Ok(()),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, "Only tape devices can hold more than one archive"))
+    }
+}
+
 /// Open an object for total control of a tape device.
 ///
 /// # Parameters
@@ -104,6 +520,10 @@ pub fn open_sink<P: AsRef<path::Path>, I>(outfile: P, tuning: &Configuration, li
 /// (e.g. `open_sink("/dev/nst0")` must match `open_tape("/dev/nst0")`, but
 /// `open_tape("/dev/sda0")` is allowed to error.)
 ///
+/// The device is opened as the given `tape::TapeOptions` ask, which let
+/// callers share the drive with monitoring tools, retry opens differently, or
+/// choose a block mode for unusual drives.
+///
 /// # Returns
 ///
 /// If the path given in outfile names a valid tape device, a boxed
@@ -117,16 +537,189 @@ pub fn open_sink<P: AsRef<path::Path>, I>(outfile: P, tuning: &Configuration, li
 ///
 /// This is the portable version of the function. Since portable tape access
 /// isn't a thing that makes sense, this function only returns errors.
-pub fn open_tape<P: AsRef<path::Path>>(_tapedev: P) -> io::Result<Box<tape::TapeDevice>> where ffi::OsString: From<P>, P: Clone {
+pub fn open_tape<P: AsRef<path::Path>>(_tapedev: P, _options: &tape::TapeOptions) -> io::Result<Box<tape::TapeDevice>> where ffi::OsString: From<P>, P: Clone {
     Err(io::Error::new(io::ErrorKind::Other, "Magnetic tape control is not implemented for this operating system."))
 }
 
+/// A drive held open across several archives.
+///
+/// Every clone of a `SharedDrive` writes to the same open device, which is
+/// only closed once the last clone is dropped. Each archive written to the
+/// drive gets a sink of it's own from `open_drive_sink`, so archives can use
+/// different tuning, limits, and filters while the drive stays open (and
+/// positioned) between them.
+pub struct SharedDrive<I> {
+    device: Arc<Mutex<Box<dyn ArchivalSink<I>>>>
+}
+
+impl<I> Clone for SharedDrive<I> {
+    fn clone(&self) -> Self {
+        SharedDrive {
+            device: self.device.clone()
+        }
+    }
+}
+
+impl<I> SharedDrive<I> {
+    /// Share an opened device.
+    pub fn new(device: Box<dyn ArchivalSink<I>>) -> SharedDrive<I> {
+        SharedDrive {
+            device: Arc::new(Mutex::new(device))
+        }
+    }
+
+    fn with_tape<R, F>(&self, cmd: F) -> io::Result<R> where F: FnOnce(&mut dyn tape::TapeDevice) -> io::Result<R> {
+        let mut device = self.device.lock().unwrap();
+
+        match device.downcast_tapedevice() {
+            Some(tape) => cmd(tape),
+            None => Err(io::Error::new(io::ErrorKind::Other, "Shared drive is not a tape device"))
+        }
+    }
+}
+
+impl<I> io::Write for SharedDrive<I> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.device.lock().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.device.lock().unwrap().flush()
+    }
+}
+
+impl<I> io::Read for SharedDrive<I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.with_tape(|tape| tape.read(buf))
+    }
+}
+
+impl<I> spanning::RecoverableWrite<I> for SharedDrive<I> {
+    fn begin_data_zone(&mut self, ident: I) {
+        self.device.lock().unwrap().begin_data_zone(ident)
+    }
+
+    fn resume_data_zone(&mut self, ident: I, committed: u64) {
+        self.device.lock().unwrap().resume_data_zone(ident, committed)
+    }
+
+    fn end_data_zone(&mut self) {
+        self.device.lock().unwrap().end_data_zone()
+    }
+
+    fn uncommitted_writes(&self) -> Vec<spanning::DataZone<I>> {
+        self.device.lock().unwrap().uncommitted_writes()
+    }
+}
+
+impl<I> tape::TapeDevice for SharedDrive<I> {
+    fn read_block(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.with_tape(|tape| tape.read_block(buf))
+    }
+
+    fn write_filemark(&mut self, blocking: bool) -> io::Result<()> {
+        self.with_tape(|tape| tape.write_filemark(blocking))
+    }
+
+    fn write_setmark(&mut self, blocking: bool) -> io::Result<()> {
+        self.with_tape(|tape| tape.write_setmark(blocking))
+    }
+
+    fn set_filemark_policy(&mut self, policy: tape::FilemarkPolicy) -> io::Result<()> {
+        self.with_tape(|tape| tape.set_filemark_policy(policy))
+    }
+
+    fn set_compression(&mut self, enabled: bool) -> io::Result<()> {
+        self.with_tape(|tape| tape.set_compression(enabled))
+    }
+
+    fn fixed_block_size(&self) -> Option<u32> {
+        self.with_tape(|tape| Ok(tape.fixed_block_size())).unwrap_or(None)
+    }
+
+    fn seek_blocks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        self.with_tape(|tape| tape.seek_blocks(pos))
+    }
+
+    fn tell_blocks(&mut self) -> io::Result<u64> {
+        self.with_tape(|tape| tape.tell_blocks())
+    }
+
+    fn seek_filemarks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        self.with_tape(|tape| tape.seek_filemarks(pos))
+    }
+
+    fn seek_setmarks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        self.with_tape(|tape| tape.seek_setmarks(pos))
+    }
+
+    fn seek_partition(&mut self, id: u32) -> io::Result<()> {
+        self.with_tape(|tape| tape.seek_partition(id))
+    }
+
+    fn current_partition(&mut self) -> io::Result<u32> {
+        self.with_tape(|tape| tape.current_partition())
+    }
+
+    fn partition_count(&mut self) -> io::Result<u32> {
+        self.with_tape(|tape| tape.partition_count())
+    }
+}
+
+impl<I> ArchivalSink<I> for SharedDrive<I> where I: Send {
+    fn downcast_seek(&mut self) -> Option<&mut dyn io::Seek> {
+        None
+    }
+
+    fn downcast_tapedevice(&mut self) -> Option<&mut dyn tape::TapeDevice> {
+        if self.device.lock().unwrap().downcast_tapedevice().is_some() {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.device.lock().unwrap().sync()
+    }
+
+    fn committed_bytes(&self) -> Option<u64> {
+        self.device.lock().unwrap().committed_bytes()
+    }
+}
+
+/// Open a drive so that it can be shared by several archives.
+///
+/// Only drives benefit from being held open, as closing them can rewind,
+/// unload, or otherwise disturb the medium. Anything else yields `None`, and
+/// should be opened with `open_sink` as usual.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. There are no drives to
+/// share, so it always yields `None`.
+#[allow(unused_variables)]
+pub fn open_drive<P: AsRef<path::Path>, I>(outfile: P, tuning: &Configuration) -> io::Result<Option<SharedDrive<I>>> where ffi::OsString: From<P>, P: Clone, I: 'static + Send + Clone + PartialEq {
+    Ok(None)
+}
+
+/// Open a sink object for writing an archive to a shared drive.
+///
+/// The sink is assembled as `open_sink` would for the drive's device, and
+/// writes through the drive without taking ownership of it.
+pub fn open_drive_sink<I>(drive: &SharedDrive<I>, tuning: &Configuration, limit: Option<u64>, cancel: &CancellationToken) -> io::Result<Box<ArchivalSink<I>>> where I: 'static + Send + Clone + PartialEq {
+    let mut drive = drive.clone();
+    let record_size = drive.downcast_tapedevice().map(|tape| tape_record_size(tape, tuning));
+
+    assemble_sink(drive, record_size, tuning, limit, cancel)
+}
+
 /// Given a directory entry, produce valid Unix mode bits for it.
 ///
 /// # Parameters
 ///
-/// This function accepts one parameter, the metadata to be converted into mode
-/// bits.
+/// This function accepts two parameters, the metadata to be converted into mode
+/// bits and the path which generated the metadata.
 ///
 /// # Returns
 ///
@@ -151,10 +744,7 @@ pub fn open_tape<P: AsRef<path::Path>>(_tapedev: P) -> io::Result<Box<tape::Tape
 ///  - The other bits faithfully represent the permissions afforded to every
 ///    user on the system, or failing that, the least privileged user on the
 ///    system.
-/// 
-/// TODO: Make a Windows (NT?) version of this that queries the Security API to
-/// produce plausible mode bits.
-pub fn get_unix_mode(metadata: &fs::Metadata) -> io::Result<u32> {
+pub fn get_unix_mode(metadata: &fs::Metadata, _path: &path::Path) -> io::Result<u32> {
     if !metadata.is_dir() {
         if metadata.permissions().readonly() {
             Ok(0o444)
@@ -200,6 +790,27 @@ pub fn get_file_type(metadata: &fs::Metadata) -> io::Result<tar::header::TarFile
     }
 }
 
+/// Determine if a link found within a traversal should be followed, rather
+/// than archived as a link.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. Every symbolic link is
+/// followed.
+pub fn is_followed_link(file_type: &fs::FileType) -> bool {
+    file_type.is_symlink()
+}
+
+/// Read the target of a symbolic link, in the form it should be archived.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. The target is archived
+/// exactly as the platform reports it.
+pub fn read_link_target(path: &path::Path) -> io::Result<path::PathBuf> {
+    fs::read_link(path)
+}
+
 /// Determine the UNIX owner ID and name for a given file.
 /// 
 /// # Parameters
@@ -236,4 +847,599 @@ pub fn get_unix_owner(_metadata: &fs::Metadata, _path: &path::Path) -> io::Resul
 /// all files are owned by the root group. (Some systems call this 'wheel'.)
 pub fn get_unix_group(_metadata: &fs::Metadata, _path: &path::Path) -> io::Result<(u32, String)> {
     Ok((0, "root".to_string()))
-}
\ No newline at end of file
+}
+
+/// Open a file whose contents are to be archived.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. It has no way to avoid
+/// access time updates, and opens all files normally.
+pub fn open_source_file(path: &path::Path, _atime: AtimePolicy) -> io::Result<fs::File> {
+    fs::File::open(path)
+}
+
+/// Open a file whose contents are to be archived, without following a
+/// symbolic link in it's place.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. It has no way to refuse
+/// symbolic links, and opens all files normally.
+pub fn open_source_file_nofollow(path: &path::Path, atime: AtimePolicy) -> io::Result<fs::File> {
+    open_source_file(path, atime)
+}
+
+/// Change the access time of an open file, leaving it's other times alone.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. It cannot change file times
+/// and always yields an error.
+pub fn set_file_atime(_file: &fs::File, _atime: time::SystemTime) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "Changing access times is not implemented for this operating system."))
+}
+
+/// Change the access, modification, and creation times of an open file, at
+/// full precision. Times given as `None` are left alone.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. It cannot change file times
+/// and always yields an error.
+pub fn set_file_times(_file: &fs::File, _atime: Option<time::SystemTime>, _mtime: Option<time::SystemTime>, _birthtime: Option<time::SystemTime>) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "Changing file times is not implemented for this operating system."))
+}
+
+/// Change the UNIX permissions of an open file, setuid, setgid, and sticky
+/// bits included.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. There is no portable notion
+/// of UNIX permissions, so this does nothing.
+pub fn set_file_mode(_file: &fs::File, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Determine how many files this process may have open at once.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. There is no portable way to
+/// ask, so it always yields `None`, meaning the limit is unknown.
+pub fn open_file_limit() -> Option<usize> {
+    None
+}
+
+/// Determine if a file has been flagged to be left out of backups.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. There is no portable notion
+/// of a no-dump flag, so this always yields `false`.
+pub fn is_nodump(_metadata: &fs::Metadata, _path: &path::Path) -> bool {
+    false
+}
+
+/// Determine which project a file is accounted to, for project quotas.
+///
+/// Yields `None` for files outside of any project.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. There is no portable notion
+/// of projects, so this always yields `None`.
+pub fn get_project_id(_metadata: &fs::Metadata, _path: &path::Path) -> io::Result<Option<u32>> {
+    Ok(None)
+}
+
+/// Determine which project an already open file is accounted to.
+///
+/// This is `get_project_id` for a file which is already open, such as one
+/// whose metadata was captured from it's handle.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. There is no portable notion
+/// of projects, so this always yields `None`.
+pub fn get_project_id_of_file(_metadata: &fs::Metadata, _file: &fs::File) -> io::Result<Option<u32>> {
+    Ok(None)
+}
+
+/// Account a file to a project, for project quotas.
+///
+/// This is intended for restoring archived project IDs on extraction, and
+/// usually needs administrative privileges.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. There is no portable notion
+/// of projects, so this always fails.
+pub fn set_project_id(_path: &path::Path, _project_id: u32) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Other, "Project IDs are not supported on this platform"))
+}
+
+/// Read the macOS-specific metadata of a file, such as it's Finder info and
+/// resource fork.
+///
+/// Yields the file's `com.apple.*` extended attributes, by name. Symbolic
+/// links are not followed.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. Only macOS has such
+/// metadata, so this always yields no attributes.
+pub fn get_apple_metadata(_path: &path::Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    Ok(Vec::new())
+}
+
+/// Determine when a file's metadata or contents last changed.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. Rust doesn't provide change
+/// times portably, so this always yields `None`.
+pub fn get_change_time(_metadata: &fs::Metadata, _path: &path::Path) -> Option<time::SystemTime> {
+    None
+}
+
+/// Determine the permission bits this process clears from files it creates.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. There is no portable notion
+/// of a umask, so this always yields `None`.
+pub fn process_umask() -> Option<u32> {
+    None
+}
+
+/// Determine how far local time is ahead of UTC at a given time, in seconds.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. Rust has no portable notion
+/// of time zones, so local time is taken to be UTC.
+pub fn utc_offset(_time: time::SystemTime) -> i64 {
+    0
+}
+
+/// Identifies a file independently of the path it was reached by.
+///
+/// Two paths with the same `FileId` name the same file, such as two hard links
+/// to it, or a directory and a symbolic link to it. The device half also tells
+/// which filesystem the file lives on.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct FileId {
+    device: u64,
+    index: u64,
+}
+
+impl FileId {
+    pub fn new(device: u64, index: u64) -> FileId {
+        FileId {
+            device: device,
+            index: index
+        }
+    }
+
+    /// The filesystem the file lives on.
+    pub fn device(&self) -> u64 {
+        self.device
+    }
+
+    /// The file's number within it's filesystem.
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+}
+
+/// Determine the identity of a file, for telling when two paths lead to the
+/// same file.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. There is no portable notion
+/// of file identity, so this always fails.
+pub fn file_identity(_metadata: &fs::Metadata, _path: &path::Path) -> io::Result<FileId> {
+    Err(io::Error::new(io::ErrorKind::Other, "File identities are not supported on this platform"))
+}
+
+/// Determine the identity of a regular file with more than one hard link, so
+/// that it's other links can be archived as links to it.
+///
+/// Yields None for files with only one link, and anything that isn't a
+/// regular file.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. It never finds any hard
+/// links, so every link to a file is archived as a copy of it.
+pub fn hard_link_identity(_metadata: &fs::Metadata, _path: &path::Path) -> Option<FileId> {
+    None
+}
+
+/// Find the data extents of a sparse file, in order.
+///
+/// Yields None for files without any holes, and anything that isn't a
+/// regular file.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. There is no portable way to
+/// find holes, so this always yields `None`.
+pub fn sparse_extents(_metadata: &fs::Metadata, _path: &path::Path) -> io::Result<Option<Vec<tar::sparse::SparseExtent>>> {
+    Ok(None)
+}
+
+/// Find the data extents of an already open sparse file, in order.
+///
+/// This is `sparse_extents` for a file which is already open, such as one
+/// whose metadata was captured from it's handle.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. There is no portable way to
+/// find holes, so this always yields `None`.
+pub fn sparse_extents_of_file(_metadata: &fs::Metadata, _file: &fs::File) -> io::Result<Option<Vec<tar::sparse::SparseExtent>>> {
+    Ok(None)
+}
+
+/// Read the access ACL of a file, and the default ACL of a directory.
+///
+/// Access ACLs which grant nothing beyond the file's mode bits yield None, as
+/// do files without any default ACL. Symbolic links have no ACLs.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. There is no portable notion
+/// of ACLs, so this always yields `None` for both.
+pub fn get_acls(_metadata: &fs::Metadata, _path: &path::Path) -> io::Result<(Option<tar::acl::Acl>, Option<tar::acl::Acl>)> {
+    Ok((None, None))
+}
+
+/// Read the access ACL of an already open file, and the default ACL of an
+/// already open directory.
+///
+/// This is `get_acls` for a file which is already open, such as one whose
+/// metadata was captured from it's handle. `path` is the path the file was
+/// opened from, for platforms which can only read ACLs by path.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. There is no portable notion
+/// of ACLs, so this always yields `None` for both.
+pub fn get_acls_of_file(_metadata: &fs::Metadata, _file: &fs::File, _path: &path::Path) -> io::Result<(Option<tar::acl::Acl>, Option<tar::acl::Acl>)> {
+    Ok((None, None))
+}
+
+/// Determine the major and minor numbers of a character or block device.
+///
+/// Yields None for anything that isn't a device.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. There is no portable notion
+/// of device numbers, so this always yields `None`.
+pub fn get_device_numbers(_metadata: &fs::Metadata, _path: &path::Path) -> Option<(u32, u32)> {
+    None
+}
+
+/// How many symbolic links may be followed while resolving one member, as
+/// with Linux's own limit.
+pub const MAX_LINKS_BENEATH: usize = 40;
+
+/// The error yielded for members which would be extracted outside of the
+/// directory they are being extracted into.
+pub(crate) fn escapes_root_error() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "Member path leads outside of the extraction directory")
+}
+
+/// Split a member's path into the names of it's components, refusing any
+/// member which isn't plainly relative.
+pub(crate) fn member_components(member: &path::Path) -> io::Result<Vec<ffi::OsString>> {
+    let mut components = Vec::new();
+
+    for component in member.components() {
+        match component {
+            path::Component::Normal(name) => components.push(name.to_os_string()),
+            path::Component::CurDir => {},
+            path::Component::ParentDir => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Member name contains '..'")),
+            path::Component::Prefix(_) | path::Component::RootDir => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Member name is absolute"))
+        }
+    }
+
+    Ok(components)
+}
+
+/// Where a member is to be extracted: a directory beneath the extraction
+/// root, and the member's name within it.
+///
+/// Members are created through this, rather than by path, so that whatever is
+/// already at the member's name is replaced instead of written through.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the type. The directory is held as a path,
+/// so links planted in it's parents after it was resolved are not noticed.
+pub struct ResolvedMember {
+    pub(crate) parent: path::PathBuf,
+    pub(crate) name: ffi::OsString
+}
+
+impl ResolvedMember {
+    fn path(&self) -> path::PathBuf {
+        self.parent.join(&self.name)
+    }
+
+    /// Remove whatever isn't a directory from the member's name.
+    fn unlink(&self) -> io::Result<()> {
+        match fs::symlink_metadata(self.path()) {
+            Ok(ref metadata) if !metadata.is_dir() => fs::remove_file(self.path()),
+            _ => Ok(())
+        }
+    }
+
+    /// Create the member as a new, empty file with the given permissions,
+    /// replacing anything but a directory already there.
+    pub fn create_file(&self, _mode: u32) -> io::Result<fs::File> {
+        self.unlink()?;
+
+        fs::OpenOptions::new().write(true).create_new(true).open(self.path())
+    }
+
+    /// Open the existing file at the member's name for writing, without
+    /// truncating it. Links are not followed.
+    pub fn open_file(&self) -> io::Result<fs::File> {
+        match fs::symlink_metadata(self.path())?.is_file() {
+            true => fs::OpenOptions::new().write(true).open(self.path()),
+            false => Err(io::Error::new(io::ErrorKind::InvalidInput, "Member is not a file"))
+        }
+    }
+
+    /// Create the member as a directory with the given permissions.
+    ///
+    /// Directories which already exist are kept, and yield `false`.
+    pub fn create_dir(&self, _mode: u32) -> io::Result<bool> {
+        match fs::create_dir(self.path()) {
+            Ok(()) => Ok(true),
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists && fs::symlink_metadata(self.path())?.is_dir() => Ok(false),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Open the directory at the member's name, so that it's permissions and
+    /// times can be restored. Links are not followed.
+    pub fn open_dir(&self) -> io::Result<fs::File> {
+        match fs::symlink_metadata(self.path())?.is_dir() {
+            true => fs::File::open(self.path()),
+            false => Err(io::Error::new(io::ErrorKind::InvalidInput, "Member is not a directory"))
+        }
+    }
+
+    /// Create the member as a symbolic link to `target`.
+    pub fn create_symlink(&self, _target: &path::Path) -> io::Result<()> {
+        Err(io::Error::new(io::ErrorKind::Other, "Symbolic links cannot be extracted on this platform"))
+    }
+
+    /// Create the member as a hard link to another, already extracted member.
+    pub fn create_hard_link(&self, original: &ResolvedMember) -> io::Result<()> {
+        self.unlink()?;
+
+        fs::hard_link(original.path(), self.path())
+    }
+}
+
+/// Find where a member belongs beneath the directory it's being extracted
+/// into, without letting symbolic links lead it anywhere else.
+///
+/// `member` is the member's path relative to `root`, as `tar::restored_path`
+/// yields it. Members with absolute names or `..` components are refused
+/// outright, as is any member whose parent directories would lead outside of
+/// `root` through a symbolic link. An archive can't then plant a link to
+/// somewhere else and extract later members through it. Links that stay
+/// beneath `root` are resolved. The member's own name is never followed: a
+/// link there is to be replaced, not written through. A member naming `root`
+/// itself resolves to `.` within it.
+///
+/// Parent directories which don't exist yet are created beneath `root`. Only
+/// links already on disk are accounted for, so members should be resolved as
+/// they are extracted, and not ahead of time. Members extracted with absolute
+/// names (`-P`) are not confined at all, and should not be resolved with this.
+///
+/// # Platform considerations
+///
+/// This is the portable version of the function. It walks the member's path
+/// one component at a time, reading each link it comes across and resolving
+/// it's target in the same way. Links with absolute targets are refused, as
+/// are members that pass through more than `MAX_LINKS_BENEATH` links.
+pub fn resolve_beneath(root: &path::Path, member: &path::Path) -> io::Result<ResolvedMember> {
+    let mut pending = member_components(member)?;
+    let name = match pending.pop() {
+        Some(name) => name,
+        None => return Ok(ResolvedMember { parent: root.to_path_buf(), name: ffi::OsString::from(".") })
+    };
+
+    //Components are taken from the end, so they're kept in reverse. Link
+    //targets are the only place `..` can turn up.
+    pending.reverse();
+
+    let mut resolved = root.to_path_buf();
+    let mut depth = 0;
+    let mut links = 0;
+    let mut missing = false;
+
+    while let Some(component) = pending.pop() {
+        if component == ".." {
+            if depth == 0 {
+                return Err(escapes_root_error());
+            }
+
+            resolved.pop();
+            depth -= 1;
+            continue;
+        }
+
+        let candidate = resolved.join(&component);
+        let metadata = match missing {
+            true => None,
+            false => match fs::symlink_metadata(&candidate) {
+                Ok(metadata) => Some(metadata),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e)
+            }
+        };
+
+        match metadata {
+            Some(ref metadata) if metadata.file_type().is_symlink() => {
+                links += 1;
+                if links > MAX_LINKS_BENEATH {
+                    return Err(io::Error::new(io::ErrorKind::Other, "Too many levels of symbolic links"));
+                }
+
+                let target = fs::read_link(&candidate)?;
+
+                for component in target.components().rev() {
+                    match component {
+                        path::Component::Normal(name) => pending.push(name.to_os_string()),
+                        path::Component::ParentDir => pending.push(ffi::OsString::from("..")),
+                        path::Component::CurDir => {},
+                        path::Component::Prefix(_) | path::Component::RootDir => return Err(escapes_root_error())
+                    }
+                }
+            },
+            metadata => {
+                missing = metadata.is_none();
+                resolved = candidate;
+                depth += 1;
+            }
+        }
+    }
+
+    fs::create_dir_all(&resolved)?;
+
+    Ok(ResolvedMember { parent: resolved, name: name })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use crate::cancel::CancellationToken;
+    use crate::testing::RecordingSink;
+    use crate::tuning::Configuration;
+    use super::{ArchivalSink, build_pipeline, build_tape_pipeline};
+
+    #[test]
+    fn typed_pipelines() {
+        let mut tuning = Configuration::default();
+        tuning.blocking_factor = 2;
+
+        let file = RecordingSink::<u64>::new();
+        let file_log = file.log();
+        let mut sink = build_pipeline(file, &tuning, &CancellationToken::new()).unwrap();
+
+        sink.write_all(&[1; 1536]).unwrap();
+        sink.flush().unwrap();
+        assert!(sink.downcast_tapedevice().is_none());
+        assert_eq!(file_log.data(), vec![1; 1536]);
+
+        let tape = RecordingSink::<u64>::new_tape();
+        let tape_log = tape.log();
+        let mut sink = build_tape_pipeline(tape, &tuning, &CancellationToken::new()).unwrap();
+
+        sink.write_all(&[1; 1536]).unwrap();
+        sink.flush().unwrap();
+        assert!(sink.downcast_tapedevice().is_some());
+        assert_eq!(tape_log.records().iter().map(|record| record.len()).collect::<Vec<_>>(), vec![1024, 1024]);
+
+        let mut tape = RecordingSink::<u64>::new_tape();
+        tape.tape_mut().set_fixed_block_size(Some(800));
+        let tape_log = tape.log();
+        let mut sink = build_tape_pipeline(tape, &tuning, &CancellationToken::new()).unwrap();
+
+        sink.write_all(&[1; 1536]).unwrap();
+        sink.flush().unwrap();
+        assert_eq!(tape_log.records().iter().map(|record| record.len()).collect::<Vec<_>>(), vec![800, 800]);
+    }
+
+    /// Build a directory of links for `resolve_beneath` to find it's way
+    /// through.
+    #[cfg(unix)]
+    fn beneath_fixture(name: &str) -> std::path::PathBuf {
+        use std::os::unix::fs::symlink;
+
+        let root = std::env::temp_dir().join(format!("rapidtar-{}-{}", name, std::process::id()));
+
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        symlink("sub", root.join("inside")).unwrap();
+        symlink("../../sub", root.join("sub/deeper/up")).unwrap();
+        symlink("..", root.join("sub/parent")).unwrap();
+        symlink("../..", root.join("escape")).unwrap();
+        symlink("sub/parent/..", root.join("sneaky")).unwrap();
+        symlink("/tmp", root.join("absolute")).unwrap();
+        symlink("new/dir", root.join("dangling")).unwrap();
+        symlink("loop2", root.join("loop1")).unwrap();
+        symlink("loop1", root.join("loop2")).unwrap();
+
+        root
+    }
+
+    /// Check that a `resolve_beneath` implementation confines members to the
+    /// fixture directory, by creating a file through each member it resolves.
+    #[cfg(unix)]
+    fn check_resolve_beneath<F>(root: &std::path::Path, create: F) where F: Fn(&std::path::Path, &std::path::Path) -> std::io::Result<std::fs::File> {
+        use std::path::Path;
+
+        let create = |member: &str| create(root, Path::new(member));
+        let beneath = |member: &str, expected: &str| {
+            create(member).unwrap();
+
+            assert!(std::fs::symlink_metadata(root.join(expected)).unwrap().is_file(), "resolving {}", member);
+        };
+
+        beneath("file", "file");
+        beneath("./sub/file", "sub/file");
+        beneath("inside/file", "sub/file");
+        beneath("sub/deeper/up/file", "sub/file");
+        beneath("sub/parent/file", "file");
+        beneath("missing/dirs/file", "missing/dirs/file");
+        beneath("dangling/file", "new/dir/file");
+
+        for member in ["escape/file", "sneaky/file", "absolute/file"].iter() {
+            assert_eq!(create(member).unwrap_err().kind(), std::io::ErrorKind::PermissionDenied, "resolving {}", member);
+        }
+
+        for member in ["../file", "sub/../file", "/etc/passwd"].iter() {
+            assert_eq!(create(member).unwrap_err().kind(), std::io::ErrorKind::InvalidInput, "resolving {}", member);
+        }
+
+        assert!(create("loop1/file").is_err());
+        assert!(create(".").is_err());
+
+        //Links are never followed as the member's own name, but replaced.
+        beneath("escape", "escape");
+        beneath("inside", "inside");
+        assert!(root.join("sub").is_dir());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_beneath() {
+        let root = beneath_fixture("beneath-portable");
+
+        check_resolve_beneath(&root, |root, member| super::resolve_beneath(root, member)?.create_file(0o644));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_beneath_natively() {
+        let root = beneath_fixture("beneath-native");
+
+        check_resolve_beneath(&root, |root, member| crate::fs::resolve_beneath(root, member)?.create_file(0o644));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
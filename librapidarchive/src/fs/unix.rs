@@ -1,15 +1,18 @@
 //! Unix-specific implementations of fs methods.
 
-use std::{io, fs, path, ffi, ptr, mem};
+use std::{io, fs, path, ffi, ptr, mem, time};
 use std::os::unix::prelude::*;
 use libc::{getpwuid_r, getgrgid_r, passwd, group, ERANGE};
-use crate::{tar, tape, spanning};
+use crate::tar;
+#[cfg(feature = "tape")]
+use crate::tape;
+#[cfg(feature = "tape")]
 use crate::tape::unix::UnixTapeDevice;
-use crate::blocking::BlockingWriter;
-use crate::concurrentbuf::ConcurrentWriteBuffer;
 use crate::tuning::Configuration;
+use crate::fs::portable::assemble_sink;
+use crate::cancel::CancellationToken;
 
-pub use crate::fs::portable::ArchivalSink;
+pub use crate::fs::portable::{ArchivalSink, ArchivalSource, build_pipeline, build_tape_pipeline, tape_record_size, open_sink_at, SharedDrive, open_drive_sink, SinkCapabilities, AtimePolicy, ArchivalFile, FileId, is_standard_stream, seek_archive_file, is_followed_link, read_link_target};
 
 /// Open a sink object for writing an archive (aka "tape").
 /// 
@@ -18,27 +21,76 @@ pub use crate::fs::portable::ArchivalSink;
 /// # Platform considerations
 /// 
 /// This is the UNIX version of the function. It supports writes to files and
-/// tape devices.
-pub fn open_sink<P: AsRef<path::Path>, I>(outfile: P, tuning: &Configuration, limit: Option<u64>) -> io::Result<Box<ArchivalSink<I>>> where ffi::OsString: From<P>, P: Clone, I: 'static + Send + Clone + PartialEq {
+/// tape devices. Without the `tape` feature, tape devices are written to as if
+/// they were files.
+pub fn open_sink<P: AsRef<path::Path>, I>(outfile: P, tuning: &Configuration, limit: Option<u64>, cancel: &CancellationToken) -> io::Result<Box<ArchivalSink<I>>> where ffi::OsString: From<P>, P: Clone, I: 'static + Send + Clone + PartialEq {
+    #[cfg(feature = "tape")]
     if let Ok(metadata) = fs::metadata(outfile.clone()) {
         //TODO: Better tape detection. This assumes all character devices are tapes.
         if metadata.file_type().is_char_device() {
-            return match UnixTapeDevice::open_device(&ffi::OsString::from(outfile)) {
-                Ok(tape) => match limit {
-                    Some(limit) => Ok(Box::new(spanning::LimitingWriter::wrap(BlockingWriter::new_with_factor(ConcurrentWriteBuffer::new(tape, tuning.serial_buffer_limit), tuning.blocking_factor), limit))),
-                    None => Ok(Box::new(BlockingWriter::new_with_factor(ConcurrentWriteBuffer::new(tape, tuning.serial_buffer_limit), tuning.blocking_factor)))
-                },
-                Err(e) => Err(e)
-            }
+            let options = tape::TapeOptions { fallback_block_size: tuning.tape_fallback_block_size, ..tape::TapeOptions::default() };
+            let tape = UnixTapeDevice::open_device(&ffi::OsString::from(outfile), &options)?;
+            let record_size = tape_record_size(&tape, tuning);
+
+            return assemble_sink(tape, Some(record_size), tuning, limit, cancel);
         }
     }
 
     let file = fs::File::create(outfile.as_ref())?;
-    
-    match limit {
-        Some(limit) => Ok(Box::new(spanning::LimitingWriter::wrap(ConcurrentWriteBuffer::new(file, tuning.serial_buffer_limit), limit))),
-        None => Ok(Box::new(ConcurrentWriteBuffer::new(file, tuning.serial_buffer_limit)))
+
+    assemble_sink(file, None, tuning, limit, cancel)
+}
+
+/// Open a drive so that it can be shared by several archives.
+///
+/// For more information, please see `rapidtar::fs::portable::open_drive`.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. Tape devices are shared, unless
+/// the `tape` feature is disabled.
+#[allow(unused_variables)]
+pub fn open_drive<P: AsRef<path::Path>, I>(outfile: P, tuning: &Configuration) -> io::Result<Option<SharedDrive<I>>> where ffi::OsString: From<P>, P: Clone, I: 'static + Send + Clone + PartialEq {
+    #[cfg(feature = "tape")]
+    if let Ok(metadata) = fs::metadata(outfile.clone()) {
+        //TODO: Better tape detection. This assumes all character devices are tapes.
+        if metadata.file_type().is_char_device() {
+            let options = tape::TapeOptions { fallback_block_size: tuning.tape_fallback_block_size, ..tape::TapeOptions::default() };
+            let tape = UnixTapeDevice::open_device(&ffi::OsString::from(outfile), &options)?;
+
+            return Ok(Some(SharedDrive::new(Box::new(tape))));
+        }
     }
+
+    Ok(None)
+}
+
+/// Open a source object for reading an archive back.
+///
+/// For more information, please see `rapidtar::fs::portable::open_source`.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. It supports reads from files,
+/// standard input, and tape devices. Tapes are opened read-only, so that
+/// write-protected media can still be read. Without the `tape` feature, tape
+/// devices are read as if they were files.
+pub fn open_source<P: AsRef<path::Path>>(infile: P) -> io::Result<Box<ArchivalSource>> where ffi::OsString: From<P>, P: Clone {
+    if is_standard_stream(infile.as_ref()) {
+        return Ok(Box::new(io::stdin()));
+    }
+
+    #[cfg(feature = "tape")]
+    if let Ok(metadata) = fs::metadata(infile.clone()) {
+        //TODO: Better tape detection. This assumes all character devices are tapes.
+        if metadata.file_type().is_char_device() {
+            let tape = UnixTapeDevice::<u64>::open_device(&ffi::OsString::from(infile), &tape::TapeOptions::read_only())?;
+
+            return Ok(Box::new(tape));
+        }
+    }
+
+    Ok(Box::new(fs::File::open(infile.as_ref())?))
 }
 
 /// Open an object for total control of a tape device.
@@ -47,8 +99,9 @@ pub fn open_sink<P: AsRef<path::Path>, I>(outfile: P, tuning: &Configuration, li
 /// 
 /// This is the UNIX version of the function. It implements tape control for
 /// all tape devices
-pub fn open_tape<P: AsRef<path::Path>>(tapedev: P) -> io::Result<Box<tape::TapeDevice>> where ffi::OsString: From<P>, P: Clone {
-    match UnixTapeDevice::<u64>::open_device(&ffi::OsString::from(tapedev.clone())) {
+#[cfg(feature = "tape")]
+pub fn open_tape<P: AsRef<path::Path>>(tapedev: P, options: &tape::TapeOptions) -> io::Result<Box<tape::TapeDevice>> where ffi::OsString: From<P>, P: Clone {
+    match UnixTapeDevice::<u64>::open_device(&ffi::OsString::from(tapedev.clone()), options) {
         Ok(tape) => {
             return Ok(Box::new(tape));
         }
@@ -56,6 +109,9 @@ pub fn open_tape<P: AsRef<path::Path>>(tapedev: P) -> io::Result<Box<tape::TapeD
     }
 }
 
+#[cfg(not(feature = "tape"))]
+pub use crate::fs::portable::open_tape;
+
 /// Given a directory entry, produce valid Unix mode bits for it.
 /// 
 /// # Platform considerations
@@ -63,7 +119,7 @@ pub fn open_tape<P: AsRef<path::Path>>(tapedev: P) -> io::Result<Box<tape::TapeD
 /// This is the Unix version of the function. It pulls real mode bits off the
 /// filesystem whose semantic meaning is identical to the definition of
 /// `fs::portable::get_unix_mode`.
-pub fn get_unix_mode(metadata: &fs::Metadata) -> io::Result<u32> {
+pub fn get_unix_mode(metadata: &fs::Metadata, _path: &path::Path) -> io::Result<u32> {
     Ok(metadata.permissions().mode())
 }
 
@@ -104,13 +160,18 @@ pub fn get_file_type(metadata: &fs::Metadata) -> io::Result<tar::header::TarFile
 /// This is the Unix version of the function. It reports the correct UID for the
 /// file.
 pub fn get_unix_owner(metadata: &fs::Metadata, _path: &path::Path) -> io::Result<(u32, String)> {
+    Ok((metadata.uid(), user_name(metadata.uid())?))
+}
+
+/// Look up the name of a user by their UID.
+fn user_name(uid: u32) -> io::Result<String> {
     let mut username;
     let mut passwd = unsafe { mem::zeroed() }; //TODO: Is uninit safe?
     let mut buf = Vec::with_capacity(1024);
     
     loop {
         let mut out_passwd = &mut passwd as *mut passwd;
-        let res = unsafe { getpwuid_r(metadata.uid(), &mut passwd, buf.as_mut_ptr(), buf.capacity(), &mut out_passwd) };
+        let res = unsafe { getpwuid_r(uid, &mut passwd, buf.as_mut_ptr(), buf.capacity(), &mut out_passwd) };
         
         if (out_passwd as *mut passwd) == ptr::null_mut() {
             match res {
@@ -125,7 +186,7 @@ pub fn get_unix_owner(metadata: &fs::Metadata, _path: &path::Path) -> io::Result
         break;
     }
     
-    Ok((metadata.uid(), username))
+    Ok(username)
 }
 
 /// Determine the UNIX group ID and name for a given file.
@@ -137,13 +198,18 @@ pub fn get_unix_owner(metadata: &fs::Metadata, _path: &path::Path) -> io::Result
 /// 
 /// TODO: It should also report a group name, too...
 pub fn get_unix_group(metadata: &fs::Metadata, _path: &path::Path) -> io::Result<(u32, String)> {
+    Ok((metadata.gid(), group_name(metadata.gid())?))
+}
+
+/// Look up the name of a group by it's GID.
+fn group_name(gid: u32) -> io::Result<String> {
     let mut groupname;
     let mut group = unsafe { mem::zeroed() }; //TODO: Is uninit safe?
     let mut buf = Vec::with_capacity(1024);
     
     loop {
         let mut out_group = &mut group as *mut group;
-        let res = unsafe { getgrgid_r(metadata.gid(), &mut group, buf.as_mut_ptr(), buf.capacity(), &mut out_group) };
+        let res = unsafe { getgrgid_r(gid, &mut group, buf.as_mut_ptr(), buf.capacity(), &mut out_group) };
         
         if (out_group as *mut group) == ptr::null_mut() {
             match res {
@@ -158,5 +224,896 @@ pub fn get_unix_group(metadata: &fs::Metadata, _path: &path::Path) -> io::Result
         break;
     }
     
-    Ok((metadata.gid(), groupname))
-}
\ No newline at end of file
+    Ok(groupname)
+}
+
+/// Open a file whose contents are to be archived.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. On Linux, the `System` policy
+/// opens files with `O_NOATIME`, which the kernel only allows for files owned
+/// by the current user; other files are opened normally. Other UNIX systems
+/// have no such flag and open all files normally.
+#[cfg(target_os = "linux")]
+pub fn open_source_file(path: &path::Path, atime: AtimePolicy) -> io::Result<fs::File> {
+    match atime {
+        AtimePolicy::System => match fs::OpenOptions::new().read(true).custom_flags(libc::O_NOATIME).open(path) {
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => fs::File::open(path),
+            result => result
+        },
+        _ => fs::File::open(path)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open_source_file(path: &path::Path, _atime: AtimePolicy) -> io::Result<fs::File> {
+    fs::File::open(path)
+}
+
+/// Open a file whose contents are to be archived, without following a
+/// symbolic link in it's place.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. Files are opened with
+/// `O_NOFOLLOW`, so that a symbolic link fails to open, and `O_NONBLOCK`, so
+/// that a FIFO doesn't wait for a writer. Access times are avoided in the
+/// same manner as `open_source_file`.
+#[cfg(target_os = "linux")]
+pub fn open_source_file_nofollow(path: &path::Path, atime: AtimePolicy) -> io::Result<fs::File> {
+    let flags = libc::O_NOFOLLOW | libc::O_NONBLOCK;
+
+    match atime {
+        AtimePolicy::System => match fs::OpenOptions::new().read(true).custom_flags(flags | libc::O_NOATIME).open(path) {
+            Err(ref e) if e.raw_os_error() == Some(libc::EPERM) => fs::OpenOptions::new().read(true).custom_flags(flags).open(path),
+            result => result
+        },
+        _ => fs::OpenOptions::new().read(true).custom_flags(flags).open(path)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn open_source_file_nofollow(path: &path::Path, _atime: AtimePolicy) -> io::Result<fs::File> {
+    fs::OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK).open(path)
+}
+
+/// Change the access time of an open file, leaving it's other times alone.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. It uses `futimens`, so the
+/// modification time is left untouched, but the change time will be updated.
+pub fn set_file_atime(file: &fs::File, atime: time::SystemTime) -> io::Result<()> {
+    set_file_times(file, Some(atime), None, None)
+}
+
+/// Convert a time into a `timespec` for `futimens`.
+fn to_timespec(time: time::SystemTime) -> libc::timespec {
+    match time.duration_since(time::UNIX_EPOCH) {
+        Ok(since) => libc::timespec {
+            tv_sec: since.as_secs() as libc::time_t,
+            tv_nsec: since.subsec_nanos() as libc::c_long
+        },
+        Err(before) => {
+            let before = before.duration();
+            let mut secs = -(before.as_secs() as i64);
+            let mut nanos = before.subsec_nanos() as i64;
+
+            if nanos > 0 {
+                secs -= 1;
+                nanos = 1_000_000_000 - nanos;
+            }
+
+            libc::timespec {
+                tv_sec: secs as libc::time_t,
+                tv_nsec: nanos as libc::c_long
+            }
+        }
+    }
+}
+
+/// Change the access, modification, and creation times of an open file, at
+/// full precision. Times given as `None` are left alone.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. UNIX has no way to set a file's
+/// creation time, so `birthtime` is ignored.
+pub fn set_file_times(file: &fs::File, atime: Option<time::SystemTime>, mtime: Option<time::SystemTime>, _birthtime: Option<time::SystemTime>) -> io::Result<()> {
+    let omit = libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT };
+    let times = [atime.map_or(omit, to_timespec), mtime.map_or(omit, to_timespec)];
+
+    if unsafe { libc::futimens(file.as_raw_fd(), times.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Change the UNIX permissions of an open file, setuid, setgid, and sticky
+/// bits included.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. It uses `fchmod`.
+pub fn set_file_mode(file: &fs::File, mode: u32) -> io::Result<()> {
+    if unsafe { libc::fchmod(file.as_raw_fd(), mode as libc::mode_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+const FS_NODUMP_FL: libc::c_long = 0x00000040;
+
+/// The extended inode attributes read and written by `FS_IOC_FSGETXATTR` and
+/// `FS_IOC_FSSETXATTR`.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct fsxattr {
+    fsx_xflags: u32,
+    fsx_extsize: u32,
+    fsx_nextents: u32,
+    fsx_projid: u32,
+    fsx_cowextsize: u32,
+    fsx_pad: [u8; 8],
+}
+
+#[cfg(target_os = "linux")]
+mod ioctls {
+    use super::fsxattr;
+
+    ioctl!(read fs_ioc_getflags with 'f', 1; libc::c_long);
+    ioctl!(read fs_ioc_fsgetxattr with 'X', 31; fsxattr);
+    ioctl!(write_ptr fs_ioc_fssetxattr with 'X', 32; fsxattr);
+}
+
+/// Determine how many files this process may have open at once.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. It yields the soft
+/// `RLIMIT_NOFILE` limit, or `None` if there isn't one.
+pub fn open_file_limit() -> Option<usize> {
+    let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 || limit.rlim_cur == libc::RLIM_INFINITY {
+        return None;
+    }
+
+    Some(limit.rlim_cur as usize)
+}
+
+/// Determine if a file has been flagged to be left out of backups.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. On Linux, it checks for the
+/// no-dump inode flag (as set by `chattr +d`), which requires opening the
+/// file. Only files and directories are checked, since opening anything else
+/// can have side effects, and files we can't open are assumed not to be
+/// flagged. Filesystems without inode flags report no flags at all.
+#[cfg(target_os = "linux")]
+pub fn is_nodump(metadata: &fs::Metadata, path: &path::Path) -> bool {
+    if !metadata.is_file() && !metadata.is_dir() {
+        return false;
+    }
+
+    let file = match fs::OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK).open(path) {
+        Ok(file) => file,
+        Err(_) => return false
+    };
+
+    let mut flags : libc::c_long = 0;
+
+    match unsafe { ioctls::fs_ioc_getflags(file.as_raw_fd(), &mut flags) } {
+        Ok(_) => flags & FS_NODUMP_FL != 0,
+        Err(_) => false
+    }
+}
+
+/// Determine if a file has been flagged to be left out of backups.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. On BSD-derived systems, it
+/// checks for the `UF_NODUMP` flag (as set by `chflags nodump`), which is
+/// reported along with the rest of the file's metadata.
+#[cfg(any(target_os = "macos", target_os = "freebsd"))]
+pub fn is_nodump(metadata: &fs::Metadata, _path: &path::Path) -> bool {
+    #[cfg(target_os = "macos")]
+    use std::os::macos::fs::MetadataExt;
+
+    #[cfg(target_os = "freebsd")]
+    use std::os::freebsd::fs::MetadataExt;
+
+    const UF_NODUMP: u32 = 0x00000001;
+
+    metadata.st_flags() & UF_NODUMP != 0
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "freebsd")))]
+pub use crate::fs::portable::is_nodump;
+
+/// Determine if an ioctl failed because the file or filesystem doesn't support
+/// it at all.
+#[cfg(target_os = "linux")]
+fn is_unsupported_ioctl(error: &nix::Error) -> bool {
+    match error {
+        nix::Error::Sys(errno) => *errno == nix::errno::Errno::ENOTTY || *errno == nix::errno::Errno::EOPNOTSUPP || *errno == nix::errno::Errno::EINVAL,
+        _ => false
+    }
+}
+
+/// Determine which project a file is accounted to, for project quotas.
+///
+/// Yields `None` for files outside of any project.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. On Linux, it reads the file's
+/// project ID with `FS_IOC_FSGETXATTR`, which requires opening the file. As
+/// with `is_nodump`, only files and directories are checked. Project 0, the
+/// default, is reported as no project, as are files on filesystems without
+/// project IDs.
+#[cfg(target_os = "linux")]
+pub fn get_project_id(metadata: &fs::Metadata, path: &path::Path) -> io::Result<Option<u32>> {
+    if !metadata.is_file() && !metadata.is_dir() {
+        return Ok(None);
+    }
+
+    let file = fs::OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK).open(path)?;
+
+    get_project_id_of_file(metadata, &file)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub use crate::fs::portable::get_project_id;
+
+/// Determine which project an already open file is accounted to.
+///
+/// For more information, please see
+/// `rapidtar::fs::portable::get_project_id_of_file`.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. On Linux, it reads the project
+/// ID from the open file, in the same manner as `get_project_id`.
+#[cfg(target_os = "linux")]
+pub fn get_project_id_of_file(metadata: &fs::Metadata, file: &fs::File) -> io::Result<Option<u32>> {
+    if !metadata.is_file() && !metadata.is_dir() {
+        return Ok(None);
+    }
+
+    let mut attributes = fsxattr::default();
+
+    match unsafe { ioctls::fs_ioc_fsgetxattr(file.as_raw_fd(), &mut attributes) } {
+        Ok(_) if attributes.fsx_projid == 0 => Ok(None),
+        Ok(_) => Ok(Some(attributes.fsx_projid)),
+        Err(ref e) if is_unsupported_ioctl(e) => Ok(None),
+        Err(e) => Err(io::Error::new(io::ErrorKind::Other, e))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub use crate::fs::portable::get_project_id_of_file;
+
+/// Account a file to a project, for project quotas.
+///
+/// This is intended for restoring archived project IDs on extraction, and
+/// usually needs administrative privileges.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. On Linux, it sets the file's
+/// project ID with `FS_IOC_FSSETXATTR`, leaving the rest of it's extended
+/// inode attributes alone. Symbolic links are not followed.
+#[cfg(target_os = "linux")]
+pub fn set_project_id(path: &path::Path, project_id: u32) -> io::Result<()> {
+    let file = fs::OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK).open(path)?;
+    let mut attributes = fsxattr::default();
+
+    unsafe { ioctls::fs_ioc_fsgetxattr(file.as_raw_fd(), &mut attributes) }.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    attributes.fsx_projid = project_id;
+
+    unsafe { ioctls::fs_ioc_fssetxattr(file.as_raw_fd(), &attributes) }.map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub use crate::fs::portable::set_project_id;
+
+/// Read the macOS-specific metadata of a file, such as it's Finder info and
+/// resource fork.
+///
+/// Yields the file's `com.apple.*` extended attributes, by name. Symbolic
+/// links are not followed.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. On macOS, it lists and reads the
+/// file's extended attributes; attributes which vanish while being read are
+/// skipped. Filesystems without extended attributes report none.
+#[cfg(target_os = "macos")]
+pub fn get_apple_metadata(path: &path::Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let c_path = ffi::CString::new(path.as_os_str().as_bytes())?;
+    let list_size = unsafe { libc::listxattr(c_path.as_ptr(), ptr::null_mut(), 0, libc::XATTR_NOFOLLOW) };
+
+    if list_size < 0 {
+        return match io::Error::last_os_error() {
+            ref e if e.raw_os_error() == Some(libc::ENOTSUP) => Ok(Vec::new()),
+            e => Err(e)
+        };
+    }
+
+    let mut names = vec![0u8; list_size as usize];
+    let list_size = unsafe { libc::listxattr(c_path.as_ptr(), names.as_mut_ptr() as *mut libc::c_char, names.len(), libc::XATTR_NOFOLLOW) };
+
+    if list_size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    names.truncate(list_size as usize);
+
+    let mut attributes = Vec::new();
+
+    for name in names.split(|b| *b == 0).filter(|name| name.starts_with(b"com.apple.")) {
+        let c_name = ffi::CString::new(name)?;
+        let size = unsafe { libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), ptr::null_mut(), 0, 0, libc::XATTR_NOFOLLOW) };
+
+        if size < 0 {
+            continue;
+        }
+
+        let mut value = vec![0u8; size as usize];
+        let size = unsafe { libc::getxattr(c_path.as_ptr(), c_name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len(), 0, libc::XATTR_NOFOLLOW) };
+
+        if size < 0 {
+            continue;
+        }
+
+        value.truncate(size as usize);
+        attributes.push((String::from_utf8_lossy(name).into_owned(), value));
+    }
+
+    Ok(attributes)
+}
+
+#[cfg(not(target_os = "macos"))]
+pub use crate::fs::portable::get_apple_metadata;
+
+/// Determine when a file's metadata or contents last changed.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. It reports the inode change time
+/// from the file's metadata.
+pub fn get_change_time(metadata: &fs::Metadata, _path: &path::Path) -> Option<time::SystemTime> {
+    let secs = metadata.ctime();
+    let nanos = metadata.ctime_nsec() as u32;
+
+    if secs >= 0 {
+        Some(time::UNIX_EPOCH + time::Duration::new(secs as u64, nanos))
+    } else {
+        Some(time::UNIX_EPOCH - time::Duration::new((-secs) as u64, 0) + time::Duration::new(0, nanos))
+    }
+}
+
+/// Determine the permission bits this process clears from files it creates.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. The umask can only be read by
+/// changing it, so where the kernel reports it in `/proc/self/status`, that's
+/// used instead. Otherwise, it's briefly cleared and put back, which may
+/// affect files created by other threads in the meantime; call this before
+/// starting any.
+pub fn process_umask() -> Option<u32> {
+    if let Ok(status) = fs::read_to_string("/proc/self/status") {
+        let reported = status.lines()
+            .find(|line| line.starts_with("Umask:"))
+            .and_then(|line| u32::from_str_radix(line["Umask:".len()..].trim(), 8).ok());
+
+        if reported.is_some() {
+            return reported;
+        }
+    }
+
+    unsafe {
+        let umask = libc::umask(0);
+        libc::umask(umask);
+
+        Some(umask as u32)
+    }
+}
+
+/// Determine how far local time is ahead of UTC at a given time, in seconds.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. The offset comes from the C
+/// library's idea of the local time zone, which honors `TZ`.
+pub fn utc_offset(time: time::SystemTime) -> i64 {
+    let secs = match time.duration_since(time::UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64)
+    };
+
+    unsafe {
+        let timestamp = secs as libc::time_t;
+        let mut local : libc::tm = mem::zeroed();
+
+        if libc::localtime_r(&timestamp, &mut local).is_null() {
+            return 0;
+        }
+
+        local.tm_gmtoff as i64
+    }
+}
+
+/// Determine the identity of a file, for telling when two paths lead to the
+/// same file.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. Files are identified by their
+/// device and inode numbers, which come with the rest of their metadata.
+pub fn file_identity(metadata: &fs::Metadata, _path: &path::Path) -> io::Result<FileId> {
+    Ok(FileId::new(metadata.dev(), metadata.ino()))
+}
+
+/// Determine the identity of a regular file with more than one hard link, so
+/// that it's other links can be archived as links to it.
+///
+/// Yields None for files with only one link, and anything that isn't a
+/// regular file.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. Link counts come with the rest
+/// of a file's metadata, so this costs nothing extra.
+pub fn hard_link_identity(metadata: &fs::Metadata, _path: &path::Path) -> Option<FileId> {
+    match metadata.is_file() && metadata.nlink() > 1 {
+        true => Some(FileId::new(metadata.dev(), metadata.ino())),
+        false => None
+    }
+}
+
+/// Find the data extents of a sparse file, in order.
+///
+/// Yields None for files without any holes, and anything that isn't a
+/// regular file.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. On Linux, files taking up fewer
+/// blocks than their size would need are opened and searched for holes with
+/// `SEEK_DATA` and `SEEK_HOLE`; other files aren't opened at all. Filesystems
+/// which can't search for holes are treated as not having any.
+#[cfg(target_os = "linux")]
+pub fn sparse_extents(metadata: &fs::Metadata, path: &path::Path) -> io::Result<Option<Vec<tar::sparse::SparseExtent>>> {
+    if !metadata.is_file() || metadata.blocks() * 512 >= metadata.len() {
+        return Ok(None);
+    }
+
+    let file = fs::OpenOptions::new().read(true).custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK).open(path)?;
+
+    sparse_extents_of_file(metadata, &file)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub use crate::fs::portable::sparse_extents;
+
+/// Find the data extents of an already open sparse file, in order.
+///
+/// For more information, please see
+/// `rapidtar::fs::portable::sparse_extents_of_file`.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. On Linux, the open file is
+/// searched for holes in the same manner as `sparse_extents`. The file's
+/// position is left wherever the search finished.
+#[cfg(target_os = "linux")]
+pub fn sparse_extents_of_file(metadata: &fs::Metadata, file: &fs::File) -> io::Result<Option<Vec<tar::sparse::SparseExtent>>> {
+    if !metadata.is_file() || metadata.blocks() * 512 >= metadata.len() {
+        return Ok(None);
+    }
+
+    let mut extents = Vec::new();
+    let mut offset = 0;
+
+    while offset < metadata.len() {
+        let data = match unsafe { libc::lseek(file.as_raw_fd(), offset as libc::off_t, libc::SEEK_DATA) } {
+            -1 => match io::Error::last_os_error() {
+                ref e if e.raw_os_error() == Some(libc::ENXIO) => break,
+                ref e if e.raw_os_error() == Some(libc::EINVAL) => return Ok(None),
+                e => return Err(e)
+            },
+            data => data as u64
+        };
+        let hole = match unsafe { libc::lseek(file.as_raw_fd(), data as libc::off_t, libc::SEEK_HOLE) } {
+            -1 => return Err(io::Error::last_os_error()),
+            hole => (hole as u64).min(metadata.len())
+        };
+
+        extents.push(tar::sparse::SparseExtent {
+            offset: data,
+            length: hole.saturating_sub(data)
+        });
+        offset = hole.max(data + 1);
+    }
+
+    match extents.as_slice() {
+        [only] if only.offset == 0 && only.length == metadata.len() => Ok(None),
+        _ => Ok(Some(extents))
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub use crate::fs::portable::sparse_extents_of_file;
+
+/// Determine the major and minor numbers of a character or block device.
+///
+/// Yields None for anything that isn't a device.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. Device numbers come with the
+/// rest of a file's metadata, and are split into their major and minor halves
+/// the way the platform's C library would. Platforms whose encoding we don't
+/// know yield `None`.
+#[cfg(any(target_os = "linux", target_os = "macos"))]
+pub fn get_device_numbers(metadata: &fs::Metadata, _path: &path::Path) -> Option<(u32, u32)> {
+    let file_type = metadata.file_type();
+
+    if !file_type.is_char_device() && !file_type.is_block_device() {
+        return None;
+    }
+
+    Some(split_device_number(metadata.rdev()))
+}
+
+#[cfg(target_os = "linux")]
+fn split_device_number(rdev: u64) -> (u32, u32) {
+    (nix::sys::stat::major(rdev) as u32, nix::sys::stat::minor(rdev) as u32)
+}
+
+#[cfg(target_os = "macos")]
+fn split_device_number(rdev: u64) -> (u32, u32) {
+    (((rdev >> 24) & 0xff) as u32, (rdev & 0xff_ffff) as u32)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos")))]
+pub use crate::fs::portable::get_device_numbers;
+
+/// Read the access ACL of a file, and the default ACL of a directory.
+///
+/// For more information, please see `rapidtar::fs::portable::get_acls`.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. On Linux, POSIX ACLs are read
+/// from the `system.posix_acl_access` and `system.posix_acl_default` extended
+/// attributes, and the users and groups they name are looked up; filesystems
+/// without ACL support have none. Other UNIX systems don't have POSIX ACLs,
+/// and report none.
+#[cfg(target_os = "linux")]
+pub fn get_acls(metadata: &fs::Metadata, path: &path::Path) -> io::Result<(Option<tar::acl::Acl>, Option<tar::acl::Acl>)> {
+    if metadata.file_type().is_symlink() {
+        return Ok((None, None));
+    }
+
+    let c_path = ffi::CString::new(path.as_os_str().as_bytes())?;
+
+    read_acls(metadata, |name, value, size| unsafe { libc::lgetxattr(c_path.as_ptr(), name, value, size) })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub use crate::fs::portable::get_acls;
+
+/// Read the access ACL of an already open file, and the default ACL of an
+/// already open directory.
+///
+/// For more information, please see
+/// `rapidtar::fs::portable::get_acls_of_file`.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. On Linux, ACLs are read from the
+/// open file's extended attributes, in the same manner as `get_acls`; `path`
+/// is not used. Other UNIX systems report none.
+#[cfg(target_os = "linux")]
+pub fn get_acls_of_file(metadata: &fs::Metadata, file: &fs::File, _path: &path::Path) -> io::Result<(Option<tar::acl::Acl>, Option<tar::acl::Acl>)> {
+    if metadata.file_type().is_symlink() {
+        return Ok((None, None));
+    }
+
+    let fd = file.as_raw_fd();
+
+    read_acls(metadata, |name, value, size| unsafe { libc::fgetxattr(fd, name, value, size) })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub use crate::fs::portable::get_acls_of_file;
+
+/// Read a file's ACLs with a `getxattr`-like function.
+#[cfg(target_os = "linux")]
+fn read_acls<F>(metadata: &fs::Metadata, getxattr: F) -> io::Result<(Option<tar::acl::Acl>, Option<tar::acl::Acl>)> where F: Fn(*const libc::c_char, *mut libc::c_void, libc::size_t) -> libc::ssize_t {
+    let access = match read_acl_xattr(&getxattr, "system.posix_acl_access")? {
+        Some(ref acl) if acl.entries.iter().all(|entry| entry.tag != tar::acl::AclTag::User && entry.tag != tar::acl::AclTag::Group) => None,
+        access => access
+    };
+    let default = match metadata.is_dir() {
+        true => read_acl_xattr(&getxattr, "system.posix_acl_default")?,
+        false => None
+    };
+
+    Ok((access, default))
+}
+
+/// Read an ACL stored as an extended attribute, naming the users and groups
+/// in it.
+#[cfg(target_os = "linux")]
+fn read_acl_xattr<F>(getxattr: &F, name: &str) -> io::Result<Option<tar::acl::Acl>> where F: Fn(*const libc::c_char, *mut libc::c_void, libc::size_t) -> libc::ssize_t {
+    let c_name = ffi::CString::new(name)?;
+    let absent = |e: &io::Error| e.raw_os_error() == Some(libc::ENODATA) || e.raw_os_error() == Some(libc::ENOTSUP);
+
+    let value = loop {
+        let size = getxattr(c_name.as_ptr(), ptr::null_mut(), 0);
+
+        if size < 0 {
+            return match io::Error::last_os_error() {
+                ref e if absent(e) => Ok(None),
+                e => Err(e)
+            };
+        }
+
+        let mut value = vec![0u8; size as usize];
+        let size = getxattr(c_name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len());
+
+        if size < 0 {
+            match io::Error::last_os_error() {
+                ref e if e.raw_os_error() == Some(libc::ERANGE) => continue,
+                ref e if absent(e) => return Ok(None),
+                e => return Err(e)
+            }
+        }
+
+        value.truncate(size as usize);
+        break value;
+    };
+
+    let mut acl = tar::acl::Acl::from_posix_xattr(&value)?;
+
+    for entry in acl.entries.iter_mut() {
+        entry.name = match (entry.tag, entry.id) {
+            (tar::acl::AclTag::User, Some(uid)) => user_name(uid).ok(),
+            (tar::acl::AclTag::Group, Some(gid)) => group_name(gid).ok(),
+            _ => None
+        };
+    }
+
+    Ok(Some(acl))
+}
+
+/// The `open_how` structure taken by `openat2`, which is newer than our libc.
+#[cfg(target_os = "linux")]
+#[repr(C)]
+struct open_how {
+    flags: u64,
+    mode: u64,
+    resolve: u64,
+}
+
+//openat2 shares the same syscall number on every architecture but Alpha.
+#[cfg(target_os = "linux")]
+const SYS_OPENAT2: libc::c_long = 437;
+#[cfg(target_os = "linux")]
+const RESOLVE_NO_MAGICLINKS: u64 = 0x02;
+#[cfg(target_os = "linux")]
+const RESOLVE_BENEATH: u64 = 0x08;
+
+/// Open a directory beneath another with `openat2`, without letting anything
+/// in the path lead outside of it.
+#[cfg(target_os = "linux")]
+fn open_dir_beneath(root: &fs::File, path: &ffi::CStr) -> io::Result<fs::File> {
+    let how = open_how {
+        flags: (libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC) as u64,
+        mode: 0,
+        resolve: RESOLVE_BENEATH | RESOLVE_NO_MAGICLINKS
+    };
+    let fd = unsafe { libc::syscall(SYS_OPENAT2, root.as_raw_fd(), path.as_ptr(), &how as *const open_how, mem::size_of::<open_how>()) };
+
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(unsafe { fs::File::from_raw_fd(fd as RawFd) })
+}
+
+/// Where a member is to be extracted: a directory beneath the extraction
+/// root, and the member's name within it.
+///
+/// For more information, please see
+/// `rapidtar::fs::portable::ResolvedMember`.
+///
+/// # Platform considerations
+///
+/// This is the Linux version of the type. The directory is held open, and the
+/// member is created relative to it with the `*at` family of calls, so links
+/// planted in it's parents after it was resolved can't redirect it.
+#[cfg(target_os = "linux")]
+pub struct ResolvedMember {
+    parent: fs::File,
+    name: ffi::CString
+}
+
+#[cfg(target_os = "linux")]
+impl ResolvedMember {
+    /// Remove whatever isn't a directory from the member's name.
+    fn unlink(&self) -> io::Result<()> {
+        if unsafe { libc::unlinkat(self.parent.as_raw_fd(), self.name.as_ptr(), 0) } != 0 {
+            let e = io::Error::last_os_error();
+
+            match e.raw_os_error() {
+                Some(libc::ENOENT) | Some(libc::EISDIR) => {},
+                _ => return Err(e)
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Open the member's name relative to it's directory.
+    fn open(&self, flags: libc::c_int, mode: u32) -> io::Result<fs::File> {
+        let fd = unsafe { libc::openat(self.parent.as_raw_fd(), self.name.as_ptr(), flags | libc::O_NOFOLLOW | libc::O_CLOEXEC, mode as libc::c_uint) };
+
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(unsafe { fs::File::from_raw_fd(fd) })
+    }
+
+    /// Create the member as a new, empty file with the given permissions,
+    /// replacing anything but a directory already there.
+    pub fn create_file(&self, mode: u32) -> io::Result<fs::File> {
+        self.unlink()?;
+        self.open(libc::O_WRONLY | libc::O_CREAT | libc::O_EXCL, mode)
+    }
+
+    /// Open the existing file at the member's name for writing, without
+    /// truncating it. Links are not followed.
+    pub fn open_file(&self) -> io::Result<fs::File> {
+        self.open(libc::O_WRONLY, 0)
+    }
+
+    /// Create the member as a directory with the given permissions.
+    ///
+    /// Directories which already exist are kept, and yield `false`.
+    pub fn create_dir(&self, mode: u32) -> io::Result<bool> {
+        if unsafe { libc::mkdirat(self.parent.as_raw_fd(), self.name.as_ptr(), mode as libc::mode_t) } == 0 {
+            return Ok(true);
+        }
+
+        let e = io::Error::last_os_error();
+
+        match e.raw_os_error() {
+            Some(libc::EEXIST) => self.open_dir().map(|_| false),
+            _ => Err(e)
+        }
+    }
+
+    /// Open the directory at the member's name, so that it's permissions and
+    /// times can be restored. Links are not followed.
+    pub fn open_dir(&self) -> io::Result<fs::File> {
+        self.open(libc::O_RDONLY | libc::O_DIRECTORY, 0)
+    }
+
+    /// Create the member as a symbolic link to `target`.
+    pub fn create_symlink(&self, target: &path::Path) -> io::Result<()> {
+        let target = ffi::CString::new(target.as_os_str().as_bytes())?;
+
+        self.unlink()?;
+
+        if unsafe { libc::symlinkat(target.as_ptr(), self.parent.as_raw_fd(), self.name.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    /// Create the member as a hard link to another, already extracted member.
+    pub fn create_hard_link(&self, original: &ResolvedMember) -> io::Result<()> {
+        self.unlink()?;
+
+        if unsafe { libc::linkat(original.parent.as_raw_fd(), original.name.as_ptr(), self.parent.as_raw_fd(), self.name.as_ptr(), 0) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+}
+
+/// Open a directory to create members in, without opening it for reading.
+#[cfg(target_os = "linux")]
+fn open_path_dir(path: &path::Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().read(true).custom_flags(libc::O_PATH | libc::O_DIRECTORY).open(path)
+}
+
+/// Resolve a member with the portable resolution, and open the directory it
+/// found.
+#[cfg(target_os = "linux")]
+fn resolve_beneath_portably(root: &path::Path, member: &path::Path) -> io::Result<ResolvedMember> {
+    let resolved = crate::fs::portable::resolve_beneath(root, member)?;
+
+    Ok(ResolvedMember {
+        parent: open_path_dir(&resolved.parent)?,
+        name: ffi::CString::new(resolved.name.into_vec())?
+    })
+}
+
+/// Find where a member belongs beneath the directory it's being extracted
+/// into, without letting symbolic links lead it anywhere else.
+///
+/// For more information, please see
+/// `rapidtar::fs::portable::resolve_beneath`.
+///
+/// # Platform considerations
+///
+/// This is the UNIX version of the function. On Linux, each of the member's
+/// parent directories is opened in turn with `openat2` and `RESOLVE_BENEATH`,
+/// so the kernel resolves any links along the way and refuses those leading
+/// outside of `root`. Directories which don't exist are created inside the
+/// one opened before them. The member's own directory is kept open, so
+/// nothing can be swapped out from under it between resolving and extracting
+/// the member.
+///
+/// Kernels older than 5.6 lack `openat2`, and some sandboxes refuse it. Those,
+/// and members whose missing parent directories are really dangling links,
+/// fall back to the portable, component by component resolution.
+#[cfg(target_os = "linux")]
+pub fn resolve_beneath(root: &path::Path, member: &path::Path) -> io::Result<ResolvedMember> {
+    let mut parents = crate::fs::portable::member_components(member)?;
+    let name = match parents.pop() {
+        Some(name) => ffi::CString::new(name.into_vec())?,
+        None => return Ok(ResolvedMember { parent: open_path_dir(root)?, name: ffi::CString::new(".")? })
+    };
+    let root_dir = open_path_dir(root)?;
+    let mut dir = root_dir.try_clone()?;
+
+    for depth in 1..=parents.len() {
+        let existing = ffi::CString::new(parents[..depth].iter().collect::<path::PathBuf>().into_os_string().into_vec())?;
+
+        dir = match open_dir_beneath(&root_dir, &existing) {
+            Ok(next) => next,
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOENT) => {
+                let missing = ffi::CString::new(parents[depth - 1].as_bytes())?;
+
+                if unsafe { libc::mkdirat(dir.as_raw_fd(), missing.as_ptr(), 0o777) } != 0 {
+                    return match io::Error::last_os_error() {
+                        //Something's already there, so it must be a dangling link.
+                        ref e if e.raw_os_error() == Some(libc::EEXIST) => resolve_beneath_portably(root, member),
+                        e => Err(e)
+                    };
+                }
+
+                open_dir_beneath(&root_dir, &existing)?
+            },
+            Err(ref e) if e.raw_os_error() == Some(libc::EXDEV) => return Err(crate::fs::portable::escapes_root_error()),
+            Err(ref e) if e.raw_os_error() == Some(libc::ENOSYS) || e.raw_os_error() == Some(libc::EPERM) => return resolve_beneath_portably(root, member),
+            Err(e) => return Err(e)
+        };
+    }
+
+    Ok(ResolvedMember { parent: dir, name: name })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub use crate::fs::portable::{resolve_beneath, ResolvedMember};
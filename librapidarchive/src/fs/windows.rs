@@ -1,20 +1,25 @@
 //! Windows-specific implementations of fs methods.
 
-use std::{io, fs, ffi, path, thread, time, ptr, mem};
+use std::{io, fs, ffi, path, time, ptr, mem};
 use std::cmp::PartialEq;
 use std::os::windows::io::AsRawHandle;
-use std::os::windows::ffi::OsStringExt;
-use winapi::um::{winbase, aclapi};
+use std::os::windows::fs::{OpenOptionsExt, MetadataExt};
+use std::os::windows::ffi::{OsStringExt, OsStrExt};
+use winapi::um::{winbase, aclapi, fileapi, minwinbase, securitybaseapi, ioapiset, winioctl};
 use winapi::um::accctrl::SE_FILE_OBJECT;
-use winapi::um::winnt::{WCHAR, PSID, OWNER_SECURITY_INFORMATION};
-use winapi::shared::winerror::{ERROR_MEDIA_CHANGED};
-use crate::{tape, spanning};
+use winapi::um::winnt::{WCHAR, PSID, PACL, OWNER_SECURITY_INFORMATION, GROUP_SECURITY_INFORMATION, DACL_SECURITY_INFORMATION, GENERIC_READ, GENERIC_WRITE, GENERIC_EXECUTE, GENERIC_ALL, FILE_WRITE_ATTRIBUTES, FILE_READ_DATA, FILE_WRITE_DATA, FILE_EXECUTE, SECURITY_MAX_SID_SIZE, WinWorldSid, FILE_ATTRIBUTE_SPARSE_FILE, ACE_HEADER, ACCESS_ALLOWED_ACE, ACCESS_ALLOWED_ACE_TYPE, INHERIT_ONLY_ACE, SID_NAME_USE, SidTypeUser};
+use winapi::shared::minwindef::FILETIME;
+use winapi::shared::winerror::{ERROR_SUCCESS, ERROR_MORE_DATA};
+use crate::tar;
+#[cfg(feature = "tape")]
+use crate::tape;
+#[cfg(feature = "tape")]
 use crate::tape::windows::WindowsTapeDevice;
-use crate::blocking::BlockingWriter;
-use crate::concurrentbuf::ConcurrentWriteBuffer;
 use crate::tuning::Configuration;
+use crate::fs::portable::assemble_sink;
+use crate::cancel::CancellationToken;
 
-pub use crate::fs::portable::{ArchivalSink, get_unix_mode, get_file_type};
+pub use crate::fs::portable::{ArchivalSink, ArchivalSource, build_pipeline, build_tape_pipeline, tape_record_size, open_sink_at, SharedDrive, open_drive_sink, SinkCapabilities, is_standard_stream, seek_archive_file, AtimePolicy, ArchivalFile, FileId, process_umask, utc_offset, get_file_type, is_nodump, get_project_id, get_project_id_of_file, set_project_id, get_apple_metadata, hard_link_identity, get_device_numbers, resolve_beneath, ResolvedMember, set_file_mode};
 
 /// Open a sink object for writing an archive (aka "tape").
 /// 
@@ -23,60 +28,88 @@ pub use crate::fs::portable::{ArchivalSink, get_unix_mode, get_file_type};
 /// # Platform considerations
 /// 
 /// This is the Windows version of the function. It supports writes to files
-/// and tape devices.
-pub fn open_sink<P: AsRef<path::Path>, I>(outfile: P, tuning: &Configuration, limit: Option<u64>) -> io::Result<Box<ArchivalSink<I>>> where ffi::OsString: From<P>, P: Clone, I: 'static + Send + Clone + PartialEq {
-    let mut is_tape = false;
-    
+/// and tape devices. Without the `tape` feature, tape devices are written to
+/// as if they were files.
+pub fn open_sink<P: AsRef<path::Path>, I>(outfile: P, tuning: &Configuration, limit: Option<u64>, cancel: &CancellationToken) -> io::Result<Box<ArchivalSink<I>>> where ffi::OsString: From<P>, P: Clone, I: 'static + Send + Clone + PartialEq {
+    #[cfg(feature = "tape")]
     {
-        let path = outfile.as_ref();
-        for component in path.components() {
-            if let path::Component::Prefix(prefix) = component {
-                if let path::Prefix::DeviceNS(device_name) = prefix.kind() {
-                    if let Some(device_name) = device_name.to_str() {
-                        if device_name.starts_with("TAPE") {
-                            is_tape = true;
-                        }
-                    }
-                }
-            }
+        if is_tape_path(outfile.as_ref()) {
+            let options = tape::TapeOptions { fallback_block_size: tuning.tape_fallback_block_size, ..tape::TapeOptions::default() };
+            let tape = WindowsTapeDevice::open_device(&ffi::OsString::from(outfile), &options)?;
+            let record_size = tape_record_size(&tape, tuning);
+
+            return assemble_sink(tape, Some(record_size), tuning, limit, cancel);
         }
     }
-    
-    //Windows does this fun thing where tape devices throw an error if you've
-    //changed the media out, so we absorb up to five of these spurious errors
-    //when opening up a new tape
-    let mut notfound_count = 0;
-    
-    if is_tape {
-        loop {
-            match WindowsTapeDevice::open_device(&ffi::OsString::from(outfile.clone())) {
-                Ok(tape) => return match limit {
-                    Some(limit) => Ok(Box::new(spanning::LimitingWriter::wrap(BlockingWriter::new_with_factor(ConcurrentWriteBuffer::new(tape, tuning.serial_buffer_limit), tuning.blocking_factor), limit))),
-                    None => Ok(Box::new(BlockingWriter::new_with_factor(ConcurrentWriteBuffer::new(tape, tuning.serial_buffer_limit), tuning.blocking_factor)))
-                },
-                Err(e) => {
-                    match e.raw_os_error() {
-                        Some(errcode) if errcode == ERROR_MEDIA_CHANGED as i32 => {
-                            notfound_count += 1;
-                        },
-                        Some(_) => return Err(e),
-                        None => return Err(e)
-                    }
 
-                    if notfound_count > 5 {
-                        return Err(e);
+    let file = fs::File::create(outfile.as_ref())?;
+
+    assemble_sink(file, None, tuning, limit, cancel)
+}
+
+/// Open a drive so that it can be shared by several archives.
+///
+/// For more information, please see `rapidtar::fs::portable::open_drive`.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. Tape devices are shared,
+/// unless the `tape` feature is disabled.
+#[allow(unused_variables)]
+pub fn open_drive<P: AsRef<path::Path>, I>(outfile: P, tuning: &Configuration) -> io::Result<Option<SharedDrive<I>>> where ffi::OsString: From<P>, P: Clone, I: 'static + Send + Clone + PartialEq {
+    #[cfg(feature = "tape")]
+    {
+        if is_tape_path(outfile.as_ref()) {
+            let options = tape::TapeOptions { fallback_block_size: tuning.tape_fallback_block_size, ..tape::TapeOptions::default() };
+            let tape = WindowsTapeDevice::open_device(&ffi::OsString::from(outfile), &options)?;
+
+            return Ok(Some(SharedDrive::new(Box::new(tape))));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Determine if a path names a tape device in the `\\.\TAPEn` namespace.
+#[cfg(feature = "tape")]
+fn is_tape_path(path: &path::Path) -> bool {
+    for component in path.components() {
+        if let path::Component::Prefix(prefix) = component {
+            if let path::Prefix::DeviceNS(device_name) = prefix.kind() {
+                if let Some(device_name) = device_name.to_str() {
+                    if device_name.starts_with("TAPE") {
+                        return true;
                     }
                 }
             }
         }
-    } else {
-        let file = fs::File::create(outfile.as_ref())?;
-        
-        match limit {
-            Some(limit) => Ok(Box::new(spanning::LimitingWriter::wrap(ConcurrentWriteBuffer::new(file, tuning.serial_buffer_limit), limit))),
-            None => Ok(Box::new(ConcurrentWriteBuffer::new(file, tuning.serial_buffer_limit)))
-        }
     }
+
+    false
+}
+
+/// Open a source object for reading an archive back.
+///
+/// For more information, please see `rapidtar::fs::portable::open_source`.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. It supports reads from files,
+/// standard input, and tape devices. Without the `tape` feature, tape devices
+/// are read as if they were files.
+pub fn open_source<P: AsRef<path::Path>>(infile: P) -> io::Result<Box<ArchivalSource>> where ffi::OsString: From<P>, P: Clone {
+    if is_standard_stream(infile.as_ref()) {
+        return Ok(Box::new(io::stdin()));
+    }
+
+    #[cfg(feature = "tape")]
+    if is_tape_path(infile.as_ref()) {
+        let tape : WindowsTapeDevice<u64> = WindowsTapeDevice::open_device(&ffi::OsString::from(infile), &tape::TapeOptions::default())?;
+
+        return Ok(Box::new(tape));
+    }
+
+    Ok(Box::new(fs::File::open(infile.as_ref())?))
 }
 
 /// Open an object for total control of a tape device.
@@ -85,37 +118,16 @@ pub fn open_sink<P: AsRef<path::Path>, I>(outfile: P, tuning: &Configuration, li
 /// 
 /// This is the Windows version of the function. It implements tape control for
 /// all tape devices in the `\\.\TAPEn` namespace.
-pub fn open_tape<P: AsRef<path::Path>>(tapedev: P) -> io::Result<Box<tape::TapeDevice>> where ffi::OsString: From<P>, P: Clone {
-    //Windows does this fun thing where it pretends tape devices don't exist
-    //sometimes, so we ignore up to 5 file/path not found errors before actually
-    //forwarding one along
-    let notfound_count = 0;
-    
-    loop {
-        match WindowsTapeDevice::<u64>::open_device(&ffi::OsString::from(tapedev.clone())) {
-            Ok(tape) => {
-                return Ok(Box::new(tape));
-            }
-            Err(e) => {
-                match e.raw_os_error() {
-                    Some(errcode) => {
-                        if errcode == 2 || errcode == 3 {
-                            if notfound_count > 5 {
-                                return Err(e);
-                            }
-                            
-                            thread::sleep(time::Duration::from_millis(10));
-                        } else {
-                            return Err(e);
-                        }
-                    },
-                    None => return Err(e)
-                }
-            }
-        }
-    }
+#[cfg(feature = "tape")]
+pub fn open_tape<P: AsRef<path::Path>>(tapedev: P, options: &tape::TapeOptions) -> io::Result<Box<tape::TapeDevice>> where ffi::OsString: From<P>, P: Clone {
+    let tape = WindowsTapeDevice::<u64>::open_device(&ffi::OsString::from(tapedev), options)?;
+
+    Ok(Box::new(tape))
 }
 
+#[cfg(not(feature = "tape"))]
+pub use crate::fs::portable::open_tape;
+
 fn conv_wcstr_to_ruststr(wcstr: &[WCHAR]) -> Option<String> {
     //Rust has no facility for wide cstr conversion so we'll make our own
     let mut lookup_name_length = 0;
@@ -135,7 +147,9 @@ fn conv_wcstr_to_ruststr(wcstr: &[WCHAR]) -> Option<String> {
     Some(ffi::OsString::from_wide(&wcstr[..lookup_name_length]).to_string_lossy().into_owned())
 }
 
-fn lookup_name_of_sid(sid: PSID) -> io::Result<(String, String)> {
+/// Look up the name and domain of the account a SID belongs to, and what
+/// sort of account it is.
+fn lookup_name_of_sid(sid: PSID) -> io::Result<(String, String, SID_NAME_USE)> {
     let mut principalname;
     let mut principaldomain;
     let mut lookup_name_buffer : Vec<WCHAR> = Vec::with_capacity(256);
@@ -192,7 +206,100 @@ fn lookup_name_of_sid(sid: PSID) -> io::Result<(String, String)> {
         break;
     }
 
-    Ok((principalname, principaldomain))
+    Ok((principalname, principaldomain, lookup_use))
+}
+
+/// Determine which of read, write, and execute a DACL grants to a SID, as the
+/// lowest three bits of a UNIX mode.
+unsafe fn effective_mode_bits(dacl: PACL, sid: PSID) -> Option<u32> {
+    let mut trustee = mem::zeroed();
+    let mut rights = 0;
+
+    aclapi::BuildTrusteeWithSidW(&mut trustee, sid);
+
+    if aclapi::GetEffectiveRightsFromAclW(dacl, &mut trustee, &mut rights) != ERROR_SUCCESS {
+        return None;
+    }
+
+    let mut bits = 0;
+
+    if rights & (FILE_READ_DATA | GENERIC_READ | GENERIC_ALL) != 0 {
+        bits |= 0o4;
+    }
+
+    if rights & (FILE_WRITE_DATA | GENERIC_WRITE | GENERIC_ALL) != 0 {
+        bits |= 0o2;
+    }
+
+    if rights & (FILE_EXECUTE | GENERIC_EXECUTE | GENERIC_ALL) != 0 {
+        bits |= 0o1;
+    }
+
+    Some(bits)
+}
+
+/// Given a directory entry, produce plausible Unix mode bits for it.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. It asks the file's DACL what
+/// access it grants to the file's owner, the file's group, and Everyone, and
+/// reports those as the user, group, and other bits. For directories, the
+/// rights to list, add files to, and traverse the directory share their bits
+/// with read, write, and execute, just as on UNIX. Files marked read-only lose
+/// their write bits regardless of what the DACL says.
+///
+/// A file without a DACL grants everyone full access, and so reports 777, as
+/// do symbolic links and junctions, like their UNIX counterparts. If the
+/// security descriptor can't be read, we fall back to the fabricated mode
+/// bits of `fs::portable::get_unix_mode`.
+pub fn get_unix_mode(metadata: &fs::Metadata, path: &path::Path) -> io::Result<u32> {
+    if metadata.file_type().is_symlink() {
+        return Ok(0o777);
+    }
+
+    let fallback = crate::fs::portable::get_unix_mode(metadata, path);
+    let mut wide_path : Vec<u16> = path.as_os_str().encode_wide().collect();
+    let mut owner_sid = ptr::null_mut();
+    let mut group_sid = ptr::null_mut();
+    let mut dacl = ptr::null_mut();
+    let mut security_descriptor = ptr::null_mut();
+
+    wide_path.push(0);
+
+    let status = unsafe { aclapi::GetNamedSecurityInfoW(wide_path.as_ptr(), SE_FILE_OBJECT, OWNER_SECURITY_INFORMATION | GROUP_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION, &mut owner_sid, &mut group_sid, &mut dacl, ptr::null_mut(), &mut security_descriptor) };
+    if status != ERROR_SUCCESS {
+        return fallback;
+    }
+
+    let mut world_sid = [0u8; SECURITY_MAX_SID_SIZE];
+    let mut world_sid_size = SECURITY_MAX_SID_SIZE as u32;
+    let has_world_sid = unsafe { securitybaseapi::CreateWellKnownSid(WinWorldSid, ptr::null_mut(), world_sid.as_mut_ptr() as PSID, &mut world_sid_size) } != 0;
+
+    let bits = match dacl.is_null() {
+        true => Some(0o777),
+        false => unsafe {
+            let owner = effective_mode_bits(dacl, owner_sid);
+            let group = effective_mode_bits(dacl, group_sid);
+            let world = match has_world_sid {
+                true => effective_mode_bits(dacl, world_sid.as_mut_ptr() as PSID),
+                false => None
+            };
+
+            match (owner, group, world) {
+                (Some(owner), Some(group), Some(world)) => Some(owner << 6 | group << 3 | world),
+                _ => None
+            }
+        }
+    };
+
+    unsafe { winbase::LocalFree(security_descriptor) };
+
+    match bits {
+        Some(bits) if metadata.permissions().readonly() => Ok(bits & !0o222),
+        Some(bits) => Ok(bits),
+        None => fallback
+    }
 }
 
 /// Determine the UNIX owner ID and name for a given file.
@@ -201,7 +308,8 @@ fn lookup_name_of_sid(sid: PSID) -> io::Result<(String, String)> {
 ///
 /// This is the Windows version of the function. It queries the file's security
 /// descriptor to obtain the file owner's SID, and then reports the name
-/// attached to the SID.
+/// attached to the SID. Symbolic links and junctions report their own owner,
+/// not that of their target.
 /// 
 /// The UID is always reported as 65534, which is `nobody` at least on Linux.
 /// It may make sense to instead report the Relative SID, which is numerical and
@@ -211,7 +319,7 @@ fn lookup_name_of_sid(sid: PSID) -> io::Result<(String, String)> {
 /// GNU tar on Windows appears to report some kind of UID, but the UIDs it puts
 /// in the tar header don't appear to have any relation to Windows SIDs.
 pub fn get_unix_owner(_metadata: &fs::Metadata, path: &path::Path) -> io::Result<(u32, String)> {
-    let file = fs::File::open(path)?;
+    let file = open_without_following(path)?;
     let nt_handle = file.as_raw_handle();
     let mut owner_sid = unsafe { mem::zeroed() };
     let mut security_descriptor = unsafe { mem::zeroed() };
@@ -233,7 +341,8 @@ pub fn get_unix_owner(_metadata: &fs::Metadata, path: &path::Path) -> io::Result
 ///
 /// This is the Windows version of the function. It queries the file's security
 /// descriptor to obtain the file group's SID, and then reports the name
-/// attached to the SID.
+/// attached to the SID. Symbolic links and junctions report their own group,
+/// not that of their target.
 /// 
 /// The GID is always reported as 65534, which is `nogroup` at least on Linux.
 /// It may make sense to instead report the Relative SID, which is numerical and
@@ -243,7 +352,7 @@ pub fn get_unix_owner(_metadata: &fs::Metadata, path: &path::Path) -> io::Result
 /// GNU tar on Windows appears to report some kind of GID, but the GIDs it puts
 /// in the tar header don't appear to have any relation to Windows SIDs.
 pub fn get_unix_group(_metadata: &fs::Metadata, path: &path::Path) -> io::Result<(u32, String)> {
-    let file = fs::File::open(path)?;
+    let file = open_without_following(path)?;
     let nt_handle = file.as_raw_handle();
     let mut group_sid = unsafe { mem::zeroed() };
     let mut security_descriptor = unsafe { mem::zeroed() };
@@ -257,4 +366,394 @@ pub fn get_unix_group(_metadata: &fs::Metadata, path: &path::Path) -> io::Result
     }
     
     Ok((0, grouplookup.0))
-}
\ No newline at end of file
+}
+
+/// Open any file, directory, or link for querying it's metadata, without
+/// following a symbolic link or junction in it's place.
+fn open_without_following(path: &path::Path) -> io::Result<fs::File> {
+    fs::OpenOptions::new().read(true).custom_flags(winbase::FILE_FLAG_BACKUP_SEMANTICS | winbase::FILE_FLAG_OPEN_REPARSE_POINT).open(path)
+}
+
+/// Determine if a link found within a traversal should be followed, rather
+/// than archived as a link.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. Symbolic links and junctions
+/// are never followed: Windows places junctions that loop back onto their
+/// own parents in every user profile, and links elsewhere commonly point into
+/// directories the archiving user can't read. Both are archived as symbolic
+/// links instead. Other kinds of reparse points, such as deduplicated or
+/// cloud-backed files, are not links, and are archived as their contents.
+pub fn is_followed_link(_file_type: &fs::FileType) -> bool {
+    false
+}
+
+/// Read the target of a symbolic link, in the form it should be archived.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. Symbolic links and junctions
+/// both have targets; they are converted to forward slashes, and absolute
+/// targets lose their verbatim prefix. See `tar::windows_link_target`.
+pub fn read_link_target(path: &path::Path) -> io::Result<path::PathBuf> {
+    let target = fs::read_link(path)?;
+
+    Ok(path::PathBuf::from(tar::windows_link_target(&target.to_string_lossy())))
+}
+
+/// Open a file whose contents are to be archived.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. Files read under either the
+/// `Replace` or `System` policies are opened with permission to write their
+/// attributes, falling back to read-only access if that isn't allowed. Under
+/// the `System` policy, the handle is then told not to update the file's
+/// access time at all.
+pub fn open_source_file(path: &path::Path, atime: AtimePolicy) -> io::Result<fs::File> {
+    open_source_file_with_flags(path, atime, 0)
+}
+
+/// Open a file whose contents are to be archived, without following a
+/// symbolic link in it's place.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. Files are opened with
+/// `FILE_FLAG_OPEN_REPARSE_POINT`, so that a symbolic link or junction is
+/// opened itself, rather than it's target. Access times are avoided in the
+/// same manner as `open_source_file`.
+pub fn open_source_file_nofollow(path: &path::Path, atime: AtimePolicy) -> io::Result<fs::File> {
+    open_source_file_with_flags(path, atime, winbase::FILE_FLAG_OPEN_REPARSE_POINT)
+}
+
+fn open_source_file_with_flags(path: &path::Path, atime: AtimePolicy, flags: u32) -> io::Result<fs::File> {
+    if atime == AtimePolicy::Ignore {
+        return fs::OpenOptions::new().read(true).custom_flags(flags).open(path);
+    }
+
+    let file = match fs::OpenOptions::new().read(true).access_mode(GENERIC_READ | FILE_WRITE_ATTRIBUTES).custom_flags(flags).open(path) {
+        Ok(file) => file,
+        Err(_) => return fs::OpenOptions::new().read(true).custom_flags(flags).open(path)
+    };
+
+    if atime == AtimePolicy::System {
+        //A time of all ones tells Windows to stop updating that time for as
+        //long as the handle is open.
+        let never = FILETIME {
+            dwLowDateTime: 0xFFFFFFFF,
+            dwHighDateTime: 0xFFFFFFFF
+        };
+
+        unsafe { fileapi::SetFileTime(file.as_raw_handle() as *mut winapi::ctypes::c_void, ptr::null(), &never, ptr::null()) };
+    }
+
+    Ok(file)
+}
+
+/// Change the access time of an open file, leaving it's other times alone.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. The file must have been
+/// opened with permission to write it's attributes (see `open_source_file`).
+pub fn set_file_atime(file: &fs::File, atime: time::SystemTime) -> io::Result<()> {
+    set_file_times(file, Some(atime), None, None)
+}
+
+/// Convert a time into a `FILETIME`, truncating it to 100ns precision.
+fn to_filetime(time: time::SystemTime) -> FILETIME {
+    //FILETIMEs count 100ns intervals since 1601, which is this many seconds
+    //before the UNIX epoch.
+    const EPOCH_DIFFERENCE : i64 = 11_644_473_600;
+
+    let ticks = match time.duration_since(time::UNIX_EPOCH) {
+        Ok(since) => (since.as_secs() as i64 + EPOCH_DIFFERENCE) * 10_000_000 + (since.subsec_nanos() / 100) as i64,
+        Err(before) => {
+            let before = before.duration();
+
+            (EPOCH_DIFFERENCE - before.as_secs() as i64) * 10_000_000 - (before.subsec_nanos() / 100) as i64
+        }
+    };
+
+    FILETIME {
+        dwLowDateTime: ticks as u32,
+        dwHighDateTime: (ticks >> 32) as u32
+    }
+}
+
+/// Change the access, modification, and creation times of an open file, at
+/// full precision. Times given as `None` are left alone.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. All three times are set in a
+/// single call to `SetFileTime`, at the 100ns precision NTFS stores. The file
+/// must have been opened with permission to write it's attributes.
+pub fn set_file_times(file: &fs::File, atime: Option<time::SystemTime>, mtime: Option<time::SystemTime>, birthtime: Option<time::SystemTime>) -> io::Result<()> {
+    let atime = atime.map(to_filetime);
+    let mtime = mtime.map(to_filetime);
+    let birthtime = birthtime.map(to_filetime);
+    let as_ptr = |filetime: &Option<FILETIME>| filetime.as_ref().map_or(ptr::null(), |ft| ft as *const FILETIME);
+
+    if unsafe { fileapi::SetFileTime(file.as_raw_handle() as *mut winapi::ctypes::c_void, as_ptr(&birthtime), as_ptr(&atime), as_ptr(&mtime)) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Determine how many files this process may have open at once.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. Files are opened as kernel
+/// handles, which are limited only by the system-wide handle quota (about 16
+/// million per process), so this yields `None`.
+pub fn open_file_limit() -> Option<usize> {
+    None
+}
+
+/// Determine when a file's metadata or contents last changed.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. Windows tracks change times,
+/// but only exposes them through an open handle, so the file is opened to
+/// query them. Yields `None` if the file can't be opened.
+pub fn get_change_time(metadata: &fs::Metadata, path: &path::Path) -> Option<time::SystemTime> {
+    let file = match metadata.is_dir() || metadata.file_type().is_symlink() {
+        true => open_without_following(path),
+        false => fs::File::open(path)
+    }.ok()?;
+    let mut info : fileapi::FILE_BASIC_INFO = unsafe { mem::zeroed() };
+
+    if unsafe { winbase::GetFileInformationByHandleEx(file.as_raw_handle() as *mut winapi::ctypes::c_void, minwinbase::FileBasicInfo, &mut info as *mut _ as *mut winapi::ctypes::c_void, mem::size_of::<fileapi::FILE_BASIC_INFO>() as u32) } == 0 {
+        return None;
+    }
+
+    //FILETIMEs count 100ns intervals since 1601, which is this many seconds
+    //before the UNIX epoch.
+    const EPOCH_DIFFERENCE : u64 = 11_644_473_600;
+
+    let ticks = unsafe { *info.ChangeTime.QuadPart() } as u64;
+    let since_1601 = time::Duration::new(ticks / 10_000_000, ((ticks % 10_000_000) * 100) as u32);
+
+    Some(time::UNIX_EPOCH - time::Duration::from_secs(EPOCH_DIFFERENCE) + since_1601)
+}
+
+/// Determine the identity of a file, for telling when two paths lead to the
+/// same file.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. Files are identified by their
+/// volume serial number and file index, which are only exposed through an
+/// open handle. The handle is opened without any access rights, so that files
+/// locked by other processes can still be identified.
+pub fn file_identity(_metadata: &fs::Metadata, path: &path::Path) -> io::Result<FileId> {
+    let file = fs::OpenOptions::new().access_mode(0).custom_flags(winbase::FILE_FLAG_BACKUP_SEMANTICS).open(path)?;
+    let mut info : fileapi::BY_HANDLE_FILE_INFORMATION = unsafe { mem::zeroed() };
+
+    if unsafe { fileapi::GetFileInformationByHandle(file.as_raw_handle() as *mut winapi::ctypes::c_void, &mut info) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    Ok(FileId::new(info.dwVolumeSerialNumber as u64, (info.nFileIndexHigh as u64) << 32 | info.nFileIndexLow as u64))
+}
+
+/// One extent of allocated data, as reported by `FSCTL_QUERY_ALLOCATED_RANGES`.
+#[repr(C)]
+#[derive(Copy, Clone, Default)]
+struct FileAllocatedRangeBuffer {
+    file_offset: i64,
+    length: i64,
+}
+
+/// Find the data extents of a sparse file, in order.
+///
+/// Yields None for files without any holes, and anything that isn't a
+/// regular file.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. Only files marked sparse are
+/// opened, and their allocated ranges queried with
+/// `FSCTL_QUERY_ALLOCATED_RANGES`.
+pub fn sparse_extents(metadata: &fs::Metadata, path: &path::Path) -> io::Result<Option<Vec<tar::sparse::SparseExtent>>> {
+    if !metadata.is_file() || metadata.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE == 0 {
+        return Ok(None);
+    }
+
+    let file = fs::File::open(path)?;
+
+    sparse_extents_of_file(metadata, &file)
+}
+
+/// Find the data extents of an already open sparse file, in order.
+///
+/// For more information, please see
+/// `rapidtar::fs::portable::sparse_extents_of_file`.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. The open file's allocated
+/// ranges are queried in the same manner as `sparse_extents`.
+pub fn sparse_extents_of_file(metadata: &fs::Metadata, file: &fs::File) -> io::Result<Option<Vec<tar::sparse::SparseExtent>>> {
+    if !metadata.is_file() || metadata.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE == 0 {
+        return Ok(None);
+    }
+
+    let mut extents = Vec::new();
+    let mut query = FileAllocatedRangeBuffer {
+        file_offset: 0,
+        length: metadata.len() as i64
+    };
+    let mut ranges = vec![FileAllocatedRangeBuffer::default(); 512];
+
+    loop {
+        let mut returned = 0;
+        let succeeded = unsafe { ioapiset::DeviceIoControl(file.as_raw_handle() as *mut winapi::ctypes::c_void, winioctl::FSCTL_QUERY_ALLOCATED_RANGES, &mut query as *mut _ as *mut winapi::ctypes::c_void, mem::size_of::<FileAllocatedRangeBuffer>() as u32, ranges.as_mut_ptr() as *mut winapi::ctypes::c_void, (ranges.len() * mem::size_of::<FileAllocatedRangeBuffer>()) as u32, &mut returned, ptr::null_mut()) } != 0;
+        let error = io::Error::last_os_error();
+
+        if !succeeded && error.raw_os_error() != Some(ERROR_MORE_DATA as i32) {
+            return Err(error);
+        }
+
+        let count = returned as usize / mem::size_of::<FileAllocatedRangeBuffer>();
+
+        for range in ranges[..count].iter() {
+            extents.push(tar::sparse::SparseExtent {
+                offset: range.file_offset as u64,
+                length: range.length as u64
+            });
+        }
+
+        match (succeeded, ranges[..count].last()) {
+            (false, Some(last)) => {
+                let end = last.file_offset + last.length;
+
+                query.length -= end - query.file_offset;
+                query.file_offset = end;
+            },
+            _ => break
+        }
+    }
+
+    Ok(Some(extents))
+}
+
+
+/// Read the access ACL of a file, and the default ACL of a directory.
+///
+/// For more information, please see `rapidtar::fs::portable::get_acls`.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. DACLs don't map exactly onto
+/// POSIX ACLs, so an approximate access ACL is built from the file's DACL: the
+/// owner, group, and other entries get the permissions `get_unix_mode` reports,
+/// and every other account the DACL grants access to becomes a named user or
+/// group entry with the rights it effectively has. The mask covers every named
+/// entry and the group. DACLs that grant nobody else access yield no ACL, and
+/// inheritable entries are not mapped onto default ACLs. Symbolic links and
+/// junctions have no ACLs.
+pub fn get_acls(metadata: &fs::Metadata, path: &path::Path) -> io::Result<(Option<tar::acl::Acl>, Option<tar::acl::Acl>)> {
+    if metadata.file_type().is_symlink() {
+        return Ok((None, None));
+    }
+
+    let mut wide_path : Vec<u16> = path.as_os_str().encode_wide().collect();
+    let mut owner_sid = ptr::null_mut();
+    let mut group_sid = ptr::null_mut();
+    let mut dacl = ptr::null_mut();
+    let mut security_descriptor = ptr::null_mut();
+
+    wide_path.push(0);
+
+    let status = unsafe { aclapi::GetNamedSecurityInfoW(wide_path.as_ptr(), SE_FILE_OBJECT, OWNER_SECURITY_INFORMATION | GROUP_SECURITY_INFORMATION | DACL_SECURITY_INFORMATION, &mut owner_sid, &mut group_sid, &mut dacl, ptr::null_mut(), &mut security_descriptor) };
+    if status != ERROR_SUCCESS {
+        return Err(io::Error::from_raw_os_error(status as i32));
+    }
+
+    let mut world_sid = [0u8; SECURITY_MAX_SID_SIZE];
+    let mut world_sid_size = SECURITY_MAX_SID_SIZE as u32;
+    let has_world_sid = unsafe { securitybaseapi::CreateWellKnownSid(WinWorldSid, ptr::null_mut(), world_sid.as_mut_ptr() as PSID, &mut world_sid_size) } != 0;
+    let mut named : Vec<tar::acl::AclEntry> = Vec::new();
+
+    if !dacl.is_null() {
+        for index in 0..unsafe { (*dacl).AceCount } {
+            unsafe {
+                let mut ace = ptr::null_mut();
+
+                if securitybaseapi::GetAce(dacl, index as u32, &mut ace) == 0 {
+                    continue;
+                }
+
+                let header = &*(ace as *const ACE_HEADER);
+
+                if header.AceType != ACCESS_ALLOWED_ACE_TYPE || header.AceFlags & INHERIT_ONLY_ACE != 0 {
+                    continue;
+                }
+
+                let sid = &(*(ace as *const ACCESS_ALLOWED_ACE)).SidStart as *const u32 as PSID;
+
+                if securitybaseapi::EqualSid(sid, owner_sid) != 0 || securitybaseapi::EqualSid(sid, group_sid) != 0 || (has_world_sid && securitybaseapi::EqualSid(sid, world_sid.as_mut_ptr() as PSID) != 0) {
+                    continue;
+                }
+
+                let (name, _, sid_use) = match lookup_name_of_sid(sid) {
+                    Ok(lookup) => lookup,
+                    Err(_) => continue
+                };
+                let tag = if sid_use == SidTypeUser { tar::acl::AclTag::User } else { tar::acl::AclTag::Group };
+
+                if named.iter().any(|entry| entry.tag == tag && entry.name.as_ref() == Some(&name)) {
+                    continue;
+                }
+
+                named.push(tar::acl::AclEntry {
+                    tag: tag,
+                    name: Some(name),
+                    id: None,
+                    permissions: effective_mode_bits(dacl, sid).unwrap_or(0)
+                });
+            }
+        }
+    }
+
+    unsafe { winbase::LocalFree(security_descriptor) };
+
+    if named.is_empty() {
+        return Ok((None, None));
+    }
+
+    let mode = get_unix_mode(metadata, path)?;
+    let mask = named.iter().fold((mode >> 3) & 0o7, |mask, entry| mask | entry.permissions);
+    let object = |tag, permissions| tar::acl::AclEntry { tag: tag, name: None, id: None, permissions: permissions };
+    let mut entries = vec![object(tar::acl::AclTag::UserObj, (mode >> 6) & 0o7)];
+
+    entries.extend(named.iter().filter(|entry| entry.tag == tar::acl::AclTag::User).cloned());
+    entries.push(object(tar::acl::AclTag::GroupObj, (mode >> 3) & 0o7));
+    entries.extend(named.iter().filter(|entry| entry.tag == tar::acl::AclTag::Group).cloned());
+    entries.push(object(tar::acl::AclTag::Mask, mask));
+    entries.push(object(tar::acl::AclTag::Other, mode & 0o7));
+
+    Ok((Some(tar::acl::Acl { entries: entries }), None))
+}
+
+/// Read the access ACL of an already open file, and the default ACL of an
+/// already open directory.
+///
+/// For more information, please see
+/// `rapidtar::fs::portable::get_acls_of_file`.
+///
+/// # Platform considerations
+///
+/// This is the Windows version of the function. The file's security
+/// information is read by path, in the same manner as `get_acls`, so it may
+/// not belong to the file which is open if the path was since replaced.
+pub fn get_acls_of_file(metadata: &fs::Metadata, _file: &fs::File, path: &path::Path) -> io::Result<(Option<tar::acl::Acl>, Option<tar::acl::Acl>)> {
+    get_acls(metadata, path)
+}
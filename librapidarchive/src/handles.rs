@@ -0,0 +1,120 @@
+//! A budget on how many files are open at once.
+//!
+//! Every traversal thread may hold a directory open while listing it, or a
+//! file open while reading ahead into it, so a large `parallel_io_limit` can
+//! exceed the number of files the process is allowed to have open. Rather
+//! than failing with a storm of `EMFILE` errors, opens wait for a slot in a
+//! process-wide `HandleBudget`, sized from the platform's limit (see
+//! `fs::open_file_limit`) with some headroom left over for archive devices,
+//! spill files, and the like.
+//!
+//! Holders of a slot must not wait for another one, or they may deadlock.
+
+use std::sync::{Mutex, Condvar, OnceLock};
+use crate::fs::open_file_limit;
+
+/// How many open files to leave out of the budget, at minimum.
+const RESERVED_HANDLES: usize = 32;
+
+/// A counting semaphore over open files.
+pub struct HandleBudget {
+    used: Mutex<usize>,
+    released: Condvar,
+    limit: Option<usize>,
+}
+
+/// A slot in a `HandleBudget`, which is returned when dropped.
+pub struct HandleGuard<'a> {
+    budget: &'a HandleBudget,
+}
+
+impl HandleBudget {
+    /// Create a budget allowing `limit` files open at once, or any number if
+    /// `None`.
+    pub fn new(limit: Option<usize>) -> HandleBudget {
+        HandleBudget {
+            used: Mutex::new(0),
+            released: Condvar::new(),
+            limit: limit.map(|limit| limit.max(1))
+        }
+    }
+
+    /// Wait for a free slot, and take it.
+    pub fn acquire(&self) -> HandleGuard<'_> {
+        let mut used = self.used.lock().unwrap();
+
+        if let Some(limit) = self.limit {
+            while *used >= limit {
+                used = self.released.wait(used).unwrap();
+            }
+        }
+
+        *used += 1;
+
+        HandleGuard {
+            budget: self
+        }
+    }
+
+    /// How many slots are currently taken.
+    pub fn used(&self) -> usize {
+        *self.used.lock().unwrap()
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+}
+
+impl<'a> Drop for HandleGuard<'a> {
+    fn drop(&mut self) {
+        *self.budget.used.lock().unwrap() -= 1;
+        self.budget.released.notify_one();
+    }
+}
+
+/// The budget shared by everything in this process.
+///
+/// One eighth of the platform's limit, or `RESERVED_HANDLES`, whichever is
+/// more, is kept in reserve.
+pub fn global() -> &'static HandleBudget {
+    static BUDGET: OnceLock<HandleBudget> = OnceLock::new();
+
+    BUDGET.get_or_init(|| HandleBudget::new(open_file_limit().map(|limit| limit.saturating_sub((limit / 8).max(RESERVED_HANDLES)))))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use super::HandleBudget;
+
+    #[test]
+    fn handle_budget() {
+        let budget = Arc::new(HandleBudget::new(Some(2)));
+        let first = budget.acquire();
+        let _second = budget.acquire();
+        let acquired = Arc::new(AtomicBool::new(false));
+
+        let waiter = {
+            let budget = budget.clone();
+            let acquired = acquired.clone();
+
+            thread::spawn(move || {
+                let _third = budget.acquire();
+                acquired.store(true, Ordering::SeqCst);
+            })
+        };
+
+        thread::sleep(time::Duration::from_millis(20));
+        assert!(!acquired.load(Ordering::SeqCst));
+
+        drop(first);
+        waiter.join().unwrap();
+        assert!(acquired.load(Ordering::SeqCst));
+        assert_eq!(budget.used(), 1);
+
+        assert_eq!(HandleBudget::new(None).limit(), None);
+    }
+}
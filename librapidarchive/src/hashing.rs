@@ -0,0 +1,219 @@
+//! Hashing on dedicated threads.
+//!
+//! Hashing everything written to an archive is cheap next to disk I/O, but a
+//! fast tape drive can take data faster than a single core can hash it. If
+//! the serializer did the hashing itself, it would become the bottleneck. A
+//! `HasherPool` instead hands copies of the data off to a few hasher threads,
+//! so that hashing overlaps with writing.
+//!
+//! Each `HashStream` is pinned to one thread of the pool, so it's data is
+//! always hashed in order; different streams may be hashed in parallel.
+
+use std::{io, thread, time};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use crate::digest::Sha256;
+use crate::chunking::Chunker;
+
+/// How many buffers may wait for each hasher thread before writers block.
+const QUEUE_DEPTH: usize = 16;
+
+/// Something which digests a stream of data.
+pub trait StreamHasher: Send {
+    fn update(&mut self, data: &[u8]) -> io::Result<()>;
+}
+
+impl StreamHasher for Sha256 {
+    fn update(&mut self, data: &[u8]) -> io::Result<()> {
+        Sha256::update(self, data);
+        Ok(())
+    }
+}
+
+impl StreamHasher for Chunker {
+    fn update(&mut self, data: &[u8]) -> io::Result<()> {
+        Chunker::update(self, data)
+    }
+}
+
+/// How much work a pool's hasher threads have done.
+#[derive(Default)]
+pub struct HashStats {
+    bytes: AtomicU64,
+    busy_nanos: AtomicU64,
+}
+
+impl HashStats {
+    fn record(&self, bytes: usize, busy: time::Duration) {
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.busy_nanos.fetch_add(busy.as_secs() * 1_000_000_000 + busy.subsec_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// How many bytes have been hashed.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// How long the hasher threads have spent hashing, all together.
+    pub fn busy(&self) -> time::Duration {
+        let nanos = self.busy_nanos.load(Ordering::Relaxed);
+
+        time::Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+    }
+
+    /// How many bytes a single hasher thread gets through per second.
+    pub fn throughput(&self) -> f64 {
+        let busy = self.busy();
+        let secs = busy.as_secs() as f64 + busy.subsec_nanos() as f64 / 1_000_000_000.0;
+
+        match secs > 0.0 {
+            true => self.bytes() as f64 / secs,
+            false => 0.0
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A set of threads which hash data on behalf of `HashStream`s.
+///
+/// The threads exit once the pool and every stream made from it are dropped.
+pub struct HasherPool {
+    workers: Vec<SyncSender<Job>>,
+    next: AtomicUsize,
+    stats: Arc<HashStats>,
+}
+
+impl HasherPool {
+    pub fn new(threads: usize) -> HasherPool {
+        let workers = (0..threads.max(1)).map(|i| {
+            let (sender, receiver) = sync_channel::<Job>(QUEUE_DEPTH);
+
+            thread::Builder::new().name(format!("Hasher Thread {}", i)).spawn(move || {
+                while let Ok(job) = receiver.recv() {
+                    job();
+                }
+            }).expect("Could not start hasher thread");
+
+            sender
+        }).collect();
+
+        HasherPool {
+            workers: workers,
+            next: AtomicUsize::new(0),
+            stats: Arc::new(HashStats::default())
+        }
+    }
+
+    /// Start hashing a stream on one of the pool's threads.
+    ///
+    /// The hasher is shared so that it's results can be read once the stream
+    /// has been waited on.
+    pub fn stream<H: 'static + StreamHasher>(&self, hasher: Arc<Mutex<H>>) -> HashStream<H> {
+        let worker = self.next.fetch_add(1, Ordering::Relaxed) % self.workers.len();
+
+        HashStream {
+            hasher: hasher,
+            worker: self.workers[worker].clone(),
+            stats: self.stats.clone(),
+            error: Arc::new(Mutex::new(None))
+        }
+    }
+
+    pub fn stats(&self) -> Arc<HashStats> {
+        self.stats.clone()
+    }
+}
+
+/// A stream of data being hashed by a `HasherPool`.
+///
+/// Errors from the hasher are reported by the next call to `update` or `wait`
+/// after they happen. Dropping the stream waits for it.
+pub struct HashStream<H: 'static + StreamHasher> {
+    hasher: Arc<Mutex<H>>,
+    worker: SyncSender<Job>,
+    stats: Arc<HashStats>,
+    error: Arc<Mutex<Option<io::Error>>>,
+}
+
+impl<H: 'static + StreamHasher> HashStream<H> {
+    fn take_error(&self) -> io::Result<()> {
+        match self.error.lock().unwrap().take() {
+            Some(e) => Err(e),
+            None => Ok(())
+        }
+    }
+
+    /// Queue more data to be hashed, waiting if the hasher is too far behind.
+    pub fn update(&self, data: &[u8]) -> io::Result<()> {
+        self.take_error()?;
+
+        let buffer = data.to_vec();
+        let hasher = self.hasher.clone();
+        let stats = self.stats.clone();
+        let error = self.error.clone();
+
+        self.worker.send(Box::new(move || {
+            let start = time::Instant::now();
+            let result = hasher.lock().unwrap().update(&buffer);
+
+            stats.record(buffer.len(), start.elapsed());
+
+            if let Err(e) = result {
+                error.lock().unwrap().get_or_insert(e);
+            }
+        })).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Hasher thread has exited"))
+    }
+
+    /// Wait for everything queued so far to be hashed.
+    pub fn wait(&self) -> io::Result<()> {
+        let (done, finished) = sync_channel(1);
+
+        self.worker.send(Box::new(move || {
+            let _ = done.send(());
+        })).map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "Hasher thread has exited"))?;
+
+        let _ = finished.recv();
+
+        self.take_error()
+    }
+}
+
+impl<H: 'static + StreamHasher> Drop for HashStream<H> {
+    fn drop(&mut self) {
+        if let Err(e) = self.wait() {
+            warn!("Error hashing archive data: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use crate::digest::Sha256;
+    use super::HasherPool;
+
+    #[test]
+    fn hasher_pool() {
+        let pool = HasherPool::new(2);
+        let first = Arc::new(Mutex::new(Sha256::new()));
+        let second = Arc::new(Mutex::new(Sha256::new()));
+        let first_stream = pool.stream(first.clone());
+        let second_stream = pool.stream(second.clone());
+
+        for chunk in b"The quick brown fox jumps over the lazy dog".chunks(5) {
+            first_stream.update(chunk).unwrap();
+            second_stream.update(b"x").unwrap();
+        }
+
+        first_stream.wait().unwrap();
+        drop(first_stream);
+        drop(second_stream);
+
+        let finish = |hasher: Arc<Mutex<Sha256>>| Arc::try_unwrap(hasher).ok().unwrap().into_inner().unwrap().finish();
+        assert_eq!(finish(first), Sha256::digest(b"The quick brown fox jumps over the lazy dog"));
+        assert_eq!(finish(second), Sha256::digest(&[b'x'; 9]));
+        assert_eq!(pool.stats().bytes(), 43 + 9);
+    }
+}
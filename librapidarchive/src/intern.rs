@@ -0,0 +1,88 @@
+//! Interning of strings repeated across many headers.
+//!
+//! Archives of millions of files usually have only a handful of distinct
+//! owner and group names between them, but every `TarHeader` used to carry
+//! it's own copy of both. A `StringPool` hands out shared references to a
+//! single copy of each string instead, so that the thousands of headers queued
+//! up between the headergen workers and the archive writer don't each hold
+//! allocations of their own.
+//!
+//! Pools are cheap to clone, and clones share their strings, so one pool can
+//! be handed to every worker taking part in an operation.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+
+/// The most strings a pool will hold.
+///
+/// Archives we read may name a different owner for every member; past this
+/// point strings are handed out unshared rather than growing the pool without
+/// bound.
+const MAX_POOLED_STRINGS: usize = 64 * 1024;
+
+/// A set of strings shared between everything that uses the pool.
+#[derive(Clone, Default)]
+pub struct StringPool {
+    strings: Arc<Mutex<HashSet<Arc<str>>>>
+}
+
+impl StringPool {
+    pub fn new() -> StringPool {
+        StringPool::default()
+    }
+
+    /// Get the pool's copy of a string, adding it to the pool if it isn't
+    /// already there.
+    pub fn intern(&self, string: &str) -> Arc<str> {
+        let mut strings = self.strings.lock().unwrap();
+
+        if let Some(pooled) = strings.get(string) {
+            return pooled.clone();
+        }
+
+        let pooled : Arc<str> = Arc::from(string);
+
+        if strings.len() < MAX_POOLED_STRINGS {
+            strings.insert(pooled.clone());
+        }
+
+        pooled
+    }
+
+    /// How many distinct strings the pool holds.
+    pub fn len(&self) -> usize {
+        self.strings.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use super::StringPool;
+
+    #[test]
+    fn string_pool() {
+        let pool = StringPool::new();
+        let workers : Vec<_> = (0..4).map(|_| {
+            let pool = pool.clone();
+
+            thread::spawn(move || (pool.intern("root"), pool.intern("wheel")))
+        }).collect();
+        let interned : Vec<_> = workers.into_iter().map(|worker| worker.join().unwrap()).collect();
+
+        assert_eq!(pool.len(), 2);
+
+        for (owner, group) in interned.iter() {
+            assert!(Arc::ptr_eq(owner, &interned[0].0));
+            assert!(Arc::ptr_eq(group, &interned[0].1));
+        }
+
+        assert_eq!(&*pool.intern("root"), "root");
+        assert_eq!(pool.len(), 2);
+    }
+}
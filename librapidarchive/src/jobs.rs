@@ -0,0 +1,310 @@
+//! Batch files describing many archive jobs at once.
+//!
+//! A jobs file lets a nightly backup of many datasets run as one process,
+//! rather than a shell script starting one process per dataset. It is written
+//! in TOML:
+//!
+//! ```toml
+//! # Settings before the first job apply to every job.
+//! file = "/dev/nst0"
+//! append-to-tape = true
+//!
+//! [[job]]
+//! name = "home"
+//! create = true
+//! directory = "/home"
+//! paths = ["alice", "bob"]
+//!
+//! [[job]]
+//! name = "etc"
+//! create = true
+//! directory = "/"
+//! paths = ["etc"]
+//! format = "posix"
+//! ```
+//!
+//! Each setting names a long command-line option, without the leading dashes.
+//! Strings and integers become the option's value, `true` enables a flag (and
+//! `false` leaves it out), and arrays repeat the option once per element. Two
+//! keys are special: `name` identifies the job in reports, and `paths` lists
+//! the files to archive. A job's own settings replace any default of the same
+//! name.
+//!
+//! Jobs run one after the other. Jobs writing to the same tape drive share one
+//! open handle to it, through `SharedDrives`, so the drive isn't rewound or
+//! unloaded between them and each job's archive follows the last one on tape.
+//!
+//! Floats, dates, and tables other than `[[job]]` have no option to become,
+//! and are an error.
+
+use std::{io, fmt};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use serde::Deserialize;
+use crate::fs;
+use crate::tuning::Configuration;
+use crate::cancel::CancellationToken;
+
+/// A value given to a job setting.
+#[derive(Clone, Debug, PartialEq)]
+pub enum JobValue {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<JobValue>),
+}
+
+impl fmt::Display for JobValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobValue::String(s) => write!(f, "{}", s),
+            JobValue::Integer(i) => write!(f, "{}", i),
+            JobValue::Boolean(b) => write!(f, "{}", b),
+            JobValue::Array(values) => {
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+
+                    write!(f, "{}", value)?;
+                }
+
+                Ok(())
+            }
+        }
+    }
+}
+
+impl JobValue {
+    /// Convert a TOML value given to the setting `key`.
+    fn from_toml(key: &str, value: toml::Value) -> io::Result<JobValue> {
+        match value {
+            toml::Value::String(s) => Ok(JobValue::String(s)),
+            toml::Value::Integer(i) => Ok(JobValue::Integer(i)),
+            toml::Value::Boolean(b) => Ok(JobValue::Boolean(b)),
+            toml::Value::Array(values) => Ok(JobValue::Array(values.into_iter().map(|value| JobValue::from_toml(key, value)).collect::<io::Result<_>>()?)),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, format!("Jobs file setting {} must be a string, integer, boolean, or array of those", key)))
+        }
+    }
+}
+
+/// One job from a jobs file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct JobSpec {
+    /// The name of the job, or it's position in the file (counting from 1) if
+    /// it wasn't given one.
+    pub name: String,
+
+    /// Every setting of the job, including the defaults it inherited, in the
+    /// order they were given.
+    pub settings: Vec<(String, JobValue)>,
+}
+
+impl JobSpec {
+    /// Render this job's settings as command-line arguments.
+    ///
+    /// Options come first, followed by `--` and the paths to archive, so that
+    /// paths starting with a dash aren't taken for options.
+    pub fn command_line(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let mut paths = Vec::new();
+
+        for (key, value) in self.settings.iter() {
+            match (key.as_str(), value) {
+                ("paths", JobValue::Array(values)) => paths.extend(values.iter().map(|v| v.to_string())),
+                ("paths", value) => paths.push(value.to_string()),
+                (_, JobValue::Boolean(true)) => args.push(format!("--{}", key)),
+                (_, JobValue::Boolean(false)) => {},
+                (_, JobValue::Array(values)) => args.extend(values.iter().map(|v| format!("--{}={}", key, v))),
+                (_, value) => args.push(format!("--{}={}", key, value))
+            }
+        }
+
+        if !paths.is_empty() {
+            args.push("--".to_string());
+            args.extend(paths);
+        }
+
+        args
+    }
+}
+
+/// A jobs file, as written.
+#[derive(Deserialize)]
+struct JobsFile {
+    #[serde(default)]
+    job: Vec<JobTable>,
+
+    /// Settings given before the first job.
+    #[serde(flatten)]
+    defaults: toml::Table,
+}
+
+/// One `[[job]]` table of a jobs file.
+#[derive(Deserialize)]
+struct JobTable {
+    name: Option<String>,
+
+    #[serde(flatten)]
+    settings: toml::Table,
+}
+
+/// Parse the contents of a jobs file.
+pub fn parse_jobs(text: &str) -> io::Result<Vec<JobSpec>> {
+    let file : JobsFile = toml::from_str(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Jobs file: {}", e)))?;
+    let mut defaults = Vec::new();
+    let mut jobs = Vec::new();
+
+    for (key, value) in file.defaults.into_iter() {
+        let value = JobValue::from_toml(&key, value)?;
+
+        defaults.push((key, value));
+    }
+
+    for (i, table) in file.job.into_iter().enumerate() {
+        let mut settings : Vec<(String, JobValue)> = defaults.iter().filter(|(key, _)| !table.settings.contains_key(key)).cloned().collect();
+
+        for (key, value) in table.settings.into_iter() {
+            let value = JobValue::from_toml(&key, value)?;
+
+            settings.push((key, value));
+        }
+
+        jobs.push(JobSpec {
+            name: table.name.unwrap_or_else(|| (i + 1).to_string()),
+            settings: settings
+        });
+    }
+
+    Ok(jobs)
+}
+
+/// Drives held open from one job to the next, by the name they were opened
+/// with.
+///
+/// Jobs writing to the same drive share one open handle to it, so that
+/// closing the drive between jobs doesn't rewind or unload the tape. Clones
+/// share the same drives, which are closed once every clone is dropped.
+pub struct SharedDrives<I> {
+    drives: Arc<Mutex<HashMap<String, fs::SharedDrive<I>>>>
+}
+
+impl<I> Clone for SharedDrives<I> {
+    fn clone(&self) -> Self {
+        SharedDrives {
+            drives: self.drives.clone()
+        }
+    }
+}
+
+impl<I> Default for SharedDrives<I> {
+    fn default() -> Self {
+        SharedDrives {
+            drives: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+}
+
+impl<I> SharedDrives<I> where I: 'static + Send + Clone + PartialEq {
+    pub fn new() -> SharedDrives<I> {
+        SharedDrives::default()
+    }
+
+    /// Open a sink for writing an archive to `outfile`.
+    ///
+    /// Drives are opened the first time a job writes to them, and shared by
+    /// every job after that. With `remount`, a drive already open is closed
+    /// and opened again first, as it is when a new volume has been loaded.
+    /// Anything that isn't a drive is opened with `fs::open_sink`.
+    pub fn open_sink(&self, outfile: &str, remount: bool, tuning: &Configuration, limit: Option<u64>, cancel: &CancellationToken) -> io::Result<Box<dyn fs::ArchivalSink<I>>> {
+        let mut drives = self.drives.lock().unwrap();
+
+        if remount {
+            drives.remove(outfile);
+        }
+
+        let drive = match drives.get(outfile) {
+            Some(drive) => drive.clone(),
+            None => match fs::open_drive(outfile, tuning)? {
+                Some(drive) => {
+                    drives.insert(outfile.to_string(), drive.clone());
+                    drive
+                },
+                None => return fs::open_sink(outfile, tuning, limit, cancel)
+            }
+        };
+
+        fs::open_drive_sink(&drive, tuning, limit, cancel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use crate::fs::{ArchivalSink, SharedDrive, open_drive_sink};
+    use crate::tape::memory::MemoryTapeDevice;
+    use crate::tuning::Configuration;
+    use crate::cancel::CancellationToken;
+    use super::{JobValue, parse_jobs};
+
+    #[test]
+    fn jobs_file() {
+        let jobs = parse_jobs("# Shared settings\nfile = \"/dev/nst0\"\nappend-to-tape = true\nexclude = [\"*.o\"]\n\n[[job]]\nname = 'home' # comment\ncreate = true\npaths = [\"alice\",\n  \"bob\", # trailing comma\n]\nchannel_queue_depth = 1_024\nexclude = [\"*.tmp\", \"*.swp\"]\n\n[[job]]\nforce = false\n").unwrap();
+
+        assert_eq!(jobs.len(), 2);
+        assert_eq!(jobs[0].name, "home");
+        assert_eq!(jobs[1].name, "2");
+        assert_eq!(jobs[0].settings[0], ("file".to_string(), JobValue::String("/dev/nst0".to_string())));
+        assert_eq!(jobs[0].command_line(), vec!["--file=/dev/nst0", "--append-to-tape", "--create", "--channel_queue_depth=1024", "--exclude=*.tmp", "--exclude=*.swp", "--", "alice", "bob"]);
+        assert_eq!(jobs[1].command_line(), vec!["--file=/dev/nst0", "--append-to-tape", "--exclude=*.o"]);
+
+        let error = parse_jobs("[[job]]\nname = \"ok\"\ncreate = yes\n").unwrap_err();
+        assert!(error.to_string().contains("line 3"));
+        assert!(parse_jobs("[table]\n").is_err());
+        assert!(parse_jobs("[[job]]\nblocking-factor = 2.5\n").is_err());
+        assert!(parse_jobs("[[job]]\nname = \"twice\"\nname = \"again\"\n").is_err());
+    }
+
+    #[test]
+    fn jobs_quoting() {
+        let jobs = parse_jobs("[[job]]\n\"transform\" = 's/\\\\/_/'\nlabel = \"Tab\\there \\\"quoted\\\" \\u00e9\"\ndirectory = '''C:\\Backups'''\nlabel-comment = \"\"\"\nmultiple\nlines\"\"\"\n").unwrap();
+
+        assert_eq!(jobs[0].settings, vec![
+            ("transform".to_string(), JobValue::String("s/\\\\/_/".to_string())),
+            ("label".to_string(), JobValue::String("Tab\there \"quoted\" \u{e9}".to_string())),
+            ("directory".to_string(), JobValue::String("C:\\Backups".to_string())),
+            ("label-comment".to_string(), JobValue::String("multiple\nlines".to_string())),
+        ]);
+    }
+
+    #[test]
+    fn jobs_dashed_paths() {
+        let jobs = parse_jobs("[[job]]\ncreate = true\npaths = [\"-rf\", \"--file=/dev/null\"]\n").unwrap();
+
+        assert_eq!(jobs[0].command_line(), vec!["--create", "--", "-rf", "--file=/dev/null"]);
+    }
+
+    #[test]
+    fn jobs_shared_drive() {
+        let drive = SharedDrive::<u64>::new(Box::new(MemoryTapeDevice::<u64>::new()));
+        let tuning = Configuration::default();
+        let cancel = CancellationToken::new();
+
+        for job in 0..2u8 {
+            let mut sink = open_drive_sink(&drive, &tuning, None, &cancel).unwrap();
+
+            sink.write_all(&[job; 1024]).unwrap();
+            sink.flush().unwrap();
+            sink.downcast_tapedevice().unwrap().write_filemark(true).unwrap();
+        }
+
+        let mut drive = drive;
+        let tape = drive.downcast_tapedevice().unwrap();
+
+        tape.seek_filemarks(std::io::SeekFrom::Start(1)).unwrap();
+
+        let mut block = Vec::new();
+        tape.read_block(&mut block).unwrap();
+        assert_eq!(&block[..1024], &[1; 1024][..]);
+    }
+}
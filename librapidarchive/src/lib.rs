@@ -3,6 +3,9 @@ extern crate pad;
 extern crate num;
 extern crate num_traits;
 
+#[macro_use]
+extern crate log;
+
 #[cfg(windows)]
 extern crate winapi;
 
@@ -17,7 +20,32 @@ pub mod tape;
 pub mod fs;
 pub mod normalize;
 pub mod spanning;
+pub mod retry;
+pub mod filter;
+pub mod ltfs;
+pub mod snapshot;
+pub mod digest;
+pub mod chunking;
+#[cfg(feature = "compression")]
+pub mod compress;
+#[cfg(feature = "compression")]
+pub mod decompress;
+pub mod hashing;
+pub mod manifest;
+pub mod filelist;
+pub mod multistream;
+pub mod jobs;
+pub mod rotation;
 
 pub mod concurrentbuf;
+pub mod ordered;
+pub mod cancel;
+pub mod status;
+pub mod timeout;
+pub mod handles;
+pub mod queue;
+pub mod testing;
 pub mod tuning;
-pub mod units;
\ No newline at end of file
+pub mod logging;
+pub mod units;
+pub mod intern;
\ No newline at end of file
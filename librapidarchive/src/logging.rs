@@ -0,0 +1,96 @@
+//! Leveled, time-stamped diagnostic output.
+//!
+//! librapidarchive reports everything that isn't an outright `Err` through the
+//! `log` crate, so embedders can route our messages wherever the rest of their
+//! program's logs go. Programs that don't have a logger of their own can
+//! install the `StderrLogger` provided here.
+
+use std::io::Write;
+use std::{io, fmt, time};
+use log::{Log, Metadata, Record, LevelFilter, SetLoggerError};
+use log::kv::{Key, Value, VisitSource};
+use crate::units::UTCTime;
+
+/// A logger which writes time-stamped lines to standard error.
+///
+/// Each line consists of the UTC time the message was logged, it's level, the
+/// module that logged it, the message, and any structured context attached to
+/// the message as `key=value` pairs.
+pub struct StderrLogger {
+    level: LevelFilter
+}
+
+impl StderrLogger {
+    pub fn new(level: LevelFilter) -> StderrLogger {
+        StderrLogger {
+            level: level
+        }
+    }
+}
+
+/// Appends structured logging context onto a formatted log line.
+struct ContextFormatter<'a> {
+    line: &'a mut String
+}
+
+impl<'a, 'kvs> VisitSource<'kvs> for ContextFormatter<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), log::kv::Error> {
+        fmt::write(self.line, format_args!(" {}={}", key, value))?;
+
+        Ok(())
+    }
+}
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut line = format!("{} {:<5} [{}] {}", UTCTime::from(time::SystemTime::now()), record.level(), record.target(), record.args());
+
+        //Context is nice to have; if it fails to format we still want the
+        //message itself.
+        let _ = record.key_values().visit(&mut ContextFormatter{ line: &mut line });
+
+        let stderr = io::stderr();
+        let mut handle = stderr.lock();
+
+        let _ = writeln!(handle, "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+/// Convert a verbosity count into a level filter.
+///
+/// Verbosity zero corresponds to warnings and errors. Each increment above
+/// zero enables the next most verbose level, while each decrement below zero
+/// silences the next least verbose level. This maps directly onto repeatable
+/// `-v` and `-q` command-line flags.
+pub fn level_for_verbosity(verbosity: i32) -> LevelFilter {
+    match verbosity {
+        v if v <= -2 => LevelFilter::Off,
+        -1 => LevelFilter::Error,
+        0 => LevelFilter::Warn,
+        1 => LevelFilter::Info,
+        2 => LevelFilter::Debug,
+        _ => LevelFilter::Trace
+    }
+}
+
+/// Install a `StderrLogger` as the process-wide logger.
+///
+/// This fails if another logger has already been installed.
+pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_boxed_logger(Box::new(StderrLogger::new(level)))?;
+    log::set_max_level(level);
+
+    Ok(())
+}
@@ -0,0 +1,633 @@
+//! Writer for the Linear Tape File System.
+//!
+//! LTFS lays a tape out in two partitions. The index partition holds the
+//! latest copy of the volume's directory tree, as an XML *index*; the data
+//! partition holds file contents, followed by the index that describes them.
+//! Each partition starts with an ANSI `VOL1` label and an XML *LTFS label*,
+//! each followed by a filemark. Tapes written this way can be mounted by any
+//! LTFS driver and browsed like a disk.
+//!
+//! `LtfsWriter` writes a complete, single-generation volume onto a tape that
+//! has already been formatted with two partitions. Anything previously on the
+//! tape is lost.
+
+use std::{io, path, time, cmp};
+use std::io::Seek;
+use std::collections::BTreeMap;
+use std::collections::btree_map::Entry;
+use crate::tape::TapeDevice;
+use crate::tar::header::{TarHeader, TarFileType, HeaderGenResult};
+use crate::tar::label::random_uuid;
+
+/// The version of the LTFS format we write.
+pub const LTFS_VERSION: &str = "2.4.0";
+
+/// The partition the index is written to.
+pub const INDEX_PARTITION: u32 = 1;
+
+/// The partition file data is written to.
+pub const DATA_PARTITION: u32 = 2;
+
+/// The block size recommended by the LTFS format.
+pub const DEFAULT_BLOCK_SIZE: usize = 512 * 1024;
+
+/// The first block after the labels at the start of each partition.
+const FIRST_CONTENT_BLOCK: u64 = 4;
+
+/// Parameters for a new LTFS volume.
+#[derive(Clone)]
+pub struct LtfsOptions {
+    /// The name of the volume, shown as the name of it's root directory.
+    pub volume_name: String,
+
+    /// The six-character volume serial to write into the `VOL1` label,
+    /// usually the barcode of the cartridge.
+    pub volume_serial: String,
+
+    /// The size of each block written to tape.
+    pub block_size: usize,
+
+    /// Whether the drive should compress what it writes.
+    ///
+    /// The drive is set this way when the volume is formatted, and the label
+    /// records whether it actually is: drives without hardware compression
+    /// write uncompressed no matter what's asked for here.
+    pub compression: bool,
+}
+
+impl Default for LtfsOptions {
+    fn default() -> Self {
+        LtfsOptions {
+            volume_name: "rapidtar".to_string(),
+            volume_serial: "".to_string(),
+            block_size: DEFAULT_BLOCK_SIZE,
+            compression: true
+        }
+    }
+}
+
+/// The LTFS name for a partition, given it's number.
+fn partition_letter(id: u32) -> char {
+    (b'a' + (id - 1) as u8) as char
+}
+
+/// Format a time the way LTFS indexes expect, which is ISO 8601 in UTC with
+/// nanosecond precision.
+fn format_time(time: time::SystemTime) -> String {
+    let (secs, nanos) = match time.duration_since(time::UNIX_EPOCH) {
+        Ok(since) => (since.as_secs() as i64, since.subsec_nanos()),
+        Err(e) => {
+            let before = e.duration();
+
+            match before.subsec_nanos() {
+                0 => (-(before.as_secs() as i64), 0),
+                n => (-(before.as_secs() as i64) - 1, 1_000_000_000 - n)
+            }
+        }
+    };
+
+    let days = secs.div_euclid(86400);
+    let day_secs = secs.rem_euclid(86400);
+
+    //Days to civil date conversion, per Howard Hinnant's algorithm.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z", year, month, day, day_secs / 3600, (day_secs / 60) % 60, day_secs % 60, nanos)
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c)
+        }
+    }
+
+    escaped
+}
+
+/// Timestamps of a file or directory.
+#[derive(Copy, Clone)]
+struct LtfsTimes {
+    creation: time::SystemTime,
+    change: time::SystemTime,
+    modify: time::SystemTime,
+    access: time::SystemTime,
+}
+
+impl LtfsTimes {
+    fn now() -> LtfsTimes {
+        let now = time::SystemTime::now();
+
+        LtfsTimes {
+            creation: now,
+            change: now,
+            modify: now,
+            access: now
+        }
+    }
+
+    fn from_header(header: &TarHeader) -> LtfsTimes {
+        let now = time::SystemTime::now();
+        let modify = header.mtime.unwrap_or(now);
+
+        LtfsTimes {
+            creation: header.birthtime.unwrap_or(modify),
+            change: header.ctime.unwrap_or(modify),
+            modify: modify,
+            access: header.atime.unwrap_or(modify)
+        }
+    }
+
+    fn to_xml(&self, xml: &mut String, indent: &str) {
+        xml.push_str(&format!("{}<creationtime>{}</creationtime>\n", indent, format_time(self.creation)));
+        xml.push_str(&format!("{}<changetime>{}</changetime>\n", indent, format_time(self.change)));
+        xml.push_str(&format!("{}<modifytime>{}</modifytime>\n", indent, format_time(self.modify)));
+        xml.push_str(&format!("{}<accesstime>{}</accesstime>\n", indent, format_time(self.access)));
+        xml.push_str(&format!("{}<backuptime>{}</backuptime>\n", indent, format_time(self.modify)));
+    }
+}
+
+struct LtfsFile {
+    uid: u64,
+    times: LtfsTimes,
+    readonly: bool,
+    length: u64,
+
+    /// The first block of the file's data, if it has any.
+    start_block: Option<u64>,
+    symlink: Option<String>,
+}
+
+struct LtfsDirectory {
+    uid: u64,
+    times: LtfsTimes,
+    readonly: bool,
+    children: BTreeMap<String, LtfsNode>,
+}
+
+enum LtfsNode {
+    File(LtfsFile),
+    Directory(LtfsDirectory)
+}
+
+impl LtfsDirectory {
+    fn new(uid: u64, times: LtfsTimes) -> LtfsDirectory {
+        LtfsDirectory {
+            uid: uid,
+            times: times,
+            readonly: false,
+            children: BTreeMap::new()
+        }
+    }
+
+    fn to_xml(&self, name: &str, xml: &mut String, indent: &str) {
+        let inner = format!("{}  ", indent);
+
+        xml.push_str(&format!("{}<directory>\n", indent));
+        xml.push_str(&format!("{}<name>{}</name>\n", inner, escape_xml(name)));
+        xml.push_str(&format!("{}<readonly>{}</readonly>\n", inner, self.readonly));
+        self.times.to_xml(xml, &inner);
+        xml.push_str(&format!("{}<fileuid>{}</fileuid>\n", inner, self.uid));
+        xml.push_str(&format!("{}<contents>\n", inner));
+
+        let child_indent = format!("{}  ", inner);
+
+        for (child_name, child) in self.children.iter() {
+            match child {
+                LtfsNode::Directory(dir) => dir.to_xml(child_name, xml, &child_indent),
+                LtfsNode::File(file) => file.to_xml(child_name, xml, &child_indent)
+            }
+        }
+
+        xml.push_str(&format!("{}</contents>\n", inner));
+        xml.push_str(&format!("{}</directory>\n", indent));
+    }
+}
+
+impl LtfsFile {
+    fn to_xml(&self, name: &str, xml: &mut String, indent: &str) {
+        let inner = format!("{}  ", indent);
+
+        xml.push_str(&format!("{}<file>\n", indent));
+        xml.push_str(&format!("{}<name>{}</name>\n", inner, escape_xml(name)));
+        xml.push_str(&format!("{}<length>{}</length>\n", inner, self.length));
+        xml.push_str(&format!("{}<readonly>{}</readonly>\n", inner, self.readonly));
+        self.times.to_xml(xml, &inner);
+        xml.push_str(&format!("{}<fileuid>{}</fileuid>\n", inner, self.uid));
+
+        if let Some(ref target) = self.symlink {
+            xml.push_str(&format!("{}<symlink>{}</symlink>\n", inner, escape_xml(target)));
+        } else if let Some(start_block) = self.start_block {
+            xml.push_str(&format!("{}<extentinfo>\n", inner));
+            xml.push_str(&format!("{}  <extent>\n", inner));
+            xml.push_str(&format!("{}    <fileoffset>0</fileoffset>\n", inner));
+            xml.push_str(&format!("{}    <partition>{}</partition>\n", inner, partition_letter(DATA_PARTITION)));
+            xml.push_str(&format!("{}    <startblock>{}</startblock>\n", inner, start_block));
+            xml.push_str(&format!("{}    <byteoffset>0</byteoffset>\n", inner));
+            xml.push_str(&format!("{}    <bytecount>{}</bytecount>\n", inner, self.length));
+            xml.push_str(&format!("{}  </extent>\n", inner));
+            xml.push_str(&format!("{}</extentinfo>\n", inner));
+        }
+
+        xml.push_str(&format!("{}</file>\n", indent));
+    }
+}
+
+/// Writes an LTFS volume onto a tape.
+///
+/// Files are written to the data partition as they are added, each starting
+/// on a fresh block. The index is only written when the volume is finished, so
+/// a volume that is never finished can't be mounted.
+pub struct LtfsWriter<'a> {
+    tape: &'a mut dyn TapeDevice,
+    options: LtfsOptions,
+    volume_uuid: String,
+    root: LtfsDirectory,
+    highest_uid: u64,
+    next_block: u64,
+    bytes_written: u64,
+}
+
+impl<'a> LtfsWriter<'a> {
+    /// Begin writing a new volume, labeling both partitions of the tape.
+    pub fn format(tape: &'a mut dyn TapeDevice, mut options: LtfsOptions) -> io::Result<LtfsWriter<'a>> {
+        if let Ok(count) = tape.partition_count() {
+            if count < DATA_PARTITION {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "LTFS requires a tape formatted with two partitions"));
+            }
+        }
+
+        //Drives that can't set compression don't have any to turn on.
+        options.compression = match tape.set_compression(options.compression) {
+            Ok(()) => options.compression,
+            Err(_) => false
+        };
+
+        let writer = LtfsWriter {
+            tape: tape,
+            options: options,
+            volume_uuid: random_uuid(),
+            root: LtfsDirectory::new(1, LtfsTimes::now()),
+            highest_uid: 1,
+            next_block: FIRST_CONTENT_BLOCK,
+            bytes_written: 0,
+        };
+
+        let formatted_at = time::SystemTime::now();
+
+        for partition in [INDEX_PARTITION, DATA_PARTITION].iter() {
+            writer.tape.seek_partition(*partition)?;
+            writer.tape.seek_blocks(io::SeekFrom::Start(0))?;
+
+            let vol1 = writer.vol1_label();
+            writer.tape.write_all(&vol1)?;
+            writer.tape.write_filemark(true)?;
+
+            let label = writer.ltfs_label(*partition, formatted_at);
+            writer.tape.write_all(label.as_bytes())?;
+            writer.tape.write_filemark(true)?;
+        }
+
+        Ok(writer)
+    }
+
+    /// How much file data has been written to the volume so far.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    /// Construct the 80-byte ANSI volume label.
+    fn vol1_label(&self) -> Vec<u8> {
+        let mut label = vec![b' '; 80];
+        let serial : Vec<u8> = self.options.volume_serial.bytes().take(6).collect();
+
+        label[0..4].copy_from_slice(b"VOL1");
+        label[4..4 + serial.len()].copy_from_slice(&serial);
+        label[10] = b'L';
+        label[24..28].copy_from_slice(b"LTFS");
+        label[79] = b'4';
+
+        label
+    }
+
+    fn ltfs_label(&self, partition: u32, formatted_at: time::SystemTime) -> String {
+        let mut xml = String::new();
+
+        xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(&format!("<ltfslabel version=\"{}\">\n", LTFS_VERSION));
+        xml.push_str(&format!("  <creator>librapidarchive {}</creator>\n", env!("CARGO_PKG_VERSION")));
+        xml.push_str(&format!("  <formattime>{}</formattime>\n", format_time(formatted_at)));
+        xml.push_str(&format!("  <volumeuuid>{}</volumeuuid>\n", self.volume_uuid));
+        xml.push_str(&format!("  <location>\n    <partition>{}</partition>\n  </location>\n", partition_letter(partition)));
+        xml.push_str(&format!("  <partitions>\n    <index>{}</index>\n    <data>{}</data>\n  </partitions>\n", partition_letter(INDEX_PARTITION), partition_letter(DATA_PARTITION)));
+        xml.push_str(&format!("  <blocksize>{}</blocksize>\n", self.options.block_size));
+        xml.push_str(&format!("  <compression>{}</compression>\n", self.options.compression));
+        xml.push_str("</ltfslabel>\n");
+
+        xml
+    }
+
+    fn index(&self, partition: u32, start_block: u64, previous: Option<(u32, u64)>) -> String {
+        let mut xml = String::new();
+
+        xml.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+        xml.push_str(&format!("<ltfsindex version=\"{}\">\n", LTFS_VERSION));
+        xml.push_str(&format!("  <creator>librapidarchive {}</creator>\n", env!("CARGO_PKG_VERSION")));
+        xml.push_str(&format!("  <volumeuuid>{}</volumeuuid>\n", self.volume_uuid));
+        xml.push_str("  <generationnumber>1</generationnumber>\n");
+        xml.push_str(&format!("  <updatetime>{}</updatetime>\n", format_time(time::SystemTime::now())));
+        xml.push_str(&format!("  <location>\n    <partition>{}</partition>\n    <startblock>{}</startblock>\n  </location>\n", partition_letter(partition), start_block));
+
+        if let Some((prev_partition, prev_block)) = previous {
+            xml.push_str(&format!("  <previousgenerationlocation>\n    <partition>{}</partition>\n    <startblock>{}</startblock>\n  </previousgenerationlocation>\n", partition_letter(prev_partition), prev_block));
+        }
+
+        xml.push_str("  <allowpolicyupdate>true</allowpolicyupdate>\n");
+        xml.push_str(&format!("  <highestfileuid>{}</highestfileuid>\n", self.highest_uid));
+        self.root.to_xml(&self.options.volume_name, &mut xml, "  ");
+        xml.push_str("</ltfsindex>\n");
+
+        xml
+    }
+
+    /// Write data to the tape in blocks, returning how many were written.
+    fn write_blocks(&mut self, data: &[u8]) -> io::Result<u64> {
+        let mut count = 0;
+
+        for block in data.chunks(self.options.block_size) {
+            self.tape.write_all(block)?;
+            count += 1;
+        }
+
+        Ok(count)
+    }
+
+    fn next_uid(&mut self) -> u64 {
+        self.highest_uid += 1;
+        self.highest_uid
+    }
+
+    /// Find the directory that should contain an entry, creating any missing
+    /// directories along the way. Yields the directory and the entry's name.
+    fn parent_of(&mut self, archival_path: &path::Path) -> io::Result<(&mut LtfsDirectory, String)> {
+        let mut names : Vec<String> = Vec::new();
+
+        for component in archival_path.components() {
+            match component {
+                path::Component::Normal(name) => names.push(name.to_string_lossy().into_owned()),
+                path::Component::CurDir | path::Component::RootDir | path::Component::Prefix(_) => {},
+                path::Component::ParentDir => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Path {:?} escapes the volume", archival_path)))
+            }
+        }
+
+        let name = names.pop().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Cannot add the root of the volume"))?;
+        let highest_uid = &mut self.highest_uid;
+        let mut dir = &mut self.root;
+
+        for dirname in names.into_iter() {
+            let node = dir.children.entry(dirname.clone()).or_insert_with(|| {
+                *highest_uid += 1;
+                LtfsNode::Directory(LtfsDirectory::new(*highest_uid, LtfsTimes::now()))
+            });
+
+            dir = match node {
+                LtfsNode::Directory(subdir) => subdir,
+                LtfsNode::File(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is a file, not a directory", dirname)))
+            };
+        }
+
+        Ok((dir, name))
+    }
+
+    /// Add a directory to the volume.
+    ///
+    /// If the directory was already created implicitly by adding something
+    /// inside of it, it's timestamps are updated instead.
+    pub fn add_directory(&mut self, header: &TarHeader) -> io::Result<()> {
+        let times = LtfsTimes::from_header(header);
+        let readonly = header.unix_mode & 0o222 == 0;
+        let uid = self.next_uid();
+        let (parent, name) = self.parent_of(&header.path)?;
+
+        match parent.children.entry(name) {
+            Entry::Vacant(vacant) => {
+                let mut dir = LtfsDirectory::new(uid, times);
+                dir.readonly = readonly;
+                vacant.insert(LtfsNode::Directory(dir));
+            },
+            Entry::Occupied(mut occupied) => match occupied.get_mut() {
+                LtfsNode::Directory(dir) => {
+                    dir.times = times;
+                    dir.readonly = readonly;
+                },
+                LtfsNode::File(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{:?} was already added as a file", header.path)))
+            }
+        }
+
+        Ok(())
+    }
+
+    fn insert_file(&mut self, header: &TarHeader, file: LtfsFile) -> io::Result<()> {
+        let (parent, name) = self.parent_of(&header.path)?;
+
+        match parent.children.entry(name) {
+            Entry::Vacant(vacant) => {
+                vacant.insert(LtfsNode::File(file));
+                Ok(())
+            },
+            Entry::Occupied(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{:?} was already added to the volume", header.path)))
+        }
+    }
+
+    /// Add a symbolic link to the volume.
+    pub fn add_symlink(&mut self, header: &TarHeader, target: &path::Path) -> io::Result<()> {
+        let file = LtfsFile {
+            uid: self.next_uid(),
+            times: LtfsTimes::from_header(header),
+            readonly: false,
+            length: 0,
+            start_block: None,
+            symlink: Some(target.to_string_lossy().into_owned())
+        };
+
+        self.insert_file(header, file)
+    }
+
+    /// Add a regular file to the volume, copying it's contents onto tape.
+    ///
+    /// Exactly `header.file_size` bytes are copied from `data`. Sources that
+    /// end early are an error, and leave their partial data on tape
+    /// unreferenced.
+    pub fn add_file(&mut self, header: &TarHeader, data: &mut dyn io::Read) -> io::Result<()> {
+        let start_block = self.next_block;
+        let mut remaining = header.file_size;
+        let mut block = vec![0; self.options.block_size];
+
+        while remaining > 0 {
+            let want = cmp::min(remaining, self.options.block_size as u64) as usize;
+            data.read_exact(&mut block[0..want]).map_err(|e| match e.kind() {
+                io::ErrorKind::UnexpectedEof => io::Error::new(io::ErrorKind::InvalidData, format!("File {:?} was shorter than indicated in traversal by {} bytes", header.path, remaining)),
+                _ => e
+            })?;
+
+            self.tape.write_all(&block[0..want])?;
+            self.next_block += 1;
+            self.bytes_written += want as u64;
+            remaining -= want as u64;
+        }
+
+        let file = LtfsFile {
+            uid: self.next_uid(),
+            times: LtfsTimes::from_header(header),
+            readonly: header.unix_mode & 0o222 == 0,
+            length: header.file_size,
+            start_block: if header.file_size > 0 { Some(start_block) } else { None },
+            symlink: None
+        };
+
+        self.insert_file(header, file)
+    }
+
+    /// Add a traversed entry to the volume.
+    ///
+    /// Returns `false` if the entry was skipped because LTFS can't represent
+    /// it, such as device files, pipes, and hard links.
+    pub fn add_entry(&mut self, traversal: &HeaderGenResult) -> io::Result<bool> {
+        let header = &traversal.tar_header;
+
+        match header.file_type {
+            TarFileType::Directory => self.add_directory(header)?,
+            TarFileType::SymbolicLink => match header.symlink_path {
+                Some(ref target) => self.add_symlink(header, target)?,
+                None => return Ok(false)
+            },
+            TarFileType::FileStream => {
+                let prefix : &[u8] = traversal.file_prefix.as_ref().map(|p| p.as_slice()).unwrap_or(&[]);
+
+                if prefix.len() as u64 >= header.file_size {
+                    self.add_file(header, &mut &prefix[..])?;
+                } else {
+                    let mut source_file = traversal.open_source()?;
+                    source_file.seek(io::SeekFrom::Start(prefix.len() as u64))?;
+
+                    self.add_file(header, &mut io::Read::chain(prefix, source_file))?;
+                }
+            },
+            _ => return Ok(false)
+        }
+
+        Ok(true)
+    }
+
+    /// Write the index to both partitions, completing the volume.
+    ///
+    /// The data partition gets it's index first, then the index partition
+    /// gets a copy that points back to it.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.tape.write_filemark(true)?;
+
+        //The index starts after the filemark we just wrote.
+        let data_index_block = self.next_block + 1;
+        let data_index = self.index(DATA_PARTITION, data_index_block, None);
+
+        self.write_blocks(data_index.as_bytes())?;
+        self.tape.write_filemark(true)?;
+
+        self.tape.seek_partition(INDEX_PARTITION)?;
+        self.tape.seek_blocks(io::SeekFrom::Start(FIRST_CONTENT_BLOCK))?;
+
+        let index_index = self.index(INDEX_PARTITION, FIRST_CONTENT_BLOCK, Some((DATA_PARTITION, data_index_block)));
+        self.write_blocks(index_index.as_bytes())?;
+        self.tape.write_filemark(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, time};
+    use crate::tape::memory::{MemoryTapeDevice, TapeRecord};
+    use crate::tar::header::{TarHeader, TarFileType};
+    use super::{LtfsWriter, LtfsOptions, format_time};
+
+    fn block_text(record: &TapeRecord) -> String {
+        match record {
+            TapeRecord::Block(data) => String::from_utf8(data.clone()).unwrap(),
+            _ => panic!("Expected a block")
+        }
+    }
+
+    #[test]
+    fn ltfs_format_time() {
+        assert_eq!(format_time(time::UNIX_EPOCH), "1970-01-01T00:00:00.000000000Z");
+        assert_eq!(format_time(time::UNIX_EPOCH + time::Duration::new(951782400, 5)), "2000-02-29T00:00:00.000000005Z");
+        assert_eq!(format_time(time::UNIX_EPOCH - time::Duration::new(0, 1)), "1969-12-31T23:59:59.999999999Z");
+    }
+
+    #[test]
+    fn ltfs_write_volume() {
+        let mut tape = MemoryTapeDevice::<u64>::with_partitions(2);
+        let options = LtfsOptions { volume_name: "vol".to_string(), volume_serial: "ABC123".to_string(), block_size: 4, compression: false };
+
+        {
+            let mut writer = LtfsWriter::format(&mut tape, options).unwrap();
+
+            writer.add_file(&TarHeader::for_test("dir/a&b", TarFileType::FileStream, 6), &mut io::Cursor::new(b"abcdef".to_vec())).unwrap();
+            writer.add_directory(&TarHeader::for_test("dir", TarFileType::Directory, 0)).unwrap();
+            assert!(writer.add_file(&TarHeader::for_test("dir/a&b/c", TarFileType::FileStream, 0), &mut io::empty()).is_err());
+            assert_eq!(writer.bytes_written(), 6);
+
+            writer.finish().unwrap();
+        }
+
+        assert!(!tape.compression());
+
+        let index_partition = tape.partition_records(1);
+        let data_partition = tape.partition_records(2);
+
+        for partition in [&index_partition, &data_partition].iter() {
+            match &partition[0] {
+                TapeRecord::Block(vol1) => {
+                    assert_eq!(vol1.len(), 80);
+                    assert_eq!(&vol1[0..11], b"VOL1ABC123L");
+                    assert_eq!(&vol1[24..28], b"LTFS");
+                },
+                _ => panic!("Expected a VOL1 label")
+            }
+
+            assert!(partition[1] == TapeRecord::Filemark);
+            assert!(block_text(&partition[2]).contains("<ltfslabel"));
+            assert!(block_text(&partition[2]).contains("<compression>false</compression>"));
+            assert!(partition[3] == TapeRecord::Filemark);
+        }
+
+        assert!(data_partition[4] == TapeRecord::Block(b"abcd".to_vec()));
+        assert!(data_partition[5] == TapeRecord::Block(b"ef".to_vec()));
+        assert!(data_partition[6] == TapeRecord::Filemark);
+        assert!(data_partition.last() == Some(&TapeRecord::Filemark));
+
+        let data_index : String = data_partition[7..data_partition.len() - 1].iter().map(block_text).collect();
+        assert!(data_index.contains("<startblock>7</startblock>"));
+        assert!(data_index.contains("<name>a&amp;b</name>"));
+        assert!(data_index.contains("<startblock>4</startblock>"));
+        assert!(data_index.contains("<bytecount>6</bytecount>"));
+
+        let index_index : String = index_partition[4..index_partition.len() - 1].iter().map(block_text).collect();
+        assert!(index_index.contains("<previousgenerationlocation>"));
+        assert!(index_index.contains("<name>vol</name>"));
+    }
+}
@@ -0,0 +1,101 @@
+//! Machine-readable records of what went into an archive.
+//!
+//! A manifest is a text file written alongside an archive, holding one record
+//! per line. Each record starts with it's kind, followed by tab-separated
+//! fields specific to that kind:
+//!
+//!  * `member`: volume number, offset within the volume, size, type flag, and
+//!    path of an archived member
+//!  * `chunk`: offset within the archive stream, length, and SHA-256 digest of
+//!    a content-defined chunk (see `chunking`)
+//!  * `link`: type flag, path, and target of an archived symbolic or hard
+//!    link, following the `member` record for the same path
+//!  * `stream`: stream number, offset within the stream, size, type flag, and
+//!    path of a member written to one of several simultaneous archives (see
+//!    `multistream`)
+//!
+//! Only links get `link` records, so that audits can check an untrusted
+//! archive for symlinks pointing outside of it without reading every member.
+//! A hard link's target is the member holding it's data; hard links sharing a
+//! target form one group.
+//!
+//! Tools consuming manifests should ignore record kinds they don't recognize.
+
+use std::{io, path};
+use crate::tar::header::TarFileType;
+
+/// Writes manifest records to an underlying writer.
+pub struct Manifest<W: io::Write> {
+    out: W
+}
+
+/// Escape a path for inclusion in a tab-separated record.
+fn escape_path(path: &path::Path) -> String {
+    let mut escaped = String::new();
+
+    for c in path.to_string_lossy().chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\t' => escaped.push_str("\\t"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c)
+        }
+    }
+
+    escaped
+}
+
+impl<W: io::Write> Manifest<W> {
+    pub fn new(out: W) -> Manifest<W> {
+        Manifest {
+            out: out
+        }
+    }
+
+    /// Record an archived member.
+    pub fn write_member(&mut self, volume: usize, offset: u64, file_size: u64, file_type: TarFileType, path: &path::Path) -> io::Result<()> {
+        writeln!(self.out, "member\t{}\t{}\t{}\t{}\t{}", volume, offset, file_size, file_type.type_flag(), escape_path(path))
+    }
+
+    /// Record a member written to one of several simultaneous archives.
+    pub fn write_stream_member(&mut self, stream: usize, offset: u64, file_size: u64, file_type: TarFileType, path: &path::Path) -> io::Result<()> {
+        writeln!(self.out, "stream\t{}\t{}\t{}\t{}\t{}", stream, offset, file_size, file_type.type_flag(), escape_path(path))
+    }
+
+    /// Record the target of a link member.
+    pub fn write_link(&mut self, file_type: TarFileType, path: &path::Path, target: &path::Path) -> io::Result<()> {
+        writeln!(self.out, "link\t{}\t{}\t{}", file_type.type_flag(), escape_path(path), escape_path(target))
+    }
+
+    /// Record a content-defined chunk of the archive stream.
+    pub fn write_chunk(&mut self, offset: u64, length: u64, digest: &[u8]) -> io::Result<()> {
+        writeln!(self.out, "chunk\t{}\t{}\t{}", offset, length, crate::digest::to_hex(digest))
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+    use crate::tar::header::TarFileType;
+    use super::Manifest;
+
+    #[test]
+    fn manifest_records() {
+        let mut manifest = Manifest::new(vec![]);
+
+        manifest.write_member(1, 512, 3, TarFileType::FileStream, path::Path::new("dir/a\tb")).unwrap();
+        manifest.write_chunk(0, 2048, &[0xAB, 0x01]).unwrap();
+        manifest.write_link(TarFileType::SymbolicLink, path::Path::new("dir/l"), path::Path::new("../../etc")).unwrap();
+        manifest.write_stream_member(1, 1024, 0, TarFileType::Directory, path::Path::new("dir")).unwrap();
+
+        assert_eq!(String::from_utf8(manifest.into_inner()).unwrap(), "member\t1\t512\t3\t0\tdir/a\\tb\nchunk\t0\t2048\tab01\nlink\t2\tdir/l\t../../etc\nstream\t1\t1024\t0\t5\tdir\n");
+    }
+}
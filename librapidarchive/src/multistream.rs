@@ -0,0 +1,189 @@
+//! Writing one traversal to several archives at once.
+//!
+//! Sites with more than one tape drive can shorten their backup windows by
+//! writing to all of them at the same time. `write_streams` deals the members
+//! of a single traversal out to a set of sinks, each of which receives an
+//! independent, complete archive of it's share of the members. Each member
+//! goes to whichever stream has been given the fewest bytes so far, so the
+//! streams finish at about the same time.
+//!
+//! Restoring everything means restoring every stream. The `StreamedMember`s
+//! reported for each stream say which stream holds which member, and are
+//! usually recorded in a manifest.
+
+use std::{io, path, thread};
+use std::sync::mpsc::sync_channel;
+use crate::tar;
+use crate::tar::header::{HeaderGenResult, TarFileType};
+use crate::fs::ArchivalSink;
+use crate::queue::BudgetedReceiver;
+use crate::tuning::{Configuration, FsyncPolicy};
+use crate::cancel::{CancellationToken, is_cancellation};
+
+/// How many members may wait for each stream before dealing blocks.
+const STREAM_QUEUE_DEPTH: usize = 4;
+
+/// A member written to one of several streams.
+#[derive(Clone, PartialEq)]
+pub struct StreamedMember {
+    /// Which stream the member was written to, counting from zero.
+    pub stream: usize,
+
+    /// How far into the stream the member's headers begin, in bytes.
+    pub offset: u64,
+    pub file_size: u64,
+    pub file_type: TarFileType,
+    pub path: path::PathBuf,
+}
+
+/// What was written to one stream.
+#[derive(Clone, Default, PartialEq)]
+pub struct StreamResult {
+    /// The number of bytes written, including the end-of-archive marker.
+    pub bytes: u64,
+    pub members: Vec<StreamedMember>,
+}
+
+/// How many bytes a member is expected to take up in an archive.
+fn estimated_size(entry: &HeaderGenResult) -> u64 {
+    entry.encoded_header.len() as u64 + (entry.data_size() + 511) / 512 * 512
+}
+
+/// Write a stream's share of members into it's sink, then end the archive.
+fn write_stream<I>(stream: usize, sink: &mut ArchivalSink<I>, members: &std::sync::mpsc::Receiver<HeaderGenResult>, tuning: &Configuration, cancel: &CancellationToken) -> io::Result<StreamResult> {
+    let mut result = StreamResult::default();
+
+    while let Ok(entry) = members.recv() {
+        let member = tar::serialize(&entry, sink, &mut result.bytes, tuning.entry_timeout, cancel)?;
+
+        if member.voided {
+            error!(path:? = entry.original_path; "Timed out reading file, it's member in the archive is filled with zeroes");
+        }
+
+        result.members.push(StreamedMember {
+            stream: stream,
+            offset: member.offset,
+            file_size: entry.tar_header.file_size,
+            file_type: entry.tar_header.file_type,
+            path: entry.tar_header.path.as_ref().clone()
+        });
+    }
+
+    cancel.check()?;
+
+    sink.write_all(&[0; 1024])?;
+    sink.flush()?;
+    result.bytes += 1024;
+
+    if tuning.fsync != FsyncPolicy::None {
+        sink.sync()?;
+    }
+
+    Ok(result)
+}
+
+/// Write the members from a traversal across several sinks at once.
+///
+/// Each sink is written by a thread of it's own, and gets an archive of it's
+/// own, terminated and synced according to the tuning parameters. Yields what
+/// was written to each stream, in the order the sinks were given.
+///
+/// If any stream fails, the given token is cancelled so that the traversal
+/// and the other streams stop too, and the first error other than the
+/// cancellation is reported.
+pub fn write_streams<S, I>(sinks: Vec<S>, receiver: &BudgetedReceiver<HeaderGenResult>, tuning: &Configuration, cancel: &CancellationToken) -> io::Result<Vec<StreamResult>> where S: 'static + ArchivalSink<I>, I: 'static {
+    let mut senders = Vec::with_capacity(sinks.len());
+    let mut writers = Vec::with_capacity(sinks.len());
+
+    for (stream, mut sink) in sinks.into_iter().enumerate() {
+        let (sender, members) = sync_channel(STREAM_QUEUE_DEPTH);
+        let tuning = *tuning;
+        let cancel = cancel.clone();
+
+        writers.push(thread::Builder::new().name(format!("Stream Writer {}", stream)).spawn(move || {
+            let result = write_stream(stream, &mut sink, &members, &tuning, &cancel);
+
+            if result.is_err() {
+                cancel.cancel();
+            }
+
+            result
+        })?);
+        senders.push(sender);
+    }
+
+    let mut assigned = vec![0; senders.len()];
+
+    while let Ok(entry) = receiver.recv() {
+        let stream = match (0..assigned.len()).min_by_key(|&stream| assigned[stream]) {
+            Some(stream) => stream,
+            None => break
+        };
+
+        assigned[stream] += estimated_size(&entry);
+
+        //A stream only stops taking members if it failed, and cancelled
+        //everything else in the process.
+        if senders[stream].send(entry).is_err() {
+            break;
+        }
+    }
+
+    drop(senders);
+
+    let results : Vec<io::Result<StreamResult>> = writers.into_iter().map(|writer| writer.join().unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "Stream writer panicked")))).collect();
+
+    if let Some(Err(e)) = results.iter().find(|result| result.as_ref().err().map_or(false, |e| !is_cancellation(e))) {
+        return Err(io::Error::new(e.kind(), e.to_string()));
+    }
+
+    results.into_iter().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+    use crate::cancel::CancellationToken;
+    use crate::queue::budgeted_channel;
+    use crate::tar::header::{TarFormat, TarHeader, headergen_in_memory};
+    use crate::tar::reader::TarReader;
+    use crate::testing::RecordingSink;
+    use crate::tuning::Configuration;
+    use super::write_streams;
+
+    #[test]
+    fn balanced_streams() {
+        let (sender, receiver) = budgeted_channel(16, 1024 * 1024);
+
+        for (name, size) in vec![("big", 8192), ("small1", 1024), ("small2", 1024), ("small3", 1024), ("medium", 4096)] {
+            let header = TarHeader::for_test(name, crate::tar::header::TarFileType::FileStream, size as u64);
+
+            sender.send(headergen_in_memory(path::Path::new(name), header, TarFormat::USTAR, vec![1; size]).unwrap()).unwrap();
+        }
+
+        drop(sender);
+
+        let sinks = vec![RecordingSink::<u64>::new(), RecordingSink::new()];
+        let logs : Vec<_> = sinks.iter().map(|sink| sink.log()).collect();
+        let results = write_streams(sinks, &receiver, &Configuration::default(), &CancellationToken::new()).unwrap();
+
+        let names = |stream: usize| results[stream].members.iter().map(|member| member.path.to_string_lossy().into_owned()).collect::<Vec<_>>();
+        assert_eq!(names(0), vec!["big"]);
+        assert_eq!(names(1), vec!["small1", "small2", "small3", "medium"]);
+
+        for (stream, log) in logs.iter().enumerate() {
+            let data = log.data();
+            assert_eq!(data.len() as u64, results[stream].bytes);
+
+            let mut reader = TarReader::new(std::io::Cursor::new(data));
+            let mut read = vec![];
+
+            while let Some(entry) = reader.next_entry().unwrap() {
+                read.push(entry.header.path.to_string_lossy().into_owned());
+            }
+
+            assert_eq!(read, names(stream));
+            assert!(reader.is_terminated());
+        }
+    }
+}
@@ -0,0 +1,287 @@
+//! Ordered writing of archive members from many threads at once.
+//!
+//! Members of an archive are usually prepared in parallel but have to land in
+//! the archive one after the other, in a predictable order. `SharedWriter`
+//! hands out *tickets* which fix where in the archive a member will go; each
+//! producer serializes it's member and submits it under it's ticket whenever
+//! it's ready. Members submitted early are held until every member before them
+//! has been written.
+//!
+//! Held members are limited to a byte budget: producers that get too far
+//! ahead block until the stragglers catch up, the same way a full channel
+//! blocks it's senders. Dropping a ticket without submitting anything skips
+//! it, so a producer that fails can't stall the archive. Cancelling the writer
+//! wakes everyone up with an error.
+
+use std::io;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex, MutexGuard, Condvar};
+use crate::cancel::cancelled_error;
+
+struct State<W> {
+    inner: W,
+    next_ticket: u64,
+    next_write: u64,
+    held: BTreeMap<u64, Option<Vec<u8>>>,
+    held_bytes: usize,
+    bytes_written: u64,
+    error: Option<(io::ErrorKind, String)>,
+    cancelled: bool,
+}
+
+/// A writer which many threads can append whole members to, in ticket order.
+///
+/// The writer is meant to be shared with an `Arc`; tickets hold a reference
+/// to it.
+pub struct SharedWriter<W: io::Write> {
+    state: Mutex<State<W>>,
+    progress: Condvar,
+    budget: usize,
+}
+
+/// A reserved place in the archive.
+///
+/// Dropping a ticket without submitting it skips it's place.
+pub struct Ticket<W: io::Write> {
+    writer: Arc<SharedWriter<W>>,
+    sequence: u64,
+    resolved: bool,
+}
+
+impl<W: io::Write> State<W> {
+    fn check(&self) -> io::Result<()> {
+        if self.cancelled {
+            return Err(cancelled_error());
+        }
+
+        match self.error {
+            Some((kind, ref message)) => Err(io::Error::new(kind, message.clone())),
+            None => Ok(())
+        }
+    }
+
+    /// Write everything held that can now be written, in order.
+    fn drain(&mut self) -> io::Result<()> {
+        while let Some(member) = self.held.remove(&self.next_write) {
+            self.next_write += 1;
+
+            if let Some(data) = member {
+                self.held_bytes -= data.len();
+
+                if let Err(e) = self.inner.write_all(&data) {
+                    self.error = Some((e.kind(), format!("{}", e)));
+                    return Err(e);
+                }
+
+                self.bytes_written += data.len() as u64;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl<W: io::Write> SharedWriter<W> {
+    /// Construct a shared writer, holding at most `budget` bytes of members
+    /// submitted out of order.
+    ///
+    /// A single member larger than the budget is still accepted once nothing
+    /// else is held.
+    pub fn new(inner: W, budget: usize) -> Arc<SharedWriter<W>> {
+        Arc::new(SharedWriter {
+            state: Mutex::new(State {
+                inner: inner,
+                next_ticket: 0,
+                next_write: 0,
+                held: BTreeMap::new(),
+                held_bytes: 0,
+                bytes_written: 0,
+                error: None,
+                cancelled: false
+            }),
+            progress: Condvar::new(),
+            budget: budget
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State<W>> {
+        self.state.lock().unwrap()
+    }
+
+    /// Reserve the next place in the archive.
+    pub fn ticket(self: &Arc<Self>) -> Ticket<W> {
+        let mut state = self.lock();
+        let sequence = state.next_ticket;
+
+        state.next_ticket += 1;
+
+        Ticket {
+            writer: self.clone(),
+            sequence: sequence,
+            resolved: false
+        }
+    }
+
+    /// Stop all writing.
+    ///
+    /// Producers blocked waiting for budget, and anyone waiting for the writer
+    /// to finish, are woken with a cancellation error, as is anyone who
+    /// submits afterwards. Held members are discarded.
+    pub fn cancel(&self) {
+        let mut state = self.lock();
+
+        state.cancelled = true;
+        state.held.clear();
+        state.held_bytes = 0;
+
+        self.progress.notify_all();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.lock().cancelled
+    }
+
+    /// How many bytes have been written to the inner writer.
+    pub fn bytes_written(&self) -> u64 {
+        self.lock().bytes_written
+    }
+
+    /// Wait for every ticket handed out so far to be written or skipped, then
+    /// flush the inner writer.
+    pub fn finish(&self) -> io::Result<()> {
+        let mut state = self.lock();
+
+        loop {
+            state.check()?;
+
+            if state.next_write == state.next_ticket {
+                return state.inner.flush();
+            }
+
+            state = self.progress.wait(state).unwrap();
+        }
+    }
+
+    /// Take back the inner writer, once nothing else refers to the shared
+    /// writer.
+    ///
+    /// Members still held are lost; call `finish` first.
+    pub fn into_inner(writer: Arc<Self>) -> Result<W, Arc<Self>> {
+        Arc::try_unwrap(writer).map(|writer| writer.state.into_inner().unwrap().inner)
+    }
+
+    fn resolve(&self, sequence: u64, data: Option<Vec<u8>>) -> io::Result<()> {
+        let mut state = self.lock();
+        let size = data.as_ref().map(|d| d.len()).unwrap_or(0);
+
+        //Members that can't be written yet wait for budget, unless nothing is
+        //held, in which case waiting would never end.
+        while sequence != state.next_write && state.held_bytes > 0 && state.held_bytes + size > self.budget {
+            state.check()?;
+            state = self.progress.wait(state).unwrap();
+        }
+
+        state.check()?;
+
+        state.held_bytes += size;
+        state.held.insert(sequence, data);
+
+        let result = state.drain();
+
+        self.progress.notify_all();
+
+        result
+    }
+}
+
+impl<W: io::Write> Ticket<W> {
+    /// Where this ticket falls in the archive, counting from zero.
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    /// Submit a serialized member for this ticket's place in the archive.
+    ///
+    /// If the member can't be written yet, it is held until it can. This
+    /// blocks if too much data is already being held. Errors writing to the
+    /// inner writer are reported to whoever submitted the member being
+    /// written, and to every submission after that.
+    pub fn submit(mut self, data: Vec<u8>) -> io::Result<()> {
+        self.resolved = true;
+        self.writer.resolve(self.sequence, Some(data))
+    }
+
+    /// Give up this ticket's place in the archive.
+    pub fn skip(mut self) -> io::Result<()> {
+        self.resolved = true;
+        self.writer.resolve(self.sequence, None)
+    }
+}
+
+impl<W: io::Write> Drop for Ticket<W> {
+    fn drop(&mut self) {
+        if !self.resolved {
+            let _ = self.writer.resolve(self.sequence, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time};
+    use crate::cancel::is_cancellation;
+    use super::SharedWriter;
+
+    #[test]
+    fn shared_writer_orders_members() {
+        let writer = SharedWriter::new(vec![], 64);
+        let tickets : Vec<_> = (0..8).map(|_| writer.ticket()).collect();
+        let mut threads = vec![];
+
+        //Submit in reverse, with a ticket skipped, from separate threads.
+        for ticket in tickets.into_iter().rev() {
+            threads.push(thread::spawn(move || {
+                let sequence = ticket.sequence();
+
+                thread::sleep(time::Duration::from_millis(8 - sequence));
+
+                match sequence {
+                    3 => drop(ticket),
+                    _ => ticket.submit(vec![b'a' + sequence as u8; 16]).unwrap()
+                }
+            }));
+        }
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        writer.finish().unwrap();
+        assert_eq!(writer.bytes_written(), 7 * 16);
+
+        let out = SharedWriter::into_inner(writer).ok().unwrap();
+        let expected : Vec<u8> = [0u8, 1, 2, 4, 5, 6, 7].iter().flat_map(|s| vec![b'a' + s; 16]).collect();
+        assert_eq!(out, expected);
+    }
+
+    #[test]
+    fn shared_writer_cancel() {
+        let writer = SharedWriter::new(vec![], 16);
+        let first = writer.ticket();
+        let second = writer.ticket();
+        let third = writer.ticket();
+
+        second.submit(vec![0; 16]).unwrap();
+
+        //The budget is full, so this blocks until cancelled.
+        let blocked = thread::spawn(move || third.submit(vec![0; 16]));
+
+        thread::sleep(time::Duration::from_millis(20));
+        writer.cancel();
+
+        assert!(is_cancellation(&blocked.join().unwrap().err().unwrap()));
+        assert!(is_cancellation(&first.submit(vec![0; 16]).err().unwrap()));
+        assert!(is_cancellation(&writer.finish().err().unwrap()));
+        assert_eq!(writer.bytes_written(), 0);
+    }
+}
@@ -0,0 +1,215 @@
+//! Memory-bounded queues between traversal and serialization.
+//!
+//! Traversal reads ahead into each file it finds, so the entries waiting to be
+//! archived can be anywhere from a few hundred bytes to many kilobytes each.
+//! Bounding the queue by entry count alone means a directory of huge files
+//! uses far more memory than a directory of tiny ones. The queues here are
+//! bounded by the memory their entries actually hold, as well as by count.
+
+use std::mem;
+use std::sync::{Arc, Mutex, Condvar};
+use std::sync::mpsc::{sync_channel, SyncSender, Receiver, SendError, RecvError, TryRecvError};
+
+/// Something which can report how much memory it holds onto.
+pub trait MemoryFootprint {
+    /// The approximate number of bytes held by this value, including heap
+    /// allocations it owns.
+    fn memory_footprint(&self) -> usize;
+}
+
+struct BudgetState {
+    used: usize,
+    closed: bool,
+}
+
+/// A byte-counting semaphore shared by both ends of a budgeted channel.
+struct MemoryBudget {
+    state: Mutex<BudgetState>,
+    released: Condvar,
+    limit: usize,
+}
+
+/// Memory charged against a budget, which is returned when dropped.
+struct Charge {
+    budget: Arc<MemoryBudget>,
+    size: usize,
+}
+
+impl MemoryBudget {
+    /// Wait until `size` bytes fit within the budget, then charge them.
+    ///
+    /// A charge larger than the whole budget is allowed once nothing else is
+    /// charged, otherwise it would never fit. Returns `None` if the budget was
+    /// closed while waiting.
+    fn charge(budget: &Arc<MemoryBudget>, size: usize) -> Option<Charge> {
+        let mut state = budget.state.lock().unwrap();
+
+        while !state.closed && state.used > 0 && state.used + size > budget.limit {
+            state = budget.released.wait(state).unwrap();
+        }
+
+        if state.closed {
+            return None;
+        }
+
+        state.used += size;
+
+        Some(Charge {
+            budget: budget.clone(),
+            size: size
+        })
+    }
+
+    /// Wake everyone waiting on the budget, and refuse any further charges.
+    fn close(&self) {
+        self.state.lock().unwrap().closed = true;
+        self.released.notify_all();
+    }
+
+    fn used(&self) -> usize {
+        self.state.lock().unwrap().used
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.budget.state.lock().unwrap().used -= self.size;
+        self.budget.released.notify_all();
+    }
+}
+
+/// The sending half of a budgeted channel.
+pub struct BudgetedSender<T> {
+    inner: SyncSender<(T, Charge)>,
+    budget: Arc<MemoryBudget>,
+}
+
+/// The receiving half of a budgeted channel.
+///
+/// Dropping the receiver wakes any senders waiting for budget, whose sends
+/// then fail.
+pub struct BudgetedReceiver<T> {
+    inner: Receiver<(T, Charge)>,
+    budget: Arc<MemoryBudget>,
+}
+
+/// Create a channel holding at most `depth` entries, and at most
+/// `memory_limit` bytes' worth of entries.
+///
+/// Senders block when either bound is reached. A single entry larger than
+/// `memory_limit` is still accepted when the channel is otherwise empty.
+pub fn budgeted_channel<T: MemoryFootprint>(depth: usize, memory_limit: usize) -> (BudgetedSender<T>, BudgetedReceiver<T>) {
+    let (sender, receiver) = sync_channel(depth);
+    let budget = Arc::new(MemoryBudget {
+        state: Mutex::new(BudgetState {
+            used: 0,
+            closed: false
+        }),
+        released: Condvar::new(),
+        limit: memory_limit
+    });
+
+    (BudgetedSender { inner: sender, budget: budget.clone() }, BudgetedReceiver { inner: receiver, budget: budget })
+}
+
+impl<T: MemoryFootprint> BudgetedSender<T> {
+    /// Send an entry, waiting for room in the channel if necessary.
+    pub fn send(&self, entry: T) -> Result<(), SendError<T>> {
+        let size = entry.memory_footprint() + mem::size_of::<T>();
+        let charge = match MemoryBudget::charge(&self.budget, size) {
+            Some(charge) => charge,
+            None => return Err(SendError(entry))
+        };
+
+        self.inner.send((entry, charge)).map_err(|SendError((entry, _))| SendError(entry))
+    }
+}
+
+impl<T> Clone for BudgetedSender<T> {
+    fn clone(&self) -> Self {
+        BudgetedSender {
+            inner: self.inner.clone(),
+            budget: self.budget.clone()
+        }
+    }
+}
+
+impl<T> BudgetedReceiver<T> {
+    /// Wait for an entry, returning it's memory to the budget.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        self.inner.recv().map(|(entry, _)| entry)
+    }
+
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        self.inner.try_recv().map(|(entry, _)| entry)
+    }
+
+    /// How many bytes' worth of entries are currently queued.
+    pub fn queued_bytes(&self) -> usize {
+        self.budget.used()
+    }
+}
+
+impl<T> Drop for BudgetedReceiver<T> {
+    fn drop(&mut self) {
+        self.budget.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{mem, thread, time};
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use super::{MemoryFootprint, budgeted_channel};
+
+    impl MemoryFootprint for Vec<u8> {
+        fn memory_footprint(&self) -> usize {
+            self.capacity()
+        }
+    }
+
+    #[test]
+    fn budgeted_channel_limits_memory() {
+        let entry_size = 1000 + mem::size_of::<Vec<u8>>();
+        let (sender, receiver) = budgeted_channel::<Vec<u8>>(1024, entry_size * 2);
+        let sent = Arc::new(AtomicUsize::new(0));
+        let thread_sent = sent.clone();
+
+        let producer = thread::spawn(move || {
+            for _ in 0..4 {
+                sender.send(vec![0; 1000]).unwrap();
+                thread_sent.fetch_add(1, Ordering::SeqCst);
+            }
+
+            //Larger than the whole budget, so it waits for an empty queue.
+            sender.send(vec![0; 10000]).unwrap();
+        });
+
+        thread::sleep(time::Duration::from_millis(50));
+        assert_eq!(sent.load(Ordering::SeqCst), 2);
+        assert_eq!(receiver.queued_bytes(), entry_size * 2);
+
+        for _ in 0..4 {
+            assert_eq!(receiver.recv().unwrap().len(), 1000);
+        }
+
+        assert_eq!(receiver.recv().unwrap().len(), 10000);
+        producer.join().unwrap();
+        assert!(receiver.recv().is_err());
+    }
+
+    #[test]
+    fn budgeted_channel_receiver_dropped() {
+        let (sender, receiver) = budgeted_channel::<Vec<u8>>(1024, 1);
+
+        sender.send(vec![0; 16]).unwrap();
+
+        let blocked = thread::spawn(move || sender.send(vec![0; 16]));
+
+        thread::sleep(time::Duration::from_millis(20));
+        drop(receiver);
+
+        assert!(blocked.join().unwrap().is_err());
+    }
+}
@@ -0,0 +1,312 @@
+//! Automatic retry of device writes which fail for transient reasons.
+//!
+//! Tape drives (and the buses they hang off of) occasionally report errors
+//! that don't indicate any actual problem with the data: a SCSI bus reset, a
+//! busy device, or an interrupted system call. Retrying the same write a moment
+//! later almost always succeeds, so it makes little sense to fail an entire
+//! archive over one of these.
+//!
+//! Some errors are worse: after a unit attention the drive may no longer be
+//! where we left it. Those can't be retried in place; `is_position_lost`
+//! identifies them so that callers can reopen and reposition the device.
+
+use std::{io, thread, time, cmp};
+use crate::{spanning, tape};
+use crate::fs::ArchivalSink;
+
+/// Describes how many times, and how patiently, a failing write should be
+/// retried.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// How many additional attempts to make after the first write fails.
+    ///
+    /// Zero disables retrying entirely.
+    pub max_retries: u32,
+
+    /// How long to wait before the first retry.
+    pub initial_backoff: time::Duration,
+
+    /// The longest we will wait between any two attempts.
+    ///
+    /// The delay between attempts doubles after every failure until it hits
+    /// this limit.
+    pub max_backoff: time::Duration,
+}
+
+impl RetryPolicy {
+    /// A policy which never retries anything.
+    pub fn never() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 0,
+            initial_backoff: time::Duration::from_millis(0),
+            max_backoff: time::Duration::from_millis(0),
+        }
+    }
+
+    /// Determine how long to wait before making a given retry attempt.
+    ///
+    /// Attempts are numbered starting from zero for the first retry.
+    pub fn backoff_for(&self, attempt: u32) -> time::Duration {
+        let factor = 1u32.checked_shl(attempt).unwrap_or(u32::max_value());
+        let backoff = self.initial_backoff.checked_mul(factor).unwrap_or(self.max_backoff);
+
+        cmp::min(backoff, self.max_backoff)
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_backoff: time::Duration::from_millis(100),
+            max_backoff: time::Duration::from_secs(5),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn is_transient_os_error(errcode: i32) -> bool {
+    errcode == libc::EBUSY
+}
+
+#[cfg(windows)]
+fn is_transient_os_error(errcode: i32) -> bool {
+    use winapi::shared::winerror::{ERROR_BUS_RESET, ERROR_NOT_READY, ERROR_BUSY};
+
+    errcode == ERROR_BUS_RESET as i32 ||
+        errcode == ERROR_NOT_READY as i32 ||
+        errcode == ERROR_BUSY as i32
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_transient_os_error(_errcode: i32) -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn is_position_lost_os_error(errcode: i32) -> bool {
+    errcode == libc::EIO
+}
+
+#[cfg(windows)]
+fn is_position_lost_os_error(errcode: i32) -> bool {
+    use winapi::shared::winerror::{ERROR_BUS_RESET, ERROR_MEDIA_CHANGED};
+
+    //Windows reports a SCSI unit attention as ERROR_MEDIA_CHANGED, even when
+    //the media hasn't actually changed.
+    errcode == ERROR_BUS_RESET as i32 || errcode == ERROR_MEDIA_CHANGED as i32
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_position_lost_os_error(_errcode: i32) -> bool {
+    false
+}
+
+/// Determine if an error is likely to go away if the operation is retried.
+///
+/// # Platform considerations
+///
+/// On all platforms, interrupted and would-block errors are considered
+/// transient. Windows additionally treats bus resets and not-ready or busy
+/// devices as transient. UNIX systems treat `EBUSY` as transient.
+///
+/// Unit attentions are *not* transient, since the drive may have been rewound
+/// by whatever caused them. See `is_position_lost`.
+pub fn is_transient(error: &io::Error) -> bool {
+    match error.kind() {
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock => true,
+        _ => match error.raw_os_error() {
+            Some(errcode) => is_transient_os_error(errcode),
+            None => false
+        }
+    }
+}
+
+/// Determine if an error may have left a tape device at an unknown position.
+///
+/// Errors of this kind can't be fixed by retrying the write; the device has to
+/// be reopened and repositioned to the end of the data we know it committed.
+///
+/// # Platform considerations
+///
+/// Windows reports these as bus resets (once retries have been exhausted) or
+/// media changes. Linux's `st` driver reports a reset or unit attention as a
+/// generic `EIO`, which can't be told apart from a real media error, so all
+/// `EIO`s on UNIX systems are treated as having lost the tape position.
+pub fn is_position_lost(error: &io::Error) -> bool {
+    match error.raw_os_error() {
+        Some(errcode) => is_position_lost_os_error(errcode),
+        None => false
+    }
+}
+
+/// Run an operation, retrying it according to a policy if it fails with a
+/// transient error.
+///
+/// Non-transient errors, and the last transient error once the policy runs out
+/// of retries, are returned as-is.
+pub fn with_retries<T, F>(policy: &RetryPolicy, mut op: F) -> io::Result<T> where F: FnMut() -> io::Result<T> {
+    let mut attempt = 0;
+
+    loop {
+        match op() {
+            Err(ref e) if attempt < policy.max_retries && is_transient(e) => {
+                let backoff = policy.backoff_for(attempt);
+
+                warn!(attempt = attempt + 1, max_retries = policy.max_retries; "Transient device error, retrying in {:?}: {}", backoff, e);
+
+                thread::sleep(backoff);
+                attempt += 1;
+            },
+            result => return result
+        }
+    }
+}
+
+/// A writer which retries writes and flushes to it's inner writer when they
+/// fail with a transient error.
+///
+/// This should wrap the device itself, underneath any buffering, so that the
+/// retried write is exactly the one the device rejected.
+pub struct RetryingWriter<W: io::Write> {
+    inner: W,
+    policy: RetryPolicy,
+}
+
+impl<W: io::Write> RetryingWriter<W> {
+    pub fn wrap(inner: W, policy: RetryPolicy) -> RetryingWriter<W> {
+        RetryingWriter {
+            inner: inner,
+            policy: policy
+        }
+    }
+
+    pub fn as_inner_writer(&self) -> &W {
+        &self.inner
+    }
+}
+
+impl<W: io::Write> io::Write for RetryingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+
+        with_retries(&self.policy, || inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+
+        with_retries(&self.policy, || inner.flush())
+    }
+}
+
+impl<W, I> spanning::RecoverableWrite<I> for RetryingWriter<W> where W: spanning::RecoverableWrite<I> {
+    fn begin_data_zone(&mut self, ident: I) {
+        self.inner.begin_data_zone(ident);
+    }
+
+    fn resume_data_zone(&mut self, ident: I, committed: u64) {
+        self.inner.resume_data_zone(ident, committed);
+    }
+
+    fn end_data_zone(&mut self) {
+        self.inner.end_data_zone();
+    }
+
+    fn uncommitted_writes(&self) -> Vec<spanning::DataZone<I>> {
+        self.inner.uncommitted_writes()
+    }
+}
+
+impl<W, I> ArchivalSink<I> for RetryingWriter<W> where W: ArchivalSink<I> + Send {
+    fn downcast_seek(&mut self) -> Option<&mut dyn io::Seek> {
+        self.inner.downcast_seek()
+    }
+
+    fn downcast_tapedevice(&mut self) -> Option<&mut dyn tape::TapeDevice> {
+        self.inner.downcast_tapedevice()
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn committed_bytes(&self) -> Option<u64> {
+        self.inner.committed_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, time};
+    use std::io::Write;
+    use super::{RetryPolicy, RetryingWriter, is_transient};
+
+    /// Writer which fails a set number of times before accepting data.
+    struct FlakyWriter {
+        failures: u32,
+        kind: io::ErrorKind,
+        data: Vec<u8>
+    }
+
+    impl io::Write for FlakyWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::new(self.kind, "flaky"));
+            }
+
+            self.data.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn quick_policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: max_retries,
+            initial_backoff: time::Duration::from_millis(0),
+            max_backoff: time::Duration::from_millis(0),
+        }
+    }
+
+    #[test]
+    fn retry_transient() {
+        let mut writer = RetryingWriter::wrap(FlakyWriter { failures: 2, kind: io::ErrorKind::Interrupted, data: vec![] }, quick_policy(3));
+
+        assert_eq!(writer.write(b"data").unwrap(), 4);
+        assert_eq!(writer.as_inner_writer().data, b"data");
+    }
+
+    #[test]
+    fn retry_exhausted() {
+        let mut writer = RetryingWriter::wrap(FlakyWriter { failures: 4, kind: io::ErrorKind::Interrupted, data: vec![] }, quick_policy(3));
+
+        assert_eq!(writer.write(b"data").unwrap_err().kind(), io::ErrorKind::Interrupted);
+        assert_eq!(writer.as_inner_writer().failures, 0);
+    }
+
+    #[test]
+    fn retry_permanent() {
+        let mut writer = RetryingWriter::wrap(FlakyWriter { failures: 1, kind: io::ErrorKind::PermissionDenied, data: vec![] }, quick_policy(3));
+
+        assert_eq!(writer.write(b"data").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        assert!(!is_transient(&io::Error::new(io::ErrorKind::PermissionDenied, "nope")));
+    }
+
+    #[test]
+    fn retry_backoff() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_backoff: time::Duration::from_millis(100),
+            max_backoff: time::Duration::from_millis(1000),
+        };
+
+        assert_eq!(policy.backoff_for(0), time::Duration::from_millis(100));
+        assert_eq!(policy.backoff_for(2), time::Duration::from_millis(400));
+        assert_eq!(policy.backoff_for(4), time::Duration::from_millis(1000));
+        assert_eq!(policy.backoff_for(40), time::Duration::from_millis(1000));
+    }
+}
@@ -0,0 +1,533 @@
+//! Media rotation schemes for tape libraries.
+//!
+//! A rotation scheme decides which set of media each night's backup goes to,
+//! such that older backups stay around for longer without needing a new tape
+//! every night. Two schemes are supported:
+//!
+//!  * Grandfather-father-son, where each day of the week has a daily tape, the
+//!    weekly backup is kept for a month, and the backup at the end of each
+//!    month is kept for a number of months.
+//!  * Tower of Hanoi, where the first set is used every other day, the second
+//!    every fourth day, the third every eighth day, and so on, with the last
+//!    set taking every run the others don't.
+//!
+//! Media sets are bound to actual volumes the first time they are used, by
+//! taking whichever volume in the library no set has claimed yet. A
+//! `UsageCatalog` remembers those bindings between runs, along with how many
+//! times each volume has been written, so that worn-out tapes can be spotted.
+//!
+//! rapidtar does not drive media changers itself; the library's inventory is
+//! given to us, and it's up to the caller to load the slot we select. A
+//! `RotationConfig` can name a command to do that with, such as `mtx load`.
+
+use std::{io, fs, time};
+use std::str::FromStr;
+use crate::units::civil_from_days;
+use crate::snapshot::shell_command;
+
+/// The environment variable the load command is told the selected slot with.
+pub const SLOT_ENV: &str = "RAPIDTAR_SLOT";
+
+/// The environment variable the load command is told the selected volume tag
+/// with.
+pub const VOLUME_ENV: &str = "RAPIDTAR_VOLUME";
+
+/// The environment variable the load command is told the media set with.
+pub const SET_ENV: &str = "RAPIDTAR_MEDIA_SET";
+
+/// The day of the week weekly and monthly backups are taken on, counting
+/// from Sunday. This is Friday.
+pub const GFS_WEEKLY_DAY: u32 = 5;
+
+const WEEKDAY_NAMES: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+/// Which media set each day's backup is written to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RotationPolicy {
+    /// Daily, weekly and monthly sets, with a number of monthly sets to
+    /// rotate through before the first is reused.
+    GrandfatherFatherSon { months: u32 },
+
+    /// Tower of Hanoi rotation, with a number of sets from 1 to 26.
+    TowerOfHanoi { sets: u32 },
+}
+
+impl Default for RotationPolicy {
+    fn default() -> Self {
+        RotationPolicy::GrandfatherFatherSon { months: 12 }
+    }
+}
+
+impl FromStr for RotationPolicy {
+    type Err = ();
+
+    /// Parse a policy name, optionally followed by a colon and how many
+    /// monthly sets (for `gfs`) or sets (for `hanoi`) to use.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(2, ':');
+        let name = parts.next().unwrap_or("");
+        let count = match parts.next() {
+            Some(count) => Some(count.parse::<u32>().map_err(|_| ())?),
+            None => None
+        };
+
+        match (name, count) {
+            ("gfs", None) => Ok(RotationPolicy::GrandfatherFatherSon { months: 12 }),
+            ("gfs", Some(months)) if months > 0 => Ok(RotationPolicy::GrandfatherFatherSon { months: months }),
+            ("hanoi", None) => Ok(RotationPolicy::TowerOfHanoi { sets: 5 }),
+            ("hanoi", Some(sets)) if sets > 0 && sets <= 26 => Ok(RotationPolicy::TowerOfHanoi { sets: sets }),
+            _ => Err(())
+        }
+    }
+}
+
+impl RotationPolicy {
+    /// Name the media set a backup taken on a given day belongs to.
+    ///
+    /// Days are counted from the UNIX epoch, as `day_number` does.
+    pub fn set_for_day(&self, day: i64) -> String {
+        match *self {
+            RotationPolicy::GrandfatherFatherSon { months } => {
+                let weekday = (day + 4).rem_euclid(7) as u32;
+                let (year, month, date) = civil_from_days(day);
+
+                if weekday != GFS_WEEKLY_DAY {
+                    format!("daily-{}", WEEKDAY_NAMES[weekday as usize])
+                } else if civil_from_days(day + 7).1 != month {
+                    let index = (year * 12 + month as i64 - 1).rem_euclid(months as i64) + 1;
+
+                    format!("monthly-{:02}", index)
+                } else {
+                    format!("weekly-{}", (date - 1) / 7 + 1)
+                }
+            },
+            RotationPolicy::TowerOfHanoi { sets } => {
+                let set = match day {
+                    0 => sets - 1,
+                    day => std::cmp::min(day.trailing_zeros(), sets - 1)
+                };
+
+                format!("hanoi-{}", (b'A' + set as u8) as char)
+            }
+        }
+    }
+}
+
+/// Count the days between the UNIX epoch and a given time, in UTC.
+pub fn day_number(when: time::SystemTime) -> i64 {
+    match when.duration_since(time::UNIX_EPOCH) {
+        Ok(since) => (since.as_secs() / 86400) as i64,
+        Err(e) => -(e.duration().as_secs().div_ceil(86400) as i64)
+    }
+}
+
+/// A storage slot in a tape library, and the volume it holds, if any.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Slot {
+    pub number: u32,
+
+    /// The volume tag (barcode) of the tape in the slot.
+    pub volume: Option<String>,
+}
+
+/// The contents of a tape library's storage slots.
+#[derive(Clone, Debug, Default)]
+pub struct Inventory {
+    slots: Vec<Slot>
+}
+
+impl Inventory {
+    pub fn new() -> Inventory {
+        Inventory {
+            slots: Vec::new()
+        }
+    }
+
+    pub fn push(&mut self, number: u32, volume: Option<String>) {
+        self.slots.push(Slot { number: number, volume: volume });
+    }
+
+    pub fn slots(&self) -> &[Slot] {
+        &self.slots
+    }
+
+    /// Find the slot a given volume is in.
+    pub fn slot_of(&self, volume: &str) -> Option<u32> {
+        self.slots.iter().find(|slot| slot.volume.as_deref() == Some(volume)).map(|slot| slot.number)
+    }
+
+    /// Parse an inventory, one slot per line.
+    ///
+    /// Each line holds a slot number, and the tag of the volume in it
+    /// separated by a tab. Empty slots have no tag. Blank lines are ignored.
+    pub fn from_text(text: &str) -> io::Result<Inventory> {
+        let mut inventory = Inventory::new();
+
+        for line in text.lines().filter(|line| !line.trim().is_empty()) {
+            let mut fields = line.splitn(2, '\t');
+            let number = fields.next().and_then(|f| f.trim().parse().ok()).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid inventory line {:?}", line)))?;
+            let volume = fields.next().map(|f| f.trim()).filter(|f| !f.is_empty()).map(|f| f.to_string());
+
+            inventory.push(number, volume);
+        }
+
+        Ok(inventory)
+    }
+}
+
+/// What's known about one volume in a rotation.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VolumeUsage {
+    pub volume: String,
+
+    /// The media set the volume has been bound to.
+    pub set: String,
+
+    /// How many backups have been written to the volume.
+    pub uses: u64,
+
+    /// The day the volume was last written, counted from the UNIX epoch.
+    pub last_used: i64,
+}
+
+/// A record of which volumes belong to which media sets, and how often
+/// they've been used.
+#[derive(Clone, Debug, Default)]
+pub struct UsageCatalog {
+    volumes: Vec<VolumeUsage>
+}
+
+impl UsageCatalog {
+    pub fn new() -> UsageCatalog {
+        UsageCatalog {
+            volumes: Vec::new()
+        }
+    }
+
+    pub fn volumes(&self) -> &[VolumeUsage] {
+        &self.volumes
+    }
+
+    /// Find the volume bound to a given media set.
+    pub fn volume_for_set(&self, set: &str) -> Option<&VolumeUsage> {
+        self.volumes.iter().find(|usage| usage.set == set)
+    }
+
+    /// Record that a selected volume has been written to on a given day,
+    /// binding it to it's media set if this is it's first use.
+    pub fn record_use(&mut self, selection: &Selection, day: i64) {
+        match self.volumes.iter_mut().find(|usage| usage.volume == selection.volume) {
+            Some(usage) => {
+                usage.uses += 1;
+                usage.last_used = day;
+            },
+            None => self.volumes.push(VolumeUsage {
+                volume: selection.volume.clone(),
+                set: selection.set.clone(),
+                uses: 1,
+                last_used: day
+            })
+        }
+    }
+
+    /// Render the catalog as text, one volume per line.
+    ///
+    /// Each line holds the volume tag, media set, use count, and the day it
+    /// was last used, separated by tabs.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+
+        for usage in self.volumes.iter() {
+            text.push_str(&format!("{}\t{}\t{}\t{}\n", usage.volume, usage.set, usage.uses, usage.last_used));
+        }
+
+        text
+    }
+
+    /// Parse a catalog previously rendered by `to_text`.
+    pub fn from_text(text: &str) -> io::Result<UsageCatalog> {
+        let mut catalog = UsageCatalog::new();
+
+        for line in text.lines() {
+            let mut fields = line.splitn(4, '\t');
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid usage catalog line {:?}", line));
+
+            let volume = fields.next().filter(|f| !f.is_empty()).ok_or_else(invalid)?;
+            let set = fields.next().filter(|f| !f.is_empty()).ok_or_else(invalid)?;
+            let uses = fields.next().and_then(|f| f.parse().ok()).ok_or_else(invalid)?;
+            let last_used = fields.next().and_then(|f| f.parse().ok()).ok_or_else(invalid)?;
+
+            catalog.volumes.push(VolumeUsage {
+                volume: volume.to_string(),
+                set: set.to_string(),
+                uses: uses,
+                last_used: last_used
+            });
+        }
+
+        Ok(catalog)
+    }
+
+    /// Read a catalog from a file, which is empty if the file doesn't exist
+    /// yet.
+    pub fn load(path: &str) -> io::Result<UsageCatalog> {
+        match fs::read_to_string(path) {
+            Ok(text) => UsageCatalog::from_text(&text),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(UsageCatalog::new()),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Write the catalog to a file, replacing what was there.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        fs::write(path, self.to_text())
+    }
+}
+
+/// The volume chosen for a backup, and where to find it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Selection {
+    pub slot: u32,
+    pub volume: String,
+    pub set: String,
+
+    /// Indicates the volume hasn't been bound to it's set before.
+    pub first_use: bool,
+}
+
+/// Describes how to pick and load the volume for each backup.
+#[derive(Clone, Debug, Default)]
+pub struct RotationConfig {
+    /// The rotation to follow, if any.
+    pub policy: Option<RotationPolicy>,
+
+    /// The file listing the library's inventory, as read by
+    /// `Inventory::from_text`.
+    pub inventory: Option<String>,
+
+    /// The file the `UsageCatalog` is kept in between runs.
+    pub catalog: Option<String>,
+
+    /// A shell command that loads the selected volume into the drive.
+    ///
+    /// It's told the selection in the `SLOT_ENV`, `VOLUME_ENV`, and `SET_ENV`
+    /// environment variables.
+    pub load_command: Option<String>,
+}
+
+impl RotationConfig {
+    /// Determine if a rotation was requested at all.
+    pub fn is_enabled(&self) -> bool {
+        self.policy.is_some()
+    }
+
+    /// Select the volume for a backup taken on a given day.
+    ///
+    /// The usage catalog is yielded alongside the selection, for recording
+    /// it's use once the backup has succeeded.
+    pub fn select(&self, day: i64) -> io::Result<(Selection, UsageCatalog)> {
+        let (policy, inventory, catalog) = match (self.policy, self.inventory.as_ref(), self.catalog.as_ref()) {
+            (Some(policy), Some(inventory), Some(catalog)) => (policy, inventory, catalog),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "A rotation needs both an inventory and a usage catalog"))
+        };
+        let inventory = Inventory::from_text(&fs::read_to_string(inventory)?)?;
+        let usage = UsageCatalog::load(catalog)?;
+        let selection = select(&policy, &inventory, &usage, day)?;
+
+        Ok((selection, usage))
+    }
+
+    /// Run the load command for a selection.
+    ///
+    /// Yields false if there is no load command, in which case the volume
+    /// has to be loaded some other way.
+    pub fn load(&self, selection: &Selection) -> io::Result<bool> {
+        let command = match self.load_command {
+            Some(ref command) => command,
+            None => return Ok(false)
+        };
+        let status = shell_command(command)
+            .env(SLOT_ENV, selection.slot.to_string())
+            .env(VOLUME_ENV, &selection.volume)
+            .env(SET_ENV, &selection.set)
+            .status()?;
+
+        match status.success() {
+            true => Ok(true),
+            false => Err(io::Error::new(io::ErrorKind::Other, format!("Load command failed with {}", status)))
+        }
+    }
+
+    /// Record that a backup to a selected volume succeeded, in the usage
+    /// catalog.
+    pub fn record_use(&self, selection: &Selection, mut usage: UsageCatalog, day: i64) -> io::Result<()> {
+        usage.record_use(selection, day);
+
+        match self.catalog {
+            Some(ref catalog) => usage.save(catalog),
+            None => Ok(())
+        }
+    }
+}
+
+/// Select which volume a given day's backup should be written to.
+///
+/// The volume already bound to the day's media set is used if there is one,
+/// and it must be somewhere in the library. Otherwise, the volume in the
+/// lowest numbered slot that no set has claimed is selected. Nothing is
+/// recorded until `UsageCatalog::record_use` is called, which should only be
+/// done once the backup has succeeded.
+pub fn select(policy: &RotationPolicy, inventory: &Inventory, usage: &UsageCatalog, day: i64) -> io::Result<Selection> {
+    let set = policy.set_for_day(day);
+
+    if let Some(bound) = usage.volume_for_set(&set) {
+        return match inventory.slot_of(&bound.volume) {
+            Some(slot) => Ok(Selection { slot: slot, volume: bound.volume.clone(), set: set, first_use: false }),
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("Volume {} for media set {} is not in the library", bound.volume, set)))
+        };
+    }
+
+    let scratch = inventory.slots().iter().filter_map(|slot| match slot.volume {
+        Some(ref volume) if !usage.volumes().iter().any(|usage| usage.volume == *volume) => Some((slot.number, volume)),
+        _ => None
+    }).min_by_key(|(number, _)| *number);
+
+    match scratch {
+        Some((slot, volume)) => Ok(Selection { slot: slot, volume: volume.clone(), set: set, first_use: true }),
+        None => Err(io::Error::new(io::ErrorKind::NotFound, format!("No unclaimed volumes are left in the library for media set {}", set)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, io, time};
+    use super::{RotationPolicy, RotationConfig, Inventory, UsageCatalog, select, day_number};
+
+    //Days from the epoch to some Fridays and a Monday in 2024.
+    const MAY_3: i64 = 19846;
+    const MAY_24: i64 = 19867;
+    const MAY_27: i64 = 19870;
+    const MAY_31: i64 = 19874;
+    const JUNE_7: i64 = 19881;
+
+    #[test]
+    fn rotation_policy_parse() {
+        assert_eq!("gfs".parse(), Ok(RotationPolicy::GrandfatherFatherSon { months: 12 }));
+        assert_eq!("gfs:6".parse(), Ok(RotationPolicy::GrandfatherFatherSon { months: 6 }));
+        assert_eq!("hanoi".parse(), Ok(RotationPolicy::TowerOfHanoi { sets: 5 }));
+        assert_eq!("hanoi:3".parse(), Ok(RotationPolicy::TowerOfHanoi { sets: 3 }));
+        assert_eq!("hanoi:27".parse::<RotationPolicy>(), Err(()));
+        assert_eq!("gfs:0".parse::<RotationPolicy>(), Err(()));
+        assert_eq!("fifo".parse::<RotationPolicy>(), Err(()));
+    }
+
+    #[test]
+    fn rotation_gfs_sets() {
+        let gfs = RotationPolicy::GrandfatherFatherSon { months: 12 };
+
+        assert_eq!(gfs.set_for_day(MAY_3), "weekly-1");
+        assert_eq!(gfs.set_for_day(MAY_24), "weekly-4");
+        assert_eq!(gfs.set_for_day(MAY_27), "daily-mon");
+        assert_eq!(gfs.set_for_day(MAY_31), "monthly-05");
+        assert_eq!(gfs.set_for_day(JUNE_7), "weekly-1");
+
+        //With fewer monthly sets, they wrap around.
+        assert_eq!(RotationPolicy::GrandfatherFatherSon { months: 3 }.set_for_day(MAY_31), "monthly-02");
+    }
+
+    #[test]
+    fn rotation_hanoi_sets() {
+        let hanoi = RotationPolicy::TowerOfHanoi { sets: 4 };
+        let sets : Vec<String> = (1..=16).map(|day| hanoi.set_for_day(day)).collect();
+
+        assert_eq!(sets.join(","), "hanoi-A,hanoi-B,hanoi-A,hanoi-C,hanoi-A,hanoi-B,hanoi-A,hanoi-D,hanoi-A,hanoi-B,hanoi-A,hanoi-C,hanoi-A,hanoi-B,hanoi-A,hanoi-D");
+        assert_eq!(hanoi.set_for_day(0), "hanoi-D");
+    }
+
+    #[test]
+    fn rotation_select() {
+        let gfs = RotationPolicy::default();
+        let mut inventory = Inventory::new();
+        let mut usage = UsageCatalog::new();
+
+        inventory.push(1, None);
+        inventory.push(2, Some("A00002".to_string()));
+        inventory.push(3, Some("A00003".to_string()));
+
+        //New sets claim the first unclaimed volume...
+        let monday = select(&gfs, &inventory, &usage, MAY_27).unwrap();
+        assert_eq!((monday.slot, monday.volume.as_str(), monday.set.as_str(), monday.first_use), (2, "A00002", "daily-mon", true));
+        usage.record_use(&monday, MAY_27);
+
+        let friday = select(&gfs, &inventory, &usage, MAY_31).unwrap();
+        assert_eq!((friday.slot, friday.volume.as_str(), friday.first_use), (3, "A00003", true));
+        usage.record_use(&friday, MAY_31);
+
+        //...and keep it afterwards.
+        let next_monday = select(&gfs, &inventory, &usage, MAY_27 + 7).unwrap();
+        assert_eq!((next_monday.slot, next_monday.first_use), (2, false));
+        usage.record_use(&next_monday, MAY_27 + 7);
+        assert_eq!(usage.volume_for_set("daily-mon").unwrap().uses, 2);
+
+        //Every volume is claimed, so a new set can't be started.
+        assert!(select(&gfs, &inventory, &usage, MAY_27 + 1).is_err());
+
+        //Bound volumes must still be in the library.
+        let mut moved = Inventory::new();
+        moved.push(7, Some("A00003".to_string()));
+        assert!(select(&gfs, &moved, &usage, MAY_27).is_err());
+        assert_eq!(select(&gfs, &moved, &usage, MAY_31).unwrap().slot, 7);
+
+        let parsed = UsageCatalog::from_text(&usage.to_text()).unwrap();
+        assert_eq!(parsed.volumes(), usage.volumes());
+        assert!(UsageCatalog::from_text("A00002\tdaily-mon\tmany\t0\n").is_err());
+    }
+
+    #[test]
+    fn rotation_config() {
+        let dir = env::temp_dir().join(format!("rapidtar-rotation-{}", std::process::id()));
+        let inventory = dir.join("inventory");
+        let catalog = dir.join("catalog");
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&inventory, "1\n2\tA00002\n\n3\tA00003\n").unwrap();
+
+        let parsed = Inventory::from_text(&fs::read_to_string(&inventory).unwrap()).unwrap();
+        assert_eq!(parsed.slots().iter().map(|slot| (slot.number, slot.volume.clone())).collect::<Vec<_>>(), vec![(1, None), (2, Some("A00002".to_string())), (3, Some("A00003".to_string()))]);
+        assert!(Inventory::from_text("slot one\tA00001\n").is_err());
+
+        let mut config = RotationConfig { policy: Some(RotationPolicy::default()), ..RotationConfig::default() };
+        assert_eq!(config.select(MAY_27).err().unwrap().kind(), io::ErrorKind::InvalidInput);
+
+        config.inventory = Some(inventory.to_string_lossy().into_owned());
+        config.catalog = Some(catalog.to_string_lossy().into_owned());
+
+        let (monday, usage) = config.select(MAY_27).unwrap();
+        assert_eq!((monday.slot, monday.first_use), (2, true));
+        assert!(!config.load(&monday).unwrap());
+        config.record_use(&monday, usage, MAY_27).unwrap();
+
+        //The next Monday's backup goes to the same volume, as the catalog
+        //remembers it.
+        let (next_monday, _) = config.select(MAY_27 + 7).unwrap();
+        assert_eq!((next_monday.slot, next_monday.first_use), (2, false));
+
+        #[cfg(unix)]
+        {
+            config.load_command = Some(format!("echo \"$RAPIDTAR_SLOT $RAPIDTAR_VOLUME $RAPIDTAR_MEDIA_SET\" > {}", dir.join("loaded").display()));
+            assert!(config.load(&next_monday).unwrap());
+            assert_eq!(fs::read_to_string(dir.join("loaded")).unwrap(), "2 A00002 daily-mon\n");
+
+            config.load_command = Some("false".to_string());
+            assert!(config.load(&next_monday).is_err());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rotation_day_number() {
+        assert_eq!(day_number(time::UNIX_EPOCH + time::Duration::from_secs(MAY_31 as u64 * 86400 + 3600)), MAY_31);
+        assert_eq!(day_number(time::UNIX_EPOCH - time::Duration::from_secs(3600)), -1);
+    }
+}
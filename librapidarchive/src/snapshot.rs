@@ -0,0 +1,270 @@
+//! Point-in-time snapshots of the data being archived.
+//!
+//! Archiving a live filesystem means archiving files while they change, so
+//! the resulting archive may not reflect any state the system was actually
+//! in. If the data lives on a filesystem or volume manager that can snapshot
+//! it, we can instead archive a read-only snapshot, which is consistent by
+//! construction.
+//!
+//! Snapshots can be taken by user-supplied hook commands (say, for LVM, or
+//! to quiesce a database first), by rapidtar itself on btrfs, or both.
+
+use std::{io, path, env, process, ffi};
+use std::str::FromStr;
+
+/// The environment variable hook commands are told the snapshotted path with.
+pub const SOURCE_ENV: &str = "RAPIDTAR_SNAPSHOT_SOURCE";
+
+/// The environment variable the post hook is told the snapshot's path with.
+pub const SNAPSHOT_ENV: &str = "RAPIDTAR_SNAPSHOT_PATH";
+
+/// How rapidtar should take a snapshot itself.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SnapshotMethod {
+    /// Don't take a snapshot, other than what the hooks do.
+    None,
+
+    /// Take a read-only btrfs snapshot of the source subvolume.
+    Btrfs
+}
+
+impl Default for SnapshotMethod {
+    fn default() -> Self {
+        SnapshotMethod::None
+    }
+}
+
+impl FromStr for SnapshotMethod {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(SnapshotMethod::None),
+            "btrfs" => Ok(SnapshotMethod::Btrfs),
+            _ => Err(())
+        }
+    }
+}
+
+/// Describes how to snapshot a source before archiving it.
+#[derive(Clone, Debug, Default)]
+pub struct SnapshotConfig {
+    pub method: SnapshotMethod,
+
+    /// A shell command run before the snapshot is taken.
+    ///
+    /// If no other snapshot method is in use, the last line the command
+    /// prints is taken as the path of the snapshot it created. Commands that
+    /// print nothing leave the source to be archived in place.
+    pub pre_hook: Option<String>,
+
+    /// A shell command run after the snapshot is no longer needed.
+    pub post_hook: Option<String>,
+}
+
+impl SnapshotConfig {
+    /// Determine if any snapshotting was requested at all.
+    pub fn is_enabled(&self) -> bool {
+        self.method != SnapshotMethod::None || self.pre_hook.is_some() || self.post_hook.is_some()
+    }
+}
+
+/// A snapshot of a source directory, cleaned up when dropped.
+///
+/// Cleanup deletes any snapshot we took ourselves and then runs the post
+/// hook. Errors during cleanup are logged; call `release` to handle them
+/// yourself.
+pub struct Snapshot {
+    source: path::PathBuf,
+    path: path::PathBuf,
+    config: SnapshotConfig,
+    owned: bool,
+    released: bool,
+}
+
+pub(crate) fn shell_command(command: &str) -> process::Command {
+    if cfg!(windows) {
+        let mut shell = process::Command::new("cmd");
+        shell.arg("/C").arg(command);
+        shell
+    } else {
+        let mut shell = process::Command::new("sh");
+        shell.arg("-c").arg(command);
+        shell
+    }
+}
+
+/// Run a command, failing if it does not exit successfully.
+fn run(command: &mut process::Command, what: &str) -> io::Result<process::Output> {
+    let output = command.stderr(process::Stdio::inherit()).output()?;
+
+    if !output.status.success() {
+        return Err(io::Error::new(io::ErrorKind::Other, format!("{} failed with {}", what, output.status)));
+    }
+
+    Ok(output)
+}
+
+/// Make a path absolute without resolving symlinks.
+fn absolute(path: &path::Path) -> io::Result<path::PathBuf> {
+    if path.is_absolute() {
+        Ok(path.to_path_buf())
+    } else {
+        Ok(env::current_dir()?.join(path))
+    }
+}
+
+/// Pick where a btrfs snapshot of a subvolume should go.
+///
+/// Snapshots have to live on the same filesystem as their subvolume, so we
+/// put it next to the source, hidden and tagged with our process ID.
+fn btrfs_snapshot_path(source: &path::Path) -> io::Result<path::PathBuf> {
+    let parent = source.parent().ok_or(io::Error::new(io::ErrorKind::InvalidInput, "Cannot snapshot the root of the filesystem into itself"))?;
+    let mut name = ffi::OsString::from(".");
+
+    name.push(source.file_name().unwrap_or(ffi::OsStr::new("root")));
+    name.push(format!(".rapidtar-snapshot-{}", process::id()));
+
+    Ok(parent.join(name))
+}
+
+impl Snapshot {
+    /// Snapshot a source directory.
+    ///
+    /// The pre hook, if any, runs first, followed by whatever snapshot method
+    /// is configured.
+    pub fn take<P: AsRef<path::Path>>(source: P, config: &SnapshotConfig) -> io::Result<Snapshot> {
+        let source = absolute(source.as_ref())?;
+        let mut snapshot = Snapshot {
+            source: source.clone(),
+            path: source.clone(),
+            config: config.clone(),
+            owned: false,
+
+            //A failed pre hook leaves nothing for the post hook to undo.
+            released: true,
+        };
+
+        if let Some(ref pre_hook) = config.pre_hook {
+            let output = run(shell_command(pre_hook).env(SOURCE_ENV, &source), "Snapshot pre hook")?;
+            snapshot.released = false;
+
+            if config.method == SnapshotMethod::None {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+
+                if let Some(line) = stdout.lines().map(|l| l.trim()).filter(|l| l.len() > 0).last() {
+                    snapshot.path = absolute(path::Path::new(line))?;
+                }
+            }
+        }
+
+        //Without a pre hook, the post hook still has to run once we're done.
+        snapshot.released = false;
+
+        if config.method == SnapshotMethod::Btrfs {
+            let target = btrfs_snapshot_path(&source)?;
+
+            run(process::Command::new("btrfs").args(&["subvolume", "snapshot", "-r"]).arg(&source).arg(&target).stdout(process::Stdio::null()), "btrfs snapshot")?;
+
+            snapshot.path = target;
+            snapshot.owned = true;
+        }
+
+        info!(source:? = snapshot.source, snapshot:? = snapshot.path; "Archiving from snapshot");
+
+        Ok(snapshot)
+    }
+
+    /// The directory that was snapshotted.
+    pub fn source(&self) -> &path::Path {
+        &self.source
+    }
+
+    /// Where the snapshot of the source directory can be read from.
+    pub fn path(&self) -> &path::Path {
+        &self.path
+    }
+
+    /// Translate a path within the source into the same path within the
+    /// snapshot.
+    ///
+    /// Paths outside of the source are returned unchanged.
+    pub fn map_path(&self, path: &path::Path) -> io::Result<path::PathBuf> {
+        let full_path = absolute(path)?;
+
+        Ok(match full_path.strip_prefix(&self.source) {
+            Ok(rest) if rest.as_os_str().is_empty() => self.path.clone(),
+            Ok(rest) => self.path.join(rest),
+            Err(_) => path.to_path_buf()
+        })
+    }
+
+    fn cleanup(&mut self) -> io::Result<()> {
+        if self.released {
+            return Ok(());
+        }
+
+        self.released = true;
+
+        let deleted = match self.owned {
+            true => run(process::Command::new("btrfs").args(&["subvolume", "delete"]).arg(&self.path).stdout(process::Stdio::null()), "btrfs snapshot deletion").map(|_| ()),
+            false => Ok(())
+        };
+
+        //The post hook may be what releases resources the pre hook took, so
+        //it runs even if we couldn't delete our own snapshot.
+        let hooked = match self.config.post_hook {
+            Some(ref post_hook) => run(shell_command(post_hook).env(SOURCE_ENV, &self.source).env(SNAPSHOT_ENV, &self.path), "Snapshot post hook").map(|_| ()),
+            None => Ok(())
+        };
+
+        deleted.and(hooked)
+    }
+
+    /// Release the snapshot, reporting any errors encountered.
+    pub fn release(mut self) -> io::Result<()> {
+        self.cleanup()
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if let Err(e) = self.cleanup() {
+            error!(snapshot:? = self.path; "Could not clean up snapshot: {}", e);
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::path;
+    use super::{Snapshot, SnapshotConfig, btrfs_snapshot_path};
+
+    #[test]
+    fn snapshot_hooks() {
+        let dir = std::env::temp_dir().join(format!("rapidtar-snapshot-test-{}", std::process::id()));
+        let marker = dir.join("post-hook-ran");
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let config = SnapshotConfig {
+            pre_hook: Some("echo; echo /mnt/snap".to_string()),
+            post_hook: Some(format!("echo \"$RAPIDTAR_SNAPSHOT_PATH\" > {:?}", marker)),
+            ..SnapshotConfig::default()
+        };
+
+        let snapshot = Snapshot::take("/srv/data", &config).unwrap();
+        assert_eq!(snapshot.path(), path::Path::new("/mnt/snap"));
+        assert_eq!(snapshot.map_path(path::Path::new("/srv/data/a/b")).unwrap(), path::PathBuf::from("/mnt/snap/a/b"));
+        assert_eq!(snapshot.map_path(path::Path::new("/srv/data")).unwrap(), path::PathBuf::from("/mnt/snap"));
+        assert_eq!(snapshot.map_path(path::Path::new("/srv/other")).unwrap(), path::PathBuf::from("/srv/other"));
+
+        snapshot.release().unwrap();
+        assert_eq!(std::fs::read_to_string(&marker).unwrap(), "/mnt/snap\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let failing = SnapshotConfig { pre_hook: Some("exit 1".to_string()), ..SnapshotConfig::default() };
+        assert!(Snapshot::take("/srv/data", &failing).is_err());
+
+        assert_eq!(btrfs_snapshot_path(path::Path::new("/srv/data")).unwrap(), path::PathBuf::from(format!("/srv/.data.rapidtar-snapshot-{}", std::process::id())));
+    }
+}
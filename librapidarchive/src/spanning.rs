@@ -1,8 +1,10 @@
 //! Facilities for tracking data within a write buffer for error recovery.
 
 use std::{io, fs, cmp};
+use std::str::FromStr;
 use std::collections::VecDeque;
 use crate::{fs as rapidtar_fs, tape};
+use crate::units::{DataSize, DataSizeError};
 
 /// Represents data which has been committed to a write buffer and may fail to
 /// be written to the device.
@@ -414,6 +416,24 @@ pub trait RecoverableWrite<P> : io::Write {
 impl <T, P> RecoverableWrite<P> for io::Cursor<T> where io::Cursor<T> : io::Write {
 }
 
+impl <S, P> RecoverableWrite<P> for Box<S> where S: RecoverableWrite<P> + ?Sized {
+    fn begin_data_zone(&mut self, ident: P) {
+        (**self).begin_data_zone(ident);
+    }
+
+    fn resume_data_zone(&mut self, ident: P, committed: u64) {
+        (**self).resume_data_zone(ident, committed);
+    }
+
+    fn end_data_zone(&mut self) {
+        (**self).end_data_zone();
+    }
+
+    fn uncommitted_writes(&self) -> Vec<DataZone<P>> {
+        (**self).uncommitted_writes()
+    }
+}
+
 impl <P> RecoverableWrite<P> for fs::File {
 }
 
@@ -532,11 +552,78 @@ impl<W, I> rapidtar_fs::ArchivalSink<I> for LimitingWriter<W> where W: rapidtar_
     fn downcast_tapedevice(&mut self) -> Option<&mut dyn tape::TapeDevice> {
         self.inner.downcast_tapedevice()
     }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.inner.sync()
+    }
+
+    fn committed_bytes(&self) -> Option<u64> {
+        self.inner.committed_bytes()
+    }
+}
+
+/// The sizes of each volume of a multi-volume archive.
+///
+/// Media sets aren't always uniform: the first tape may already be partly
+/// used, or the set may mix tapes of different generations. A schedule lists
+/// the size of each volume in order. Volumes past the end of the schedule are
+/// the same size as the last one listed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VolumeSizeSchedule {
+    sizes: Vec<u64>
+}
+
+impl VolumeSizeSchedule {
+    pub fn new(sizes: Vec<u64>) -> VolumeSizeSchedule {
+        VolumeSizeSchedule {
+            sizes: sizes
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.sizes.is_empty()
+    }
+
+    /// The size limit of a given volume, numbered from 1.
+    ///
+    /// Yields `None` if the schedule is empty, in which case volumes are
+    /// unlimited.
+    pub fn limit_for_volume(&self, volume: usize) -> Option<u64> {
+        let index = cmp::min(volume.saturating_sub(1), self.sizes.len().checked_sub(1)?);
+
+        Some(self.sizes[index])
+    }
+}
+
+impl FromStr for VolumeSizeSchedule {
+    type Err = DataSizeError<<u64 as FromStr>::Err>;
+
+    /// Parse a comma-separated list of data sizes, such as `200G,2500G`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut sizes = Vec::new();
+
+        for size in s.split(',') {
+            sizes.push(DataSize::<u64>::from_str(size.trim())?.into_inner());
+        }
+
+        Ok(VolumeSizeSchedule::new(sizes))
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{DataZone, DataZoneStream};
+    use super::{DataZone, DataZoneStream, VolumeSizeSchedule};
+
+    #[test]
+    fn volume_size_schedule() {
+        let schedule : VolumeSizeSchedule = "100k, 2M".parse().unwrap();
+
+        assert_eq!(schedule.limit_for_volume(1), Some(100 * 1024));
+        assert_eq!(schedule.limit_for_volume(2), Some(2 * 1024 * 1024));
+        assert_eq!(schedule.limit_for_volume(5), Some(2 * 1024 * 1024));
+        assert_eq!(VolumeSizeSchedule::default().limit_for_volume(1), None);
+        assert!("100k,".parse::<VolumeSizeSchedule>().is_err());
+    }
 
     #[test]
     fn datazone_buffer() {
@@ -0,0 +1,132 @@
+//! The status of a running archive operation.
+//!
+//! Whatever is writing an archive keeps an `ArchiveStatus` up to date as it
+//! goes, and anything else holding the status may read it at the same time:
+//! progress displays, supervisors of batch jobs, and so on. Totals are kept in
+//! atomics, so reading them never waits on the writer. The per-volume
+//! breakdown is kept behind a lock, which is only ever held briefly.
+//!
+//! The status also carries the operation's `CancellationToken`, so that
+//! whoever is watching an operation can stop it.
+
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use crate::cancel::CancellationToken;
+
+/// How much has been written to a single volume.
+///
+/// Byte counts only include data committed to the volume; anything recovered
+/// onto the next volume counts against that one instead.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct VolumeStatus {
+    pub bytes: u64,
+    pub members: usize,
+}
+
+/// Counters describing the progress of an archive operation.
+pub struct ArchiveStatus {
+    cancel: CancellationToken,
+    bytes: AtomicU64,
+    volumes: Mutex<Vec<VolumeStatus>>,
+}
+
+impl ArchiveStatus {
+    /// Create the status of an operation which has yet to write anything to
+    /// it's first volume.
+    pub fn new(cancel: CancellationToken) -> ArchiveStatus {
+        ArchiveStatus {
+            cancel: cancel,
+            bytes: AtomicU64::new(0),
+            volumes: Mutex::new(vec![VolumeStatus::default()])
+        }
+    }
+
+    /// The token which stops the operation.
+    pub fn cancel_token(&self) -> &CancellationToken {
+        &self.cancel
+    }
+
+    /// Ask the operation to stop.
+    pub fn cancel(&self) {
+        self.cancel.cancel();
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancel.is_cancelled()
+    }
+
+    /// How many bytes have been written across all volumes.
+    pub fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Count bytes written to the archive.
+    pub fn add_bytes(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// How many volumes have been started, including the current one.
+    pub fn volume_count(&self) -> usize {
+        self.volumes.lock().unwrap().len()
+    }
+
+    /// How much has been written to each volume, in order.
+    pub fn volumes(&self) -> Vec<VolumeStatus> {
+        self.volumes.lock().unwrap().clone()
+    }
+
+    /// How much has been written to the current volume.
+    pub fn current_volume(&self) -> VolumeStatus {
+        self.volumes.lock().unwrap().last().cloned().unwrap_or_default()
+    }
+
+    /// Record how much has been written to the current volume.
+    pub fn update_volume(&self, volume: VolumeStatus) {
+        let mut volumes = self.volumes.lock().unwrap();
+
+        match volumes.last_mut() {
+            Some(last) => *last = volume,
+            None => volumes.push(volume)
+        }
+    }
+
+    /// Start counting a new volume.
+    pub fn begin_volume(&self) {
+        self.volumes.lock().unwrap().push(VolumeStatus::default());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::sync::Arc;
+    use crate::cancel::CancellationToken;
+    use super::{ArchiveStatus, VolumeStatus};
+
+    #[test]
+    fn archive_status() {
+        let status = Arc::new(ArchiveStatus::new(CancellationToken::new()));
+        let writer = {
+            let status = status.clone();
+
+            thread::spawn(move || {
+                status.add_bytes(1536);
+                status.update_volume(VolumeStatus { bytes: 1536, members: 1 });
+                status.begin_volume();
+                status.add_bytes(512);
+                status.update_volume(VolumeStatus { bytes: 512, members: 1 });
+            })
+        };
+
+        writer.join().unwrap();
+
+        assert_eq!(status.bytes(), 2048);
+        assert_eq!(status.volume_count(), 2);
+        assert_eq!(status.volumes(), vec![VolumeStatus { bytes: 1536, members: 1 }, VolumeStatus { bytes: 512, members: 1 }]);
+        assert_eq!(status.current_volume().bytes, 512);
+
+        let token = status.cancel_token().clone();
+        status.cancel();
+        assert!(token.is_cancelled());
+    }
+}
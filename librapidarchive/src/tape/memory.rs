@@ -0,0 +1,531 @@
+//! In-memory tape device impls
+//!
+//! `MemoryTapeDevice` models each partition of a tape as a list of records, so
+//! that code which positions and reads tapes can be exercised without a drive
+//! attached. It follows the same positioning rules the real devices are
+//! expected to follow; see `TapeDevice`.
+
+use std::{io, mem, cmp};
+use std::convert::TryFrom;
+use std::marker::PhantomData;
+use crate::tape::{TapeDevice, SeekOrigin, FilemarkPolicy, decompose_seek};
+use crate::fs::{ArchivalSink, ArchivalSource};
+use crate::spanning::RecoverableWrite;
+
+/// A single thing recorded on an in-memory tape.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TapeRecord {
+    Block(Vec<u8>),
+    Filemark,
+    Setmark
+}
+
+impl TapeRecord {
+    fn is_mark(&self) -> bool {
+        match self {
+            TapeRecord::Block(_) => false,
+            _ => true
+        }
+    }
+}
+
+pub struct MemoryTapeDevice<P = u64> {
+    /// The records of the current partition.
+    records: Vec<TapeRecord>,
+
+    /// The records of every partition. The current partition's slot is left
+    /// empty while it's records are in `records`.
+    partitions: Vec<Vec<TapeRecord>>,
+    partition: usize,
+    position: usize,
+    naninani: PhantomData<P>,
+    block_spill_buffer: Vec<u8>,
+    block_spill_read_pos: usize,
+    eof_condition: bool,
+    fixed_block_size: Option<u32>,
+    compression: bool,
+}
+
+impl<P> MemoryTapeDevice<P> {
+    /// Create a blank tape.
+    pub fn new() -> Self {
+        MemoryTapeDevice::with_records(vec![])
+    }
+
+    /// Create a tape that has already been recorded on, positioned at the
+    /// beginning.
+    pub fn with_records(records: Vec<TapeRecord>) -> Self {
+        MemoryTapeDevice {
+            records: records,
+            partitions: vec![vec![]],
+            partition: 0,
+            position: 0,
+            naninani: PhantomData,
+            block_spill_buffer: Vec::new(),
+            block_spill_read_pos: 0,
+            eof_condition: false,
+            fixed_block_size: None,
+            compression: true,
+        }
+    }
+
+    /// Put the tape into fixed block mode, or back into variable block mode.
+    ///
+    /// In fixed block mode, writes which aren't a whole number of blocks fail,
+    /// as they would on a real drive.
+    pub fn set_fixed_block_size(&mut self, block_size: Option<u32>) {
+        self.fixed_block_size = block_size;
+    }
+
+    /// Create a blank tape formatted with a number of partitions, positioned
+    /// at the beginning of the first.
+    pub fn with_partitions(count: u32) -> Self {
+        let mut tape = MemoryTapeDevice::new();
+
+        tape.partitions = vec![vec![]; cmp::max(count, 1) as usize];
+
+        tape
+    }
+
+    /// Whether the drive would compress what's written to it, as real drives
+    /// do by default.
+    pub fn compression(&self) -> bool {
+        self.compression
+    }
+
+    /// Everything recorded in the current partition.
+    pub fn records(&self) -> &[TapeRecord] {
+        &self.records
+    }
+
+    /// Everything recorded in a partition, numbered from 1.
+    pub fn partition_records(&self, id: u32) -> &[TapeRecord] {
+        match (id as usize).checked_sub(1) {
+            Some(index) if index == self.partition => &self.records,
+            Some(index) if index < self.partitions.len() => &self.partitions[index],
+            _ => &[]
+        }
+    }
+
+    /// Record something at the current position, erasing everything after it.
+    fn record(&mut self, record: TapeRecord) {
+        self.eof_condition = false;
+        self.block_spill_read_pos = 0;
+        self.records.truncate(self.position);
+        self.records.push(record);
+        self.position += 1;
+    }
+
+    fn read_next_block(&mut self) {
+        self.block_spill_buffer.clear();
+
+        match self.records.get(self.position) {
+            Some(TapeRecord::Block(data)) => {
+                self.block_spill_buffer.extend_from_slice(data);
+                self.position += 1;
+            },
+            Some(_) => {
+                self.position += 1;
+                self.eof_condition = true;
+            },
+            None => self.eof_condition = true
+        }
+    }
+
+    /// Space over `count` records, stopping early after crossing a mark.
+    fn space_blocks(&mut self, count: i64) -> io::Result<()> {
+        if count >= 0 {
+            for _ in 0..count {
+                match self.records.get(self.position) {
+                    Some(record) => {
+                        self.position += 1;
+
+                        if record.is_mark() {
+                            break;
+                        }
+                    },
+                    None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Reached end of recorded data"))
+                }
+            }
+        } else {
+            for _ in 0..count.unsigned_abs() {
+                if self.position == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Reached beginning of partition"));
+                }
+
+                self.position -= 1;
+
+                if self.records[self.position].is_mark() {
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Space over `count` marks of a particular kind.
+    fn space_marks(&mut self, count: i64, mark: TapeRecord) -> io::Result<()> {
+        let mut remaining = count.unsigned_abs();
+
+        while remaining > 0 {
+            if count > 0 {
+                match self.records.get(self.position) {
+                    Some(record) => {
+                        self.position += 1;
+
+                        if *record == mark {
+                            remaining -= 1;
+                        }
+                    },
+                    None => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Reached end of recorded data"))
+                }
+            } else {
+                if self.position == 0 {
+                    return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Reached beginning of partition"));
+                }
+
+                self.position -= 1;
+
+                if self.records[self.position] == mark {
+                    remaining -= 1;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn seek_marks(&mut self, pos: io::SeekFrom, mark: TapeRecord) -> io::Result<()> {
+        let (origin, count) = decompose_seek(pos)?;
+
+        self.eof_condition = false;
+        self.block_spill_read_pos = 0;
+
+        match origin {
+            SeekOrigin::Beginning => self.position = 0,
+            SeekOrigin::Current => {},
+            SeekOrigin::EndOfData => self.position = self.records.len()
+        }
+
+        self.space_marks(count, mark)
+    }
+}
+
+impl<P> Default for MemoryTapeDevice<P> {
+    fn default() -> Self {
+        MemoryTapeDevice::new()
+    }
+}
+
+impl<P> io::Write for MemoryTapeDevice<P> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.len() == 0 {
+            return Ok(0);
+        }
+
+        if let Some(block_size) = self.fixed_block_size {
+            if data.len() % block_size as usize != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "Writes to a tape in fixed block mode must be whole blocks"));
+            }
+        }
+
+        self.record(TapeRecord::Block(data.to_vec()));
+
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<P> io::Read for MemoryTapeDevice<P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut wrote = 0;
+
+        while wrote < buf.len() {
+            let remain = buf.len() - wrote;
+
+            if self.block_spill_read_pos == 0 {
+                if self.eof_condition {
+                    break;
+                }
+
+                self.read_next_block();
+
+                if self.eof_condition {
+                    break;
+                }
+            }
+
+            let spill_remain = self.block_spill_buffer.len() - self.block_spill_read_pos;
+            let copied = if spill_remain < remain { spill_remain } else { remain };
+
+            buf[wrote..wrote + copied].copy_from_slice(&self.block_spill_buffer[self.block_spill_read_pos..self.block_spill_read_pos + copied]);
+            wrote += copied;
+
+            if copied == spill_remain {
+                self.block_spill_read_pos = 0;
+            } else {
+                self.block_spill_read_pos += copied;
+            }
+        }
+
+        Ok(wrote)
+    }
+}
+
+impl<P> RecoverableWrite<P> for MemoryTapeDevice<P> where P: Clone {
+}
+
+impl<P> ArchivalSink<P> for MemoryTapeDevice<P> where P: Send + Clone {
+    fn downcast_tapedevice(&mut self) -> Option<&mut dyn TapeDevice> {
+        Some(self)
+    }
+}
+
+impl<P> ArchivalSource for MemoryTapeDevice<P> where P: Send {
+    fn downcast_tapedevice(&mut self) -> Option<&mut dyn TapeDevice> {
+        Some(self)
+    }
+}
+
+impl<P> TapeDevice for MemoryTapeDevice<P> {
+    fn read_block(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        if self.block_spill_read_pos == 0 {
+            if self.eof_condition {
+                buf.clear();
+                return Ok(());
+            }
+
+            self.read_next_block();
+        } else {
+            self.block_spill_buffer.drain(..self.block_spill_read_pos);
+        }
+
+        mem::swap(buf, &mut self.block_spill_buffer);
+
+        self.block_spill_buffer = Vec::new();
+        self.block_spill_read_pos = 0;
+
+        Ok(())
+    }
+
+    fn write_filemark(&mut self, _blocking: bool) -> io::Result<()> {
+        self.record(TapeRecord::Filemark);
+
+        Ok(())
+    }
+
+    fn write_setmark(&mut self, _blocking: bool) -> io::Result<()> {
+        self.record(TapeRecord::Setmark);
+
+        Ok(())
+    }
+
+    /// In-memory tapes are never closed, so nothing is marked on them.
+    fn set_filemark_policy(&mut self, _policy: FilemarkPolicy) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn set_compression(&mut self, enabled: bool) -> io::Result<()> {
+        self.compression = enabled;
+
+        Ok(())
+    }
+
+    fn fixed_block_size(&self) -> Option<u32> {
+        self.fixed_block_size
+    }
+
+    fn seek_blocks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        let (origin, count) = decompose_seek(pos)?;
+
+        self.eof_condition = false;
+        self.block_spill_read_pos = 0;
+
+        match origin {
+            SeekOrigin::Beginning => match usize::try_from(count) {
+                Ok(block) if block <= self.records.len() => {
+                    self.position = block;
+
+                    Ok(())
+                },
+                _ => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Block is past the end of recorded data"))
+            },
+            SeekOrigin::Current => self.space_blocks(count),
+            SeekOrigin::EndOfData => {
+                self.position = self.records.len();
+
+                self.space_blocks(count)
+            }
+        }
+    }
+
+    fn tell_blocks(&mut self) -> io::Result<u64> {
+        Ok(self.position as u64)
+    }
+
+    fn seek_filemarks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        self.seek_marks(pos, TapeRecord::Filemark)
+    }
+
+    fn seek_setmarks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        self.seek_marks(pos, TapeRecord::Setmark)
+    }
+
+    fn seek_partition(&mut self, id: u32) -> io::Result<()> {
+        if id == 0 {
+            return Ok(());
+        }
+
+        let index = id as usize - 1;
+
+        if index >= self.partitions.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "Tape does not have that many partitions"));
+        }
+
+        mem::swap(&mut self.records, &mut self.partitions[self.partition]);
+        mem::swap(&mut self.records, &mut self.partitions[index]);
+
+        self.partition = index;
+        self.position = 0;
+        self.eof_condition = false;
+        self.block_spill_read_pos = 0;
+
+        Ok(())
+    }
+
+    fn current_partition(&mut self) -> io::Result<u32> {
+        Ok(self.partition as u32 + 1)
+    }
+
+    fn partition_count(&mut self) -> io::Result<u32> {
+        Ok(self.partitions.len() as u32)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::io::{Read, Write};
+    use crate::tape::TapeDevice;
+    use super::MemoryTapeDevice;
+
+    /// Two tape files of three blocks each, each followed by a filemark.
+    fn two_file_tape() -> MemoryTapeDevice {
+        let mut tape = MemoryTapeDevice::new();
+
+        for file in [b'a', b'b'].iter() {
+            for block in 0..3 {
+                tape.write_all(&[*file, b'0' + block]).unwrap();
+            }
+
+            tape.write_filemark(true).unwrap();
+        }
+
+        tape
+    }
+
+    #[test]
+    fn memory_tape_seek_blocks() {
+        let mut tape = two_file_tape();
+
+        assert_eq!(tape.tell_blocks().unwrap(), 8);
+
+        tape.seek_blocks(io::SeekFrom::Current(-2)).unwrap();
+        assert_eq!(tape.tell_blocks().unwrap(), 7);
+
+        tape.seek_blocks(io::SeekFrom::Current(-2)).unwrap();
+        assert_eq!(tape.tell_blocks().unwrap(), 5);
+
+        tape.seek_blocks(io::SeekFrom::Start(1)).unwrap();
+        assert_eq!(tape.tell_blocks().unwrap(), 1);
+
+        tape.seek_blocks(io::SeekFrom::Current(5)).unwrap();
+        assert_eq!(tape.tell_blocks().unwrap(), 4);
+
+        tape.seek_blocks(io::SeekFrom::End(0)).unwrap();
+        assert_eq!(tape.tell_blocks().unwrap(), 8);
+
+        tape.seek_blocks(io::SeekFrom::End(-1)).unwrap();
+        assert_eq!(tape.tell_blocks().unwrap(), 7);
+
+        assert!(tape.seek_blocks(io::SeekFrom::End(1)).is_err());
+        assert!(tape.seek_blocks(io::SeekFrom::Start(9)).is_err());
+        assert!(tape.seek_blocks(io::SeekFrom::Start(i64::max_value() as u64)).is_err());
+
+        tape.seek_blocks(io::SeekFrom::Start(0)).unwrap();
+        assert!(tape.seek_blocks(io::SeekFrom::Current(i64::min_value())).is_err());
+        assert!(tape.seek_filemarks(io::SeekFrom::Current(i64::min_value())).is_err());
+    }
+
+    #[test]
+    fn memory_tape_seek_filemarks() {
+        let mut tape = two_file_tape();
+        let mut block = vec![];
+
+        tape.seek_filemarks(io::SeekFrom::Start(1)).unwrap();
+        assert_eq!(tape.tell_blocks().unwrap(), 4);
+
+        tape.read_block(&mut block).unwrap();
+        assert_eq!(block, b"b0");
+
+        tape.seek_filemarks(io::SeekFrom::Current(-1)).unwrap();
+        assert_eq!(tape.tell_blocks().unwrap(), 3);
+
+        tape.seek_filemarks(io::SeekFrom::End(-1)).unwrap();
+        assert_eq!(tape.tell_blocks().unwrap(), 7);
+
+        tape.seek_filemarks(io::SeekFrom::Start(0)).unwrap();
+        assert_eq!(tape.tell_blocks().unwrap(), 0);
+
+        assert!(tape.seek_filemarks(io::SeekFrom::Start(3)).is_err());
+        assert!(tape.seek_filemarks(io::SeekFrom::End(1)).is_err());
+    }
+
+    #[test]
+    fn memory_tape_read_stops_at_filemark() {
+        let mut tape = two_file_tape();
+        let mut data = vec![];
+
+        tape.seek_filemarks(io::SeekFrom::Start(0)).unwrap();
+        tape.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"a0a1a2");
+
+        tape.seek_filemarks(io::SeekFrom::Current(0)).unwrap();
+        data.clear();
+        tape.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"b0b1b2");
+    }
+
+    #[test]
+    fn memory_tape_compression() {
+        let mut tape = MemoryTapeDevice::<u64>::new();
+        assert!(tape.compression());
+
+        tape.set_compression(false).unwrap();
+        assert!(!tape.compression());
+
+        tape.set_compression(true).unwrap();
+        assert!(tape.compression());
+    }
+
+    #[test]
+    fn memory_tape_partitions() {
+        //Unpartitioned tapes have just partition 1, and 0 stays put.
+        let mut tape = MemoryTapeDevice::<u64>::new();
+        assert_eq!(tape.partition_count().unwrap(), 1);
+        tape.seek_partition(0).unwrap();
+        tape.seek_partition(1).unwrap();
+        assert!(tape.seek_partition(2).is_err());
+
+        let mut tape = MemoryTapeDevice::<u64>::with_partitions(2);
+        tape.write_all(b"data").unwrap();
+        tape.seek_partition(2).unwrap();
+        assert_eq!(tape.current_partition().unwrap(), 2);
+        assert!(tape.records().is_empty());
+        assert_eq!(tape.partition_records(1).len(), 1);
+    }
+}
@@ -1,13 +1,232 @@
 //! Abstraction layer for platform-specific magnetic tape behaviors.
 
-use std::io;
+use std::{io, thread, time};
+use std::str::FromStr;
 
-#[cfg(windows)]
+#[cfg(all(windows, feature = "tape"))]
 pub mod windows;
 
-#[cfg(unix)]
+#[cfg(all(unix, feature = "tape"))]
 pub mod unix;
 
+pub mod memory;
+pub mod probe;
+
+/// Where a tape seek is measured from.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SeekOrigin {
+    /// The beginning of the current partition.
+    Beginning,
+
+    /// The current tape position.
+    Current,
+
+    /// The end of recorded data in the current partition.
+    EndOfData
+}
+
+/// Break a seek down into an origin and a signed count of units to space from
+/// there.
+///
+/// Tapes can't be positioned past the end of recorded data, so seeks from the
+/// end must have a count of zero or less. Counts which don't fit in an `i64`
+/// are also rejected.
+pub fn decompose_seek(pos: io::SeekFrom) -> io::Result<(SeekOrigin, i64)> {
+    match pos {
+        io::SeekFrom::Start(count) if count > i64::max_value() as u64 => Err(io::Error::new(io::ErrorKind::InvalidInput, "Tape seek is too far")),
+        io::SeekFrom::Start(count) => Ok((SeekOrigin::Beginning, count as i64)),
+        io::SeekFrom::Current(count) => Ok((SeekOrigin::Current, count)),
+        io::SeekFrom::End(count) if count > 0 => Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot seek past the end of recorded data")),
+        io::SeekFrom::End(count) => Ok((SeekOrigin::EndOfData, count))
+    }
+}
+
+/// Split a signed count of units into the low and high halves of a 64-bit
+/// two's complement offset, as the Windows tape API takes them.
+///
+/// Negative counts keep their sign in the high half, so spacing backwards from
+/// the end of data (or anywhere else) is split correctly.
+pub fn split_offset(count: i64) -> (u32, u32) {
+    let offset = u64::from_ne_bytes(count.to_ne_bytes());
+
+    ((offset & 0xFFFFFFFF) as u32, (offset >> 32) as u32)
+}
+
+/// How the end of the data written to a tape is marked.
+/// 
+/// Tape devices mark the end of what was written to them when they are closed
+/// after writing. Sites and other tools differ on what that mark should be.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FilemarkPolicy {
+    /// End with a single filemark.
+    Single,
+
+    /// End with two filemarks, leaving the tape positioned between them.
+    /// 
+    /// Reading two filemarks in a row traditionally signals the end of
+    /// recorded data. Writing from between them overwrites the second.
+    Double,
+
+    /// End with a setmark.
+    Setmark
+}
+
+impl FromStr for FilemarkPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "single" => Ok(FilemarkPolicy::Single),
+            "double" => Ok(FilemarkPolicy::Double),
+            "setmark" => Ok(FilemarkPolicy::Setmark),
+            _ => Err(())
+        }
+    }
+}
+
+/// What access to a tape device is requested when opening it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TapeAccess {
+    /// Read and write. Write-protected media can't be opened this way.
+    ReadWrite,
+
+    /// Read only.
+    ReadOnly,
+}
+
+/// What other handles may be opened on a tape device while we have it open.
+///
+/// Only Windows honors this. UNIX tape drivers decide for themselves; the
+/// `st` driver only ever allows one open at a time.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TapeShare {
+    /// Nobody else may open the device.
+    Exclusive,
+
+    /// Others may open the device for reading, such as monitoring tools
+    /// querying the drive's status.
+    Read,
+
+    /// Others may open the device for reading or writing.
+    ReadWrite,
+}
+
+impl FromStr for TapeShare {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exclusive" => Ok(TapeShare::Exclusive),
+            "read" => Ok(TapeShare::Read),
+            "readwrite" => Ok(TapeShare::ReadWrite),
+            _ => Err(())
+        }
+    }
+}
+
+/// How a tape device's block size is configured when opening it.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum BlockMode {
+    /// Leave the drive configured as it already is.
+    Unchanged,
+
+    /// Variable block mode, where each write becomes one block.
+    Variable,
+
+    /// Fixed block mode, with blocks of the given size in bytes.
+    Fixed(u32),
+}
+
+/// Options for opening a tape device.
+///
+/// The defaults are what rapidtar itself uses: exclusive read/write access,
+/// retrying up to five times at 10ms intervals if the drive is still settling
+/// after a media change. Windows tape drives are also put into variable block
+/// mode by default, since they can't be read without a block size; elsewhere
+/// the drive's configuration is left alone.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct TapeOptions {
+    pub access: TapeAccess,
+    pub share: TapeShare,
+
+    /// How many times to retry opening a device which failed to open for a
+    /// transient reason, such as the media having just been changed.
+    pub open_retries: u32,
+
+    /// How long to wait between retries.
+    pub open_retry_delay: time::Duration,
+
+    pub block_mode: BlockMode,
+
+    /// The block size to fall back on if the drive can't be put into
+    /// variable block mode, as is the case for some older drives.
+    pub fallback_block_size: Option<u32>,
+}
+
+impl Default for TapeOptions {
+    fn default() -> Self {
+        TapeOptions {
+            access: TapeAccess::ReadWrite,
+            share: TapeShare::Exclusive,
+            open_retries: 5,
+            open_retry_delay: time::Duration::from_millis(10),
+            block_mode: if cfg!(windows) { BlockMode::Variable } else { BlockMode::Unchanged },
+            fallback_block_size: None
+        }
+    }
+}
+
+impl TapeOptions {
+    /// The default options, but without requesting write access.
+    pub fn read_only() -> TapeOptions {
+        TapeOptions {
+            access: TapeAccess::ReadOnly,
+            ..TapeOptions::default()
+        }
+    }
+
+    /// The block mode to try if `block_mode` couldn't be set, if any.
+    pub fn fallback_block_mode(&self) -> Option<BlockMode> {
+        match (self.block_mode, self.fallback_block_size) {
+            (BlockMode::Variable, Some(size)) => Some(BlockMode::Fixed(size)),
+            _ => None
+        }
+    }
+
+    /// Attempt to open a device, retrying transient failures as these options
+    /// allow.
+    ///
+    /// `is_transient` decides which errors are worth retrying; what those are
+    /// varies by platform. The last error is yielded once the retries run out.
+    pub fn retry_open<T, F>(&self, is_transient: fn(&io::Error) -> bool, mut open: F) -> io::Result<T> where F: FnMut() -> io::Result<T> {
+        let mut retries = 0;
+
+        loop {
+            match open() {
+                Ok(device) => return Ok(device),
+                Err(ref e) if is_transient(e) && retries < self.open_retries => {
+                    retries += 1;
+                    thread::sleep(self.open_retry_delay);
+                },
+                Err(e) => return Err(e)
+            }
+        }
+    }
+}
+
+/// Mark the end of the data just written to a tape, according to a policy.
+pub fn write_end_marks(tape: &mut dyn TapeDevice, policy: FilemarkPolicy) -> io::Result<()> {
+    match policy {
+        FilemarkPolicy::Single => tape.write_filemark(true),
+        FilemarkPolicy::Double => {
+            tape.write_filemark(true)?;
+            tape.write_filemark(true)?;
+            tape.seek_filemarks(io::SeekFrom::Current(-1))
+        },
+        FilemarkPolicy::Setmark => tape.write_setmark(true)
+    }
+}
+
 pub trait TapeDevice : io::Write + io::Read {
     /// Read until the end of the current tape block.
     /// 
@@ -18,12 +237,53 @@ pub trait TapeDevice : io::Write + io::Read {
     /// same data contents as code that exclusively uses `read` or
     /// `read_block`. Code that exclusively uses `read_block` is
     /// guaranteed to never encounter a partial block.
+    ///
+    /// #Filemarks
+    /// Reads stop at filemarks, so that each tape file reads as a separate
+    /// stream. Once a filemark has been read, `read` returns no data and
+    /// `read_block` returns empty blocks until the tape is repositioned.
+    /// `seek_filemarks(io::SeekFrom::Current(0))` continues reading with the
+    /// tape file after the filemark.
     fn read_block(&mut self, buf: &mut Vec<u8>) -> io::Result<()>;
 
     /// Write a filemark onto the tape.
     fn write_filemark(&mut self, blocking: bool) -> io::Result<()>;
 
+    /// Write a setmark onto the tape.
+    /// 
+    /// Not many tape formats support setmarks; see `seek_setmarks`.
+    fn write_setmark(&mut self, blocking: bool) -> io::Result<()>;
+
+    /// Choose how the end of written data is marked when the device is closed.
+    /// 
+    /// Each device has it's own default, matching the convention of it's
+    /// platform. The policy only applies if data was the last thing written;
+    /// see the device for details.
+    fn set_filemark_policy(&mut self, policy: FilemarkPolicy) -> io::Result<()>;
+
+    /// Turn the drive's hardware compression of written data on or off.
+    ///
+    /// This is a setting of the drive, not the device handle: it usually
+    /// lasts until it's changed again or the drive is reset. Drives without
+    /// hardware compression yield an error.
+    fn set_compression(&mut self, enabled: bool) -> io::Result<()>;
+
+    /// Get the block size of a device in fixed block mode.
+    ///
+    /// Devices in fixed block mode only accept writes of a whole number of
+    /// blocks, so archives should be written to them in records of exactly
+    /// this size. Devices in variable block mode, or which can't tell, yield
+    /// None.
+    fn fixed_block_size(&self) -> Option<u32>;
+
     /// Seek by a number of blocks on the tape.
+    ///
+    /// `SeekFrom::Start` locates an absolute block number, as returned by
+    /// `tell_blocks`. `SeekFrom::Current` spaces forward (or, if negative,
+    /// backward) over that many blocks. `SeekFrom::End` spaces backward from
+    /// the end of recorded data, and must not be positive.
+    ///
+    /// Spacing over blocks stops early once a filemark has been crossed.
     fn seek_blocks(&mut self, pos: io::SeekFrom) -> io::Result<()>;
 
     /// Get the current block ID
@@ -35,6 +295,12 @@ pub trait TapeDevice : io::Write + io::Read {
     /// filemarks instead. A filemark is the tape marking that divides files on
     /// a tape.
     /// 
+    /// `SeekFrom::Start(n)` rewinds and then spaces forward over `n`
+    /// filemarks, leaving the tape at the start of the `n`th tape file.
+    /// Spacing forward leaves the tape just past the last filemark spaced
+    /// over; spacing backward (including from `SeekFrom::End`) leaves the tape
+    /// just before it.
+    ///
     /// All seek operations are relative to the current partition, if the tape
     /// has partitions.
     fn seek_filemarks(&mut self, pos: io::SeekFrom) -> io::Result<()>;
@@ -65,4 +331,87 @@ pub trait TapeDevice : io::Write + io::Read {
     /// multiple partitions.
     /// 
     fn seek_partition(&mut self, id: u32) -> io::Result<()>;
-}
\ No newline at end of file
+
+    /// Get the ID of the partition the tape is currently positioned in.
+    ///
+    /// Partitions are numbered from 1, as in `seek_partition`. Tapes which
+    /// have not been partitioned report being in partition 1.
+    fn current_partition(&mut self) -> io::Result<u32>;
+
+    /// Get the number of partitions the loaded tape has been formatted with.
+    ///
+    /// Unpartitioned tapes have one partition. Some drivers have no way of
+    /// reporting this, in which case this yields an error.
+    fn partition_count(&mut self) -> io::Result<u32>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, time};
+    use crate::tape::memory::{MemoryTapeDevice, TapeRecord};
+    use super::{SeekOrigin, FilemarkPolicy, TapeOptions, decompose_seek, split_offset, write_end_marks};
+
+    #[test]
+    fn tape_decompose_seek() {
+        assert_eq!(decompose_seek(io::SeekFrom::Start(5)).unwrap(), (SeekOrigin::Beginning, 5));
+        assert_eq!(decompose_seek(io::SeekFrom::Current(-3)).unwrap(), (SeekOrigin::Current, -3));
+        assert_eq!(decompose_seek(io::SeekFrom::End(-1)).unwrap(), (SeekOrigin::EndOfData, -1));
+        assert!(decompose_seek(io::SeekFrom::End(1)).is_err());
+        assert!(decompose_seek(io::SeekFrom::Start(u64::max_value())).is_err());
+        assert_eq!(decompose_seek(io::SeekFrom::Current(i64::min_value())).unwrap(), (SeekOrigin::Current, i64::min_value()));
+    }
+
+    #[test]
+    fn tape_split_offset() {
+        assert_eq!(split_offset(0), (0, 0));
+        assert_eq!(split_offset((1 << 32) + 5), (5, 1));
+        assert_eq!(split_offset(-1), (0xFFFFFFFF, 0xFFFFFFFF));
+        assert_eq!(split_offset(-(1 << 32)), (0, 0xFFFFFFFF));
+        assert_eq!(split_offset(i64::min_value()), (0, 0x80000000));
+        assert_eq!(split_offset(i64::max_value()), (0xFFFFFFFF, 0x7FFFFFFF));
+    }
+
+    #[test]
+    fn tape_end_marks() {
+        let mut tape = MemoryTapeDevice::<u64>::new();
+
+        write_end_marks(&mut tape, FilemarkPolicy::Double).unwrap();
+        write_end_marks(&mut tape, FilemarkPolicy::Setmark).unwrap();
+        assert_eq!(tape.records(), &[TapeRecord::Filemark, TapeRecord::Setmark]);
+
+        write_end_marks(&mut tape, FilemarkPolicy::Single).unwrap();
+        assert_eq!(tape.records(), &[TapeRecord::Filemark, TapeRecord::Setmark, TapeRecord::Filemark]);
+    }
+    #[test]
+    fn tape_open_retries() {
+        let options = TapeOptions { open_retries: 2, open_retry_delay: time::Duration::from_millis(0), ..TapeOptions::default() };
+        let transient = |e: &io::Error| e.kind() == io::ErrorKind::Interrupted;
+        let mut attempts = 0;
+
+        let opened = options.retry_open(transient, || {
+            attempts += 1;
+
+            match attempts {
+                1 | 2 => Err(io::Error::new(io::ErrorKind::Interrupted, "settling")),
+                _ => Ok(attempts)
+            }
+        });
+        assert_eq!(opened.unwrap(), 3);
+
+        attempts = 0;
+        let opened : io::Result<()> = options.retry_open(transient, || {
+            attempts += 1;
+            Err(io::Error::new(io::ErrorKind::Interrupted, "settling"))
+        });
+        assert!(opened.is_err());
+        assert_eq!(attempts, 3);
+
+        attempts = 0;
+        let opened : io::Result<()> = options.retry_open(transient, || {
+            attempts += 1;
+            Err(io::Error::new(io::ErrorKind::NotFound, "no drive"))
+        });
+        assert_eq!(opened.unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(attempts, 1);
+    }
+}
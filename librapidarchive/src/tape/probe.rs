@@ -0,0 +1,147 @@
+//! Recognizing what's already recorded on a tape.
+//!
+//! Tapes don't have filesystems to tell us they're in use, and writing at the
+//! start of one silently destroys everything after it. Before writing there,
+//! we look at the first block to see if it holds anything we recognize.
+
+use std::{io, fmt, time};
+use crate::tape::TapeDevice;
+use crate::tar::reader::is_tar_header;
+use crate::tar::label::read_retention;
+
+/// Something recognizable found at the start of a tape.
+#[derive(Clone, Debug, PartialEq)]
+pub enum TapeContents {
+    /// A tar archive, or at least a valid tar header.
+    TarArchive,
+
+    /// An LTFS volume, with it's volume serial.
+    Ltfs(String),
+
+    /// Some other volume with an ANSI standard label, with it's volume serial.
+    AnsiLabel(String),
+}
+
+impl fmt::Display for TapeContents {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TapeContents::TarArchive => write!(f, "a tar archive"),
+            TapeContents::Ltfs(serial) => write!(f, "an LTFS volume (serial \"{}\")", serial),
+            TapeContents::AnsiLabel(serial) => write!(f, "a labeled volume (serial \"{}\")", serial)
+        }
+    }
+}
+
+/// Identify the contents of a tape from it's first block.
+pub fn identify(block: &[u8]) -> Option<TapeContents> {
+    if block.len() >= 80 && &block[0..4] == b"VOL1" {
+        let serial = String::from_utf8_lossy(&block[4..10]).trim_end().to_string();
+
+        if &block[24..28] == b"LTFS" {
+            return Some(TapeContents::Ltfs(serial));
+        }
+
+        return Some(TapeContents::AnsiLabel(serial));
+    }
+
+    if is_tar_header(block) {
+        return Some(TapeContents::TarArchive);
+    }
+
+    None
+}
+
+/// Check if writing at the current tape position would overwrite something.
+///
+/// Only the start of a partition is checked: positioning a tape past existing
+/// data to write there is assumed to be deliberate. The first block is read
+/// and the tape is returned to where it started.
+///
+/// Blank tapes usually fail to read rather than returning no data, so read
+/// errors are taken to mean there's nothing there.
+pub fn probe(tape: &mut dyn TapeDevice) -> io::Result<Option<TapeContents>> {
+    Ok(read_first_block(tape)?.and_then(|block| identify(&block)))
+}
+
+/// Check if writing at the current tape position would overwrite a volume
+/// that is to be retained, and if so, until when.
+///
+/// As with `probe`, only the start of a partition is checked.
+pub fn probe_retention(tape: &mut dyn TapeDevice) -> io::Result<Option<time::SystemTime>> {
+    Ok(read_first_block(tape)?.and_then(|block| read_retention(&block)))
+}
+
+/// Read the first block of a tape partition, if the tape is positioned there
+/// and it isn't blank, and return the tape to where it started.
+fn read_first_block(tape: &mut dyn TapeDevice) -> io::Result<Option<Vec<u8>>> {
+    if tape.tell_blocks()? != 0 {
+        return Ok(None);
+    }
+
+    let mut block = Vec::new();
+    let result = match tape.read_block(&mut block) {
+        Ok(()) => Some(block),
+        Err(e) => {
+            debug!("Could not read first block of tape, assuming it is blank: {}", e);
+            None
+        }
+    };
+
+    tape.seek_blocks(io::SeekFrom::Start(0))?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use crate::tape::TapeDevice;
+    use crate::tape::memory::MemoryTapeDevice;
+    use std::time;
+    use crate::tar::header::TarFormat;
+    use crate::tar::label::{TarLabel, labelgen, check_retention};
+    use super::{TapeContents, identify, probe, probe_retention};
+
+    #[test]
+    fn probe_tape_contents() {
+        let mut tape = MemoryTapeDevice::<u64>::new();
+        assert_eq!(probe(&mut tape).unwrap(), None);
+
+        let mut label = vec![b' '; 80];
+        label[0..4].copy_from_slice(b"VOL1");
+        label[4..10].copy_from_slice(b"ABC123");
+        assert_eq!(identify(&label), Some(TapeContents::AnsiLabel("ABC123".to_string())));
+
+        label[24..28].copy_from_slice(b"LTFS");
+        tape.write_all(&label).unwrap();
+        tape.write_all(&[0; 512]).unwrap();
+        tape.seek_blocks(std::io::SeekFrom::Start(0)).unwrap();
+
+        assert_eq!(probe(&mut tape).unwrap(), Some(TapeContents::Ltfs("ABC123".to_string())));
+        assert_eq!(tape.tell_blocks().unwrap(), 0);
+
+        //Past the start of the tape, nothing is checked.
+        tape.seek_blocks(std::io::SeekFrom::Start(1)).unwrap();
+        assert_eq!(probe(&mut tape).unwrap(), None);
+
+        assert_eq!(identify(&[0; 512]), None);
+    }
+
+    #[test]
+    fn probe_tape_retention() {
+        let mut tape = MemoryTapeDevice::<u64>::new();
+        let retain_until = time::UNIX_EPOCH + time::Duration::from_secs(2_000_000_000);
+        let mut tarlabel = TarLabel::default();
+
+        tarlabel.retain_until = Some(retain_until);
+        tape.write_all(&labelgen(TarFormat::POSIX, &tarlabel).unwrap()).unwrap();
+        tape.seek_blocks(std::io::SeekFrom::Start(0)).unwrap();
+
+        assert_eq!(probe_retention(&mut tape).unwrap(), Some(retain_until));
+        assert_eq!(tape.tell_blocks().unwrap(), 0);
+
+        let day = time::Duration::from_secs(86400);
+        assert_eq!(check_retention(retain_until, retain_until - day * 2).unwrap_err().kind(), std::io::ErrorKind::PermissionDenied);
+        assert!(check_retention(retain_until, retain_until + day).is_ok());
+    }
+}
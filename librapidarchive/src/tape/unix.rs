@@ -2,14 +2,15 @@
 
 #![allow(dead_code)]
 use std::{ffi, fs, io, mem};
+use std::convert::TryFrom;
 use std::io::{Read, Write};
 use std::os::unix::io::{IntoRawFd, RawFd};
 use std::marker::PhantomData;
 
 use libc;
 
-use crate::tape::TapeDevice;
-use crate::fs::ArchivalSink;
+use crate::tape::{TapeDevice, TapeOptions, TapeAccess, BlockMode, SeekOrigin, FilemarkPolicy, decompose_seek, write_end_marks};
+use crate::fs::{ArchivalSink, ArchivalSource};
 use crate::spanning::RecoverableWrite;
 
 const MTRESET: libc::c_short = 0;
@@ -51,7 +52,106 @@ pub struct mtop {
     mt_count: libc::c_int
 }
 
+#[repr(C)]
+pub struct mtpos {
+    mt_blkno: libc::c_long
+}
+
+#[repr(C)]
+pub struct mtget {
+    mt_type: libc::c_long,
+    mt_resid: libc::c_long,
+    mt_dsreg: libc::c_long,
+    mt_gstat: libc::c_long,
+    mt_erreg: libc::c_long,
+    mt_fileno: libc::c_int,
+    mt_blkno: libc::c_int
+}
+
 ioctl!(write_ptr mt_ioctop with 'm', 1; mtop);
+ioctl!(read mt_iocget with 'm', 2; mtget);
+ioctl!(read mt_iocpos with 'm', 3; mtpos);
+
+/// A SCSI command passed through to the drive, as `SG_IO` takes it.
+#[repr(C)]
+pub struct sg_io_hdr {
+    interface_id: libc::c_int,
+    dxfer_direction: libc::c_int,
+    cmd_len: libc::c_uchar,
+    mx_sb_len: libc::c_uchar,
+    iovec_count: libc::c_ushort,
+    dxfer_len: libc::c_uint,
+    dxferp: *mut libc::c_void,
+    cmdp: *const libc::c_uchar,
+    sbp: *mut libc::c_uchar,
+    timeout: libc::c_uint,
+    flags: libc::c_uint,
+    pack_id: libc::c_int,
+    usr_ptr: *mut libc::c_void,
+    status: libc::c_uchar,
+    masked_status: libc::c_uchar,
+    msg_status: libc::c_uchar,
+    sb_len_wr: libc::c_uchar,
+    host_status: libc::c_ushort,
+    driver_status: libc::c_ushort,
+    resid: libc::c_int,
+    duration: libc::c_uint,
+    info: libc::c_uint
+}
+
+const SG_DXFER_FROM_DEV: libc::c_int = -3;
+
+ioctl!(bad readwrite sg_io with 0x2285; sg_io_hdr);
+
+const MODE_SENSE_6: u8 = 0x1A;
+
+/// The mode page describing how the medium is partitioned.
+const MEDIUM_PARTITION_PAGE: u8 = 0x11;
+
+/// Find the number of partitions on the medium from the reply to a MODE SENSE
+/// of the medium partition page.
+///
+/// The page follows the mode parameter header and any block descriptors. It's
+/// fourth byte counts the partitions past the first.
+fn medium_partition_count(reply: &[u8]) -> Option<u32> {
+    let page = reply.get(4 + *reply.get(3)? as usize..)?;
+
+    match page {
+        [code, _, _, additional, ..] if code & 0x3F == MEDIUM_PARTITION_PAGE => Some(*additional as u32 + 1),
+        _ => None
+    }
+}
+
+/// Construct the operation that spaces over `count` units on the tape.
+///
+/// The `st` driver wants a positive count, so negative counts select the
+/// backward version of the operation instead. Spacing zero units needs no
+/// operation at all.
+fn space_op(forward: libc::c_short, backward: libc::c_short, count: i64) -> io::Result<Option<mtop>> {
+    if count == 0 {
+        return Ok(None);
+    }
+
+    match count.checked_abs() {
+        Some(magnitude) if magnitude <= libc::c_int::max_value() as i64 => Ok(Some(mtop {
+            mt_op: if count > 0 { forward } else { backward },
+            mt_count: magnitude as libc::c_int
+        })),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Tape seek is too far"))
+    }
+}
+
+/// Determine if an error opening a tape device is worth retrying.
+///
+/// The `st` driver refuses a second open of a drive with `EBUSY`, which only
+/// lasts as long as whoever else has it open.
+fn is_transient_open_error(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::EBUSY)
+}
+
+/// The part of `mt_dsreg` holding the drive's block size, which is zero in
+/// variable block mode.
+const MT_ST_BLKSIZE_MASK: libc::c_long = 0xffffff;
 
 fn conv_nix_error<T>(res: nix::Result<T>) -> io::Result<T> {
     match res {
@@ -63,16 +163,83 @@ fn conv_nix_error<T>(res: nix::Result<T>) -> io::Result<T> {
     }
 }
 
+/// A tape device opened through the `st` driver.
+/// 
+/// When the device is closed after writing data, the end of the data is
+/// marked according to it's `FilemarkPolicy`. This defaults to a single
+/// filemark, which is what the driver would write by itself.
 pub struct UnixTapeDevice<P = u64> {
     tape_device: RawFd,
     naninani: PhantomData<P>,
     block_spill_buffer: Vec<u8>,
     block_spill_read_pos: usize,
+    eof_condition: bool,
+    filemark_policy: FilemarkPolicy,
+
+    /// Whether data was the last thing written, and still needs marking.
+    unmarked_write: bool,
+
+    fixed_block_size: Option<u32>,
 }
 
 impl<P> UnixTapeDevice<P> {
-    pub fn open_device(unix_device_path: &ffi::OsStr) -> io::Result<Self> {
-        unsafe { Ok(Self::from_file_descriptor(fs::OpenOptions::new().read(true).write(true).open(unix_device_path)?.into_raw_fd())) }
+    /// Open a tape device by it's path.
+    ///
+    /// Write-protected media can only be opened with `TapeAccess::ReadOnly`.
+    /// The share mode is up to the driver, and so is ignored.
+    pub fn open_device(unix_device_path: &ffi::OsStr, options: &TapeOptions) -> io::Result<Self> {
+        let file = options.retry_open(is_transient_open_error, || {
+            fs::OpenOptions::new().read(true).write(options.access == TapeAccess::ReadWrite).open(unix_device_path)
+        })?;
+        let mut tape = unsafe { Self::from_file_descriptor(file.into_raw_fd()) };
+
+        match (tape.set_block_mode(options.block_mode), options.fallback_block_mode()) {
+            (Ok(()), _) => {},
+            (Err(e), Some(fallback)) => {
+                warn!("Could not put tape drive into variable block mode ({}), falling back to fixed block mode", e);
+                tape.set_block_mode(fallback)?;
+            },
+            (Err(e), None) => return Err(e)
+        }
+
+        Ok(tape)
+    }
+
+    /// Configure the drive's block size, and note what it ends up as.
+    ///
+    /// Leaving the block size unchanged still asks the driver what it is.
+    /// Devices which can't say, such as character devices which aren't tapes
+    /// at all, are treated as being in variable block mode.
+    fn set_block_mode(&mut self, block_mode: BlockMode) -> io::Result<()> {
+        let block_size = match block_mode {
+            BlockMode::Unchanged => {
+                let mut status : mtget = unsafe { mem::zeroed() };
+
+                self.fixed_block_size = match conv_nix_error(unsafe { mt_iocget(self.tape_device, &mut status) }) {
+                    Ok(_) if status.mt_dsreg & MT_ST_BLKSIZE_MASK != 0 => Some((status.mt_dsreg & MT_ST_BLKSIZE_MASK) as u32),
+                    _ => None
+                };
+
+                return Ok(());
+            },
+            BlockMode::Variable => 0,
+            BlockMode::Fixed(size) if size > 0 && size <= libc::c_int::max_value() as u32 => size as libc::c_int,
+            BlockMode::Fixed(_) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid tape block size"))
+        };
+
+        let op = mtop {
+            mt_op: MTSETBLK,
+            mt_count: block_size
+        };
+
+        conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
+
+        self.fixed_block_size = match block_size {
+            0 => None,
+            size => Some(size as u32)
+        };
+
+        Ok(())
     }
 
     pub unsafe fn from_file_descriptor(unix_fd: RawFd) -> Self {
@@ -81,10 +248,16 @@ impl<P> UnixTapeDevice<P> {
             naninani: PhantomData,
             block_spill_buffer: Vec::with_capacity(1024),
             block_spill_read_pos: 0,
+            eof_condition: false,
+            filemark_policy: FilemarkPolicy::Single,
+            unmarked_write: false,
+            fixed_block_size: None,
         }
     }
 
     fn read_next_block(&mut self) -> io::Result<()> {
+        self.unmarked_write = false;
+
         loop {
             let size = unsafe{ libc::read(self.tape_device, self.block_spill_buffer.as_mut_ptr() as *mut libc::c_void, self.block_spill_buffer.capacity()) };
 
@@ -92,6 +265,13 @@ impl<P> UnixTapeDevice<P> {
                 assert!(size as usize <= self.block_spill_buffer.capacity());
                 unsafe { self.block_spill_buffer.set_len(size as usize) };
 
+                //A zero-length read means we hit a filemark (or the end of
+                //data). Hold there until the tape is repositioned, so that
+                //each tape file reads as it's own stream.
+                if size == 0 {
+                    self.eof_condition = true;
+                }
+
                 return Ok(())
             } else {
                 let err = io::Error::last_os_error();
@@ -114,17 +294,94 @@ impl<P> UnixTapeDevice<P> {
     }
 }
 
+impl<P> UnixTapeDevice<P> {
+    fn tape_op(&mut self, op: &mtop) -> io::Result<()> {
+        self.unmarked_write = false;
+
+        conv_nix_error(unsafe { mt_ioctop(self.tape_device, op) })?;
+
+        Ok(())
+    }
+
+    /// Pass a SCSI command which reads data back from the drive through to
+    /// it, yielding how much of `buf` it filled.
+    fn scsi_read(&mut self, cdb: &[u8], buf: &mut [u8]) -> io::Result<usize> {
+        let mut sense = [0u8; 32];
+        let mut hdr = sg_io_hdr {
+            interface_id: b'S' as libc::c_int,
+            dxfer_direction: SG_DXFER_FROM_DEV,
+            cmd_len: cdb.len() as libc::c_uchar,
+            mx_sb_len: sense.len() as libc::c_uchar,
+            iovec_count: 0,
+            dxfer_len: buf.len() as libc::c_uint,
+            dxferp: buf.as_mut_ptr() as *mut libc::c_void,
+            cmdp: cdb.as_ptr(),
+            sbp: sense.as_mut_ptr(),
+            timeout: 60_000,
+            flags: 0,
+            pack_id: 0,
+            usr_ptr: std::ptr::null_mut(),
+            status: 0,
+            masked_status: 0,
+            msg_status: 0,
+            sb_len_wr: 0,
+            host_status: 0,
+            driver_status: 0,
+            resid: 0,
+            duration: 0,
+            info: 0
+        };
+
+        conv_nix_error(unsafe { sg_io(self.tape_device, &mut hdr) })?;
+
+        if hdr.status != 0 || hdr.host_status != 0 || hdr.driver_status != 0 {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("Tape drive rejected SCSI command {:#04x}", cdb[0])));
+        }
+
+        Ok(buf.len().saturating_sub(hdr.resid.max(0) as usize))
+    }
+
+    /// Seek in units of some kind of tape mark.
+    fn seek_marks(&mut self, pos: io::SeekFrom, forward: libc::c_short, backward: libc::c_short) -> io::Result<()> {
+        let (origin, count) = decompose_seek(pos)?;
+
+        self.eof_condition = false;
+
+        match origin {
+            SeekOrigin::Beginning => self.tape_op(&mtop { mt_op: MTREW, mt_count: 1 })?,
+            SeekOrigin::Current => {},
+            SeekOrigin::EndOfData => self.tape_op(&mtop { mt_op: MTEOM, mt_count: 1 })?
+        }
+
+        if let Some(op) = space_op(forward, backward, count)? {
+            self.tape_op(&op)?;
+        }
+
+        Ok(())
+    }
+}
+
 impl<P> Drop for UnixTapeDevice<P> {
+    #[allow(unused_must_use)]
     fn drop(&mut self) {
+        if self.unmarked_write {
+            let policy = self.filemark_policy;
+
+            write_end_marks(self, policy);
+        }
+
         unsafe { libc::close(self.tape_device) };
     }
 }
 
 impl<P> Write for UnixTapeDevice<P> {
     fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.eof_condition = false;
+
         let size = unsafe{ libc::write(self.tape_device, data.as_ptr() as *const libc::c_void, data.len()) };
 
         if size >= 0 {
+            self.unmarked_write = true;
             Ok(size as usize)
         } else {
             Err(io::Error::last_os_error())
@@ -147,8 +404,16 @@ impl<P> Read for UnixTapeDevice<P> {
             let remain = buf.len() - wrote;
 
             if self.block_spill_read_pos == 0 {
+                if self.eof_condition {
+                    break;
+                }
+
                 self.read_next_block()?;
 
+                if self.eof_condition {
+                    break;
+                }
+
                 if self.block_spill_buffer.len() <= remain {
                     //Given buffer is long enough, return a tape block.
                     //TODO: Can we avoid this copy?
@@ -191,9 +456,20 @@ impl<P> ArchivalSink<P> for UnixTapeDevice<P> where P: Send + Clone {
     }
 }
 
+impl<P> ArchivalSource for UnixTapeDevice<P> where P: Send {
+    fn downcast_tapedevice(&mut self) -> Option<&mut dyn TapeDevice> {
+        Some(self)
+    }
+}
+
 impl<P> TapeDevice for UnixTapeDevice<P> {
     fn read_block(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
         if self.block_spill_read_pos == 0 {
+            if self.eof_condition {
+                buf.clear();
+                return Ok(());
+            }
+
             self.read_next_block()?;
         }
         
@@ -208,158 +484,137 @@ impl<P> TapeDevice for UnixTapeDevice<P> {
     }
     
     fn write_filemark(&mut self, _blocking: bool) -> io::Result<()> {
-        let op = mtop {
-            mt_op: MTWEOF,
-            mt_count: 1
-        };
+        self.eof_condition = false;
 
-        conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
+        self.tape_op(&mtop { mt_op: MTWEOF, mt_count: 1 })
+    }
+
+    fn write_setmark(&mut self, _blocking: bool) -> io::Result<()> {
+        self.eof_condition = false;
+
+        self.tape_op(&mtop { mt_op: MTWSM, mt_count: 1 })
+    }
+
+    fn set_filemark_policy(&mut self, policy: FilemarkPolicy) -> io::Result<()> {
+        self.filemark_policy = policy;
 
         Ok(())
     }
+
+    fn set_compression(&mut self, enabled: bool) -> io::Result<()> {
+        self.tape_op(&mtop { mt_op: MTCOMPRESSION, mt_count: enabled as libc::c_int })
+    }
+
+    fn fixed_block_size(&self) -> Option<u32> {
+        self.fixed_block_size
+    }
     
     fn seek_blocks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
-        match pos {
-            io::SeekFrom::Start(pos) => {
-                let op = mtop {
-                    mt_op: MTSEEK,
-                    mt_count: pos as i32
-                };
+        let (origin, count) = decompose_seek(pos)?;
 
-                conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
-            },
-            io::SeekFrom::Current(pos) => {
-                let op = mtop {
-                    mt_op: if pos > 0 {
-                        MTFSR
-                    } else {
-                        MTBSR
-                    },
-                    mt_count: pos as i32
-                };
+        self.eof_condition = false;
 
-                conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
+        match origin {
+            SeekOrigin::Beginning if count > libc::c_int::max_value() as i64 => Err(io::Error::new(io::ErrorKind::InvalidInput, "Tape seek is too far")),
+            SeekOrigin::Beginning => self.tape_op(&mtop { mt_op: MTSEEK, mt_count: count as libc::c_int }),
+            SeekOrigin::Current => match space_op(MTFSR, MTBSR, count)? {
+                Some(op) => self.tape_op(&op),
+                None => Ok(())
             },
-            io::SeekFrom::End(pos) => {
-                let mut op = mtop {
-                    mt_op: MTEOM,
-                    mt_count: pos as i32
-                };
-
-                conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
-
-                op.mt_op = MTBSR;
-                op.mt_count = pos as i32;
+            SeekOrigin::EndOfData => {
+                self.tape_op(&mtop { mt_op: MTEOM, mt_count: 1 })?;
 
-                conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
+                match space_op(MTFSR, MTBSR, count)? {
+                    Some(op) => self.tape_op(&op),
+                    None => Ok(())
+                }
             }
         }
-
-        Ok(())
     }
     
     fn tell_blocks(&mut self) -> io::Result<u64> {
-        Ok(0)
-    }
+        let mut pos = mtpos { mt_blkno: 0 };
 
-    fn seek_filemarks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
-        match pos {
-            io::SeekFrom::Start(pos) => {
-                let mut op = mtop {
-                    mt_op: MTREW,
-                    mt_count: 1
-                };
+        conv_nix_error(unsafe { mt_iocpos(self.tape_device, &mut pos) })?;
 
-                conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
+        //The driver reports -1 when it has lost track of the position.
+        match u64::try_from(pos.mt_blkno) {
+            Ok(block) => Ok(block),
+            Err(_) => Err(io::Error::new(io::ErrorKind::Other, "Tape position is unknown"))
+        }
+    }
 
-                op.mt_op = MTFSF;
-                op.mt_count = pos as i32;
+    fn seek_filemarks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        self.seek_marks(pos, MTFSF, MTBSF)
+    }
+    
+    fn seek_setmarks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        self.seek_marks(pos, MTFSS, MTBSS)
+    }
+    
+    fn seek_partition(&mut self, id: u32) -> io::Result<()> {
+        self.eof_condition = false;
 
-                conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
-            },
-            io::SeekFrom::Current(pos) => {
-                let op = mtop {
-                    mt_op: if pos > 0 {
-                        MTFSF
-                    } else {
-                        MTBSF
-                    },
-                    mt_count: pos as i32
-                };
+        if id == 0 {
+            return Ok(());
+        }
 
-                conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
-            },
-            io::SeekFrom::End(pos) => { //wait how do we even do this
-                let mut op = mtop {
-                    mt_op: MTEOM,
-                    mt_count: pos as i32
-                };
+        //The st driver numbers partitions from zero.
+        self.tape_op(&mtop { mt_op: MTSETPART, mt_count: (id - 1) as libc::c_int })
+    }
 
-                conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
+    fn current_partition(&mut self) -> io::Result<u32> {
+        let mut status : mtget = unsafe { mem::zeroed() };
 
-                op.mt_op = MTBSF;
-                op.mt_count = pos as i32;
+        conv_nix_error(unsafe { mt_iocget(self.tape_device, &mut status) })?;
 
-                conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
-            }
+        //st reports the current partition, numbered from zero, in place of the
+        //residual count.
+        match u32::try_from(status.mt_resid).ok().and_then(|partition| partition.checked_add(1)) {
+            Some(partition) => Ok(partition),
+            None => Err(io::Error::new(io::ErrorKind::Other, "Tape partition is unknown"))
         }
-
-        Ok(())
     }
-    
-    fn seek_setmarks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
-        match pos {
-            io::SeekFrom::Start(pos) => {
-                let mut op = mtop {
-                    mt_op: MTREW,
-                    mt_count: 1
-                };
 
-                conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
+    fn partition_count(&mut self) -> io::Result<u32> {
+        //The st driver only learns the partition count from MODE SENSE
+        //internally, and doesn't pass it along through any of it's own
+        //ioctls, so we ask the drive ourselves.
+        let mut reply = [0; 64];
+        let length = self.scsi_read(&[MODE_SENSE_6, 0x08, MEDIUM_PARTITION_PAGE, 0, reply.len() as u8, 0], &mut reply)?;
 
-                op.mt_op = MTFSS;
-                op.mt_count = pos as i32;
-
-                conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
-            },
-            io::SeekFrom::Current(pos) => {
-                let op = mtop {
-                    mt_op: if pos > 0 {
-                        MTFSS
-                    } else {
-                        MTBSS
-                    },
-                    mt_count: pos as i32
-                };
+        medium_partition_count(&reply[..length]).ok_or(io::Error::new(io::ErrorKind::Other, "Tape drive did not report it's partitions"))
+    }
+}
 
-                conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
-            },
-            io::SeekFrom::End(pos) => { //wait how do we even do this
-                let mut op = mtop {
-                    mt_op: MTEOM,
-                    mt_count: pos as i32
-                };
+#[cfg(test)]
+mod tests {
+    use super::{space_op, medium_partition_count, MTFSF, MTBSF};
 
-                conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
+    #[test]
+    fn unix_space_op() {
+        assert!(space_op(MTFSF, MTBSF, 0).unwrap().is_none());
 
-                op.mt_op = MTBSS;
-                op.mt_count = pos as i32;
+        let forward = space_op(MTFSF, MTBSF, 3).unwrap().unwrap();
+        assert_eq!((forward.mt_op, forward.mt_count), (MTFSF, 3));
 
-                conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
-            }
-        }
+        let backward = space_op(MTFSF, MTBSF, -2).unwrap().unwrap();
+        assert_eq!((backward.mt_op, backward.mt_count), (MTBSF, 2));
 
-        Ok(())
+        assert!(space_op(MTFSF, MTBSF, i64::min_value()).is_err());
+        assert!(space_op(MTFSF, MTBSF, 1 << 40).is_err());
     }
-    
-    fn seek_partition(&mut self, id: u32) -> io::Result<()> {
-        let op = mtop {
-            mt_op: MTSETPART,
-            mt_count: id as i32
-        };
 
-        conv_nix_error(unsafe { mt_ioctop(self.tape_device, &op) })?;
-
-        Ok(())
+    #[test]
+    fn unix_partition_page() {
+        //Mode parameter header with one block descriptor, then the page of a
+        //tape with two partitions.
+        let mut reply = vec![0x1B, 0, 0x10, 8, 0, 0, 0, 0, 0, 0, 0, 0];
+        reply.extend(&[0x91, 0x0A, 0x03, 0x01, 0x20, 0, 0x03, 0, 0, 0, 0, 0]);
+        assert_eq!(medium_partition_count(&reply), Some(2));
+
+        reply[12] = 0x0F;
+        assert_eq!(medium_partition_count(&reply), None);
+        assert_eq!(medium_partition_count(&reply[..10]), None);
     }
-}
\ No newline at end of file
+}
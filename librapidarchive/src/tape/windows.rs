@@ -7,13 +7,36 @@ use winapi::um::{winbase, fileapi, handleapi};
 use winapi::shared::ntdef::{TRUE, FALSE};
 use winapi::shared::minwindef::{BOOL, LPVOID, LPCVOID, DWORD};
 use winapi::shared::winerror::{NO_ERROR, ERROR_END_OF_MEDIA, ERROR_MORE_DATA, ERROR_FILEMARK_DETECTED, ERROR_SETMARK_DETECTED, ERROR_NO_DATA_DETECTED, ERROR_MEDIA_CHANGED};
-use winapi::um::winnt::{WCHAR, HANDLE, GENERIC_READ, GENERIC_WRITE, TAPE_LOGICAL_POSITION, TAPE_SPACE_END_OF_DATA, TAPE_SPACE_FILEMARKS, TAPE_SPACE_SETMARKS, TAPE_LOGICAL_BLOCK, TAPE_SPACE_RELATIVE_BLOCKS, TAPE_REWIND, TAPE_FILEMARKS, TAPE_SET_MEDIA_PARAMETERS};
+use winapi::shared::winerror::{ERROR_FILE_NOT_FOUND, ERROR_PATH_NOT_FOUND};
+use winapi::um::winnt::{WCHAR, HANDLE, GENERIC_READ, GENERIC_WRITE, FILE_SHARE_READ, FILE_SHARE_WRITE, TAPE_LOGICAL_POSITION, TAPE_SPACE_END_OF_DATA, TAPE_SPACE_FILEMARKS, TAPE_SPACE_SETMARKS, TAPE_LOGICAL_BLOCK, TAPE_SPACE_RELATIVE_BLOCKS, TAPE_REWIND, TAPE_FILEMARKS, TAPE_SETMARKS, TAPE_SET_MEDIA_PARAMETERS, TAPE_GET_MEDIA_PARAMETERS, TAPE_GET_DRIVE_PARAMETERS, TAPE_SET_DRIVE_PARAMETERS};
 use winapi::um::fileapi::{OPEN_EXISTING};
 use winapi::um::handleapi::INVALID_HANDLE_VALUE;
 use num;
-use crate::tape::TapeDevice;
+use crate::tape::{TapeDevice, TapeOptions, TapeAccess, TapeShare, BlockMode, SeekOrigin, FilemarkPolicy, decompose_seek, split_offset, write_end_marks};
 use crate::spanning::RecoverableWrite;
-use crate::fs::ArchivalSink;
+use crate::fs::{ArchivalSink, ArchivalSource};
+
+/// `GetTapeParameters` operation for querying the loaded media.
+///
+/// winapi doesn't export this one.
+const GET_TAPE_MEDIA_INFORMATION: DWORD = 0;
+
+/// `GetTapeParameters` and `SetTapeParameters` operations for the drive
+/// itself, rather than the loaded media.
+const GET_TAPE_DRIVE_INFORMATION: DWORD = 1;
+const SET_TAPE_DRIVE_INFORMATION: DWORD = 1;
+
+/// Determine if an error opening a tape device is worth retrying.
+///
+/// Windows does this fun thing where tape devices throw an error if you've
+/// changed the media out, and sometimes pretends they don't exist at all for
+/// a moment.
+fn is_transient_open_error(err: &io::Error) -> bool {
+    match err.raw_os_error() {
+        Some(errcode) => errcode == ERROR_MEDIA_CHANGED as i32 || errcode == ERROR_FILE_NOT_FOUND as i32 || errcode == ERROR_PATH_NOT_FOUND as i32,
+        None => false
+    }
+}
 
 enum TapeCommand {
     Write,
@@ -22,13 +45,21 @@ enum TapeCommand {
     NoneOfTheAbove
 }
 
+/// A tape device opened through the Windows tape APIs.
+/// 
+/// When the device is closed after writing, the end of the data is marked
+/// according to it's `FilemarkPolicy`. This defaults to two filemarks. If the
+/// last thing written was a filemark, the double filemark policy only adds the
+/// second one.
 pub struct WindowsTapeDevice<P = u64> where P: Sized + Clone {
     tape_device: HANDLE,
     last_ident: PhantomData<P>,
     block_spill_buffer: Vec<u8>,
     block_spill_read_pos: usize,
     last_command: TapeCommand,
-    eof_condition: bool
+    eof_condition: bool,
+    filemark_policy: FilemarkPolicy,
+    fixed_block_size: Option<u32>
 }
 
 /// Absolutely not safe in the general case, but Windows handles are definitely
@@ -39,35 +70,85 @@ unsafe impl<P> Send for WindowsTapeDevice<P> where P: Clone {
 
 impl<P> WindowsTapeDevice<P> where P: Clone {
     /// Open a tape device by it's number.
-    pub fn open_tape_number<I: num::Integer>(nt_tape_id: I) -> io::Result<WindowsTapeDevice<P>> where I: fmt::Display {
+    pub fn open_tape_number<I: num::Integer>(nt_tape_id: I, options: &TapeOptions) -> io::Result<WindowsTapeDevice<P>> where I: fmt::Display {
         let filepath = format!("\\\\.\\TAPE{}", nt_tape_id);
-        WindowsTapeDevice::open_device(&ffi::OsString::from(filepath))
+        WindowsTapeDevice::open_device(&ffi::OsString::from(filepath), options)
     }
     
     /// Open a tape device by it's NT device path.
-    pub fn open_device(nt_device_path : &ffi::OsStr) -> io::Result<WindowsTapeDevice<P>> {
+    pub fn open_device(nt_device_path : &ffi::OsStr, options: &TapeOptions) -> io::Result<WindowsTapeDevice<P>> {
         let mut nt_device_path_ffi : Vec<WCHAR> = nt_device_path.encode_wide().collect();
         nt_device_path_ffi.push(0 as WCHAR);
 
         let nt_device_ptr = nt_device_path_ffi.as_ptr();
+        let access = match options.access {
+            TapeAccess::ReadWrite => GENERIC_READ | GENERIC_WRITE,
+            TapeAccess::ReadOnly => GENERIC_READ
+        };
+        let share = match options.share {
+            TapeShare::Exclusive => 0,
+            TapeShare::Read => FILE_SHARE_READ,
+            TapeShare::ReadWrite => FILE_SHARE_READ | FILE_SHARE_WRITE
+        };
         
-        let nt_device = unsafe { fileapi::CreateFileW(nt_device_ptr, GENERIC_READ | GENERIC_WRITE, 0, ptr::null_mut(), OPEN_EXISTING, 0, ptr::null_mut()) };
-        
-        if nt_device == INVALID_HANDLE_VALUE {
-            return Err(io::Error::last_os_error());
+        let nt_device = options.retry_open(is_transient_open_error, || {
+            let nt_device = unsafe { fileapi::CreateFileW(nt_device_ptr, access, share, ptr::null_mut(), OPEN_EXISTING, 0, ptr::null_mut()) };
+
+            match nt_device {
+                INVALID_HANDLE_VALUE => Err(io::Error::last_os_error()),
+                nt_device => Ok(nt_device)
+            }
+        })?;
+
+        let mut tape = unsafe { WindowsTapeDevice::from_device_handle(nt_device) };
+
+        match (tape.set_block_mode(options.block_mode), options.fallback_block_mode()) {
+            (Ok(()), _) => {},
+            (Err(e), Some(fallback)) => {
+                warn!("Could not put tape drive into variable block mode ({}), falling back to fixed block mode", e);
+                tape.set_block_mode(fallback)?;
+            },
+            (Err(e), None) => return Err(e)
         }
+        
+        Ok(tape)
+    }
 
-        //Kick the drive into variable block mode.
-        //If we don't specify a block size, then reads always fail.
-        let media_param = TAPE_SET_MEDIA_PARAMETERS{ BlockSize: 0 };
-        let param_err = unsafe { winbase::SetTapeParameters(nt_device, 0, &media_param as *const _ as LPVOID) };
+    /// Configure the drive's block size, and note what it ends up as.
+    ///
+    /// Leaving the block size unchanged still asks the drive what it is. If
+    /// we don't specify a block size, then reads always fail.
+    fn set_block_mode(&mut self, block_mode: BlockMode) -> io::Result<()> {
+        let block_size = match block_mode {
+            BlockMode::Unchanged => {
+                let mut media_info : TAPE_GET_MEDIA_PARAMETERS = unsafe { mem::zeroed() };
+                let mut size = mem::size_of::<TAPE_GET_MEDIA_PARAMETERS>() as DWORD;
+
+                let error = unsafe { winbase::GetTapeParameters(self.tape_device, GET_TAPE_MEDIA_INFORMATION, &mut size, &mut media_info as *mut _ as LPVOID) };
+                self.fixed_block_size = match (error, media_info.BlockSize) {
+                    (NO_ERROR, block_size) if block_size != 0 => Some(block_size),
+                    _ => None
+                };
+
+                return Ok(());
+            },
+            BlockMode::Variable => 0,
+            BlockMode::Fixed(0) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Invalid tape block size")),
+            BlockMode::Fixed(size) => size
+        };
+
+        let media_param = TAPE_SET_MEDIA_PARAMETERS{ BlockSize: block_size };
+        let param_err = unsafe { winbase::SetTapeParameters(self.tape_device, 0, &media_param as *const _ as LPVOID) };
         if param_err != NO_ERROR {
             return Err(io::Error::from_raw_os_error(param_err as i32));
         }
-        
-        unsafe {
-            Ok(WindowsTapeDevice::from_device_handle(nt_device))
-        }
+
+        self.fixed_block_size = match block_size {
+            0 => None,
+            size => Some(size)
+        };
+
+        Ok(())
     }
     
     /// Construct a tape device directly from an NT handle.
@@ -82,7 +163,9 @@ impl<P> WindowsTapeDevice<P> where P: Clone {
             block_spill_buffer: Vec::with_capacity(1024),
             block_spill_read_pos: 0,
             last_command: TapeCommand::NoneOfTheAbove,
-            eof_condition: false
+            eof_condition: false,
+            filemark_policy: FilemarkPolicy::Double,
+            fixed_block_size: None
         }
     }
 
@@ -104,6 +187,40 @@ impl<P> WindowsTapeDevice<P> where P: Clone {
     fn handle_tell_error(&mut self, err: io::Error) -> io::Result<()> {
         self.handle_seek_error(err)
     }
+
+    /// Reposition the tape with `SetTapePosition`.
+    ///
+    /// `count` is split into the two halves of the signed 64-bit offset the
+    /// API expects (see `split_offset`); negative counts space backwards.
+    fn set_position(&mut self, method: DWORD, partition: DWORD, count: i64) -> io::Result<()> {
+        let (low, high) = split_offset(count);
+        let error = unsafe { winbase::SetTapePosition(self.tape_device, method, partition, low, high, FALSE as BOOL) };
+        if error != NO_ERROR {
+            self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
+        }
+
+        Ok(())
+    }
+
+    /// Seek in units of some kind of tape mark.
+    fn seek_marks(&mut self, pos: io::SeekFrom, method: DWORD) -> io::Result<()> {
+        let (origin, count) = decompose_seek(pos)?;
+
+        self.last_command = TapeCommand::NoneOfTheAbove;
+        self.eof_condition = false;
+
+        match origin {
+            SeekOrigin::Beginning => self.set_position(TAPE_REWIND, 0, 0)?,
+            SeekOrigin::Current => {},
+            SeekOrigin::EndOfData => self.set_position(TAPE_SPACE_END_OF_DATA, 0, 0)?
+        }
+
+        if count != 0 {
+            self.set_position(method, 0, count)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl<P> Drop for WindowsTapeDevice<P> where P: Clone {
@@ -111,11 +228,11 @@ impl<P> Drop for WindowsTapeDevice<P> where P: Clone {
     fn drop(&mut self) {
         match self.last_command {
             TapeCommand::Write => {
-                self.write_filemark(true);
-                self.write_filemark(true);
-                self.seek_filemarks(io::SeekFrom::Current(-1));
+                let policy = self.filemark_policy;
+
+                write_end_marks(self, policy);
             },
-            TapeCommand::WriteFilemark => {
+            TapeCommand::WriteFilemark if self.filemark_policy == FilemarkPolicy::Double => {
                 self.write_filemark(true);
                 self.seek_filemarks(io::SeekFrom::Current(-1));
             },
@@ -153,7 +270,8 @@ impl<P> WindowsTapeDevice<P> where P: Clone {
                     Some(errcode) if errcode == ERROR_MORE_DATA as i32 || errcode == ERROR_MEDIA_CHANGED as i32 => {
                         self.block_spill_buffer.reserve(self.block_spill_buffer.capacity() * 2);
 
-                        let res = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_SPACE_RELATIVE_BLOCKS, 0, ((-1 as i64) & 0xFFFFFFFF) as DWORD, ((-1 as i64) >> 32) as DWORD, FALSE as BOOL) };
+                        let (low, high) = split_offset(-1);
+                        let res = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_SPACE_RELATIVE_BLOCKS, 0, low, high, FALSE as BOOL) };
                         if res != NO_ERROR {
                             return self.handle_seek_error(io::Error::from_raw_os_error(res as i32));
                         }
@@ -270,6 +388,12 @@ impl<P> ArchivalSink<P> for WindowsTapeDevice<P> where P: Send + Clone {
     }
 }
 
+impl<P> ArchivalSource for WindowsTapeDevice<P> where P: Send + Clone {
+    fn downcast_tapedevice(&mut self) -> Option<&mut dyn TapeDevice> {
+        Some(self)
+    }
+}
+
 impl<P> TapeDevice for WindowsTapeDevice<P> where P: Clone {
     fn read_block(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
         if self.block_spill_read_pos == 0 {
@@ -307,37 +431,73 @@ impl<P> TapeDevice for WindowsTapeDevice<P> where P: Clone {
         Ok(())
     }
 
+    fn write_setmark(&mut self, blocking: bool) -> io::Result<()> {
+        let b_immediate = match blocking {
+            true => TRUE as BOOL,
+            false => FALSE as BOOL
+        };
+
+        self.last_command = TapeCommand::NoneOfTheAbove;
+
+        let error = unsafe { winbase::WriteTapemark(self.tape_device, TAPE_SETMARKS, 1, b_immediate) };
+        if error != NO_ERROR {
+            self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
+        }
+
+        Ok(())
+    }
+
+    fn set_filemark_policy(&mut self, policy: FilemarkPolicy) -> io::Result<()> {
+        self.filemark_policy = policy;
+
+        Ok(())
+    }
+
+    /// The drive's other parameters are set along with it's compression, so
+    /// they're read back first and left as they were.
+    fn set_compression(&mut self, enabled: bool) -> io::Result<()> {
+        let mut drive_info : TAPE_GET_DRIVE_PARAMETERS = unsafe { mem::zeroed() };
+        let mut size = mem::size_of::<TAPE_GET_DRIVE_PARAMETERS>() as DWORD;
+
+        let error = unsafe { winbase::GetTapeParameters(self.tape_device, GET_TAPE_DRIVE_INFORMATION, &mut size, &mut drive_info as *mut _ as LPVOID) };
+        if error != NO_ERROR {
+            return Err(io::Error::from_raw_os_error(error as i32));
+        }
+
+        let drive_param = TAPE_SET_DRIVE_PARAMETERS {
+            ECC: drive_info.ECC,
+            Compression: if enabled { TRUE } else { FALSE },
+            DataPadding: drive_info.DataPadding,
+            ReportSetmarks: drive_info.ReportSetmarks,
+            EOTWarningZoneSize: drive_info.EOTWarningZoneSize
+        };
+
+        let error = unsafe { winbase::SetTapeParameters(self.tape_device, SET_TAPE_DRIVE_INFORMATION, &drive_param as *const _ as LPVOID) };
+        if error != NO_ERROR {
+            return Err(io::Error::from_raw_os_error(error as i32));
+        }
+
+        Ok(())
+    }
+
+    fn fixed_block_size(&self) -> Option<u32> {
+        self.fixed_block_size
+    }
+
     fn seek_blocks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        let (origin, count) = decompose_seek(pos)?;
+
         self.last_command = TapeCommand::NoneOfTheAbove;
         self.eof_condition = false;
 
-        match pos {
-            io::SeekFrom::Start(target) => {
-                let error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_LOGICAL_BLOCK, 0, (target & 0xFFFFFFFF) as DWORD, (target >> 32) as DWORD, FALSE as BOOL) };
-                if error != NO_ERROR {
-                    self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
-                }
-            },
-            io::SeekFrom::Current(target) => {
-                let error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_SPACE_RELATIVE_BLOCKS, 0, (target & 0xFFFFFFFF) as DWORD, (target >> 32) as DWORD, FALSE as BOOL) };
-                if error != NO_ERROR {
-                    self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
-                }
-            },
-            io::SeekFrom::End(target) => {
-                let mut error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_SPACE_END_OF_DATA, 0, 0, 0, FALSE as BOOL) };
-                if error != NO_ERROR {
-                    self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
-                }
-                
-                error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_SPACE_RELATIVE_BLOCKS, 0, ((target * -1) & 0xFFFFFFFF) as DWORD, ((target * -1) >> 32) as DWORD, FALSE as BOOL) };
-                if error != NO_ERROR {
-                    self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
-                }
+        match origin {
+            SeekOrigin::Beginning => self.set_position(TAPE_LOGICAL_BLOCK, 0, count),
+            SeekOrigin::Current => self.set_position(TAPE_SPACE_RELATIVE_BLOCKS, 0, count),
+            SeekOrigin::EndOfData => {
+                self.set_position(TAPE_SPACE_END_OF_DATA, 0, 0)?;
+                self.set_position(TAPE_SPACE_RELATIVE_BLOCKS, 0, count)
             }
         }
-
-        Ok(())
     }
 
     fn tell_blocks(&mut self) -> io::Result<u64> {
@@ -354,90 +514,43 @@ impl<P> TapeDevice for WindowsTapeDevice<P> where P: Clone {
     }
 
     fn seek_filemarks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
-        self.last_command = TapeCommand::NoneOfTheAbove;
-        self.eof_condition = false;
-
-        match pos {
-            io::SeekFrom::Start(target) => {
-                let mut error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_REWIND, 0, 0, 0, FALSE as BOOL) };
-                if error != NO_ERROR {
-                    self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
-                }
-                
-                error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_SPACE_FILEMARKS, 0, (target & 0xFFFFFFFF) as DWORD, (target >> 32) as DWORD, FALSE as BOOL) };
-                if error != NO_ERROR {
-                    self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
-                }
-            },
-            io::SeekFrom::Current(target) => {
-                let error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_SPACE_FILEMARKS, 0, (target & 0xFFFFFFFF) as DWORD, (target >> 32) as DWORD, FALSE as BOOL) };
-                if error != NO_ERROR {
-                    self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
-                }
-            },
-            io::SeekFrom::End(target) => {
-                let mut error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_SPACE_END_OF_DATA, 0, 0, 0, FALSE as BOOL) };
-                if error != NO_ERROR {
-                    self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
-                }
-                
-                error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_SPACE_FILEMARKS, 0, ((target * -1) & 0xFFFFFFFF) as DWORD, ((target * -1) >> 32) as DWORD, FALSE as BOOL) };
-                if error != NO_ERROR {
-                    self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
-                }
-            }
-        }
-        
-        Ok(())
+        self.seek_marks(pos, TAPE_SPACE_FILEMARKS)
     }
     
     fn seek_setmarks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
-        self.last_command = TapeCommand::NoneOfTheAbove;
-        self.eof_condition = false;
-        
-        match pos {
-            io::SeekFrom::Start(target) => {
-                let mut error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_REWIND, 0, 0, 0, FALSE as BOOL) };
-                if error != NO_ERROR {
-                    self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
-                }
-                
-                error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_SPACE_SETMARKS, 0, (target & 0xFFFFFFFF) as DWORD, (target >> 32) as DWORD, FALSE as BOOL) };
-                if error != NO_ERROR {
-                    self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
-                }
-            },
-            io::SeekFrom::Current(target) => {
-                let error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_SPACE_SETMARKS, 0, (target & 0xFFFFFFFF) as DWORD, (target >> 32) as DWORD, FALSE as BOOL) };
-                if error != NO_ERROR {
-                    self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
-                }
-            },
-            io::SeekFrom::End(target) => {
-                let mut error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_SPACE_END_OF_DATA, 0, 0, 0, FALSE as BOOL) };
-                if error != NO_ERROR {
-                    self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
-                }
-                
-                error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_SPACE_SETMARKS, 0, (target & 0xFFFFFFFF) as DWORD, (target >> 32) as DWORD, FALSE as BOOL) };
-                if error != NO_ERROR {
-                    self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
-                }
-            }
-        }
-        
-        Ok(())
+        self.seek_marks(pos, TAPE_SPACE_SETMARKS)
     }
     
     fn seek_partition(&mut self, id: u32) -> io::Result<()> {
         self.last_command = TapeCommand::NoneOfTheAbove;
         self.eof_condition = false;
         
-        let error = unsafe { winbase::SetTapePosition(self.tape_device, TAPE_LOGICAL_BLOCK, id as DWORD, 0, 0, FALSE as BOOL) };
+        self.set_position(TAPE_LOGICAL_BLOCK, id as DWORD, 0)
+    }
+
+    fn current_partition(&mut self) -> io::Result<u32> {
+        let mut part = 0;
+        let mut lo = 0;
+        let mut hi = 0;
+
+        let error = unsafe { winbase::GetTapePosition(self.tape_device, TAPE_LOGICAL_POSITION, &mut part, &mut lo, &mut hi) };
         if error != NO_ERROR {
-            self.handle_seek_error(io::Error::from_raw_os_error(error as i32))?;
+            self.handle_tell_error(io::Error::from_raw_os_error(error as i32))?;
         }
-        
-        Ok(())
+
+        //Unpartitioned tapes report partition 0.
+        Ok(cmp::max(part, 1))
+    }
+
+    fn partition_count(&mut self) -> io::Result<u32> {
+        let mut media_info : TAPE_GET_MEDIA_PARAMETERS = unsafe { mem::zeroed() };
+        let mut size = mem::size_of::<TAPE_GET_MEDIA_PARAMETERS>() as DWORD;
+
+        let error = unsafe { winbase::GetTapeParameters(self.tape_device, GET_TAPE_MEDIA_INFORMATION, &mut size, &mut media_info as *mut _ as LPVOID) };
+        if error != NO_ERROR {
+            return Err(io::Error::from_raw_os_error(error as i32));
+        }
+
+        Ok(cmp::max(media_info.PartitionCount, 1))
     }
 }
\ No newline at end of file
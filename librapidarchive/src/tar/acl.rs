@@ -0,0 +1,296 @@
+//! Archiving access control lists.
+//!
+//! Mode bits only say what a file's owner, group, and everybody else may do
+//! with it. POSIX ACLs can also grant access to other named users and groups,
+//! and directories can carry a default ACL which new files within them
+//! inherit. Both are recorded in pax format archives the way star and GNU tar
+//! record them, as `SCHILY.acl.access` and `SCHILY.acl.default` attributes
+//! holding the ACL in it's short text form:
+//!
+//! ```text
+//! user::rw-,user:alice:r--:1001,group::r--,mask::r--,other::---
+//! ```
+//!
+//! Named entries carry the user or group's numeric ID after their
+//! permissions, so that ACLs can be restored on systems which don't know the
+//! name. USTAR and GNU format archives can't record ACLs at all.
+
+use std::{fmt, fs, io, path};
+use std::str::FromStr;
+use crate::fs::{get_acls, get_acls_of_file};
+use crate::tar::filter::{EntryAction, EntryFilter};
+use crate::tar::header::TarHeader;
+
+/// The pax attribute holding a file's access ACL.
+pub const ACCESS_KEY: &str = "SCHILY.acl.access";
+
+/// The pax attribute holding a directory's default ACL.
+pub const DEFAULT_KEY: &str = "SCHILY.acl.default";
+
+/// Who an ACL entry grants permissions to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AclTag {
+    /// The file's owner.
+    UserObj,
+
+    /// A named user.
+    User,
+
+    /// The file's group.
+    GroupObj,
+
+    /// A named group.
+    Group,
+
+    /// The most that any named entry, or the file's group, may be granted.
+    Mask,
+
+    /// Everybody else.
+    Other,
+}
+
+/// One entry of an access control list.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AclEntry {
+    pub tag: AclTag,
+
+    /// The name of the user or group a named entry grants permissions to.
+    pub name: Option<String>,
+
+    /// The ID of the user or group a named entry grants permissions to.
+    pub id: Option<u32>,
+
+    /// The read, write, and execute permissions granted, as the lowest three
+    /// bits of a UNIX mode.
+    pub permissions: u32,
+}
+
+/// An access control list.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Acl {
+    pub entries: Vec<AclEntry>,
+}
+
+/// The tag values Linux uses in ACLs stored as extended attributes.
+const XATTR_USER_OBJ: u16 = 0x01;
+const XATTR_USER: u16 = 0x02;
+const XATTR_GROUP_OBJ: u16 = 0x04;
+const XATTR_GROUP: u16 = 0x08;
+const XATTR_MASK: u16 = 0x10;
+const XATTR_OTHER: u16 = 0x20;
+
+impl Acl {
+    /// Decode an ACL as Linux stores it in the `system.posix_acl_access` and
+    /// `system.posix_acl_default` extended attributes.
+    ///
+    /// Named entries are decoded with their IDs, but not their names.
+    pub fn from_posix_xattr(data: &[u8]) -> io::Result<Acl> {
+        let malformed = || io::Error::new(io::ErrorKind::InvalidData, "Malformed POSIX ACL");
+
+        if data.len() < 4 || data.len() % 8 != 4 || data[0..4] != [2, 0, 0, 0] {
+            return Err(malformed());
+        }
+
+        let mut entries = Vec::new();
+
+        for entry in data[4..].chunks(8) {
+            let tag = u16::from_le_bytes([entry[0], entry[1]]);
+            let permissions = u16::from_le_bytes([entry[2], entry[3]]) as u32 & 0o7;
+            let id = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+
+            let (tag, id) = match tag {
+                XATTR_USER_OBJ => (AclTag::UserObj, None),
+                XATTR_USER => (AclTag::User, Some(id)),
+                XATTR_GROUP_OBJ => (AclTag::GroupObj, None),
+                XATTR_GROUP => (AclTag::Group, Some(id)),
+                XATTR_MASK => (AclTag::Mask, None),
+                XATTR_OTHER => (AclTag::Other, None),
+                _ => return Err(malformed())
+            };
+
+            entries.push(AclEntry {
+                tag: tag,
+                name: None,
+                id: id,
+                permissions: permissions
+            });
+        }
+
+        Ok(Acl { entries: entries })
+    }
+}
+
+impl fmt::Display for Acl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+
+            let tag = match entry.tag {
+                AclTag::UserObj | AclTag::User => "user",
+                AclTag::GroupObj | AclTag::Group => "group",
+                AclTag::Mask => "mask",
+                AclTag::Other => "other"
+            };
+            let permissions = format!("{}{}{}",
+                if entry.permissions & 0o4 != 0 { 'r' } else { '-' },
+                if entry.permissions & 0o2 != 0 { 'w' } else { '-' },
+                if entry.permissions & 0o1 != 0 { 'x' } else { '-' });
+
+            match (entry.tag, &entry.name, entry.id) {
+                (AclTag::User, Some(name), Some(id)) | (AclTag::Group, Some(name), Some(id)) => write!(f, "{}:{}:{}:{}", tag, name, permissions, id)?,
+                (AclTag::User, Some(name), None) | (AclTag::Group, Some(name), None) => write!(f, "{}:{}:{}", tag, name, permissions)?,
+                (AclTag::User, None, Some(id)) | (AclTag::Group, None, Some(id)) => write!(f, "{}:{}:{}", tag, id, permissions)?,
+                _ => write!(f, "{}::{}", tag, permissions)?
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl FromStr for Acl {
+    type Err = ();
+
+    /// Parse an ACL in it's text form.
+    ///
+    /// Entries may be separated by commas or newlines, and their tags may be
+    /// abbreviated to their first letter. Qualifiers which are entirely
+    /// digits are taken to be IDs rather than names.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut entries = Vec::new();
+
+        for entry in s.split(|c| c == ',' || c == '\n').map(|entry| entry.trim()).filter(|entry| !entry.is_empty()) {
+            let fields : Vec<&str> = entry.split(':').collect();
+
+            if fields.len() < 3 || fields.len() > 4 || fields[2].len() != 3 {
+                return Err(());
+            }
+
+            let named = !fields[1].is_empty();
+            let tag = match (fields[0], named) {
+                ("user", false) | ("u", false) => AclTag::UserObj,
+                ("user", true) | ("u", true) => AclTag::User,
+                ("group", false) | ("g", false) => AclTag::GroupObj,
+                ("group", true) | ("g", true) => AclTag::Group,
+                ("mask", false) | ("m", false) => AclTag::Mask,
+                ("other", false) | ("o", false) => AclTag::Other,
+                _ => return Err(())
+            };
+
+            let mut permissions = 0;
+
+            for (c, (expected, bit)) in fields[2].chars().zip([('r', 0o4), ('w', 0o2), ('x', 0o1)].iter()) {
+                match c {
+                    c if c == *expected => permissions |= bit,
+                    '-' => {},
+                    _ => return Err(())
+                }
+            }
+
+            let (name, id) = match (named, fields.get(3)) {
+                (false, None) => (None, None),
+                (false, Some(_)) => return Err(()),
+                (true, Some(id)) => (Some(fields[1].to_string()), Some(id.parse().map_err(|_| ())?)),
+                (true, None) => match fields[1].parse() {
+                    Ok(id) => (None, Some(id)),
+                    Err(_) => (Some(fields[1].to_string()), None)
+                }
+            };
+
+            entries.push(AclEntry {
+                tag: tag,
+                name: name,
+                id: id,
+                permissions: permissions
+            });
+        }
+
+        Ok(Acl { entries: entries })
+    }
+}
+
+/// A filter which records the access ACL of each file, and the default ACL
+/// of each directory, as `tar --acls` does.
+///
+/// Reading ACLs costs extra system calls for every file, so the filter should
+/// only be used when asked for. Files whose ACLs say no more than their mode
+/// bits do are archived without them, as are files whose ACLs can't be read.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RecordAcls;
+
+impl EntryFilter for RecordAcls {
+    fn filter_entry(&self, header: &mut TarHeader, entry_path: &path::Path, metadata: &fs::Metadata) -> EntryAction {
+        record_acls(header, entry_path, get_acls(metadata, entry_path))
+    }
+
+    fn filter_open_entry(&self, header: &mut TarHeader, entry_path: &path::Path, metadata: &fs::Metadata, file: &fs::File) -> EntryAction {
+        record_acls(header, entry_path, get_acls_of_file(metadata, file, entry_path))
+    }
+}
+
+fn record_acls(header: &mut TarHeader, entry_path: &path::Path, acls: io::Result<(Option<Acl>, Option<Acl>)>) -> EntryAction {
+    match acls {
+        Ok((access, default)) => {
+            header.acl_access = access;
+            header.acl_default = default;
+        },
+        Err(e) => warn!(path:? = entry_path; "Could not read access control lists: {}", e)
+    }
+
+    EntryAction::Keep
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Acl, AclEntry, AclTag};
+
+    fn entry(tag: AclTag, name: Option<&str>, id: Option<u32>, permissions: u32) -> AclEntry {
+        AclEntry {
+            tag: tag,
+            name: name.map(|name| name.to_string()),
+            id: id,
+            permissions: permissions
+        }
+    }
+
+    #[test]
+    fn acl_text() {
+        let acl = Acl { entries: vec![
+            entry(AclTag::UserObj, None, None, 0o6),
+            entry(AclTag::User, Some("alice"), Some(1001), 0o4),
+            entry(AclTag::User, None, Some(1002), 0o7),
+            entry(AclTag::GroupObj, None, None, 0o4),
+            entry(AclTag::Group, Some("staff"), None, 0o5),
+            entry(AclTag::Mask, None, None, 0o7),
+            entry(AclTag::Other, None, None, 0),
+        ]};
+        let text = "user::rw-,user:alice:r--:1001,user:1002:rwx,group::r--,group:staff:r-x,mask::rwx,other::---";
+
+        assert_eq!(acl.to_string(), text);
+        assert_eq!(text.parse::<Acl>(), Ok(acl.clone()));
+        assert_eq!("u::rw-\nu:alice:r--:1001\nu:1002:rwx\ng::r--\ng:staff:r-x\nm::rwx\no::---\n".parse::<Acl>(), Ok(acl));
+
+        assert!("user::rw".parse::<Acl>().is_err());
+        assert!("user::rwz".parse::<Acl>().is_err());
+        assert!("mask:alice:rwx".parse::<Acl>().is_err());
+        assert!("user:alice:rwx:bob".parse::<Acl>().is_err());
+        assert!("nobody::rwx".parse::<Acl>().is_err());
+    }
+
+    #[test]
+    fn posix_xattr() {
+        let mut data = vec![2, 0, 0, 0];
+
+        for (tag, permissions, id) in [(0x01u16, 6u16, u32::max_value()), (0x02, 4, 1001), (0x04, 4, u32::max_value()), (0x10, 4, u32::max_value()), (0x20, 0, u32::max_value())].iter() {
+            data.extend(&tag.to_le_bytes());
+            data.extend(&permissions.to_le_bytes());
+            data.extend(&id.to_le_bytes());
+        }
+
+        assert_eq!(Acl::from_posix_xattr(&data).unwrap().to_string(), "user::rw-,user:1001:r--,group::r--,mask::r--,other::---");
+        assert!(Acl::from_posix_xattr(&data[..10]).is_err());
+        assert!(Acl::from_posix_xattr(&[1, 0, 0, 0]).is_err());
+    }
+}
@@ -0,0 +1,216 @@
+//! Handling of macOS-specific file metadata.
+//!
+//! Files on macOS may carry Finder info (type and creator codes, label
+//! colors, and the like) and a resource fork, both of which are exposed as
+//! `com.apple.*` extended attributes. There are two common ways of archiving
+//! them:
+//!
+//!  * As pax extended attribute records, which GNU tar and libarchive restore
+//!    on systems that support them.
+//!  * As AppleDouble files: a `._` companion member, archived just before the
+//!    file it belongs to, holding the Finder info and resource fork. This is
+//!    what macOS's own tar does, and what other platforms' unzip and Finder
+//!    copies leave lying around.
+//!
+//! Archives intended for other platforms are often better off without either,
+//! so the metadata can also be left out altogether.
+
+use std::{io, fs, path};
+use std::io::Read;
+use std::str::FromStr;
+use crate::fs::get_apple_metadata;
+use crate::tar::header::{TarFormat, TarFileType, TarHeader, HeaderGenResult, headergen_in_memory};
+use crate::tar::filter::{EntryAction, EntryFilter};
+
+/// The extended attribute holding a file's Finder info.
+pub const FINDER_INFO_XATTR: &str = "com.apple.FinderInfo";
+
+/// The extended attribute holding a file's resource fork.
+pub const RESOURCE_FORK_XATTR: &str = "com.apple.ResourceFork";
+
+const APPLEDOUBLE_MAGIC: u32 = 0x0005_1607;
+const APPLEDOUBLE_VERSION: u32 = 0x0002_0000;
+const APPLEDOUBLE_RESOURCE_FORK: u32 = 2;
+const APPLEDOUBLE_FINDER_INFO: u32 = 9;
+const FINDER_INFO_LENGTH: usize = 32;
+
+/// How macOS-specific metadata should be archived.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum AppleMetadataPolicy {
+    /// Record every `com.apple.*` attribute as a pax extended attribute.
+    Pax,
+
+    /// Record the Finder info and resource fork in AppleDouble companions.
+    ///
+    /// Other `com.apple.*` attributes are not recorded.
+    AppleDouble,
+
+    /// Record nothing, and leave out any AppleDouble files found on disk.
+    Omit,
+}
+
+impl Default for AppleMetadataPolicy {
+    fn default() -> Self {
+        AppleMetadataPolicy::Pax
+    }
+}
+
+impl FromStr for AppleMetadataPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pax" => Ok(AppleMetadataPolicy::Pax),
+            "appledouble" => Ok(AppleMetadataPolicy::AppleDouble),
+            "none" => Ok(AppleMetadataPolicy::Omit),
+            _ => Err(())
+        }
+    }
+}
+
+/// The path of the AppleDouble companion for a given path.
+pub fn appledouble_path(path: &path::Path) -> path::PathBuf {
+    let mut name = std::ffi::OsString::from("._");
+
+    if let Some(file_name) = path.file_name() {
+        name.push(file_name);
+    }
+
+    path.with_file_name(name)
+}
+
+/// Encode Finder info and a resource fork as an AppleDouble file.
+///
+/// The Finder info is always present, as macOS expects; if the file has none,
+/// it's zeroed.
+pub fn encode_appledouble(finder_info: Option<&[u8]>, resource_fork: Option<&[u8]>) -> Vec<u8> {
+    let mut finder_info = finder_info.unwrap_or(&[]).to_vec();
+    finder_info.resize(FINDER_INFO_LENGTH, 0);
+
+    let mut entries = vec![(APPLEDOUBLE_FINDER_INFO, finder_info.as_slice())];
+
+    if let Some(resource_fork) = resource_fork {
+        entries.push((APPLEDOUBLE_RESOURCE_FORK, resource_fork));
+    }
+
+    let mut encoded = Vec::new();
+
+    encoded.extend(&APPLEDOUBLE_MAGIC.to_be_bytes());
+    encoded.extend(&APPLEDOUBLE_VERSION.to_be_bytes());
+    encoded.extend(b"Mac OS X        ");
+    encoded.extend(&(entries.len() as u16).to_be_bytes());
+
+    let mut offset = encoded.len() + entries.len() * 12;
+
+    for (id, data) in entries.iter() {
+        encoded.extend(&id.to_be_bytes());
+        encoded.extend(&(offset as u32).to_be_bytes());
+        encoded.extend(&(data.len() as u32).to_be_bytes());
+        offset += data.len();
+    }
+
+    for (_, data) in entries.iter() {
+        encoded.extend(*data);
+    }
+
+    encoded
+}
+
+/// Determine if a file on disk is an AppleDouble file.
+fn is_appledouble_file(path: &path::Path, metadata: &fs::Metadata) -> bool {
+    let is_companion = path.file_name().map_or(false, |name| name.to_string_lossy().starts_with("._"));
+
+    if !is_companion || !metadata.is_file() {
+        return false;
+    }
+
+    let mut magic = [0; 4];
+
+    match fs::File::open(path).and_then(|mut file| file.read_exact(&mut magic)) {
+        Ok(_) => u32::from_be_bytes(magic) == APPLEDOUBLE_MAGIC,
+        Err(_) => false
+    }
+}
+
+/// A filter which applies an `AppleMetadataPolicy` to traversed entries.
+///
+/// Under the `Pax` policy, the filter records the entry's metadata in it's
+/// header. AppleDouble companions can't be made by a filter, since they are
+/// members of their own; use `appledouble_headergen` for those.
+pub struct AppleMetadata {
+    policy: AppleMetadataPolicy,
+}
+
+impl AppleMetadata {
+    pub fn new(policy: AppleMetadataPolicy) -> AppleMetadata {
+        AppleMetadata {
+            policy: policy
+        }
+    }
+}
+
+impl EntryFilter for AppleMetadata {
+    fn filter_entry(&self, header: &mut TarHeader, entry_path: &path::Path, metadata: &fs::Metadata) -> EntryAction {
+        match self.policy {
+            AppleMetadataPolicy::Pax => match get_apple_metadata(entry_path) {
+                Ok(attributes) => header.xattrs.extend(attributes),
+                Err(e) => warn!(path:? = entry_path; "Could not read macOS metadata: {}", e)
+            },
+            AppleMetadataPolicy::Omit if is_appledouble_file(entry_path, metadata) => return EntryAction::Skip,
+            _ => {}
+        }
+
+        EntryAction::Keep
+    }
+}
+
+/// Generate an AppleDouble companion member for an entry, if it has any
+/// Finder info or a resource fork.
+///
+/// The companion takes it's ownership and times from the entry, and should be
+/// archived immediately before it.
+pub fn appledouble_headergen(entry: &HeaderGenResult, format: TarFormat) -> io::Result<Option<HeaderGenResult>> {
+    let attributes = get_apple_metadata(&entry.canonical_path)?;
+    let find = |name| attributes.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_slice());
+    let (finder_info, resource_fork) = (find(FINDER_INFO_XATTR), find(RESOURCE_FORK_XATTR));
+
+    if finder_info.is_none() && resource_fork.is_none() {
+        return Ok(None);
+    }
+
+    let mut header = entry.tar_header.clone();
+
+    header.path = Box::new(appledouble_path(&header.path));
+    header.file_type = TarFileType::FileStream;
+    header.unix_mode = 0o100000 | (header.unix_mode & 0o777);
+    header.symlink_path = None;
+    header.xattrs = Vec::new();
+    header.recovery_path = None;
+    header.recovery_remaining_size = None;
+    header.recovery_seek_offset = None;
+
+    Ok(Some(headergen_in_memory(&appledouble_path(&entry.original_path), header, format, encode_appledouble(finder_info, resource_fork))?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+    use super::{appledouble_path, encode_appledouble};
+
+    #[test]
+    fn appledouble() {
+        assert_eq!(appledouble_path(path::Path::new("dir/file.txt")), path::Path::new("dir/._file.txt"));
+        assert_eq!(appledouble_path(path::Path::new("file")), path::Path::new("._file"));
+
+        let encoded = encode_appledouble(Some(b"TEXTttxt"), Some(b"fork"));
+
+        assert_eq!(&encoded[..8], &[0, 5, 0x16, 0x07, 0, 2, 0, 0]);
+        assert_eq!(&encoded[24..26], &[0, 2]);
+        assert_eq!(&encoded[26..38], &[0, 0, 0, 9, 0, 0, 0, 50, 0, 0, 0, 32]);
+        assert_eq!(&encoded[38..50], &[0, 0, 0, 2, 0, 0, 0, 82, 0, 0, 0, 4]);
+        assert_eq!(&encoded[50..58], b"TEXTttxt");
+        assert_eq!(&encoded[82..], b"fork");
+
+        assert_eq!(encode_appledouble(None, None).len(), 38 + 32);
+    }
+}
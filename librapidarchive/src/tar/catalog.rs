@@ -0,0 +1,324 @@
+//! Member catalogs for partitioned tapes.
+//!
+//! Listing a tar archive normally means reading the whole thing. On media with
+//! more than one partition (e.g. LTO-5 and later), we can instead write an
+//! index of every member into a partition of it's own, where it can be read
+//! back in seconds. This is the same trick LTFS uses, without adopting the
+//! rest of the LTFS format: the data partition holds an ordinary tar archive,
+//! and the catalog partition holds a small tar archive describing it.
+
+use std::{io, path, time};
+use crate::tape::TapeDevice;
+use crate::tar::{ustar, pax, gnu, PathStyle};
+use crate::tar::reader::TarReader;
+use crate::tar::header::{TarFormat, TarHeader, TarFileType};
+
+/// The partition archive data is written to.
+pub const DATA_PARTITION: u32 = 1;
+
+/// The partition catalogs are written to.
+pub const CATALOG_PARTITION: u32 = 2;
+
+/// The name of the catalog member within the catalog partition.
+pub const CATALOG_MEMBER_NAME: &str = "rapidtar.catalog";
+
+/// Where a single archive member can be found.
+#[derive(Clone, PartialEq)]
+pub struct CatalogEntry {
+    pub path: Box<path::PathBuf>,
+    pub file_type: TarFileType,
+    pub file_size: u64,
+
+    /// How far into the volume the member's headers begin, in bytes.
+    pub offset: u64,
+
+    /// What the member links to, if it's a link.
+    ///
+    /// This is kept for manifests, and is not stored in the catalog partition.
+    pub link_target: Option<Box<path::PathBuf>>,
+}
+
+/// An index of the members of one archive volume.
+#[derive(Clone, Default)]
+pub struct Catalog {
+    entries: Vec<CatalogEntry>
+}
+
+pub(crate) fn escape_path(path: &path::Path, out: &mut String) {
+    for c in path.to_string_lossy().chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c)
+        }
+    }
+}
+
+pub(crate) fn unescape_path(escaped: &str) -> path::PathBuf {
+    let mut unescaped = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('t') => unescaped.push('\t'),
+            Some('n') => unescaped.push('\n'),
+            Some(other) => unescaped.push(other),
+            None => unescaped.push('\\')
+        }
+    }
+
+    path::PathBuf::from(unescaped)
+}
+
+impl Catalog {
+    pub fn new() -> Catalog {
+        Catalog {
+            entries: Vec::new()
+        }
+    }
+
+    pub fn push(&mut self, entry: CatalogEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn entries(&self) -> &[CatalogEntry] {
+        &self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Forget every member which starts at or after a given offset.
+    ///
+    /// This is used when data at the end of a volume didn't actually make it
+    /// onto the volume.
+    pub fn truncate_at(&mut self, offset: u64) {
+        self.entries.retain(|entry| entry.offset < offset);
+    }
+
+    /// Render the catalog as text, one member per line.
+    ///
+    /// Each line holds the member's offset, size, type flag, and path,
+    /// separated by tabs. Backslashes, tabs and newlines within paths are
+    /// escaped with a backslash.
+    pub fn to_text(&self) -> String {
+        let mut text = String::new();
+
+        for entry in self.entries.iter() {
+            text.push_str(&format!("{}\t{}\t{}\t", entry.offset, entry.file_size, entry.file_type.type_flag()));
+            escape_path(&entry.path, &mut text);
+            text.push('\n');
+        }
+
+        text
+    }
+
+    /// Parse a catalog previously rendered by `to_text`.
+    pub fn from_text(text: &str) -> io::Result<Catalog> {
+        let mut catalog = Catalog::new();
+
+        for line in text.lines() {
+            let mut fields = line.splitn(4, '\t');
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid catalog line {:?}", line));
+
+            let offset = fields.next().and_then(|f| f.parse().ok()).ok_or_else(invalid)?;
+            let file_size = fields.next().and_then(|f| f.parse().ok()).ok_or_else(invalid)?;
+            let flag = fields.next().and_then(|f| f.chars().next()).ok_or_else(invalid)?;
+            let path = fields.next().ok_or_else(invalid)?;
+
+            catalog.push(CatalogEntry {
+                path: Box::new(unescape_path(path)),
+                file_type: TarFileType::from_type_flag(flag),
+                file_size: file_size,
+                offset: offset,
+                link_target: None
+            });
+        }
+
+        Ok(catalog)
+    }
+
+    /// Encode the catalog as a complete tar archive, holding a single member
+    /// named `CATALOG_MEMBER_NAME`.
+    pub fn encode(&self, format: TarFormat) -> io::Result<Vec<u8>> {
+        let text = self.to_text().into_bytes();
+        let header = TarHeader {
+            path: Box::new(path::PathBuf::from(CATALOG_MEMBER_NAME)),
+            unix_mode: 0o644,
+            unix_uid: 0,
+            unix_gid: 0,
+            file_size: text.len() as u64,
+            mtime: Some(time::SystemTime::now()),
+            file_type: TarFileType::FileStream,
+            symlink_path: None,
+            unix_uname: "".into(),
+            unix_gname: "".into(),
+            unix_devmajor: 0,
+            unix_devminor: 0,
+            atime: None,
+            ctime: None,
+            birthtime: None,
+            xattrs: Vec::new(),
+            project_id: None,
+            acl_access: None,
+            acl_default: None,
+            path_style: PathStyle::default(),
+            sparse_map: None,
+            recovery_path: None,
+            recovery_remaining_size: None,
+            recovery_seek_offset: None
+        };
+
+        let mut archive = match format {
+            TarFormat::USTAR => {
+                let mut concrete_header = ustar::ustar_header(&header)?;
+                ustar::checksum_header(&mut concrete_header);
+                concrete_header
+            },
+            TarFormat::POSIX => {
+                let mut concrete_header = pax::pax_header(&header)?;
+                pax::checksum_header(&mut concrete_header);
+                concrete_header
+            },
+            TarFormat::GNU => {
+                let mut concrete_header = gnu::gnu_header(&header)?;
+                gnu::checksum_header(&mut concrete_header);
+                concrete_header
+            }
+        };
+
+        archive.extend(text);
+
+        let padding_needed = archive.len() % 512;
+        if padding_needed != 0 {
+            archive.extend(vec![0; 512 - padding_needed]);
+        }
+
+        archive.extend(vec![0; 1024]);
+
+        Ok(archive)
+    }
+}
+
+/// Reject tapes which are known to have too few partitions for a catalog.
+///
+/// If the driver can't tell us how many partitions there are, the tape is
+/// given the benefit of the doubt.
+pub fn check_partitioned(tape: &mut dyn TapeDevice) -> io::Result<()> {
+    match tape.partition_count() {
+        Ok(count) if count < CATALOG_PARTITION => Err(io::Error::new(io::ErrorKind::InvalidInput, "Tape has not been partitioned; --catalog-partition needs at least two partitions")),
+        _ => Ok(())
+    }
+}
+
+/// Write a catalog into the catalog partition of a tape.
+///
+/// The tape should be positioned at the end of the archive the catalog
+/// describes. The archive is ended with a filemark before switching
+/// partitions, and the catalog replaces anything already in the catalog
+/// partition. Data is written in records of `record_size` bytes.
+///
+/// Tapes with fewer than two partitions are rejected. If the driver can't
+/// tell us how many partitions there are, we find out when switching to the
+/// catalog partition fails.
+pub fn write_catalog_partition(tape: &mut dyn TapeDevice, catalog: &Catalog, format: TarFormat, record_size: usize) -> io::Result<()> {
+    check_partitioned(tape)?;
+
+    let mut encoded = catalog.encode(format)?;
+
+    let padding_needed = encoded.len() % record_size;
+    if padding_needed != 0 {
+        encoded.extend(vec![0; record_size - padding_needed]);
+    }
+
+    tape.write_filemark(true)?;
+    tape.seek_partition(CATALOG_PARTITION)?;
+    tape.seek_blocks(io::SeekFrom::Start(0))?;
+
+    for record in encoded.chunks(record_size) {
+        tape.write_all(record)?;
+    }
+
+    tape.write_filemark(true)
+}
+
+/// Read the catalog back out of the catalog partition of a tape.
+///
+/// The tape is left in the catalog partition afterwards. Tapes with fewer than
+/// two partitions are rejected, as are catalog partitions which don't hold a
+/// catalog.
+pub fn read_catalog_partition(tape: &mut dyn TapeDevice) -> io::Result<Catalog> {
+    check_partitioned(tape)?;
+
+    tape.seek_partition(CATALOG_PARTITION)?;
+    tape.seek_blocks(io::SeekFrom::Start(0))?;
+
+    let mut reader = TarReader::new(tape);
+
+    while let Some(entry) = reader.next_entry()? {
+        if entry.header.path.as_path() != path::Path::new(CATALOG_MEMBER_NAME) {
+            continue;
+        }
+
+        let mut text = String::new();
+
+        io::Read::read_to_string(&mut reader, &mut text)?;
+
+        return Catalog::from_text(&text);
+    }
+
+    Err(io::Error::new(io::ErrorKind::NotFound, "Catalog partition does not hold a catalog"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+    use crate::tape::memory::MemoryTapeDevice;
+    use crate::tar::header::{TarFormat, TarFileType};
+    use super::{Catalog, CatalogEntry, CATALOG_PARTITION, write_catalog_partition, read_catalog_partition};
+
+    #[test]
+    fn catalog_text_roundtrip() {
+        let mut catalog = Catalog::new();
+
+        catalog.push(CatalogEntry { path: Box::new(path::PathBuf::from("dir/")), file_type: TarFileType::Directory, file_size: 0, offset: 0, link_target: None });
+        catalog.push(CatalogEntry { path: Box::new(path::PathBuf::from("dir/odd\tname\\")), file_type: TarFileType::FileStream, file_size: 1234, offset: 512, link_target: None });
+
+        let parsed = Catalog::from_text(&catalog.to_text()).unwrap();
+        assert!(parsed.entries() == catalog.entries());
+
+        catalog.truncate_at(512);
+        assert_eq!(catalog.entries().len(), 1);
+
+        let encoded = catalog.encode(TarFormat::USTAR).unwrap();
+        assert_eq!(encoded.len() % 512, 0);
+        assert_eq!(&encoded[0..16], b"rapidtar.catalog");
+    }
+
+    #[test]
+    fn catalog_partition_roundtrip() {
+        let mut catalog = Catalog::new();
+
+        catalog.push(CatalogEntry { path: Box::new(path::PathBuf::from("file")), file_type: TarFileType::FileStream, file_size: 100, offset: 0, link_target: None });
+
+        let mut tape = MemoryTapeDevice::<()>::with_partitions(2);
+
+        write_catalog_partition(&mut tape, &catalog, TarFormat::POSIX, 10240).unwrap();
+        assert!(!tape.partition_records(CATALOG_PARTITION).is_empty());
+
+        let read = read_catalog_partition(&mut tape).unwrap();
+        assert!(read.entries() == catalog.entries());
+
+        let mut unpartitioned = MemoryTapeDevice::<()>::new();
+
+        assert!(write_catalog_partition(&mut unpartitioned, &catalog, TarFormat::POSIX, 10240).is_err());
+        assert!(read_catalog_partition(&mut unpartitioned).is_err());
+    }
+}
@@ -0,0 +1,170 @@
+//! Piping archive members into other programs.
+//!
+//! Rather than restoring members to disk, they can be handed to a command one
+//! at a time, in the manner of GNU tar's `--to-command`. This allows streaming
+//! restores into databases, deduplicating stores, or anything else that reads
+//! it's standard input.
+//!
+//! The command is run by the shell once per regular file, with the member's
+//! contents on it's standard input and it's metadata in the same environment
+//! variables GNU tar uses (`TAR_FILENAME`, `TAR_SIZE`, `TAR_MODE`, and so on).
+//! Other kinds of members are not piped anywhere.
+
+use std::{io, process, time};
+use crate::snapshot::shell_command;
+use crate::tar::header::{TarFormat, TarFileType};
+use crate::tar::reader::TarEntry;
+
+/// Details about the archive a member came from, for the command's
+/// environment.
+#[derive(Clone, Debug)]
+pub struct ArchiveContext {
+    /// The name of the archive, as given by the user.
+    pub archive: String,
+    pub volume: usize,
+    pub blocking_factor: usize,
+    pub format: TarFormat,
+}
+
+/// Render a time as seconds since the UNIX epoch, with nanoseconds.
+fn format_time(time: time::SystemTime) -> String {
+    match time.duration_since(time::UNIX_EPOCH) {
+        Ok(since) => format!("{}.{:09}", since.as_secs(), since.subsec_nanos()),
+        Err(before) => format!("-{}.{:09}", before.duration().as_secs(), before.duration().subsec_nanos())
+    }
+}
+
+/// The single-letter file type GNU tar gives in `TAR_FILETYPE`.
+fn file_type_letter(file_type: TarFileType) -> &'static str {
+    match file_type {
+        TarFileType::FileStream => "f",
+        TarFileType::Directory => "d",
+        TarFileType::SymbolicLink => "l",
+        TarFileType::HardLink => "h",
+        TarFileType::CharacterDevice => "c",
+        TarFileType::BlockDevice => "b",
+        TarFileType::FIFOPipe => "p",
+        _ => "u"
+    }
+}
+
+/// The environment variables describing a member to a command.
+pub fn member_environment(entry: &TarEntry, context: &ArchiveContext) -> Vec<(&'static str, String)> {
+    let header = &entry.header;
+    let mut environment = vec![
+        ("TAR_VERSION", env!("CARGO_PKG_VERSION").to_string()),
+        ("TAR_ARCHIVE", context.archive.clone()),
+        ("TAR_VOLUME", context.volume.to_string()),
+        ("TAR_BLOCKING_FACTOR", context.blocking_factor.to_string()),
+        ("TAR_FORMAT", match context.format {
+            TarFormat::USTAR => "ustar",
+            TarFormat::POSIX => "posix",
+            TarFormat::GNU => "gnu"
+        }.to_string()),
+        ("TAR_FILETYPE", file_type_letter(header.file_type).to_string()),
+        ("TAR_FILENAME", header.path.to_string_lossy().into_owned()),
+        ("TAR_REALNAME", header.path.to_string_lossy().into_owned()),
+        ("TAR_MODE", format!("{:04o}", header.unix_mode & 0o7777)),
+        ("TAR_UNAME", header.unix_uname.to_string()),
+        ("TAR_GNAME", header.unix_gname.to_string()),
+        ("TAR_UID", header.unix_uid.to_string()),
+        ("TAR_GID", header.unix_gid.to_string()),
+        ("TAR_SIZE", header.file_size.to_string()),
+    ];
+
+    let times = [("TAR_ATIME", header.atime), ("TAR_MTIME", header.mtime), ("TAR_CTIME", header.ctime)];
+
+    for (name, time) in times.iter() {
+        if let Some(time) = time {
+            environment.push((name, format_time(*time)));
+        }
+    }
+
+    environment
+}
+
+/// Run a command with a member's data on it's standard input.
+///
+/// Commands may exit without reading all of their input; whatever they leave
+/// unread is left in `data`. Yields the command's exit status.
+pub fn pipe_member<R: io::Read>(command: &str, entry: &TarEntry, data: &mut R, context: &ArchiveContext) -> io::Result<process::ExitStatus> {
+    let mut child = shell_command(command)
+        .envs(member_environment(entry, context))
+        .stdin(process::Stdio::piped())
+        .spawn()?;
+
+    if let Some(mut stdin) = child.stdin.take() {
+        match io::copy(data, &mut stdin) {
+            Ok(_) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {},
+            Err(e) => {
+                drop(stdin);
+                child.wait()?;
+                return Err(e);
+            }
+        }
+    }
+
+    child.wait()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, time};
+    use crate::tar::header::{TarFormat, TarHeader, TarFileType};
+    use crate::tar::reader::TarEntry;
+    use super::{ArchiveContext, member_environment};
+
+    fn entry() -> TarEntry {
+        let mut header = TarHeader::for_test("dir/file.txt", TarFileType::FileStream, 5);
+
+        header.unix_mode = 0o100640;
+        header.mtime = Some(time::UNIX_EPOCH + time::Duration::new(10, 5));
+
+        TarEntry {
+            header: header,
+            header_offset: 0,
+            data_offset: 512,
+            data_size: 5,
+            unknown_attributes: Vec::new()
+        }
+    }
+
+    fn context() -> ArchiveContext {
+        ArchiveContext {
+            archive: "test.tar".to_string(),
+            volume: 1,
+            blocking_factor: 20,
+            format: TarFormat::POSIX
+        }
+    }
+
+    #[test]
+    fn command_environment() {
+        let environment = member_environment(&entry(), &context());
+        let get = |name| environment.iter().find(|(key, _)| *key == name).map(|(_, value)| value.as_str());
+
+        assert_eq!(get("TAR_FILENAME"), Some("dir/file.txt"));
+        assert_eq!(get("TAR_FILETYPE"), Some("f"));
+        assert_eq!(get("TAR_MODE"), Some("0640"));
+        assert_eq!(get("TAR_SIZE"), Some("5"));
+        assert_eq!(get("TAR_MTIME"), Some("10.000000005"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn command_pipe() {
+        use std::io;
+        use super::pipe_member;
+
+        let output = env::temp_dir().join(format!("rapidtar-to-command-{}", std::process::id()));
+        let command = format!("cat > {:?}; echo \"$TAR_FILENAME\" >> {:?}", output, output);
+        let status = pipe_member(&command, &entry(), &mut io::Cursor::new(b"hello".to_vec()), &context()).unwrap();
+
+        assert!(status.success());
+        assert_eq!(fs::read_to_string(&output).unwrap(), "hellodir/file.txt\n");
+        fs::remove_file(&output).unwrap();
+
+        assert!(!pipe_member("exit 3", &entry(), &mut io::empty(), &context()).unwrap().success());
+    }
+}
@@ -0,0 +1,359 @@
+//! Differences between archive members and the filesystem.
+//!
+//! Compare mode reports each way an archived member disagrees with the file it
+//! was archived from as a `DifferenceRecord`. Records display in the same
+//! terse style GNU tar uses, and can also be written as JSON, one object per
+//! line, for consumption by monitoring systems:
+//!
+//! ```text
+//! {"path":"dir/a.txt","kind":"size","archive":5,"disk":12}
+//! ```
+//!
+//! Every object has a `path` and a `kind`; all kinds except `missing` also
+//! carry the `archive` and `disk` values which disagree. Times are given as
+//! fractional seconds since the UNIX epoch, modes in octal, device numbers as
+//! `major,minor`, and digests in hexadecimal.
+//!
+//! `compare_member` finds the differences for one member. Sizes, types, modes,
+//! device numbers, and modification times are always compared; contents are only compared if
+//! asked for, since that means reading every file in full.
+
+use std::{io, fmt, fs, path, time};
+use std::str::FromStr;
+use crate::digest::{Sha256, Sha256Digest, to_hex};
+use crate::tar::header::{TarHeader, TarFileType};
+use crate::fs::{get_file_type, get_unix_mode, get_device_numbers};
+
+/// One way in which an archive member differs from the filesystem.
+#[derive(Clone, PartialEq)]
+pub enum Difference {
+    /// The member does not exist on disk.
+    Missing,
+    FileType { archive: TarFileType, disk: TarFileType },
+    Size { archive: u64, disk: u64 },
+    Mtime { archive: time::SystemTime, disk: time::SystemTime },
+    Mode { archive: u32, disk: u32 },
+
+    /// A device has different major and minor numbers than the one on disk.
+    Device { archive: (u32, u32), disk: (u32, u32) },
+
+    /// The contents of the member hash differently from the file on disk.
+    Content { archive: Sha256Digest, disk: Sha256Digest },
+}
+
+impl Difference {
+    /// The name of this kind of difference, as used in JSON output.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Difference::Missing => "missing",
+            Difference::FileType { .. } => "type",
+            Difference::Size { .. } => "size",
+            Difference::Mtime { .. } => "mtime",
+            Difference::Mode { .. } => "mode",
+            Difference::Device { .. } => "device",
+            Difference::Content { .. } => "content"
+        }
+    }
+}
+
+/// A difference, along with the member it was found in.
+#[derive(Clone, PartialEq)]
+pub struct DifferenceRecord {
+    pub path: Box<path::PathBuf>,
+    pub difference: Difference,
+}
+
+/// Render a time as fractional seconds since the UNIX epoch.
+fn format_time(time: time::SystemTime) -> String {
+    let (sign, offset) = match time.duration_since(time::UNIX_EPOCH) {
+        Ok(offset) => ("", offset),
+        Err(e) => ("-", e.duration())
+    };
+
+    match offset.subsec_nanos() {
+        0 => format!("{}{}", sign, offset.as_secs()),
+        nanos => format!("{}{}.{}", sign, offset.as_secs(), format!("{:09}", nanos).trim_end_matches('0'))
+    }
+}
+
+/// Escape a path as a JSON string, including the surrounding quotes.
+pub fn json_path(path: &path::Path) -> String {
+    let mut escaped = String::from("\"");
+
+    for c in path.to_string_lossy().chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c)
+        }
+    }
+
+    escaped.push('"');
+    escaped
+}
+
+impl fmt::Display for DifferenceRecord {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let path = self.path.to_string_lossy();
+
+        match self.difference {
+            Difference::Missing => write!(f, "{}: Warning: Cannot stat: No such file or directory", path),
+            Difference::FileType { .. } => write!(f, "{}: File type differs", path),
+            Difference::Size { archive, disk } => write!(f, "{}: Size differs ({} in archive, {} on disk)", path, archive, disk),
+            Difference::Mtime { .. } => write!(f, "{}: Mod time differs", path),
+            Difference::Mode { archive, disk } => write!(f, "{}: Mode differs ({:o} in archive, {:o} on disk)", path, archive, disk),
+            Difference::Device { .. } => write!(f, "{}: Device number differs", path),
+            Difference::Content { .. } => write!(f, "{}: Contents differ", path)
+        }
+    }
+}
+
+impl DifferenceRecord {
+    /// Write this record as a single line of JSON.
+    pub fn write_json<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
+        write!(out, "{{\"path\":{},\"kind\":\"{}\"", json_path(&self.path), self.difference.kind())?;
+
+        match self.difference {
+            Difference::Missing => {},
+            Difference::FileType { archive, disk } => write!(out, ",\"archive\":\"{}\",\"disk\":\"{}\"", archive.type_flag(), disk.type_flag())?,
+            Difference::Size { archive, disk } => write!(out, ",\"archive\":{},\"disk\":{}", archive, disk)?,
+            Difference::Mtime { archive, disk } => write!(out, ",\"archive\":{},\"disk\":{}", format_time(archive), format_time(disk))?,
+            Difference::Mode { archive, disk } => write!(out, ",\"archive\":\"{:o}\",\"disk\":\"{:o}\"", archive, disk)?,
+            Difference::Device { archive, disk } => write!(out, ",\"archive\":\"{},{}\",\"disk\":\"{},{}\"", archive.0, archive.1, disk.0, disk.1)?,
+            Difference::Content { ref archive, ref disk } => write!(out, ",\"archive\":\"{}\",\"disk\":\"{}\"", to_hex(archive), to_hex(disk))?
+        }
+
+        writeln!(out, "}}")
+    }
+}
+
+/// How to compare the contents of members with files on disk.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ContentDigest {
+    /// Compare the SHA-256 digests of both.
+    Sha256,
+}
+
+impl FromStr for ContentDigest {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sha256" => Ok(ContentDigest::Sha256),
+            _ => Err(())
+        }
+    }
+}
+
+/// What `compare_member` should compare beyond a member's metadata.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CompareOptions {
+    /// How to compare contents, if at all.
+    pub content: Option<ContentDigest>,
+}
+
+/// Hash everything a reader has to give.
+fn digest_of<R: io::Read + ?Sized>(reader: &mut R) -> io::Result<Sha256Digest> {
+    let mut hasher = Sha256::new();
+
+    io::copy(reader, &mut hasher)?;
+
+    Ok(hasher.finish())
+}
+
+/// Truncate a time to whole seconds, which is all every tar format stores.
+fn whole_seconds(time: time::SystemTime) -> i64 {
+    match time.duration_since(time::UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64) - if before.duration().subsec_nanos() > 0 { 1 } else { 0 }
+    }
+}
+
+/// Compare an archive member with the file it was archived from.
+///
+/// `data` should yield the member's data, which is only read when comparing
+/// contents. Members whose type differs from the file on disk report only
+/// that, since nothing else about them is comparable. Hard links are compared
+/// as the files they link to.
+///
+/// Symbolic links on disk are followed, as they are when archiving, so that
+/// a linked file compares equal to the member it was archived as. Members
+/// which are themselves symbolic links are compared with the link instead.
+pub fn compare_member<R: io::Read + ?Sized>(header: &TarHeader, data: &mut R, disk_path: &path::Path, options: &CompareOptions) -> io::Result<Vec<Difference>> {
+    let metadata = match header.file_type {
+        TarFileType::SymbolicLink => fs::symlink_metadata(disk_path),
+        _ => fs::metadata(disk_path)
+    };
+    let metadata = match metadata {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![Difference::Missing]),
+        Err(e) => return Err(e)
+    };
+
+    let archive_type = match header.file_type {
+        TarFileType::HardLink => TarFileType::FileStream,
+        file_type => file_type
+    };
+    let disk_type = get_file_type(&metadata)?;
+
+    if archive_type != disk_type {
+        return Ok(vec![Difference::FileType { archive: header.file_type, disk: disk_type }]);
+    }
+
+    let mut differences = Vec::new();
+
+    if header.file_type == TarFileType::FileStream && header.file_size != metadata.len() {
+        differences.push(Difference::Size { archive: header.file_size, disk: metadata.len() });
+    }
+
+    if let (Some(archive), Ok(disk)) = (header.mtime, metadata.modified()) {
+        if whole_seconds(archive) != whole_seconds(disk) {
+            differences.push(Difference::Mtime { archive: archive, disk: disk });
+        }
+    }
+
+    let disk_mode = get_unix_mode(&metadata, disk_path)? & 0o7777;
+
+    if header.unix_mode & 0o7777 != disk_mode {
+        differences.push(Difference::Mode { archive: header.unix_mode & 0o7777, disk: disk_mode });
+    }
+
+    if let Some(disk) = get_device_numbers(&metadata, disk_path) {
+        let archive = (header.unix_devmajor, header.unix_devminor);
+
+        if archive != disk {
+            differences.push(Difference::Device { archive: archive, disk: disk });
+        }
+    }
+
+    if let Some(ContentDigest::Sha256) = options.content {
+        if header.file_type == TarFileType::FileStream {
+            let archive = digest_of(data)?;
+            let disk = digest_of(&mut fs::File::open(disk_path)?)?;
+
+            if archive != disk {
+                differences.push(Difference::Content { archive: archive, disk: disk });
+            }
+        }
+    }
+
+    Ok(differences)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, io, path, time};
+    use crate::tar::header::TarHeader;
+    use super::{Difference, DifferenceRecord, CompareOptions, ContentDigest, compare_member};
+
+    fn json(path: &str, difference: Difference) -> String {
+        let mut out = vec![];
+
+        DifferenceRecord { path: Box::new(path::PathBuf::from(path)), difference: difference }.write_json(&mut out).unwrap();
+
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn difference_json() {
+        assert_eq!(json("a \"quoted\"\tname", Difference::Missing), "{\"path\":\"a \\\"quoted\\\"\\tname\",\"kind\":\"missing\"}\n");
+        assert_eq!(json("a", Difference::Size { archive: 5, disk: 12 }), "{\"path\":\"a\",\"kind\":\"size\",\"archive\":5,\"disk\":12}\n");
+        assert_eq!(json("a", Difference::Mode { archive: 0o644, disk: 0o755 }), "{\"path\":\"a\",\"kind\":\"mode\",\"archive\":\"644\",\"disk\":\"755\"}\n");
+
+        let archive = time::UNIX_EPOCH + time::Duration::new(1_500_000_000, 500_000_000);
+        let disk = time::UNIX_EPOCH - time::Duration::from_secs(10);
+        assert_eq!(json("a", Difference::Mtime { archive: archive, disk: disk }), "{\"path\":\"a\",\"kind\":\"mtime\",\"archive\":1500000000.5,\"disk\":-10}\n");
+        assert_eq!(json("a", Difference::Device { archive: (1, 3), disk: (1, 5) }), "{\"path\":\"a\",\"kind\":\"device\",\"archive\":\"1,3\",\"disk\":\"1,5\"}\n");
+    }
+
+    #[test]
+    fn compare_members() {
+        let dir = env::temp_dir().join(format!("rapidtar-compare-{}", std::process::id()));
+        let file = dir.join("file");
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&file, b"original").unwrap();
+
+        let metadata = fs::symlink_metadata(&file).unwrap();
+        let mut header = TarHeader::abstract_header_for_file(path::Path::new("file"), &metadata, &file).unwrap();
+        let content = CompareOptions { content: Some(ContentDigest::Sha256) };
+        let kinds = |header: &TarHeader, data: &[u8], options: &CompareOptions| compare_member(header, &mut io::Cursor::new(data), &file, options).unwrap().iter().map(|d| d.kind()).collect::<Vec<_>>();
+
+        assert!(kinds(&header, b"original", &content).is_empty());
+        assert_eq!(kinds(&header, b"modified", &content), vec!["content"]);
+        assert!(kinds(&header, b"modified", &CompareOptions::default()).is_empty());
+
+        header.file_size = 3;
+        header.unix_mode ^= 0o111;
+        header.mtime = Some(time::UNIX_EPOCH);
+        assert_eq!(kinds(&header, b"new", &CompareOptions::default()), vec!["size", "mtime", "mode"]);
+
+        let dir_metadata = fs::symlink_metadata(&dir).unwrap();
+        let dir_header = TarHeader::abstract_header_for_file(path::Path::new("file"), &dir_metadata, &dir).unwrap();
+        assert_eq!(kinds(&dir_header, b"", &content), vec!["type"]);
+
+        fs::remove_file(&file).unwrap();
+        assert_eq!(kinds(&header, b"", &content), vec!["missing"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn compare_followed_links() {
+        use std::sync::{Arc, Mutex};
+        use crate::cancel::CancellationToken;
+        use crate::traverse::{traverse, SpecialFilePolicy};
+        use crate::tar::header::TarFileType;
+
+        let base = env::temp_dir().join(format!("rapidtar-compare-links-{}", std::process::id()));
+        let tree = base.join("tree");
+
+        fs::create_dir_all(&tree).unwrap();
+        fs::write(tree.join("file"), b"linked").unwrap();
+        std::os::unix::fs::symlink("file", tree.join("link")).unwrap();
+
+        let archived = Arc::new(Mutex::new(vec![]));
+        let archive = |iopath: &path::Path, tarpath: &path::Path, metadata: &fs::Metadata, archived: &Arc<Mutex<Vec<(TarHeader, Vec<u8>)>>>| {
+            let header = TarHeader::abstract_header_for_file(tarpath, metadata, iopath)?;
+            let data = match metadata.is_file() {
+                true => fs::read(iopath)?,
+                false => vec![]
+            };
+
+            archived.lock().unwrap().push((header, data));
+            Ok(())
+        };
+
+        traverse(tree.clone(), &archive, archived.clone(), Some(path::PathBuf::from("tree")), SpecialFilePolicy::Error, false, &CancellationToken::new()).unwrap();
+
+        let archived = archived.lock().unwrap().clone();
+        let content = CompareOptions { content: Some(ContentDigest::Sha256) };
+
+        assert_eq!(archived.len(), 3);
+        assert!(archived.iter().any(|(header, _)| *header.path == path::Path::new("tree/link") && header.file_type == TarFileType::FileStream));
+
+        for (header, data) in archived.iter() {
+            assert!(compare_member(header, &mut io::Cursor::new(data), &base.join(&*header.path), &content).unwrap().is_empty(), "{:?} differs", header.path);
+        }
+
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn compare_devices() {
+        let null = path::Path::new("/dev/null");
+        let metadata = fs::symlink_metadata(null).unwrap();
+        let mut header = TarHeader::abstract_header_for_file(path::Path::new("dev/null"), &metadata, null).unwrap();
+
+        assert_eq!((header.unix_devmajor, header.unix_devminor), (1, 3));
+        assert!(compare_member(&header, &mut io::empty(), null, &CompareOptions::default()).unwrap().iter().all(|d| d.kind() != "device"));
+
+        header.unix_devminor = 5;
+        assert!(compare_member(&header, &mut io::empty(), null, &CompareOptions::default()).unwrap().contains(&Difference::Device { archive: (1, 5), disk: (1, 3) }));
+    }
+}
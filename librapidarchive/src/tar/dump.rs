@@ -0,0 +1,544 @@
+//! Field-by-field dumps of raw tar headers, for diagnosing archives that other
+//! tar implementations disagree with us (or each other) about.
+//!
+//! `HeaderDumper` walks an archive block by block, without interpreting more of
+//! it than it has to. Each member comes back as the header blocks that make it
+//! up: pax extended headers, GNU long names, GNU sparse extension blocks, and
+//! the member's own header. Every field is shown as the bytes actually stored
+//! in it alongside what we decode it as, so that padding, terminators, base-256
+//! numerals and bad checksums are all visible:
+//!
+//! ```text
+//! header block at offset 1024: regular file
+//!   name      "dir/a.txt\0"
+//!   mode      "0000644\0" = 0o644
+//!   size      "00000000005\0" = 5
+//!   chksum    "0011431\0" = 4889 (valid)
+//!   typeflag  "0" = regular file
+//!   ...
+//! ```
+//!
+//! Pax records are listed after the extended header they came from. The walk
+//! stops at the first zero block, the end of the stream, or after a member
+//! whose header fails it's checksum, since nothing after it can be trusted to
+//! be where the header says it is.
+
+use std::{io, fmt, path};
+use std::io::Read;
+use crate::tar::{ustar, gnu, pax};
+use crate::tar::header::TarFileType;
+use crate::tar::list::format_timestamp;
+
+/// The size of a tar block.
+const BLOCK_SIZE: usize = 512;
+
+/// The largest extended header we're willing to hold in memory.
+const MAX_EXTENDED_HEADER_SIZE: u64 = 16 * 1024 * 1024;
+
+/// One header block of a member, with it's fields decoded.
+pub struct DumpedBlock {
+    /// Where the block starts in the archive.
+    pub offset: u64,
+
+    /// What kind of header the block is.
+    pub description: String,
+
+    /// Each field's name, and it's raw and decoded contents.
+    pub fields: Vec<(&'static str, String)>,
+
+    /// What the block's data holds, for extended headers: pax records, or a
+    /// GNU long name.
+    pub records: Vec<(String, String)>,
+}
+
+impl fmt::Display for DumpedBlock {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "header block at offset {}: {}", self.offset, self.description)?;
+
+        for (name, value) in self.fields.iter() {
+            writeln!(f, "  {:<10}{}", name, value)?;
+        }
+
+        for (key, value) in self.records.iter() {
+            writeln!(f, "    {} = {}", key, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// All of the header blocks of one member of an archive.
+pub struct DumpedMember {
+    /// The member's name, after any pax or GNU long names are applied.
+    pub path: path::PathBuf,
+
+    pub blocks: Vec<DumpedBlock>,
+}
+
+impl fmt::Display for DumpedMember {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for block in self.blocks.iter() {
+            write!(f, "{}", block)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Render raw field bytes as a quoted string.
+///
+/// Printable ASCII is shown as-is and anything else is escaped. Trailing nulls
+/// are shown as a single `\0`, so that terminated and unterminated fields can
+/// still be told apart without a screenful of padding.
+fn raw(field: &[u8]) -> String {
+    let end = field.iter().rposition(|b| *b != 0).map_or(0, |last| last + 1);
+    let mut quoted = String::from("\"");
+
+    for byte in field[..end].iter() {
+        match byte {
+            b'"' => quoted.push_str("\\\""),
+            b'\\' => quoted.push_str("\\\\"),
+            0 => quoted.push_str("\\0"),
+            0x20..=0x7E => quoted.push(*byte as char),
+            byte => quoted.push_str(&format!("\\x{:02x}", byte))
+        }
+    }
+
+    if end < field.len() {
+        quoted.push_str("\\0");
+    }
+
+    quoted.push('"');
+    quoted
+}
+
+/// Render a numeric field, and it's value in the given radix.
+fn numeral(field: &[u8], octal: bool) -> String {
+    let base256 = match field.first() {
+        Some(first) if first & 0x80 != 0 => " (base-256)",
+        _ => ""
+    };
+
+    match (gnu::parse_gnu_numeral(field), octal) {
+        (Some(value), true) => format!("{} = 0o{:o}{}", raw(field), value, base256),
+        (Some(value), false) => format!("{} = {}{}", raw(field), value, base256),
+        (None, _) => format!("{} = malformed", raw(field))
+    }
+}
+
+/// Render a time field, and the time it holds.
+fn timestamp(field: &[u8]) -> String {
+    match gnu::parse_gnu_time(field) {
+        Some(time) => format!("{} ({} UTC)", numeral(field, false), format_timestamp(time, 0)),
+        None => format!("{} = malformed", raw(field))
+    }
+}
+
+/// Render the checksum field, along with whether the block matches it.
+fn checksum(block: &[u8]) -> String {
+    let sum : u64 = block.iter().enumerate().map(|(i, byte)| if i >= 148 && i < 156 { b' ' as u64 } else { *byte as u64 }).sum();
+    let stored = ustar::parse_tar_numeral(&block[148..156]);
+
+    match (stored, ustar::verify_checksum(block)) {
+        (Some(stored), true) => format!("{} = {} (valid)", raw(&block[148..156]), stored),
+        (Some(stored), false) => format!("{} = {} (invalid, block sums to {})", raw(&block[148..156]), stored, sum),
+        (None, _) => format!("{} = malformed (block sums to {})", raw(&block[148..156]), sum)
+    }
+}
+
+/// Describe a type flag.
+fn type_description(flag: u8) -> String {
+    match flag {
+        b'x' => "pax extended header".to_string(),
+        b'g' => "pax global extended header".to_string(),
+        b'L' => "GNU long name".to_string(),
+        b'K' => "GNU long link name".to_string(),
+        b'S' => "GNU old sparse file".to_string(),
+        b'V' => "GNU volume label".to_string(),
+        b'M' => "GNU multi-volume continuation".to_string(),
+        b'D' => "GNU incremental directory".to_string(),
+        b'7' => "contiguous file".to_string(),
+        0 => "regular file (pre-POSIX)".to_string(),
+        flag => match TarFileType::from_type_flag(flag as char) {
+            TarFileType::FileStream => "regular file".to_string(),
+            TarFileType::HardLink => "hard link".to_string(),
+            TarFileType::SymbolicLink => "symbolic link".to_string(),
+            TarFileType::CharacterDevice => "character device".to_string(),
+            TarFileType::BlockDevice => "block device".to_string(),
+            TarFileType::Directory => "directory".to_string(),
+            TarFileType::FIFOPipe => "FIFO".to_string(),
+            TarFileType::Other(_) => "unknown type".to_string()
+        }
+    }
+}
+
+/// Render a field of GNU sparse map slots.
+fn sparse_slots(field: &[u8]) -> String {
+    match gnu::parse_gnu_sparse_extents(field) {
+        Ok(ref extents) if extents.is_empty() => "(none)".to_string(),
+        Ok(extents) => extents.iter().map(|extent| format!("{}+{}", extent.offset, extent.length)).collect::<Vec<_>>().join(" "),
+        Err(e) => format!("malformed ({})", e)
+    }
+}
+
+/// Decode the fields of a header block.
+///
+/// Which fields follow the link name depends on the block's magic: POSIX ustar
+/// headers have a name prefix, GNU headers have extra times and sparse map
+/// slots, and pre-POSIX headers have nothing at all.
+pub fn dump_header(block: &[u8]) -> Vec<(&'static str, String)> {
+    let magic = &block[257..263];
+    let version = &block[263..265];
+    let is_gnu = &block[257..265] == b"ustar  \0";
+    let is_ustar = magic == b"ustar\0" || is_gnu;
+
+    let mut fields = vec![
+        ("name", raw(&block[0..100])),
+        ("mode", numeral(&block[100..108], true)),
+        ("uid", numeral(&block[108..116], false)),
+        ("gid", numeral(&block[116..124], false)),
+        ("size", numeral(&block[124..136], false)),
+        ("mtime", timestamp(&block[136..148])),
+        ("chksum", checksum(block)),
+        ("typeflag", format!("{} = {}", raw(&block[156..157]), type_description(block[156]))),
+        ("linkname", raw(&block[157..257])),
+        ("magic", format!("{} = {}", raw(magic), match (is_gnu, is_ustar) {
+            (true, _) => "GNU",
+            (false, true) => "POSIX ustar",
+            (false, false) => "none (pre-POSIX)"
+        })),
+    ];
+
+    if !is_ustar {
+        return fields;
+    }
+
+    fields.push(("version", raw(version)));
+    fields.push(("uname", raw(&block[265..297])));
+    fields.push(("gname", raw(&block[297..329])));
+    fields.push(("devmajor", numeral(&block[329..337], false)));
+    fields.push(("devminor", numeral(&block[337..345], false)));
+
+    if is_gnu {
+        fields.push(("atime", match block[345..357].iter().any(|b| *b != 0) {
+            true => timestamp(&block[345..357]),
+            false => raw(&block[345..357])
+        }));
+        fields.push(("ctime", match block[357..369].iter().any(|b| *b != 0) {
+            true => timestamp(&block[357..369]),
+            false => raw(&block[357..369])
+        }));
+        fields.push(("offset", numeral(&block[369..381], false)));
+        fields.push(("sparse", sparse_slots(&block[386..482])));
+        fields.push(("extended", format!("{}", block[482])));
+        fields.push(("realsize", numeral(&block[483..495], false)));
+    } else {
+        fields.push(("prefix", raw(&block[345..500])));
+    }
+
+    fields
+}
+
+/// The name a header block gives, including it's prefix if it has one.
+fn block_name(block: &[u8]) -> String {
+    let name = ustar::parse_tar_string(&block[0..100]);
+
+    match &block[257..263] == b"ustar\0" {
+        true => match ustar::parse_tar_string(&block[345..500]) {
+            ref prefix if prefix.is_empty() => name,
+            prefix => format!("{}/{}", prefix, name)
+        },
+        false => name
+    }
+}
+
+/// Walks an archive's header blocks, member by member.
+pub struct HeaderDumper<R: io::Read> {
+    inner: R,
+    offset: u64,
+    pending: Option<Vec<u8>>,
+    finished: bool,
+}
+
+impl<R: io::Read> HeaderDumper<R> {
+    pub fn new(inner: R) -> HeaderDumper<R> {
+        HeaderDumper {
+            inner: inner,
+            offset: 0,
+            pending: None,
+            finished: false
+        }
+    }
+
+    /// Read as much of a buffer as the stream has to give.
+    fn read_fully(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+
+        while filled < buf.len() {
+            match self.inner.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+        }
+
+        self.offset += filled as u64;
+
+        Ok(filled)
+    }
+
+    /// Read the next block, returning None at the end of the stream.
+    fn read_block(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if let Some(block) = self.pending.take() {
+            return Ok(Some(block));
+        }
+
+        let mut block = vec![0; BLOCK_SIZE];
+
+        match self.read_fully(&mut block)? {
+            BLOCK_SIZE => Ok(Some(block)),
+            _ => Ok(None)
+        }
+    }
+
+    /// Read the data following an extended header, padding and all.
+    fn read_data(&mut self, size: u64) -> io::Result<Vec<u8>> {
+        if size > MAX_EXTENDED_HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Extended header at offset {} is unreasonably large ({} bytes)", self.offset, size)));
+        }
+
+        let padded = (size as usize + BLOCK_SIZE - 1) / BLOCK_SIZE * BLOCK_SIZE;
+        let mut data = vec![0; padded];
+
+        if self.read_fully(&mut data)? < size as usize {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Archive ends in the middle of an extended header"));
+        }
+
+        data.truncate(size as usize);
+
+        Ok(data)
+    }
+
+    /// Skip a member's data and padding.
+    fn skip_data(&mut self, size: u64) -> io::Result<()> {
+        let padded = (size + BLOCK_SIZE as u64 - 1) / BLOCK_SIZE as u64 * BLOCK_SIZE as u64;
+        let skipped = io::copy(&mut (&mut self.inner).take(padded), &mut io::sink())?;
+
+        self.offset += skipped;
+
+        Ok(())
+    }
+
+    /// Dump the header blocks of the next member.
+    ///
+    /// Returns None at the end of the archive.
+    pub fn next_member(&mut self) -> io::Result<Option<DumpedMember>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let mut blocks = Vec::new();
+        let mut long_name = None;
+        let mut pax_path = None;
+        let mut pax_size = None;
+        let mut header_name;
+
+        loop {
+            let offset = self.offset - match self.pending {
+                Some(_) => BLOCK_SIZE as u64,
+                None => 0
+            };
+            let block = match self.read_block()? {
+                Some(ref block) if block.iter().all(|b| *b == 0) => None,
+                block => block
+            };
+            let block = match block {
+                Some(block) => block,
+                None => {
+                    self.finished = true;
+
+                    return match blocks.is_empty() {
+                        true => Ok(None),
+                        false => Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Archive ends between a member's extended headers and it's header"))
+                    };
+                }
+            };
+
+            let flag = block[156];
+            let size = gnu::parse_gnu_numeral(&block[124..136]).unwrap_or(0);
+            let valid = ustar::verify_checksum(&block);
+
+            header_name = block_name(&block);
+            let mut dumped = DumpedBlock {
+                offset: offset,
+                description: type_description(flag),
+                fields: dump_header(&block),
+                records: Vec::new()
+            };
+
+            if !valid {
+                self.finished = true;
+                blocks.push(dumped);
+                break;
+            }
+
+            match flag {
+                b'x' | b'g' => {
+                    let data = self.read_data(size)?;
+
+                    match pax::parse_pax_binary_attributes(&data) {
+                        Ok(attributes) => for (key, value) in attributes {
+                            let text = String::from_utf8_lossy(&value).into_owned();
+
+                            if flag == b'x' {
+                                match key.as_str() {
+                                    "path" => pax_path = Some(text.clone()),
+                                    "size" => pax_size = text.parse().ok(),
+                                    _ => {}
+                                }
+                            }
+
+                            dumped.records.push((key, format!("{:?}", text)));
+                        },
+                        Err(e) => dumped.records.push(("(malformed)".to_string(), e.to_string()))
+                    }
+
+                    blocks.push(dumped);
+                },
+                b'L' | b'K' => {
+                    let data = self.read_data(size)?;
+                    let text = ustar::parse_tar_string(&data);
+
+                    if flag == b'L' {
+                        long_name = Some(text.clone());
+                    }
+
+                    dumped.records.push(("name".to_string(), format!("{:?}", text)));
+                    blocks.push(dumped);
+                },
+                _ => {
+                    let mut extended = flag == b'S' && block[482] != 0;
+
+                    blocks.push(dumped);
+
+                    while extended {
+                        let offset = self.offset;
+                        let extension = self.read_block()?.ok_or(io::Error::new(io::ErrorKind::UnexpectedEof, "Archive ends in the middle of a sparse map"))?;
+
+                        extended = extension[504] != 0;
+                        blocks.push(DumpedBlock {
+                            offset: offset,
+                            description: "GNU sparse extension".to_string(),
+                            fields: vec![
+                                ("sparse", sparse_slots(&extension[0..504])),
+                                ("extended", format!("{}", extension[504]))
+                            ],
+                            records: Vec::new()
+                        });
+                    }
+
+                    let size = pax_size.unwrap_or(size);
+                    let carries_data = flag == 0 || TarFileType::from_type_flag(flag as char).has_data();
+
+                    //Some archivers give links and directories a size anyway;
+                    //only skip it if a header doesn't immediately follow.
+                    if carries_data || size == 0 {
+                        self.skip_data(size)?;
+                    } else {
+                        match self.read_block()? {
+                            Some(ref next) if ustar::verify_checksum(next) => self.pending = Some(next.clone()),
+                            Some(_) => self.skip_data(size.saturating_sub(BLOCK_SIZE as u64))?,
+                            None => {}
+                        }
+                    }
+
+                    break;
+                }
+            }
+        }
+
+        let name = pax_path.or(long_name).unwrap_or(header_name);
+
+        Ok(Some(DumpedMember {
+            path: path::PathBuf::from(name.trim_end_matches('/')),
+            blocks: blocks
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path};
+    use crate::tar::header::{TarHeader, TarFileType};
+    use crate::tar::{ustar, gnu, pax};
+    use super::{HeaderDumper, raw};
+
+    fn field<'a>(fields: &'a [(&'static str, String)], name: &str) -> &'a str {
+        &fields.iter().find(|(field, _)| *field == name).unwrap().1
+    }
+
+    #[test]
+    fn raw_fields() {
+        assert_eq!(raw(b"abc\0\0\0"), "\"abc\\0\"");
+        assert_eq!(raw(b"full"), "\"full\"");
+        assert_eq!(raw(b"a\"b\\\x80\0c"), "\"a\\\"b\\\\\\x80\\0c\"");
+    }
+
+    #[test]
+    fn dump_members() {
+        let long_name = format!("dir/{}.txt", "long".repeat(50));
+
+        let mut archive = ustar::ustar_header(&TarHeader::for_test("dir/a.txt", TarFileType::FileStream, 5)).unwrap();
+        ustar::checksum_header(&mut archive);
+        archive.extend(b"hello");
+        archive.extend(vec![0; 507]);
+
+        let mut pax_member = pax::pax_header(&TarHeader::for_test(&long_name, TarFileType::FileStream, 0)).unwrap();
+        pax::checksum_header(&mut pax_member);
+        archive.extend(pax_member);
+
+        let mut gnu_member = gnu::gnu_header(&TarHeader::for_test(&long_name, TarFileType::FileStream, 0)).unwrap();
+        gnu::checksum_header(&mut gnu_member);
+        archive.extend(gnu_member);
+
+        let mut corrupt = ustar::ustar_header(&TarHeader::for_test("corrupt", TarFileType::FileStream, 0)).unwrap();
+        ustar::checksum_header(&mut corrupt);
+        corrupt[0] = b'C';
+        archive.extend(corrupt);
+
+        let mut unreached = ustar::ustar_header(&TarHeader::for_test("unreached", TarFileType::FileStream, 0)).unwrap();
+        ustar::checksum_header(&mut unreached);
+        archive.extend(unreached);
+        archive.extend(vec![0; 1024]);
+
+        let mut dumper = HeaderDumper::new(io::Cursor::new(archive));
+
+        let file = dumper.next_member().unwrap().unwrap();
+        assert!(file.path.as_path() == path::Path::new("dir/a.txt"));
+        assert_eq!(file.blocks.len(), 1);
+        assert_eq!(file.blocks[0].description, "regular file");
+        assert_eq!(field(&file.blocks[0].fields, "mode"), "\"0000644\\0\" = 0o644");
+        assert_eq!(field(&file.blocks[0].fields, "size"), "\"00000000005\\0\" = 5");
+        assert!(field(&file.blocks[0].fields, "chksum").ends_with("(valid)"));
+        assert_eq!(field(&file.blocks[0].fields, "prefix"), "\"\\0\"");
+
+        let pax_file = dumper.next_member().unwrap().unwrap();
+        assert_eq!(pax_file.path.to_string_lossy(), long_name);
+        assert_eq!(pax_file.blocks.len(), 2);
+        assert_eq!(pax_file.blocks[0].offset, 1024);
+        assert_eq!(pax_file.blocks[0].description, "pax extended header");
+        assert!(pax_file.blocks[0].records.contains(&("path".to_string(), format!("{:?}", long_name))));
+
+        let gnu_file = dumper.next_member().unwrap().unwrap();
+        assert_eq!(gnu_file.path.to_string_lossy(), long_name);
+        assert_eq!(gnu_file.blocks.len(), 2);
+        assert_eq!(gnu_file.blocks[0].description, "GNU long name");
+        assert!(field(&gnu_file.blocks[1].fields, "magic").ends_with("GNU"));
+
+        let corrupt = dumper.next_member().unwrap().unwrap();
+        assert!(corrupt.path.as_path() == path::Path::new("Corrupt"));
+        assert!(field(&corrupt.blocks[0].fields, "chksum").contains("(invalid, block sums to "));
+        assert!(dumper.next_member().unwrap().is_none());
+    }
+}
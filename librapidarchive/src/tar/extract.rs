@@ -0,0 +1,189 @@
+//! Extracting archive members onto disk.
+//!
+//! Every member is placed beneath the directory being extracted into, through
+//! `fs::resolve_beneath`, so that links extracted earlier in an archive can't
+//! redirect later members anywhere else. Members are resolved as they are
+//! extracted, so a link is accounted for as soon as it's on disk.
+//!
+//! Regular files, directories, and links are extracted. Directories get their
+//! archived permissions and times only once the whole archive has been
+//! extracted, so that members inside of read-only directories can still be
+//! created, and creating them doesn't disturb the directory's times.
+
+use std::{io, path, time};
+use std::io::Seek;
+use crate::fs::{resolve_beneath, set_file_times, set_file_mode};
+use crate::tar::{restored_path, UncRestore, PathStyle};
+use crate::tar::header::{TarHeader, TarFileType};
+use crate::tar::permissions::PermissionPolicy;
+
+/// A directory whose permissions and times are restored once extraction ends.
+struct PendingDirectory {
+    path: path::PathBuf,
+    mode: Option<u32>,
+    mtime: Option<time::SystemTime>
+}
+
+/// Extracts members into a directory.
+pub struct Extractor {
+    root: path::PathBuf,
+    permissions: PermissionPolicy,
+    unc: UncRestore,
+    style: PathStyle,
+    directories: Vec<PendingDirectory>
+}
+
+impl Extractor {
+    /// Create an extractor placing members beneath `root`.
+    ///
+    /// `unc` and `style` decide where members are placed, as with
+    /// `tar::restored_path`.
+    pub fn new(root: &path::Path, permissions: PermissionPolicy, unc: UncRestore, style: PathStyle) -> Extractor {
+        Extractor {
+            root: root.to_path_buf(),
+            permissions: permissions,
+            unc: unc,
+            style: style,
+            directories: Vec::new()
+        }
+    }
+
+    /// Extract one member, reading it's contents from `data`.
+    pub fn extract<R: io::Read>(&mut self, header: &TarHeader, data: &mut R) -> io::Result<()> {
+        let member_path = restored_path(&header.path, self.unc, self.style);
+        let member = resolve_beneath(&self.root, &member_path)?;
+        let creation_mode = self.permissions.creation_mode(header.unix_mode);
+
+        match header.file_type {
+            TarFileType::FileStream => {
+                let mut file = member.create_file(creation_mode)?;
+
+                io::copy(data, &mut file)?;
+
+                if let Some(mode) = self.permissions.restored_mode(header.unix_mode, true) {
+                    set_file_mode(&file, mode)?;
+                }
+
+                set_file_times(&file, None, header.mtime, header.birthtime)
+            },
+            TarFileType::Directory => {
+                let created = member.create_dir(creation_mode)?;
+
+                self.directories.push(PendingDirectory {
+                    path: member_path,
+                    mode: self.permissions.restored_mode(header.unix_mode, created),
+                    mtime: header.mtime
+                });
+
+                Ok(())
+            },
+            TarFileType::SymbolicLink => match header.symlink_path {
+                Some(ref target) => member.create_symlink(target),
+                None => Err(io::Error::new(io::ErrorKind::InvalidData, "Symbolic link has no target"))
+            },
+            TarFileType::HardLink => match header.symlink_path {
+                Some(ref target) => member.create_hard_link(&resolve_beneath(&self.root, &restored_path(target, self.unc, self.style))?),
+                None => Err(io::Error::new(io::ErrorKind::InvalidData, "Hard link has no target"))
+            },
+            _ => Err(io::Error::new(io::ErrorKind::Other, "Devices, pipes, and other special files cannot be extracted"))
+        }
+    }
+
+    /// Extract the rest of a member which was torn at the end of the previous
+    /// volume, writing `data` into the file already extracted for it from
+    /// `offset` onwards.
+    pub fn extract_continuation<R: io::Read>(&mut self, header: &TarHeader, offset: u64, data: &mut R) -> io::Result<()> {
+        if header.file_type != TarFileType::FileStream {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Only files can be continued from the previous volume"));
+        }
+
+        let member = resolve_beneath(&self.root, &restored_path(&header.path, self.unc, self.style))?;
+        let mut file = member.open_file()?;
+
+        file.seek(io::SeekFrom::Start(offset))?;
+        io::copy(data, &mut file)?;
+
+        set_file_times(&file, None, header.mtime, header.birthtime)
+    }
+
+    /// Restore the permissions and times of every directory extracted.
+    ///
+    /// Directories are restored deepest first, so that restoring one doesn't
+    /// stop it's subdirectories from being restored. Every directory is
+    /// attempted; the first error is yielded afterwards.
+    pub fn finish(self) -> io::Result<()> {
+        let mut directories = self.directories;
+        let mut result = Ok(());
+
+        directories.sort_by_key(|directory| std::cmp::Reverse(directory.path.components().count()));
+
+        for directory in directories {
+            let restored = resolve_beneath(&self.root, &directory.path).and_then(|member| member.open_dir()).and_then(|dir| {
+                if let Some(mode) = directory.mode {
+                    set_file_mode(&dir, mode)?;
+                }
+
+                set_file_times(&dir, None, directory.mtime, None)
+            });
+
+            if result.is_ok() {
+                result = restored;
+            }
+        }
+
+        result
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{env, fs, io, path, time};
+    use std::os::unix::fs::PermissionsExt;
+    use crate::tar::{UncRestore, PathStyle};
+    use crate::tar::header::{TarHeader, TarFileType};
+    use crate::tar::permissions::PermissionPolicy;
+    use super::Extractor;
+
+    #[test]
+    fn extract_members() {
+        let root = env::temp_dir().join(format!("rapidtar-extract-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir(&root).unwrap();
+
+        let mut extractor = Extractor::new(&root, PermissionPolicy::Exact, UncRestore::default(), PathStyle::default());
+        let mut dir = TarHeader::for_test("dir/", TarFileType::Directory, 0);
+        let file = TarHeader::for_test("dir/file", TarFileType::FileStream, 5);
+        let mut link = TarHeader::for_test("link", TarFileType::SymbolicLink, 0);
+        let mut hard = TarHeader::for_test("dir/hard", TarFileType::HardLink, 0);
+        let mut escape = TarHeader::for_test("escape", TarFileType::SymbolicLink, 0);
+        let planted = TarHeader::for_test("escape/file", TarFileType::FileStream, 5);
+
+        dir.unix_mode = 0o555;
+        link.symlink_path = Some(Box::new(path::PathBuf::from("dir")));
+        hard.symlink_path = Some(Box::new(path::PathBuf::from("dir/file")));
+        escape.symlink_path = Some(Box::new(env::temp_dir()));
+
+        extractor.extract(&dir, &mut &b""[..]).unwrap();
+        extractor.extract(&file, &mut &b"hello"[..]).unwrap();
+        extractor.extract(&link, &mut &b""[..]).unwrap();
+        extractor.extract(&hard, &mut &b""[..]).unwrap();
+        extractor.extract(&escape, &mut &b""[..]).unwrap();
+        assert_eq!(extractor.extract(&planted, &mut &b"oops!"[..]).unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+        extractor.extract_continuation(&file, 3, &mut &b"p!"[..]).unwrap();
+        extractor.finish().unwrap();
+
+        assert_eq!(fs::read(root.join("dir/file")).unwrap(), b"help!");
+        assert_eq!(fs::read(root.join("dir/hard")).unwrap(), b"help!");
+        assert_eq!(fs::read_link(root.join("link")).unwrap(), path::PathBuf::from("dir"));
+        assert_eq!(fs::metadata(root.join("dir/file")).unwrap().permissions().mode() & 0o7777, 0o644);
+        assert_eq!(fs::metadata(root.join("dir/file")).unwrap().modified().unwrap(), time::UNIX_EPOCH + time::Duration::from_secs(1_500_000_000));
+
+        let dir_metadata = fs::metadata(root.join("dir")).unwrap();
+
+        assert_eq!(dir_metadata.permissions().mode() & 0o7777, 0o555);
+        assert_eq!(dir_metadata.modified().unwrap(), time::UNIX_EPOCH + time::Duration::from_secs(1_500_000_000));
+
+        fs::set_permissions(root.join("dir"), fs::Permissions::from_mode(0o755)).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
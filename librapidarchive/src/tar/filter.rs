@@ -0,0 +1,724 @@
+//! Per-entry policies applied between traversal and header generation.
+//!
+//! Programs embedding librapidarchive often need to adjust what gets archived
+//! without reimplementing traversal: renaming members, overriding ownership,
+//! or leaving certain files out altogether. An `EntryFilter` sees the abstract
+//! header of every traversed entry before it is encoded, and may alter it or
+//! veto it.
+
+use std::{path, fs, io, num, ffi, time};
+use std::collections::HashMap;
+use std::str::FromStr;
+use crate::traverse;
+use crate::fs::{ArchivalFile, AtimePolicy, get_project_id, get_project_id_of_file, hard_link_identity};
+use crate::cancel::CancellationToken;
+use crate::intern::StringPool;
+use crate::tar::PathStyle;
+use crate::tar::header::{TarFormat, TarHeader, HeaderGenResult, headergen_from_source};
+
+/// What should happen to an entry after it has been filtered.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EntryAction {
+    /// Archive the entry, using whatever header the filter left behind.
+    Keep,
+
+    /// Leave the entry out of the archive. If the entry is a directory, it's
+    /// contents are still traversed.
+    Skip,
+
+    /// Leave the entry out of the archive, and if it is a directory, do not
+    /// traverse it's contents either.
+    Prune
+}
+
+/// A policy which may alter or veto entries before they are archived.
+///
+/// Filters are called from traversal threads, possibly many at once, so they
+/// must be `Sync`. Any closure of the appropriate signature is a filter.
+pub trait EntryFilter: Send + Sync {
+    /// Inspect the header of an entry about to be archived.
+    ///
+    /// `entry_path` is the path the entry will be read from, and `metadata`
+    /// is the metadata the header was generated from. Changes made to
+    /// `header` will be reflected in the archive, including on continuation
+    /// volumes.
+    fn filter_entry(&self, header: &mut TarHeader, entry_path: &path::Path, metadata: &fs::Metadata) -> EntryAction;
+
+    /// Inspect the header of an entry whose metadata was captured from an
+    /// open file.
+    ///
+    /// `file` is the file that `metadata` was read from, and that the entry
+    /// will be archived from; `entry_path` may no longer name it. Filters
+    /// which read more of an entry's metadata should read it from `file`.
+    /// By default, this is `filter_entry`.
+    fn filter_open_entry(&self, header: &mut TarHeader, entry_path: &path::Path, metadata: &fs::Metadata, _file: &fs::File) -> EntryAction {
+        self.filter_entry(header, entry_path, metadata)
+    }
+}
+
+impl<F> EntryFilter for F where F: Fn(&mut TarHeader, &path::Path, &fs::Metadata) -> EntryAction + Send + Sync {
+    fn filter_entry(&self, header: &mut TarHeader, entry_path: &path::Path, metadata: &fs::Metadata) -> EntryAction {
+        self(header, entry_path, metadata)
+    }
+}
+
+/// An ordered list of entry filters.
+///
+/// Each filter sees the header as altered by the filters before it. The first
+/// filter to skip or prune an entry ends filtering for that entry.
+pub struct EntryFilterChain {
+    filters: Vec<Box<dyn EntryFilter>>
+}
+
+impl EntryFilterChain {
+    pub fn new() -> EntryFilterChain {
+        EntryFilterChain {
+            filters: Vec::new()
+        }
+    }
+
+    /// Add a filter onto the end of the chain.
+    pub fn push<F: 'static + EntryFilter>(&mut self, filter: F) {
+        self.filters.push(Box::new(filter));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+}
+
+impl Default for EntryFilterChain {
+    fn default() -> Self {
+        EntryFilterChain::new()
+    }
+}
+
+impl EntryFilter for EntryFilterChain {
+    fn filter_entry(&self, header: &mut TarHeader, entry_path: &path::Path, metadata: &fs::Metadata) -> EntryAction {
+        for filter in self.filters.iter() {
+            match filter.filter_entry(header, entry_path, metadata) {
+                EntryAction::Keep => continue,
+                action => return action
+            }
+        }
+
+        EntryAction::Keep
+    }
+
+    fn filter_open_entry(&self, header: &mut TarHeader, entry_path: &path::Path, metadata: &fs::Metadata, file: &fs::File) -> EntryAction {
+        for filter in self.filters.iter() {
+            match filter.filter_open_entry(header, entry_path, metadata, file) {
+                EntryAction::Keep => continue,
+                action => return action
+            }
+        }
+
+        EntryAction::Keep
+    }
+}
+
+/// A UNIX permission mode, written in octal.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct OctalMode(pub u32);
+
+impl FromStr for OctalMode {
+    type Err = num::ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(OctalMode(u32::from_str_radix(s, 8)? & 0o7777))
+    }
+}
+
+/// A filter which replaces or masks the permissions of every member.
+///
+/// The permissions we record are fabricated on platforms without UNIX modes,
+/// so it's often better to state them outright. `mode` replaces the
+/// permission bits of every member; then any bits set in `mask` are cleared,
+/// in the same manner as a umask. File type bits are left alone.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ModeOverride {
+    pub mode: Option<u32>,
+    pub mask: Option<u32>,
+}
+
+impl ModeOverride {
+    pub fn is_empty(&self) -> bool {
+        self.mode.is_none() && self.mask.is_none()
+    }
+}
+
+impl EntryFilter for ModeOverride {
+    fn filter_entry(&self, header: &mut TarHeader, _entry_path: &path::Path, _metadata: &fs::Metadata) -> EntryAction {
+        if let Some(mode) = self.mode {
+            header.unix_mode = (header.unix_mode & !0o7777) | (mode & 0o7777);
+        }
+
+        if let Some(mask) = self.mask {
+            header.unix_mode &= !(mask & 0o7777);
+        }
+
+        EntryAction::Keep
+    }
+}
+
+/// A path style is also a filter, which records every member's drive letter
+/// in that style.
+impl EntryFilter for PathStyle {
+    fn filter_entry(&self, header: &mut TarHeader, _entry_path: &path::Path, _metadata: &fs::Metadata) -> EntryAction {
+        header.path_style = *self;
+
+        EntryAction::Keep
+    }
+}
+
+/// A filter which places every member within a virtual directory.
+///
+/// The directory itself is not archived; extracting the archive creates it
+/// implicitly. Root directories and drive prefixes are dropped from member
+/// paths so that they land inside the directory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathPrefix {
+    prefix: path::PathBuf
+}
+
+impl PathPrefix {
+    pub fn new<P: AsRef<path::Path>>(prefix: P) -> PathPrefix {
+        PathPrefix {
+            prefix: prefix.as_ref().to_path_buf()
+        }
+    }
+}
+
+impl EntryFilter for PathPrefix {
+    fn filter_entry(&self, header: &mut TarHeader, _entry_path: &path::Path, _metadata: &fs::Metadata) -> EntryAction {
+        let mut prefixed = self.prefix.clone();
+
+        for component in header.path.components() {
+            match component {
+                path::Component::Normal(_) | path::Component::ParentDir => prefixed.push(component),
+                _ => {}
+            }
+        }
+
+        header.path = Box::new(prefixed);
+
+        EntryAction::Keep
+    }
+}
+
+/// How much of a directory containing a tag file `ExcludeTag` leaves out.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum TagScope {
+    /// Leave out the directory's contents, except for the tag file itself.
+    Contents,
+
+    /// Leave out all of the directory's contents, including the tag file.
+    Under,
+
+    /// Leave out the directory, and everything within it.
+    All,
+}
+
+/// A filter which excludes directories flagged by a tag file, in the manner of
+/// GNU tar's `--exclude-tag` family of options.
+///
+/// Each entry is checked for a tag file in it's parent directory (or, for
+/// `TagScope::All`, within the entry itself). This costs an extra lookup for
+/// every entry, so the filter should only be used when asked for.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExcludeTag {
+    tag: ffi::OsString,
+    scope: TagScope,
+}
+
+impl ExcludeTag {
+    pub fn new<S: AsRef<ffi::OsStr>>(tag: S, scope: TagScope) -> ExcludeTag {
+        ExcludeTag {
+            tag: tag.as_ref().to_os_string(),
+            scope: scope
+        }
+    }
+
+    fn is_tagged(&self, dir: &path::Path) -> bool {
+        fs::symlink_metadata(dir.join(&self.tag)).is_ok()
+    }
+}
+
+impl EntryFilter for ExcludeTag {
+    fn filter_entry(&self, _header: &mut TarHeader, entry_path: &path::Path, metadata: &fs::Metadata) -> EntryAction {
+        if self.scope == TagScope::All {
+            return match metadata.is_dir() && self.is_tagged(entry_path) {
+                true => EntryAction::Prune,
+                false => EntryAction::Keep
+            };
+        }
+
+        let parent = match entry_path.parent() {
+            Some(parent) => parent,
+            None => return EntryAction::Keep
+        };
+
+        if !self.is_tagged(parent) {
+            return EntryAction::Keep;
+        }
+
+        match (self.scope, entry_path.file_name()) {
+            (TagScope::Contents, Some(name)) if name == self.tag.as_os_str() => EntryAction::Keep,
+            _ => EntryAction::Prune
+        }
+    }
+}
+
+/// A filter which records the project ID each file is accounted to, for
+/// fileservers using project quotas.
+///
+/// Reading project IDs costs an extra open for every file, so the filter
+/// should only be used when asked for. Files whose project ID can't be read
+/// are archived without one.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RecordProjectId;
+
+impl EntryFilter for RecordProjectId {
+    fn filter_entry(&self, header: &mut TarHeader, entry_path: &path::Path, metadata: &fs::Metadata) -> EntryAction {
+        record_project_id(header, entry_path, get_project_id(metadata, entry_path))
+    }
+
+    fn filter_open_entry(&self, header: &mut TarHeader, entry_path: &path::Path, metadata: &fs::Metadata, file: &fs::File) -> EntryAction {
+        record_project_id(header, entry_path, get_project_id_of_file(metadata, file))
+    }
+}
+
+fn record_project_id(header: &mut TarHeader, entry_path: &path::Path, project_id: io::Result<Option<u32>>) -> EntryAction {
+    match project_id {
+        Ok(project_id) => header.project_id = project_id,
+        Err(e) => warn!(path:? = entry_path; "Could not read project ID: {}", e)
+    }
+
+    EntryAction::Keep
+}
+
+/// A filter which skips files that haven't changed since they were archived,
+/// for updating an archive in the manner of `tar -u`.
+///
+/// Each member of the archive being updated is noted with `add_member`. A
+/// file is skipped if it's member was archived with the same path, and
+/// `TarHeader::changed_since` says it hasn't changed since the later of the
+/// member's modification and change times. Directories which are skipped are
+/// still traversed, so that changed files within them are found.
+///
+/// Paths are compared as they would be archived, so this filter should come
+/// after any filter which renames members.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SkipUnchanged {
+    archived: HashMap<path::PathBuf, time::SystemTime>
+}
+
+impl SkipUnchanged {
+    pub fn new() -> SkipUnchanged {
+        SkipUnchanged::default()
+    }
+
+    /// Note a member of the archive being updated.
+    ///
+    /// Times without a fractional part may have been truncated by the tar
+    /// format, so they're taken to cover the whole second. Members archived
+    /// more than once keep their latest time.
+    pub fn add_member(&mut self, header: &TarHeader) {
+        let archived = match header.mtime.into_iter().chain(header.ctime).max() {
+            Some(time) => time,
+            None => return
+        };
+        let archived = match archived.duration_since(time::UNIX_EPOCH) {
+            Ok(since) if since.subsec_nanos() == 0 => archived + time::Duration::new(0, 999_999_999),
+            _ => archived
+        };
+
+        let latest = self.archived.entry(header.path.as_ref().clone()).or_insert(archived);
+
+        if *latest < archived {
+            *latest = archived;
+        }
+    }
+
+    /// How many distinct paths have been noted.
+    pub fn len(&self) -> usize {
+        self.archived.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.archived.is_empty()
+    }
+}
+
+impl EntryFilter for SkipUnchanged {
+    fn filter_entry(&self, header: &mut TarHeader, _entry_path: &path::Path, _metadata: &fs::Metadata) -> EntryAction {
+        match self.archived.get(header.path.as_ref()) {
+            Some(archived) if !header.changed_since(*archived) => EntryAction::Skip,
+            _ => EntryAction::Keep
+        }
+    }
+}
+
+/// Match text against a shell wildcard pattern.
+///
+/// `*` matches any run of characters and `?` any single character, slashes
+/// included. Brackets match one character from a set, such as `[a-z]`, or
+/// from outside of it if the set starts with `!` or `^`. A backslash matches
+/// the character after it literally.
+fn wildcard_match(pattern: &[char], text: &[char]) -> bool {
+    let mut p = 0;
+    let mut t = 0;
+    let mut backtrack = None;
+
+    while t < text.len() {
+        let step = match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+                continue;
+            },
+            Some('?') => Some(1),
+            Some('[') => match_bracket(&pattern[p..], text[t]),
+            Some('\\') if p + 1 < pattern.len() => match pattern[p + 1] == text[t] {
+                true => Some(2),
+                false => None
+            },
+            Some(c) if *c == text[t] => Some(1),
+            _ => None
+        };
+
+        match (step, backtrack) {
+            (Some(length), _) => {
+                p += length;
+                t += 1;
+            },
+            (None, Some((star, start))) => {
+                p = star + 1;
+                t = start + 1;
+                backtrack = Some((star, start + 1));
+            },
+            (None, None) => return false
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Match a character against the bracket expression at the start of a
+/// pattern, yielding the length of the expression if it matched.
+///
+/// Unterminated brackets are matched as a literal `[`.
+fn match_bracket(pattern: &[char], c: char) -> Option<usize> {
+    let negated = pattern.get(1) == Some(&'!') || pattern.get(1) == Some(&'^');
+    let mut i = if negated { 2 } else { 1 };
+    let mut matched = false;
+    let mut first = true;
+
+    loop {
+        match pattern.get(i) {
+            None => return match c == '[' {
+                true => Some(1),
+                false => None
+            },
+            Some(']') if !first => break,
+            Some(low) => {
+                match (pattern.get(i + 1), pattern.get(i + 2)) {
+                    (Some('-'), Some(high)) if *high != ']' => {
+                        matched |= *low <= c && c <= *high;
+                        i += 3;
+                    },
+                    _ => {
+                        matched |= *low == c;
+                        i += 1;
+                    }
+                }
+            }
+        }
+
+        first = false;
+    }
+
+    match matched != negated {
+        true => Some(i + 1),
+        false => None
+    }
+}
+
+/// A filter which excludes entries matching a shell wildcard pattern, in the
+/// manner of GNU tar's `--exclude`.
+///
+/// The pattern is matched against the member's path, and against every
+/// trailing part of it that starts at a path component; so `*.o` excludes
+/// object files anywhere, and `build` any file or directory named build.
+/// Matching directories are left out along with their contents.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExcludePattern {
+    pattern: Vec<char>
+}
+
+impl ExcludePattern {
+    pub fn new<S: AsRef<str>>(pattern: S) -> ExcludePattern {
+        ExcludePattern {
+            pattern: pattern.as_ref().trim_end_matches('/').chars().collect()
+        }
+    }
+
+    /// Determine if the pattern excludes a member path.
+    pub fn is_match(&self, archival_path: &path::Path) -> bool {
+        let name : Vec<char> = archival_path.to_string_lossy().trim_end_matches('/').chars().collect();
+
+        (0..name.len()).filter(|&i| i == 0 || name[i - 1] == '/').any(|i| wildcard_match(&self.pattern, &name[i..]))
+    }
+}
+
+impl EntryFilter for ExcludePattern {
+    fn filter_entry(&self, header: &mut TarHeader, _entry_path: &path::Path, _metadata: &fs::Metadata) -> EntryAction {
+        match self.is_match(&header.path) {
+            true => EntryAction::Prune,
+            false => EntryAction::Keep
+        }
+    }
+}
+
+/// Generate a header for a traversed entry, subject to a filter.
+///
+/// This is intended to be called from within the callback given to
+/// `traverse::traverse`, with the same paths and metadata it was given. Owner
+/// and group names are taken from `pool`, which should be shared by every
+/// worker generating headers for the same archive.
+///
+/// # Returns
+///
+/// Yields the generated header if the entry was kept, or `None` if it was
+/// skipped. Pruned entries yield `TraversalError::Excluded`, which, if
+/// returned from the traversal callback, stops traversal of the entry's
+/// contents.
+///
+/// # Capturing metadata from open files
+///
+/// Traversal stats entries by path, and serialization opens them by path
+/// again later. If a file is replaced in between, as can happen in
+/// directories writable by others, the archive could pair one file's
+/// metadata with another file's contents.
+///
+/// If `from_handle` is set, regular files are opened without following
+/// symbolic links before their headers are generated. The header is generated
+/// from the open file's metadata, filters are given the open file (see
+/// `EntryFilter::filter_open_entry`), and the file is held open so that it's
+/// contents are serialized from it as well. Files which turn out to be
+/// something else once opened are not archived. Directories, links, and
+/// other entries are still described by the metadata traversal found. On
+/// platforms which look up ownership and permissions by path, those are not
+/// captured from the open file either.
+pub fn filtered_headergen<F: EntryFilter + ?Sized>(entry_path: &path::Path, archival_path: &path::Path, metadata: &fs::Metadata, format: TarFormat, atime: AtimePolicy, readahead_limit: u64, from_handle: bool, filter: &F, pool: &StringPool, cancel: &CancellationToken) -> traverse::Result<Option<HeaderGenResult>> {
+    let (source, handle_metadata) = match from_handle && metadata.is_file() {
+        true => {
+            let (source, handle_metadata) = ArchivalFile::open_nofollow(entry_path, atime)?;
+
+            if !handle_metadata.is_file() {
+                return Err(io::Error::new(io::ErrorKind::Other, "File was replaced with something other than a file before it could be archived").into());
+            }
+
+            (Some(source), Some(handle_metadata))
+        },
+        false => (None, None)
+    };
+    let metadata = handle_metadata.as_ref().unwrap_or(metadata);
+    let mut tarheader = TarHeader::with_pool(archival_path, metadata, entry_path, pool)?;
+    let action = match source {
+        Some(ref source) => filter.filter_open_entry(&mut tarheader, entry_path, metadata, source.file()),
+        None => filter.filter_entry(&mut tarheader, entry_path, metadata)
+    };
+
+    match action {
+        EntryAction::Keep => {
+            let mut hg = headergen_from_source(entry_path, archival_path, tarheader, format, atime, readahead_limit, source, cancel)?;
+
+            hg.link_identity = hard_link_identity(metadata, entry_path);
+
+            Ok(Some(hg))
+        },
+        EntryAction::Skip => Ok(None),
+        EntryAction::Prune => Err(traverse::TraversalError::Excluded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path, time};
+    use std::io::Read;
+    use crate::traverse::TraversalError;
+    use crate::fs::AtimePolicy;
+    use crate::cancel::CancellationToken;
+    use crate::intern::StringPool;
+    use crate::tar::header::{TarFormat, TarHeader, TarFileType};
+    use super::{EntryAction, EntryFilter, EntryFilterChain, ModeOverride, OctalMode, PathPrefix, ExcludeTag, ExcludePattern, SkipUnchanged, TagScope, filtered_headergen};
+
+    #[test]
+    fn entry_filter_chain() {
+        let dir = env::temp_dir();
+        let metadata = fs::symlink_metadata(&dir).unwrap();
+        let mut chain = EntryFilterChain::new();
+
+        chain.push(|header: &mut TarHeader, _: &path::Path, _: &fs::Metadata| {
+            header.unix_uname = "archivist".into();
+            EntryAction::Keep
+        });
+        chain.push(|header: &mut TarHeader, _: &path::Path, _: &fs::Metadata| {
+            match header.unix_uname.as_ref() {
+                "archivist" => EntryAction::Keep,
+                _ => EntryAction::Prune
+            }
+        });
+
+        let hg = filtered_headergen(&dir, path::Path::new("tmp"), &metadata, TarFormat::POSIX, AtimePolicy::Ignore, 0, false, &chain, &StringPool::new(), &CancellationToken::new()).unwrap().unwrap();
+        assert_eq!(&*hg.tar_header.unix_uname, "archivist");
+
+        chain.push(|_: &mut TarHeader, _: &path::Path, _: &fs::Metadata| EntryAction::Prune);
+
+        match filtered_headergen(&dir, path::Path::new("tmp"), &metadata, TarFormat::POSIX, AtimePolicy::Ignore, 0, false, &chain, &StringPool::new(), &CancellationToken::new()) {
+            Err(TraversalError::Excluded) => {},
+            _ => panic!("Entry should have been pruned")
+        }
+    }
+
+    #[test]
+    fn headergen_from_handle() {
+        let dir = env::temp_dir().join(format!("rapidtar-from-handle-{}", std::process::id()));
+        let file = dir.join("file");
+
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(&file, b"original").unwrap();
+
+        //The file is replaced between being statted and being opened.
+        let metadata = fs::symlink_metadata(&file).unwrap();
+        fs::write(dir.join("replacement"), b"replaced contents").unwrap();
+        fs::rename(dir.join("replacement"), &file).unwrap();
+
+        let hg = filtered_headergen(&file, path::Path::new("file"), &metadata, TarFormat::POSIX, AtimePolicy::Ignore, 0, true, &EntryFilterChain::new(), &StringPool::new(), &CancellationToken::new()).unwrap().unwrap();
+        assert_eq!(hg.tar_header.file_size, 17);
+
+        let mut contents = String::new();
+        hg.open_source().unwrap().read_to_string(&mut contents).unwrap();
+        assert_eq!(contents, "replaced contents");
+        assert!(hg.source.lock().unwrap().is_none());
+
+        //Files which aren't read ahead completely stay open.
+        let hg = filtered_headergen(&file, path::Path::new("file"), &metadata, TarFormat::POSIX, AtimePolicy::Ignore, 8, true, &EntryFilterChain::new(), &StringPool::new(), &CancellationToken::new()).unwrap().unwrap();
+        assert_eq!(hg.file_prefix.as_ref().map(|prefix| prefix.len()), Some(8));
+        assert!(hg.source.lock().unwrap().is_some());
+
+        let hg = filtered_headergen(&file, path::Path::new("file"), &metadata, TarFormat::POSIX, AtimePolicy::Ignore, 64, true, &EntryFilterChain::new(), &StringPool::new(), &CancellationToken::new()).unwrap().unwrap();
+        assert!(hg.source.lock().unwrap().is_none());
+
+        #[cfg(unix)]
+        {
+            fs::remove_file(&file).unwrap();
+            std::os::unix::fs::symlink(dir.join("elsewhere"), &file).unwrap();
+
+            assert!(filtered_headergen(&file, path::Path::new("file"), &metadata, TarFormat::POSIX, AtimePolicy::Ignore, 0, true, &EntryFilterChain::new(), &StringPool::new(), &CancellationToken::new()).is_err());
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn mode_override() {
+        let dir = env::temp_dir();
+        let metadata = fs::symlink_metadata(&dir).unwrap();
+        let mut header = TarHeader::for_test("tmp", TarFileType::FileStream, 0);
+
+        assert_eq!("0750".parse::<OctalMode>().unwrap(), OctalMode(0o750));
+        assert!("0790".parse::<OctalMode>().is_err());
+
+        header.unix_mode = 0o100644;
+        ModeOverride { mode: Some(0o755), mask: None }.filter_entry(&mut header, &dir, &metadata);
+        assert_eq!(header.unix_mode, 0o100755);
+
+        ModeOverride { mode: None, mask: Some(0o027) }.filter_entry(&mut header, &dir, &metadata);
+        assert_eq!(header.unix_mode, 0o100750);
+    }
+
+    #[test]
+    fn path_prefix() {
+        let dir = env::temp_dir();
+        let metadata = fs::symlink_metadata(&dir).unwrap();
+        let mut header = TarHeader::for_test("src/lib.rs", TarFileType::FileStream, 0);
+
+        PathPrefix::new("project-1.0/").filter_entry(&mut header, &dir, &metadata);
+        assert!(header.path.as_path() == path::Path::new("project-1.0/src/lib.rs"));
+
+        header.path = Box::new(path::PathBuf::from("/abs/./file"));
+        PathPrefix::new("layer").filter_entry(&mut header, &dir, &metadata);
+        assert!(header.path.as_path() == path::Path::new("layer/abs/file"));
+    }
+
+    #[test]
+    fn exclude_tag() {
+        let dir = env::temp_dir().join(format!("rapidtar-exclude-tag-{}", std::process::id()));
+        let tagged = dir.join("cache");
+
+        fs::create_dir_all(&tagged).unwrap();
+        fs::write(tagged.join("CACHEDIR.TAG"), b"").unwrap();
+        fs::write(tagged.join("data"), b"").unwrap();
+
+        let action = |scope: TagScope, entry: &path::Path| {
+            let metadata = fs::symlink_metadata(entry).unwrap();
+            let mut header = TarHeader::abstract_header_for_file(entry, &metadata, entry).unwrap();
+
+            ExcludeTag::new("CACHEDIR.TAG", scope).filter_entry(&mut header, entry, &metadata)
+        };
+
+        assert_eq!(action(TagScope::Contents, &tagged), EntryAction::Keep);
+        assert_eq!(action(TagScope::Contents, &tagged.join("CACHEDIR.TAG")), EntryAction::Keep);
+        assert_eq!(action(TagScope::Contents, &tagged.join("data")), EntryAction::Prune);
+        assert_eq!(action(TagScope::Under, &tagged), EntryAction::Keep);
+        assert_eq!(action(TagScope::Under, &tagged.join("CACHEDIR.TAG")), EntryAction::Prune);
+        assert_eq!(action(TagScope::All, &tagged), EntryAction::Prune);
+        assert_eq!(action(TagScope::All, &dir), EntryAction::Keep);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn skip_unchanged() {
+        let dir = env::temp_dir();
+        let metadata = fs::symlink_metadata(&dir).unwrap();
+        let mut header = TarHeader::for_test("file", TarFileType::FileStream, 0);
+        let mtime = time::UNIX_EPOCH + time::Duration::new(1_500_000_000, 250_000_000);
+        let mut skip = SkipUnchanged::new();
+
+        header.mtime = Some(time::UNIX_EPOCH + time::Duration::from_secs(1_500_000_000));
+        skip.add_member(&header);
+        assert_eq!(skip.len(), 1);
+
+        //A whole-second time covers the rest of it's second...
+        header.mtime = Some(mtime);
+        assert_eq!(skip.filter_entry(&mut header.clone(), &dir, &metadata), EntryAction::Skip);
+
+        //...but not the next one.
+        header.mtime = Some(mtime + time::Duration::from_secs(1));
+        assert_eq!(skip.filter_entry(&mut header.clone(), &dir, &metadata), EntryAction::Keep);
+
+        header.path = Box::new(path::PathBuf::from("other"));
+        header.mtime = Some(time::UNIX_EPOCH);
+        assert_eq!(skip.filter_entry(&mut header.clone(), &dir, &metadata), EntryAction::Keep);
+    }
+
+    #[test]
+    fn exclude_pattern() {
+        let matches = |pattern: &str, name: &str| ExcludePattern::new(pattern).is_match(path::Path::new(name));
+
+        assert!(matches("*.o", "src/main.o"));
+        assert!(matches("build", "project/build"));
+        assert!(matches("build/", "project/build"));
+        assert!(!matches("build", "project/builder"));
+        assert!(matches("project/b?ild", "project/build"));
+        assert!(matches("src/*", "a/src/b/c.rs"));
+        assert!(matches("[a-c]*.log", "var/b1.log"));
+        assert!(!matches("[!a-c]*.log", "b1.log"));
+        assert!(matches("file\\*", "file*"));
+        assert!(!matches("file\\*", "files"));
+        assert!(matches("[x", "[x"));
+    }
+}
@@ -1,10 +1,33 @@
-//! Support for GNU extensions to the tar header format.
+//! Support for GNU extensions to the tar header format, and the GNU format
+//! itself.
+//!
+//! GNU format headers look like USTAR headers with a slightly different magic
+//! number, but don't split long names between the name and prefix fields.
+//! Instead, names and link targets too long for their fields are written out
+//! in full as the data of a `././@LongLink` pseudo-entry (type `L` for names,
+//! `K` for link targets) immediately before the member they belong to.
+//! Numerals too large for octal are written in base-256, and times before the
+//! UNIX epoch as negative base-256 numerals.
+//!
+//! Sparse files are written as old GNU sparse members (type `S`). The first
+//! four extents of their sparse map are kept in the header; any more follow
+//! it in extension blocks of 21 extents each. See `tar::sparse`.
 
 use std::{io, time, fmt};
 use pad::{PadStr, Alignment};
 use num;
 use num::ToPrimitive;
 use num_traits;
+use crate::tar::ustar::{format_tar_numeral, checksum_header as checksum_block};
+use crate::tar::header::{TarHeader, TarFileType};
+use crate::tar::sparse::{self, SparseExtent};
+use crate::tar::{canonicalized_tar_path, canonicalized_link_target};
+
+/// The name GNU tar gives to long name and long link pseudo-entries.
+const LONG_LINK_NAME: &[u8] = b"././@LongLink";
+
+/// The magic and version fields of a GNU format header.
+const GNU_MAGIC: &[u8] = b"ustar  \0";
 
 /* Fun fact: This is how GNU tar generates multivolume headers:
 
@@ -38,12 +61,16 @@ use num_traits;
 /// In the event that the number cannot be represented in even this form, the
 /// function yields None.
 pub fn format_gnu_numeral<N: num::Integer>(number: N, field_size: usize) -> Option<Vec<u8>> where N: fmt::Octal + num::traits::CheckedShr + std::ops::BitAnd + num_traits::cast::ToPrimitive + From<u8>, <N as std::ops::BitAnd>::Output: num_traits::cast::ToPrimitive {
-    let numsize = number.to_f32()?.log(8.0);
-    let gnusize = number.to_f32()?.log(256.0);
-    
-    if gnusize >= (field_size as f32 - 1.0) {
+    //Compared exactly, since floating-point logarithms round numbers just
+    //under a field's limit up to it.
+    let fits = |bits: u32| match number.to_u64() {
+        Some(n) => n.checked_shr(bits).unwrap_or(0) == 0,
+        None => false
+    };
+
+    if !fits((field_size as u32 - 1) * 8) {
         None
-    } else if numsize >= (field_size as f32 - 1.0) {
+    } else if !fits((field_size as u32 - 1) * 3) {
         let mut result : Vec<u8> = vec![0; field_size];
         
         result[0] = 0x80;
@@ -65,16 +92,268 @@ pub fn format_gnu_numeral<N: num::Integer>(number: N, field_size: usize) -> Opti
     }
 }
 
+/// Parse a number in GNU/STAR octal/integer hybrid format.
+/// 
+/// Fields whose first byte has the high bit set are base-256; anything else is
+/// parsed as a tar octal numeral. Negative base-256 values, and values too
+/// large for a `u64`, yield None.
+pub fn parse_gnu_numeral(field: &[u8]) -> Option<u64> {
+    match field.first() {
+        Some(0xFF) => None,
+        Some(first) if first & 0x80 != 0 => {
+            let mut value = (first & 0x7F) as u64;
+
+            for byte in field[1..].iter() {
+                value = value.checked_mul(256)?.checked_add(*byte as u64)?;
+            }
+
+            Some(value)
+        },
+        _ => crate::tar::ustar::parse_tar_numeral(field)
+    }
+}
+
+/// Format a time in GNU format, for fields which can't hold negative times.
+///
+/// Times before the UNIX epoch yield an error. Headers which can carry them
+/// elsewhere, such as pax `mtime` records, should fall back to doing so;
+/// otherwise, use `format_gnu_signed_time`.
 pub fn format_gnu_time(dirtime: &time::SystemTime) -> io::Result<Vec<u8>> {
     match dirtime.duration_since(time::UNIX_EPOCH) {
         Ok(unix_duration) => format_gnu_numeral(unix_duration.as_secs(), 12).ok_or(io::Error::new(io::ErrorKind::InvalidData, "Tar numeral too large")),
-        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "File older than UNIX")) //TODO: Negative time
+        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "Time is before the UNIX epoch"))
+    }
+}
+
+/// Format a time in GNU format, including times before the UNIX epoch.
+///
+/// Times before the epoch are rounded down to the second, then written as a
+/// base-256 numeral in two's complement, which GNU tar marks with a leading
+/// 0xFF byte.
+pub fn format_gnu_signed_time(dirtime: &time::SystemTime, field_size: usize) -> io::Result<Vec<u8>> {
+    match dirtime.duration_since(time::UNIX_EPOCH) {
+        Ok(unix_duration) => format_gnu_numeral(unix_duration.as_secs(), field_size).ok_or(io::Error::new(io::ErrorKind::InvalidData, "Tar numeral too large")),
+        Err(e) => {
+            let before = e.duration();
+            let seconds = before.as_secs().checked_add(if before.subsec_nanos() > 0 { 1 } else { 0 }).filter(|s| *s <= i64::MAX as u64).ok_or(io::Error::new(io::ErrorKind::InvalidData, "Tar numeral too large"))?;
+            let value = (-(seconds as i64)).to_be_bytes();
+            let mut result = vec![0xFF; field_size];
+
+            result[field_size - value.len()..].copy_from_slice(&value);
+
+            Ok(result)
+        }
+    }
+}
+
+/// Parse a time in GNU format, as written by `format_gnu_signed_time`.
+///
+/// Yields None if the field isn't a valid numeral or the time can't be
+/// represented.
+pub fn parse_gnu_time(field: &[u8]) -> Option<time::SystemTime> {
+    match field.first() {
+        Some(0xFF) => {
+            //Only the low eight bytes can be significant; the rest must be
+            //sign extension.
+            let split = field.len().checked_sub(8)?;
+
+            if field[..split].iter().any(|b| *b != 0xFF) || field[split] & 0x80 == 0 {
+                return None;
+            }
+
+            let mut value = [0; 8];
+            value.copy_from_slice(&field[split..]);
+
+            time::UNIX_EPOCH.checked_sub(time::Duration::from_secs(i64::from_be_bytes(value).checked_neg()? as u64))
+        },
+        _ => time::UNIX_EPOCH.checked_add(time::Duration::from_secs(parse_gnu_numeral(field)?))
+    }
+}
+
+/// Copy a string into a header field, truncating it if it doesn't fit.
+///
+/// GNU tar always leaves room for a terminating null.
+fn format_gnu_string(bytes: &[u8], field_size: usize) -> Vec<u8> {
+    let mut result = bytes[..bytes.len().min(field_size - 1)].to_vec();
+
+    result.resize(field_size, 0);
+
+    result
+}
+
+/// Format the extents of a sparse map into an old GNU sparse map field, which
+/// is followed by a flag saying whether more extents follow.
+///
+/// Unused slots of the field are left zeroed.
+fn format_gnu_sparse_extents(extents: &[SparseExtent], slots: usize, more: bool) -> io::Result<Vec<u8>> {
+    let mut field = Vec::with_capacity(slots * 24 + 1);
+
+    for extent in extents {
+        field.extend(format_gnu_numeral(extent.offset, 12).ok_or(io::Error::new(io::ErrorKind::InvalidData, "Sparse extent offset is too large"))?);
+        field.extend(format_gnu_numeral(extent.length, 12).ok_or(io::Error::new(io::ErrorKind::InvalidData, "Sparse extent is too large"))?);
+    }
+
+    field.resize(slots * 24, 0);
+    field.push(more as u8);
+
+    Ok(field)
+}
+
+/// Parse the extents out of an old GNU sparse map field.
+///
+/// Slots are used in order, so the first empty one ends the map.
+pub fn parse_gnu_sparse_extents(field: &[u8]) -> io::Result<Vec<SparseExtent>> {
+    field.chunks_exact(24).take_while(|slot| slot[0] != 0).map(|slot| {
+        match (parse_gnu_numeral(&slot[0..12]), parse_gnu_numeral(&slot[12..24])) {
+            (Some(offset), Some(length)) => Ok(SparseExtent {
+                offset: offset,
+                length: length
+            }),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed sparse map"))
+        }
+    }).collect()
+}
+
+/// Generate a long name or long link pseudo-entry.
+///
+/// The pseudo-entry is returned with it's header checksummed and it's data
+/// padded out to a whole block.
+fn gnu_long_link(type_flag: u8, name: &[u8]) -> io::Result<Vec<u8>> {
+    let mut header : Vec<u8> = Vec::with_capacity(1024);
+
+    header.extend(format_gnu_string(LONG_LINK_NAME, 100));
+    header.extend(format_tar_numeral(0, 8).unwrap_or(vec![0; 8])); //mode
+    header.extend(format_tar_numeral(0, 8).unwrap_or(vec![0; 8])); //uid
+    header.extend(format_tar_numeral(0, 8).unwrap_or(vec![0; 8])); //gid
+    header.extend(format_gnu_numeral(name.len() as u64 + 1, 12).ok_or(io::Error::new(io::ErrorKind::InvalidData, "File name is too long"))?); //File size, including the null
+    header.extend(format_tar_numeral(0, 12).unwrap_or(vec![0; 12])); //mtime
+    header.extend("        ".as_bytes()); //checksummable format checksum value
+    header.push(type_flag);
+    header.extend(vec![0; 100]); //Link name
+    header.extend(GNU_MAGIC);
+    header.extend(vec![0; 512 - header.len()]);
+
+    checksum_block(&mut header[0..512]);
+
+    header.extend(name);
+    header.push(0);
+
+    let padding_needed = header.len() % 512;
+    if padding_needed != 0 {
+        header.extend(vec![0; 512 - padding_needed]);
+    }
+
+    Ok(header)
+}
+
+/// Given an abstract tar header, form a GNU format header for it.
+///
+/// Names and link targets of 100 bytes or more are preceded by long name or
+/// long link pseudo-entries, and truncated in the header itself. Names are
+/// written as UTF-8 with forward slashes separating path components on all
+/// platforms; symbolic link targets are written exactly as they are. Owner and group names too long for their fields are truncated,
+/// as GNU tar does.
+///
+/// Files with a sparse map are written as old GNU sparse members, with any
+/// extension blocks their maps need following the header.
+///
+/// As with `ustar_header`, the header is returned in checksummable format;
+/// `checksum_header` should be used to complete it. Any pseudo-entries are
+/// already checksummed.
+pub fn gnu_header(tarheader: &TarHeader) -> io::Result<Vec<u8>> {
+    let canonical_path = canonicalized_tar_path(&tarheader.path, tarheader.file_type, tarheader.path_style);
+    let name = canonical_path.as_bytes();
+    let linkname = tarheader.symlink_path.as_ref().map(|target| canonicalized_link_target(target, tarheader.file_type, tarheader.path_style)).unwrap_or_default();
+    let mut header : Vec<u8> = Vec::with_capacity(512);
+
+    if name.len() >= 100 {
+        header.extend(gnu_long_link(b'L', name)?);
+    }
+
+    if linkname.len() >= 100 {
+        header.extend(gnu_long_link(b'K', linkname.as_bytes())?);
+    }
+
+    header.extend(format_gnu_string(name, 100)); //First 99 bytes of path
+    header.extend(format_tar_numeral(tarheader.unix_mode, 8).ok_or(io::Error::new(io::ErrorKind::InvalidData, "UNIX mode is too long"))?); //mode
+    header.extend(format_gnu_numeral(tarheader.unix_uid, 8).ok_or(io::Error::new(io::ErrorKind::InvalidData, "UID is too large"))?);
+    header.extend(format_gnu_numeral(tarheader.unix_gid, 8).ok_or(io::Error::new(io::ErrorKind::InvalidData, "GID is too large"))?);
+    let sparse_map = match tarheader.file_type {
+        TarFileType::FileStream => tarheader.sparse_map.as_ref(),
+        _ => None
+    };
+    match (tarheader.file_type, sparse_map) {
+        (TarFileType::FileStream, Some(map)) => header.extend(format_gnu_numeral(sparse::data_size(map), 12).ok_or(io::Error::new(io::ErrorKind::InvalidData, "File is too large"))?), //Size of the data extents
+        (TarFileType::FileStream, None) => header.extend(format_gnu_numeral(tarheader.file_size, 12).ok_or(io::Error::new(io::ErrorKind::InvalidData, "File is too large"))?), //File size
+        _ => header.extend(format_gnu_numeral(0, 12).unwrap_or(vec![0; 12])) //Non-file entries must have a size of 0, or 7zip tries to skip them
+    }
+    header.extend(format_gnu_signed_time(&tarheader.mtime.unwrap_or(time::UNIX_EPOCH), 12)?); //mtime
+    header.extend("        ".as_bytes()); //checksummable format checksum value
+    header.push(match sparse_map {
+        Some(_) => b'S',
+        None => tarheader.file_type.type_flag() as u8
+    }); //File type
+    header.extend(format_gnu_string(linkname.as_bytes(), 100)); //First 99 bytes of link name
+    header.extend(GNU_MAGIC);
+    header.extend(format_gnu_string(tarheader.unix_uname.as_bytes(), 32));
+    header.extend(format_gnu_string(tarheader.unix_gname.as_bytes(), 32));
+    header.extend(format_gnu_numeral(tarheader.unix_devmajor, 8).unwrap_or(vec![0; 8])); //Device Major
+    header.extend(format_gnu_numeral(tarheader.unix_devminor, 8).unwrap_or(vec![0; 8])); //Device Minor
+    header.extend(tarheader.atime.and_then(|atime| format_gnu_signed_time(&atime, 12).ok()).unwrap_or(vec![0; 12]));
+    header.extend(tarheader.ctime.and_then(|ctime| format_gnu_signed_time(&ctime, 12).ok()).unwrap_or(vec![0; 12]));
+
+    header.extend(vec![0; 17]); //Multivolume offset and long names
+
+    match sparse_map {
+        Some(map) => {
+            let (first, rest) = map.split_at(map.len().min(4));
+
+            header.extend(format_gnu_sparse_extents(first, 4, !rest.is_empty())?);
+            header.extend(format_gnu_numeral(tarheader.file_size, 12).ok_or(io::Error::new(io::ErrorKind::InvalidData, "File is too large"))?); //Real size
+            header.extend(vec![0; 17]); //Padding
+
+            let mut extensions = rest.chunks(21).peekable();
+
+            while let Some(extents) = extensions.next() {
+                header.extend(format_gnu_sparse_extents(extents, 21, extensions.peek().is_some())?);
+                header.extend(vec![0; 7]); //Padding
+            }
+        },
+        None => header.extend(vec![0; 126]) //Sparse map and padding
+    }
+
+    Ok(header)
+}
+
+/// Given a tar header (GNU format), calculate a valid checksum.
+///
+/// Only the member's own header is checksummed; any long name or long link
+/// pseudo-entries before it were checksummed when they were generated, and
+/// sparse extension blocks after it have no checksum.
+pub fn checksum_header(header: &mut [u8]) {
+    let mut offset = 0;
+
+    while offset + 512 <= header.len() {
+        match header[offset + 156] {
+            b'L' | b'K' if &header[offset..offset + LONG_LINK_NAME.len()] == LONG_LINK_NAME => {
+                let size = parse_gnu_numeral(&header[offset + 124..offset + 136]).unwrap_or(0) as usize;
+
+                offset += 512 + (size + 511) / 512 * 512;
+            },
+            _ => {
+                checksum_block(&mut header[offset..offset + 512]);
+                return;
+            }
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::tar::gnu::{format_gnu_numeral};
+    use std::{io, path, time};
+    use crate::tar::gnu::{format_gnu_numeral, format_gnu_signed_time, parse_gnu_time, gnu_header, checksum_header};
+    use crate::tar::header::{TarHeader, TarFileType};
+    use crate::tar::reader::TarReader;
     
     #[test]
     fn format_gnu_numeral_8() {
@@ -99,4 +378,46 @@ mod tests {
             None => true
         });
     }
-}
\ No newline at end of file
+    
+    #[test]
+    fn gnu_signed_time() {
+        let before = time::UNIX_EPOCH - time::Duration::new(1, 500_000_000);
+        let formatted = format_gnu_signed_time(&before, 12).unwrap();
+
+        assert_eq!(formatted, vec![0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE]);
+        assert_eq!(parse_gnu_time(&formatted), Some(time::UNIX_EPOCH - time::Duration::from_secs(2)));
+
+        let after = time::UNIX_EPOCH + time::Duration::from_secs(0o1234);
+        assert_eq!(parse_gnu_time(&format_gnu_signed_time(&after, 12).unwrap()), Some(after));
+    }
+
+    #[test]
+    fn gnu_header_roundtrip() {
+        let long_name = "long/".repeat(30) + "name";
+        let long_target = "target/".repeat(20);
+        let mut header = TarHeader::for_test(&long_name, TarFileType::SymbolicLink, 0);
+
+        header.symlink_path = Some(Box::new(path::PathBuf::from(&long_target)));
+        header.unix_uid = 0o77777777;
+        header.mtime = Some(time::UNIX_EPOCH - time::Duration::from_secs(86400));
+
+        let mut archive = gnu_header(&header).unwrap();
+        checksum_header(&mut archive);
+
+        assert_eq!(archive.len(), 512 * 5);
+        assert_eq!(&archive[156], &b'L');
+        assert_eq!(&archive[1024 + 156], &b'K');
+        assert_eq!(&archive[2048 + 257..2048 + 265], b"ustar  \0");
+
+        archive.extend(vec![0; 1024]);
+
+        let mut reader = TarReader::new(io::Cursor::new(&archive));
+        let entry = reader.next_entry().unwrap().unwrap();
+
+        assert_eq!(entry.header.path.to_string_lossy(), long_name);
+        assert_eq!(entry.header.symlink_path.unwrap().to_string_lossy(), long_target);
+        assert_eq!(entry.header.unix_uid, 0o77777777);
+        assert_eq!(entry.header.mtime, header.mtime);
+        assert!(reader.next_entry().unwrap().is_none());
+    }
+}
@@ -0,0 +1,110 @@
+//! Archiving hard links as links, rather than as copies of their file.
+//!
+//! When a file with several hard links is archived more than once, only the
+//! first member holds it's data; the rest are hard link members naming the
+//! first. Headers are generated by many traversal workers at once, and which
+//! of them reaches a file first says nothing about which member is written
+//! first, so links are resolved as members are written instead. That way the
+//! member holding the data always comes before any links to it, which
+//! extraction relies on.
+//!
+//! Files are recognized by the `link_identity` their headers were generated
+//! with (see `fs::hard_link_identity`), which is only set for files that have
+//! more than one link to begin with.
+
+use std::path;
+use std::collections::HashMap;
+use crate::fs::FileId;
+use crate::tar::header::{HeaderGenResult, TarFileType, TarFormat};
+
+/// Remembers which member holds each linked file written so far.
+#[derive(Clone, Default)]
+pub struct HardLinkTracker {
+    first_members: HashMap<FileId, Box<path::PathBuf>>,
+}
+
+impl HardLinkTracker {
+    pub fn new() -> HardLinkTracker {
+        HardLinkTracker::default()
+    }
+
+    /// Prepare a member to be written, turning it into a hard link if it's
+    /// file was already written as an earlier member.
+    ///
+    /// This must be called on members in the order they are written. Members
+    /// which can't be encoded as links are written in full, as though they
+    /// weren't linked at all.
+    pub fn track(&mut self, mut entry: HeaderGenResult, format: TarFormat) -> HeaderGenResult {
+        let identity = match (entry.link_identity, entry.tar_header.file_type) {
+            (Some(identity), TarFileType::FileStream) => identity,
+            _ => return entry
+        };
+
+        match self.first_members.get(&identity) {
+            Some(target) => if let Err(e) = entry.link_to(target, format) {
+                warn!(path:? = entry.original_path; "Cannot archive as a hard link to {:?}, archiving a copy instead: {}", target, e);
+            },
+            None => {
+                self.first_members.insert(identity, entry.tar_header.path.clone());
+            }
+        }
+
+        entry
+    }
+
+    /// How many linked files have been written.
+    pub fn len(&self) -> usize {
+        self.first_members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.first_members.is_empty()
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{env, fs, io, path, process};
+    use crate::cancel::CancellationToken;
+    use crate::fs::AtimePolicy;
+    use crate::intern::StringPool;
+    use crate::tar::filter::{EntryFilterChain, filtered_headergen};
+    use crate::tar::header::{TarFormat, TarFileType};
+    use crate::tar::reader::TarReader;
+    use crate::tar::serialize;
+    use super::HardLinkTracker;
+
+    #[test]
+    fn hard_links() {
+        let dir = env::temp_dir().join(format!("rapidtar-hardlink-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("first"), vec![b'x'; 1000]).unwrap();
+        fs::hard_link(dir.join("first"), dir.join("second")).unwrap();
+        fs::write(dir.join("alone"), b"alone").unwrap();
+
+        let mut tracker = HardLinkTracker::new();
+        let mut archive = io::Cursor::new(vec![]);
+
+        for name in ["first", "alone", "second"].iter() {
+            let entry_path = dir.join(name);
+            let metadata = fs::symlink_metadata(&entry_path).unwrap();
+            let hg = filtered_headergen(&entry_path, path::Path::new(name), &metadata, TarFormat::POSIX, AtimePolicy::Ignore, 64 * 1024, false, &EntryFilterChain::new(), &StringPool::new(), &CancellationToken::new()).unwrap().unwrap();
+
+            serialize::<u64>(&tracker.track(hg, TarFormat::POSIX), &mut archive, &mut 0, None, &CancellationToken::new()).unwrap();
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(tracker.len(), 1);
+
+        let mut reader = TarReader::new(io::Cursor::new(archive.into_inner()));
+        let first = reader.next_entry().unwrap().unwrap();
+        let alone = reader.next_entry().unwrap().unwrap();
+        let second = reader.next_entry().unwrap().unwrap();
+
+        assert!(first.header.file_type == TarFileType::FileStream && first.header.file_size == 1000);
+        assert!(alone.header.file_type == TarFileType::FileStream && alone.header.symlink_path.is_none());
+        assert!(second.header.file_type == TarFileType::HardLink && second.header.file_size == 0);
+        assert_eq!(second.header.symlink_path.unwrap().to_str(), Some("first"));
+    }
+}
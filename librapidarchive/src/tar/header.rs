@@ -1,16 +1,21 @@
 //! Abstract representations of tar headers and utilities to generate them.
 
 use std::{path, time, io, cmp, fs};
-use std::io::Read;
+use std::io::{Read, Seek};
 use std::str::FromStr;
-use crate::fs::{get_file_type, get_unix_mode, get_unix_owner, get_unix_group};
+use std::sync::{Arc, Mutex};
+use crate::fs::{get_file_type, get_unix_mode, get_unix_owner, get_unix_group, get_change_time, get_device_numbers, read_link_target, ArchivalFile, AtimePolicy, FileId};
 use crate::{normalize, spanning};
-use crate::tar::{ustar, pax, recovery};
+use crate::tar::{ustar, pax, gnu, recovery, sparse, acl, PathStyle};
+use crate::cancel::CancellationToken;
+use crate::queue::MemoryFootprint;
+use crate::intern::StringPool;
 
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum TarFormat {
     USTAR,
-    POSIX
+    POSIX,
+    GNU
 }
 
 impl FromStr for TarFormat {
@@ -20,6 +25,7 @@ impl FromStr for TarFormat {
         match s.as_ref() {
             "ustar" => Ok(TarFormat::USTAR),
             "posix" => Ok(TarFormat::POSIX),
+            "gnu" => Ok(TarFormat::GNU),
             _ => Err(())
         }
     }
@@ -31,7 +37,7 @@ impl FromStr for TarFormat {
 ///
 /// Certain tar file formats allow opaque file types, those are represented as
 /// Other.
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq)]
 pub enum TarFileType {
     FileStream,
     HardLink,
@@ -60,12 +66,40 @@ impl TarFileType {
             TarFileType::Other(f) => f.clone()
         }
     }
+
+    /// Deserialize a file type from it's type character flag.
+    ///
+    /// Pre-POSIX archives mark regular files with a null flag, and contiguous
+    /// files ('7') are regular files to everyone but a handful of long-dead
+    /// systems, so both are read as `FileStream`.
+    pub fn from_type_flag(flag: char) -> TarFileType {
+        match flag {
+            '0' | '\0' | '7' => TarFileType::FileStream,
+            '1' => TarFileType::HardLink,
+            '2' => TarFileType::SymbolicLink,
+            '3' => TarFileType::CharacterDevice,
+            '4' => TarFileType::BlockDevice,
+            '5' => TarFileType::Directory,
+            '6' => TarFileType::FIFOPipe,
+            f => TarFileType::Other(f)
+        }
+    }
+
+    /// Determine if members of this type carry data after their header.
+    ///
+    /// Vendor-specific types are assumed to, since we can't know otherwise.
+    pub fn has_data(&self) -> bool {
+        match self {
+            TarFileType::FileStream | TarFileType::Other(_) => true,
+            _ => false
+        }
+    }
 }
 
 /// An abstract representation of the data contained within a tarball header.
 ///
 /// Some header formats may or may not actually use or provide these values.
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub struct TarHeader {
     pub path: Box<path::PathBuf>,
     pub unix_mode: u32,
@@ -75,12 +109,54 @@ pub struct TarHeader {
     pub mtime: Option<time::SystemTime>,
     pub file_type: TarFileType,
     pub symlink_path: Option<Box<path::PathBuf>>,
-    pub unix_uname: String,
-    pub unix_gname: String,
+
+    /// The owner and group names of the file.
+    ///
+    /// These are shared, since most archives only have a few distinct names
+    /// between all their members; see `intern::StringPool`.
+    pub unix_uname: Arc<str>,
+    pub unix_gname: Arc<str>,
     pub unix_devmajor: u32,
     pub unix_devminor: u32,
     pub atime: Option<time::SystemTime>,
+
+    /// The last time the file's metadata or contents changed.
+    ///
+    /// Unlike `mtime`, this can't be set by users, and is bumped by changes to
+    /// permissions or ownership that leave the contents alone.
+    pub ctime: Option<time::SystemTime>,
     pub birthtime: Option<time::SystemTime>,
+
+    /// Extended attributes of the file, by name.
+    ///
+    /// Only pax headers can record these; other formats drop them.
+    pub xattrs: Vec<(String, Vec<u8>)>,
+
+    /// The Linux project ID the file is accounted to, for project quotas.
+    ///
+    /// This isn't read from disk by default, since it costs an extra open for
+    /// every file (see `tar::filter::RecordProjectId`). Only pax headers can
+    /// record it.
+    pub project_id: Option<u32>,
+
+    /// The file's access ACL, if it grants more than it's mode bits say.
+    ///
+    /// Like project IDs, ACLs are only read from disk when asked for (see
+    /// `tar::acl::RecordAcls`), and only pax headers can record them.
+    pub acl_access: Option<acl::Acl>,
+
+    /// The ACL that new files in the directory inherit, if it has one.
+    pub acl_default: Option<acl::Acl>,
+
+    /// How drive letters in `path` (and in the targets of hard links) are to
+    /// be recorded.
+    pub path_style: PathStyle,
+
+    /// Where the file's data lies, if it's to be archived without it's holes.
+    ///
+    /// `file_size` is still the size of the whole file, holes and all. See
+    /// `tar::sparse`.
+    pub sparse_map: Option<Vec<sparse::SparseExtent>>,
     pub recovery_path: Option<Box<path::PathBuf>>,
     pub recovery_remaining_size: Option<u64>,
     pub recovery_seek_offset: Option<u64>,
@@ -88,12 +164,20 @@ pub struct TarHeader {
 
 impl TarHeader {
     pub fn abstract_header_for_file(archival_path: &path::Path, entry_metadata: &fs::Metadata, entry_path: &path::Path) -> io::Result<TarHeader> {
+        Self::with_pool(archival_path, entry_metadata, entry_path, &StringPool::new())
+    }
+
+    /// Form an abstract header for a file, taking it's owner and group names
+    /// from a pool shared with other headers.
+    pub fn with_pool(archival_path: &path::Path, entry_metadata: &fs::Metadata, entry_path: &path::Path, pool: &StringPool) -> io::Result<TarHeader> {
         let (uid, owner) = get_unix_owner(entry_metadata, entry_path).unwrap_or((65534, "nobody".to_string()));
         let (gid, group) = get_unix_group(entry_metadata, entry_path).unwrap_or((65534, "nogroup".to_string()));
+        let file_type = get_file_type(entry_metadata)?;
+        let (devmajor, devminor) = get_device_numbers(entry_metadata, entry_path).unwrap_or((0, 0));
 
         Ok(TarHeader {
             path: Box::new(normalize::normalize(&archival_path)),
-            unix_mode: get_unix_mode(entry_metadata)?,
+            unix_mode: get_unix_mode(entry_metadata, entry_path)?,
 
             //TODO: Get plausible IDs for these.
             unix_uid: uid,
@@ -102,15 +186,25 @@ impl TarHeader {
             mtime: entry_metadata.modified().ok(),
 
             //TODO: All of these are placeholders.
-            file_type: get_file_type(entry_metadata)?,
-            symlink_path: None,
-            unix_uname: owner,
-            unix_gname: group,
-            unix_devmajor: 0,
-            unix_devminor: 0,
+            file_type: file_type,
+            symlink_path: match file_type {
+                TarFileType::SymbolicLink => Some(Box::new(read_link_target(entry_path)?)),
+                _ => None
+            },
+            unix_uname: pool.intern(&owner),
+            unix_gname: pool.intern(&group),
+            unix_devmajor: devmajor,
+            unix_devminor: devminor,
 
             atime: entry_metadata.accessed().ok(),
+            ctime: get_change_time(entry_metadata, entry_path),
             birthtime: entry_metadata.created().ok(),
+            xattrs: Vec::new(),
+            project_id: None,
+            acl_access: None,
+            acl_default: None,
+            path_style: PathStyle::default(),
+            sparse_map: None,
 
             recovery_path: None,
             recovery_remaining_size: None,
@@ -119,18 +213,80 @@ impl TarHeader {
     }
 
     pub fn with_recovery(archival_path: &path::Path, entry_metadata: &fs::Metadata, entry_path: &path::Path, zone: &spanning::DataZone<recovery::RecoveryEntry>) -> io::Result<TarHeader> {
-        let mut recovery_header = Self::abstract_header_for_file(archival_path, entry_metadata, entry_path)?;
+        let recovery_header = Self::abstract_header_for_file(archival_path, entry_metadata, entry_path)?;
+
+        Ok(recovery_header.into_recovery(zone))
+    }
+
+    /// Determine if the file this header describes has changed since a given
+    /// time.
+    ///
+    /// Both the modification and change times are considered, so that files
+    /// whose permissions or ownership changed are also caught. Headers without
+    /// either time are always considered changed. Update and incremental
+    /// operations should use this, rather than comparing `mtime` directly.
+    pub fn changed_since(&self, since: time::SystemTime) -> bool {
+        match (self.mtime, self.ctime) {
+            (None, None) => true,
+            (mtime, ctime) => mtime.map_or(false, |t| t > since) || ctime.map_or(false, |t| t > since)
+        }
+    }
+
+    /// Convert a header into one describing the remainder of a member which
+    /// was torn by a failed write.
+    ///
+    /// The header's existing path is used as the recovery path, so headers
+    /// which were altered before archival (say, by an `EntryFilter`) keep
+    /// their alterations on the next volume.
+    pub fn into_recovery(mut self, zone: &spanning::DataZone<recovery::RecoveryEntry>) -> TarHeader {
+        //Offsets into sparse members don't correspond to offsets into their
+        //files, so they're archived again in full instead.
+        if self.sparse_map.take().is_some() {
+            return self;
+        }
 
         if let Some(ref ident) = zone.ident {
             let offset = zone.committed_length.checked_sub(ident.header_length).unwrap_or(0);
 
-            recovery_header.recovery_path = Some(Box::new(normalize::normalize(&ident.original_path.as_ref())));
-            recovery_header.recovery_remaining_size = Some(entry_metadata.len());
-            recovery_header.recovery_seek_offset = Some(cmp::min(offset, recovery_header.file_size));
-            recovery_header.file_size = recovery_header.file_size.checked_sub(offset).unwrap_or(0);
+            self.recovery_path = Some(self.path.clone());
+            self.recovery_remaining_size = Some(self.file_size);
+            self.recovery_seek_offset = Some(cmp::min(offset, self.file_size));
+            self.file_size = self.file_size.checked_sub(offset).unwrap_or(0);
         }
 
-        Ok(recovery_header)
+        self
+    }
+
+    /// Form a header with fixed metadata, for tests which shouldn't depend on
+    /// the metadata of whatever file is at hand.
+    #[cfg(test)]
+    pub(crate) fn for_test(name: &str, file_type: TarFileType, file_size: u64) -> TarHeader {
+        TarHeader {
+            path: Box::new(path::PathBuf::from(name)),
+            unix_mode: 0o644,
+            unix_uid: 1000,
+            unix_gid: 1000,
+            file_size: file_size,
+            mtime: Some(time::UNIX_EPOCH + time::Duration::from_secs(1_500_000_000)),
+            file_type: file_type,
+            symlink_path: None,
+            unix_uname: "user".into(),
+            unix_gname: "group".into(),
+            unix_devmajor: 0,
+            unix_devminor: 0,
+            atime: None,
+            ctime: None,
+            birthtime: None,
+            xattrs: Vec::new(),
+            project_id: None,
+            acl_access: None,
+            acl_default: None,
+            path_style: PathStyle::default(),
+            sparse_map: None,
+            recovery_path: None,
+            recovery_remaining_size: None,
+            recovery_seek_offset: None
+        }
     }
 }
 
@@ -156,34 +312,172 @@ pub struct HeaderGenResult {
 
     /// Optional cached file stream data. If populated, serialization should
     /// utilize this data while awaiting further data to copy to archive.
-    pub file_prefix: Option<Vec<u8>>
+    pub file_prefix: Option<Vec<u8>>,
+
+    /// How the file's access time should be treated when it's read.
+    pub atime_policy: AtimePolicy,
+
+    /// Identifies the file, if it has other hard links which may also be
+    /// archived. See `tar::hardlink`.
+    pub link_identity: Option<FileId>,
+
+    /// The sparse map written ahead of a sparse member's data, for formats
+    /// which keep it there rather than in the header.
+    pub sparse_map_data: Vec<u8>,
+
+    /// The file the member's metadata was captured from, if it is still held
+    /// open to read the rest of it's contents from. See `open_source`.
+    pub source: Mutex<Option<ArchivalFile>>
+}
+
+impl HeaderGenResult {
+    /// Turn this member into a hard link to an earlier member holding the
+    /// same file.
+    ///
+    /// The member is left alone if a link can't be encoded in the given
+    /// format, for example if the target's name is too long for USTAR.
+    pub fn link_to(&mut self, target: &path::Path, format: TarFormat) -> io::Result<()> {
+        let mut link_header = self.tar_header.clone();
+
+        link_header.file_type = TarFileType::HardLink;
+        link_header.symlink_path = Some(Box::new(target.to_path_buf()));
+        link_header.file_size = 0;
+        link_header.sparse_map = None;
+
+        self.encoded_header = encode_header(&link_header, format)?;
+        self.tar_header = link_header;
+        self.file_prefix = None;
+        self.link_identity = None;
+        self.sparse_map_data = Vec::new();
+        self.source = Mutex::new(None);
+
+        Ok(())
+    }
+
+    /// Open the file holding the member's contents.
+    ///
+    /// If the file was held open when the header was generated, it is handed
+    /// over, positioned wherever read-ahead left it; otherwise, the file is
+    /// opened again by path. A held file is only handed over once.
+    pub fn open_source(&self) -> io::Result<ArchivalFile> {
+        match self.source.lock().unwrap().take() {
+            Some(file) => Ok(file),
+            None => ArchivalFile::open(self.canonical_path.as_ref(), self.tar_header.atime, self.atime_policy)
+        }
+    }
+
+    /// How many bytes of data the member holds in the archive, not counting
+    /// padding.
+    ///
+    /// This is the file's size, except for sparse members, which hold only
+    /// their data extents and sparse map.
+    pub fn data_size(&self) -> u64 {
+        match (self.tar_header.file_type, &self.tar_header.sparse_map) {
+            (TarFileType::FileStream, Some(map)) => self.sparse_map_data.len() as u64 + sparse::data_size(map),
+            (TarFileType::FileStream, None) => self.tar_header.file_size,
+            _ => 0
+        }
+    }
+}
+
+/// A queued header holds onto it's encoded header, paths, and cached file data.
+impl MemoryFootprint for HeaderGenResult {
+    fn memory_footprint(&self) -> usize {
+        let paths = [&self.tar_header.path, &self.original_path, &self.canonical_path];
+
+        self.encoded_header.capacity() +
+            self.file_prefix.as_ref().map(|prefix| prefix.capacity()).unwrap_or(0) +
+            self.sparse_map_data.capacity() +
+            paths.iter().map(|path| path.as_os_str().len()).sum::<usize>()
+    }
+}
+
+/// Encode and checksum an abstract header in a given format.
+pub(crate) fn encode_header(tarheader: &TarHeader, format: TarFormat) -> io::Result<Vec<u8>> {
+    let mut concrete_tarheader = match format {
+        TarFormat::USTAR => ustar::ustar_header(tarheader)?,
+        TarFormat::POSIX => pax::pax_header(tarheader)?,
+        TarFormat::GNU => gnu::gnu_header(tarheader)?
+    };
+
+    match format {
+        TarFormat::USTAR => ustar::checksum_header(&mut concrete_tarheader),
+        TarFormat::POSIX => pax::checksum_header(&mut concrete_tarheader),
+        TarFormat::GNU => gnu::checksum_header(&mut concrete_tarheader)
+    }
+
+    Ok(concrete_tarheader)
+}
+
+/// Produce a HeaderGenResult for a member whose contents are already in
+/// memory, rather than in a file.
+///
+/// This is for members we make up ourselves, such as AppleDouble companions.
+/// The header's size is taken from `data`, which is cached in it's entirety,
+/// so the member is never read from disk.
+pub fn headergen_in_memory(archival_path: &path::Path, mut tarheader: TarHeader, format: TarFormat, data: Vec<u8>) -> io::Result<HeaderGenResult> {
+    tarheader.file_size = data.len() as u64;
+    tarheader.sparse_map = None;
+
+    Ok(HeaderGenResult{encoded_header: encode_header(&tarheader, format)?,
+        tar_header: tarheader,
+        original_path: Box::new(archival_path.to_path_buf()),
+        canonical_path: Box::new(archival_path.to_path_buf()),
+        file_prefix: Some(data),
+        atime_policy: AtimePolicy::Ignore,
+        link_identity: None,
+        sparse_map_data: Vec::new(),
+        source: Mutex::new(None)})
 }
 
 /// Given a directory entry's path and metadata, produce a valid HeaderGenResult
 /// for a given path.
 ///
 /// headergen attempts to precache the file's contents in the HeaderGenResult.
-/// A maximum of 1MB is read and stored in the HeaderGenResult. If the read
-/// fails or the item is not a file then the file_prefix field will be None.
+/// At most `readahead_limit` bytes are read and stored in the HeaderGenResult.
+/// If the read fails, read-ahead is disabled by a limit of zero, or the item
+/// is not a file, then the file_prefix field will be None.
 ///
-/// TODO: Make headergen read-ahead caching maximum configurable.
-pub fn headergen(entry_path: &path::Path, archival_path: &path::Path, tarheader: TarHeader, format: TarFormat) -> io::Result<HeaderGenResult> {
-    let mut concrete_tarheader = match format {
-        TarFormat::USTAR => ustar::ustar_header(&tarheader)?,
-        TarFormat::POSIX => pax::pax_header(&tarheader)?
-    };
+/// Files are read according to the given `AtimePolicy`. If access times are
+/// to be restored, the header's `atime` is taken as the time to restore.
+///
+/// If the operation is cancelled, no header is generated and a cancellation
+/// error is returned instead.
+///
+/// `entry_path` is used as-is to read the file, both here and when it is
+/// serialized, so it should be the canonical path `traverse` hands out.
+/// 
+/// Sparse files are archived without their holes in formats that can record
+/// them, and in full otherwise. They aren't read ahead.
+pub fn headergen(entry_path: &path::Path, archival_path: &path::Path, tarheader: TarHeader, format: TarFormat, atime: AtimePolicy, readahead_limit: u64, cancel: &CancellationToken) -> io::Result<HeaderGenResult> {
+    headergen_from_source(entry_path, archival_path, tarheader, format, atime, readahead_limit, None, cancel)
+}
 
-    match format {
-        TarFormat::USTAR => ustar::checksum_header(&mut concrete_tarheader),
-        TarFormat::POSIX => pax::checksum_header(&mut concrete_tarheader)
+/// Produce a HeaderGenResult for a file which is already open.
+///
+/// This is `headergen`, save that the file is read ahead from `source`
+/// rather than opened again. If any of the file remains unread, `source` is
+/// held open in the HeaderGenResult, so that serialization reads it from the
+/// same file the header was generated from. `None` opens the file by path, as
+/// `headergen` does.
+pub fn headergen_from_source(entry_path: &path::Path, archival_path: &path::Path, mut tarheader: TarHeader, format: TarFormat, atime: AtimePolicy, readahead_limit: u64, mut source: Option<ArchivalFile>, cancel: &CancellationToken) -> io::Result<HeaderGenResult> {
+    cancel.check()?;
+
+    if format == TarFormat::USTAR || tarheader.file_type != TarFileType::FileStream {
+        tarheader.sparse_map = None;
     }
 
-    //TODO: This should be unnecessary as we are usually handed data from traverse
-    let canonical_path = fs::canonicalize(entry_path).unwrap();
+    let concrete_tarheader = encode_header(&tarheader, format)?;
+    let canonical_path = entry_path.to_path_buf();
+    let sparse_map_data = match (&tarheader.sparse_map, format) {
+        (Some(map), TarFormat::POSIX) => sparse::format_sparse_map(map),
+        _ => Vec::new()
+    };
 
+    let source_held = source.is_some();
     let readahead = match tarheader.file_type {
-        TarFileType::FileStream => {
-            let cache_len = cmp::min(tarheader.file_size, 64*1024);
+        TarFileType::FileStream if readahead_limit > 0 && tarheader.sparse_map.is_none() => {
+            let cache_len = cmp::min(tarheader.file_size, readahead_limit);
             let mut filebuf = Vec::with_capacity(cache_len as usize);
 
             //TODO: Can we soundly replace the following code with using unsafe{} to
@@ -198,9 +492,17 @@ pub fn headergen(entry_path: &path::Path, archival_path: &path::Path, tarheader:
             //actually read, too.
             let mut final_cache_len = 0;
 
-            match fs::File::open(canonical_path.clone()) {
+            //Filters may have moved a held file's position while inspecting it.
+            let opened = match source.take() {
+                Some(mut file) => file.seek(io::SeekFrom::Start(0)).map(|_| file),
+                None => ArchivalFile::open(canonical_path.clone(), tarheader.atime, atime)
+            };
+
+            match opened {
                 Ok(mut file) => {
                     loop {
+                        cancel.check()?;
+
                         match file.read(&mut filebuf[final_cache_len..]) {
                             Ok(size) => {
                                 final_cache_len += size;
@@ -230,6 +532,11 @@ pub fn headergen(entry_path: &path::Path, archival_path: &path::Path, tarheader:
                         filebuf.set_len(final_cache_len);
                     }
 
+                    //Files which were read completely don't need to be held.
+                    if (final_cache_len as u64) < tarheader.file_size && source_held {
+                        source = Some(file);
+                    }
+
                     Some(filebuf)
                 },
                 Err(_) => {
@@ -244,5 +551,9 @@ pub fn headergen(entry_path: &path::Path, archival_path: &path::Path, tarheader:
         encoded_header: concrete_tarheader,
         original_path: Box::new(archival_path.to_path_buf()),
         canonical_path: Box::new(canonical_path),
-        file_prefix: readahead})
+        file_prefix: readahead,
+        atime_policy: atime,
+        link_identity: None,
+        sparse_map_data: sparse_map_data,
+        source: Mutex::new(source)})
 }
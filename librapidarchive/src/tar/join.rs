@@ -0,0 +1,173 @@
+//! Joining archives together, as `tar -A` does.
+//!
+//! Joining an archive onto another copies it's members verbatim, extended
+//! headers and all, leaving out it's end-of-archive marker. Archives are read
+//! as a stream, so they can come from tapes or pipes, and nothing but a few
+//! blocks of headers is held in memory no matter how large their members are.
+
+use std::io;
+use crate::tar::reader::TarReader;
+
+/// What was copied out of an archive by `copy_members`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct CopiedArchive {
+    /// How many members were copied.
+    pub members: u64,
+
+    /// How many bytes were written.
+    pub bytes: u64,
+
+    /// Whether the archive ended with an end-of-archive marker.
+    pub terminated: bool,
+}
+
+/// A reader which writes everything read through it out again, holding back
+/// whatever hasn't yet been confirmed to be part of a member.
+struct PassThrough<'a, R: io::Read, W: io::Write + ?Sized> {
+    inner: R,
+    out: &'a mut W,
+
+    /// How much of the stream has been read so far.
+    read: u64,
+
+    /// How much of the stream has been written out so far.
+    written: u64,
+
+    /// How much of the stream is known to belong to members.
+    confirmed: u64,
+
+    /// Bytes read, but not yet confirmed.
+    held: Vec<u8>,
+}
+
+impl<'a, R: io::Read, W: io::Write + ?Sized> PassThrough<'a, R, W> {
+    /// Mark everything up to `offset` as belonging to a member, writing out
+    /// whatever of it has been read.
+    fn confirm(&mut self, offset: u64) -> io::Result<()> {
+        self.confirmed = self.confirmed.max(offset);
+
+        let releasable = (self.confirmed.min(self.read) - self.written) as usize;
+
+        if releasable > 0 {
+            self.out.write_all(&self.held[..releasable])?;
+            self.held.drain(..releasable);
+            self.written += releasable as u64;
+        }
+
+        Ok(())
+    }
+}
+
+impl<'a, R: io::Read, W: io::Write + ?Sized> io::Read for PassThrough<'a, R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+
+        self.held.extend_from_slice(&buf[..count]);
+        self.read += count as u64;
+
+        let confirmed = self.confirmed;
+        self.confirm(confirmed)?;
+
+        Ok(count)
+    }
+}
+
+/// Copy every member of an archive into a sink, without it's end-of-archive
+/// marker.
+///
+/// Members are copied exactly as they were archived. Archives cut off in
+/// their last member's padding have it filled in, so that anything written
+/// after them stays aligned.
+pub fn copy_members<R: io::Read, W: io::Write + ?Sized>(source: R, sink: &mut W) -> io::Result<CopiedArchive> {
+    let mut reader = TarReader::new(PassThrough {
+        inner: source,
+        out: sink,
+        read: 0,
+        written: 0,
+        confirmed: 0,
+        held: Vec::new()
+    });
+    let mut copied = CopiedArchive::default();
+
+    while let Some(entry) = reader.next_entry()? {
+        copied.members += 1;
+        reader.get_mut().confirm(entry.end_offset())?;
+    }
+
+    let end = reader.archive_end();
+    let passthrough = reader.get_mut();
+
+    passthrough.confirm(end)?;
+
+    if passthrough.written < end {
+        let padding = (end - passthrough.written) as usize;
+
+        passthrough.out.write_all(&vec![0; padding])?;
+        passthrough.written = end;
+    }
+
+    copied.bytes = passthrough.written;
+    copied.terminated = reader.is_terminated();
+
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path};
+    use crate::tar::header::{TarFormat, TarHeader, TarFileType, headergen_in_memory};
+    use crate::tar::reader::TarReader;
+    use super::copy_members;
+
+    fn archive(names: &[&str], data_size: usize) -> Vec<u8> {
+        let mut archive = Vec::new();
+
+        for name in names {
+            let header = TarHeader::for_test(name, TarFileType::FileStream, data_size as u64);
+            let data = vec![name.as_bytes()[0]; data_size];
+            let generated = headergen_in_memory(path::Path::new(name), header, TarFormat::POSIX, data.clone()).unwrap();
+
+            archive.extend(generated.encoded_header);
+            archive.extend(&data);
+            archive.resize((archive.len() + 511) / 512 * 512, 0);
+        }
+
+        archive
+    }
+
+    #[test]
+    fn join_archives() {
+        let first = archive(&["alpha", "beta"], 3000);
+        let mut second = archive(&["gamma"], 100);
+        second.extend(vec![0; 10240]);
+
+        let mut joined = vec![];
+        let copied = copy_members(io::Cursor::new(&first), &mut joined).unwrap();
+        assert_eq!((copied.members, copied.bytes, copied.terminated), (2, first.len() as u64, false));
+
+        let copied = copy_members(io::Cursor::new(&second), &mut joined).unwrap();
+        assert_eq!((copied.members, copied.bytes, copied.terminated), (1, second.len() as u64 - 10240, true));
+
+        joined.extend(vec![0; 1024]);
+
+        let mut reader = TarReader::new(io::Cursor::new(&joined));
+        let mut names = vec![];
+
+        while let Some(entry) = reader.next_entry().unwrap() {
+            names.push(entry.header.path.to_string_lossy().into_owned());
+        }
+
+        assert_eq!(names, vec!["alpha", "beta", "gamma"]);
+        assert!(reader.is_terminated());
+
+        //Archives cut off in their last member's padding are padded out.
+        let whole = archive(&["gamma"], 100);
+        let mut truncated = whole.clone();
+        truncated.truncate(whole.len() - 412);
+
+        let mut joined = vec![];
+        let copied = copy_members(io::Cursor::new(&truncated), &mut joined).unwrap();
+        assert_eq!(copied.bytes, whole.len() as u64);
+        assert_eq!(joined, whole);
+    }
+}
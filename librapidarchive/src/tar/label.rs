@@ -1,10 +1,47 @@
 //! Code dealing with global headers, which we call labels.
 
-use std::{io, fs, process, path, cmp};
-use crate::tar::{header, pax, recovery, ustar};
+use std::{io, fs, process, path, cmp, time};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use crate::tar::{header, pax, recovery, ustar, PathStyle};
+use crate::tar::reader::TarReader;
 use crate::{normalize, spanning};
+use crate::digest::{Sha256Digest, to_hex};
 use crate::fs as rapidtar_fs;
 
+/// The pax attribute identifying which archive a volume belongs to.
+pub const VOLUME_UUID_KEY: &str = "RAPIDTAR.volume.uuid";
+
+/// The pax attribute numbering a volume within it's archive, from 1.
+pub const VOLUME_SEQUENCE_KEY: &str = "RAPIDTAR.volume.sequence";
+
+/// The pax attribute holding the SHA-256 digest of the previous volume's
+/// label.
+pub const VOLUME_PREVIOUS_KEY: &str = "RAPIDTAR.volume.previous";
+
+/// The pax attribute giving the time, in seconds since the UNIX epoch, until
+/// which a volume must not be overwritten.
+pub const VOLUME_RETAIN_UNTIL_KEY: &str = "RAPIDTAR.volume.retain_until";
+
+/// Generate a random (version 4) UUID.
+pub fn random_uuid() -> String {
+    let mut bytes = [0u8; 16];
+
+    for half in bytes.chunks_mut(8) {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(time::SystemTime::now().duration_since(time::UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0));
+
+        half.copy_from_slice(&hasher.finish().to_be_bytes());
+    }
+
+    bytes[6] = (bytes[6] & 0x0F) | 0x40;
+    bytes[8] = (bytes[8] & 0x3F) | 0x80;
+
+    let hex : Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+
+    format!("{}-{}-{}-{}-{}", hex[0..4].concat(), hex[4..6].concat(), hex[6..8].concat(), hex[8..10].concat(), hex[10..16].concat())
+}
+
 /// Represents globally-applcable information for an entire tar archive file,
 /// such as it's volume label.
 #[derive(Clone)]
@@ -17,8 +54,18 @@ pub struct TarLabel {
     //than the file header, so we need to account for that
     pub recovery_path: Option<Box<path::PathBuf>>,
     pub recovery_file_type: Option<header::TarFileType>,
+    pub recovery_path_style: PathStyle,
     pub recovery_remaining_size: Option<u64>,
     pub recovery_seek_offset: Option<u64>,
+
+    /// Identifies the archive this volume belongs to. Volumes with an archive
+    /// UUID also record their sequence number and the digest of the previous
+    /// volume's label, so that readers can tell if volumes were swapped.
+    pub archive_uuid: Option<String>,
+    pub previous_volume_digest: Option<Sha256Digest>,
+
+    /// When the volume may be overwritten, if it's to be retained.
+    pub retain_until: Option<time::SystemTime>,
 }
 
 impl Default for TarLabel {
@@ -29,8 +76,12 @@ impl Default for TarLabel {
             volume_identifier: None,
             recovery_path: None,
             recovery_file_type: None,
+            recovery_path_style: PathStyle::default(),
             recovery_remaining_size: None,
-            recovery_seek_offset: None
+            recovery_seek_offset: None,
+            archive_uuid: None,
+            previous_volume_digest: None,
+            retain_until: None
         }
     }
 }
@@ -39,11 +90,21 @@ impl TarLabel {
     pub fn with_recovery(zone: &spanning::DataZone<recovery::RecoveryEntry>) -> io::Result<Self> {
         let mut label = Self::default();
 
-        if let Some(ref ident) = zone.ident {
+        //Torn sparse members are archived again in full; see
+        //`TarHeader::into_recovery`.
+        let is_sparse = zone.ident.as_ref().and_then(|ident| ident.tar_header.as_ref()).map_or(false, |header| header.sparse_map.is_some());
+
+        if let Some(ref ident) = zone.ident.as_ref().filter(|_| !is_sparse) {
             let metadata = fs::symlink_metadata(&ident.canonical_path.as_ref())?;
             let offset = zone.committed_length.checked_sub(ident.header_length).unwrap_or(0);
 
-            label.recovery_path = Some(Box::new(normalize::normalize(&ident.original_path.as_ref())));
+            label.recovery_path = match ident.tar_header {
+                Some(ref tar_header) => {
+                    label.recovery_path_style = tar_header.path_style;
+                    Some(tar_header.path.clone())
+                },
+                None => Some(Box::new(normalize::normalize(&ident.original_path.as_ref())))
+            };
             label.recovery_file_type = Some(rapidtar_fs::get_file_type(&metadata)?);
             
             label.recovery_remaining_size = Some(metadata.len().checked_sub(offset).unwrap_or(0));
@@ -54,6 +115,171 @@ impl TarLabel {
     }
 }
 
+/// The self-describing part of a volume's label.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VolumeHeader {
+    pub archive_uuid: String,
+    pub sequence: usize,
+    pub previous_digest: Option<Sha256Digest>,
+
+    /// The digest of the label this header was read from, which the next
+    /// volume's label should name as it's previous digest.
+    pub digest: Sha256Digest,
+}
+
+/// Parse a hex-encoded SHA-256 digest.
+fn parse_digest(hex: &str) -> Option<Sha256Digest> {
+    if hex.len() != 64 || !hex.is_ascii() {
+        return None;
+    }
+
+    let mut digest = [0; 32];
+
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(digest)
+}
+
+impl VolumeHeader {
+    /// Extract a volume header from the attributes of a volume's label.
+    ///
+    /// Returns None if the label wasn't written with a volume header, or the
+    /// header is malformed.
+    pub fn from_attributes(attributes: &[(String, String)], digest: Sha256Digest) -> Option<VolumeHeader> {
+        let value = |key: &str| attributes.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v);
+
+        Some(VolumeHeader {
+            archive_uuid: value(VOLUME_UUID_KEY)?.clone(),
+            sequence: value(VOLUME_SEQUENCE_KEY)?.parse().ok()?,
+            previous_digest: match value(VOLUME_PREVIOUS_KEY) {
+                Some(hex) => Some(parse_digest(hex)?),
+                None => None
+            },
+            digest: digest
+        })
+    }
+}
+
+/// Checks that the volumes of an archive are read in order.
+pub struct VolumeSequence {
+    last: Option<VolumeHeader>
+}
+
+impl Default for VolumeSequence {
+    fn default() -> Self {
+        VolumeSequence::new()
+    }
+}
+
+impl VolumeSequence {
+    pub fn new() -> VolumeSequence {
+        VolumeSequence {
+            last: None
+        }
+    }
+
+    /// The sequence number of the volume we expect next.
+    pub fn expected(&self) -> usize {
+        match self.last {
+            Some(ref last) => last.sequence + 1,
+            None => 1
+        }
+    }
+
+    /// Accept the next volume, if it's the one that should follow the volumes
+    /// accepted so far.
+    ///
+    /// Volumes from other archives, volumes out of order, and volumes whose
+    /// previous-volume digest doesn't match the last volume accepted are all
+    /// refused with `InvalidData`.
+    pub fn accept(&mut self, header: &VolumeHeader) -> io::Result<()> {
+        if header.sequence != self.expected() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Expected volume {}, but this is volume {}", self.expected(), header.sequence)));
+        }
+
+        if let Some(ref last) = self.last {
+            if header.archive_uuid != last.archive_uuid {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Volume belongs to archive {}, not {}", header.archive_uuid, last.archive_uuid)));
+            }
+
+            if header.previous_digest != Some(last.digest) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Volume {} was not written after the volume {} that was read", header.sequence, last.sequence)));
+            }
+        }
+
+        self.last = Some(header.clone());
+
+        Ok(())
+    }
+}
+
+/// Format the volume header attributes of a label, if it has any.
+pub fn volume_header_attributes(tarlabel: &TarLabel) -> Vec<(&'static str, String)> {
+    let mut attributes = Vec::new();
+
+    if let Some(ref uuid) = tarlabel.archive_uuid {
+        attributes.push((VOLUME_UUID_KEY, uuid.clone()));
+        attributes.push((VOLUME_SEQUENCE_KEY, format!("{}", tarlabel.volume_identifier.unwrap_or(1))));
+
+        if let Some(ref digest) = tarlabel.previous_volume_digest {
+            attributes.push((VOLUME_PREVIOUS_KEY, to_hex(digest)));
+        }
+    }
+
+    if let Some(retain_until) = tarlabel.retain_until {
+        let secs = retain_until.duration_since(time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+
+        attributes.push((VOLUME_RETAIN_UNTIL_KEY, format!("{}", secs)));
+    }
+
+    attributes
+}
+
+/// Extract a volume's retention time from the attributes of it's label.
+pub fn retention_from_attributes(attributes: &[(String, String)]) -> Option<time::SystemTime> {
+    let secs : u64 = attributes.iter().rev().find(|(k, _)| k == VOLUME_RETAIN_UNTIL_KEY)?.1.parse().ok()?;
+
+    Some(time::UNIX_EPOCH + time::Duration::from_secs(secs))
+}
+
+/// Extract which member a volume continues from the previous volume, and how
+/// far into the member's data the volume picks up, from the attributes of
+/// it's label.
+pub fn continuation_from_attributes(attributes: &[(String, String)]) -> Option<(path::PathBuf, u64)> {
+    let value = |key: &str| attributes.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v);
+    let name = value("GNU.volume.filename")?;
+
+    Some((path::PathBuf::from(name.trim_end_matches('/')), value("GNU.volume.offset")?.parse().ok()?))
+}
+
+/// Read a volume's retention time from data at the start of the volume.
+///
+/// Only the label needs to be present; anything after it may be cut off.
+pub fn read_retention(volume_start: &[u8]) -> Option<time::SystemTime> {
+    let mut reader = TarReader::new(io::Cursor::new(volume_start));
+
+    //The label is read along with the first member, which may well have been
+    //cut off, so errors here don't matter.
+    let _ = reader.next_entry();
+
+    retention_from_attributes(reader.global_attributes())
+}
+
+/// Refuse to overwrite a volume which is being retained until some time after
+/// `now`.
+///
+/// This is a software WORM policy, and can't stop anything other than us from
+/// overwriting the volume. Yields `PermissionDenied` if the volume is still
+/// being retained.
+pub fn check_retention(retain_until: time::SystemTime, now: time::SystemTime) -> io::Result<()> {
+    match retain_until.duration_since(now) {
+        Ok(remaining) if remaining.as_secs() > 0 => Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("Volume is retained for another {} days", (remaining.as_secs() + 86399) / 86400))),
+        _ => Ok(())
+    }
+}
+
 pub fn labelgen(format: header::TarFormat, tarlabel: &TarLabel) -> io::Result<Vec<u8>> {
     match format {
         header::TarFormat::POSIX => {
@@ -0,0 +1,260 @@
+//! Listing the contents of archives, as `tar -t` does.
+//!
+//! Listings follow GNU tar's output closely enough that scripts which parse
+//! it keep working: a member's name on it's own, or with `-v`, a long listing
+//! in the manner of `ls -l`:
+//!
+//! ```text
+//! -rw-r--r-- user/group     1234 2019-03-01 12:00 dir/file.txt
+//! lrwxrwxrwx user/group        0 2019-03-01 12:00 dir/link -> file.txt
+//! ```
+//!
+//! Names are escaped the way GNU tar does by default, so that members with
+//! newlines or other control characters in their names can't forge extra
+//! lines of output.
+
+use std::{path, time};
+use crate::tar::header::{TarHeader, TarFileType};
+use crate::units::civil_from_days;
+
+/// How wide the owner, group, and size columns start out, together.
+///
+/// This is GNU tar's starting width; it grows as wider values are seen, so
+/// that later lines stay aligned with the widest seen so far.
+const INITIAL_OWNER_SIZE_WIDTH: usize = 19;
+
+/// Escape a member name for listing.
+///
+/// Backslashes and control characters are escaped as in C string literals,
+/// falling back to octal escapes for characters without a short form.
+pub fn quote_name(name: &str) -> String {
+    let mut quoted = String::with_capacity(name.len());
+
+    for c in name.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            '\r' => quoted.push_str("\\r"),
+            '\x07' => quoted.push_str("\\a"),
+            '\x08' => quoted.push_str("\\b"),
+            '\x0b' => quoted.push_str("\\v"),
+            '\x0c' => quoted.push_str("\\f"),
+            c if c.is_control() && (c as u32) < 0x100 => quoted.push_str(&format!("\\{:03o}", c as u32)),
+            c => quoted.push(c)
+        }
+    }
+
+    quoted
+}
+
+/// Render a member's type and permissions as `ls -l` does, such as
+/// `drwxr-xr-x` or `-rwsr-xr-x`.
+pub fn mode_string(file_type: TarFileType, unix_mode: u32) -> String {
+    let mut modes = String::with_capacity(10);
+
+    modes.push(match file_type {
+        TarFileType::FileStream => '-',
+        TarFileType::HardLink => 'h',
+        TarFileType::SymbolicLink => 'l',
+        TarFileType::CharacterDevice => 'c',
+        TarFileType::BlockDevice => 'b',
+        TarFileType::Directory => 'd',
+        TarFileType::FIFOPipe => 'p',
+        TarFileType::Other(_) => '?'
+    });
+
+    //Each class of user gets rwx, with it's special bit folded into the
+    //execute column: s/t if also executable, S/T if not.
+    let classes = [(0o400, 0o4000, 's'), (0o040, 0o2000, 's'), (0o004, 0o1000, 't')];
+
+    for (read, special, special_letter) in classes.iter() {
+        let write = read >> 1;
+        let execute = read >> 2;
+
+        modes.push(if unix_mode & read != 0 { 'r' } else { '-' });
+        modes.push(if unix_mode & write != 0 { 'w' } else { '-' });
+        modes.push(match (unix_mode & execute != 0, unix_mode & special != 0) {
+            (true, true) => *special_letter,
+            (false, true) => special_letter.to_ascii_uppercase(),
+            (true, false) => 'x',
+            (false, false) => '-'
+        });
+    }
+
+    modes
+}
+
+/// Render a time as `YYYY-MM-DD HH:MM`, offset from UTC by the given number
+/// of seconds.
+pub fn format_timestamp(time: time::SystemTime, utc_offset: i64) -> String {
+    let secs = match time.duration_since(time::UNIX_EPOCH) {
+        Ok(since) => since.as_secs() as i64,
+        Err(before) => -(before.duration().as_secs() as i64) - if before.duration().subsec_nanos() > 0 { 1 } else { 0 }
+    } + utc_offset;
+
+    let days = secs.div_euclid(60 * 60 * 24);
+    let day_secs = secs.rem_euclid(60 * 60 * 24);
+    let (year, month, day) = civil_from_days(days);
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}", year, month, day, day_secs / (60 * 60), (day_secs / 60) % 60)
+}
+
+/// Quote a member's name for listing, marking directories with a trailing
+/// slash.
+pub fn format_name(path: &path::Path, file_type: TarFileType) -> String {
+    let mut name = quote_name(&path.to_string_lossy());
+
+    if file_type == TarFileType::Directory && !name.ends_with('/') {
+        name.push('/');
+    }
+
+    name
+}
+
+/// Formats the members of an archive into listing lines.
+///
+/// The long listing's columns are aligned across lines, so the same `Lister`
+/// should be used for a whole archive.
+pub struct Lister {
+    verbose: bool,
+    owner_size_width: usize,
+}
+
+impl Lister {
+    /// Create a lister giving just member names, or long listings if
+    /// `verbose` is set.
+    pub fn new(verbose: bool) -> Lister {
+        Lister {
+            verbose: verbose,
+            owner_size_width: INITIAL_OWNER_SIZE_WIDTH
+        }
+    }
+
+    /// Format a member's listing line, without a trailing newline.
+    ///
+    /// Times are given in local time.
+    pub fn format(&mut self, header: &TarHeader) -> String {
+        let name = format_name(&header.path, header.file_type);
+
+        if !self.verbose {
+            return name;
+        }
+
+        let owner = match header.unix_uname.is_empty() {
+            true => header.unix_uid.to_string(),
+            false => header.unix_uname.to_string()
+        };
+        let group = match header.unix_gname.is_empty() {
+            true => header.unix_gid.to_string(),
+            false => header.unix_gname.to_string()
+        };
+        let size = match header.file_type {
+            TarFileType::CharacterDevice | TarFileType::BlockDevice => format!("{},{}", header.unix_devmajor, header.unix_devminor),
+            _ => header.file_size.to_string()
+        };
+
+        //The size is right-aligned so that owner, group, and size together
+        //fill the column.
+        let used = owner.len() + 1 + group.len() + 1 + size.len();
+
+        self.owner_size_width = self.owner_size_width.max(used);
+
+        let timestamp = match header.mtime {
+            Some(mtime) => format_timestamp(mtime, crate::fs::utc_offset(mtime)),
+            None => format_timestamp(time::UNIX_EPOCH, 0)
+        };
+        let mut line = format!("{} {}/{} {:>width$} {} {}", mode_string(header.file_type, header.unix_mode), owner, group, size, timestamp, name, width = self.owner_size_width - used + size.len());
+
+        match (header.file_type, header.symlink_path.as_ref()) {
+            (TarFileType::SymbolicLink, Some(target)) => {
+                line.push_str(" -> ");
+                line.push_str(&quote_name(&target.to_string_lossy()));
+            },
+            (TarFileType::HardLink, Some(target)) => {
+                line.push_str(" link to ");
+                line.push_str(&quote_name(&target.to_string_lossy()));
+            },
+            _ => {}
+        }
+
+        line
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path, time};
+    use crate::tar::header::{TarFormat, TarHeader, TarFileType, headergen_in_memory};
+    use crate::tar::reader::TarReader;
+    use super::{Lister, mode_string, quote_name, format_timestamp};
+
+    #[test]
+    fn listing_fields() {
+        assert_eq!(mode_string(TarFileType::Directory, 0o755), "drwxr-xr-x");
+        assert_eq!(mode_string(TarFileType::FileStream, 0o4755), "-rwsr-xr-x");
+        assert_eq!(mode_string(TarFileType::FileStream, 0o3644), "-rw-r-Sr-T");
+        assert_eq!(mode_string(TarFileType::SymbolicLink, 0o777), "lrwxrwxrwx");
+
+        assert_eq!(quote_name("odd\nname\\with\x01"), "odd\\nname\\\\with\\001");
+
+        let time = time::UNIX_EPOCH + time::Duration::from_secs(1_551_441_600);
+        assert_eq!(format_timestamp(time, 0), "2019-03-01 12:00");
+        assert_eq!(format_timestamp(time, -13 * 60 * 60), "2019-02-28 23:00");
+    }
+
+    #[test]
+    fn listing_roundtrip() {
+        let mtime = time::UNIX_EPOCH + time::Duration::from_secs(1_551_441_600);
+        let mut archive = Vec::new();
+
+        for (name, file_type, mode, data) in vec![("dir", TarFileType::Directory, 0o40755, vec![]), ("dir/file.txt", TarFileType::FileStream, 0o100644, vec![7; 1234]), ("dir/link", TarFileType::SymbolicLink, 0o120777, vec![])] {
+            let mut header = TarHeader::for_test(name, file_type, data.len() as u64);
+
+            header.unix_mode = mode;
+            header.mtime = Some(mtime);
+
+            let generated = headergen_in_memory(path::Path::new(name), header, TarFormat::POSIX, data.clone()).unwrap();
+
+            archive.extend(generated.encoded_header);
+            archive.extend(&data);
+            archive.resize((archive.len() + 511) / 512 * 512, 0);
+        }
+
+        archive.extend(vec![0; 1024]);
+
+        let mut reader = TarReader::new(io::Cursor::new(archive));
+        let mut short = Lister::new(false);
+        let mut long = Lister::new(true);
+        let mut short_lines = vec![];
+        let mut long_lines = vec![];
+        let mut last = None;
+
+        while let Some(entry) = reader.next_entry().unwrap() {
+            short_lines.push(short.format(&entry.header));
+            long_lines.push(long.format(&entry.header));
+            last = Some(entry.header);
+        }
+
+        let stamp = format_timestamp(mtime, crate::fs::utc_offset(mtime));
+
+        assert_eq!(short_lines, vec!["dir/", "dir/file.txt", "dir/link"]);
+        assert_eq!(long_lines, vec![
+            format!("drwxr-xr-x user/group        0 {} dir/", stamp),
+            format!("-rw-r--r-- user/group     1234 {} dir/file.txt", stamp),
+            format!("lrwxrwxrwx user/group        0 {} dir/link", stamp),
+        ]);
+
+        let mut link = last.unwrap();
+        link.symlink_path = Some(Box::new(path::PathBuf::from("file.txt")));
+        assert_eq!(long.format(&link), format!("lrwxrwxrwx user/group        0 {} dir/link -> file.txt", stamp));
+
+        link.file_type = TarFileType::HardLink;
+        link.unix_uname = "a_rather_long_user".into();
+        assert_eq!(long.format(&link), format!("hrwxrwxrwx a_rather_long_user/group 0 {} dir/link link to file.txt", stamp));
+
+        //Columns stay as wide as the widest seen so far.
+        link.unix_uname = "user".into();
+        assert_eq!(long.format(&link), format!("hrwxrwxrwx user/group               0 {} dir/link link to file.txt", stamp));
+    }
+}
@@ -6,10 +6,31 @@ mod pax;
 pub mod header;
 pub mod label;
 pub mod recovery;
+pub mod filter;
+pub mod catalog;
+pub mod reader;
+pub mod compare;
+pub mod progress;
+pub mod command;
+pub mod apple;
+pub mod rename;
+pub mod permissions;
+pub mod extract;
+pub mod list;
+pub mod verify;
+pub mod join;
+pub mod recode;
+pub mod hardlink;
+pub mod sparse;
+pub mod dump;
+pub mod acl;
 
-use std::{io, path, fs};
-use std::io::{Seek};
-use crate::fs::{ArchivalSink};
+use std::{io, path, time};
+use std::io::{Read, Seek};
+use std::str::FromStr;
+use crate::fs::ArchivalSink;
+use crate::cancel::{CancellationToken, CancellableRead};
+use crate::timeout::TimeoutRead;
 
 /// Given a filesystem path and the file's type, canonicalize the path for tar
 /// archival.
@@ -22,11 +43,27 @@ use crate::fs::{ArchivalSink};
 /// The `canonicalized_tar_path` function was written specifically to match the
 /// quirks of GNU tar, especially it's behavior of transforming Windows paths
 /// into UNIX-looking equivalents. (e.g. C:\test.txt becomes c\test.txt)
-pub fn canonicalized_tar_path(dirpath: &path::Path, filetype: header::TarFileType) -> String {
+/// 
+/// The one exception is UNC paths, which GNU tar would archive as though the
+/// server were a directory. Those are recorded as `//server/share/...`
+/// instead, so that `restored_path` can tell them apart from ordinary
+/// directories. Other tar implementations strip the leading slashes, and so
+/// still see the server as a directory.
+/// 
+/// Drive letters are recorded according to `style`; only `PathStyle::Gnu`
+/// matches GNU tar.
+pub fn canonicalized_tar_path(dirpath: &path::Path, filetype: header::TarFileType, style: PathStyle) -> String {
     let mut relapath_encoded : String = String::with_capacity(255);
     let mut first = true;
     
     for component in dirpath.components() {
+        if let path::Component::Prefix(prefix) = component {
+            match (prefix.kind(), style) {
+                (path::Prefix::Disk(_), PathStyle::StripDrive) | (path::Prefix::VerbatimDisk(_), PathStyle::StripDrive) => continue,
+                _ => {}
+            }
+        }
+
         if let path::Component::RootDir = component {
         } else {
             if !first {
@@ -37,18 +74,18 @@ pub fn canonicalized_tar_path(dirpath: &path::Path, filetype: header::TarFileTyp
             
             match component {
                 path::Component::Prefix(prefix) => {
-                    //TODO: UNC and VerbatimUNC paths won't roundtrip.
                     match prefix.kind() {
                         path::Prefix::Verbatim(rootpath) => {
                             relapath_encoded.extend(rootpath.to_string_lossy().into_owned().chars());
                         },
                         path::Prefix::VerbatimUNC(server, share) => {
+                            relapath_encoded.push_str("//");
                             relapath_encoded.extend(server.to_string_lossy().into_owned().chars());
                             relapath_encoded.push('/');
                             relapath_encoded.extend(share.to_string_lossy().into_owned().chars());
                         },
                         path::Prefix::VerbatimDisk(letter) => {
-                            relapath_encoded.push((letter as char).to_ascii_lowercase());
+                            relapath_encoded.push_str(&style.drive_component(letter));
                         },
                         path::Prefix::DeviceNS(devicename) => {
                             //ustar+ allows archiving `/dev` on UNIX, but that's because UNIX uses
@@ -60,12 +97,13 @@ pub fn canonicalized_tar_path(dirpath: &path::Path, filetype: header::TarFileTyp
                             relapath_encoded.extend(devicename.to_string_lossy().into_owned().chars());
                         },
                         path::Prefix::UNC(server, share) => {
+                            relapath_encoded.push_str("//");
                             relapath_encoded.extend(server.to_string_lossy().into_owned().chars());
                             relapath_encoded.push('/');
                             relapath_encoded.extend(share.to_string_lossy().into_owned().chars());
                         },
                         path::Prefix::Disk(letter) => {
-                            relapath_encoded.push((letter as char).to_ascii_lowercase());
+                            relapath_encoded.push_str(&style.drive_component(letter));
                         },
                     }
                 },
@@ -86,12 +124,203 @@ pub fn canonicalized_tar_path(dirpath: &path::Path, filetype: header::TarFileTyp
     relapath_encoded
 }
 
+/// How the drive letters of Windows paths are recorded in member names.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PathStyle {
+    /// Record the drive as a lowercase directory, as GNU tar does. (e.g.
+    /// `C:\test.txt` becomes `c/test.txt`)
+    Gnu,
+
+    /// Leave the drive out entirely, so that `C:\test.txt` becomes
+    /// `test.txt`.
+    StripDrive,
+
+    /// Record the drive as it is, so that `C:\test.txt` becomes
+    /// `C:/test.txt` and can be restored to the same drive.
+    KeepDrive,
+}
+
+impl PathStyle {
+    fn drive_component(self, letter: u8) -> String {
+        match self {
+            PathStyle::Gnu => (letter as char).to_ascii_lowercase().to_string(),
+            PathStyle::StripDrive => String::new(),
+            PathStyle::KeepDrive => format!("{}:", letter as char)
+        }
+    }
+}
+
+impl Default for PathStyle {
+    fn default() -> Self {
+        PathStyle::Gnu
+    }
+}
+
+impl FromStr for PathStyle {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gnu" => Ok(PathStyle::Gnu),
+            "strip-drive" => Ok(PathStyle::StripDrive),
+            "keep-drive" => Ok(PathStyle::KeepDrive),
+            _ => Err(())
+        }
+    }
+}
+
+/// Where members archived from UNC paths should be restored to.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UncRestore {
+    /// Restore them relative to the current directory, in a directory named
+    /// for the server, as other tar implementations do.
+    Relative,
+
+    /// Restore them to the share they were archived from.
+    Share,
+}
+
+impl Default for UncRestore {
+    fn default() -> Self {
+        UncRestore::Relative
+    }
+}
+
+impl FromStr for UncRestore {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "relative" => Ok(UncRestore::Relative),
+            "share" => Ok(UncRestore::Share),
+            _ => Err(())
+        }
+    }
+}
+
+/// Given the name of an archive member, determine where it belongs on disk.
+///
+/// This reverses `canonicalized_tar_path`. Members are placed relative to the
+/// current directory, with any leading slashes removed, except for members
+/// archived from UNC paths (named `//server/share/...`), which are placed
+/// according to `unc`. Shares are named with backslashes on Windows, and left
+/// as `//server/share` elsewhere.
+/// 
+/// Members whose names start with a drive (e.g. `c:/test.txt`, as written
+/// with `PathStyle::KeepDrive`) are placed on that drive if `style` is also
+/// `PathStyle::KeepDrive` and we're on Windows. Otherwise, `PathStyle::Gnu`
+/// places them in a directory named for the drive, and
+/// `PathStyle::StripDrive` leaves the drive out.
+pub fn restored_path(name: &path::Path, unc: UncRestore, style: PathStyle) -> path::PathBuf {
+    let name = name.to_string_lossy();
+    let mut components = name.split(|c| c == '/' || (cfg!(windows) && c == '\\')).filter(|component| !component.is_empty());
+    let is_unc = name.starts_with("//") && !name.starts_with("///");
+    let mut restored = path::PathBuf::new();
+
+    if is_unc && unc == UncRestore::Share {
+        if let (Some(server), Some(share)) = (components.next(), components.next()) {
+            restored.push(match cfg!(windows) {
+                true => format!("\\\\{}\\{}\\", server, share),
+                false => format!("//{}/{}/", server, share)
+            });
+        }
+    }
+
+    let mut components = components.peekable();
+    let drive = components.peek().filter(|first| {
+        let first = first.as_bytes();
+
+        !is_unc && first.len() == 2 && first[0].is_ascii_alphabetic() && first[1] == b':'
+    }).map(|first| first.as_bytes()[0]);
+
+    if let Some(letter) = drive {
+        components.next();
+
+        match style {
+            PathStyle::KeepDrive if cfg!(windows) => restored.push(format!("{}:\\", (letter as char).to_ascii_uppercase())),
+            PathStyle::StripDrive => {},
+            _ => restored.push((letter as char).to_ascii_lowercase().to_string())
+        }
+    }
+
+    for component in components {
+        restored.push(component);
+    }
+
+    restored
+}
+
+/// Given the target of a link member, format it for inclusion in a tar header.
+///
+/// Hard links name another member of the archive, so their targets are
+/// canonicalized the same way member names are. Symbolic links can point
+/// anywhere, including outside of the archive, so they are recorded exactly as
+/// they were read.
+pub fn canonicalized_link_target(target: &path::Path, filetype: header::TarFileType, style: PathStyle) -> String {
+    match filetype {
+        header::TarFileType::HardLink => canonicalized_tar_path(target, header::TarFileType::FileStream, style),
+        _ => target.to_string_lossy().into_owned()
+    }
+}
+
+/// Convert the target of a Windows symbolic link or junction, as Windows
+/// reports it, into the form other tar implementations expect.
+///
+/// Windows reports absolute targets as verbatim paths. Their prefix is
+/// removed, so `\\?\C:\dir` becomes `C:/dir` and `\\?\UNC\server\share`
+/// becomes `//server/share`; every other backslash becomes a forward slash.
+/// Targets naming a volume by its GUID have no other form, and keep their
+/// prefix as `//?/`.
+pub fn windows_link_target(target: &str) -> String {
+    let target = match (target.strip_prefix(r"\\?\UNC\"), target.strip_prefix(r"\\?\").or(target.strip_prefix(r"\??\"))) {
+        (Some(unc), _) => format!(r"\\{}", unc),
+        (None, Some(disk)) if disk.chars().nth(1) == Some(':') => disk.to_string(),
+        _ => target.to_string()
+    };
+
+    target.replace('\\', "/")
+}
+
+/// Where a member was written within an archive.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SerializedMember {
+    /// How far into the archive the member's headers begin, in bytes.
+    pub offset: u64,
+
+    /// The number of bytes written for the member, including padding.
+    pub size: u64,
+
+    /// Reading the file's data timed out, so the rest of the member was
+    /// filled with zeroes.
+    pub voided: bool,
+}
+
 /// Given a traversal result, attempt to serialize it's data as tar format data
 /// in the given tarball writer.
 /// 
-/// Returns the number of bytes written to the file/tape.
-pub fn serialize<I>(traversal: &header::HeaderGenResult, tarball: &mut ArchivalSink<I>) -> io::Result<u64> {
+/// `position` is the number of bytes written to the archive so far. It is
+/// advanced past the member once it has been written, so that the offsets of
+/// every member are known without reading the archive back.
+/// 
+/// Returns where the member was written, and how many bytes were written to
+/// the file/tape.
+/// 
+/// If `timeout` is given, any single read of the file's data taking longer
+/// than that gives up on the file. The member is *voided*: the rest of it is
+/// filled with zeroes, so that the archive stays intact, and it is reported
+/// as such.
+/// 
+/// Copying stops with a cancellation error if the operation is cancelled,
+/// leaving the member incomplete. `position` is not advanced for members that
+/// fail to serialize.
+/// 
+/// Sparse members are written as their sparse map, if the format keeps it
+/// with the data, followed by only the data extents of the file.
+pub fn serialize<I>(traversal: &header::HeaderGenResult, tarball: &mut ArchivalSink<I>, position: &mut u64, timeout: Option<time::Duration>, cancel: &CancellationToken) -> io::Result<SerializedMember> {
+    let mut voided = false;
     let mut tarball_size : u64 = 0;
+
+    cancel.check()?;
     
     tarball_size += traversal.encoded_header.len() as u64;
     tarball.write_all(&traversal.encoded_header)?;
@@ -99,6 +328,9 @@ pub fn serialize<I>(traversal: &header::HeaderGenResult, tarball: &mut ArchivalS
     if let header::TarFileType::FileStream = traversal.tar_header.file_type {
         let mut stream_needed = true;
         let mut stream_start = 0;
+
+        tarball_size += traversal.sparse_map_data.len() as u64;
+        tarball.write_all(&traversal.sparse_map_data)?;
         
         if let Some(ref readahead) = traversal.file_prefix {
             tarball_size += readahead.len() as u64;
@@ -111,14 +343,43 @@ pub fn serialize<I>(traversal: &header::HeaderGenResult, tarball: &mut ArchivalS
         }
         
         if stream_needed {
-            let mut source_file = fs::File::open(traversal.canonical_path.as_ref())?;
+            let mut source_file = traversal.open_source()?;
             
             source_file.seek(io::SeekFrom::Start(stream_start))?;
-            
-            tarball_size += io::copy(&mut source_file, tarball)?;
+
+            let source : Box<dyn io::Read + Send> = match traversal.tar_header.sparse_map {
+                Some(ref map) => Box::new(sparse::ExtentReader::new(source_file, map.clone())),
+                None => Box::new(source_file)
+            };
+            let source : Box<dyn io::Read> = match timeout {
+                Some(timeout) => Box::new(TimeoutRead::new(source, timeout)),
+                None => source
+            };
+            let mut source = CancellableRead::new(source, cancel.clone());
+            let mut buf = vec![0; 64 * 1024];
+
+            loop {
+                let read = match source.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(ref e) if e.kind() == io::ErrorKind::TimedOut => {
+                        voided = true;
+                        break;
+                    },
+                    Err(e) => return Err(e)
+                };
+
+                tarball.write_all(&buf[..read])?;
+                tarball_size += read as u64;
+            }
         }
         
-        let expected_size = traversal.encoded_header.len() as u64 + traversal.tar_header.file_size;
+        let expected_size = traversal.encoded_header.len() as u64 + traversal.data_size();
+
+        if voided {
+            tarball_size += io::copy(&mut io::repeat(0).take(expected_size.saturating_sub(tarball_size)), tarball)?;
+        }
         
         if tarball_size != expected_size {
             //TODO: If we error out the write count is wrong. Need an out-of-bound error reporting mechanism.
@@ -128,9 +389,191 @@ pub fn serialize<I>(traversal: &header::HeaderGenResult, tarball: &mut ArchivalS
     
     let padding_needed = tarball_size % 512;
     if padding_needed != 0 {
-        tarball_size += padding_needed;
+        tarball_size += 512 - padding_needed;
         tarball.write_all(&vec![0; (512 - padding_needed) as usize])?;
     }
+
+    let member = SerializedMember {
+        offset: *position,
+        size: tarball_size,
+        voided: voided
+    };
+
+    *position += tarball_size;
     
-    Ok(tarball_size)
+    Ok(member)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, io, path, process};
+    use crate::cancel::CancellationToken;
+    use crate::fs::AtimePolicy;
+    use crate::tar::header::{TarFormat, TarHeader, headergen};
+    use crate::tar::reader::TarReader;
+    use super::{serialize, restored_path, windows_link_target, UncRestore, PathStyle};
+
+    #[test]
+    fn serialize_member_offsets() {
+        let dir = env::temp_dir().join(format!("rapidtar-serialize-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut archive = io::Cursor::new(vec![]);
+        let mut position = 0;
+        let mut offsets = vec![];
+
+        for (name, size) in [("small", 5), ("large", 600)].iter() {
+            let entry_path = dir.join(name);
+            fs::write(&entry_path, vec![b'x'; *size]).unwrap();
+
+            let metadata = fs::symlink_metadata(&entry_path).unwrap();
+            let header = TarHeader::abstract_header_for_file(path::Path::new(name), &metadata, &entry_path).unwrap();
+            let hg = headergen(&entry_path, path::Path::new(name), header, TarFormat::USTAR, AtimePolicy::Ignore, 64 * 1024, &CancellationToken::new()).unwrap();
+            let member = serialize::<u64>(&hg, &mut archive, &mut position, None, &CancellationToken::new()).unwrap();
+
+            offsets.push(member.offset);
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(offsets, vec![0, 1024]);
+        assert_eq!(position, 1024 + 512 + 1024);
+        assert_eq!(archive.get_ref().len() as u64, position);
+
+        let mut reader = TarReader::new(io::Cursor::new(archive.into_inner()));
+        assert_eq!(reader.next_entry().unwrap().unwrap().header_offset, offsets[0]);
+        assert_eq!(reader.next_entry().unwrap().unwrap().header_offset, offsets[1]);
+    }
+
+    #[test]
+    fn serialize_readahead_limits() {
+        let entry_path = env::temp_dir().join(format!("rapidtar-readahead-{}", process::id()));
+        let data : Vec<u8> = (0..3000).map(|i| i as u8).collect();
+        fs::write(&entry_path, &data).unwrap();
+
+        let metadata = fs::symlink_metadata(&entry_path).unwrap();
+        let archives : Vec<Vec<u8>> = [0, 100, 64 * 1024].iter().map(|limit| {
+            let header = TarHeader::abstract_header_for_file(path::Path::new("file"), &metadata, &entry_path).unwrap();
+            let hg = headergen(&entry_path, path::Path::new("file"), header, TarFormat::USTAR, AtimePolicy::Ignore, *limit, &CancellationToken::new()).unwrap();
+            let mut archive = io::Cursor::new(vec![]);
+
+            assert_eq!(hg.file_prefix.as_ref().map(|prefix| prefix.len()), match limit {
+                0 => None,
+                limit => Some((*limit as usize).min(data.len()))
+            });
+
+            serialize::<u64>(&hg, &mut archive, &mut 0, None, &CancellationToken::new()).unwrap();
+            archive.into_inner()
+        }).collect();
+
+        fs::remove_file(&entry_path).unwrap();
+
+        assert!(archives[0].ends_with(&[&data[..], &[0; 72][..]].concat()));
+        assert_eq!(archives[0], archives[1]);
+        assert_eq!(archives[0], archives[2]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn serialize_symlink_targets() {
+        let dir = env::temp_dir().join(format!("rapidtar-symlink-{}", process::id()));
+        let long_target = "../".repeat(40) + "target";
+        fs::create_dir_all(&dir).unwrap();
+        std::os::unix::fs::symlink("target", dir.join("short")).unwrap();
+        std::os::unix::fs::symlink(&long_target, dir.join("long")).unwrap();
+
+        for format in [TarFormat::USTAR, TarFormat::POSIX, TarFormat::GNU].iter() {
+            let mut archive = io::Cursor::new(vec![]);
+            let mut results = vec![];
+
+            for name in ["short", "long"].iter() {
+                let entry_path = dir.join(name);
+                let metadata = fs::symlink_metadata(&entry_path).unwrap();
+                let header = TarHeader::abstract_header_for_file(path::Path::new(name), &metadata, &entry_path).unwrap();
+
+                match headergen(&entry_path, path::Path::new(name), header, *format, AtimePolicy::Ignore, 0, &CancellationToken::new()) {
+                    Ok(hg) => results.push(serialize::<u64>(&hg, &mut archive, &mut 0, None, &CancellationToken::new()).map(|_| ())),
+                    Err(e) => results.push(Err(e))
+                }
+            }
+
+            //USTAR has nowhere to put a target this long.
+            assert!(results[0].is_ok());
+            assert_eq!(results[1].is_ok(), *format != TarFormat::USTAR);
+
+            let mut reader = TarReader::new(io::Cursor::new(archive.into_inner()));
+            let short = reader.next_entry().unwrap().unwrap();
+            assert_eq!(short.header.symlink_path.unwrap().to_str(), Some("target"));
+
+            if *format != TarFormat::USTAR {
+                let long = reader.next_entry().unwrap().unwrap();
+                assert_eq!(long.header.symlink_path.unwrap().to_str(), Some(long_target.as_str()));
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn restored_paths() {
+        let restore = |name: &str, unc: UncRestore| restored_path(path::Path::new(name), unc, PathStyle::Gnu);
+
+        assert_eq!(restore("dir/file", UncRestore::Share), path::PathBuf::from("dir/file"));
+        assert_eq!(restore("/etc/passwd", UncRestore::Share), path::PathBuf::from("etc/passwd"));
+        assert_eq!(restore("///etc/passwd", UncRestore::Share), path::PathBuf::from("etc/passwd"));
+        assert_eq!(restore("//server/share/dir/file", UncRestore::Relative), path::PathBuf::from("server/share/dir/file"));
+
+        #[cfg(windows)]
+        assert_eq!(restore("//server/share/dir/file", UncRestore::Share), path::PathBuf::from(r"\\server\share\dir\file"));
+
+        #[cfg(not(windows))]
+        assert_eq!(restore("//server/share/dir/file", UncRestore::Share), path::PathBuf::from("//server/share/dir/file"));
+    }
+
+    #[test]
+    fn restored_drive_paths() {
+        let restore = |name: &str, style: PathStyle| restored_path(path::Path::new(name), UncRestore::Relative, style);
+
+        assert_eq!(restore("C:/dir/file", PathStyle::Gnu), path::PathBuf::from("c/dir/file"));
+        assert_eq!(restore("C:/dir/file", PathStyle::StripDrive), path::PathBuf::from("dir/file"));
+        assert_eq!(restore("c/dir/file", PathStyle::KeepDrive), path::PathBuf::from("c/dir/file"));
+        assert_eq!(restore("dir/c:/file", PathStyle::StripDrive), path::PathBuf::from("dir/c:/file"));
+
+        #[cfg(windows)]
+        assert_eq!(restore("c:/dir/file", PathStyle::KeepDrive), path::PathBuf::from(r"C:\dir\file"));
+
+        #[cfg(not(windows))]
+        assert_eq!(restore("c:/dir/file", PathStyle::KeepDrive), path::PathBuf::from("c/dir/file"));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn unc_paths_roundtrip() {
+        for original in [r"\\server\share\dir\file", r"\\?\UNC\server\share\dir\file"].iter() {
+            let name = super::canonicalized_tar_path(path::Path::new(original), crate::tar::header::TarFileType::FileStream, PathStyle::Gnu);
+
+            assert_eq!(name, "//server/share/dir/file");
+            assert_eq!(restored_path(path::Path::new(&name), UncRestore::Share, PathStyle::Gnu), path::PathBuf::from(r"\\server\share\dir\file"));
+        }
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn drive_paths_roundtrip() {
+        let encode = |style: PathStyle| super::canonicalized_tar_path(path::Path::new(r"C:\dir\file"), crate::tar::header::TarFileType::FileStream, style);
+
+        assert_eq!(encode(PathStyle::Gnu), "c/dir/file");
+        assert_eq!(encode(PathStyle::StripDrive), "dir/file");
+        assert_eq!(encode(PathStyle::KeepDrive), "C:/dir/file");
+        assert_eq!(restored_path(path::Path::new(&encode(PathStyle::KeepDrive)), UncRestore::Relative, PathStyle::KeepDrive), path::PathBuf::from(r"C:\dir\file"));
+    }
+
+    #[test]
+    fn windows_link_targets() {
+        assert_eq!(windows_link_target(r"..\sibling\file"), "../sibling/file");
+        assert_eq!(windows_link_target(r"\\?\C:\dir"), "C:/dir");
+        assert_eq!(windows_link_target(r"\??\C:\dir"), "C:/dir");
+        assert_eq!(windows_link_target(r"\\?\UNC\server\share\dir"), "//server/share/dir");
+        assert_eq!(windows_link_target(r"\\?\Volume{01234567-89ab-cdef-0123-456789abcdef}\dir"), "//?/Volume{01234567-89ab-cdef-0123-456789abcdef}/dir");
+    }
 }
@@ -2,11 +2,14 @@
 
 use std::{io, path, time, ffi};
 use crate::tar::ustar;
-use crate::tar::ustar::{format_tar_numeral, format_tar_string};
+use crate::tar::ustar::{format_tar_numeral, format_tar_string, format_tar_linkname};
 use crate::tar::gnu::{format_gnu_numeral, format_gnu_time};
 use crate::tar::header::{TarHeader, TarFileType};
-use crate::tar::label::TarLabel;
-use crate::tar::canonicalized_tar_path;
+use crate::tar::label::{TarLabel, volume_header_attributes};
+use crate::tar::{canonicalized_tar_path, canonicalized_link_target, sparse, acl, PathStyle};
+
+/// The pax attribute holding a member's Linux project ID.
+pub const PROJECT_ID_KEY: &str = "RAPIDTAR.project_id";
 
 /// Format a key-value pair in pax format.
 /// 
@@ -17,8 +20,14 @@ use crate::tar::canonicalized_tar_path;
 /// Yes, that length value includes the length of itself, which is a fun
 /// challenge.
 fn format_pax_attribute(key: &str, val: &str) -> Vec<u8> {
+    format_pax_binary_attribute(key, val.as_bytes())
+}
+
+/// Format a pax attribute whose value is arbitrary bytes, such as an extended
+/// attribute. The length prefix makes this safe, even if other readers will
+/// mangle any value that isn't UTF-8.
+fn format_pax_binary_attribute(key: &str, val_bytes: &[u8]) -> Vec<u8> {
     let key_bytes = key.as_bytes();
-    let val_bytes = val.as_bytes();
     let minimum_length = 1 + key_bytes.len() + 1 + val_bytes.len() + 1; //space, key, equals, val, newline
     let mut number_length = (minimum_length as f32).log(10.0).floor() as usize + 1; //not ceil() because even zero needs to be one, ten needs to be two, etc
     
@@ -37,10 +46,80 @@ fn format_pax_attribute(key: &str, val: &str) -> Vec<u8> {
     result
 }
 
+/// Parse a stream of pax attributes, as found in the data of an extended
+/// header.
+/// 
+/// Attributes are returned in the order they appear. The stream may be padded
+/// out with nulls, which are ignored.
+pub fn parse_pax_attributes(stream: &[u8]) -> io::Result<Vec<(String, String)>> {
+    Ok(parse_pax_binary_attributes(stream)?.into_iter().map(|(key, value)| (key, String::from_utf8_lossy(&value).into_owned())).collect())
+}
+
+/// Parse the records of a pax extended header, leaving their values as bytes.
+///
+/// Some records, such as extended attributes, hold binary data which would be
+/// mangled by `parse_pax_attributes`.
+pub fn parse_pax_binary_attributes(stream: &[u8]) -> io::Result<Vec<(String, Vec<u8>)>> {
+    let mut attributes = Vec::new();
+    let mut remaining = stream;
+
+    while let Some(first) = remaining.first() {
+        if *first == 0 {
+            break;
+        }
+
+        let space = remaining.iter().position(|b| *b == b' ').ok_or(io::Error::new(io::ErrorKind::InvalidData, "Malformed pax attribute length"))?;
+        let length : usize = std::str::from_utf8(&remaining[..space]).ok().and_then(|l| l.parse().ok()).ok_or(io::Error::new(io::ErrorKind::InvalidData, "Malformed pax attribute length"))?;
+
+        if length <= space + 1 || length > remaining.len() || remaining[length - 1] != b'\n' {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed pax attribute"));
+        }
+
+        let record = &remaining[space + 1..length - 1];
+        let equals = record.iter().position(|b| *b == b'=').ok_or(io::Error::new(io::ErrorKind::InvalidData, "Pax attribute has no value"))?;
+
+        attributes.push((String::from_utf8_lossy(&record[..equals]).into_owned(), record[equals + 1..].to_vec()));
+        remaining = &remaining[length..];
+    }
+
+    Ok(attributes)
+}
+
+/// Format the size field of an extended header.
+///
+/// USTAR size fields hold at most 8GB, and pax has no way to enlarge an
+/// extended header's own size: a `size` record describes the member that
+/// follows it. Extended header streams that large are written with a GNU
+/// base-256 size instead, which GNU tar, star, and libarchive all accept.
+/// Splitting the stream across several consecutive extended headers is not an
+/// option, as GNU tar only honors the last of them, and a single record may
+/// exceed 8GB by itself anyway.
+fn format_extended_header_size(size: u64) -> io::Result<Vec<u8>> {
+    format_gnu_numeral(size, 12).ok_or(io::Error::new(io::ErrorKind::InvalidData, "Extended header is too long"))
+}
+
+/// Format a time as decimal seconds since the UNIX epoch, with as much of the
+/// fractional part as is needed to represent it exactly.
+///
+/// Windows file times have 100ns precision, so full precision needs seven
+/// decimal places; UNIX file times may need all nine.
 fn format_pax_time(dirtime: &time::SystemTime) -> io::Result<String> {
+    let (sign, offset) = match dirtime.duration_since(time::UNIX_EPOCH) {
+        Ok(unix_duration) => ("", unix_duration),
+        Err(e) => ("-", e.duration())
+    };
+
+    match offset.subsec_nanos() {
+        0 => Ok(format!("{}{}", sign, offset.as_secs())),
+        nanos => Ok(format!("{}{}.{}", sign, offset.as_secs(), format!("{:09}", nanos).trim_end_matches('0')))
+    }
+}
+
+/// Determine if a time has a fractional part that a ustar header would lose.
+fn has_subsecond_time(dirtime: &time::SystemTime) -> bool {
     match dirtime.duration_since(time::UNIX_EPOCH) {
-        Ok(unix_duration) => Ok(format!("{}", unix_duration.as_secs())),
-        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "File older than UNIX")) //TODO: Negative time
+        Ok(unix_duration) => unix_duration.subsec_nanos() != 0,
+        Err(e) => e.duration().subsec_nanos() != 0
     }
 }
 
@@ -136,7 +215,7 @@ pub fn format_pax_legacy_filename(canonical_path: &String) -> io::Result<(Vec<u8
 /// Given a path to an archived file, compute the fallback path for that file,
 /// such that it's extended header will be unarchived to a neighboring directory
 /// when processed by a non-PAX tar implementation.
-pub fn compute_fallback_path<P: AsRef<path::Path>>(path: &P, filetype: TarFileType) -> io::Result<(Vec<u8>, Vec<u8>, bool)> {
+pub fn compute_fallback_path<P: AsRef<path::Path>>(path: &P, filetype: TarFileType, style: PathStyle) -> io::Result<(Vec<u8>, Vec<u8>, bool)> {
     let pathref = path.as_ref();
     let mut component_count = 0;
     for _ in pathref.components() {
@@ -153,7 +232,7 @@ pub fn compute_fallback_path<P: AsRef<path::Path>>(path: &P, filetype: TarFileTy
         pax_prefixed_path.push(pathref.to_path_buf());
     }
     
-    format_pax_legacy_filename(&canonicalized_tar_path(&pax_prefixed_path, filetype))
+    format_pax_legacy_filename(&canonicalized_tar_path(&pax_prefixed_path, filetype, style))
 }
 
 /// Given a directory entry, form a tar header for that given entry.
@@ -202,14 +281,32 @@ pub fn compute_fallback_path<P: AsRef<path::Path>>(path: &P, filetype: TarFileTy
 /// * Files larger than 1YB will not be extractable on pre-POSIX GNU tar
 ///   implementations. I do not expect this to be a concern for some time, if
 ///   ever.
+/// * Files with a sparse map are written in GNU sparse format 1.0, which
+///   other implementations extract as a file of packed data extents within a
+///   `GNUSparseFile.0` directory. See `tar::sparse`.
+/// * Extended headers larger than 8GB, which only enormous sets of extended
+///   attributes will produce, are given a GNU base-256 size. Implementations
+///   which don't understand those will not be able to extract the member.
 pub fn pax_header(tarheader: &TarHeader) -> io::Result<Vec<u8>> {
-    let mut item_path = tarheader.path.clone();
+    let sparse_map = match tarheader.file_type {
+        TarFileType::FileStream => tarheader.sparse_map.as_ref(),
+        _ => None
+    };
+    let stored_size = match sparse_map {
+        Some(map) => sparse::format_sparse_map(map).len() as u64 + sparse::data_size(map),
+        None => tarheader.file_size
+    };
+
+    let mut item_path = match sparse_map {
+        Some(_) => Box::new(sparse::sparse_member_name(&tarheader.path)),
+        None => tarheader.path.clone()
+    };
     if let TarFileType::Directory = tarheader.file_type {
         item_path.push(&ffi::OsString::from(""));
     }
     
     //First, compute the PAX extended header stream
-    let canonical_path = canonicalized_tar_path(&item_path, tarheader.file_type);
+    let canonical_path = canonicalized_tar_path(&item_path, tarheader.file_type, tarheader.path_style);
     let (relapath_unix, relapath_extended, legacy_format_truncated) = format_pax_legacy_filename(&canonical_path)?;
     
     assert_eq!(relapath_unix.len(), 100);
@@ -221,16 +318,38 @@ pub fn pax_header(tarheader: &TarHeader) -> io::Result<Vec<u8>> {
     
     let mut extended_stream : Vec<u8> = Vec::with_capacity(512);
     
-    if let None = format_tar_numeral(tarheader.file_size, 12) {
-        extended_stream.extend(format_pax_attribute("size", &format!("{}", tarheader.file_size)));
+    if let None = format_tar_numeral(stored_size, 12) {
+        extended_stream.extend(format_pax_attribute("size", &format!("{}", stored_size)));
     }
     
     if legacy_format_truncated {
         extended_stream.extend(format_pax_attribute("path", &canonical_path));
     }
+
+    if sparse_map.is_some() {
+        extended_stream.extend(format_pax_attribute("GNU.sparse.major", "1"));
+        extended_stream.extend(format_pax_attribute("GNU.sparse.minor", "0"));
+        extended_stream.extend(format_pax_attribute("GNU.sparse.name", &canonicalized_tar_path(&tarheader.path, tarheader.file_type, tarheader.path_style)));
+        extended_stream.extend(format_pax_attribute("GNU.sparse.realsize", &format!("{}", tarheader.file_size)));
+    }
+
+    let link_target = tarheader.symlink_path.as_ref().map(|target| canonicalized_link_target(target, tarheader.file_type, tarheader.path_style));
+    let ustar_linkname = match link_target {
+        Some(ref link_target) => match format_tar_linkname(link_target) {
+            Some(linkname) => linkname,
+            None => {
+                extended_stream.extend(format_pax_attribute("linkpath", link_target));
+
+                let mut truncated = link_target.replace(|c: char| !c.is_ascii(), "").into_bytes();
+                truncated.resize(100, 0);
+                truncated
+            }
+        },
+        None => vec![0; 100]
+    };
     
-    if let None = ustar_mtime {
-        if let Some(mtime) = tarheader.mtime {
+    if let Some(mtime) = tarheader.mtime {
+        if ustar_mtime.is_none() || has_subsecond_time(&mtime) {
             extended_stream.extend(format_pax_attribute("mtime", &format_pax_time(&mtime)?));
         }
     }
@@ -239,6 +358,10 @@ pub fn pax_header(tarheader: &TarHeader) -> io::Result<Vec<u8>> {
         extended_stream.extend(format_pax_attribute("atime", &format_pax_time(&atime)?));
     }
 
+    if let Some(ctime) = tarheader.ctime {
+        extended_stream.extend(format_pax_attribute("ctime", &format_pax_time(&ctime)?));
+    }
+
     if let None = ustar_uname {
         extended_stream.extend(format_pax_attribute("uname", &tarheader.unix_uname));
     }
@@ -251,20 +374,34 @@ pub fn pax_header(tarheader: &TarHeader) -> io::Result<Vec<u8>> {
         extended_stream.extend(format_pax_attribute("LIBARCHIVE.creationtime", &format_pax_time(&birthtime)?));
     }
 
+    if let Some(project_id) = tarheader.project_id {
+        extended_stream.extend(format_pax_attribute(PROJECT_ID_KEY, &format!("{}", project_id)));
+    }
+
+    if let Some(ref acl) = tarheader.acl_access {
+        extended_stream.extend(format_pax_attribute(acl::ACCESS_KEY, &acl.to_string()));
+    }
+
+    if let (Some(ref acl), TarFileType::Directory) = (&tarheader.acl_default, tarheader.file_type) {
+        extended_stream.extend(format_pax_attribute(acl::DEFAULT_KEY, &acl.to_string()));
+    }
+
+    //Extended attributes are stored the way star and GNU tar do it.
+    for (name, value) in tarheader.xattrs.iter() {
+        extended_stream.extend(format_pax_binary_attribute(&format!("SCHILY.xattr.{}", name), value));
+    }
+
     let mut header : Vec<u8> = Vec::with_capacity(1536);
     
     //sup dawg, I heard u like headers so we put a header on your header
     if extended_stream.len() > 0 {
-        let (pax_relapath_unix, pax_relapath_extended, _) = compute_fallback_path(&tarheader.path.as_ref(), tarheader.file_type)?;
+        let (pax_relapath_unix, pax_relapath_extended, _) = compute_fallback_path(&tarheader.path.as_ref(), tarheader.file_type, tarheader.path_style)?;
         
-        //TODO: What if the extended header exceeds 8GB?
-        //We're using GNU numerals for now, but that's probably not the correct
-        //behavior.
         header.extend(pax_relapath_unix); //Last 100 bytes of path
         header.extend(format_gnu_numeral(tarheader.unix_mode, 8).ok_or(io::Error::new(io::ErrorKind::InvalidData, "UNIX mode is too long"))?); //mode
         header.extend(format_gnu_numeral(tarheader.unix_uid, 8).unwrap_or(vec![0; 8]));
         header.extend(format_gnu_numeral(tarheader.unix_gid, 8).unwrap_or(vec![0; 8]));
-        header.extend(format_gnu_numeral(extended_stream.len() as u64, 12).ok_or(io::Error::new(io::ErrorKind::InvalidData, "File extended header is too long"))?); //File size
+        header.extend(format_extended_header_size(extended_stream.len() as u64)?); //File size
         header.extend(ustar_mtime.clone().unwrap_or(vec![0; 12])); //mtime
         header.extend("        ".as_bytes()); //checksummable format checksum value
         header.extend("x".as_bytes());
@@ -291,14 +428,14 @@ pub fn pax_header(tarheader: &TarHeader) -> io::Result<Vec<u8>> {
     header.extend(format_gnu_numeral(tarheader.unix_uid, 8).unwrap_or(vec![0; 8])); //TODO: UID
     header.extend(format_gnu_numeral(tarheader.unix_gid, 8).unwrap_or(vec![0; 8])); //TODO: GID
     if let TarFileType::FileStream = tarheader.file_type {
-        header.extend(format_gnu_numeral(tarheader.file_size, 12).unwrap_or(vec![0; 12])); //File size
+        header.extend(format_gnu_numeral(stored_size, 12).unwrap_or(vec![0; 12])); //File size
     } else {
         header.extend(format_gnu_numeral(0, 12).unwrap_or(vec![0; 12])); //Non-file entries must have a size of 0, or 7zip tries to skip them
     }
     header.extend(ustar_mtime.unwrap_or(vec![0; 12])); //mtime
     header.extend("        ".as_bytes()); //checksummable format checksum value
     header.push(tarheader.file_type.type_flag() as u8); //File type
-    header.extend(vec![0; 100]); //TODO: Link name
+    header.extend(ustar_linkname); //Link name
     header.extend("ustar\0".as_bytes()); //magic 'ustar\0'
     header.extend("00".as_bytes()); //version 00
     header.extend(ustar_uname.unwrap_or(vec![0; 32])); //TODO: UID Name
@@ -323,9 +460,13 @@ pub fn pax_label(tarlabel: &TarLabel) -> io::Result<Vec<u8>> {
         extended_stream.extend(format_pax_attribute("GNU.volume.label", &label_str));
     }
 
+    for (key, value) in volume_header_attributes(tarlabel) {
+        extended_stream.extend(format_pax_attribute(key, &value));
+    }
+
     if let Some(recovery_file_type) = tarlabel.recovery_file_type {
         if let Some(ref recovery_path) = tarlabel.recovery_path {
-            let canonical_recovery_path = canonicalized_tar_path(&recovery_path.clone(), recovery_file_type);
+            let canonical_recovery_path = canonicalized_tar_path(&recovery_path.clone(), recovery_file_type, tarlabel.recovery_path_style);
             extended_stream.extend(format_pax_attribute("GNU.volume.filename", &canonical_recovery_path));
         }
         
@@ -341,7 +482,7 @@ pub fn pax_label(tarlabel: &TarLabel) -> io::Result<Vec<u8>> {
     if extended_stream.len() > 0 {
         let mut label = Vec::with_capacity(512 + extended_stream.len());
 
-        let (relapath_unix, relapath_extended, _) = format_pax_legacy_filename(&canonicalized_tar_path(&label_path, TarFileType::FileStream))?;
+        let (relapath_unix, relapath_extended, _) = format_pax_legacy_filename(&canonicalized_tar_path(&label_path, TarFileType::FileStream, PathStyle::default()))?;
 
         assert_eq!(relapath_unix.len(), 100);
         assert_eq!(relapath_extended.len(), 155);
@@ -350,7 +491,7 @@ pub fn pax_label(tarlabel: &TarLabel) -> io::Result<Vec<u8>> {
         label.extend(format_gnu_numeral(0o644, 8).ok_or(io::Error::new(io::ErrorKind::InvalidData, "UNIX mode is too long"))?); //mode
         label.extend(format_gnu_numeral(0, 8).unwrap_or(vec![0; 8])); //TODO: UID
         label.extend(format_gnu_numeral(0, 8).unwrap_or(vec![0; 8])); //TODO: GID
-        label.extend(format_gnu_numeral(extended_stream.len() as u64, 12).unwrap_or(vec![0; 12])); //File size
+        label.extend(format_extended_header_size(extended_stream.len() as u64)?); //File size
         label.extend(format_gnu_time(&time::SystemTime::now()).unwrap_or(vec![0; 12])); //mtime
         label.extend("        ".as_bytes()); //checksummable format checksum value
         label.extend("g".as_bytes()); //File type
@@ -402,10 +543,32 @@ pub fn checksum_header(header: &mut Vec<u8>) {
 
 #[cfg(test)]
 mod tests {
-    use std::{path};
-    use crate::tar::pax::{format_pax_attribute, format_pax_legacy_filename, canonicalized_tar_path};
+    use std::{path, time};
+    use crate::tar::gnu::parse_gnu_numeral;
+    use crate::tar::pax::{format_extended_header_size, format_pax_attribute, format_pax_binary_attribute, format_pax_time, format_pax_legacy_filename, canonicalized_tar_path, parse_pax_attributes};
+    use crate::tar::PathStyle;
     use crate::tar::header::TarFileType;
     
+    #[test]
+    fn extended_header_size() {
+        assert_eq!(format_extended_header_size(1536).unwrap(), b"00000003000\0".to_vec());
+        assert_eq!(format_extended_header_size((1 << 33) - 1).unwrap(), b"77777777777\0".to_vec());
+
+        let huge = format_extended_header_size(1 << 33).unwrap();
+
+        assert_eq!(huge, vec![0x80, 0, 0, 0, 0, 0, 0, 0x02, 0, 0, 0, 0]);
+        assert_eq!(parse_gnu_numeral(&huge), Some(1 << 33));
+    }
+
+    #[test]
+    fn pax_time() {
+        let filetime = time::UNIX_EPOCH + time::Duration::new(1_500_000_000, 123_456_700);
+        assert_eq!(format_pax_time(&filetime).unwrap(), "1500000000.1234567");
+        assert_eq!(format_pax_time(&(time::UNIX_EPOCH + time::Duration::from_secs(42))).unwrap(), "42");
+        assert_eq!(format_pax_time(&(time::UNIX_EPOCH - time::Duration::new(10, 500_000_000))).unwrap(), "-10.5");
+        assert_eq!(format_pax_time(&(time::UNIX_EPOCH - time::Duration::new(123, 456_789_000))).unwrap(), "-123.456789");
+    }
+    
     #[test]
     fn pax_attribute() {
         let fmtd = format_pax_attribute("x", "y");
@@ -413,6 +576,23 @@ mod tests {
         assert_eq!(fmtd, "6 x=y\n".as_bytes());
     }
     
+    #[test]
+    fn pax_binary_attribute() {
+        let fmtd = format_pax_binary_attribute("SCHILY.xattr.k", &[0, 0xff]);
+
+        assert_eq!(fmtd, b"21 SCHILY.xattr.k=\0\xff\n".to_vec());
+    }
+    
+    #[test]
+    fn pax_attribute_parse() {
+        let mut stream = format_pax_attribute("path", "a=b");
+        stream.extend(format_pax_attribute("size", "12"));
+        stream.extend(vec![0; 20]);
+
+        assert_eq!(parse_pax_attributes(&stream).unwrap(), vec![("path".to_string(), "a=b".to_string()), ("size".to_string(), "12".to_string())]);
+        assert!(parse_pax_attributes("9 x=y\n".as_bytes()).is_err());
+    }
+    
     #[test]
     fn pax_attribute_longkey() {
         let fmtd = format_pax_attribute("xxxxxx", "y");
@@ -443,7 +623,7 @@ mod tests {
     
     #[test]
     fn pax_legacy_filename_short() {
-        let (old, posix, was_truncated) = format_pax_legacy_filename(&canonicalized_tar_path(path::Path::new("quux"), TarFileType::FileStream)).unwrap();
+        let (old, posix, was_truncated) = format_pax_legacy_filename(&canonicalized_tar_path(path::Path::new("quux"), TarFileType::FileStream, PathStyle::Gnu)).unwrap();
         
         assert_eq!(was_truncated, false);
         assert_eq!(old.len(), 100);
@@ -455,7 +635,7 @@ mod tests {
     
     #[test]
     fn pax_legacy_filename_medium() {
-        let (old, posix, was_truncated) = format_pax_legacy_filename(&canonicalized_tar_path(path::Path::new("1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/quux"), TarFileType::FileStream)).unwrap();
+        let (old, posix, was_truncated) = format_pax_legacy_filename(&canonicalized_tar_path(path::Path::new("1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/quux"), TarFileType::FileStream, PathStyle::Gnu)).unwrap();
         
         assert_eq!(was_truncated, false);
         assert_eq!(old.len(), 100);
@@ -468,7 +648,7 @@ mod tests {
     
     #[test]
     fn pax_legacy_filename_long() {
-        let (old, posix, was_truncated) = format_pax_legacy_filename(&canonicalized_tar_path(path::Path::new("1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/vqw/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/quux"), TarFileType::FileStream)).unwrap();
+        let (old, posix, was_truncated) = format_pax_legacy_filename(&canonicalized_tar_path(path::Path::new("1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/vqw/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/quux"), TarFileType::FileStream, PathStyle::Gnu)).unwrap();
         
         assert_eq!(was_truncated, true);
         assert_eq!(old.len(), 100);
@@ -480,7 +660,7 @@ mod tests {
     
     #[test]
     fn pax_legacy_filename_long_tricky() {
-        let (old, posix, was_truncated) = format_pax_legacy_filename(&canonicalized_tar_path(path::Path::new("1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/uqv/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/quux"), TarFileType::FileStream)).unwrap();
+        let (old, posix, was_truncated) = format_pax_legacy_filename(&canonicalized_tar_path(path::Path::new("1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/uqv/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/quux"), TarFileType::FileStream, PathStyle::Gnu)).unwrap();
         
         assert_eq!(was_truncated, true);
         assert_eq!(old.len(), 100);
@@ -0,0 +1,98 @@
+//! Deciding what permissions extracted members get.
+//!
+//! By default, as with other tars run by ordinary users, extracted members get
+//! their archived permissions less the process's umask. Setuid, setgid, and
+//! sticky bits are never restored this way: an archive from elsewhere could
+//! otherwise plant a setuid binary without anyone noticing. Restoring
+//! permissions exactly, special bits included, has to be asked for, and
+//! callers should confirm members with special bits before restoring them.
+//!
+//! # Default ACLs
+//!
+//! On filesystems with POSIX ACLs, a directory's default ACL replaces the
+//! umask for anything created in it. Rather than apply the umask itself, the
+//! umask policy passes archived permissions to the OS when creating a member
+//! (see `creation_mode`) and lets the OS apply the umask or default ACL, as it
+//! does for any other program. Only members which already existed, and so
+//! keep their old permissions when opened, have the umask applied by hand.
+//! Exact permissions are set explicitly, overriding default ACLs.
+
+/// The setuid, setgid, and sticky bits.
+pub const SPECIAL_BITS: u32 = 0o7000;
+
+/// The umask assumed where the platform doesn't have one.
+pub const DEFAULT_UMASK: u32 = 0o022;
+
+/// How the permissions of extracted members are decided.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum PermissionPolicy {
+    /// Apply the given umask to archived permissions, and drop any setuid,
+    /// setgid, or sticky bits (`--no-same-permissions`).
+    Umask(u32),
+
+    /// Restore archived permissions exactly, special bits included (`-p`).
+    Exact,
+}
+
+impl Default for PermissionPolicy {
+    fn default() -> Self {
+        PermissionPolicy::Umask(DEFAULT_UMASK)
+    }
+}
+
+impl PermissionPolicy {
+    /// Apply the umask this process was started with.
+    pub fn umask_from_process() -> PermissionPolicy {
+        PermissionPolicy::Umask(crate::fs::process_umask().unwrap_or(DEFAULT_UMASK))
+    }
+
+    /// The permissions to create a member with, given it's archived mode.
+    ///
+    /// The OS applies it's umask, or the parent directory's default ACL, to
+    /// these. Special bits are left for `restored_mode` to set, once the
+    /// member's contents and ownership are in place.
+    pub fn creation_mode(&self, unix_mode: u32) -> u32 {
+        unix_mode & 0o777
+    }
+
+    /// The permissions to explicitly set on a member after extracting it, if
+    /// any.
+    ///
+    /// `created` tells if the member was newly created, in which case the OS
+    /// has already applied the umask or default ACL to `creation_mode`.
+    pub fn restored_mode(&self, unix_mode: u32, created: bool) -> Option<u32> {
+        match self {
+            PermissionPolicy::Exact => Some(unix_mode & 0o7777),
+            PermissionPolicy::Umask(_) if created => None,
+            PermissionPolicy::Umask(umask) => Some(unix_mode & 0o777 & !umask)
+        }
+    }
+
+    /// Determine if restoring a member's permissions would set special bits,
+    /// which callers should confirm first.
+    pub fn needs_confirmation(&self, unix_mode: u32) -> bool {
+        *self == PermissionPolicy::Exact && unix_mode & SPECIAL_BITS != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::PermissionPolicy;
+
+    #[test]
+    fn permission_policy() {
+        let umask = PermissionPolicy::Umask(0o027);
+
+        assert_eq!(umask.creation_mode(0o104755), 0o755);
+        assert_eq!(umask.restored_mode(0o104755, true), None);
+        assert_eq!(umask.restored_mode(0o104755, false), Some(0o750));
+        assert!(!umask.needs_confirmation(0o104755));
+
+        let exact = PermissionPolicy::Exact;
+
+        assert_eq!(exact.creation_mode(0o41777), 0o777);
+        assert_eq!(exact.restored_mode(0o41777, true), Some(0o1777));
+        assert!(exact.needs_confirmation(0o41777));
+        assert!(!exact.needs_confirmation(0o100644));
+    }
+}
@@ -0,0 +1,198 @@
+//! Progress records for resumable restores.
+//!
+//! Restoring a multi-terabyte archive can take days, and starting over after
+//! an interruption wastes all of it. An `ExtractionProgress` records how far a
+//! restore has gotten: where the last fully restored member ended, and how
+//! much of the member after it was written before the interruption. Saved to
+//! a state file as the restore goes, it lets the next attempt seek (or, on
+//! tape, skip) past everything already restored.
+//!
+//! State files are text, one record per line, with tab-separated fields:
+//!
+//!  * `resume`: the archive offset just past the last fully restored member
+//!  * `last`: the path of that member
+//!  * `partial`: the header offset of a partly restored member, how many bytes
+//!    of it's data were restored, and it's path
+//!
+//! Paths are escaped in the same manner as catalogs.
+
+use std::{io, fs, path};
+use crate::tar::reader::TarEntry;
+use crate::tar::catalog::{escape_path, unescape_path};
+
+/// A member which was only partly restored.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PartialMember {
+    pub path: path::PathBuf,
+
+    /// Where the member's headers begin in the archive.
+    pub header_offset: u64,
+
+    /// How many bytes of the member's data were restored.
+    pub restored: u64,
+}
+
+/// How far a restore has progressed through an archive.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ExtractionProgress {
+    /// The archive offset just past the last fully restored member.
+    ///
+    /// Members starting before this offset have been restored.
+    pub resume_offset: u64,
+
+    /// The last fully restored member, if any.
+    pub last_member: Option<path::PathBuf>,
+
+    /// A member after `last_member` which was partly restored.
+    pub partial: Option<PartialMember>,
+}
+
+impl ExtractionProgress {
+    pub fn new() -> ExtractionProgress {
+        ExtractionProgress::default()
+    }
+
+    /// Record that a member has been restored in full.
+    pub fn member_restored(&mut self, entry: &TarEntry) {
+        self.resume_offset = entry.end_offset();
+        self.last_member = Some(entry.header.path.to_path_buf());
+        self.partial = None;
+    }
+
+    /// Record that some of a member's data has been restored.
+    pub fn member_progress(&mut self, entry: &TarEntry, restored: u64) {
+        self.partial = Some(PartialMember {
+            path: entry.header.path.to_path_buf(),
+            header_offset: entry.header_offset,
+            restored: restored
+        });
+    }
+
+    /// Determine if a member was restored by a previous attempt, and should
+    /// be skipped.
+    pub fn is_restored(&self, entry: &TarEntry) -> bool {
+        entry.header_offset < self.resume_offset
+    }
+
+    /// How much of a member's data a previous attempt already restored.
+    ///
+    /// Restoring the member can continue from this point, provided the file
+    /// it was restored to still exists.
+    pub fn restored_data(&self, entry: &TarEntry) -> u64 {
+        match self.partial {
+            Some(ref partial) if partial.header_offset == entry.header_offset => partial.restored,
+            _ => 0
+        }
+    }
+
+    /// Render the progress as the contents of a state file.
+    pub fn to_text(&self) -> String {
+        let mut text = format!("resume\t{}\n", self.resume_offset);
+
+        if let Some(ref last) = self.last_member {
+            text.push_str("last\t");
+            escape_path(last, &mut text);
+            text.push('\n');
+        }
+
+        if let Some(ref partial) = self.partial {
+            text.push_str(&format!("partial\t{}\t{}\t", partial.header_offset, partial.restored));
+            escape_path(&partial.path, &mut text);
+            text.push('\n');
+        }
+
+        text
+    }
+
+    /// Parse the contents of a state file previously rendered by `to_text`.
+    pub fn from_text(text: &str) -> io::Result<ExtractionProgress> {
+        let mut progress = ExtractionProgress::new();
+
+        for line in text.lines().filter(|line| !line.is_empty()) {
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("Invalid extraction state line {:?}", line));
+            let fields : Vec<&str> = line.splitn(4, '\t').collect();
+
+            match fields.as_slice() {
+                ["resume", offset] => progress.resume_offset = offset.parse().map_err(|_| invalid())?,
+                ["last", path] => progress.last_member = Some(unescape_path(path)),
+                ["partial", offset, restored, path] => progress.partial = Some(PartialMember {
+                    path: unescape_path(path),
+                    header_offset: offset.parse().map_err(|_| invalid())?,
+                    restored: restored.parse().map_err(|_| invalid())?
+                }),
+                _ => return Err(invalid())
+            }
+        }
+
+        Ok(progress)
+    }
+
+    /// Load progress from a state file, if it exists.
+    pub fn load<P: AsRef<path::Path>>(state_file: P) -> io::Result<Option<ExtractionProgress>> {
+        match fs::read_to_string(state_file) {
+            Ok(text) => Ok(Some(ExtractionProgress::from_text(&text)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Save progress to a state file.
+    ///
+    /// The state is written to a temporary file which then replaces the state
+    /// file, so that an interruption while saving leaves the previous state
+    /// intact.
+    pub fn save<P: AsRef<path::Path>>(&self, state_file: P) -> io::Result<()> {
+        let state_file = state_file.as_ref();
+        let mut temporary = state_file.as_os_str().to_os_string();
+
+        temporary.push(".tmp");
+
+        fs::write(&temporary, self.to_text())?;
+        fs::rename(&temporary, state_file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+    use crate::tar::header::{TarHeader, TarFileType};
+    use crate::tar::reader::TarEntry;
+    use super::ExtractionProgress;
+
+    fn entry(name: &str, header_offset: u64, file_size: u64) -> TarEntry {
+        let header = TarHeader::for_test(name, TarFileType::FileStream, file_size);
+
+        TarEntry {
+            header: header,
+            header_offset: header_offset,
+            data_offset: header_offset + 512,
+            data_size: file_size,
+            unknown_attributes: Vec::new()
+        }
+    }
+
+    #[test]
+    fn extraction_progress() {
+        let first = entry("first", 0, 100);
+        let second = entry("odd\tname", 1024, 4096);
+        let mut progress = ExtractionProgress::new();
+
+        progress.member_restored(&first);
+        progress.member_progress(&second, 2048);
+
+        assert_eq!(progress.resume_offset, 1024);
+        assert!(progress.is_restored(&first));
+        assert!(!progress.is_restored(&second));
+        assert_eq!(progress.restored_data(&second), 2048);
+        assert_eq!(progress.restored_data(&first), 0);
+
+        let state_file = env::temp_dir().join(format!("rapidtar-progress-{}", std::process::id()));
+
+        assert_eq!(ExtractionProgress::load(&state_file).unwrap(), None);
+        progress.save(&state_file).unwrap();
+        assert_eq!(ExtractionProgress::load(&state_file).unwrap(), Some(progress));
+        fs::remove_file(&state_file).unwrap();
+
+        assert!(ExtractionProgress::from_text("resume\tlots\n").is_err());
+    }
+}
@@ -0,0 +1,1190 @@
+//! Reading of tar archives.
+//!
+//! `TarReader` walks the members of an archive stream, decoding ustar, GNU and
+//! pax headers into abstract `TarHeader`s. Member data is read from the reader
+//! itself, and whatever isn't read is skipped when the next member is asked
+//! for.
+//!
+//! Archives in the wild aren't always well-formed, so the reader is
+//! deliberately forgiving about a few things other implementations get wrong:
+//!
+//!  * Non-file members (directories, links, devices, FIFOs) sometimes carry a
+//!    nonzero size field. If a valid header immediately follows, the size is
+//!    taken to be bogus; otherwise that much data is skipped. Either way the
+//!    member is reported with a size of zero.
+//!  * Archives that end without a terminator, with a single zero block, or in
+//!    the middle of the padding after the last member are treated as having
+//!    ended normally.
+//!  * A lone zero block between two members is skipped with a warning.
+//!  * Nonzero padding bytes are ignored.
+//!
+//! Volumes written by rapidtar in pax format start with a label recording
+//! which archive they belong to and where they fall in it; see
+//! `volume_header`.
+//!
+//! Pax attributes the reader doesn't understand are never an error. They're
+//! offered to any `PaxAttributeHandler` registered for them, so that programs
+//! embedding librapidarchive can decode their own vendor attributes, and
+//! whatever nobody handles is kept on the entry as `unknown_attributes`.
+
+use std::{io, path, time};
+use std::io::Read;
+use crate::tar::header::{TarHeader, TarFileType};
+use crate::tar::{ustar, gnu, pax, acl, PathStyle};
+use crate::tar::sparse::{SparseExtent, SparseAttributes, parse_sparse_map};
+use crate::tar::label::VolumeHeader;
+use crate::digest::Sha256;
+use crate::intern::StringPool;
+
+/// The size of a tar block.
+const BLOCK_SIZE: u64 = 512;
+
+/// The largest extended header we're willing to hold in memory.
+const MAX_EXTENDED_HEADER_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A member of a tar archive, as read by `TarReader`.
+pub struct TarEntry {
+    pub header: TarHeader,
+
+    /// Where the member's first header starts, including any extended headers
+    /// that precede it.
+    pub header_offset: u64,
+
+    /// Where the member's data starts.
+    pub data_offset: u64,
+
+    /// How many bytes of data the member holds in the archive.
+    ///
+    /// This is `header.file_size` for most members, but sparse members only
+    /// hold the data extents of their file.
+    pub data_size: u64,
+
+    /// Pax attributes of the member which we didn't understand.
+    ///
+    /// These are kept so that they can be reported or passed along, rather
+    /// than silently lost.
+    pub unknown_attributes: Vec<(String, String)>,
+}
+
+impl TarEntry {
+    /// Where the member ends, including the padding after it's data.
+    pub fn end_offset(&self) -> u64 {
+        self.data_offset + padded_size(self.data_size)
+    }
+}
+
+/// Decodes pax attributes the reader doesn't understand by itself.
+///
+/// Handlers are registered on a `TarReader` for a key prefix, conventionally
+/// a vendor name such as `"ACME."`. They see every unrecognized attribute of a
+/// member with that prefix, including ones inherited from global headers, and
+/// may record what they find in the member's header. Any closure of the
+/// appropriate signature is a handler.
+pub trait PaxAttributeHandler: Send {
+    /// Handle an attribute of the member whose header is given, yielding
+    /// false if it wasn't understood after all.
+    ///
+    /// Attributes which aren't understood are reported as unknown.
+    fn handle_attribute(&mut self, header: &mut TarHeader, key: &str, value: &str) -> bool;
+}
+
+impl<F> PaxAttributeHandler for F where F: FnMut(&mut TarHeader, &str, &str) -> bool + Send {
+    fn handle_attribute(&mut self, header: &mut TarHeader, key: &str, value: &str) -> bool {
+        self(header, key, value)
+    }
+}
+
+/// Where the reader is within the file of a sparse member, whose holes have
+/// to be filled back in as it's read.
+struct SparseFile {
+    extents: Vec<SparseExtent>,
+    next: usize,
+    position: u64,
+    size: u64,
+}
+
+/// Reads members out of a tar archive stream.
+pub struct TarReader<R: io::Read> {
+    inner: R,
+    offset: u64,
+    remaining: u64,
+    skip: u64,
+    pending: Option<Vec<u8>>,
+    global_attributes: Vec<(String, String)>,
+    volume_header: Option<VolumeHeader>,
+    finished: bool,
+    terminated: bool,
+    end: u64,
+
+    /// Whether the archive may end in the middle of a member's data.
+    torn_end: bool,
+
+    /// Owner and group names seen so far, shared between entries.
+    names: StringPool,
+
+    /// The current member's file, if it's sparse.
+    sparse: Option<SparseFile>,
+
+    /// Handlers for attributes we don't understand, by key prefix.
+    handlers: Vec<(String, Box<dyn PaxAttributeHandler>)>,
+}
+
+/// Round a size up to a whole number of blocks.
+fn padded_size(size: u64) -> u64 {
+    match size % BLOCK_SIZE {
+        0 => size,
+        partial => size + (BLOCK_SIZE - partial)
+    }
+}
+
+fn is_zero_block(block: &[u8]) -> bool {
+    block.iter().all(|b| *b == 0)
+}
+
+/// Determine if a block starts with a valid tar header.
+pub fn is_tar_header(block: &[u8]) -> bool {
+    block.len() >= BLOCK_SIZE as usize && !is_zero_block(&block[..BLOCK_SIZE as usize]) && ustar::verify_checksum(block)
+}
+
+/// Parse a pax time value, which may have a fractional part and may be
+/// negative.
+fn parse_pax_time(value: &str) -> Option<time::SystemTime> {
+    let (negative, value) = match value.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, value)
+    };
+    let mut parts = value.splitn(2, '.');
+    let secs : u64 = parts.next()?.parse().ok()?;
+    let nanos = match parts.next() {
+        Some(fraction) if fraction.chars().all(|c| c.is_ascii_digit()) => {
+            let digits : String = fraction.chars().chain("000000000".chars()).take(9).collect();
+            digits.parse().ok()?
+        },
+        Some(_) => return None,
+        None => 0
+    };
+    let offset = time::Duration::new(secs, nanos);
+
+    match negative {
+        false => time::UNIX_EPOCH.checked_add(offset),
+        true => time::UNIX_EPOCH.checked_sub(offset)
+    }
+}
+
+/// Decode a single header block into an abstract header.
+///
+/// The caller is responsible for validating the checksum.
+fn decode_header(block: &[u8], names: &StringPool) -> io::Result<TarHeader> {
+    let numeral = |field: &[u8], what: &str| gnu::parse_gnu_numeral(field).ok_or(io::Error::new(io::ErrorKind::InvalidData, format!("Malformed {} field in tar header", what)));
+
+    let name = ustar::parse_tar_string(&block[0..100]);
+    let is_ustar = &block[257..262] == b"ustar";
+    let is_gnu = &block[257..265] == b"ustar  \0";
+
+    //GNU tar reuses the prefix field for other things, so only POSIX headers
+    //get their names extended with it.
+    let prefix = match is_ustar && !is_gnu {
+        true => ustar::parse_tar_string(&block[345..500]),
+        false => String::new()
+    };
+
+    let full_name = match prefix.len() {
+        0 => name,
+        _ => format!("{}/{}", prefix, name)
+    };
+
+    let file_type = TarFileType::from_type_flag(block[156] as char);
+    let linkname = ustar::parse_tar_string(&block[157..257]);
+    let mtime = gnu::parse_gnu_time(&block[136..148]).ok_or(io::Error::new(io::ErrorKind::InvalidData, "Malformed mtime field in tar header"))?;
+
+    //GNU headers may also carry access and change times where the prefix
+    //would otherwise be.
+    let gnu_time = |field: &[u8]| match is_gnu && field.iter().any(|b| *b != 0) {
+        true => gnu::parse_gnu_time(field),
+        false => None
+    };
+
+    Ok(TarHeader {
+        path: Box::new(path::PathBuf::from(full_name.trim_end_matches('/'))),
+        unix_mode: numeral(&block[100..108], "mode")? as u32,
+        unix_uid: numeral(&block[108..116], "uid")? as u32,
+        unix_gid: numeral(&block[116..124], "gid")? as u32,
+        file_size: numeral(&block[124..136], "size")?,
+        mtime: Some(mtime),
+        file_type: file_type,
+        symlink_path: match linkname.len() {
+            0 => None,
+            _ => Some(Box::new(path::PathBuf::from(linkname)))
+        },
+        unix_uname: match is_ustar {
+            true => names.intern(&ustar::parse_tar_string(&block[265..297])),
+            false => names.intern("")
+        },
+        unix_gname: match is_ustar {
+            true => names.intern(&ustar::parse_tar_string(&block[297..329])),
+            false => names.intern("")
+        },
+        unix_devmajor: match is_ustar {
+            true => gnu::parse_gnu_numeral(&block[329..337]).unwrap_or(0) as u32,
+            false => 0
+        },
+        unix_devminor: match is_ustar {
+            true => gnu::parse_gnu_numeral(&block[337..345]).unwrap_or(0) as u32,
+            false => 0
+        },
+        atime: gnu_time(&block[345..357]),
+        ctime: gnu_time(&block[357..369]),
+        birthtime: None,
+        xattrs: Vec::new(),
+        project_id: None,
+        acl_access: None,
+        acl_default: None,
+        path_style: PathStyle::default(),
+        sparse_map: None,
+        recovery_path: None,
+        recovery_remaining_size: None,
+        recovery_seek_offset: None
+    })
+}
+
+/// Apply pax attributes to a decoded header.
+///
+/// Attributes whose values don't parse are ignored. Attributes we don't
+/// understand at all are returned, so that the caller can report them.
+/// Multi-volume continuation records and the informational `comment` and
+/// charset records are understood, but have no effect on the header.
+fn apply_pax_attributes<V: AsRef<[u8]>>(header: &mut TarHeader, attributes: &[(String, V)], names: &StringPool) -> Vec<(String, String)> {
+    let mut unknown = Vec::new();
+
+    for (key, value) in attributes {
+        let value = value.as_ref();
+        let text = String::from_utf8_lossy(value);
+
+        match key.as_str() {
+            "path" => header.path = Box::new(path::PathBuf::from(text.trim_end_matches('/'))),
+            "linkpath" => header.symlink_path = Some(Box::new(path::PathBuf::from(text.as_ref()))),
+            "size" => if let Ok(size) = text.parse() { header.file_size = size },
+            "mtime" => if let Some(mtime) = parse_pax_time(&text) { header.mtime = Some(mtime) },
+            "atime" => if let Some(atime) = parse_pax_time(&text) { header.atime = Some(atime) },
+            "ctime" => if let Some(ctime) = parse_pax_time(&text) { header.ctime = Some(ctime) },
+            "LIBARCHIVE.creationtime" => if let Some(birthtime) = parse_pax_time(&text) { header.birthtime = Some(birthtime) },
+            pax::PROJECT_ID_KEY => if let Ok(project_id) = text.parse() { header.project_id = Some(project_id) },
+            acl::ACCESS_KEY => if let Ok(acl) = text.parse() { header.acl_access = Some(acl) },
+            acl::DEFAULT_KEY => if let Ok(acl) = text.parse() { header.acl_default = Some(acl) },
+            "uid" => if let Ok(uid) = text.parse() { header.unix_uid = uid },
+            "gid" => if let Ok(gid) = text.parse() { header.unix_gid = gid },
+            "uname" => header.unix_uname = names.intern(&text),
+            "gname" => header.unix_gname = names.intern(&text),
+            "comment" | "charset" | "hdrcharset" => {},
+            key if key.starts_with("GNU.volume.") => {},
+            key if key.starts_with("GNU.sparse.") => {},
+            key if key.starts_with("SCHILY.xattr.") => header.xattrs.push((key["SCHILY.xattr.".len()..].to_string(), value.to_vec())),
+            _ => unknown.push((key.clone(), text.into_owned()))
+        }
+    }
+
+    unknown
+}
+
+impl<R: io::Read> TarReader<R> {
+    pub fn new(inner: R) -> TarReader<R> {
+        TarReader {
+            inner: inner,
+            offset: 0,
+            remaining: 0,
+            skip: 0,
+            pending: None,
+            global_attributes: Vec::new(),
+            volume_header: None,
+            finished: false,
+            terminated: false,
+            end: 0,
+            torn_end: false,
+            names: StringPool::new(),
+            sparse: None,
+            handlers: Vec::new()
+        }
+    }
+
+    /// Register a handler for pax attributes whose keys start with `prefix`.
+    ///
+    /// Where more than one handler's prefix matches, the first registered is
+    /// asked first. Attributes the reader understands itself never reach a
+    /// handler.
+    pub fn register_pax_handler<H>(&mut self, prefix: &str, handler: H) where H: PaxAttributeHandler + 'static {
+        self.handlers.push((prefix.to_string(), Box::new(handler)));
+    }
+
+    /// Offer unrecognized attributes to the registered handlers, yielding the
+    /// ones none of them understood.
+    fn handle_attributes(&mut self, header: &mut TarHeader, attributes: Vec<(String, String)>) -> Vec<(String, String)> {
+        if self.handlers.is_empty() {
+            return attributes;
+        }
+
+        attributes.into_iter().filter(|(key, value)| {
+            !self.handlers.iter_mut().filter(|(prefix, _)| key.starts_with(prefix.as_str())).any(|(_, handler)| handler.handle_attribute(header, key, value))
+        }).collect()
+    }
+
+    /// Create a reader for an archive stream which has already been positioned
+    /// partway through, at a member boundary.
+    ///
+    /// `offset` is where in the archive `inner` starts, so that the offsets of
+    /// entries are relative to the start of the archive. Any pax global headers
+    /// or volume labels before `offset` are not seen.
+    pub fn new_at(inner: R, offset: u64) -> TarReader<R> {
+        let mut reader = TarReader::new(inner);
+
+        reader.offset = offset;
+        reader
+    }
+
+    /// How far into the archive stream the reader is.
+    pub fn offset(&self) -> u64 {
+        match self.pending {
+            Some(ref block) => self.offset - block.len() as u64,
+            None => self.offset
+        }
+    }
+
+    /// The attributes of every pax global header read so far, in order.
+    pub fn global_attributes(&self) -> &[(String, String)] {
+        &self.global_attributes
+    }
+
+    /// The volume header in the label at the start of the stream, if there
+    /// was one.
+    /// 
+    /// This is only available once the first member has been read.
+    pub fn volume_header(&self) -> Option<&VolumeHeader> {
+        self.volume_header.as_ref()
+    }
+
+    /// Determine if the archive ended with a proper end-of-archive marker of
+    /// two zero blocks, rather than just stopping.
+    ///
+    /// This is only meaningful once `next_entry` has yielded None. Archives
+    /// without a marker may have been cut short.
+    pub fn is_terminated(&self) -> bool {
+        self.terminated
+    }
+
+    /// Treat the archive ending in the middle of a member's data as the end of
+    /// the archive, rather than an error.
+    ///
+    /// Every volume of a multi-volume archive but the last ends this way, with
+    /// the rest of the torn member at the start of the next volume. The torn
+    /// member's data just stops short, and the archive is not terminated.
+    pub fn allow_torn_end(&mut self) {
+        self.torn_end = true;
+    }
+
+    /// Where the archive's members end, and it's end-of-archive marker (if
+    /// any) begins.
+    ///
+    /// This is where members added to the archive should be written. Like
+    /// `is_terminated`, it is only meaningful once `next_entry` has yielded
+    /// None. Archives which end partway through a block are taken to end at
+    /// the end of that block.
+    pub fn archive_end(&self) -> u64 {
+        self.end
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    /// Get the underlying stream.
+    ///
+    /// Reading from it directly will confuse the reader.
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+
+    /// Read as much of `buf` as the stream has, returning how much was read.
+    fn read_fully(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+
+        while filled < buf.len() {
+            match self.inner.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e)
+            }
+        }
+
+        self.offset += filled as u64;
+
+        Ok(filled)
+    }
+
+    /// Read the next block of the archive, returning None at end of stream.
+    ///
+    /// A partial block at the end of the stream is treated as the end of the
+    /// stream.
+    fn read_block(&mut self) -> io::Result<Option<Vec<u8>>> {
+        if let Some(block) = self.pending.take() {
+            return Ok(Some(block));
+        }
+
+        let mut block = vec![0; BLOCK_SIZE as usize];
+
+        match self.read_fully(&mut block)? {
+            512 => Ok(Some(block)),
+            0 => Ok(None),
+            partial => {
+                warn!("Archive ends with a partial block of {} bytes, ignoring it", partial);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Discard bytes from the stream, returning how many were actually there.
+    fn discard(&mut self, length: u64) -> io::Result<u64> {
+        let copied = io::copy(&mut (&mut self.inner).take(length), &mut io::sink())?;
+
+        self.offset += copied;
+
+        Ok(copied)
+    }
+
+    /// Read the data of an extended header, including it's padding.
+    fn read_extended_data(&mut self, size: u64) -> io::Result<Vec<u8>> {
+        if size > MAX_EXTENDED_HEADER_SIZE {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Extended header at offset {} is unreasonably large ({} bytes)", self.offset, size)));
+        }
+
+        let mut data = vec![0; padded_size(size) as usize];
+        let read = self.read_fully(&mut data)?;
+
+        if (read as u64) < size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Archive ends in the middle of an extended header"));
+        }
+
+        Ok(data)
+    }
+
+    /// Skip whatever remains of the current member's data and padding.
+    fn skip_remainder(&mut self) -> io::Result<()> {
+        if self.remaining > 0 {
+            let remaining = self.remaining;
+
+            self.remaining = 0;
+
+            if self.discard(remaining)? < remaining {
+                if self.torn_end {
+                    self.skip = 0;
+                    return Ok(());
+                }
+
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Archive ends in the middle of a member"));
+            }
+        }
+
+        if self.skip > 0 {
+            let skip = self.skip;
+
+            //Running out of stream here means the archive ended in it's
+            //padding, which we tolerate and treat as the end of the archive.
+            self.skip = 0;
+            self.discard(skip)?;
+        }
+
+        Ok(())
+    }
+
+    /// Read the next member's headers.
+    ///
+    /// Any unread data of the previous member is skipped. Returns None at the
+    /// end of the archive.
+    pub fn next_entry(&mut self) -> io::Result<Option<TarEntry>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        self.skip_remainder()?;
+        self.sparse = None;
+
+        let mut header_offset = None;
+        let mut local_attributes : Vec<(String, Vec<u8>)> = Vec::new();
+        let mut long_name : Option<String> = None;
+        let mut long_linkname : Option<String> = None;
+
+        loop {
+            let block_offset = self.offset();
+            let block = match self.read_block()? {
+                Some(block) => block,
+                None => {
+                    if header_offset.is_some() {
+                        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Archive ends after an extended header"));
+                    }
+
+                    self.finished = true;
+                    self.end = padded_size(block_offset);
+                    return Ok(None);
+                }
+            };
+
+            if is_zero_block(&block) {
+                let next = self.read_block()?;
+
+                match next {
+                    Some(ref next_block) if is_tar_header(next_block) => {
+                        warn!("Skipping lone zero block at offset {}", block_offset);
+                        self.pending = next;
+                        continue;
+                    },
+                    _ => {
+                        self.finished = true;
+                        self.terminated = next.as_ref().map_or(false, |next_block| is_zero_block(next_block));
+                        self.end = block_offset;
+                        return Ok(None);
+                    }
+                }
+            }
+
+            if !ustar::verify_checksum(&block) {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid tar header checksum at offset {}", block_offset)));
+            }
+
+            if header_offset.is_none() {
+                header_offset = Some(block_offset);
+            }
+
+            let mut header = decode_header(&block, &self.names)?;
+
+            match block[156] {
+                b'x' => {
+                    let data = self.read_extended_data(header.file_size)?;
+                    local_attributes.extend(pax::parse_pax_binary_attributes(&data[..header.file_size as usize])?);
+                    continue;
+                },
+                b'g' => {
+                    let data = self.read_extended_data(header.file_size)?;
+                    let attributes = pax::parse_pax_attributes(&data[..header.file_size as usize])?;
+
+                    if block_offset == 0 {
+                        let mut digest = Sha256::new();
+                        digest.update(&block);
+                        digest.update(&data);
+
+                        self.volume_header = VolumeHeader::from_attributes(&attributes, digest.finish());
+                    }
+
+                    self.global_attributes.extend(attributes);
+
+                    //Global headers apply to every member after them, but
+                    //aren't part of any particular member.
+                    header_offset = None;
+                    continue;
+                },
+                b'L' => {
+                    let data = self.read_extended_data(header.file_size)?;
+                    long_name = Some(ustar::parse_tar_string(&data[..header.file_size as usize]));
+                    continue;
+                },
+                b'K' => {
+                    let data = self.read_extended_data(header.file_size)?;
+                    long_linkname = Some(ustar::parse_tar_string(&data[..header.file_size as usize]));
+                    continue;
+                },
+                b'V' => {
+                    //GNU volume labels carry no data, no matter what their
+                    //size field says.
+                    header_offset = None;
+                    continue;
+                },
+                _ => {}
+            }
+
+            //Old GNU sparse members keep their maps in their headers, and
+            //extension blocks following them.
+            let mut sparse = match (block[156], &block[257..265] == b"ustar  \0") {
+                (b'S', true) => {
+                    let mut extents = gnu::parse_gnu_sparse_extents(&block[386..482])?;
+                    let mut extended = block[482] != 0;
+
+                    while extended {
+                        let extension = self.read_block()?.ok_or(io::Error::new(io::ErrorKind::UnexpectedEof, "Archive ends in the middle of a sparse map"))?;
+
+                        extents.extend(gnu::parse_gnu_sparse_extents(&extension[0..504])?);
+                        extended = extension[504] != 0;
+                    }
+
+                    header.file_type = TarFileType::FileStream;
+
+                    Some(SparseAttributes {
+                        name: None,
+                        real_size: Some(gnu::parse_gnu_numeral(&block[483..495]).ok_or(io::Error::new(io::ErrorKind::InvalidData, "Malformed sparse file size in tar header"))?),
+                        map: extents,
+                        map_in_data: false
+                    })
+                },
+                _ => None
+            };
+
+            if let Some(name) = long_name {
+                header.path = Box::new(path::PathBuf::from(name.trim_end_matches('/')));
+            }
+
+            if let Some(linkname) = long_linkname {
+                header.symlink_path = Some(Box::new(path::PathBuf::from(linkname)));
+            }
+
+            //Global headers mostly carry volume metadata, which isn't worth
+            //reporting for every member.
+            let unknown_globals = apply_pax_attributes(&mut header, &self.global_attributes, &self.names);
+
+            self.handle_attributes(&mut header, unknown_globals);
+
+            let unknown_attributes = apply_pax_attributes(&mut header, &local_attributes, &self.names);
+            let unknown_attributes = self.handle_attributes(&mut header, unknown_attributes);
+
+            for (key, _) in unknown_attributes.iter() {
+                debug!(path:? = header.path; "Unknown pax attribute {}", key);
+            }
+
+            if let Some(attributes) = SparseAttributes::from_attributes(&local_attributes)? {
+                if let Some(ref name) = attributes.name {
+                    header.path = Box::new(path::PathBuf::from(name.trim_end_matches('/')));
+                }
+
+                sparse = Some(attributes);
+            }
+
+            let mut data_size = header.file_size;
+
+            if let Some(mut attributes) = sparse.filter(|_| header.file_type == TarFileType::FileStream) {
+                if attributes.map_in_data {
+                    let mut map_data = Vec::new();
+
+                    let (map, map_size) = loop {
+                        if map_data.len() as u64 >= data_size.min(MAX_EXTENDED_HEADER_SIZE) {
+                            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Sparse map of {:?} is missing or unreasonably large", header.path)));
+                        }
+
+                        map_data.extend(self.read_block()?.ok_or(io::Error::new(io::ErrorKind::UnexpectedEof, "Archive ends in the middle of a sparse map"))?);
+
+                        if let Some(parsed) = parse_sparse_map(&map_data)? {
+                            break parsed;
+                        }
+                    };
+
+                    attributes.map = map;
+                    data_size -= map_size as u64;
+                }
+
+                header.file_size = attributes.real_size.unwrap_or(header.file_size);
+                header.sparse_map = Some(attributes.map.clone());
+
+                self.sparse = Some(SparseFile {
+                    extents: attributes.map,
+                    next: 0,
+                    position: 0,
+                    size: header.file_size
+                });
+            }
+
+            if !header.file_type.has_data() && header.file_size > 0 {
+                let declared_size = header.file_size;
+                let next = self.read_block()?;
+
+                header.file_size = 0;
+                data_size = 0;
+
+                match next {
+                    Some(ref next_block) if is_tar_header(next_block) => {
+                        warn!("Ignoring bogus size of {} bytes on non-file member {:?}", declared_size, header.path);
+                        self.pending = next;
+                    },
+                    Some(_) => {
+                        warn!("Skipping {} bytes of data on non-file member {:?}", declared_size, header.path);
+                        self.skip = padded_size(declared_size) - BLOCK_SIZE;
+                    },
+                    None => {}
+                }
+            } else {
+                self.remaining = data_size;
+                self.skip = padded_size(data_size) - data_size;
+            }
+
+            return Ok(Some(TarEntry {
+                header: header,
+                header_offset: header_offset.unwrap_or(block_offset),
+                data_offset: self.offset(),
+                data_size: data_size,
+                unknown_attributes: unknown_attributes
+            }));
+        }
+    }
+}
+
+impl<R: io::Read> TarReader<R> {
+    /// Read the current member's data, as it's stored in the archive.
+    fn read_data(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let want = (buf.len() as u64).min(self.remaining) as usize;
+
+        if want == 0 {
+            return Ok(0);
+        }
+
+        let read = self.inner.read(&mut buf[..want])?;
+
+        if read == 0 {
+            if self.torn_end {
+                self.remaining = 0;
+                self.skip = 0;
+                return Ok(0);
+            }
+
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Archive ends in the middle of a member"));
+        }
+
+        self.offset += read as u64;
+        self.remaining -= read as u64;
+
+        Ok(read)
+    }
+}
+
+/// Reading a `TarReader` reads the data of the current member.
+///
+/// Sparse members are read as the whole of their file, with their holes
+/// filled in with zeroes.
+impl<R: io::Read> io::Read for TarReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut file = match self.sparse.take() {
+            Some(file) => file,
+            None => return self.read_data(buf)
+        };
+
+        while file.extents.get(file.next).map_or(false, |extent| extent.offset + extent.length <= file.position) {
+            file.next += 1;
+        }
+
+        let data_end = file.extents.get(file.next).map(|extent| (extent.offset, extent.offset + extent.length));
+        let want = (buf.len() as u64).min(file.size.saturating_sub(file.position));
+        let result = match data_end {
+            Some((start, end)) if start <= file.position => self.read_data(&mut buf[..want.min(end - file.position) as usize]),
+            Some((start, _)) => {
+                let hole = want.min(start - file.position) as usize;
+
+                buf[..hole].iter_mut().for_each(|b| *b = 0);
+                Ok(hole)
+            },
+            None => {
+                buf[..want as usize].iter_mut().for_each(|b| *b = 0);
+                Ok(want as usize)
+            }
+        };
+
+        if let Ok(read) = result {
+            file.position += read as u64;
+        }
+
+        self.sparse = Some(file);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path, time};
+    use std::sync::{Arc, Mutex};
+    use std::io::Read;
+    use crate::tar::header::{TarHeader, TarFileType, TarFormat};
+    use crate::tar::label::{TarLabel, VolumeHeader, VolumeSequence, labelgen};
+    use crate::tar::{ustar, pax};
+    use crate::tar::sparse::SparseExtent;
+    use crate::digest::Sha256;
+    use crate::intern::StringPool;
+    use super::{TarReader, parse_pax_time, apply_pax_attributes};
+
+    fn ustar_member(name: &str, file_type: TarFileType, data: &[u8]) -> Vec<u8> {
+        let mut member = ustar::ustar_header(&TarHeader::for_test(name, file_type, data.len() as u64)).unwrap();
+        ustar::checksum_header(&mut member);
+        member.extend(data);
+        member.extend(vec![0; (512 - data.len() % 512) % 512]);
+        member
+    }
+
+    /// A directory header which claims to have data.
+    fn bogus_directory(name: &str, declared_size: u64) -> Vec<u8> {
+        let mut member = ustar::ustar_header(&TarHeader::for_test(name, TarFileType::Directory, 0)).unwrap();
+        member[124..136].copy_from_slice(&ustar::format_tar_numeral(declared_size, 12).unwrap());
+        ustar::checksum_header(&mut member);
+        member
+    }
+
+    fn read_all(archive: Vec<u8>) -> Vec<(String, TarFileType, Vec<u8>)> {
+        let mut reader = TarReader::new(io::Cursor::new(archive));
+        let mut members = vec![];
+
+        while let Some(entry) = reader.next_entry().unwrap() {
+            let mut data = vec![];
+            reader.read_to_end(&mut data).unwrap();
+            members.push((entry.header.path.to_string_lossy().into_owned(), entry.header.file_type, data));
+        }
+
+        members
+    }
+
+    #[test]
+    fn reader_roundtrip() {
+        let mut long_header = TarHeader::for_test(&"long/".repeat(40), TarFileType::FileStream, 3);
+        long_header.path.push("file.txt");
+
+        let mut archive = ustar_member("dir", TarFileType::Directory, &[]);
+        archive.extend(ustar_member("dir/a.txt", TarFileType::FileStream, b"hello"));
+
+        let mut pax_member = pax::pax_header(&long_header).unwrap();
+        pax::checksum_header(&mut pax_member);
+        archive.extend(pax_member);
+        archive.extend(b"abc");
+        archive.extend(vec![0; 509]);
+        archive.extend(vec![0; 1024]);
+
+        let mut reader = TarReader::new(io::Cursor::new(archive));
+
+        let dir = reader.next_entry().unwrap().unwrap();
+        assert!(dir.header.path.as_path() == path::Path::new("dir"));
+        assert!(dir.header.file_type == TarFileType::Directory);
+        assert_eq!(&*dir.header.unix_uname, "user");
+
+        let file = reader.next_entry().unwrap().unwrap();
+        assert_eq!(file.header_offset, 512);
+        assert_eq!(file.data_offset, 1024);
+        assert_eq!(file.header.file_size, 5);
+        assert_eq!(file.header.mtime, Some(time::UNIX_EPOCH + time::Duration::from_secs(1_500_000_000)));
+
+        //Leave the data unread; it should be skipped.
+        let long = reader.next_entry().unwrap().unwrap();
+        assert_eq!(long.header_offset, 1536);
+        assert!(*long.header.path == *long_header.path);
+
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"abc");
+
+        assert!(reader.next_entry().unwrap().is_none());
+    }
+
+    #[test]
+    fn reader_base256_extended_size() {
+        let long_name = "long/".repeat(40) + "file.txt";
+        let mut member = pax::pax_header(&TarHeader::for_test(&long_name, TarFileType::FileStream, 0)).unwrap();
+        let extended_size = ustar::parse_tar_numeral(&member[124..136]).unwrap();
+        let mut size_field = vec![0x80, 0, 0, 0];
+
+        size_field.extend(&extended_size.to_be_bytes());
+        member[124..136].copy_from_slice(&size_field);
+        pax::checksum_header(&mut member);
+        member.extend(vec![0; 1024]);
+
+        let members = read_all(member);
+        assert_eq!(members.len(), 1);
+        assert_eq!(members[0].0, long_name);
+    }
+
+    #[test]
+    fn reader_gnu_old_sparse() {
+        //Laid out the way GNU tar -S writes it, rather than by our own writer:
+        //two extents in the header, another in an extension block, and some
+        //numbers in base-256, as GNU tar does for files past 8GiB.
+        let base256 = |value: u64| {
+            let mut field = vec![0x80, 0, 0, 0];
+            field.extend(&value.to_be_bytes());
+            field
+        };
+
+        let mut member = ustar::ustar_header(&TarHeader::for_test("sparse", TarFileType::FileStream, 13)).unwrap();
+        member[156] = b'S';
+        member[257..265].copy_from_slice(b"ustar  \0");
+        member[386..398].copy_from_slice(&ustar::format_tar_numeral(0, 12).unwrap());
+        member[398..410].copy_from_slice(&ustar::format_tar_numeral(4, 12).unwrap());
+        member[410..422].copy_from_slice(&base256(4096));
+        member[422..434].copy_from_slice(&ustar::format_tar_numeral(9, 12).unwrap());
+        member[482] = 1;
+        member[483..495].copy_from_slice(&base256(10000));
+        ustar::checksum_header(&mut member);
+
+        let mut extension = vec![0; 512];
+        extension[0..12].copy_from_slice(&base256(10000));
+        extension[12..24].copy_from_slice(&ustar::format_tar_numeral(0, 12).unwrap());
+        member.extend(extension);
+
+        member.extend(b"headtail data");
+        member.extend(vec![0; 499]);
+        member.extend(vec![0; 1024]);
+
+        let mut reader = TarReader::new(io::Cursor::new(member));
+        let entry = reader.next_entry().unwrap().unwrap();
+        let mut data = vec![];
+
+        reader.read_to_end(&mut data).unwrap();
+
+        assert!(entry.header.file_type == TarFileType::FileStream);
+        assert_eq!(entry.header.file_size, 10000);
+        assert_eq!(entry.header.sparse_map, Some(vec![
+            SparseExtent { offset: 0, length: 4 },
+            SparseExtent { offset: 4096, length: 9 },
+            SparseExtent { offset: 10000, length: 0 }
+        ]));
+
+        let mut expected = vec![0; 10000];
+        expected[0..4].copy_from_slice(b"head");
+        expected[4096..4105].copy_from_slice(b"tail data");
+        assert!(data == expected);
+        assert!(reader.next_entry().unwrap().is_none());
+    }
+
+    #[test]
+    fn reader_pax_times() {
+        let mut header = TarHeader::for_test("times", TarFileType::FileStream, 0);
+        header.atime = Some(time::UNIX_EPOCH + time::Duration::new(1_600_000_000, 250_000_000));
+        header.ctime = Some(time::UNIX_EPOCH + time::Duration::from_secs(1_600_000_001));
+        header.birthtime = Some(time::UNIX_EPOCH - time::Duration::from_secs(86400));
+        header.mtime = Some(time::UNIX_EPOCH - time::Duration::new(315_619_199, 876_543_211));
+        header.xattrs.push(("user.binary".to_string(), vec![0xff, 0, b'=', b'\n']));
+        header.project_id = Some(4242);
+        header.acl_access = Some("user::rw-,user:alice:r--:1001,group::r--,mask::r--,other::---".parse().unwrap());
+        header.acl_default = Some("user::rwx,group::r-x,other::---".parse().unwrap());
+
+        let mut archive = pax::pax_header(&header).unwrap();
+        pax::checksum_header(&mut archive);
+        archive.extend(vec![0; 1024]);
+
+        let mut reader = TarReader::new(io::Cursor::new(archive));
+        let entry = reader.next_entry().unwrap().unwrap();
+
+        assert_eq!(entry.header.mtime, header.mtime);
+        assert_eq!(entry.header.atime, header.atime);
+        assert_eq!(entry.header.ctime, header.ctime);
+        assert_eq!(entry.header.birthtime, header.birthtime);
+        assert_eq!(entry.header.xattrs, header.xattrs);
+        assert_eq!(entry.header.project_id, Some(4242));
+        assert_eq!(entry.header.acl_access, header.acl_access);
+        assert_eq!(entry.header.acl_default, None);
+        assert!(entry.unknown_attributes.is_empty());
+
+        let mut header = TarHeader::for_test("unknown", TarFileType::FileStream, 0);
+        let attributes = vec![("SCHILY.acl.ace".to_string(), "everyone@:r:allow".to_string()), ("comment".to_string(), "hi".to_string()), ("mtime".to_string(), "12.5".to_string())];
+
+        assert_eq!(apply_pax_attributes(&mut header, &attributes, &StringPool::new()), vec![("SCHILY.acl.ace".to_string(), "everyone@:r:allow".to_string())]);
+        assert_eq!(header.mtime, Some(time::UNIX_EPOCH + time::Duration::from_millis(12_500)));
+    }
+
+    /// Format a pax record, including it's own length.
+    fn pax_record(key: &str, value: &str) -> Vec<u8> {
+        let body = format!(" {}={}\n", key, value);
+        let mut length = body.len() + 1;
+
+        while format!("{}{}", length, body).len() > length {
+            length += 1;
+        }
+
+        format!("{}{}", length, body).into_bytes()
+    }
+
+    /// An extended header member of the given type holding some records.
+    fn pax_member(file_type: char, records: &[u8]) -> Vec<u8> {
+        let mut member = ustar::ustar_header(&TarHeader::for_test("PaxHeader", TarFileType::Other(file_type), 0)).unwrap();
+        member[124..136].copy_from_slice(&ustar::format_tar_numeral(records.len() as u64, 12).unwrap());
+        ustar::checksum_header(&mut member);
+        member.extend(records);
+        member.extend(vec![0; (512 - records.len() % 512) % 512]);
+        member
+    }
+
+    #[test]
+    fn reader_pax_vendor_handler() {
+        let mut archive = pax_member('g', &pax_record("ACME.site", "lab"));
+        let mut local = pax_record("ACME.owner", "wile");
+        local.extend(pax_record("ACME.unused", "1"));
+        local.extend(pax_record("OTHER.key", "2"));
+        archive.extend(pax_member('x', &local));
+        archive.extend(ustar_member("member", TarFileType::FileStream, b"data"));
+        archive.extend(vec![0; 1024]);
+
+        let sites = Arc::new(Mutex::new(vec![]));
+        let seen = sites.clone();
+        let mut reader = TarReader::new(io::Cursor::new(archive));
+
+        reader.register_pax_handler("ACME.", move |header: &mut TarHeader, key: &str, value: &str| {
+            match key {
+                "ACME.owner" => header.unix_uname = value.into(),
+                "ACME.site" => seen.lock().unwrap().push(value.to_string()),
+                _ => return false
+            }
+
+            true
+        });
+
+        let entry = reader.next_entry().unwrap().unwrap();
+
+        assert_eq!(entry.header.path.to_str(), Some("member"));
+        assert_eq!(entry.header.unix_uname.to_string(), "wile");
+        assert_eq!(entry.unknown_attributes, vec![("ACME.unused".to_string(), "1".to_string()), ("OTHER.key".to_string(), "2".to_string())]);
+        assert_eq!(*sites.lock().unwrap(), vec!["lab".to_string()]);
+        assert!(reader.next_entry().unwrap().is_none());
+    }
+
+    #[test]
+    fn reader_bogus_directory_size() {
+        //A bogus size followed directly by the next header...
+        let mut archive = bogus_directory("empty", 1000);
+        archive.extend(ustar_member("after", TarFileType::FileStream, b"x"));
+
+        //...and a bogus size with actual data behind it.
+        archive.extend(bogus_directory("stuffed", 600));
+        archive.extend(vec![0xAA; 1024]);
+        archive.extend(ustar_member("last", TarFileType::FileStream, b"y"));
+
+        let members = read_all(archive);
+
+        assert_eq!(members.len(), 4);
+        assert_eq!(members[0].0, "empty");
+        assert_eq!(members[0].2.len(), 0);
+        assert_eq!(members[1].0, "after");
+        assert_eq!(members[1].2, b"x");
+        assert_eq!(members[2].0, "stuffed");
+        assert_eq!(members[2].2.len(), 0);
+        assert_eq!(members[3].0, "last");
+        assert_eq!(members[3].2, b"y");
+    }
+
+    #[test]
+    fn reader_missing_terminator() {
+        //No terminator at all.
+        let archive = ustar_member("a", TarFileType::FileStream, b"data");
+        assert_eq!(read_all(archive.clone()).len(), 1);
+
+        let mut reader = TarReader::new(io::Cursor::new(archive.clone()));
+        while reader.next_entry().unwrap().is_some() {}
+        assert!(!reader.is_terminated());
+
+        assert_eq!(reader.archive_end(), 1024);
+
+        let mut terminated = archive.clone();
+        terminated.extend(vec![0; 1024]);
+        let mut reader = TarReader::new(io::Cursor::new(terminated));
+        while reader.next_entry().unwrap().is_some() {}
+        assert!(reader.is_terminated());
+        assert_eq!(reader.archive_end(), 1024);
+
+        //Truncated in the padding of the last member.
+        let mut archive = ustar_member("a", TarFileType::FileStream, b"data");
+        archive.truncate(600);
+        assert_eq!(read_all(archive.clone()).len(), 1);
+
+        let mut reader = TarReader::new(io::Cursor::new(archive));
+        while reader.next_entry().unwrap().is_some() {}
+        assert_eq!(reader.archive_end(), 1024);
+
+        //A single zero block.
+        let mut archive = ustar_member("a", TarFileType::FileStream, b"data");
+        archive.extend(vec![0; 512]);
+        assert_eq!(read_all(archive.clone()).len(), 1);
+
+        let mut reader = TarReader::new(io::Cursor::new(archive));
+        while reader.next_entry().unwrap().is_some() {}
+        assert_eq!(reader.archive_end(), 1024);
+
+        //A lone zero block between members, with garbage padding.
+        let mut archive = ustar_member("a", TarFileType::FileStream, b"data");
+        archive[520..1024].iter_mut().for_each(|b| *b = 0xFF);
+        archive.extend(vec![0; 512]);
+        archive.extend(ustar_member("b", TarFileType::FileStream, b""));
+        let members = read_all(archive);
+        assert_eq!(members.len(), 2);
+        assert_eq!(members[0].2, b"data");
+    }
+
+    #[test]
+    fn reader_torn_end() {
+        let mut archive = ustar_member("a", TarFileType::FileStream, &[7; 1000]);
+        archive.truncate(512 + 600);
+
+        let mut reader = TarReader::new(io::Cursor::new(archive.clone()));
+        reader.next_entry().unwrap().unwrap();
+        assert_eq!(reader.next_entry().err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+
+        let mut reader = TarReader::new(io::Cursor::new(archive.clone()));
+        let mut data = Vec::new();
+        reader.allow_torn_end();
+        reader.next_entry().unwrap().unwrap();
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![7; 600]);
+        assert!(reader.next_entry().unwrap().is_none());
+        assert!(!reader.is_terminated());
+
+        //Torn members which aren't read are skipped just the same.
+        let mut reader = TarReader::new(io::Cursor::new(archive));
+        reader.allow_torn_end();
+        reader.next_entry().unwrap().unwrap();
+        assert!(reader.next_entry().unwrap().is_none());
+    }
+
+    #[test]
+    fn reader_bad_checksum() {
+        let mut archive = ustar_member("a", TarFileType::FileStream, b"data");
+        archive[0] = b'b';
+
+        let mut reader = TarReader::new(io::Cursor::new(archive));
+        assert_eq!(reader.next_entry().err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reader_volume_sequence() {
+        let mut volumes = vec![];
+        let mut previous = None;
+
+        for sequence in 1..4 {
+            let mut tarlabel = TarLabel::default();
+            tarlabel.volume_identifier = Some(sequence);
+            tarlabel.archive_uuid = Some("4f1c2a6e-0000-4000-8000-000000000000".to_string());
+            tarlabel.previous_volume_digest = previous;
+
+            let mut volume = labelgen(TarFormat::POSIX, &tarlabel).unwrap();
+            previous = Some(Sha256::digest(&volume));
+            volume.extend(ustar_member("a", TarFileType::FileStream, b"data"));
+            volumes.push(volume);
+        }
+
+        let headers : Vec<VolumeHeader> = volumes.into_iter().map(|volume| {
+            let mut reader = TarReader::new(io::Cursor::new(volume));
+            let entry = reader.next_entry().unwrap().unwrap();
+
+            assert_eq!(entry.header_offset, 1024);
+            reader.volume_header().unwrap().clone()
+        }).collect();
+
+        assert_eq!(headers[1].sequence, 2);
+
+        let mut sequence = VolumeSequence::new();
+        sequence.accept(&headers[0]).unwrap();
+        assert!(sequence.accept(&headers[2]).is_err());
+        sequence.accept(&headers[1]).unwrap();
+        sequence.accept(&headers[2]).unwrap();
+
+        //A different volume 2 (say, from a rerun of the same backup) doesn't
+        //follow our volume 1.
+        let mut impostor = headers[1].clone();
+        impostor.previous_digest = Some([0; 32]);
+
+        let mut sequence = VolumeSequence::new();
+        sequence.accept(&headers[0]).unwrap();
+        assert_eq!(sequence.accept(&impostor).err().unwrap().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn reader_pax_time() {
+        assert_eq!(parse_pax_time("1500000000.5"), Some(time::UNIX_EPOCH + time::Duration::new(1_500_000_000, 500_000_000)));
+        assert_eq!(parse_pax_time("-10"), time::UNIX_EPOCH.checked_sub(time::Duration::from_secs(10)));
+        assert_eq!(parse_pax_time("-123.456789"), time::UNIX_EPOCH.checked_sub(time::Duration::new(123, 456_789_000)));
+        assert_eq!(parse_pax_time("12x"), None);
+    }
+}
@@ -0,0 +1,224 @@
+//! Rewriting archives in another format, as `--recode` does.
+//!
+//! Recoding reads each member of an archive and writes it out again with it's
+//! header encoded in the format asked for, never touching the filesystem. The
+//! archive is read as a stream, so old ustar or v7 tapes can be converted to
+//! pax (or one compression swapped for another) in a single pass.
+//!
+//! Sparse members stay sparse in formats which can record their holes, and
+//! are written out in full otherwise. Pax attributes we don't understand have
+//! no place in our headers, so they're dropped with a warning, as are members
+//! of types we don't know how to write.
+
+use std::io;
+use std::io::Read;
+use crate::tar::header::{TarFormat, TarFileType, encode_header};
+use crate::tar::reader::TarReader;
+use crate::tar::sparse::{self, SparseExtent};
+
+/// What was written out of an archive by `recode_members`.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct RecodedArchive {
+    /// How many members were written.
+    pub members: u64,
+
+    /// How many members couldn't be encoded in the new format, and were left
+    /// out.
+    pub failed: u64,
+
+    /// How many members were of a type we can't write, and were left out.
+    pub skipped: u64,
+
+    /// How many bytes were written.
+    pub bytes: u64,
+
+    /// Whether the archive ended with an end-of-archive marker.
+    pub terminated: bool,
+}
+
+/// Copy exactly `length` bytes of a member's data.
+fn copy_exactly<R: io::Read, W: io::Write + ?Sized>(reader: &mut TarReader<R>, sink: &mut W, length: u64) -> io::Result<u64> {
+    let copied = io::copy(&mut reader.by_ref().take(length), sink)?;
+
+    if copied < length {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Archive ends in the middle of a member"));
+    }
+
+    Ok(copied)
+}
+
+/// Copy the data extents of a sparse member, skipping the holes between them.
+fn copy_extents<R: io::Read, W: io::Write + ?Sized>(reader: &mut TarReader<R>, sink: &mut W, map: &[SparseExtent]) -> io::Result<u64> {
+    let mut position = 0;
+    let mut copied = 0;
+
+    for extent in map {
+        if extent.offset < position {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Sparse map has overlapping extents"));
+        }
+
+        copy_exactly(reader, &mut io::sink(), extent.offset - position)?;
+        copied += copy_exactly(reader, sink, extent.length)?;
+        position = extent.offset + extent.length;
+    }
+
+    Ok(copied)
+}
+
+/// Write every member of an archive into a sink in the given format, without
+/// an end-of-archive marker.
+///
+/// Members which can't be encoded in the new format, such as long names in
+/// ustar, are reported and left out; the rest of the archive is still
+/// recoded.
+pub fn recode_members<R: io::Read, W: io::Write + ?Sized>(source: R, sink: &mut W, format: TarFormat) -> io::Result<RecodedArchive> {
+    let mut reader = TarReader::new(source);
+    let mut recoded = RecodedArchive::default();
+
+    while let Some(entry) = reader.next_entry()? {
+        let mut header = entry.header;
+
+        if let TarFileType::Other(flag) = header.file_type {
+            warn!(path:? = header.path; "Leaving out member of unknown type {:?}", flag);
+            recoded.skipped += 1;
+            continue;
+        }
+
+        for (key, _) in entry.unknown_attributes.iter() {
+            warn!(path:? = header.path; "Dropping pax attribute {}", key);
+        }
+
+        if format == TarFormat::USTAR || header.file_type != TarFileType::FileStream {
+            header.sparse_map = None;
+        }
+
+        let encoded = match encode_header(&header, format) {
+            Ok(encoded) => encoded,
+            Err(e) => {
+                error!(path:? = header.path; "Cannot recode member: {}", e);
+                recoded.failed += 1;
+                continue;
+            }
+        };
+        let mut size = encoded.len() as u64;
+
+        sink.write_all(&encoded)?;
+
+        if header.file_type == TarFileType::FileStream {
+            size += match header.sparse_map {
+                Some(ref map) => {
+                    let map_data = match format {
+                        TarFormat::POSIX => sparse::format_sparse_map(map),
+                        _ => Vec::new()
+                    };
+
+                    sink.write_all(&map_data)?;
+                    map_data.len() as u64 + copy_extents(&mut reader, sink, map)?
+                },
+                None => copy_exactly(&mut reader, sink, header.file_size)?
+            };
+        }
+
+        let padding = (512 - size % 512) % 512;
+
+        sink.write_all(&vec![0; padding as usize])?;
+
+        recoded.members += 1;
+        recoded.bytes += size + padding;
+    }
+
+    recoded.terminated = reader.is_terminated();
+
+    Ok(recoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, path};
+    use std::io::Read;
+    use crate::tar::header::{TarFormat, TarHeader, TarFileType, headergen_in_memory};
+    use crate::tar::reader::TarReader;
+    use crate::tar::sparse::SparseExtent;
+    use crate::tar::gnu;
+    use super::recode_members;
+
+    fn read_all(archive: Vec<u8>) -> Vec<(String, Option<Vec<SparseExtent>>, Vec<u8>)> {
+        let mut reader = TarReader::new(io::Cursor::new(archive));
+        let mut members = vec![];
+
+        while let Some(entry) = reader.next_entry().unwrap() {
+            let mut data = vec![];
+
+            reader.read_to_end(&mut data).unwrap();
+            members.push((entry.header.path.to_string_lossy().into_owned(), entry.header.sparse_map, data));
+        }
+
+        members
+    }
+
+    #[test]
+    fn recode_formats() {
+        let mut archive = vec![];
+        let mut link = TarHeader::for_test("link", TarFileType::SymbolicLink, 0);
+        link.symlink_path = Some(Box::new(path::PathBuf::from("../".repeat(40) + "target")));
+
+        for (header, data) in [(TarHeader::for_test("file", TarFileType::FileStream, 0), vec![b's'; 700]), (link, vec![])].iter() {
+            let generated = headergen_in_memory(&header.path, header.clone(), TarFormat::POSIX, data.clone()).unwrap();
+
+            archive.extend(generated.encoded_header);
+            archive.extend(data);
+            archive.resize((archive.len() + 511) / 512 * 512, 0);
+        }
+
+        archive.extend(vec![0; 1024]);
+
+        let original = read_all(archive.clone());
+
+        let mut gnu = vec![];
+        let recoded = recode_members(io::Cursor::new(&archive), &mut gnu, TarFormat::GNU).unwrap();
+        assert_eq!((recoded.members, recoded.failed, recoded.bytes, recoded.terminated), (2, 0, gnu.len() as u64, true));
+        assert!(read_all(gnu) == original);
+
+        //ustar has nowhere to put the long link target.
+        let mut ustar = vec![];
+        let recoded = recode_members(io::Cursor::new(&archive), &mut ustar, TarFormat::USTAR).unwrap();
+        assert_eq!((recoded.members, recoded.failed), (1, 1));
+        assert_eq!(ustar.len(), 512 + 1024);
+        assert!(read_all(ustar) == original[..1]);
+    }
+
+    #[test]
+    fn recode_sparse() {
+        let map = vec![SparseExtent { offset: 0, length: 4 }, SparseExtent { offset: 4096, length: 9 }, SparseExtent { offset: 10000, length: 0 }];
+        let mut header = TarHeader::for_test("sparse", TarFileType::FileStream, 0);
+        header.file_size = 10000;
+        header.sparse_map = Some(map.clone());
+
+        let mut archive = gnu::gnu_header(&header).unwrap();
+        gnu::checksum_header(&mut archive);
+        archive.extend(b"headtail data");
+        archive.resize(archive.len() + 499 + 1024, 0);
+
+        let mut expected = vec![0; 10000];
+        expected[0..4].copy_from_slice(b"head");
+        expected[4096..4105].copy_from_slice(b"tail data");
+
+        for format in [TarFormat::POSIX, TarFormat::GNU, TarFormat::USTAR].iter() {
+            let mut recoded = vec![];
+            recode_members(io::Cursor::new(&archive), &mut recoded, *format).unwrap();
+
+            let members = read_all(recoded.clone());
+            assert_eq!(members.len(), 1);
+            assert_eq!(members[0].0, "sparse");
+            assert!(members[0].2 == expected);
+
+            match format {
+                TarFormat::USTAR => {
+                    assert_eq!(members[0].1, None);
+                    assert_eq!(recoded.len(), 512 + 10240);
+                },
+                _ => assert_eq!(members[0].1, Some(map.clone()))
+            }
+        }
+    }
+}
@@ -1,11 +1,51 @@
 //! Recovery code for handling surprise recoverable failures (e.g. volume full)
 //! necessary for spanning
+//!
+//! Archive data is written to sinks in *data zones*, one per member, each
+//! identified by a `RecoveryEntry`. When a sink fails partway through (most
+//! commonly because the volume filled up), whatever it hadn't committed to
+//! the device can be carried onto a new sink as a `PendingRecovery`:
+//!
+//!  1. Collect the lost zones from the failed sink with
+//!     `PendingRecovery::collect`, then close it.
+//!  2. Get a new volume from wherever your program gets them: ask the user,
+//!     drive a changer, or open the next file.
+//!  3. Write the lost data to the new sink with `resume_onto`. If that volume
+//!     fills up too, the pending recovery is updated in place; go back to 2.
+//!  4. Carry on archiving into the new sink.
+//!
+//! The end-of-archive marker is written in a zone of it's own, identified by
+//! `RecoveryEntry::terminator`, so that a volume which fails while it's being
+//! written gets it's marker on the next volume instead.
+//!
+//! ```no_run
+//! # use std::io;
+//! # use librapidarchive::{fs, tuning, cancel};
+//! # use librapidarchive::tar::header::TarFormat;
+//! # use librapidarchive::tar::recovery::{PendingRecovery, RecoveryEntry};
+//! # fn next_volume_path() -> String { unimplemented!() }
+//! # fn example(full_sink: Box<fs::ArchivalSink<RecoveryEntry>>) -> io::Result<Box<fs::ArchivalSink<RecoveryEntry>>> {
+//! let tuning = tuning::Configuration::default();
+//! let cancel = cancel::CancellationToken::new();
+//! let mut pending = PendingRecovery::collect(full_sink.as_ref(), TarFormat::POSIX);
+//!
+//! drop(full_sink);
+//!
+//! loop {
+//!     let mut sink = fs::open_sink(next_volume_path(), &tuning, None, &cancel)?;
+//!
+//!     if pending.resume_onto(sink.as_mut())? {
+//!         return Ok(sink);
+//!     }
+//! }
+//! # }
+//! ```
 
 use std::{fs, path, io};
 use std::io::Seek;
-use crate::tar::{ustar, pax};
+use crate::tar::{ustar, pax, gnu};
 use crate::tar::header::{TarFormat, TarHeader, TarFileType, HeaderGenResult};
-use crate::fs::ArchivalSink;
+use crate::fs::{ArchivalSink, ArchivalFile, AtimePolicy};
 use crate::spanning::DataZone;
 
 /// Information on how to recover from a failed serialization.
@@ -21,14 +61,43 @@ pub struct RecoveryEntry {
 
     /// Indicates how much of the zone is the tar header and how much is file data
     pub header_length: u64,
+
+    /// The abstract header the member was archived with, if known.
+    ///
+    /// Recovery headers are derived from this, rather than the file on disk,
+    /// so that any changes made to the header before archival carry over.
+    pub tar_header: Option<Box<TarHeader>>,
+
+    /// How the file's access time should be treated when it's reread.
+    pub atime_policy: AtimePolicy,
+
+    /// Whether this zone is the end-of-archive marker, rather than a member.
+    pub is_terminator: bool,
 }
 
+/// The size of the end-of-archive marker: two zero blocks.
+pub const TERMINATOR_SIZE: u64 = 1024;
+
 impl RecoveryEntry {
     pub fn new_from_headergen(hg : &HeaderGenResult, header_length: u64) -> RecoveryEntry {
         RecoveryEntry {
             original_path: hg.original_path.clone(),
             canonical_path: hg.canonical_path.clone(),
             header_length: header_length,
+            tar_header: Some(Box::new(hg.tar_header.clone())),
+            atime_policy: hg.atime_policy,
+            is_terminator: false,
+        }
+    }
+
+    /// Identify the zone holding the end-of-archive marker.
+    ///
+    /// The whole marker counts as header, since there's no file to read it
+    /// back from.
+    pub fn terminator() -> RecoveryEntry {
+        RecoveryEntry {
+            is_terminator: true,
+            ..RecoveryEntry::new(&path::PathBuf::new(), &path::PathBuf::new(), TERMINATOR_SIZE)
         }
     }
 
@@ -36,7 +105,10 @@ impl RecoveryEntry {
         RecoveryEntry {
             original_path: Box::new(original_path.as_ref().to_path_buf()),
             canonical_path: Box::new(canonical_path.as_ref().to_path_buf()),
-            header_length: header_length
+            header_length: header_length,
+            tar_header: None,
+            atime_policy: AtimePolicy::default(),
+            is_terminator: false
         }
     }
 
@@ -45,6 +117,115 @@ impl RecoveryEntry {
     }
 }
 
+/// Archive data lost from a failed sink, waiting to be written to a new one.
+#[derive(Clone)]
+pub struct PendingRecovery {
+    zones: Vec<DataZone<RecoveryEntry>>,
+    format: TarFormat,
+}
+
+impl PendingRecovery {
+    /// Recover a list of lost zones, in the given tar format.
+    pub fn new(zones: Vec<DataZone<RecoveryEntry>>, format: TarFormat) -> PendingRecovery {
+        PendingRecovery {
+            zones: zones,
+            format: format
+        }
+    }
+
+    /// Collect the data a failed sink did not commit to it's device.
+    ///
+    /// The sink should be closed afterwards, as nothing further written to it
+    /// is guaranteed to reach the device.
+    pub fn collect(sink: &ArchivalSink<RecoveryEntry>, format: TarFormat) -> PendingRecovery {
+        PendingRecovery::new(sink.uncommitted_writes(), format)
+    }
+
+    /// The zones which still need to be written.
+    pub fn zones(&self) -> &[DataZone<RecoveryEntry>] {
+        &self.zones
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.zones.iter().all(|zone| zone.ident.is_none())
+    }
+
+    /// How many members will be continued or rewritten on the next volume.
+    pub fn members(&self) -> usize {
+        self.zones.iter().filter(|zone| zone.ident.as_ref().map_or(false, |ident| !ident.is_terminator)).count()
+    }
+
+    /// The first member to be continued on the next volume.
+    ///
+    /// Volume labels record this member, so that readers can tell the volume
+    /// continues a previous one.
+    pub fn first_member(&self) -> Option<&DataZone<RecoveryEntry>> {
+        self.zones.iter().find(|zone| zone.ident.as_ref().map_or(false, |ident| !ident.is_terminator))
+    }
+
+    /// Determine if the end-of-archive marker is among the lost data.
+    ///
+    /// If so, the archive will be complete once the recovery is, and nothing
+    /// more should be written after it.
+    pub fn has_terminator(&self) -> bool {
+        self.zones.iter().any(|zone| zone.ident.as_ref().map_or(false, |ident| ident.is_terminator))
+    }
+
+    /// Estimate how many bytes recovery will write to the next volume.
+    ///
+    /// Sizes are taken from the files on disk, so this is only an estimate if
+    /// they have changed since they were archived.
+    pub fn estimated_size(&self) -> u64 {
+        self.zones.iter().map(|zone| match zone.ident {
+            Some(ref ident) if ident.is_terminator => TERMINATOR_SIZE,
+            Some(ref ident) => {
+                let file_size = fs::symlink_metadata(ident.canonical_path.as_ref()).map(|m| m.len()).unwrap_or(0);
+                let data_committed = zone.committed_length.saturating_sub(ident.header_length);
+
+                ident.header_length + file_size.saturating_sub(data_committed)
+            },
+            None => 0
+        }).sum()
+    }
+
+    /// Write the lost data to a new sink.
+    ///
+    /// Yields `Ok(true)` once everything has been written, after which the
+    /// sink may be archived into as normal. If the new sink failed as well,
+    /// yields `Ok(false)`, and this recovery is updated to hold whatever the
+    /// new sink lost, ready for yet another sink. Errors reading the files
+    /// being recovered yield `Err`.
+    pub fn resume_onto(&mut self, sink: &mut ArchivalSink<RecoveryEntry>) -> io::Result<bool> {
+        match recover_data(sink, self.format, self.zones.clone())? {
+            None => {
+                self.zones.clear();
+                Ok(true)
+            },
+            Some(zones) => {
+                self.zones = zones;
+                Ok(false)
+            }
+        }
+    }
+}
+
+/// Find the tape block just past the data a failed sink committed.
+///
+/// A tape drive which lost it's position must be put back at the end of what
+/// it committed before anything more is written to it. This is counted in
+/// records from `start_block`, where the volume began, and not from how much
+/// was archived onto the volume, which may have been compressed or include
+/// the estimated size of a recovery.
+pub fn reposition_block(sink: &ArchivalSink<RecoveryEntry>, start_block: u64, record_size: u64) -> io::Result<u64> {
+    let committed = sink.committed_bytes().ok_or(io::Error::new(io::ErrorKind::Other, "Sink does not count how much data it committed"))?;
+
+    if committed % record_size != 0 {
+        return Err(io::Error::new(io::ErrorKind::Other, "Committed data does not end on a record boundary"));
+    }
+
+    Ok(start_block + committed / record_size)
+}
+
 /// Given a list of failed `DataZone`s, write a *recovery stream* to a new sink
 /// containing the lost data.
 /// 
@@ -74,9 +255,25 @@ pub fn recover_data(sink: &mut ArchivalSink<RecoveryEntry>, format: TarFormat, l
     let mut outstanding_entry = None;
 
     while let Some(zone) = iter.next() {
-        if let Some(ident) = &zone.ident {
+        if let Some(ident) = zone.ident.as_ref().filter(|ident| ident.is_terminator) {
+            //Part of the marker may have made it onto the last volume, but a
+            //new volume needs all of it.
+            outstanding_entry = Some(ident.clone());
+            sink.begin_data_zone(ident.clone());
+
+            if let Err(e) = sink.write_all(&[0; TERMINATOR_SIZE as usize]) {
+                warn!("Recovery volume failed while writing end-of-archive marker: {}", e);
+                break;
+            }
+
+            sink.end_data_zone();
+            outstanding_entry = None;
+        } else if let Some(ident) = &zone.ident {
             let metadata = fs::symlink_metadata(&ident.canonical_path.as_ref())?;
-            let mut recovery_header = TarHeader::with_recovery(&ident.original_path, &metadata, &ident.canonical_path, zone)?;
+            let mut recovery_header = match ident.tar_header {
+                Some(ref tar_header) => (**tar_header).clone().into_recovery(zone),
+                None => TarHeader::with_recovery(&ident.original_path, &metadata, &ident.canonical_path, zone)?
+            };
             let offset;
             let mut concrete_tarheader;
             
@@ -96,17 +293,33 @@ pub fn recover_data(sink: &mut ArchivalSink<RecoveryEntry>, format: TarFormat, l
                     
                     concrete_tarheader = pax::pax_header(&recovery_header)?;
                     pax::checksum_header(&mut concrete_tarheader);
+                },
+                TarFormat::GNU => {
+                    //We don't write GNU multivolume headers, so this is the
+                    //same as USTAR.
+                    offset = 0;
+                    if let Some(header_offset) = recovery_header.recovery_seek_offset {
+                        recovery_header.file_size += header_offset;
+                    }
+
+                    concrete_tarheader = gnu::gnu_header(&recovery_header)?;
+                    gnu::checksum_header(&mut concrete_tarheader);
                 }
             }
 
             //TODO: This should be unnecessary as we are usually handed data from traverse
             let canonical_path = fs::canonicalize(&ident.canonical_path.as_ref())?;
-            let new_ident = RecoveryEntry::new(&ident.original_path.as_ref(), &ident.canonical_path.as_ref(), concrete_tarheader.len() as u64);
+            let mut new_ident = RecoveryEntry::new(&ident.original_path.as_ref(), &ident.canonical_path.as_ref(), concrete_tarheader.len() as u64);
+            new_ident.tar_header = ident.tar_header.clone();
+            new_ident.atime_policy = ident.atime_policy;
             
+            debug!(path:? = ident.original_path; "Recovering torn member from offset {}", offset);
+
             outstanding_entry = Some(new_ident.clone());
             sink.resume_data_zone(new_ident, zone.committed_length.checked_sub(ident.header_length).unwrap_or(0));
 
-            if let Err(_) = sink.write_all(&concrete_tarheader) {
+            if let Err(e) = sink.write_all(&concrete_tarheader) {
+                warn!(path:? = ident.original_path; "Recovery volume failed while writing header: {}", e);
                 break;
             }
 
@@ -114,16 +327,21 @@ pub fn recover_data(sink: &mut ArchivalSink<RecoveryEntry>, format: TarFormat, l
             //We really should fail the archival operation entirely instead.
             let recovery_result = match recovery_header.file_type {
                 TarFileType::FileStream => {
-                    let mut file = fs::File::open(canonical_path)?;
+                    let mut file = ArchivalFile::open(canonical_path, recovery_header.atime, ident.atime_policy)?;
 
                     file.seek(io::SeekFrom::Start(offset))?;
 
-                    io::copy(&mut file, sink).map(|_| ())
+                    //The member has to end on a block boundary for the
+                    //marker, or anything else, to follow it.
+                    io::copy(&mut file, sink).and_then(|copied| {
+                        sink.write_all(&vec![0; ((512 - copied % 512) % 512) as usize])
+                    })
                 },
                 _ => Ok(())
             };
 
-            if let Err(_) = recovery_result {
+            if let Err(e) = recovery_result {
+                warn!(path:? = ident.original_path; "Recovery volume failed while writing data: {}", e);
                 break;
             }
 
@@ -145,3 +363,113 @@ pub fn recover_data(sink: &mut ArchivalSink<RecoveryEntry>, format: TarFormat, l
 
     Ok(None)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+    use std::io::Write;
+    use crate::blocking::BlockingWriter;
+    use crate::spanning::DataZone;
+    use crate::tape::memory::MemoryTapeDevice;
+    use crate::tar::header::TarFormat;
+    use crate::testing::{RecordingSink, SinkEvent};
+    use super::{PendingRecovery, RecoveryEntry, reposition_block};
+
+    #[test]
+    fn pending_recovery() {
+        let path = env::temp_dir().join(format!("rapidtar-recovery-test-{}", process::id()));
+        let data : Vec<u8> = (0..2000).map(|i| i as u8).collect();
+        fs::write(&path, &data).unwrap();
+
+        //The header and 488 bytes of data made it onto the old volume.
+        let mut zone = DataZone::new(RecoveryEntry::new(&path, &path, 512));
+        zone.length = 2560;
+        zone.committed_length = 1000;
+        zone.uncommitted_length = 1560;
+
+        let mut pending = PendingRecovery::new(vec![DataZone::slack_zone(), zone], TarFormat::POSIX);
+        assert_eq!(pending.members(), 1);
+        assert_eq!(pending.estimated_size(), 512 + 1512);
+
+        let mut sink = RecordingSink::new();
+        let log = sink.log();
+
+        assert!(pending.resume_onto(&mut sink).unwrap());
+        assert!(pending.is_empty());
+
+        match log.events().first() {
+            Some(SinkEvent::ResumeDataZone(ident, 488)) => assert!(ident.canonical_path.as_path() == path.as_path()),
+            _ => panic!("Recovery should resume the torn zone")
+        }
+
+        let written = log.data();
+        assert!(written[..written.len() - 24].ends_with(&data[488..]));
+        assert_eq!(&written[written.len() - 24..], &[0; 24][..]);
+        assert_eq!(written.len() % 512, 0);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn terminator_recovery() {
+        //Half of the marker made it onto the old volume.
+        let mut zone = DataZone::new(RecoveryEntry::terminator());
+        zone.length = 1024;
+        zone.committed_length = 512;
+        zone.uncommitted_length = 512;
+
+        let mut pending = PendingRecovery::new(vec![DataZone::slack_zone(), zone], TarFormat::POSIX);
+        assert_eq!(pending.members(), 0);
+        assert!(pending.first_member().is_none());
+        assert!(pending.has_terminator());
+        assert_eq!(pending.estimated_size(), 1024);
+
+        let mut sink = RecordingSink::new();
+        let log = sink.log();
+
+        assert!(pending.resume_onto(&mut sink).unwrap());
+
+        match log.events().first() {
+            Some(SinkEvent::BeginDataZone(ident)) => assert!(ident.is_terminator),
+            _ => panic!("Recovery should rewrite the marker in it's own zone")
+        }
+
+        assert_eq!(log.data(), vec![0; 1024]);
+    }
+
+    #[test]
+    fn reposition_after_recovery() {
+        let path = env::temp_dir().join(format!("rapidtar-reposition-test-{}", process::id()));
+        fs::write(&path, vec![1; 2000]).unwrap();
+
+        let mut zone = DataZone::new(RecoveryEntry::new(&path, &path, 512));
+        zone.length = 2560;
+        zone.committed_length = 1000;
+        zone.uncommitted_length = 1560;
+
+        let mut pending = PendingRecovery::new(vec![zone], TarFormat::POSIX);
+        let estimate = pending.estimated_size();
+
+        //The file grows after the estimate, so recovery writes more than was
+        //expected.
+        fs::write(&path, vec![1; 5000]).unwrap();
+
+        //The volume starts after two records of an earlier tape file.
+        let mut tape = MemoryTapeDevice::<RecoveryEntry>::new();
+        tape.write_all(&[0; 1024]).unwrap();
+        tape.write_all(&[0; 1024]).unwrap();
+
+        let mut sink = BlockingWriter::new_with_record_size(tape, 1024);
+
+        assert!(pending.resume_onto(&mut sink).unwrap());
+
+        //A partial record is never committed.
+        sink.write_all(&[0; 300]).unwrap();
+
+        let committed_records = sink.as_inner_writer().records().len() as u64;
+        assert!(committed_records > 2 + estimate / 1024 + 1);
+        assert_eq!(reposition_block(&sink, 2, 1024).unwrap(), committed_records);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,90 @@
+//! Renaming members as they are extracted.
+//!
+//! A `RenameMap` moves members from one place in the archive's directory
+//! layout to another, so that an archive can be restored straight into a
+//! reorganized tree instead of a staging area. Each rename applies to the
+//! member of that name and, if it's a directory, everything beneath it. Where
+//! several renames apply to a member, the most specific one wins.
+//!
+//! Rename map files are text, one rename per line: the old path, a tab, and
+//! the new path. Paths are escaped in the same manner as catalogs. Blank lines
+//! and lines starting with `#` are ignored.
+
+use std::{io, fs, path};
+use crate::tar::catalog::unescape_path;
+
+/// A set of renames to apply to archive members.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RenameMap {
+    renames: Vec<(path::PathBuf, path::PathBuf)>
+}
+
+impl RenameMap {
+    pub fn new() -> RenameMap {
+        RenameMap::default()
+    }
+
+    /// Rename members at or beneath `old` to `new`.
+    pub fn insert<P: AsRef<path::Path>, Q: AsRef<path::Path>>(&mut self, old: P, new: Q) {
+        self.renames.push((old.as_ref().to_path_buf(), new.as_ref().to_path_buf()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.renames.is_empty()
+    }
+
+    /// Parse the contents of a rename map file.
+    pub fn from_text(text: &str) -> io::Result<RenameMap> {
+        let mut map = RenameMap::new();
+
+        for line in text.lines().filter(|line| !line.is_empty() && !line.starts_with('#')) {
+            let fields : Vec<&str> = line.splitn(2, '\t').collect();
+
+            match fields.as_slice() {
+                [old, new] if !old.is_empty() => map.insert(unescape_path(old), unescape_path(new)),
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Invalid rename map line {:?}", line)))
+            }
+        }
+
+        Ok(map)
+    }
+
+    /// Load a rename map file.
+    pub fn load<P: AsRef<path::Path>>(map_file: P) -> io::Result<RenameMap> {
+        RenameMap::from_text(&fs::read_to_string(map_file)?)
+    }
+
+    /// Determine where a member should be extracted to.
+    ///
+    /// Yields `None` if no rename applies, in which case the member should be
+    /// extracted under it's own name.
+    pub fn rename(&self, member_path: &path::Path) -> Option<path::PathBuf> {
+        self.renames.iter()
+            .filter_map(|(old, new)| member_path.strip_prefix(old).ok().map(|rest| (old, new, rest)))
+            .max_by_key(|(old, _, _)| old.components().count())
+            .map(|(_, new, rest)| match rest.as_os_str().is_empty() {
+                true => new.clone(),
+                false => new.join(rest)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+    use super::RenameMap;
+
+    #[test]
+    fn rename_map() {
+        let map = RenameMap::from_text("# moves\nhome/old\tsrv/new\nhome/old/keep\thome/keep\n\nodd\\tname\tplain\n").unwrap();
+        let rename = |p| map.rename(path::Path::new(p));
+
+        assert_eq!(rename("home/old"), Some(path::PathBuf::from("srv/new")));
+        assert_eq!(rename("home/old/a/b.txt"), Some(path::PathBuf::from("srv/new/a/b.txt")));
+        assert_eq!(rename("home/old/keep/c"), Some(path::PathBuf::from("home/keep/c")));
+        assert_eq!(rename("home/older"), None);
+        assert_eq!(rename("odd\tname"), Some(path::PathBuf::from("plain")));
+
+        assert!(RenameMap::from_text("no tab here\n").is_err());
+    }
+}
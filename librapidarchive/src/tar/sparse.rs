@@ -0,0 +1,410 @@
+//! Archiving sparse files without their holes.
+//!
+//! Files such as virtual machine disk images are often mostly holes, which
+//! read back as zeroes but take up no space on disk. Archiving them as-is
+//! stores every one of those zeroes. Instead, a sparse member records a
+//! *sparse map* of where the file's data lies, and only that data is written
+//! to the archive; everything else is restored as a hole.
+//!
+//! Two encodings of sparse maps are supported:
+//!
+//!  * GNU format archives use old GNU sparse members (type `S`), whose maps
+//!    are kept in the header and any extension blocks following it.
+//!  * pax format archives use GNU sparse format 1.0, whose maps are written
+//!    at the start of the member's data. The member is named
+//!    `GNUSparseFile.0/...` in it's ustar header, with the real name in a
+//!    `GNU.sparse.name` attribute, so that readers which don't understand
+//!    sparse members extract the packed data off to one side rather than
+//!    over the real file. GNU tar's older 0.0 and 0.1 pax encodings can be
+//!    read, but aren't written.
+//!
+//! USTAR can't record holes at all, so sparse files are archived in full.
+
+use std::{fs, io, path};
+use crate::fs::{sparse_extents, sparse_extents_of_file};
+use crate::tar::filter::{EntryAction, EntryFilter};
+use crate::tar::header::{TarHeader, TarFileType};
+
+/// The most extents we're willing to read from a single sparse map.
+const MAX_SPARSE_EXTENTS: u64 = 1024 * 1024;
+
+/// A run of data within a sparse file.
+///
+/// Everything between extents is a hole, and is restored as zeroes.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SparseExtent {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// How many bytes of data a sparse map holds.
+pub fn data_size(map: &[SparseExtent]) -> u64 {
+    map.iter().map(|extent| extent.length).sum()
+}
+
+/// Prepare the data extents of a file for archival as a sparse map.
+///
+/// GNU tar expects the last extent of a map to reach the end of the file, so
+/// files ending in a hole get an empty extent there.
+pub fn archival_map(mut extents: Vec<SparseExtent>, file_size: u64) -> Vec<SparseExtent> {
+    let end = extents.last().map(|extent| extent.offset + extent.length).unwrap_or(0);
+
+    if end < file_size || extents.is_empty() {
+        extents.push(SparseExtent {
+            offset: file_size,
+            length: 0
+        });
+    }
+
+    extents
+}
+
+/// Format a sparse map the way GNU sparse format 1.0 writes it ahead of a
+/// member's data.
+///
+/// The map is written as decimal lines: the number of extents, then each
+/// extent's offset and length. It's padded out to a whole number of blocks.
+pub fn format_sparse_map(map: &[SparseExtent]) -> Vec<u8> {
+    let mut text = format!("{}\n", map.len());
+
+    for extent in map {
+        text.push_str(&format!("{}\n{}\n", extent.offset, extent.length));
+    }
+
+    let mut data = text.into_bytes();
+    let padding_needed = data.len() % 512;
+
+    if padding_needed != 0 {
+        data.extend(vec![0; 512 - padding_needed]);
+    }
+
+    data
+}
+
+/// Parse a sparse map in GNU sparse format 1.0 from the start of a member's
+/// data.
+///
+/// Yields the map and how many bytes it takes up, including padding, or None
+/// if `data` ends before the map does. Maps are read a block at a time, so
+/// more of the member should be read and tried again in that case.
+pub fn parse_sparse_map(data: &[u8]) -> io::Result<Option<(Vec<SparseExtent>, usize)>> {
+    let malformed = || io::Error::new(io::ErrorKind::InvalidData, "Malformed sparse map");
+    let mut lines = data.split(|b| *b == b'\n');
+    let mut consumed = 0;
+    let mut numbers = Vec::new();
+    let mut count = None;
+
+    while count.map_or(true, |count: u64| (numbers.len() as u64) < count * 2) {
+        let line = match lines.next() {
+            Some(line) if consumed + line.len() < data.len() => line,
+            _ => return Ok(None)
+        };
+
+        consumed += line.len() + 1;
+
+        let number : u64 = String::from_utf8_lossy(line).parse().map_err(|_| malformed())?;
+
+        match count {
+            None if number > MAX_SPARSE_EXTENTS => return Err(malformed()),
+            None => count = Some(number),
+            Some(_) => numbers.push(number)
+        }
+    }
+
+    let map = numbers.chunks(2).map(|pair| SparseExtent {
+        offset: pair[0],
+        length: pair[1]
+    }).collect();
+
+    Ok(Some((map, (consumed + 511) / 512 * 512)))
+}
+
+/// Parse the comma-separated offsets and lengths of a `GNU.sparse.map` pax
+/// attribute, as written by GNU sparse format 0.1.
+pub fn parse_sparse_pairs(text: &str) -> io::Result<Vec<SparseExtent>> {
+    let numbers = text.split(',').map(|number| number.trim().parse::<u64>()).collect::<Result<Vec<_>, _>>().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Malformed sparse map"))?;
+
+    if numbers.len() % 2 != 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed sparse map"));
+    }
+
+    Ok(numbers.chunks(2).map(|pair| SparseExtent {
+        offset: pair[0],
+        length: pair[1]
+    }).collect())
+}
+
+/// The sparse map and real size described by a member's pax attributes, in
+/// any of GNU tar's pax sparse formats.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SparseAttributes {
+    /// The real name of the member.
+    pub name: Option<String>,
+
+    /// The size of the file, holes and all.
+    pub real_size: Option<u64>,
+
+    /// The map, for formats which keep it in the attributes.
+    pub map: Vec<SparseExtent>,
+
+    /// Whether the map is at the start of the member's data (format 1.0).
+    pub map_in_data: bool,
+}
+
+impl SparseAttributes {
+    /// Collect the sparse attributes of a member, if it has any.
+    pub fn from_attributes<V: AsRef<[u8]>>(attributes: &[(String, V)]) -> io::Result<Option<SparseAttributes>> {
+        let mut sparse = SparseAttributes::default();
+        let mut major = None;
+        let mut is_sparse = false;
+        let mut pending_offset = None;
+
+        for (key, value) in attributes {
+            let text = String::from_utf8_lossy(value.as_ref());
+            let number = || text.trim().parse::<u64>().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Malformed {} attribute", key)));
+
+            match key.as_str() {
+                "GNU.sparse.name" => sparse.name = Some(text.into_owned()),
+                "GNU.sparse.realsize" | "GNU.sparse.size" => sparse.real_size = Some(number()?),
+                "GNU.sparse.major" => major = Some(number()?),
+                "GNU.sparse.map" => sparse.map = parse_sparse_pairs(&text)?,
+                "GNU.sparse.offset" => pending_offset = Some(number()?),
+                "GNU.sparse.numbytes" => if let Some(offset) = pending_offset.take() {
+                    sparse.map.push(SparseExtent {
+                        offset: offset,
+                        length: number()?
+                    });
+                },
+                _ => continue
+            }
+
+            is_sparse = true;
+        }
+
+        sparse.map_in_data = major == Some(1);
+
+        match is_sparse && sparse.real_size.is_some() {
+            true => Ok(Some(sparse)),
+            false => Ok(None)
+        }
+    }
+}
+
+/// The name a sparse member goes by in it's ustar header, in GNU sparse
+/// format 1.0.
+pub fn sparse_member_name(path: &path::Path) -> path::PathBuf {
+    let name = path.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+
+    path.parent().unwrap_or_else(|| path::Path::new("")).join("GNUSparseFile.0").join(name)
+}
+
+/// A reader which reads only the extents of a sparse map out of a file.
+pub struct ExtentReader<R: io::Read + io::Seek> {
+    inner: R,
+    extents: Vec<SparseExtent>,
+    next: usize,
+    remaining: u64,
+}
+
+impl<R: io::Read + io::Seek> ExtentReader<R> {
+    pub fn new(inner: R, extents: Vec<SparseExtent>) -> ExtentReader<R> {
+        ExtentReader {
+            inner: inner,
+            extents: extents,
+            next: 0,
+            remaining: 0
+        }
+    }
+}
+
+impl<R: io::Read + io::Seek> io::Read for ExtentReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.remaining == 0 {
+            let extent = match self.extents.get(self.next) {
+                Some(extent) => *extent,
+                None => return Ok(0)
+            };
+
+            self.inner.seek(io::SeekFrom::Start(extent.offset))?;
+            self.remaining = extent.length;
+            self.next += 1;
+        }
+
+        let want = (buf.len() as u64).min(self.remaining) as usize;
+        let read = self.inner.read(&mut buf[..want])?;
+
+        self.remaining -= read as u64;
+
+        Ok(read)
+    }
+}
+
+/// A filter which finds the holes in sparse files, so that they are archived
+/// without them, as `tar --sparse` does.
+///
+/// Finding holes can cost an extra open for every file that might have any,
+/// so the filter should only be used when asked for. Files whose holes can't
+/// be found are archived in full.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct DetectSparse;
+
+impl EntryFilter for DetectSparse {
+    fn filter_entry(&self, header: &mut TarHeader, entry_path: &path::Path, metadata: &fs::Metadata) -> EntryAction {
+        if header.file_type != TarFileType::FileStream {
+            return EntryAction::Keep;
+        }
+
+        record_extents(header, entry_path, sparse_extents(metadata, entry_path))
+    }
+
+    fn filter_open_entry(&self, header: &mut TarHeader, entry_path: &path::Path, metadata: &fs::Metadata, file: &fs::File) -> EntryAction {
+        if header.file_type != TarFileType::FileStream {
+            return EntryAction::Keep;
+        }
+
+        record_extents(header, entry_path, sparse_extents_of_file(metadata, file))
+    }
+}
+
+fn record_extents(header: &mut TarHeader, entry_path: &path::Path, extents: io::Result<Option<Vec<SparseExtent>>>) -> EntryAction {
+    match extents {
+        Ok(Some(extents)) => header.sparse_map = Some(archival_map(extents, header.file_size)),
+        Ok(None) => {},
+        Err(e) => warn!(path:? = entry_path; "Could not find holes in sparse file, archiving it in full: {}", e)
+    }
+
+    EntryAction::Keep
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, io, path, process};
+    use std::io::Read;
+    use crate::cancel::CancellationToken;
+    use crate::fs::AtimePolicy;
+    use crate::tar::serialize;
+    use crate::tar::header::{TarFormat, TarHeader, headergen};
+    use crate::tar::reader::TarReader;
+    use super::{SparseExtent, SparseAttributes, ExtentReader, archival_map, format_sparse_map, parse_sparse_map};
+
+    #[test]
+    fn sparse_maps() {
+        let map = archival_map(vec![SparseExtent { offset: 0, length: 10 }, SparseExtent { offset: 4096, length: 512 }], 10000);
+        assert_eq!(map.last(), Some(&SparseExtent { offset: 10000, length: 0 }));
+        assert_eq!(archival_map(vec![], 10000), vec![SparseExtent { offset: 10000, length: 0 }]);
+
+        let data = format_sparse_map(&map);
+        assert_eq!(data.len(), 512);
+        assert!(data.starts_with(b"3\n0\n10\n4096\n512\n10000\n0\n\0"));
+
+        assert_eq!(parse_sparse_map(&data).unwrap(), Some((map.clone(), 512)));
+        assert_eq!(parse_sparse_map(&data[..10]).unwrap(), None);
+        assert!(parse_sparse_map(b"2\nfoo\n").is_err());
+
+        let attributes = vec![
+            ("GNU.sparse.size".to_string(), "10000".to_string()),
+            ("GNU.sparse.numblocks".to_string(), "2".to_string()),
+            ("GNU.sparse.offset".to_string(), "0".to_string()),
+            ("GNU.sparse.numbytes".to_string(), "10".to_string()),
+            ("GNU.sparse.offset".to_string(), "4096".to_string()),
+            ("GNU.sparse.numbytes".to_string(), "512".to_string())
+        ];
+        let sparse = SparseAttributes::from_attributes(&attributes).unwrap().unwrap();
+        assert_eq!((sparse.real_size, sparse.map_in_data), (Some(10000), false));
+        assert_eq!(sparse.map, map[..2].to_vec());
+
+        let attributes = vec![("GNU.sparse.map".to_string(), "0,10,4096,512".to_string()), ("GNU.sparse.size".to_string(), "10000".to_string())];
+        assert_eq!(SparseAttributes::from_attributes(&attributes).unwrap().unwrap().map, map[..2].to_vec());
+        assert_eq!(SparseAttributes::from_attributes(&[("path".to_string(), "file".to_string())]).unwrap(), None);
+    }
+
+    #[test]
+    fn extent_reader() {
+        let file : Vec<u8> = (0..100).collect();
+        let mut reader = ExtentReader::new(io::Cursor::new(file), vec![SparseExtent { offset: 10, length: 3 }, SparseExtent { offset: 50, length: 2 }, SparseExtent { offset: 100, length: 0 }]);
+        let mut data = vec![];
+
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, vec![10, 11, 12, 50, 51]);
+    }
+
+    #[test]
+    fn sparse_roundtrip() {
+        let entry_path = env::temp_dir().join(format!("rapidtar-sparse-{}", process::id()));
+        let mut data = vec![0; 64 * 1024];
+        let mut extents = vec![];
+
+        //Enough extents to need GNU extension blocks.
+        for i in 0..30 {
+            let offset = i * 2048;
+
+            data[offset..offset + 100].iter_mut().for_each(|b| *b = i as u8 + 1);
+            extents.push(SparseExtent { offset: offset as u64, length: 100 });
+        }
+
+        fs::write(&entry_path, &data).unwrap();
+
+        let map = archival_map(extents, data.len() as u64);
+        let metadata = fs::symlink_metadata(&entry_path).unwrap();
+
+        for format in [TarFormat::POSIX, TarFormat::GNU, TarFormat::USTAR].iter() {
+            let mut header = TarHeader::abstract_header_for_file(path::Path::new("dir/sparse"), &metadata, &entry_path).unwrap();
+            header.sparse_map = Some(map.clone());
+
+            let hg = headergen(&entry_path, path::Path::new("dir/sparse"), header, *format, AtimePolicy::Ignore, 64 * 1024, &CancellationToken::new()).unwrap();
+            let mut archive = io::Cursor::new(vec![]);
+
+            serialize::<u64>(&hg, &mut archive, &mut 0, None, &CancellationToken::new()).unwrap();
+            archive.get_mut().extend(vec![0; 1024]);
+
+            let archive = archive.into_inner();
+            let mut reader = TarReader::new(io::Cursor::new(&archive));
+            let entry = reader.next_entry().unwrap().unwrap();
+            let mut restored = vec![];
+
+            reader.read_to_end(&mut restored).unwrap();
+
+            assert_eq!(entry.header.path.to_str(), Some("dir/sparse"));
+            assert_eq!(entry.header.file_size, data.len() as u64);
+            assert!(restored == data);
+            assert!(reader.next_entry().unwrap().is_none());
+
+            match format {
+                TarFormat::USTAR => assert_eq!(entry.header.sparse_map, None),
+                _ => {
+                    assert_eq!(entry.header.sparse_map.as_ref(), Some(&map));
+                    assert!(archive.len() < data.len() / 4);
+                }
+            }
+        }
+
+        fs::remove_file(&entry_path).unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn detect_holes() {
+        use std::io::{Seek, Write};
+        use crate::fs::sparse_extents;
+
+        let entry_path = env::temp_dir().join(format!("rapidtar-holes-{}", process::id()));
+        let mut file = fs::File::create(&entry_path).unwrap();
+
+        file.set_len(4 * 1024 * 1024).unwrap();
+        file.seek(io::SeekFrom::Start(1024 * 1024)).unwrap();
+        file.write_all(&[1; 4096]).unwrap();
+        file.sync_all().unwrap();
+
+        let metadata = fs::symlink_metadata(&entry_path).unwrap();
+        let extents = sparse_extents(&metadata, &entry_path).unwrap();
+
+        fs::remove_file(&entry_path).unwrap();
+
+        //Not every filesystem can make holes, but those that do should find
+        //the data we wrote.
+        if let Some(extents) = extents {
+            assert!(extents.iter().any(|extent| extent.offset <= 1024 * 1024 && extent.offset + extent.length >= 1024 * 1024 + 4096));
+            assert!(extents.iter().map(|extent| extent.length).sum::<u64>() < 4 * 1024 * 1024);
+        }
+    }
+}
@@ -1,10 +1,10 @@
 //! Implementation of the UNIX Standard tar format, aka USTAR.
 
-use std::{io, path, time, fmt};
+use std::{io, path, time, fmt, cmp};
 use pad::{PadStr, Alignment};
 use crate::tar::pax;
 use crate::tar::header::{TarHeader, TarFileType};
-use crate::tar::canonicalized_tar_path;
+use crate::tar::{canonicalized_tar_path, canonicalized_link_target, PathStyle};
 use num;
 use num_traits;
 
@@ -12,9 +12,14 @@ use num_traits;
 /// 
 /// If the number is too large to fit, this function yields None.
 pub fn format_tar_numeral<N: num::Integer>(number: N, field_size: usize) -> Option<Vec<u8>> where N: fmt::Octal + num_traits::cast::ToPrimitive {
-    let numsize = number.to_f32()?.log(8.0);
-    
-    if numsize >= (field_size as f32 - 1.0) {
+    let digits = (field_size as u32 - 1) * 3;
+
+    let fits = match number.to_u64() {
+        Some(n) => n.checked_shr(digits).unwrap_or(0) == 0,
+        None => false
+    };
+
+    if !fits {
         None
     } else {
         let mut value = format!("{:o}", number).pad(field_size - 1, '0', Alignment::Right, true).into_bytes();
@@ -38,11 +43,36 @@ pub fn format_tar_string(the_string: &str, field_size: usize) -> Option<Vec<u8>>
     }
 }
 
-fn format_tar_time(dirtime: &time::SystemTime) -> io::Result<Vec<u8>> {
-    match dirtime.duration_since(time::UNIX_EPOCH) {
-        Ok(unix_duration) => format_tar_numeral(unix_duration.as_secs(), 12).ok_or(io::Error::new(io::ErrorKind::InvalidData, "Tar numeral too large")),
-        Err(_) => Err(io::Error::new(io::ErrorKind::InvalidData, "File older than UNIX")) //TODO: Negative time
+/// The latest time a ustar header can hold, in seconds since the UNIX epoch.
+const MAX_TAR_TIME: u64 = 0o77777777777;
+
+/// Format a time for the mtime field of a ustar header.
+///
+/// Ustar headers only hold whole seconds from 1970 up to the year 2242, with no
+/// way to write times before the UNIX epoch. Times outside that range are
+/// clamped to the nearest one it can hold; the posix and gnu formats archive
+/// them exactly.
+fn format_tar_time(dirtime: &time::SystemTime) -> Vec<u8> {
+    let seconds = match dirtime.duration_since(time::UNIX_EPOCH) {
+        Ok(unix_duration) => cmp::min(unix_duration.as_secs(), MAX_TAR_TIME),
+        Err(_) => 0
+    };
+
+    format_tar_numeral(seconds, 12).unwrap_or(vec![0; 12])
+}
+
+/// Format a link target for the linkname field of a tar header.
+///
+/// Targets which aren't ASCII, or are longer than the field, yield None.
+pub fn format_tar_linkname(target: &str) -> Option<Vec<u8>> {
+    if !target.is_ascii() || target.len() > 100 {
+        return None;
     }
+
+    let mut result = target.as_bytes().to_vec();
+    result.resize(100, 0);
+
+    Some(result)
 }
 
 /// Given a directory path, format it for inclusion in a tar header.
@@ -58,8 +88,8 @@ fn format_tar_time(dirtime: &time::SystemTime) -> io::Result<Vec<u8>> {
 /// 
 /// If the path cannot be split to fit the tar file naming length requirements
 /// then this function returns an error.
-pub fn format_tar_filename(dirpath: &path::Path, filetype: TarFileType) -> io::Result<(Vec<u8>, Vec<u8>)> {
-    let (unix, prefix, was_truncated) = pax::format_pax_legacy_filename(&canonicalized_tar_path(dirpath, filetype))?;
+pub fn format_tar_filename(dirpath: &path::Path, filetype: TarFileType, style: PathStyle) -> io::Result<(Vec<u8>, Vec<u8>)> {
+    let (unix, prefix, was_truncated) = pax::format_pax_legacy_filename(&canonicalized_tar_path(dirpath, filetype, style))?;
     
     if was_truncated {
         return Err(io::Error::new(io::ErrorKind::InvalidData, "File name is too long or contains non-ASCII characters"));
@@ -98,7 +128,7 @@ pub fn format_tar_filename(dirpath: &path::Path, filetype: TarFileType) -> io::R
 pub fn ustar_header(tarheader: &TarHeader) -> io::Result<Vec<u8>> {
     let mut header : Vec<u8> = Vec::with_capacity(512);
     
-    let (relapath_unix, relapath_extended) = format_tar_filename(&tarheader.path, tarheader.file_type)?;
+    let (relapath_unix, relapath_extended) = format_tar_filename(&tarheader.path, tarheader.file_type, tarheader.path_style)?;
     
     assert_eq!(relapath_unix.len(), 100);
     assert_eq!(relapath_extended.len(), 155);
@@ -112,10 +142,13 @@ pub fn ustar_header(tarheader: &TarHeader) -> io::Result<Vec<u8>> {
     } else {
         header.extend(format_tar_numeral(0, 12).unwrap_or(vec![0; 12])); //Non-file entries must have a size of 0, or 7zip tries to skip them
     }
-    header.extend(format_tar_time(&tarheader.mtime.unwrap_or(time::UNIX_EPOCH)).unwrap_or(vec![0; 12])); //mtime
+    header.extend(format_tar_time(&tarheader.mtime.unwrap_or(time::UNIX_EPOCH))); //mtime
     header.extend("        ".as_bytes()); //checksummable format checksum value
     header.push(tarheader.file_type.type_flag() as u8); //File type
-    header.extend(vec![0; 100]); //TODO: Link name
+    header.extend(match tarheader.symlink_path {
+        Some(ref target) => format_tar_linkname(&canonicalized_link_target(target, tarheader.file_type, tarheader.path_style)).ok_or(io::Error::new(io::ErrorKind::InvalidData, "Link target is too long or contains non-ASCII characters"))?,
+        None => vec![0; 100]
+    }); //Link name
     header.extend("ustar\0".as_bytes()); //magic 'ustar\0'
     header.extend("00".as_bytes()); //version 00
     header.extend(format_tar_string(&tarheader.unix_uname, 32).unwrap_or(vec![0; 32])); //TODO: UID Name
@@ -128,6 +161,62 @@ pub fn ustar_header(tarheader: &TarHeader) -> io::Result<Vec<u8>> {
     Ok(header)
 }
 
+/// Parse a number in tar octal format.
+/// 
+/// Leading spaces and trailing spaces or nulls are ignored, as different tar
+/// implementations pad numerals differently. An empty field is zero. Fields
+/// containing anything else, or values too large for a `u64`, yield None.
+pub fn parse_tar_numeral(field: &[u8]) -> Option<u64> {
+    let digits = field.iter().skip_while(|b| **b == b' ').take_while(|b| **b != 0 && **b != b' ');
+    let mut value : u64 = 0;
+
+    for digit in digits {
+        match digit {
+            b'0'..=b'7' => value = value.checked_mul(8)?.checked_add((digit - b'0') as u64)?,
+            _ => return None
+        }
+    }
+
+    Some(value)
+}
+
+/// Parse a null-terminated string from a tar header field.
+/// 
+/// Fields which fill their entire length have no terminator. Invalid UTF-8 is
+/// replaced with U+FFFD.
+pub fn parse_tar_string(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Determine if a tar header block has a valid checksum.
+/// 
+/// Some historical implementations summed the header as signed bytes, so
+/// either sum is accepted.
+pub fn verify_checksum(header: &[u8]) -> bool {
+    if header.len() < 512 {
+        return false;
+    }
+
+    let expected = match parse_tar_numeral(&header[148..156]) {
+        Some(expected) => expected,
+        None => return false
+    };
+
+    let mut unsigned_sum : u64 = 0;
+    let mut signed_sum : i64 = 0;
+
+    for (i, byte) in header[0..512].iter().enumerate() {
+        let byte = if i >= 148 && i < 156 { b' ' } else { *byte };
+
+        unsigned_sum += byte as u64;
+        signed_sum += byte as i8 as i64;
+    }
+
+    unsigned_sum == expected || signed_sum == expected as i64
+}
+
 /// Given a tar header (ustar format), calculate a valid checksum.
 /// 
 /// Any existing data in the header checksum field will be destroyed.
@@ -153,10 +242,18 @@ pub fn checksum_header(header: &mut [u8]) {
 
 #[cfg(test)]
 mod tests {
-    use crate::tar::ustar::{format_tar_numeral, format_tar_string, format_tar_filename};
+    use crate::tar::ustar::{format_tar_numeral, format_tar_string, format_tar_filename, format_tar_time};
+    use crate::tar::PathStyle;
     use crate::tar::header::TarFileType;
-    use std::{io, path};
+    use std::{io, path, time};
     
+    #[test]
+    fn tar_time_clamped() {
+        assert_eq!(format_tar_time(&(time::UNIX_EPOCH + time::Duration::new(42, 500_000_000))), b"00000000052\0".to_vec());
+        assert_eq!(format_tar_time(&(time::UNIX_EPOCH - time::Duration::from_secs(86400))), b"00000000000\0".to_vec());
+        assert_eq!(format_tar_time(&(time::UNIX_EPOCH + time::Duration::from_secs(1 << 34))), b"77777777777\0".to_vec());
+    }
+
     #[test]
     fn format_tar_numeral_8() {
         assert_eq!(match format_tar_numeral(0o755, 8) {
@@ -182,7 +279,7 @@ mod tests {
     
     #[test]
     fn format_tar_filename_short() {
-        let (old, posix) = format_tar_filename(path::Path::new("quux"), TarFileType::FileStream).unwrap();
+        let (old, posix) = format_tar_filename(path::Path::new("quux"), TarFileType::FileStream, PathStyle::Gnu).unwrap();
         assert_eq!(old.len(), 100);
         assert_eq!(posix.len(), 155);
         assert_eq!("quux".as_bytes(), &old[0..4]);
@@ -192,7 +289,7 @@ mod tests {
     
     #[test]
     fn format_tar_filename_medium() {
-        let (old, posix) = format_tar_filename(path::Path::new("1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/quux"), TarFileType::FileStream).unwrap();
+        let (old, posix) = format_tar_filename(path::Path::new("1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/quux"), TarFileType::FileStream, PathStyle::Gnu).unwrap();
         
         assert_eq!(old.len(), 100);
         assert_eq!(posix.len(), 155);
@@ -204,7 +301,7 @@ mod tests {
     
     #[test]
     fn format_tar_filename_long() {
-        let my_err = format_tar_filename(path::Path::new("1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/quux"), TarFileType::FileStream).unwrap_err();
+        let my_err = format_tar_filename(path::Path::new("1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/1/2/3/4/5/6/7/8/9/a/b/c/d/e/f/g/h/i/j/k/l/m/n/o/p/q/r/s/t/u/v/w/x/y/z/aa/ab/ac/ad/ae/af/ag/ah/ai/aj/ak/quux"), TarFileType::FileStream, PathStyle::Gnu).unwrap_err();
         
         assert_eq!(my_err.kind(), io::ErrorKind::InvalidData);
     }
@@ -0,0 +1,274 @@
+//! Reports verifying a restored tree against the archive it came from.
+//!
+//! Compliance regimes often want proof that a restore is complete and
+//! faithful, not just that it didn't error out. A `RestoreReport` collects the
+//! results of comparing every member of an archive with a restored tree (see
+//! `compare`), along with any files in the tree the archive doesn't account
+//! for, and renders them as a plain text report:
+//!
+//! ```text
+//! Restore verification report
+//! Archive: /dev/nst0
+//! Restored tree: /srv/restore
+//! Verified at: 2019-03-01 12:00 UTC
+//! Members verified: 1042
+//! Bytes verified: 73400320
+//! Members differing: 1
+//! Unexpected files: 1
+//! Result: FAIL
+//!
+//! etc/passwd: Contents differ
+//! Unexpected file: etc/passwd.bak
+//!
+//! SHA-256: 5d41402abc4b2a76b9719d911017c592...
+//! ```
+//!
+//! The last line is a digest of everything before it, so that a report which
+//! has been signed off can't be quietly edited afterwards; `check_seal`
+//! confirms a report still matches it's digest. The digest is a seal, not a
+//! signature: anyone can recompute it, so reports needing to prove who wrote
+//! them should also be signed by external means.
+
+use std::{io, fs, path, time};
+use std::collections::HashSet;
+use crate::digest::{Sha256, to_hex};
+use crate::tar::header::{TarHeader, TarFileType};
+use crate::tar::compare::{Difference, DifferenceRecord};
+use crate::tar::list::{format_timestamp, quote_name};
+
+/// The prefix of a report's final line, holding it's digest.
+const SEAL_PREFIX: &str = "SHA-256: ";
+
+/// Reduce an archived or restored path to the form both are compared in.
+///
+/// Archives may name members with a leading `./`, or directories with a
+/// trailing slash; neither changes where the member is restored to.
+fn normalize(path: &path::Path) -> path::PathBuf {
+    path.components().filter(|component| *component != path::Component::CurDir).collect()
+}
+
+/// The results of verifying a restored tree against an archive.
+pub struct RestoreReport {
+    archive: String,
+    restored: path::PathBuf,
+    verified_at: time::SystemTime,
+    members: u64,
+    bytes: u64,
+    differing: u64,
+    lines: Vec<String>,
+    archived: HashSet<path::PathBuf>,
+    unexpected: Vec<path::PathBuf>,
+}
+
+impl RestoreReport {
+    /// Start a report on verifying the tree at `restored` against the named
+    /// archive.
+    pub fn new(archive: &str, restored: &path::Path) -> RestoreReport {
+        RestoreReport {
+            archive: archive.to_string(),
+            restored: restored.to_path_buf(),
+            verified_at: time::SystemTime::now(),
+            members: 0,
+            bytes: 0,
+            differing: 0,
+            lines: Vec::new(),
+            archived: HashSet::new(),
+            unexpected: Vec::new()
+        }
+    }
+
+    /// Record the differences found between a member and the restored tree.
+    pub fn add_member(&mut self, header: &TarHeader, differences: Vec<Difference>) {
+        self.members += 1;
+        self.archived.insert(normalize(&header.path));
+
+        if header.file_type == TarFileType::FileStream {
+            self.bytes += header.file_size;
+        }
+
+        if !differences.is_empty() {
+            self.differing += 1;
+        }
+
+        //Names are quoted so that odd ones can't forge lines of the report.
+        let quoted = Box::new(path::PathBuf::from(quote_name(&header.path.to_string_lossy())));
+
+        for difference in differences {
+            self.lines.push(DifferenceRecord { path: quoted.clone(), difference: difference }.to_string());
+        }
+    }
+
+    /// Record a member which could not be compared at all.
+    pub fn add_failure(&mut self, header: &TarHeader, error: &io::Error) {
+        self.members += 1;
+        self.differing += 1;
+        self.archived.insert(normalize(&header.path));
+        self.lines.push(format!("{}: Cannot verify: {}", quote_name(&header.path.to_string_lossy()), error));
+    }
+
+    /// Note that the archive was cut short, and so may not cover everything
+    /// that was restored from it.
+    pub fn add_truncation(&mut self) {
+        self.lines.push("Archive has no end-of-archive marker, and may have been cut short".to_string());
+    }
+
+    /// Walk the restored tree for files which aren't members of the archive.
+    ///
+    /// This should be done after every member has been added. Paths in
+    /// `ignore`, relative to the restored tree, are skipped; typically these
+    /// are the archive or report themselves. Directories are not descended
+    /// into through symbolic links.
+    pub fn find_unexpected(&mut self, ignore: &[path::PathBuf]) -> io::Result<()> {
+        let ignore : HashSet<path::PathBuf> = ignore.iter().map(|path| normalize(path)).collect();
+        let mut pending = vec![path::PathBuf::new()];
+
+        while let Some(relative) = pending.pop() {
+            let mut entries = fs::read_dir(self.restored.join(&relative))?.collect::<io::Result<Vec<_>>>()?;
+
+            entries.sort_by_key(|entry| entry.file_name());
+
+            for entry in entries.into_iter().rev() {
+                let child = relative.join(entry.file_name());
+
+                if ignore.contains(&child) {
+                    continue;
+                }
+
+                if !self.archived.contains(&child) {
+                    self.unexpected.push(child.clone());
+                }
+
+                if entry.file_type()?.is_dir() {
+                    pending.push(child);
+                }
+            }
+        }
+
+        self.unexpected.sort();
+
+        Ok(())
+    }
+
+    /// Determine if the restored tree matched the archive exactly.
+    pub fn is_clean(&self) -> bool {
+        self.differing == 0 && self.unexpected.is_empty() && self.lines.is_empty()
+    }
+
+    /// How many members differed from, or could not be compared with, the
+    /// restored tree.
+    pub fn differing(&self) -> u64 {
+        self.differing
+    }
+
+    /// Files in the restored tree which aren't members of the archive.
+    pub fn unexpected(&self) -> &[path::PathBuf] {
+        &self.unexpected
+    }
+
+    /// Write out the report, sealed with it's digest.
+    pub fn write<W: io::Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut body = format!("Restore verification report\nArchive: {}\nRestored tree: {}\nVerified at: {} UTC\nMembers verified: {}\nBytes verified: {}\nMembers differing: {}\nUnexpected files: {}\nResult: {}\n",
+            quote_name(&self.archive),
+            quote_name(&self.restored.to_string_lossy()),
+            format_timestamp(self.verified_at, 0),
+            self.members,
+            self.bytes,
+            self.differing,
+            self.unexpected.len(),
+            if self.is_clean() { "PASS" } else { "FAIL" });
+
+        if !self.lines.is_empty() || !self.unexpected.is_empty() {
+            body.push('\n');
+        }
+
+        for line in self.lines.iter() {
+            body.push_str(line);
+            body.push('\n');
+        }
+
+        for path in self.unexpected.iter() {
+            body.push_str(&format!("Unexpected file: {}\n", quote_name(&path.to_string_lossy())));
+        }
+
+        body.push('\n');
+
+        out.write_all(body.as_bytes())?;
+        writeln!(out, "{}{}", SEAL_PREFIX, to_hex(&Sha256::digest(body.as_bytes())))
+    }
+}
+
+/// Determine if a written report still matches the digest it was sealed with.
+pub fn check_seal(report: &str) -> bool {
+    let sealed = report.trim_end_matches('\n');
+    let seal_start = match sealed.rfind('\n') {
+        Some(newline) => newline + 1,
+        None => return false
+    };
+
+    match sealed[seal_start..].strip_prefix(SEAL_PREFIX) {
+        Some(digest) => to_hex(&Sha256::digest(report[..seal_start].as_bytes())) == digest,
+        None => false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path};
+    use crate::tar::header::TarHeader;
+    use crate::tar::compare::Difference;
+    use super::{RestoreReport, check_seal};
+
+    #[test]
+    fn restore_report() {
+        let dir = env::temp_dir().join(format!("rapidtar-verify-{}", std::process::id()));
+
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::write(dir.join("sub/file"), b"restored").unwrap();
+        fs::write(dir.join("sub/extra"), b"stray").unwrap();
+        fs::write(dir.join("archive.tar"), b"").unwrap();
+
+        let metadata = fs::symlink_metadata(dir.join("sub/file")).unwrap();
+        let mut file = TarHeader::abstract_header_for_file(path::Path::new("sub/file"), &metadata, &dir.join("sub/file")).unwrap();
+        let dir_metadata = fs::symlink_metadata(dir.join("sub")).unwrap();
+        let sub = TarHeader::abstract_header_for_file(path::Path::new("sub/"), &dir_metadata, &dir.join("sub")).unwrap();
+
+        //Members archived by other tars may be named with a leading ./
+        file.path = Box::new(path::PathBuf::from("./sub/file"));
+
+        let mut report = RestoreReport::new("archive.tar", &dir);
+        report.add_member(&sub, vec![]);
+        report.add_member(&file, vec![]);
+        report.find_unexpected(&[path::PathBuf::from("archive.tar")]).unwrap();
+
+        assert_eq!(report.unexpected(), &[path::PathBuf::from("sub/extra")]);
+        assert!(!report.is_clean());
+
+        let mut out = vec![];
+        report.write(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+
+        assert!(text.contains("\nMembers verified: 2\nBytes verified: 8\nMembers differing: 0\nUnexpected files: 1\nResult: FAIL\n\nUnexpected file: sub/extra\n\nSHA-256: "));
+        assert!(check_seal(&text));
+        assert!(!check_seal(&text.replace("FAIL", "PASS")));
+        assert!(!check_seal("no seal\n"));
+
+        fs::remove_file(dir.join("sub/extra")).unwrap();
+
+        let mut report = RestoreReport::new("archive.tar", &dir);
+        report.add_member(&sub, vec![]);
+        report.add_member(&file, vec![Difference::Size { archive: 9, disk: 8 }]);
+        report.find_unexpected(&[path::PathBuf::from("archive.tar")]).unwrap();
+
+        assert!(report.unexpected().is_empty());
+        assert_eq!(report.differing(), 1);
+
+        let mut out = vec![];
+        report.write(&mut out).unwrap();
+        let text = String::from_utf8(out).unwrap();
+
+        assert!(text.contains("Result: FAIL\n\n./sub/file: Size differs (9 in archive, 8 on disk)\n\nSHA-256: "));
+        assert!(check_seal(&text));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
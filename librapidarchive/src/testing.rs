@@ -0,0 +1,399 @@
+//! Support for testing archive writers without devices.
+//!
+//! `RecordingSink` is an in-memory `ArchivalSink` which remembers everything
+//! done to it: the size of every write (which, on tape, becomes a record),
+//! filemarks, and the data zones used for spanning. It can optionally act as a
+//! tape, backed by a `MemoryTapeDevice`.
+//!
+//! `diff_archives` and `check_golden` compare a produced archive against a
+//! known-good fixture, and describe the first difference in terms of archive
+//! members and header fields rather than raw offsets.
+
+use std::{io, fmt, fs, env, path};
+use std::sync::{Arc, Mutex};
+use crate::fs::ArchivalSink;
+use crate::spanning::RecoverableWrite;
+use crate::tape::{TapeDevice, FilemarkPolicy};
+use crate::tape::memory::MemoryTapeDevice;
+use crate::tar::reader::{TarReader, is_tar_header};
+
+/// Something done to a `RecordingSink`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum SinkEvent<P> {
+    /// A single write, holding the data actually accepted.
+    Record(Vec<u8>),
+    Flush,
+    Filemark,
+    Setmark,
+    BeginDataZone(P),
+    ResumeDataZone(P, u64),
+    EndDataZone,
+    Sync,
+}
+
+/// A handle to the events recorded by a `RecordingSink`.
+///
+/// The handle stays usable after the sink itself has been moved into (or
+/// dropped along with) a stack of writers.
+#[derive(Clone)]
+pub struct SinkLog<P> {
+    events: Arc<Mutex<Vec<SinkEvent<P>>>>
+}
+
+impl<P: Clone> SinkLog<P> {
+    fn push(&self, event: SinkEvent<P>) {
+        self.events.lock().unwrap().push(event);
+    }
+
+    /// Everything recorded so far, in order.
+    pub fn events(&self) -> Vec<SinkEvent<P>> {
+        self.events.lock().unwrap().clone()
+    }
+
+    /// The data of every write, in order, with their original sizes.
+    pub fn records(&self) -> Vec<Vec<u8>> {
+        self.events.lock().unwrap().iter().filter_map(|event| match event {
+            SinkEvent::Record(data) => Some(data.clone()),
+            _ => None
+        }).collect()
+    }
+
+    /// All data written, as one stream.
+    pub fn data(&self) -> Vec<u8> {
+        self.records().concat()
+    }
+}
+
+/// An in-memory sink which records everything done to it.
+///
+/// Data is committed as soon as it is written, so a `RecordingSink` never
+/// reports uncommitted writes.
+pub struct RecordingSink<P = u64> {
+    tape: MemoryTapeDevice<P>,
+    is_tape: bool,
+    log: SinkLog<P>
+}
+
+impl<P: Clone> RecordingSink<P> {
+    /// Create a sink which behaves like a file.
+    pub fn new() -> RecordingSink<P> {
+        RecordingSink {
+            tape: MemoryTapeDevice::new(),
+            is_tape: false,
+            log: SinkLog {
+                events: Arc::new(Mutex::new(Vec::new()))
+            }
+        }
+    }
+
+    /// Create a sink which behaves like a blank tape.
+    pub fn new_tape() -> RecordingSink<P> {
+        let mut sink = RecordingSink::new();
+
+        sink.is_tape = true;
+
+        sink
+    }
+
+    /// Get a handle to the events recorded by this sink.
+    pub fn log(&self) -> SinkLog<P> {
+        self.log.clone()
+    }
+
+    /// The tape backing this sink.
+    pub fn tape(&self) -> &MemoryTapeDevice<P> {
+        &self.tape
+    }
+
+    /// The tape backing this sink, for setting it up.
+    pub fn tape_mut(&mut self) -> &mut MemoryTapeDevice<P> {
+        &mut self.tape
+    }
+}
+
+impl<P: Clone> Default for RecordingSink<P> {
+    fn default() -> Self {
+        RecordingSink::new()
+    }
+}
+
+impl<P: Clone> io::Write for RecordingSink<P> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let size = self.tape.write(data)?;
+
+        if size > 0 {
+            self.log.push(SinkEvent::Record(data[..size].to_vec()));
+        }
+
+        Ok(size)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.log.push(SinkEvent::Flush);
+        self.tape.flush()
+    }
+}
+
+impl<P: Clone> io::Read for RecordingSink<P> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.tape.read(buf)
+    }
+}
+
+impl<P: Clone> RecoverableWrite<P> for RecordingSink<P> {
+    fn begin_data_zone(&mut self, ident: P) {
+        self.log.push(SinkEvent::BeginDataZone(ident));
+    }
+
+    fn resume_data_zone(&mut self, ident: P, committed: u64) {
+        self.log.push(SinkEvent::ResumeDataZone(ident, committed));
+    }
+
+    fn end_data_zone(&mut self) {
+        self.log.push(SinkEvent::EndDataZone);
+    }
+}
+
+impl<P: Clone + Send> ArchivalSink<P> for RecordingSink<P> {
+    fn downcast_tapedevice(&mut self) -> Option<&mut dyn TapeDevice> {
+        match self.is_tape {
+            true => Some(self),
+            false => None
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.log.push(SinkEvent::Sync);
+        Ok(())
+    }
+}
+
+impl<P: Clone> TapeDevice for RecordingSink<P> {
+    fn read_block(&mut self, buf: &mut Vec<u8>) -> io::Result<()> {
+        self.tape.read_block(buf)
+    }
+
+    fn write_filemark(&mut self, blocking: bool) -> io::Result<()> {
+        self.log.push(SinkEvent::Filemark);
+        self.tape.write_filemark(blocking)
+    }
+
+    fn write_setmark(&mut self, blocking: bool) -> io::Result<()> {
+        self.log.push(SinkEvent::Setmark);
+        self.tape.write_setmark(blocking)
+    }
+
+    fn set_filemark_policy(&mut self, policy: FilemarkPolicy) -> io::Result<()> {
+        self.tape.set_filemark_policy(policy)
+    }
+
+    fn set_compression(&mut self, enabled: bool) -> io::Result<()> {
+        self.tape.set_compression(enabled)
+    }
+
+    fn fixed_block_size(&self) -> Option<u32> {
+        self.tape.fixed_block_size()
+    }
+
+    fn seek_blocks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        self.tape.seek_blocks(pos)
+    }
+
+    fn tell_blocks(&mut self) -> io::Result<u64> {
+        self.tape.tell_blocks()
+    }
+
+    fn seek_filemarks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        self.tape.seek_filemarks(pos)
+    }
+
+    fn seek_setmarks(&mut self, pos: io::SeekFrom) -> io::Result<()> {
+        self.tape.seek_setmarks(pos)
+    }
+
+    fn seek_partition(&mut self, id: u32) -> io::Result<()> {
+        self.tape.seek_partition(id)
+    }
+
+    fn current_partition(&mut self) -> io::Result<u32> {
+        self.tape.current_partition()
+    }
+
+    fn partition_count(&mut self) -> io::Result<u32> {
+        self.tape.partition_count()
+    }
+}
+
+/// The fields of a ustar header, as (name, offset, length).
+const HEADER_FIELDS: &[(&str, usize, usize)] = &[
+    ("name", 0, 100),
+    ("mode", 100, 8),
+    ("uid", 108, 8),
+    ("gid", 116, 8),
+    ("size", 124, 12),
+    ("mtime", 136, 12),
+    ("chksum", 148, 8),
+    ("typeflag", 156, 1),
+    ("linkname", 157, 100),
+    ("magic", 257, 6),
+    ("version", 263, 2),
+    ("uname", 265, 32),
+    ("gname", 297, 32),
+    ("devmajor", 329, 8),
+    ("devminor", 337, 8),
+    ("prefix", 345, 155),
+];
+
+/// Where a produced archive first differs from the expected one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchiveDifference {
+    /// The offset of the first differing byte.
+    pub offset: u64,
+
+    /// The path of the expected member the difference falls within, if any.
+    pub member: Option<path::PathBuf>,
+
+    /// The header field the difference falls within, if it's in a header.
+    pub field: Option<&'static str>,
+
+    pub produced_len: u64,
+    pub expected_len: u64,
+}
+
+impl fmt::Display for ArchiveDifference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Archives differ at byte {} (block {})", self.offset, self.offset / 512)?;
+
+        if let Some(ref member) = self.member {
+            write!(f, ", within member {:?}", member)?;
+        }
+
+        if let Some(field) = self.field {
+            write!(f, ", in header field {}", field)?;
+        }
+
+        if self.produced_len != self.expected_len {
+            write!(f, "; produced {} bytes, expected {}", self.produced_len, self.expected_len)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Compare a produced archive against the expected one.
+///
+/// Returns `None` if they are identical.
+pub fn diff_archives(produced: &[u8], expected: &[u8]) -> Option<ArchiveDifference> {
+    let offset = match produced.iter().zip(expected.iter()).position(|(p, e)| p != e) {
+        Some(offset) => offset,
+        None if produced.len() == expected.len() => return None,
+        None => produced.len().min(expected.len())
+    };
+
+    let mut member = None;
+    let mut reader = TarReader::new(io::Cursor::new(expected));
+
+    while let Ok(Some(entry)) = reader.next_entry() {
+        if entry.header_offset > offset as u64 {
+            break;
+        }
+
+        member = Some(entry.header.path.to_path_buf());
+    }
+
+    let block_start = offset - offset % 512;
+    let field = match expected.get(block_start..block_start + 512) {
+        Some(block) if is_tar_header(block) => HEADER_FIELDS.iter().find(|(_, start, len)| offset % 512 >= *start && offset % 512 < start + len).map(|(name, _, _)| *name),
+        _ => None
+    };
+
+    Some(ArchiveDifference {
+        offset: offset as u64,
+        member: member,
+        field: field,
+        produced_len: produced.len() as u64,
+        expected_len: expected.len() as u64
+    })
+}
+
+/// Check a produced archive against a golden fixture file.
+///
+/// If the `RAPIDTAR_BLESS` environment variable is set, the fixture is
+/// replaced with the produced archive instead, for updating fixtures after a
+/// deliberate format change.
+pub fn check_golden<P: AsRef<path::Path>>(produced: &[u8], fixture: P) -> io::Result<Option<ArchiveDifference>> {
+    if env::var_os("RAPIDTAR_BLESS").is_some() {
+        fs::write(fixture, produced)?;
+        return Ok(None);
+    }
+
+    Ok(diff_archives(produced, &fs::read(fixture)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path;
+    use std::io::Write;
+    use crate::blocking::BlockingWriter;
+    use crate::fs::ArchivalSink;
+    use crate::spanning::RecoverableWrite;
+    use super::{RecordingSink, SinkEvent, diff_archives};
+
+    #[test]
+    fn recording_sink_events() {
+        let sink : RecordingSink<u64> = RecordingSink::new_tape();
+        let log = sink.log();
+        let mut blocked = BlockingWriter::new_with_factor(sink, 2);
+
+        blocked.begin_data_zone(7);
+        blocked.write_all(&[1; 1536]).unwrap();
+        blocked.flush().unwrap();
+        blocked.downcast_tapedevice().unwrap().write_filemark(true).unwrap();
+
+        let sizes : Vec<usize> = log.records().iter().map(|r| r.len()).collect();
+        assert_eq!(sizes, vec![1024, 1024]);
+        assert_eq!(log.data().len(), 2048);
+        assert_eq!(log.events().first(), Some(&SinkEvent::BeginDataZone(7)));
+        assert_eq!(log.events().last(), Some(&SinkEvent::Filemark));
+    }
+
+    /// A minimal ustar header for an empty file.
+    fn empty_file_header(name: &str) -> Vec<u8> {
+        let mut header = vec![0; 512];
+
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        header[100..107].copy_from_slice(b"0000644");
+        header[124..135].copy_from_slice(b"00000000000");
+        header[136..147].copy_from_slice(b"00000000000");
+        header[156] = b'0';
+        header[257..263].copy_from_slice(b"ustar\0");
+        header[263..265].copy_from_slice(b"00");
+        header[148..156].copy_from_slice(b"        ");
+
+        let checksum : u32 = header.iter().map(|b| *b as u32).sum();
+        header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+
+        header
+    }
+
+    #[test]
+    fn golden_diff() {
+        let mut expected = empty_file_header("file.txt");
+        expected.extend(vec![0; 1024]);
+
+        assert_eq!(diff_archives(&expected, &expected), None);
+
+        let mut produced = expected.clone();
+        produced[100] = b'1';
+
+        let difference = diff_archives(&produced, &expected).unwrap();
+        assert_eq!(difference.offset, 100);
+        assert_eq!(difference.field, Some("mode"));
+        assert!(difference.member.unwrap() == path::Path::new("file.txt"));
+
+        let difference = diff_archives(&expected[..512], &expected).unwrap();
+        assert_eq!(difference.offset, 512);
+        assert_eq!(difference.field, None);
+
+    }
+}
@@ -0,0 +1,122 @@
+//! Time limits on reads which might never finish.
+//!
+//! Reads from a flaky network mount can block forever, and there's no portable
+//! way to interrupt a blocked read. `TimeoutRead` instead moves the reads onto
+//! a thread of their own and stops waiting for them after a while. A read that
+//! times out is abandoned along with it's thread, which exits (dropping the
+//! reader) whenever the read finally returns, if ever.
+
+use std::{io, thread, time};
+use std::sync::mpsc::{channel, Sender, Receiver, RecvTimeoutError};
+
+/// A reader which fails with `ErrorKind::TimedOut` if any single read takes
+/// longer than a given time.
+///
+/// Once a read has timed out, every later read fails immediately, as the
+/// abandoned read may still complete and consume data.
+pub struct TimeoutRead {
+    requests: Option<Sender<usize>>,
+    responses: Receiver<io::Result<Vec<u8>>>,
+    timeout: time::Duration,
+    timed_out: bool,
+}
+
+impl TimeoutRead {
+    pub fn new<R: 'static + io::Read + Send>(mut inner: R, timeout: time::Duration) -> TimeoutRead {
+        let (requests, request_receiver) = channel::<usize>();
+        let (response_sender, responses) = channel();
+
+        thread::spawn(move || {
+            while let Ok(size) = request_receiver.recv() {
+                let mut buf = vec![0; size];
+                let response = inner.read(&mut buf).map(|read| {
+                    buf.truncate(read);
+                    buf
+                });
+
+                if response_sender.send(response).is_err() {
+                    break;
+                }
+            }
+        });
+
+        TimeoutRead {
+            requests: Some(requests),
+            responses: responses,
+            timeout: timeout,
+            timed_out: false
+        }
+    }
+
+    fn timed_out_error(&self) -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, format!("Read did not complete within {:?}", self.timeout))
+    }
+}
+
+impl io::Read for TimeoutRead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.timed_out {
+            return Err(self.timed_out_error());
+        }
+
+        let sent = match self.requests {
+            Some(ref requests) => requests.send(buf.len()).is_ok(),
+            None => false
+        };
+
+        if !sent {
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Reader thread has exited"));
+        }
+
+        match self.responses.recv_timeout(self.timeout) {
+            Ok(Ok(data)) => {
+                buf[..data.len()].copy_from_slice(&data);
+                Ok(data.len())
+            },
+            Ok(Err(e)) => Err(e),
+            Err(RecvTimeoutError::Timeout) => {
+                self.timed_out = true;
+                self.requests = None;
+                Err(self.timed_out_error())
+            },
+            Err(RecvTimeoutError::Disconnected) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "Reader thread has exited"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io, thread, time};
+    use std::io::Read;
+    use super::TimeoutRead;
+
+    /// A reader which stalls after it's first read.
+    struct Stalling(bool);
+
+    impl io::Read for Stalling {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0 {
+                thread::sleep(time::Duration::from_secs(5));
+            }
+
+            self.0 = true;
+            buf[0] = 42;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn timeout_read() {
+        let mut reader = TimeoutRead::new(io::repeat(7).take(10), time::Duration::from_secs(5));
+        let mut out = vec![];
+        assert_eq!(reader.read_to_end(&mut out).unwrap(), 10);
+        assert_eq!(out, vec![7; 10]);
+
+        let mut reader = TimeoutRead::new(Stalling(false), time::Duration::from_millis(20));
+        let mut buf = [0; 4];
+        assert_eq!(reader.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 42);
+        assert_eq!(reader.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+        assert_eq!(reader.read(&mut buf).unwrap_err().kind(), io::ErrorKind::TimedOut);
+    }
+}
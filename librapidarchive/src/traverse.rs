@@ -1,11 +1,22 @@
 //! Multithreaded path traversal (the thing which makes rapidtar rapid).
 
-use std::sync::mpsc::{SyncSender, SendError};
+use std::sync::mpsc::SendError;
 use std::{io, path, fs, error, fmt, result};
+use std::str::FromStr;
+use std::sync::Arc;
+use crate::cancel::{CancellationToken, is_cancellation};
+use crate::fs::{get_file_type, file_identity, is_followed_link, FileId};
+use crate::handles;
 
 #[derive(Debug)]
 pub enum TraversalError {
     TraversalCancelled,
+
+    /// The traversal callback excluded an entry and all of it's contents.
+    ///
+    /// This is not an error as such: `traverse` stops descending into the
+    /// excluded entry and reports success.
+    Excluded,
     IOError(io::Error)
 }
 
@@ -15,6 +26,7 @@ impl fmt::Display for TraversalError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             TraversalCancelled => write!(f, "Traversal operation was cancelled")?,
+            Excluded => write!(f, "Entry was excluded from traversal")?,
             IOError(err) => err.fmt(f)?
         }
         
@@ -26,13 +38,14 @@ impl error::Error for TraversalError {
     fn description(&self) -> &str {
         match self {
             TraversalCancelled => "Traversal operation was cancelled",
+            Excluded => "Entry was excluded from traversal",
             IOError(err) => err.description()
         }
     }
     
     fn cause(&self) -> Option<&error::Error> {
         match self {
-            TraversalCancelled => None,
+            TraversalCancelled | Excluded => None,
             IOError(err) => Some(err)
         }
     }
@@ -40,7 +53,10 @@ impl error::Error for TraversalError {
 
 impl From<io::Error> for TraversalError {
     fn from(error: io::Error) -> Self {
-        IOError(error)
+        match is_cancellation(&error) {
+            true => TraversalCancelled,
+            false => IOError(error)
+        }
     }
 }
 
@@ -52,12 +68,52 @@ impl<T> From<SendError<T>> for TraversalError {
 
 pub type Result<T> = result::Result<T, TraversalError>;
 
+/// What to do with entries that have no tar representation, such as sockets.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum SpecialFilePolicy {
+    /// Skip the entry, logging a warning.
+    Warn,
+
+    /// Report the entry as an error, the same as any other unreadable entry.
+    Error,
+
+    /// Skip the entry without comment.
+    Silent,
+}
+
+impl Default for SpecialFilePolicy {
+    fn default() -> Self {
+        SpecialFilePolicy::Warn
+    }
+}
+
+impl FromStr for SpecialFilePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> result::Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(SpecialFilePolicy::Warn),
+            "error" => Ok(SpecialFilePolicy::Error),
+            "silent" => Ok(SpecialFilePolicy::Silent),
+            _ => Err(())
+        }
+    }
+}
+
 /// Traverse a directory and stream it and it's contents into memory.
 /// 
 /// Traversal occurs in a multi-threaded manner to maximize I/O queue
 /// utilization. The given `archive_header_fn` will be called within said tasks
 /// with the absolute and relative file names, and non-symlink metadata, to do
-/// with as it wishes.
+/// with as it wishes. If it returns `TraversalError::Excluded`, the entry's
+/// contents will not be traversed.
+/// 
+/// Entries of a type that can't be archived (say, a socket) are handled
+/// according to `special`, and are never given to `archive_header_fn`.
+/// 
+/// If `one_file_system` is set, directories on a different filesystem from
+/// `path` are given to `archive_header_fn`, but their contents are not
+/// traversed.
 /// 
 /// # Relative path management in the age of maximum path lengths
 /// 
@@ -74,45 +130,174 @@ pub type Result<T> = result::Result<T, TraversalError>;
 /// 
 /// # Multithreaded communication
 /// 
-/// For convenience we also allow the caller to provide a `SyncSender` which
-/// will be cloned and distributed throughout the job queue.
-pub fn traverse<'a, 'b, P: AsRef<path::Path>, Q, F>(path: P, archive_header_fn: &'a F, c: SyncSender<Q>, relative_path: Option<P>) -> Result<()>
-    where P: Send + Sync + Clone, Q: Send + Sized + 'a,
-        F: Fn(&path::Path, &path::Path, &fs::Metadata, &SyncSender<Q>) -> Result<()> + Send + Sync + 'a,
+/// For convenience we also allow the caller to provide a sender (e.g. a
+/// `SyncSender`) which will be cloned and distributed throughout the job
+/// queue.
+/// 
+/// # Cancellation
+/// 
+/// Once the given `CancellationToken` is cancelled, no further entries are
+/// visited and `traverse` yields `TraversalCancelled`. Jobs blocked sending
+/// into a full channel only notice once the receiver is dropped.
+/// 
+/// # Metadata
+/// 
+/// Every entry is statted exactly once, and the metadata handed to
+/// `archive_header_fn` should be used in place of statting it again. On
+/// network filesystems each stat is a round trip to the server, so it adds up.
+/// Only the root of the traversal is canonicalized: everything beneath it is
+/// named by joining directory entries onto a canonical path, which is already
+/// canonical, save for symbolic links.
+/// 
+/// # Loops
+/// 
+/// Since symbolic links are followed, a directory may turn up inside itself.
+/// (Windows never follows links or junctions, and archives them as links;
+/// see `fs::is_followed_link`.)
+/// Directories are told apart by their `FileId`, and any directory found
+/// within itself is skipped with a warning. On platforms without file
+/// identities, neither loops nor filesystem boundaries can be detected.
+pub fn traverse<'a, 'b, P: AsRef<path::Path>, S, F>(path: P, archive_header_fn: &'a F, c: S, relative_path: Option<P>, special: SpecialFilePolicy, one_file_system: bool, cancel: &CancellationToken) -> Result<()>
+    where P: Send + Sync + Clone, S: Send + Sync + Clone + 'a,
+        F: Fn(&path::Path, &path::Path, &fs::Metadata, &S) -> Result<()> + Send + Sync + 'a,
         'a: 'b {
-    let self_metadata = fs::symlink_metadata(path.clone())?;
-    let my_relative_path = relative_path.unwrap_or(path.clone());
+    if cancel.is_cancelled() {
+        return Err(TraversalCancelled);
+    }
+
+    let metadata = fs::symlink_metadata(path.as_ref())?;
+    let my_relative_path = relative_path.unwrap_or(path.clone()).as_ref().to_path_buf();
+
+    //Canonicalizing a symlink would resolve it, so those are left alone.
+    let io_path = match metadata.file_type().is_symlink() {
+        true => path.as_ref().to_path_buf(),
+        false => fs::canonicalize(path.as_ref())?
+    };
+
+    let root_device = match one_file_system {
+        true => match file_identity(&metadata, &io_path) {
+            Ok(identity) => Some(identity.device()),
+            Err(e) => {
+                warn!(path:? = io_path; "Cannot stay on one filesystem: {}", e);
+                None
+            }
+        },
+        false => None
+    };
+
+    traverse_entry(io_path, metadata, my_relative_path, archive_header_fn, c, special, Arc::new(Vec::new()), root_device, cancel)
+}
+
+/// Traverse an entry which has already been statted.
+/// 
+/// `ancestors` holds the identities of every directory above the entry.
+fn traverse_entry<'a, S, F>(path: path::PathBuf, self_metadata: fs::Metadata, my_relative_path: path::PathBuf, archive_header_fn: &'a F, c: S, special: SpecialFilePolicy, ancestors: Arc<Vec<FileId>>, root_device: Option<u64>, cancel: &CancellationToken) -> Result<()>
+    where S: Send + Sync + Clone + 'a,
+        F: Fn(&path::Path, &path::Path, &fs::Metadata, &S) -> Result<()> + Send + Sync + 'a {
+    if cancel.is_cancelled() {
+        return Err(TraversalCancelled);
+    }
+
+    if let Err(e) = get_file_type(&self_metadata) {
+        match special {
+            SpecialFilePolicy::Warn => warn!(path:? = path; "Skipping unarchivable file: {}", e),
+            SpecialFilePolicy::Error => return Err(IOError(e)),
+            SpecialFilePolicy::Silent => {}
+        }
+
+        return Ok(());
+    }
+
+    let identity = match self_metadata.is_dir() {
+        true => file_identity(&self_metadata, &path).ok(),
+        false => None
+    };
+
+    if let Some(identity) = identity {
+        if ancestors.contains(&identity) {
+            warn!(path:? = path; "Refusing to traverse directory, it contains itself");
+            return Ok(());
+        }
+    }
     
-    archive_header_fn(path.as_ref(), my_relative_path.as_ref(), &self_metadata, &c)?;
+    match archive_header_fn(&path, &my_relative_path, &self_metadata, &c) {
+        Err(Excluded) => return Ok(()),
+        result => result?
+    }
+
+    if let (Some(identity), Some(root_device)) = (identity, root_device) {
+        if identity.device() != root_device {
+            info!(path:? = path; "Not traversing directory on another filesystem");
+            return Ok(());
+        }
+    }
     
     if self_metadata.is_dir() {
+        let ancestors = match identity {
+            Some(identity) => {
+                let mut lineage = (*ancestors).clone();
+                lineage.push(identity);
+
+                Arc::new(lineage)
+            },
+            None => ancestors
+        };
+
         rayon::scope(|s| {
-            let paths = fs::read_dir(path).unwrap(); //TODO: We should have a way of reporting errors...
+            //The directory is only held open while listing it, before any of
+            //it's contents are visited.
+            let _handle = handles::global().acquire();
+            let paths = fs::read_dir(&path).unwrap(); //TODO: We should have a way of reporting errors...
             
             for entry in paths {
+                if cancel.is_cancelled() {
+                    break;
+                }
+
                 if let Ok(entry) = entry {
                     //Do not traverse parent or self directories.
                     //That way lies madness.
                     if entry.file_name() == "." || entry.file_name() == ".." {
-                        eprintln!("Error attempting to traverse directory path {:?}, would recurse", entry.path());
+                        warn!(path:? = entry.path(); "Refusing to traverse directory path, would recurse");
                         continue;
                     }
                     
                     let entry_path = entry.path();
-                    let child_path = fs::canonicalize(entry_path.clone()).unwrap();
-                    let path_filename = entry_path.file_name().unwrap();
-                    let mut child_relative_path = my_relative_path.as_ref().to_path_buf();
-                    child_relative_path.push(path_filename);
+
+                    //Symlinks within a traversal are followed, where the
+                    //platform allows. Everything else is already canonical, and the directory listing may have
+                    //already told us it's metadata.
+                    let child = match entry.file_type() {
+                        Ok(ref file_type) if is_followed_link(file_type) => fs::canonicalize(&entry_path).and_then(|child_path| {
+                            let metadata = fs::symlink_metadata(&child_path)?;
+
+                            Ok((child_path, metadata))
+                        }),
+                        _ => entry.metadata().map(|metadata| (entry_path.clone(), metadata))
+                    };
+
+                    let (child_path, child_metadata) = match child {
+                        Ok(child) => child,
+                        Err(e) => {
+                            error!(path:? = entry_path; "Error attempting to traverse directory path: {}", e);
+                            continue;
+                        }
+                    };
+
+                    let mut child_relative_path = my_relative_path.clone();
+                    child_relative_path.push(entry.file_name());
                     
                     let child_c = c.clone();
+                    let child_cancel = cancel.clone();
+                    let child_ancestors = ancestors.clone();
                     
                     s.spawn(move |_| {
-                        let pathname_string = format!("{:?}", child_path);
+                        let pathname = child_path.clone();
 
-                        match traverse(child_path, archive_header_fn, child_c, Some(child_relative_path)) {
+                        match traverse_entry(child_path, child_metadata, child_relative_path, archive_header_fn, child_c, special, child_ancestors, root_device, &child_cancel) {
                             Ok(_) => {},
-                            Err(IOError(e)) => eprintln!("Error attempting to traverse directory path {:?}, got error {:?}", pathname_string, e),
-                            Err(TraversalCancelled) => {},
+                            Err(IOError(e)) => error!(path:? = pathname; "Error attempting to traverse directory path: {}", e),
+                            Err(TraversalCancelled) | Err(Excluded) => {},
                         }
                     });
                 }
@@ -123,4 +308,75 @@ pub fn traverse<'a, 'b, P: AsRef<path::Path>, Q, F>(path: P, archive_header_fn:
     drop(c);
     
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path};
+    use std::sync::{Arc, Mutex};
+    use crate::cancel::CancellationToken;
+    use super::{traverse, SpecialFilePolicy};
+
+    #[test]
+    fn traverse_metadata() {
+        let root = env::temp_dir().join(format!("rapidtar-traverse-{}", std::process::id()));
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("dir/file"), b"hello").unwrap();
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let record = |iopath: &path::Path, tarpath: &path::Path, metadata: &fs::Metadata, seen: &Arc<Mutex<Vec<(path::PathBuf, path::PathBuf, u64)>>>| {
+            assert!(iopath.is_absolute());
+            seen.lock().unwrap().push((iopath.to_path_buf(), tarpath.to_path_buf(), if metadata.is_file() { metadata.len() } else { 0 }));
+            Ok(())
+        };
+
+        traverse(root.clone(), &record, seen.clone(), Some(path::PathBuf::from("root")), SpecialFilePolicy::Error, false, &CancellationToken::new()).unwrap();
+
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+
+        let canonical = fs::canonicalize(&root).unwrap();
+        assert_eq!(seen, vec![
+            (canonical.clone(), path::PathBuf::from("root"), 0),
+            (canonical.join("dir"), path::PathBuf::from("root/dir"), 0),
+            (canonical.join("dir/file"), path::PathBuf::from("root/dir/file"), 5),
+        ]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn traverse_symlink_loop() {
+        use crate::fs::file_identity;
+
+        let root = env::temp_dir().join(format!("rapidtar-traverse-loop-{}", std::process::id()));
+        fs::create_dir_all(root.join("dir")).unwrap();
+        fs::write(root.join("dir/file"), b"hello").unwrap();
+        std::os::unix::fs::symlink(&root, root.join("dir/back")).unwrap();
+
+        let canonical = fs::canonicalize(&root).unwrap();
+        let metadata = fs::metadata(&root).unwrap();
+        assert_eq!(file_identity(&metadata, &root).unwrap(), file_identity(&fs::metadata(root.join("dir/back")).unwrap(), &root.join("dir/back")).unwrap());
+        assert_ne!(file_identity(&metadata, &root).unwrap(), file_identity(&fs::metadata(root.join("dir")).unwrap(), &root.join("dir")).unwrap());
+
+        let seen = Arc::new(Mutex::new(vec![]));
+        let record = |_iopath: &path::Path, tarpath: &path::Path, _metadata: &fs::Metadata, seen: &Arc<Mutex<Vec<path::PathBuf>>>| {
+            seen.lock().unwrap().push(tarpath.to_path_buf());
+            Ok(())
+        };
+
+        traverse(canonical.clone(), &record, seen.clone(), Some(path::PathBuf::from("root")), SpecialFilePolicy::Error, true, &CancellationToken::new()).unwrap();
+
+        let mut seen = seen.lock().unwrap().clone();
+        seen.sort();
+
+        assert_eq!(seen, vec![
+            path::PathBuf::from("root"),
+            path::PathBuf::from("root/dir"),
+            path::PathBuf::from("root/dir/file"),
+        ]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
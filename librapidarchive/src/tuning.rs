@@ -1,20 +1,117 @@
 //! Performance tuning related configuration
 
+use std::time;
+use std::str::FromStr;
+use crate::retry::RetryPolicy;
+use crate::units::DataSize;
+
+/// When to make archive data durable, on devices which cache writes (files).
+///
+/// Until a file is synced, a crash or power loss can lose data the operating
+/// system has already accepted, leaving a truncated archive behind.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum FsyncPolicy {
+    /// Never sync, and leave it to the operating system.
+    None,
+
+    /// Sync once the whole archive has been written.
+    End,
+
+    /// Sync at the end of every volume.
+    PerVolume,
+
+    /// Sync every time this many bytes have been written, and at the end.
+    PerBytes(u64),
+}
+
+impl Default for FsyncPolicy {
+    fn default() -> Self {
+        FsyncPolicy::End
+    }
+}
+
+impl FromStr for FsyncPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(FsyncPolicy::None),
+            "end" => Ok(FsyncPolicy::End),
+            "per-volume" => Ok(FsyncPolicy::PerVolume),
+            _ => match DataSize::<u64>::from_str(s).map(|size| size.into_inner()) {
+                Ok(interval) if interval > 0 => Ok(FsyncPolicy::PerBytes(interval)),
+                _ => Err(())
+            }
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct Configuration {
     pub channel_queue_depth: usize,
+
+    /// How many bytes of headers and read-ahead file data may be queued
+    /// between traversal and serialization, regardless of queue depth.
+    pub queue_memory_limit: usize,
     pub parallel_io_limit: usize,
     pub blocking_factor: usize,
+
+    /// Write in whole records of `blocking_factor` even to devices which
+    /// aren't record-oriented, such as files.
+    pub force_blocking: bool,
     pub serial_buffer_limit: u64,
+    pub write_retry: RetryPolicy,
+
+    /// Spill data exceeding `serial_buffer_limit` to a temporary file rather
+    /// than waiting for the device to catch up.
+    pub spill_to_disk: bool,
+
+    /// How long a single read of a file's data may take before the file is
+    /// given up on, if at all.
+    pub entry_timeout: Option<time::Duration>,
+
+    /// How many threads may hash archive data, when hashing is called for.
+    pub hasher_threads: usize,
+
+    /// How many bytes of each file to read ahead while it waits to be
+    /// archived. Files no larger than this are read into memory whole. Zero
+    /// disables read-ahead, so files are only read as they're archived.
+    pub readahead_limit: u64,
+
+    /// When file-based archives are synced to disk.
+    pub fsync: FsyncPolicy,
+
+    /// The block size to write tapes with if the drive can't be put into
+    /// variable block mode.
+    pub tape_fallback_block_size: Option<u32>,
+
+    /// How hard to compress archives, if they're compressed at all. Higher
+    /// levels are slower but smaller; None uses the format's default.
+    pub compression_level: Option<i32>,
+
+    /// How many threads may compress archive data, for formats which can be
+    /// compressed in parallel, or 0 to compress on the writing thread.
+    pub compression_threads: usize,
 }
 
 impl Default for Configuration {
     fn default() -> Self {
         Configuration {
             channel_queue_depth: 1024,
+            queue_memory_limit: 64*1024*1024, //64MB
             parallel_io_limit: 32,
             blocking_factor: 20, //Compatibility with other tars that read 10k records
+            force_blocking: false,
             serial_buffer_limit: 1024*1024*1024, //1GB
+            write_retry: RetryPolicy::default(),
+            spill_to_disk: false,
+            entry_timeout: None,
+            hasher_threads: 2,
+            readahead_limit: 64*1024, //64KB
+            fsync: FsyncPolicy::default(),
+            tape_fallback_block_size: None,
+            compression_level: None,
+            compression_threads: 2,
         }
     }
 }
\ No newline at end of file
@@ -1,12 +1,12 @@
-use std::fmt;
+use std::{fmt, error};
 use std::result::Result;
 use std::str::FromStr;
 use std::ops::{Add, AddAssign, Sub, Mul, Div};
 use std::fmt::{Display, Formatter};
-use num::{NumCast, ToPrimitive};
+use num::{NumCast, ToPrimitive, CheckedMul};
 
 /// A type which represents a byte size input by a user.
-#[derive(Clone, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub struct DataSize<I> {
     inner: I
 }
@@ -71,32 +71,49 @@ impl<I> Div for DataSize<I> where I: Div + From<<I as Div>::Output> {
     }
 }
 
-impl<I> FromStr for DataSize<I> where I: FromStr + Mul + From<<I as Mul>::Output> + NumCast {
-    type Err = I::Err;
-    
+/// An error encountered parsing a `DataSize`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum DataSizeError<E> {
+    /// The number itself could not be parsed.
+    Invalid(E),
+
+    /// The size is too large for the type it's being parsed into.
+    TooLarge
+}
+
+impl<E> Display for DataSizeError<E> where E: Display {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            DataSizeError::Invalid(e) => write!(f, "{}", e),
+            DataSizeError::TooLarge => write!(f, "size is too large")
+        }
+    }
+}
+
+impl<E> error::Error for DataSizeError<E> where E: error::Error {
+}
+
+impl<I> FromStr for DataSize<I> where I: FromStr + CheckedMul + NumCast {
+    type Err = DataSizeError<I::Err>;
+
+    /// Parse a size in bytes, optionally suffixed with K, M, G, or T for
+    /// binary multiples thereof.
+    ///
+    /// Sizes which don't fit in `I` are refused, rather than wrapping around.
     fn from_str(s: &str) -> Result<DataSize<I>, Self::Err> {
-        let slen = s.len();
-        
-        if slen > 0 && s.chars().last().unwrap().to_ascii_lowercase() == 't' {
-            Ok(DataSize{
-                inner: From::from(I::from_str(&s[..slen - 1])? * NumCast::from(1024 * 1024 * 1024 * 1024).unwrap())
-            })
-        } else if slen > 0 && s.chars().last().unwrap().to_ascii_lowercase() == 'g' {
-            Ok(DataSize{
-                inner: From::from(I::from_str(&s[..slen - 1])? * NumCast::from(1024 * 1024 * 1024).unwrap())
-            })
-        } else if slen > 0 && s.chars().last().unwrap().to_ascii_lowercase() == 'm' {
-            Ok(DataSize{
-                inner: From::from(I::from_str(&s[..slen - 1])? * NumCast::from(1024 * 1024).unwrap())
-            })
-        } else if slen > 0 && s.chars().last().unwrap().to_ascii_lowercase() == 'k' {
-            Ok(DataSize{
-                inner: From::from(I::from_str(&s[..slen - 1])? * NumCast::from(1024).unwrap())
-            })
-        } else {
-            Ok(DataSize{
-                inner: I::from_str(s)?
-            })
+        let (number, shift) = match s.chars().last().map(|c| c.to_ascii_lowercase()) {
+            Some('t') => (&s[..s.len() - 1], 40),
+            Some('g') => (&s[..s.len() - 1], 30),
+            Some('m') => (&s[..s.len() - 1], 20),
+            Some('k') => (&s[..s.len() - 1], 10),
+            _ => (s, 0)
+        };
+        let number = I::from_str(number).map_err(DataSizeError::Invalid)?;
+        let factor : I = NumCast::from(1u64 << shift).ok_or(DataSizeError::TooLarge)?;
+
+        match number.checked_mul(&factor) {
+            Some(inner) => Ok(DataSize { inner }),
+            None => Err(DataSizeError::TooLarge)
         }
     }
 }
@@ -125,4 +142,25 @@ impl<I> Display for DataSize<I> where I: Clone + Display + Div + NumCast + ToPri
         
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use super::{DataSize, DataSizeError};
+
+    #[test]
+    fn parse_data_size() {
+        assert_eq!(DataSize::<u64>::from_str("512").unwrap().into_inner(), 512);
+        assert_eq!(DataSize::<u64>::from_str("2k").unwrap().into_inner(), 2048);
+        assert_eq!(DataSize::<u64>::from_str("3T").unwrap().into_inner(), 3 << 40);
+        assert_eq!(DataSize::<u64>::from_str("16777215T").unwrap().into_inner(), 16_777_215 << 40);
+        assert!(match DataSize::<u64>::from_str("x") { Err(DataSizeError::Invalid(_)) => true, _ => false });
+
+        assert_eq!(DataSize::<u64>::from_str("16777216T"), Err(DataSizeError::TooLarge));
+        assert_eq!(DataSize::<u32>::from_str("1t"), Err(DataSizeError::TooLarge));
+        assert_eq!(DataSize::<u32>::from_str("4g"), Err(DataSizeError::TooLarge));
+        assert_eq!(DataSize::<u32>::from_str("3g").unwrap().into_inner(), 3 << 30);
+        assert_eq!(DataSize::<i64>::from_str("-1k").unwrap().into_inner(), -1024);
+    }
+}
@@ -1,6 +1,6 @@
 use std::fmt;
 use std::fmt::{Display, Formatter};
-use std::time::Duration;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// Wrapper structure for printing durations in human printable format.
 pub struct HRDuration {
@@ -15,9 +15,9 @@ impl From<Duration> for HRDuration {
     }
 }
 
-impl Into<Duration> for HRDuration {
-    fn into(self) -> Duration {
-        self.inner
+impl From<HRDuration> for Duration {
+    fn from(duration: HRDuration) -> Duration {
+        duration.inner
     }
 }
 
@@ -70,10 +70,67 @@ impl Display for HRDuration {
     }
 }
 
+/// Wrapper structure for printing points in time as UTC ISO 8601 timestamps.
+pub struct UTCTime {
+    inner: SystemTime
+}
+
+impl From<SystemTime> for UTCTime {
+    fn from(time: SystemTime) -> UTCTime {
+        UTCTime {
+            inner: time
+        }
+    }
+}
+
+impl From<UTCTime> for SystemTime {
+    fn from(time: UTCTime) -> SystemTime {
+        time.inner
+    }
+}
+
+/// Convert a count of days since the UNIX epoch into a (year, month, day)
+/// tuple on the proleptic Gregorian calendar.
+/// 
+/// This is Howard Hinnant's `civil_from_days` algorithm.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    
+    (year, month, day)
+}
+
+impl Display for UTCTime {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let total_secs = match self.inner.duration_since(UNIX_EPOCH) {
+            Ok(duration) => duration.as_secs() as i64,
+            Err(e) => {
+                let before = e.duration();
+                
+                //Round towards negative infinity so the time of day is correct
+                -(before.as_secs() as i64) - if before.subsec_nanos() > 0 { 1 } else { 0 }
+            }
+        };
+        
+        let days = total_secs.div_euclid(60 * 60 * 24);
+        let day_secs = total_secs.rem_euclid(60 * 60 * 24);
+        let (year, month, day) = civil_from_days(days);
+        
+        write!(f, "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, day_secs / (60 * 60), (day_secs / 60) % 60, day_secs % 60)
+    }
+}
+
 #[cfg(test)]
 mod test {
-    use crate::units::time::HRDuration;
-    use std::time::Duration;
+    use crate::units::time::{HRDuration, UTCTime};
+    use std::time::{Duration, UNIX_EPOCH};
     
     #[test]
     fn time_hours() {
@@ -98,4 +155,25 @@ mod test {
         
         assert_eq!(fmtd, "30m14s123ns");
     }
+    
+    #[test]
+    fn utctime_epoch() {
+        let fmtd = format!("{}", UTCTime::from(UNIX_EPOCH));
+        
+        assert_eq!(fmtd, "1970-01-01T00:00:00Z");
+    }
+    
+    #[test]
+    fn utctime_leapday() {
+        let fmtd = format!("{}", UTCTime::from(UNIX_EPOCH + Duration::new(951782400 + 12*60*60 + 30*60 + 14, 0)));
+        
+        assert_eq!(fmtd, "2000-02-29T12:30:14Z");
+    }
+    
+    #[test]
+    fn utctime_pre_epoch() {
+        let fmtd = format!("{}", UTCTime::from(UNIX_EPOCH - Duration::new(1, 0)));
+        
+        assert_eq!(fmtd, "1969-12-31T23:59:59Z");
+    }
 }
\ No newline at end of file
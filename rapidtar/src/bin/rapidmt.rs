@@ -1,18 +1,82 @@
 extern crate argparse;
 extern crate librapidarchive;
 
-use argparse::{ArgumentParser, Store};
+use argparse::{ArgumentParser, Store, StoreOption, StoreTrue};
 use std::{env, io, fs};
-use librapidarchive::units;
+use std::io::{Read, Write};
+use librapidarchive::{units, tape};
 use librapidarchive::fs::open_tape;
 
+/// Fill a buffer from a reader, stopping early only at end of stream.
+/// 
+/// Returns how much of the buffer was filled.
+fn fill_record(source: &mut dyn Read, record: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    
+    while filled < record.len() {
+        match source.read(&mut record[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+    
+    Ok(filled)
+}
+
+/// Copy a stream onto tape in records of exactly `record_size` bytes, one
+/// write per record, like `dd obs=`.
+/// 
+/// The last record is written short if the stream doesn't divide evenly into
+/// records.
+fn write_records(source: &mut dyn Read, tape: &mut dyn Write, record_size: usize) -> io::Result<()> {
+    let mut record = vec![0; record_size];
+    
+    loop {
+        let filled = fill_record(source, &mut record)?;
+        
+        if filled == 0 {
+            return Ok(());
+        }
+        
+        let written = tape.write(&record[..filled])?;
+        if written != filled {
+            return Err(io::Error::new(io::ErrorKind::WriteZero, format!("Tape accepted only {} bytes of a {} byte record", written, filled)));
+        }
+        
+        if filled < record_size {
+            return Ok(());
+        }
+    }
+}
+
+/// Copy records off of a tape, one read per record, writing each out whole.
+/// 
+/// Records larger than `record_size` can't be read and will error.
+fn read_records(tape: &mut dyn Read, sink: &mut dyn Write, record_size: usize) -> io::Result<()> {
+    let mut record = vec![0; record_size];
+    
+    loop {
+        match tape.read(&mut record) {
+            Ok(0) => return sink.flush(),
+            Ok(n) => sink.write_all(&record[..n])?,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {},
+            Err(e) => return Err(e)
+        }
+    }
+}
+
 fn main() -> io::Result<()> {
     //Here's some configuration!
     let mut tapename = env::var("TAPE").unwrap_or("".to_string());
     let mut command = "".to_string();
-    let mut count = 1;
+    let mut argument : Option<String> = None;
     let mut filename = "-".to_string();
     let mut blocksize = units::DataSize::from(1024*1024);
+    let mut record_size : Option<units::DataSize<usize>> = None;
+    let mut tape_options = tape::TapeOptions::default();
+    let mut read_only = false;
     
     {
         let mut ap = ArgumentParser::new();
@@ -22,8 +86,11 @@ fn main() -> io::Result<()> {
         ap.refer(&mut tapename).add_option(&["-f"], Store, "The tape device to control (otherwise reads $TAPE)");
         ap.refer(&mut filename).add_option(&["-o"], Store, "A file to transfer data to or from. (Use - or don't specify for stdio)");
         ap.refer(&mut blocksize).add_option(&["--bs"], Store, "The (recommended, not required) block size to use when reading or writing to or from the tape.");
+        ap.refer(&mut record_size).add_option(&["--record-size"], StoreOption, "Read or write exactly one tape record of this size per call, instead of buffering to --bs. Written data is chunked into records like dd obs=; the last record may be short.");
+        ap.refer(&mut tape_options.share).add_option(&["--share"], Store, "Let others open the tape device while we have it open: for reading (read), for reading or writing (readwrite), or not at all (exclusive, the default). Only supported on Windows.");
+        ap.refer(&mut read_only).add_option(&["--read-only"], StoreTrue, "Open the tape device without requesting write access, as is needed for write-protected media.");
         ap.refer(&mut command).add_argument("operation", Store, "The command to issue to the tape drive.");
-        ap.refer(&mut count).add_argument("count", Store, "How many times to repeat the command. (e.g. fsf 2 = skip 2 files)");
+        ap.refer(&mut argument).add_argument("argument", StoreOption, "How many times to repeat the command (e.g. fsf 2 = skip 2 files), or on or off for compression.");
         
         ap.parse_args_or_exit();
     }
@@ -32,7 +99,21 @@ fn main() -> io::Result<()> {
         return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Please specify a device name, either with -f or TAPE environment variable")));
     }
     
-    let mut tapedevice = open_tape(tapename).expect("Could not access tape device");
+    if read_only {
+        tape_options.access = tape::TapeAccess::ReadOnly;
+    }
+
+    let count : i64 = match (command.as_ref(), argument.as_ref()) {
+        ("compression", _) | (_, None) => 1,
+        (_, Some(count)) => count.parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not a count", count)))?
+    };
+
+    let mut tapedevice = open_tape(tapename, &tape_options).expect("Could not access tape device");
+    let record_size = match record_size {
+        Some(size) if size.clone().into_inner() == 0 => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Record size must be nonzero")),
+        Some(size) => Some(size.into_inner()),
+        None => None
+    };
     
     match command.as_ref() {
         "fsf" => tapedevice.seek_filemarks(io::SeekFrom::Current(count)),
@@ -52,7 +133,31 @@ fn main() -> io::Result<()> {
         "bsr" => tapedevice.seek_blocks(io::SeekFrom::Current(count * -1)),
         "asr" => tapedevice.seek_blocks(io::SeekFrom::Start(count as u64)),
         "tell" => { println!("{}", tapedevice.tell_blocks()?); Ok(()) },
+        "status" => {
+            let partition = tapedevice.current_partition()?;
+            let block = tapedevice.tell_blocks()?;
+
+            match tapedevice.partition_count() {
+                Ok(count) => println!("Partition {} of {}, block {}", partition, count, block),
+                Err(_) => println!("Partition {}, block {}", partition, block)
+            }
+
+            Ok(())
+        },
         "setpartition" => tapedevice.seek_partition(count as u32 + 1),
+        "compression" => match argument.as_ref().map(|s| s.as_str()) {
+            Some("on") => tapedevice.set_compression(true),
+            Some("off") => tapedevice.set_compression(false),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "Specify compression on or off"))
+        },
+        "read" if record_size.is_some() => match filename.as_ref() {
+            "-" => read_records(&mut tapedevice, &mut io::stdout(), record_size.unwrap()),
+            name => read_records(&mut tapedevice, &mut fs::File::create(name).expect("Could not open target file to dump to"), record_size.unwrap())
+        },
+        "write" if record_size.is_some() => match filename.as_ref() {
+            "-" => write_records(&mut io::stdin(), &mut tapedevice, record_size.unwrap()),
+            name => write_records(&mut fs::File::open(name).expect("Could not open target file to dump from"), &mut tapedevice, record_size.unwrap())
+        },
         "read" => match filename.as_ref() {
             "-" => io::copy(&mut io::BufReader::with_capacity(blocksize.into_inner(), tapedevice), &mut io::stdout()),
             name => io::copy(&mut io::BufReader::with_capacity(blocksize.into_inner(), tapedevice), &mut fs::File::create(name).expect("Could not open target file to dump to"))
@@ -1,14 +1,16 @@
 extern crate rayon;
 extern crate argparse;
 extern crate librapidarchive;
+#[macro_use]
+extern crate log;
 
-use argparse::{ArgumentParser, Store, StoreConst, StoreTrue, StoreOption, Collect};
-use std::{io, time, env};
-use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
-use librapidarchive::{fs, tar, traverse, tuning, units, spanning};
-use librapidarchive::fs::open_sink;
+use argparse::{ArgumentParser, Store, StoreConst, StoreTrue, StoreOption, Collect, IncrBy, DecrBy};
+use std::{io, time, env, thread, path, process};
+use std::sync::{Arc, Mutex};
+use librapidarchive::{fs, tar, traverse, tuning, units, spanning, logging, retry, ltfs, snapshot, rotation, filter, chunking, compress, decompress, hashing, manifest, filelist, multistream, digest, tape, cancel, queue, jobs, status, intern};
+use librapidarchive::fs::{open_sink, open_source};
 
-use std::io::Write;
+use std::io::{Read, Seek, Write};
 use std::ops::DerefMut;
 
 #[derive(Copy, Clone)]
@@ -19,7 +21,9 @@ enum TarOperation {
     List,
     Append,
     Update,
-    Extract
+    Extract,
+    DumpHeader,
+    Recode
 }
 
 #[derive(Clone)]
@@ -29,12 +33,58 @@ struct TarParameter {
     pub basepath: String,
     pub outfile: String,
     pub traversal_list: Vec<String>,
-    pub verbose: bool,
+    pub files_from: Option<String>,
+    pub exclude: Vec<String>,
+    pub verbosity: i32,
+    pub show_pax: bool,
     pub totals: bool,
+    pub totals_json: Option<String>,
+    pub diff_json: Option<String>,
+    pub compare_content: Option<tar::compare::ContentDigest>,
+    pub verify_restore: Option<String>,
+    pub unc_restore: tar::UncRestore,
+    pub path_style: tar::PathStyle,
     pub spanning: bool,
-    pub spanning_size_limit: Option<u64>,
+    pub spanning_size_limit: spanning::VolumeSizeSchedule,
     pub perf_tuning: tuning::Configuration,
-    pub label_title: Option<String>
+    pub label_title: Option<String>,
+    pub atime_preserve: fs::AtimePolicy,
+    pub file_number: u64,
+    pub catalog_partition: bool,
+    pub ltfs: bool,
+    pub streams: Vec<String>,
+    pub snapshot: snapshot::SnapshotConfig,
+    pub snapshot_source: String,
+    pub rotation: rotation::RotationConfig,
+    pub ignore_nodump: bool,
+    pub mode_override: tar::filter::ModeOverride,
+    pub prefix: Option<String>,
+    pub skip_special: traverse::SpecialFilePolicy,
+    pub one_file_system: bool,
+    pub hard_dereference: bool,
+    pub secure_metadata: bool,
+    pub project_ids: bool,
+    pub acls: bool,
+    pub sparse: bool,
+    pub dump_count: usize,
+    pub apple_metadata: tar::apple::AppleMetadataPolicy,
+    pub exclude_tag: Vec<String>,
+    pub exclude_tag_under: Vec<String>,
+    pub exclude_tag_all: Vec<String>,
+    pub to_command: Option<String>,
+    pub rename_map: Option<String>,
+    pub jobs_file: Option<String>,
+    pub chunk_stats: bool,
+    pub manifest: Option<String>,
+    pub force: bool,
+    pub retention_days: Option<u64>,
+    pub check_retention: bool,
+    pub trim_torn: bool,
+    pub atomic: bool,
+    pub compression: Option<compress::Codec>,
+    pub append_to_tape: bool,
+    pub end_marks: Option<tape::FilemarkPolicy>,
+    pub permissions: tar::permissions::PermissionPolicy
 }
 
 impl Default for TarParameter {
@@ -48,87 +98,475 @@ impl Default for TarParameter {
             },
             outfile: "out.tar".to_string(),
             traversal_list: Vec::new(),
-            verbose: false,
+            files_from: None,
+            exclude: Vec::new(),
+            verbosity: 0,
+            show_pax: false,
             totals: false,
+            totals_json: None,
+            diff_json: None,
+            compare_content: None,
+            verify_restore: None,
+            unc_restore: tar::UncRestore::default(),
+            path_style: tar::PathStyle::default(),
             spanning: false,
-            spanning_size_limit: None,
+            spanning_size_limit: spanning::VolumeSizeSchedule::default(),
             perf_tuning: tuning::Configuration::default(),
-            label_title: None
+            label_title: None,
+            atime_preserve: fs::AtimePolicy::Ignore,
+            file_number: 0,
+            catalog_partition: false,
+            ltfs: false,
+            streams: Vec::new(),
+            snapshot: snapshot::SnapshotConfig::default(),
+            snapshot_source: ".".to_string(),
+            rotation: rotation::RotationConfig::default(),
+            ignore_nodump: false,
+            mode_override: tar::filter::ModeOverride::default(),
+            prefix: None,
+            skip_special: traverse::SpecialFilePolicy::default(),
+            one_file_system: false,
+            hard_dereference: false,
+            secure_metadata: false,
+            project_ids: false,
+            acls: false,
+            sparse: false,
+            dump_count: 1,
+            apple_metadata: tar::apple::AppleMetadataPolicy::default(),
+            exclude_tag: Vec::new(),
+            exclude_tag_under: Vec::new(),
+            exclude_tag_all: Vec::new(),
+            to_command: None,
+            rename_map: None,
+            jobs_file: None,
+            chunk_stats: false,
+            manifest: None,
+            force: false,
+            retention_days: None,
+            check_retention: false,
+            trim_torn: false,
+            atomic: false,
+            compression: None,
+            append_to_tape: false,
+            end_marks: None,
+            permissions: tar::permissions::PermissionPolicy::umask_from_process()
         }
     }
 }
 
 impl TarParameter {
+    /// Determine if we are adding to an existing archive (`-r` or `-u`).
+    fn is_appending(&self) -> bool {
+        match self.operation {
+            Some(TarOperation::Append) | Some(TarOperation::Update) | Some(TarOperation::Join) => true,
+            _ => false
+        }
+    }
+
     fn from_proc_args() -> Self {
+        match TarParameter::from_args(env::args().collect()) {
+            Ok(tarparams) => tarparams,
+            Err(code) => process::exit(code)
+        }
+    }
+
+    /// Parse a command line, including the program name.
+    ///
+    /// Yields the exit code to end the process with if the command line was
+    /// invalid or asked for help.
+    fn from_args(args: Vec<String>) -> Result<Self, i32> {
         let mut tarparams = TarParameter::default();
         let mut serial_buffer_limit_input = units::DataSize::from(1024*1024*1024 as u64);
-        let mut volume_size_limit : Option<units::DataSize<u64>> = None;
+        let mut queue_memory_limit_input = units::DataSize::from(tarparams.perf_tuning.queue_memory_limit);
+        let mut readahead_limit_input = units::DataSize::from(tarparams.perf_tuning.readahead_limit);
+        let mut write_retry_backoff_ms = tarparams.perf_tuning.write_retry.initial_backoff.as_millis() as u64;
+        let mut mode_input : Option<tar::filter::OctalMode> = None;
+        let mut entry_timeout_secs : Option<u64> = None;
+        let mut mode_mask_input : Option<tar::filter::OctalMode> = None;
         
         {
             let mut ap = ArgumentParser::new();
 
             ap.set_description("Create an archive file from a given directory's contents in parallel.");
             
-            ap.refer(&mut tarparams.operation).add_option(&["-A", "--catenate", "--concatenate"], StoreConst(Some(TarOperation::Join)), "Join two tar archives into a single file.")
+            ap.refer(&mut tarparams.operation).add_option(&["-A", "--catenate", "--concatenate"], StoreConst(Some(TarOperation::Join)), "Join the archives named on the command line onto the end of the archive.")
                 .add_option(&["-c", "--create"], StoreConst(Some(TarOperation::Create)), "Create a new tar archive.")
                 .add_option(&["-d", "--diff", "--compare"], StoreConst(Some(TarOperation::Compare)), "List differences between a tar archive and the filesystem.")
                 .add_option(&["-t", "--list"], StoreConst(Some(TarOperation::List)), "List the contents of a tar archive.")
                 .add_option(&["-r", "--append"], StoreConst(Some(TarOperation::Append)), "Add files to the end of an archive.")
                 .add_option(&["-u", "--update"], StoreConst(Some(TarOperation::Update)), "Update files within an archive that have changed.")
-                .add_option(&["-x", "--extract", "--get"], StoreConst(Some(TarOperation::Extract)), "Extract files from an archive.");
-            ap.refer(&mut tarparams.verbose).add_option(&["-v"], StoreTrue, "Verbose mode");
-            ap.refer(&mut tarparams.outfile).add_option(&["-f"], Store, "The file to write the archive to. Allowed to be a tape device.");
+                .add_option(&["-x", "--extract", "--get"], StoreConst(Some(TarOperation::Extract)), "Extract files from an archive.")
+                .add_option(&["--dump-header"], StoreConst(Some(TarOperation::DumpHeader)), "Print every field of the raw headers of members of an archive, for diagnosing archives other tar implementations disagree about.")
+                .add_option(&["--recode"], StoreConst(Some(TarOperation::Recode)), "Rewrite the archives named on the command line into a new archive, in the format, compression, and blocking given.");
+            ap.refer(&mut tarparams.verbosity).add_option(&["-v", "--verbose"], IncrBy(1), "Verbose mode (repeat for more detail)")
+                .add_option(&["-q", "--quiet"], DecrBy(1), "Quiet mode (repeat to silence errors too)");
+            ap.refer(&mut tarparams.show_pax).add_option(&["--show-pax"], StoreTrue, "When listing verbosely, also list any pax attributes of each member that weren't understood.");
+            ap.refer(&mut tarparams.outfile).add_option(&["-f", "--file"], Store, "The file to write the archive to. Allowed to be a tape device.");
             ap.refer(&mut tarparams.basepath).add_option(&["-C", "--directory"], Store, "The base path of the archival operation. Defaults to current working directory.");
-            ap.refer(&mut tarparams.format).add_option(&["--format"], Store, "The tar format to write or expect.");
+            ap.refer(&mut tarparams.format).add_option(&["--format"], Store, "The tar format to write or expect: ustar, posix or gnu.");
             ap.refer(&mut tarparams.totals).add_option(&["--totals"], StoreTrue, "Print performance statistics after the operation has completed.");
+            ap.refer(&mut tarparams.totals_json).add_option(&["--totals-json"], StoreOption, "Write statistics, including per-volume statistics, as JSON to the given file (or - for stdout).");
+            ap.refer(&mut tarparams.diff_json).add_option(&["--diff-json"], StoreOption, "When comparing, also write each difference found as a line of JSON to the given file (or - for stdout).");
+            ap.refer(&mut tarparams.compare_content).add_option(&["--compare-content"], StoreOption, "When comparing, also compare the contents of files using the given digest (sha256)");
+            ap.refer(&mut tarparams.unc_restore).add_option(&["--unc-restore"], Store, "Where members archived from UNC paths are found when comparing: relative (in a directory named for the server, the default) or share (on the share they came from)");
+            ap.refer(&mut tarparams.path_style).add_option(&["--path-style"], Store, "How Windows drive letters are recorded in member names, and read back when comparing: gnu (as a lowercase directory, the default), strip-drive (left out) or keep-drive (as C:/...)");
+            ap.refer(&mut tarparams.verify_restore).add_option(&["--verify-restore"], StoreOption, "When comparing, treat the directory as a tree restored from the archive: compare contents by SHA-256, also look for files the archive doesn't account for, and write a sealed verification report to the given file (or - for stdout)");
             ap.refer(&mut tarparams.spanning).add_option(&["-M", "--multi-volume"], StoreTrue, "Use multiple-volume tar archives.");
-            ap.refer(&mut volume_size_limit).add_option(&["-L", "--tape-length"], StoreOption, "The maximum volume size to create");
+            ap.refer(&mut tarparams.spanning_size_limit).add_option(&["-L", "--tape-length"], Store, "The maximum volume size to create, or a comma-separated list of sizes for each volume in turn (the last size repeats)");
             ap.refer(&mut tarparams.perf_tuning.channel_queue_depth).add_option(&["--channel_queue_depth"], Store, "How many files may be stored in memory pending archival");
+            ap.refer(&mut queue_memory_limit_input).add_option(&["--queue_memory_limit"], Store, "How many bytes of headers and file data may be stored in memory pending archival");
             ap.refer(&mut tarparams.perf_tuning.parallel_io_limit).add_option(&["--parallel_io_limit"], Store, "How many threads may be created to retrieve file metadata and contents");
-            ap.refer(&mut tarparams.perf_tuning.blocking_factor).add_option(&["--blocking_factor"], Store, "The number of bytes * 512 to write at once - only applies for tape");
+            ap.refer(&mut tarparams.perf_tuning.blocking_factor).add_option(&["--blocking_factor"], Store, "The number of bytes * 512 to write at once - only applies for tape, unless --force-blocking is given");
+            ap.refer(&mut tarparams.perf_tuning.tape_fallback_block_size).add_option(&["--fixed-block-size"], StoreOption, "For tape drives which can't be put into variable block mode, the block size in bytes to put them into instead. Tapes in fixed block mode are written one block per record");
+            ap.refer(&mut tarparams.perf_tuning.force_blocking).add_option(&["--force-blocking"], StoreTrue, "Pad archives written to files out to whole records of the blocking factor, as is done for tape");
+            ap.refer(&mut readahead_limit_input).add_option(&["--readahead_limit"], Store, "How much of each file to read ahead while it waits to be archived, or 0 to disable read-ahead");
             ap.refer(&mut serial_buffer_limit_input).add_option(&["--serial_buffer_limit"], Store, "How many bytes to buffer on the tarball side of the operation");
+            ap.refer(&mut tarparams.perf_tuning.write_retry.max_retries).add_option(&["--write_retries"], Store, "How many times to retry a write that failed due to a transient device error");
+            ap.refer(&mut write_retry_backoff_ms).add_option(&["--write_retry_backoff"], Store, "How many milliseconds to wait before the first retry of a failed write; doubles with each retry");
+            ap.refer(&mut entry_timeout_secs).add_option(&["--entry_timeout"], StoreOption, "How many seconds a single read of a file may take before giving up on it and filling the rest of it's member with zeroes");
+            ap.refer(&mut tarparams.perf_tuning.hasher_threads).add_option(&["--hasher_threads"], Store, "How many threads may hash archive data (for --chunk-stats), or 0 to hash on the writing thread");
+            ap.refer(&mut tarparams.perf_tuning.compression_level).add_option(&["--compression_level"], StoreOption, "How hard to compress the archive, from 1 (fastest) to 9 for gzip and xz, or 19 for zstd");
+            ap.refer(&mut tarparams.perf_tuning.compression_threads).add_option(&["--compression_threads"], Store, "How many threads may compress archive data, or 0 to compress on the writing thread");
+            ap.refer(&mut tarparams.perf_tuning.fsync).add_option(&["--fsync"], Store, "When to sync archives written to files to disk: never (none), once the archive is complete (end, the default), at the end of every volume (per-volume), or every so many bytes (e.g. 512M)");
+            ap.refer(&mut tarparams.perf_tuning.spill_to_disk).add_option(&["--spill_buffer"], StoreTrue, "Spill data exceeding serial_buffer_limit to a temporary file instead of waiting for the device");
             ap.refer(&mut tarparams.traversal_list).add_argument("file", Collect, "The files to archive");
+            ap.refer(&mut tarparams.files_from).add_option(&["-T", "--files-from"], StoreOption, "Also archive the files named in the given file, one per line. Lines may also change directory (-C), exclude files (--exclude), or name files starting with a dash (--add-file) for the files listed after them");
+            ap.refer(&mut tarparams.exclude).add_option(&["--exclude"], Collect, "Leave out files and directories matching the given shell wildcard pattern");
             ap.refer(&mut tarparams.label_title).add_option(&["-V", "--label"], StoreOption, "The volume label to create or expect");
+            ap.refer(&mut tarparams.catalog_partition).add_option(&["--catalog-partition"], StoreTrue, "Write data to the first partition of a partitioned tape, and a catalog of it's members to the second. With -t, list members from the catalog instead of reading the archive");
+            ap.refer(&mut tarparams.ltfs).add_option(&["--ltfs"], StoreTrue, "Write an LTFS volume instead of a tar archive. The tape must be formatted with two partitions");
+            ap.refer(&mut tarparams.streams).add_option(&["--stream"], Collect, "Also write to the given file or device, splitting the members between it and every other one given, each of which gets an archive of it's own. Can be given more than once");
+            ap.refer(&mut tarparams.snapshot.method).add_option(&["--snapshot"], Store, "Archive from a read-only snapshot of the source, taken with the given method (btrfs)");
+            ap.refer(&mut tarparams.snapshot.pre_hook).add_option(&["--snapshot-pre"], StoreOption, "A command to run before archiving. Without --snapshot, the last line it prints is the path of the snapshot to archive from");
+            ap.refer(&mut tarparams.snapshot.post_hook).add_option(&["--snapshot-post"], StoreOption, "A command to run once the snapshot is no longer needed");
+            ap.refer(&mut tarparams.snapshot_source).add_option(&["--snapshot-source"], Store, "The directory (subvolume or mount point) to snapshot. Defaults to the base path");
+            ap.refer(&mut tarparams.ignore_nodump).add_option(&["--ignore-nodump"], StoreTrue, "Archive files flagged as no-dump (chattr +d, chflags nodump) instead of skipping them");
+            ap.refer(&mut mode_input).add_option(&["--mode"], StoreOption, "Record the given permissions (in octal) for every member, instead of those found on disk");
+            ap.refer(&mut mode_mask_input).add_option(&["--mode-mask"], StoreOption, "Clear the given permission bits (in octal, like a umask) from every member");
+            ap.refer(&mut tarparams.skip_special).add_option(&["--skip-special"], Store, "What to do with files that can't be archived, such as sockets: skip them with a warning (warn), report an error (error), or skip them quietly (silent)");
+            ap.refer(&mut tarparams.one_file_system).add_option(&["--one-file-system"], StoreTrue, "Do not descend into directories on a different filesystem from the path being archived");
+            ap.refer(&mut tarparams.hard_dereference).add_option(&["--hard-dereference"], StoreTrue, "Archive every hard link to a file as a copy of it, rather than as a link to the first");
+            ap.refer(&mut tarparams.secure_metadata).add_option(&["--secure-metadata"], StoreTrue, "Open each file before reading its metadata, and archive both from the same open file, so that files replaced during archival can't mix one file's metadata with another's contents");
+            ap.refer(&mut tarparams.sparse).add_option(&["-S", "--sparse"], StoreTrue, "Archive sparse files without their holes. Requires the posix or gnu format; ustar archives store them in full");
+            ap.refer(&mut tarparams.dump_count).add_option(&["--dump-count"], Store, "How many members to dump with --dump-header, or 0 for all of them. Defaults to 1");
+            ap.refer(&mut tarparams.project_ids).add_option(&["--project-ids"], StoreTrue, "Record the Linux project ID (as used by XFS and ext4 project quotas) of each file. Requires the posix format");
+            ap.refer(&mut tarparams.acls).add_option(&["--acls"], StoreTrue, "Record the POSIX access and default ACLs of each file. Requires the posix format");
+            ap.refer(&mut tarparams.apple_metadata).add_option(&["--apple-metadata"], Store, "How to archive macOS Finder info and resource forks: as pax extended attributes (pax), as ._ AppleDouble companion files (appledouble), or not at all, also leaving out ._ files found on disk (none)");
+            ap.refer(&mut tarparams.exclude_tag).add_option(&["--exclude-tag"], Collect, "Leave out the contents of directories containing the given file, except for the file itself");
+            ap.refer(&mut tarparams.exclude_tag_under).add_option(&["--exclude-tag-under"], Collect, "Leave out all contents of directories containing the given file");
+            ap.refer(&mut tarparams.to_command).add_option(&["--to-command"], StoreOption, "When extracting, pipe the contents of each file to the given command instead of writing it to disk. Member details are given in TAR_* environment variables");
+            ap.refer(&mut tarparams.rename_map).add_option(&["--rename-map"], StoreOption, "With --to-command, give members new paths according to the given file before piping them, with one tab-separated old and new path per line. Renaming a directory moves it's contents as well");
+            ap.refer(&mut tarparams.permissions).add_option(&["-p", "--preserve-permissions", "--same-permissions"], StoreConst(tar::permissions::PermissionPolicy::Exact), "When extracting, restore archived permissions exactly instead of applying the umask. Restoring setuid, setgid, or sticky bits is confirmed for each member first")
+                .add_option(&["--no-same-permissions"], StoreConst(tar::permissions::PermissionPolicy::umask_from_process()), "When extracting, apply the umask to archived permissions and drop any setuid, setgid, or sticky bits (the default)");
+            ap.refer(&mut tarparams.exclude_tag_all).add_option(&["--exclude-tag-all"], Collect, "Leave out directories containing the given file entirely");
+            ap.refer(&mut tarparams.prefix).add_option(&["--prefix"], StoreOption, "Store every member within the given directory, e.g. --prefix=project-1.0/");
+            ap.refer(&mut tarparams.chunk_stats).add_option(&["--chunk-stats"], StoreTrue, "Estimate how well the archive would deduplicate, by splitting it into content-defined chunks as it is written");
+            ap.refer(&mut tarparams.manifest).add_option(&["--manifest"], StoreOption, "Write a manifest of archived members and link targets (and chunks, with --chunk-stats) to the given file");
+            ap.refer(&mut tarparams.atomic).add_option(&["--atomic"], StoreTrue, "Write a new archive to the given name with .partial added, and only rename it into place once it is complete and synced to disk, so that failed runs never leave half an archive under the name asked for");
+            ap.refer(&mut tarparams.compression).add_option(&["-z", "--gzip", "--gunzip", "--ungzip"], StoreConst(Some(compress::Codec::Gzip)), "Compress the archive with gzip as it is written; compressed archives are recognized when read")
+                .add_option(&["--zstd"], StoreConst(Some(compress::Codec::Zstd)), "Compress the archive with Zstandard as it is written")
+                .add_option(&["-J", "--xz"], StoreConst(Some(compress::Codec::Xz)), "Compress the archive with xz as it is written");
+            ap.refer(&mut tarparams.trim_torn).add_option(&["--trim-torn"], StoreTrue, "If a volume fills up without -M, remove the partly written file from the end of it so the volume still ends with a complete archive");
+            ap.refer(&mut tarparams.append_to_tape).add_option(&["--append-to-tape"], StoreTrue, "Space to the end of recorded data before writing to a tape, so the archive is added after any already on it. Use a non-rewinding device (e.g. /dev/nst0) to stack several archives");
+            ap.refer(&mut tarparams.end_marks).add_option(&["--tape-end-marks"], StoreOption, "How to mark the end of each tape volume: single (filemark), double (filemarks), or setmark. Defaults to the platform's convention");
+            ap.refer(&mut tarparams.retention_days).add_option(&["--retention-days"], StoreOption, "Record in each volume's label that it is to be kept for the given number of days");
+            ap.refer(&mut tarparams.check_retention).add_option(&["--check-retention"], StoreTrue, "Refuse to overwrite a volume whose recorded retention has not yet passed, even with --force");
+            ap.refer(&mut tarparams.force).add_option(&["--force"], StoreTrue, "Write to tapes that already hold an archive, LTFS volume, or labeled volume without asking first");
+            ap.refer(&mut tarparams.rotation.policy).add_option(&["--rotation"], StoreOption, "Pick the volume to write to from a tape library, following the given rotation: gfs (grandfather-father-son, optionally gfs:MONTHS) or hanoi (Tower of Hanoi, optionally hanoi:SETS). Needs --inventory and --usage-catalog");
+            ap.refer(&mut tarparams.rotation.inventory).add_option(&["--inventory"], StoreOption, "With --rotation, a file listing the library's slots, one per line: the slot number, then a tab and the volume tag of the tape in it, if any");
+            ap.refer(&mut tarparams.rotation.catalog).add_option(&["--usage-catalog"], StoreOption, "With --rotation, the file recording which volume belongs to each media set and how often it's been written. Created if it doesn't exist, and updated once the archive is complete");
+            ap.refer(&mut tarparams.rotation.load_command).add_option(&["--load-command"], StoreOption, "With --rotation, a command that loads the selected volume into the drive, told it's slot, volume tag, and media set in RAPIDTAR_SLOT, RAPIDTAR_VOLUME, and RAPIDTAR_MEDIA_SET. Without it, you're asked to load the volume yourself");
+            ap.refer(&mut tarparams.file_number).add_option(&["--file-number"], Store, "Which archive on a tape to read, counting from 0 at the start of the tape");
+            ap.refer(&mut tarparams.atime_preserve).add_option(&["--atime-preserve"], Store, "Preserve access times of archived files, either by restoring them after reading (replace) or by asking the OS not to change them (system)");
+            ap.refer(&mut tarparams.jobs_file).add_option(&["--jobs-file"], StoreOption, "Run each job described in the given file in turn, then summarize them. Other options given here apply to every job");
             
-            ap.parse_args_or_exit();
+            ap.parse(args, &mut io::stdout(), &mut io::stderr())?;
         }
 
         tarparams.perf_tuning.serial_buffer_limit = serial_buffer_limit_input.into_inner();
-        tarparams.spanning_size_limit = match volume_size_limit {
-            Some(limit) => Some(limit.into_inner()),
-            None => None
-        };
+        tarparams.perf_tuning.queue_memory_limit = queue_memory_limit_input.into_inner();
+        tarparams.perf_tuning.readahead_limit = readahead_limit_input.into_inner();
+        tarparams.perf_tuning.write_retry.initial_backoff = time::Duration::from_millis(write_retry_backoff_ms);
+        tarparams.perf_tuning.entry_timeout = entry_timeout_secs.map(time::Duration::from_secs);
+        tarparams.mode_override.mode = mode_input.map(|m| m.0);
+        tarparams.mode_override.mask = mode_mask_input.map(|m| m.0);
+
+        Ok(tarparams)
+    }
+}
+
+/// Statistics for a single volume of a (possibly multi-volume) archive.
+/// 
+/// Byte counts reflect data submitted to the sink while the volume was
+/// mounted, minus any data that had to be recovered onto the next volume.
+#[derive(Clone)]
+struct VolumeResult {
+    pub start_instant: time::Instant,
+    pub end_instant: Option<time::Instant>,
+    pub bytes: u64,
+    pub members: usize,
+
+    /// The tape block the volume started at, if it was written to a tape.
+    pub start_block: Option<u64>,
+
+    /// Where in the file the volume started, which is only nonzero when
+    /// appending to an existing archive.
+    pub start_offset: u64,
 
-        tarparams
+    /// Where each member archived onto this volume begins.
+    pub catalog: tar::catalog::Catalog,
+
+    /// Members which begin on this volume and continue onto the next.
+    pub splits: Vec<SplitMember>,
+}
+
+/// A member which was split between two volumes.
+///
+/// Restoring a split member requires both volumes, so operators need to know
+/// about them.
+#[derive(Clone)]
+struct SplitMember {
+    pub path: Box<path::PathBuf>,
+
+    /// How much of the member, including it's headers, is on the volume it
+    /// begins on.
+    pub bytes_on_volume: u64,
+
+    /// How much of the member continues onto the next volume.
+    pub bytes_remaining: u64,
+}
+
+impl Default for VolumeResult {
+    fn default() -> Self {
+        VolumeResult {
+            start_instant: time::Instant::now(),
+            end_instant: None,
+            bytes: 0,
+            members: 0,
+            start_block: None,
+            start_offset: 0,
+            catalog: tar::catalog::Catalog::new(),
+            splits: Vec::new()
+        }
+    }
+}
+
+impl VolumeResult {
+    fn duration(&self) -> time::Duration {
+        match self.end_instant {
+            Some(end_instant) => end_instant.duration_since(self.start_instant),
+            None => self.start_instant.elapsed()
+        }
     }
 }
 
 #[derive(Clone)]
 struct TarResult {
-    pub cancelled: bool,
+    /// Totals and cancellation, shared with anything watching the operation.
+    /// 
+    /// Per-volume counts are kept in `volumes` as we go, and published to the
+    /// status with `publish_volume`.
+    pub status: Arc<status::ArchiveStatus>,
     pub start_instant: time::Instant,
-    pub tarball_size: units::DataSize<u64>,
-    pub volume_count: usize,
+    pub volumes: Vec<VolumeResult>,
+
+    /// Where members (and chunks) are being recorded, if anywhere.
+    pub manifest: Option<Arc<Mutex<manifest::Manifest<io::BufWriter<std::fs::File>>>>>,
+
+    /// Chunks everything written to the archive, if we're estimating dedup.
+    pub chunker: Option<Arc<Mutex<chunking::Chunker>>>,
+
+    /// The threads doing our hashing, if we have anything to hash.
+    pub hasher_pool: Option<Arc<hashing::HasherPool>>,
+
+    /// The members already in the archive, when updating it.
+    pub unchanged: Option<Arc<tar::filter::SkipUnchanged>>,
+
+    /// Which member holds each hard linked file written so far.
+    pub hard_links: tar::hardlink::HardLinkTracker,
+
+    /// Identifies this archive in the label of every volume.
+    pub archive_uuid: String,
+
+    /// The digest of the last volume label written, which the next volume's
+    /// label records so that readers can check volume order.
+    pub last_label_digest: Option<digest::Sha256Digest>,
+
+    /// Drives held open for a batch of jobs, if we're running one.
+    pub drives: Option<jobs::SharedDrives<tar::recovery::RecoveryEntry>>,
 }
 
 impl Default for TarResult {
     fn default() -> Self {
         TarResult {
-            cancelled: false,
+            status: Arc::new(status::ArchiveStatus::new(cancel::CancellationToken::new())),
             start_instant: time::Instant::now(),
-            tarball_size: units::DataSize::from(0),
-            volume_count: 1
+            volumes: vec![VolumeResult::default()],
+            manifest: None,
+            chunker: None,
+            hasher_pool: None,
+            unchanged: None,
+            hard_links: tar::hardlink::HardLinkTracker::new(),
+            archive_uuid: tar::label::random_uuid(),
+            last_label_digest: None,
+            drives: None
+        }
+    }
+}
+
+impl TarResult {
+    /// Statistics for the volume currently being written.
+    fn current_volume(&mut self) -> &mut VolumeResult {
+        if self.volumes.len() == 0 {
+            self.volumes.push(VolumeResult::default());
+        }
+
+        let last = self.volumes.len() - 1;
+        &mut self.volumes[last]
+    }
+
+    /// Mark the current volume as finished and start counting a new one.
+    fn begin_volume(&mut self) {
+        self.finish_volume();
+        self.volumes.push(VolumeResult::default());
+        self.status.begin_volume();
+    }
+
+    /// The number of the volume currently being written, starting from 1.
+    fn volume_count(&self) -> usize {
+        self.status.volume_count()
+    }
+
+    /// How much has been written to every volume so far.
+    fn tarball_size(&self) -> units::DataSize<u64> {
+        units::DataSize::from(self.status.bytes())
+    }
+
+    /// Make the current volume's counts visible to whoever is watching.
+    fn publish_volume(&mut self) {
+        let volume = self.current_volume();
+        let counts = status::VolumeStatus {
+            bytes: volume.bytes,
+            members: volume.members
+        };
+
+        self.status.update_volume(counts);
+    }
+
+    /// How many members were split across volumes.
+    fn split_members(&self) -> usize {
+        self.volumes.iter().map(|volume| volume.splits.len()).sum()
+    }
+
+    fn finish_volume(&mut self) {
+        self.publish_volume();
+
+        let volume = self.current_volume();
+
+        if let None = volume.end_instant {
+            volume.end_instant = Some(time::Instant::now());
         }
     }
 }
 
+fn float_secs(duration: time::Duration) -> f64 {
+    (duration.as_secs() as f64) + (duration.subsec_nanos() as f64) / (1000 * 1000 * 1000) as f64
+}
+
 fn totals_cli(tarresult: &TarResult) {
     let write_time = tarresult.start_instant.elapsed();
-    let float_secs = (write_time.as_secs() as f64) + (write_time.subsec_nanos() as f64) / (1000 * 1000 * 1000) as f64;
-    let rate = units::DataSize::from(tarresult.tarball_size.clone().into_inner() as f64 / float_secs);
+    let rate = units::DataSize::from(tarresult.status.bytes() as f64 / float_secs(write_time));
     let displayable_time = units::HRDuration::from(write_time);
     
-    eprintln!("Wrote {} in {} ({}/s)", tarresult.tarball_size, displayable_time, rate);
+    eprintln!("Wrote {} in {} ({}/s)", tarresult.tarball_size(), displayable_time, rate);
+
+    if tarresult.split_members() > 0 {
+        eprintln!("{} members were split across volumes", tarresult.split_members());
+    }
+
+    if let Some(ref chunker) = tarresult.chunker {
+        let stats = chunker.lock().unwrap().stats();
+
+        eprintln!("Deduplication estimate: {} of {} chunks unique, {} of {} ({:.2}x)", stats.unique_chunks, stats.chunks, units::DataSize::from(stats.unique_bytes), units::DataSize::from(stats.total_bytes), stats.dedup_ratio());
+    }
+
+    if let Some(ref pool) = tarresult.hasher_pool {
+        let stats = pool.stats();
+
+        eprintln!("Hashed {} in {} of hasher time ({}/s per thread)", units::DataSize::from(stats.bytes()), units::HRDuration::from(stats.busy()), units::DataSize::from(stats.throughput()));
+    }
+}
+
+/// Print a per-volume breakdown of a multi-volume archive.
+fn volume_totals_cli(tarresult: &TarResult) {
+    for (i, volume) in tarresult.volumes.iter().enumerate() {
+        let duration = volume.duration();
+        let rate = units::DataSize::from(volume.bytes as f64 / float_secs(duration));
+
+        eprintln!("Volume {}: {} members, {} in {} ({}/s)", i + 1, volume.members, units::DataSize::from(volume.bytes), units::HRDuration::from(duration), rate);
+
+        for split in volume.splits.iter() {
+            eprintln!("  {:?} continues onto volume {} after {} ({} remaining)", split.path, i + 2, units::DataSize::from(split.bytes_on_volume), units::DataSize::from(split.bytes_remaining));
+        }
+    }
+}
+
+/// Write archive statistics, including the per-volume breakdown, as JSON.
+fn totals_json<W: Write>(tarresult: &TarResult, out: &mut W) -> io::Result<()> {
+    write!(out, "{{\"bytes\":{},\"duration_secs\":{:.3},\"split_members\":{},\"volumes\":[", tarresult.status.bytes(), float_secs(tarresult.start_instant.elapsed()), tarresult.split_members())?;
+
+    for (i, volume) in tarresult.volumes.iter().enumerate() {
+        if i > 0 {
+            write!(out, ",")?;
+        }
+
+        write!(out, "{{\"volume\":{},\"bytes\":{},\"members\":{},\"duration_secs\":{:.3},\"splits\":[", i + 1, volume.bytes, volume.members, float_secs(volume.duration()))?;
+
+        for (j, split) in volume.splits.iter().enumerate() {
+            if j > 0 {
+                write!(out, ",")?;
+            }
+
+            write!(out, "{{\"path\":{},\"bytes_on_volume\":{},\"bytes_remaining\":{}}}", tar::compare::json_path(&split.path), split.bytes_on_volume, split.bytes_remaining)?;
+        }
+
+        write!(out, "]}}")?;
+    }
+
+    write!(out, "]")?;
+
+    if let Some(ref chunker) = tarresult.chunker {
+        let stats = chunker.lock().unwrap().stats();
+
+        write!(out, ",\"chunks\":{{\"bytes\":{},\"chunks\":{},\"unique_bytes\":{},\"unique_chunks\":{},\"dedup_ratio\":{:.3}}}", stats.total_bytes, stats.chunks, stats.unique_bytes, stats.unique_chunks, stats.dedup_ratio())?;
+    }
+
+    if let Some(ref pool) = tarresult.hasher_pool {
+        let stats = pool.stats();
+
+        write!(out, ",\"hashing\":{{\"bytes\":{},\"busy_secs\":{:.3},\"bytes_per_sec\":{:.0}}}", stats.bytes(), float_secs(stats.busy()), stats.throughput())?;
+    }
+
+    writeln!(out, "}}")
+}
+
+/// Report final statistics in whatever forms the user asked for.
+fn report_totals(tarparams: &TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
+    tarresult.finish_volume();
+
+    if tarparams.totals {
+        totals_cli(tarresult);
+
+        if tarresult.volumes.len() > 1 {
+            volume_totals_cli(tarresult);
+        }
+    }
+
+    match tarparams.totals_json.as_ref().map(|s| s.as_str()) {
+        Some("-") => totals_json(tarresult, &mut io::stdout()),
+        Some(path) => totals_json(tarresult, &mut std::fs::File::create(path)?),
+        None => Ok(())
+    }
 }
 
 /// Produces CLI to prompt a user to exchange a volume due to a previous volume
@@ -137,10 +575,10 @@ fn totals_cli(tarresult: &TarResult) {
 /// The user is allowed to alter the parameters of the operation or cancel it
 /// outright. Check the parameters and result to determine how to proceed.
 fn volume_exchange_cli(tarparams: &mut TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
-    eprintln!("Volume {} ran out of space and needs to be replaced.", tarresult.volume_count);
+    eprintln!("Volume {} ran out of space and needs to be replaced.", tarresult.volume_count());
     eprintln!("Prepare the next volume and press enter when ready (or ? for more options)...");
     
-    while tarresult.cancelled == false {
+    while !tarresult.status.is_cancelled() {
         let mut response = String::new();
 
         match io::stdin().read_line(&mut response) {
@@ -154,7 +592,7 @@ fn volume_exchange_cli(tarparams: &mut TarParameter, tarresult: &mut TarResult)
                 },
                 "q" => {
                     eprintln!("Cancelling archival.");
-                    tarresult.cancelled = true;
+                    tarresult.status.cancel();
                 },
                 "y" => {
                     break;
@@ -167,7 +605,7 @@ fn volume_exchange_cli(tarparams: &mut TarParameter, tarresult: &mut TarResult)
             },
             Err(ref error) if error.kind() == io::ErrorKind::InvalidData => eprintln!("Please enter a valid response."),
             Err(error) => {
-                eprintln!("Got unknown error {}!", error);
+                error!("Got unknown error {}!", error);
                 return Err(error);
             }
         }
@@ -189,208 +627,2036 @@ fn label_proc<P>(tarball: &mut fs::ArchivalSink<P>, zone: Option<&spanning::Data
     };
 
     tarlabel.label = tarparams.label_title.clone();
-    tarlabel.volume_identifier = match tarparams.spanning {
-        true => Some(tarresult.volume_count),
+    tarlabel.volume_identifier = match tarparams.spanning || tarresult.volume_count() > 1 {
+        true => Some(tarresult.volume_count()),
         false => None
     };
+    tarlabel.archive_uuid = Some(tarresult.archive_uuid.clone());
+    tarlabel.previous_volume_digest = tarresult.last_label_digest;
+    tarlabel.retain_until = tarparams.retention_days.map(|days| time::SystemTime::now() + time::Duration::from_secs(days * 86400));
 
-    tarball.write_all(&tar::label::labelgen(tarparams.format, &tarlabel)?)
+    let label = tar::label::labelgen(tarparams.format, &tarlabel)?;
+
+    if label.len() > 0 {
+        tarresult.last_label_digest = Some(digest::Sha256::digest(&label));
+    }
+
+    tarball.write_all(&label)?;
+    tarresult.current_volume().bytes += label.len() as u64;
+    tarresult.publish_volume();
+
+    Ok(())
 }
 
-/// Recover a partially-completed write operation.
+/// Make sure writing to a freshly opened volume won't destroy something.
 /// 
-/// CLI will be presented to the user to select a new volume to write to, and
-/// then a new `ArchivalSink` will be opened. Any data already presented, but
-/// not yet committed to the tarball, will be migrated to the new sink. As this
-/// process can also fail partially, we repeat this process until all data has
-/// been committed to any number of volumes, and then return the last sink used
-/// in the queue.
-fn recover_proc(old_tarball: Box<fs::ArchivalSink<tar::recovery::RecoveryEntry>>, tarparams: &mut TarParameter, tarresult: &mut TarResult) -> io::Result<Box<fs::ArchivalSink<tar::recovery::RecoveryEntry>>> {
-    if tarresult.cancelled {
-        Ok(old_tarball)
-    } else {
-        let mut lost_zones : Vec<spanning::DataZone<tar::recovery::RecoveryEntry>> = old_tarball.uncommitted_writes();
-        let mut ret = None;
-
-        drop(old_tarball);
-        
-        if tarparams.totals {
-            totals_cli(tarresult);
+/// If the sink is a tape positioned at it's beginning, and the first block
+/// holds anything we recognize, the user has to confirm the overwrite (unless
+/// `--force` was given). Without a terminal to ask on, the overwrite is
+/// refused.
+/// 
+/// With `--check-retention`, volumes whose retention hasn't passed are never
+/// overwritten, `--force` or not.
+fn check_overwrite(tape: &mut dyn tape::TapeDevice, tarparams: &TarParameter) -> io::Result<()> {
+    if tarparams.check_retention {
+        if let Some(retain_until) = tape::probe::probe_retention(tape)? {
+            tar::label::check_retention(retain_until, time::SystemTime::now())?;
         }
+    }
 
-        while tarresult.cancelled == false {
-            volume_exchange_cli(tarparams, tarresult)?;
+    if tarparams.force {
+        return Ok(());
+    }
 
-            if tarresult.cancelled {
-                return Err(io::Error::new(io::ErrorKind::Other, "User cancelled the operation"));
-            }
+    let contents = match tape::probe::probe(tape)? {
+        Some(contents) => contents,
+        None => return Ok(())
+    };
 
-            let mut tarball = match open_sink(&tarparams.outfile, &tarparams.perf_tuning, tarparams.spanning_size_limit) {
-                Ok(tarball) => tarball,
-                Err(e) => {
-                    eprintln!("Error trying to open new volume: {}", e);
-                    continue;
-                }
-            };
+    eprintln!("The tape in {} already holds {}.", tarparams.outfile, contents);
+    eprintln!("Overwrite it? (y/N)");
 
-            tarresult.volume_count += 1;
+    let mut response = String::new();
+    io::stdin().read_line(&mut response)?;
 
-            let mut did_label = false;
+    match response.trim() {
+        "y" | "Y" | "yes" => Ok(()),
+        _ => Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("Refusing to overwrite {} without --force", contents)))
+    }
+}
 
-            for ref zone in lost_zones.iter() {
-                if let Some(_) = &zone.ident {
-                    label_proc(tarball.deref_mut(), Some(zone), tarparams, tarresult)?;
-                    did_label = true;
-                    break;
-                }
-            }
+/// Make sure opening an archive file for writing won't destroy a volume that
+/// is to be retained.
+/// 
+/// Tapes are checked once they're opened, by `check_overwrite`; files have to
+/// be checked first, since opening them truncates them.
+fn check_file_retention(outfile: &str) -> io::Result<()> {
+    let file = match std::fs::File::open(outfile) {
+        Ok(ref file) if !file.metadata()?.is_file() => return Ok(()),
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e)
+    };
 
-            if !did_label {
-                label_proc(tarball.deref_mut(), None, tarparams, tarresult)?;
-            }
-            
-            match tar::recovery::recover_data(tarball.deref_mut(), tarparams.format, lost_zones.clone()) {
-                Ok(None) => {
-                    ret = Some(tarball);
-                    break
-                },
-                Ok(Some(zones)) => lost_zones = zones,
-                Err(e) => {
-                    eprintln!("Unknown error recovering torn writes: {}", e);
-                    return Err(e);
-                }
-            }
-        }
+    let mut volume_start = Vec::new();
+    file.take(64 * 1024).read_to_end(&mut volume_start)?;
 
-        ret.ok_or(io::Error::new(io::ErrorKind::Other, "Didn't exchange tarballs, even though we were supposed to."))
+    match tar::label::read_retention(&volume_start) {
+        Some(retain_until) => tar::label::check_retention(retain_until, time::SystemTime::now()),
+        None => Ok(())
     }
 }
 
-/// Serialize the files from a traversal channel into the tarball.
+/// Position a freshly opened tape for writing a new volume.
 /// 
-/// # Write failures
+/// Tapes are normally written wherever they happen to be positioned. With
+/// `--append-to-tape` or `-r`, we space to the end of recorded data first (in
+/// the data partition, if a catalog is being written), so that the new archive
+/// lands after every archive already on the tape. Either way, the tape is never
+/// rewound.
+fn position_tape(tape: &mut dyn tape::TapeDevice, tarparams: &TarParameter) -> io::Result<()> {
+    if tarparams.append_to_tape || tarparams.is_appending() {
+        if tarparams.catalog_partition && tape.current_partition()? != tar::catalog::DATA_PARTITION {
+            tape.seek_partition(tar::catalog::DATA_PARTITION)?;
+        }
+
+        tape.seek_filemarks(io::SeekFrom::End(0))?;
+        info!("Appending to tape at block {}", tape.tell_blocks()?);
+    }
+
+    check_overwrite(tape, tarparams)
+}
+
+/// Prepare a freshly opened sink to be written to as a new volume.
 /// 
-/// In the event of a write failure, this function will report the failed entry
-/// for possible error recovery.
-fn serialize_proc(tarball: &mut fs::ArchivalSink<tar::recovery::RecoveryEntry>, receiver: &Receiver<tar::header::HeaderGenResult>, failed_entry: &mut Option<tar::header::HeaderGenResult>, tarparams: &mut TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
-    label_proc(tarball, None, tarparams, tarresult)?;
+/// If the sink is a tape, it's starting position is recorded so that we can
+/// find our way back should the drive lose it's position mid-volume. Tapes
+/// which will receive a catalog are moved to their data partition first, and
+/// every tape is told how to mark the end of the volume.
+fn start_volume<P>(tarball: &mut fs::ArchivalSink<P>, tarparams: &TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
+    if let (Some(policy), Some(tape)) = (tarparams.end_marks, tarball.downcast_tapedevice()) {
+        tape.set_filemark_policy(policy)?;
+    }
 
-    while let Ok(entry) = receiver.recv() {
-        if tarparams.verbose {
-            eprintln!("{:?}", entry.original_path);
-        }
+    if tarparams.catalog_partition {
+        if let Some(tape) = tarball.downcast_tapedevice() {
+            tar::catalog::check_partitioned(tape)?;
 
-        if tarparams.spanning {
-            let header_length = entry.encoded_header.len() as u64;
-            tarball.begin_data_zone(tar::recovery::RecoveryEntry::new_from_headergen(&entry, header_length));
+            if tape.current_partition()? != tar::catalog::DATA_PARTITION {
+                tape.seek_partition(tar::catalog::DATA_PARTITION)?;
+            }
         }
+    }
 
-        match tar::serialize(&entry, tarball) {
-            Ok(size) => tarresult.tarball_size += units::DataSize::from(size),
+    tarresult.current_volume().start_block = match tarball.downcast_tapedevice() {
+        Some(tape) => match tape.tell_blocks() {
+            Ok(block) => Some(block),
             Err(e) => {
-                *failed_entry = Some(entry);
-                return Err(e);
+                warn!("Could not determine starting position of tape, device errors will not be recoverable: {}", e);
+                None
             }
-        }
-    }
+        },
+        None => None
+    };
+
+    tarresult.current_volume().start_offset = match tarball.downcast_seek() {
+        Some(seekable) => seekable.seek(io::SeekFrom::Current(0))?,
+        None => 0
+    };
 
     Ok(())
 }
 
-/// Prepare a multithreaded directory traversal for reading files into a
-/// tarball.
-/// 
-/// This function returns a `Receiver` which can be used to retrieve all of the
-/// discovered directories.
-fn read_traverse(parallel_read_pool: &rayon::ThreadPool, tarparams: &TarParameter) -> io::Result<Receiver<tar::header::HeaderGenResult>> {
-    //This is a sync channel, which means that it's channel bound forms a
-    //rudimentary backpressure mechanism. If there are 512 files already queued,
-    //then the 512 threads in the reading pool will eventually block, resulting
-    //in a maximum number of 1024 files - 1MB each - in memory at one time.
-    let (sender, receiver) = sync_channel(tarparams.perf_tuning.channel_queue_depth);
-
-    for traversal_path in tarparams.traversal_list.clone() {
-        let child_sender = sender.clone();
-        let format = tarparams.format;
+/// Find where to continue writing an archive file we're appending to.
+///
+/// Only the first volume is appended to; any later ones are written afresh.
+/// Yields None if the archive should be opened as usual, as for archives that
+/// don't exist yet, or tapes, which `position_tape` spaces to the end of their
+/// data instead.
+fn append_offset(volume: usize, tarparams: &TarParameter) -> io::Result<Option<u64>> {
+    if !tarparams.is_appending() || volume > 1 {
+        return Ok(None);
+    }
 
-        parallel_read_pool.spawn(move || {
-            traverse::traverse(traversal_path, &move |iopath, tarpath, metadata, c: &SyncSender<tar::header::HeaderGenResult>| {
-                let tarheader = tar::header::TarHeader::abstract_header_for_file(tarpath, metadata, iopath)?;
-                c.send(tar::header::headergen(iopath, tarpath, tarheader, format)?)?;
-                Ok(())
-            }, child_sender, None).unwrap();
-        });
+    if fs::is_standard_stream(&tarparams.outfile) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot append to an archive on standard output"));
     }
 
-    Ok(receiver)
+    let file = match std::fs::File::open(&tarparams.outfile) {
+        Ok(ref file) if !file.metadata()?.is_file() => return Ok(None),
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e)
+    };
+
+    let mut file = io::BufReader::new(file);
+
+    if decompress::detect(&decompress::read_magic(&mut file)?).is_some() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot append to or update a compressed archive"));
+    }
+
+    file.seek(io::SeekFrom::Start(0))?;
+
+    let mut reader = tar::reader::TarReader::new(file);
+
+    while reader.next_entry()?.is_some() {}
+
+    if !reader.is_terminated() {
+        warn!("Archive has no end-of-archive marker, appending after it's last member");
+    }
+
+    info!("Appending to archive at offset {}", reader.archive_end());
+
+    Ok(Some(reader.archive_end()))
 }
 
-/// Close a tar file.
+/// Open the sink named in the parameters for a given volume, passing it
+/// through whatever write filters we need.
 /// 
-/// This function takes ownership of the tarball sink, and thus drops it.
-fn close_tarball(tarball: Box<fs::ArchivalSink<tar::recovery::RecoveryEntry>>, tarresult: &mut TarResult) -> io::Result<()> {
-    tarresult.tarball_size += units::DataSize::from(1024);
+/// Volumes are numbered from 1, and are limited to the size the volume size
+/// schedule gives them.
+fn open_archive_sink(volume: usize, tarparams: &TarParameter, tarresult: &TarResult) -> io::Result<Box<fs::ArchivalSink<tar::recovery::RecoveryEntry>>> {
+    if tarparams.check_retention {
+        check_file_retention(&tarparams.outfile)?;
+    }
 
-    let mut tarball = tarball;
+    let limit = tarparams.spanning_size_limit.limit_for_volume(volume);
+    let tarball = match append_offset(volume, tarparams)? {
+        Some(offset) => fs::open_sink_at(&tarparams.outfile, offset, &tarparams.perf_tuning, limit, tarresult.status.cancel_token())?,
+        None => match tarresult.drives {
+            Some(ref drives) => drives.open_sink(&tarparams.outfile, volume > 1, &tarparams.perf_tuning, limit, tarresult.status.cancel_token())?,
+            None => open_sink(&tarparams.outfile, &tarparams.perf_tuning, limit, tarresult.status.cancel_token())?
+        }
+    };
+    let mut filters = filter::FilterChain::new();
 
-    tarball.write_all(&vec![0; 1024])?;
-    tarball.flush()?;
+    if let Some(ref chunker) = tarresult.chunker {
+        filters.push(match tarresult.hasher_pool {
+            Some(ref pool) => chunking::ChunkFilter::offloaded(chunker.clone(), pool),
+            None => chunking::ChunkFilter::new(chunker.clone())
+        });
+    }
 
-    Ok(())
+    //Chunks are found in the archive itself, not it's compressed form.
+    if let Some(codec) = tarparams.compression {
+        filters.push(codec.filter(&tarparams.perf_tuning)?);
+    }
+
+    Ok(filters.apply(tarball))
 }
 
-fn main() -> io::Result<()> {
-    //Here's some configuration!
-    let mut tarparams = TarParameter::from_proc_args();
-    let mut tarresult = TarResult::default();
+/// Open the manifest and start chunking, if the parameters ask for them.
+fn start_analysis(tarparams: &TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
+    if let Some(ref path) = tarparams.manifest {
+        let file = std::fs::File::create(path)?;
 
-    let parallel_io_pool = rayon::ThreadPoolBuilder::new().num_threads(tarparams.perf_tuning.parallel_io_limit).thread_name(|i| {
-        format!("I/O Thread {}", i)
-    }).build().unwrap();
-    
-    env::set_current_dir(tarparams.basepath.clone())?;
-    
-    match tarparams.operation {
-        None => Err(io::Error::new(io::ErrorKind::InvalidInput, "You must specify one of the Acdtrux options.")),
-        Some(TarOperation::Create) => {
-            let mut tarball = open_sink(&tarparams.outfile, &tarparams.perf_tuning, tarparams.spanning_size_limit)?;
-            let receiver : Receiver<tar::header::HeaderGenResult> = read_traverse(&parallel_io_pool, &tarparams)?;
+        tarresult.manifest = Some(Arc::new(Mutex::new(manifest::Manifest::new(io::BufWriter::new(file)))));
+    }
 
-            while tarresult.cancelled == false {
-                let mut last_error_entry = None;
+    if tarparams.chunk_stats {
+        tarresult.chunker = Some(Arc::new(Mutex::new(match tarresult.manifest {
+            Some(ref manifest) => {
+                let manifest = manifest.clone();
 
-                match serialize_proc(tarball.as_mut(), &receiver, &mut last_error_entry, &mut tarparams, &mut tarresult).err() {
-                    None => {
-                        close_tarball(tarball, &mut tarresult)?;
-                        break;
-                    },
-                    Some(ref e) if e.kind() == io::ErrorKind::WriteZero => {
-                        if tarparams.spanning { 
-                            tarball = match recover_proc(tarball, &mut tarparams, &mut tarresult) {
-                                Ok(tarball) => tarball,
-                                Err(e) => {
-                                    eprintln!("Got error when trying to open next volume: {:?}", e);
-                                    break;
-                                }
-                            }
-                        } else {
-                            eprintln!("Ran out of space archiving file {:?}", last_error_entry.unwrap().original_path);
-                            break;
-                        }
-                    },
-                    Some(e) => eprintln!("Error archiving file {:?}: {:?}", last_error_entry.unwrap().original_path, e)
-                }
-            }
-            
-            if tarparams.totals {
-                totals_cli(&tarresult);
-            }
+                chunking::Chunker::with_observer(move |chunk| manifest.lock().unwrap().write_chunk(chunk.offset, chunk.length, &chunk.digest))
+            },
+            None => chunking::Chunker::new()
+        })));
 
-            Ok(())
-        },
-        _ => {
-            eprintln!("Not implemented yet.");
-            Ok(())
+        if tarparams.perf_tuning.hasher_threads > 0 {
+            tarresult.hasher_pool = Some(Arc::new(hashing::HasherPool::new(tarparams.perf_tuning.hasher_threads)));
         }
     }
+
+    Ok(())
+}
+
+/// Finish chunking and flush the manifest, once the archive is complete.
+fn finish_analysis(tarresult: &mut TarResult) -> io::Result<()> {
+    if let Some(ref chunker) = tarresult.chunker {
+        chunker.lock().unwrap().finish()?;
+    }
+
+    if let Some(ref manifest) = tarresult.manifest {
+        manifest.lock().unwrap().flush()?;
+    }
+
+    Ok(())
+}
+
+/// Record the members of the current volume in the manifest.
+/// 
+/// Like catalogs, this should be done once it's known which members made it
+/// onto the volume. Members are recorded on the volume they start on.
+fn manifest_proc(tarresult: &mut TarResult) -> io::Result<()> {
+    let volume_number = tarresult.volume_count();
+    let start_offset = tarresult.current_volume().start_offset;
+
+    if let Some(manifest) = tarresult.manifest.clone() {
+        let mut manifest = manifest.lock().unwrap();
+
+        for entry in tarresult.current_volume().catalog.entries() {
+            manifest.write_member(volume_number, start_offset + entry.offset, entry.file_size, entry.file_type, &entry.path)?;
+
+            if let Some(ref target) = entry.link_target {
+                manifest.write_link(entry.file_type, &entry.path, target)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the catalog of the current volume into the catalog partition of the
+/// tape it was written to.
+/// 
+/// The volume itself is complete by the time we get here, so failing to write
+/// it's catalog is only worth a warning.
+fn catalog_proc<P>(tarball: &mut fs::ArchivalSink<P>, tarparams: &TarParameter, tarresult: &mut TarResult) {
+    let record_size = tarparams.perf_tuning.blocking_factor * 512;
+    let catalog = &tarresult.current_volume().catalog;
+
+    match tarball.downcast_tapedevice() {
+        Some(tape) => match tar::catalog::write_catalog_partition(tape, catalog, tarparams.format, record_size) {
+            Ok(()) => info!("Wrote catalog of {} members", catalog.entries().len()),
+            Err(e) => warn!("Could not write catalog partition: {}", e)
+        },
+        None => warn!("Catalogs can only be written to partitioned tapes")
+    }
+}
+
+/// Find the block a tape which lost it's position has to be put back to, from
+/// the records the torn sink committed.
+///
+/// This must be asked of the torn sink before it is closed. Repositioning is
+/// only possible if all committed data ends on a record boundary, which is
+/// always true of data written through a `BlockingWriter`.
+fn reposition_target(old_tarball: &fs::ArchivalSink<tar::recovery::RecoveryEntry>, tarparams: &TarParameter, tarresult: &mut TarResult) -> io::Result<u64> {
+    let record_size = (tarparams.perf_tuning.blocking_factor * 512) as u64;
+    let start_block = tarresult.current_volume().start_block.ok_or(io::Error::new(io::ErrorKind::Other, "Starting position of the volume is unknown"))?;
+
+    tar::recovery::reposition_block(old_tarball, start_block, record_size)
+}
+
+/// Reopen a tape device which lost it's position and reposition it to the end
+/// of the data it committed, at `target_block`.
+/// 
+/// The torn volume is ended with a filemark, and the returned sink is ready to
+/// have a continuation volume written onto the next tape file.
+fn reposition_proc(target_block: u64, tarparams: &mut TarParameter, tarresult: &mut TarResult) -> io::Result<Box<fs::ArchivalSink<tar::recovery::RecoveryEntry>>> {
+    let mut tarball = reopen_archive_sink(tarresult.volume_count() + 1, tarparams, tarresult)?;
+
+    {
+        let tape = tarball.downcast_tapedevice().ok_or(io::Error::new(io::ErrorKind::Other, "Reopened device is not a tape"))?;
+
+        tape.seek_blocks(io::SeekFrom::Start(target_block))?;
+        tape.write_filemark(true)?;
+    }
+
+    info!("Repositioned tape to block {} and ended torn volume", target_block);
+
+    Ok(tarball)
+}
+
+/// Open the sink named in the parameters again, for a given volume.
+/// 
+/// Drives tend to keep rejecting commands for a little while after a reset,
+/// or while the last sink opened on them is still shutting down, so reopening
+/// the device is given the same leeway we give writes.
+fn reopen_archive_sink(volume: usize, tarparams: &TarParameter, tarresult: &TarResult) -> io::Result<Box<fs::ArchivalSink<tar::recovery::RecoveryEntry>>> {
+    let policy = tarparams.perf_tuning.write_retry;
+    let mut attempt = 0;
+
+    loop {
+        match open_archive_sink(volume, tarparams, tarresult) {
+            Ok(tarball) => return Ok(tarball),
+            Err(ref e) if attempt < policy.max_retries => {
+                warn!("Could not reopen device, retrying: {}", e);
+                thread::sleep(policy.backoff_for(attempt));
+                attempt += 1;
+            },
+            Err(e) => return Err(e)
+        }
+    }
+}
+
+/// Remove a partly written member from the end of a volume that ran out of
+/// space, and end the volume there instead.
+/// 
+/// Every member before the torn one must have been committed, which is true
+/// when the volume filled up because of a size limit. Files are truncated to
+/// the start of the torn member. Tapes are repositioned to the record holding
+/// the start of the torn member, which is read back and rewritten up to that
+/// point; writing on a tape discards everything after it.
+/// 
+/// The volume is then closed as usual, which writes the end-of-archive marker.
+fn trim_torn_proc(tarball: Box<fs::ArchivalSink<tar::recovery::RecoveryEntry>>, tarparams: &TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
+    let mut tarball = tarball;
+    let offset = tarresult.current_volume().bytes;
+    let start_offset = tarresult.current_volume().start_offset;
+    let is_tape = tarball.downcast_tapedevice().is_some();
+
+    if !is_tape && tarball.downcast_seek().is_none() {
+        return Err(io::Error::new(io::ErrorKind::Other, "Archive cannot be rewound to remove the torn file"));
+    }
+
+    drop(tarball);
+
+    if !is_tape {
+        let mut file = std::fs::OpenOptions::new().write(true).open(&tarparams.outfile)?;
+
+        file.set_len(start_offset + offset)?;
+        file.seek(io::SeekFrom::Start(start_offset + offset))?;
+
+        return close_tarball(Box::new(file), tarparams, tarresult);
+    }
+
+    let record_size = (tarparams.perf_tuning.blocking_factor * 512) as u64;
+    let start_block = tarresult.current_volume().start_block.ok_or(io::Error::new(io::ErrorKind::Other, "Starting position of the volume is unknown"))?;
+    let target_block = start_block + offset / record_size;
+    let mut prefix = Vec::new();
+    let mut tarball = reopen_archive_sink(tarresult.volume_count(), tarparams, tarresult)?;
+
+    {
+        let tape = tarball.downcast_tapedevice().ok_or(io::Error::new(io::ErrorKind::Other, "Reopened device is not a tape"))?;
+
+        if offset % record_size != 0 {
+            tape.seek_blocks(io::SeekFrom::Start(target_block))?;
+            tape.read_block(&mut prefix)?;
+            prefix.truncate((offset % record_size) as usize);
+        }
+
+        tape.seek_blocks(io::SeekFrom::Start(target_block))?;
+    }
+
+    tarball.write_all(&prefix)?;
+
+    info!("Removed torn file from the end of the volume at block {}", target_block);
+
+    close_tarball(tarball, tarparams, tarresult)
+}
+
+/// Recover a partially-completed write operation.
+/// 
+/// CLI will be presented to the user to select a new volume to write to, and
+/// then a new `ArchivalSink` will be opened. Any data already presented, but
+/// not yet committed to the tarball, will be migrated to the new sink. As this
+/// process can also fail partially, we repeat this process until all data has
+/// been committed to any number of volumes, and then return the last sink used
+/// in the queue.
+/// 
+/// If `reposition` is set, the write failed because the tape device lost it's
+/// position rather than because it ran out of space. We first try to reopen
+/// the same tape and continue onto it (see `reposition_proc`) before falling
+/// back to asking for a new volume.
+fn recover_proc(old_tarball: Box<fs::ArchivalSink<tar::recovery::RecoveryEntry>>, reposition: bool, tarparams: &mut TarParameter, tarresult: &mut TarResult) -> io::Result<Box<fs::ArchivalSink<tar::recovery::RecoveryEntry>>> {
+    if tarresult.status.is_cancelled() {
+        Ok(old_tarball)
+    } else {
+        let mut pending = tar::recovery::PendingRecovery::collect(old_tarball.as_ref(), tarparams.format);
+        let lost_zones = pending.zones();
+        let mut ret = None;
+
+        //Data that didn't make it onto the old volume shouldn't count against
+        //it. Every member with a lost zone gets finished on the next volume;
+        //all but the one that actually failed (the last one) were already
+        //counted here, and the failed one only counts for what it committed.
+        //If the end-of-archive marker is the torn zone, every member before it
+        //was counted.
+        let torn_index = lost_zones.iter().rposition(|zone| zone.ident.is_some());
+        let torn_is_member = torn_index.map_or(false, |i| lost_zones[i].ident.as_ref().map_or(false, |ident| !ident.is_terminator));
+        let lost_bytes : u64 = lost_zones.iter().enumerate().filter(|(i, _)| Some(*i) != torn_index).map(|(_, zone)| zone.uncommitted_length).sum();
+        let torn_bytes = torn_index.map(|i| lost_zones[i].committed_length).unwrap_or(0);
+        let recovered_bytes = pending.estimated_size();
+        let recovered_members = pending.members();
+        let old_volume = tarresult.current_volume();
+
+        //Only members with data on both sides of the volume boundary count as
+        //split; the rest are moved onto the next volume whole.
+        for zone in lost_zones.iter().filter(|zone| zone.committed_length > 0) {
+            if let Some(ref ident) = zone.ident.as_ref().filter(|ident| !ident.is_terminator) {
+                //The member that failed to write never finished it's zone, so
+                //it's full length has to come from it's header.
+                let (path, bytes_remaining) = match ident.tar_header {
+                    Some(ref header) => (header.path.clone(), (ident.header_length + (header.file_size + 511) / 512 * 512).saturating_sub(zone.committed_length)),
+                    None => (ident.original_path.clone(), zone.uncommitted_length)
+                };
+
+                old_volume.splits.push(SplitMember {
+                    path: path,
+                    bytes_on_volume: zone.committed_length,
+                    bytes_remaining: bytes_remaining
+                });
+            }
+        }
+
+        old_volume.bytes = (old_volume.bytes + torn_bytes).saturating_sub(lost_bytes);
+        old_volume.members = old_volume.members.saturating_sub(match torn_is_member {
+            true => recovered_members.saturating_sub(1),
+            false => recovered_members
+        });
+        old_volume.catalog.truncate_at(old_volume.bytes);
+        manifest_proc(tarresult)?;
+
+        //Members recovered onto the next volume are only cataloged on the
+        //volume they started on.
+        let mut old_tarball = old_tarball;
+        let target_block = match reposition {
+            true => Some(reposition_target(old_tarball.as_ref(), tarparams, tarresult)),
+            false => None
+        };
+
+        if tarparams.catalog_partition && !reposition {
+            catalog_proc(old_tarball.deref_mut(), tarparams, tarresult);
+        }
+
+        if tarparams.perf_tuning.fsync == tuning::FsyncPolicy::PerVolume {
+            if let Err(e) = old_tarball.sync() {
+                warn!("Could not sync volume {} to disk: {}", tarresult.volume_count(), e);
+            }
+        }
+
+        drop(old_tarball);
+        
+        if tarparams.totals {
+            totals_cli(tarresult);
+        }
+
+        let mut next_tarball = None;
+
+        if let Some(target_block) = target_block {
+            match target_block.and_then(|target_block| reposition_proc(target_block, tarparams, tarresult)) {
+                Ok(tarball) => next_tarball = Some(tarball),
+                Err(e) => {
+                    error!("Could not reposition tape device: {}", e);
+
+                    if !tarparams.spanning {
+                        return Err(e);
+                    }
+                }
+            }
+        }
+
+        while !tarresult.status.is_cancelled() {
+            let mut tarball = match next_tarball.take() {
+                Some(tarball) => tarball,
+                None => {
+                    volume_exchange_cli(tarparams, tarresult)?;
+
+                    if tarresult.status.is_cancelled() {
+                        return Err(cancel::cancelled_error());
+                    }
+
+                    match open_archive_sink(tarresult.volume_count() + 1, tarparams, tarresult) {
+                        Ok(tarball) => tarball,
+                        Err(e) => {
+                            error!("Error trying to open new volume: {}", e);
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            if let Some(tape) = tarball.downcast_tapedevice() {
+                if let Err(e) = position_tape(tape, tarparams) {
+                    error!("{}", e);
+                    continue;
+                }
+            }
+
+            tarresult.begin_volume();
+            start_volume(tarball.deref_mut(), tarparams, tarresult)?;
+            tarresult.current_volume().bytes += recovered_bytes;
+            tarresult.current_volume().members += recovered_members;
+            tarresult.publish_volume();
+
+            label_proc(tarball.deref_mut(), pending.first_member(), tarparams, tarresult)?;
+            
+            match pending.resume_onto(tarball.deref_mut()) {
+                Ok(true) => {
+                    ret = Some(tarball);
+                    break
+                },
+                Ok(false) => {},
+                Err(e) => {
+                    error!("Unknown error recovering torn writes: {}", e);
+                    return Err(e);
+                }
+            }
+        }
+
+        ret.ok_or(io::Error::new(io::ErrorKind::Other, "Didn't exchange tarballs, even though we were supposed to."))
+    }
+}
+
+/// Determine if writes to the current volume should be tracked in data zones.
+/// 
+/// Data zones are needed to continue onto another volume, or to pick up where
+/// we left off if a tape drive loses it's position.
+fn tracks_zones(tarparams: &TarParameter, tarresult: &mut TarResult) -> bool {
+    tarparams.spanning || tarresult.current_volume().start_block.is_some()
+}
+
+/// Serialize the files from a traversal channel into the tarball.
+/// 
+/// # Write failures
+/// 
+/// In the event of a write failure, this function will report the failed entry
+/// for possible error recovery.
+fn serialize_proc(tarball: &mut fs::ArchivalSink<tar::recovery::RecoveryEntry>, receiver: &queue::BudgetedReceiver<tar::header::HeaderGenResult>, failed_entry: &mut Option<tar::header::HeaderGenResult>, tarparams: &mut TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
+    let track_zones = tracks_zones(tarparams, tarresult);
+    let cancel = tarresult.status.cancel_token().clone();
+
+    while let Ok(entry) = receiver.recv() {
+        if tarparams.verbosity > 0 {
+            //Archives written to standard output leave only standard error
+            //for the listing.
+            let name = tar::list::format_name(&entry.original_path, entry.tar_header.file_type);
+
+            match fs::is_standard_stream(&tarparams.outfile) {
+                true => eprintln!("{}", name),
+                false => println!("{}", name)
+            }
+        }
+
+        let entry = match tarparams.hard_dereference {
+            true => entry,
+            false => tarresult.hard_links.track(entry, tarparams.format)
+        };
+
+        if track_zones {
+            let header_length = entry.encoded_header.len() as u64;
+            tarball.begin_data_zone(tar::recovery::RecoveryEntry::new_from_headergen(&entry, header_length));
+        }
+
+        match tar::serialize(&entry, tarball, &mut tarresult.current_volume().bytes, tarparams.perf_tuning.entry_timeout, &cancel) {
+            Ok(member) => {
+                if member.voided {
+                    error!(path:? = entry.original_path; "Timed out reading file, it's member in the archive is filled with zeroes");
+                }
+
+                if tarparams.catalog_partition || tarparams.manifest.is_some() {
+                    tarresult.current_volume().catalog.push(tar::catalog::CatalogEntry {
+                        path: entry.tar_header.path.clone(),
+                        file_type: entry.tar_header.file_type,
+                        file_size: entry.tar_header.file_size,
+                        offset: member.offset,
+                        link_target: entry.tar_header.symlink_path.clone()
+                    });
+                }
+
+                tarresult.status.add_bytes(member.size);
+                tarresult.current_volume().members += 1;
+                tarresult.publish_volume();
+            },
+            Err(e) => {
+                *failed_entry = Some(entry);
+                return Err(e);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Build the chain of entry filters implied by our parameters.
+/// 
+/// Options which alter or exclude individual archive members should be
+/// implemented as filters here, rather than within the traversal itself.
+/// 
+/// `excludes` are patterns to exclude on top of those given with `--exclude`,
+/// such as those from a file list.
+fn entry_filters(tarparams: &TarParameter, excludes: &[String]) -> tar::filter::EntryFilterChain {
+    let mut filters = tar::filter::EntryFilterChain::new();
+
+    for pattern in tarparams.exclude.iter().chain(excludes.iter()) {
+        filters.push(tar::filter::ExcludePattern::new(pattern));
+    }
+
+    if let Some(ref prefix) = tarparams.prefix {
+        filters.push(tar::filter::PathPrefix::new(prefix));
+    }
+
+    if !tarparams.mode_override.is_empty() {
+        filters.push(tarparams.mode_override);
+    }
+
+    if tarparams.path_style != tar::PathStyle::default() {
+        filters.push(tarparams.path_style);
+    }
+
+    for tag in tarparams.exclude_tag.iter() {
+        filters.push(tar::filter::ExcludeTag::new(tag, tar::filter::TagScope::Contents));
+    }
+
+    for tag in tarparams.exclude_tag_under.iter() {
+        filters.push(tar::filter::ExcludeTag::new(tag, tar::filter::TagScope::Under));
+    }
+
+    for tag in tarparams.exclude_tag_all.iter() {
+        filters.push(tar::filter::ExcludeTag::new(tag, tar::filter::TagScope::All));
+    }
+
+    if !tarparams.ignore_nodump {
+        filters.push(|_: &mut tar::header::TarHeader, entry_path: &path::Path, metadata: &std::fs::Metadata| {
+            match fs::is_nodump(metadata, entry_path) {
+                true => {
+                    info!(path:? = entry_path; "Skipping no-dump file");
+                    tar::filter::EntryAction::Prune
+                },
+                false => tar::filter::EntryAction::Keep
+            }
+        });
+    }
+
+    filters.push(tar::apple::AppleMetadata::new(tarparams.apple_metadata));
+
+    if tarparams.project_ids {
+        filters.push(tar::filter::RecordProjectId);
+    }
+
+    if tarparams.acls {
+        filters.push(tar::acl::RecordAcls);
+    }
+
+    if tarparams.sparse {
+        filters.push(tar::sparse::DetectSparse);
+    }
+
+    filters
+}
+
+/// Gather up the paths to archive, from the command line and then any file
+/// list given with `-T`.
+fn traversal_items(tarparams: &TarParameter) -> io::Result<Vec<filelist::ListedPath>> {
+    let mut items : Vec<filelist::ListedPath> = tarparams.traversal_list.iter().map(|name| filelist::ListedPath {
+        path: path::PathBuf::from(name),
+        directory: None,
+        excludes: Vec::new()
+    }).collect();
+
+    match tarparams.files_from.as_ref() {
+        Some(list) if fs::is_standard_stream(list) => items.extend(filelist::parse_file_list(io::stdin().lock())?),
+        Some(list) => items.extend(filelist::parse_file_list(io::BufReader::new(std::fs::File::open(list)?))?),
+        None => {}
+    }
+
+    Ok(items)
+}
+
+/// Prepare a multithreaded directory traversal for reading files into a
+/// tarball.
+/// 
+/// This function returns a `Receiver` which can be used to retrieve all of the
+/// discovered directories.
+/// 
+/// If a snapshot is given, files within it's source are read from the
+/// snapshot instead, but archived under their original names. When updating
+/// an archive, files which haven't changed since they were archived are left
+/// out (see `start_update`).
+fn read_traverse(parallel_read_pool: &rayon::ThreadPool, tarparams: &TarParameter, snapshot: Option<&snapshot::Snapshot>, tarresult: &TarResult) -> io::Result<queue::BudgetedReceiver<tar::header::HeaderGenResult>> {
+    let cancel = tarresult.status.cancel_token();
+    //This is a bounded channel, which means that it's bounds form a
+    //rudimentary backpressure mechanism. Once too many files, or too much of
+    //their read-ahead data, are queued, the threads in the reading pool will
+    //block until the archive catches up.
+    let (sender, receiver) = queue::budgeted_channel(tarparams.perf_tuning.channel_queue_depth, tarparams.perf_tuning.queue_memory_limit);
+
+    //Every traversal shares one pool of owner and group names.
+    let names = intern::StringPool::new();
+
+    for item in traversal_items(tarparams)? {
+        let child_sender = sender.clone();
+        let mut filters = entry_filters(tarparams, &item.excludes);
+
+        if let Some(ref unchanged) = tarresult.unchanged {
+            let unchanged = unchanged.clone();
+
+            filters.push(move |header: &mut tar::header::TarHeader, entry_path: &path::Path, metadata: &std::fs::Metadata| tar::filter::EntryFilter::filter_entry(unchanged.as_ref(), header, entry_path, metadata));
+        }
+
+        let child_filters = Arc::new(filters);
+        let format = tarparams.format;
+        let atime_preserve = tarparams.atime_preserve;
+        let readahead_limit = tarparams.perf_tuning.readahead_limit;
+        let secure_metadata = tarparams.secure_metadata;
+        let skip_special = tarparams.skip_special;
+        let one_file_system = tarparams.one_file_system;
+        let appledouble = tarparams.apple_metadata == tar::apple::AppleMetadataPolicy::AppleDouble;
+        let child_cancel = cancel.clone();
+        let child_names = names.clone();
+        let archival_path = item.path.clone();
+        let io_path = match snapshot {
+            Some(snapshot) => snapshot.map_path(&item.source_path())?,
+            None => item.source_path()
+        };
+
+        parallel_read_pool.spawn(move || {
+            let header_cancel = child_cancel.clone();
+
+            match traverse::traverse(io_path, &move |iopath, tarpath, metadata, c: &queue::BudgetedSender<tar::header::HeaderGenResult>| {
+                if let Some(hg) = tar::filter::filtered_headergen(iopath, tarpath, metadata, format, atime_preserve, readahead_limit, secure_metadata, child_filters.as_ref(), &child_names, &header_cancel)? {
+                    if appledouble {
+                        match tar::apple::appledouble_headergen(&hg, format) {
+                            Ok(Some(companion)) => c.send(companion)?,
+                            Ok(None) => {},
+                            Err(e) => warn!(path:? = iopath; "Could not read macOS metadata: {}", e)
+                        }
+                    }
+
+                    c.send(hg)?;
+                }
+
+                Ok(())
+            }, child_sender, Some(archival_path), skip_special, one_file_system, &child_cancel) {
+                Ok(()) | Err(traverse::TraversalError::TraversalCancelled) | Err(traverse::TraversalError::Excluded) => {},
+                Err(traverse::TraversalError::IOError(e)) => error!("Error attempting to traverse path: {}", e)
+            }
+        });
+    }
+
+    Ok(receiver)
+}
+
+/// Write the end-of-archive marker and flush it out to the volume.
+/// 
+/// The marker is written in a data zone of it's own, so that if the volume
+/// fills up or loses it's position before the marker is committed, it can be
+/// recovered onto the next volume like any other data. It's only counted once
+/// it's been committed.
+fn terminate_tarball(tarball: &mut fs::ArchivalSink<tar::recovery::RecoveryEntry>, tarparams: &TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
+    let track_zones = tracks_zones(tarparams, tarresult);
+
+    if track_zones {
+        tarball.begin_data_zone(tar::recovery::RecoveryEntry::terminator());
+    }
+
+    tarball.write_all(&[0; tar::recovery::TERMINATOR_SIZE as usize])?;
+
+    if track_zones {
+        tarball.end_data_zone();
+    }
+
+    tarball.flush()?;
+
+    tarresult.status.add_bytes(tar::recovery::TERMINATOR_SIZE);
+    tarresult.current_volume().bytes += tar::recovery::TERMINATOR_SIZE;
+    tarresult.publish_volume();
+
+    Ok(())
+}
+
+/// Close a tar file.
+/// 
+/// This function takes ownership of the tarball sink, and thus drops it.
+fn close_tarball(tarball: Box<fs::ArchivalSink<tar::recovery::RecoveryEntry>>, tarparams: &TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
+    let mut tarball = tarball;
+
+    terminate_tarball(tarball.deref_mut(), tarparams, tarresult)?;
+    finish_tarball(tarball, tarparams, tarresult)
+}
+
+/// Finish off a tar file whose end-of-archive marker has been written.
+/// 
+/// This function takes ownership of the tarball sink, and thus drops it.
+fn finish_tarball(tarball: Box<fs::ArchivalSink<tar::recovery::RecoveryEntry>>, tarparams: &TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
+    let mut tarball = tarball;
+
+    if tarparams.perf_tuning.fsync != tuning::FsyncPolicy::None || tarparams.atomic {
+        tarball.sync()?;
+    }
+
+    manifest_proc(tarresult)?;
+
+    if tarparams.catalog_partition {
+        catalog_proc(tarball.deref_mut(), tarparams, tarresult);
+    }
+
+    Ok(())
+}
+
+/// Open the archive named in the parameters for reading, positioned at the
+/// start of the requested archive and decompressed if it needs to be.
+fn open_archive_source(tarparams: &TarParameter) -> io::Result<Box<dyn fs::ArchivalSource>> {
+    let mut source = open_source(&tarparams.outfile)?;
+
+    fs::seek_archive_file(source.as_mut(), tarparams.file_number)?;
+
+    decompress::decompress_source(source)
+}
+
+/// Produces CLI to prompt a user to insert the next volume of an archive being
+/// read.
+///
+/// The user may name a different file to read the volume from, or cancel the
+/// operation outright, in which case an `Interrupted` error is yielded.
+fn volume_insert_cli(outfile: &mut String, volume: usize) -> io::Result<()> {
+    eprintln!("Insert volume {} of the archive and press enter when ready (or ? for more options)...", volume);
+
+    loop {
+        let mut response = String::new();
+
+        if io::stdin().read_line(&mut response)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Volume {} was never inserted", volume)));
+        }
+
+        match response.trim_end().chars().next() {
+            Some('?') => {
+                eprintln!("Valid options are:");
+                eprintln!("? - Read this description");
+                eprintln!("q - Cancel the operation");
+                eprintln!("n (filename) - Read from a new file");
+                eprintln!("y - Reopen the file and read the next volume");
+            },
+            Some('q') => return Err(io::Error::new(io::ErrorKind::Interrupted, "Cancelled while waiting for the next volume")),
+            Some('y') | None => return Ok(()),
+            Some('n') if response.trim_end().len() > 2 => {
+                *outfile = String::from(response[2..].trim());
+                return Ok(());
+            },
+            _ => eprintln!("Please enter a valid response.")
+        }
+    }
+}
+
+/// Produces CLI to confirm restoring the setuid, setgid, or sticky bits of a
+/// member extracted with `-p`.
+///
+/// Yields whether to restore them. Answering `a` restores them on every member
+/// from then on. Without anyone to ask, they are not restored.
+fn special_bits_cli(header: &tar::header::TarHeader, restore_all: &mut bool) -> io::Result<bool> {
+    if *restore_all {
+        return Ok(true);
+    }
+
+    eprintln!("{} would be extracted as {}, with setuid, setgid, or sticky bits.", tar::list::quote_name(&header.path.to_string_lossy()), tar::list::mode_string(header.file_type, header.unix_mode));
+    eprintln!("Restore them? (y/N, or a for every member)");
+
+    let mut response = String::new();
+
+    if io::stdin().read_line(&mut response)? == 0 {
+        return Ok(false);
+    }
+
+    match response.trim() {
+        "y" | "Y" | "yes" => Ok(true),
+        "a" | "A" => {
+            *restore_all = true;
+            Ok(true)
+        },
+        _ => Ok(false)
+    }
+}
+
+/// Reads the members of the archive named in the parameters, crossing onto
+/// each further volume in turn with `-M`.
+///
+/// A volume that ends without an end-of-archive marker is taken to continue
+/// on the next volume, which the user is asked to insert. Every volume must
+/// carry a volume header, as pax volumes written by rapidtar do; volumes from
+/// other archives, or out of order, are refused and the right one asked for.
+struct VolumeReader<'a> {
+    tarparams: &'a TarParameter,
+    outfile: String,
+    reader: tar::reader::TarReader<Box<dyn fs::ArchivalSource>>,
+    sequence: tar::label::VolumeSequence,
+    checked: bool,
+    continued: Option<u64>
+}
+
+impl<'a> VolumeReader<'a> {
+    fn open(tarparams: &'a TarParameter) -> io::Result<VolumeReader<'a>> {
+        let mut reader = tar::reader::TarReader::new(open_archive_source(tarparams)?);
+
+        if tarparams.spanning {
+            reader.allow_torn_end();
+        }
+
+        Ok(VolumeReader {
+            tarparams: tarparams,
+            outfile: tarparams.outfile.clone(),
+            reader: reader,
+            sequence: tar::label::VolumeSequence::new(),
+            checked: false,
+            continued: None
+        })
+    }
+
+    /// Read the next member, moving onto the next volume if this one has run
+    /// out.
+    fn next_entry(&mut self) -> io::Result<Option<tar::reader::TarEntry>> {
+        loop {
+            let entry = self.reader.next_entry()?;
+
+            self.continued = None;
+
+            if self.tarparams.spanning && !self.checked {
+                self.checked = true;
+
+                let header = match self.reader.volume_header() {
+                    Some(header) => header.clone(),
+                    None => {
+                        error!("Volume has no volume header, so it can't be read as part of a multi-volume archive");
+                        self.next_volume()?;
+                        continue;
+                    }
+                };
+
+                if let Err(e) = self.sequence.accept(&header) {
+                    error!("{}", e);
+                    self.next_volume()?;
+                    continue;
+                }
+
+                if let (Some(entry), true) = (entry.as_ref(), header.sequence > 1) {
+                    self.continued = match tar::label::continuation_from_attributes(self.reader.global_attributes()) {
+                        Some((ref path, offset)) if path == entry.header.path.as_ref() => Some(offset),
+                        _ => None
+                    };
+                }
+            }
+
+            match entry {
+                None if self.tarparams.spanning && !self.reader.is_terminated() => self.next_volume()?,
+                entry => return Ok(entry)
+            }
+        }
+    }
+
+    /// Ask for the next volume, and start reading it.
+    fn next_volume(&mut self) -> io::Result<()> {
+        volume_insert_cli(&mut self.outfile, self.sequence.expected())?;
+
+        self.reader = tar::reader::TarReader::new(decompress::decompress_source(open_source(&self.outfile)?)?);
+        self.reader.allow_torn_end();
+        self.checked = false;
+
+        Ok(())
+    }
+
+    /// Where in it's data the member last read picks up, if it was continued
+    /// from the previous volume.
+    fn continued(&self) -> Option<u64> {
+        self.continued
+    }
+
+    fn is_terminated(&self) -> bool {
+        self.reader.is_terminated()
+    }
+}
+
+/// Reading a `VolumeReader` reads the data of the current member.
+impl<'a> io::Read for VolumeReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+/// Extract every member of the archive into the current directory.
+///
+/// Members are kept beneath the current directory, whatever links the archive
+/// plants along the way. Members which can't be extracted are reported, but
+/// don't stop the rest of the archive from being extracted; the operation as
+/// a whole fails afterwards.
+///
+/// With `-p`, setuid, setgid, and sticky bits are only restored once the user
+/// confirms them, member by member.
+fn extract_proc(tarparams: &TarParameter) -> io::Result<()> {
+    let mut reader = VolumeReader::open(tarparams)?;
+    let mut extractor = tar::extract::Extractor::new(&env::current_dir()?, tarparams.permissions, tarparams.unc_restore, tarparams.path_style);
+    let mut restore_all = false;
+    let mut failures = 0;
+
+    while let Some(mut entry) = reader.next_entry()? {
+        if tarparams.verbosity > 0 {
+            println!("{}", tar::list::quote_name(&entry.header.path.to_string_lossy()));
+        }
+
+        //Archives read from standard input leave nobody to ask.
+        if tarparams.permissions.needs_confirmation(entry.header.unix_mode) && (fs::is_standard_stream(&tarparams.outfile) || !special_bits_cli(&entry.header, &mut restore_all)?) {
+            warn!(path:? = entry.header.path; "Not restoring setuid, setgid, or sticky bits");
+            entry.header.unix_mode &= !tar::permissions::SPECIAL_BITS;
+        }
+
+        let extracted = match reader.continued() {
+            Some(offset) => extractor.extract_continuation(&entry.header, offset, &mut reader),
+            None => extractor.extract(&entry.header, &mut reader)
+        };
+
+        if let Err(e) = extracted {
+            error!(path:? = entry.header.path; "Could not extract member: {}", e);
+            failures += 1;
+        }
+    }
+
+    if !reader.is_terminated() {
+        warn!("Archive has no end-of-archive marker, and may have been cut short");
+    }
+
+    if let Err(e) = extractor.finish() {
+        error!("Could not restore permissions and times of extracted directories: {}", e);
+        failures += 1;
+    }
+
+    match failures {
+        0 => Ok(()),
+        failures => Err(io::Error::new(io::ErrorKind::Other, format!("{} members could not be extracted", failures)))
+    }
+}
+
+/// Pipe each file in the archive to the command given with `--to-command`.
+///
+/// Commands which fail are reported, but don't stop the rest of the archive
+/// from being piped; the operation as a whole fails afterwards.
+fn to_command_proc(tarparams: &TarParameter) -> io::Result<()> {
+    let command = tarparams.to_command.as_ref().map(|c| c.as_str()).unwrap_or("");
+    let mut reader = tar::reader::TarReader::new(open_archive_source(tarparams)?);
+    let renames = match tarparams.rename_map {
+        Some(ref map_file) => tar::rename::RenameMap::load(map_file)?,
+        None => tar::rename::RenameMap::new()
+    };
+    let mut failures = 0;
+
+    while let Some(mut entry) = reader.next_entry()? {
+        if entry.header.file_type != tar::header::TarFileType::FileStream {
+            continue;
+        }
+
+        if let Some(renamed) = renames.rename(&entry.header.path) {
+            entry.header.path = Box::new(renamed);
+        }
+
+        if tarparams.verbosity > 0 {
+            println!("{}", tar::list::format_name(&entry.header.path, entry.header.file_type));
+        }
+
+        let context = tar::command::ArchiveContext {
+            archive: tarparams.outfile.clone(),
+            volume: reader.volume_header().map(|header| header.sequence).unwrap_or(1),
+            blocking_factor: tarparams.perf_tuning.blocking_factor,
+            format: tarparams.format
+        };
+
+        match tar::command::pipe_member(command, &entry, &mut reader, &context) {
+            Ok(status) if status.success() => {},
+            Ok(status) => {
+                error!(path:? = entry.header.path; "Command failed with {}", status);
+                failures += 1;
+            },
+            Err(e) => {
+                error!(path:? = entry.header.path; "Could not run command: {}", e);
+                failures += 1;
+            }
+        }
+    }
+
+    if !reader.is_terminated() {
+        warn!("Archive has no end-of-archive marker, and may have been cut short");
+    }
+
+    match failures {
+        0 => Ok(()),
+        failures => Err(io::Error::new(io::ErrorKind::Other, format!("Command failed for {} members", failures)))
+    }
+}
+
+/// Join the archives named on the command line onto the end of ours, as
+/// `tar -A` does.
+///
+/// Each archive's members are copied verbatim, and only the joined archive
+/// gets an end-of-archive marker. Archives on tape are read from wherever the
+/// tape is positioned.
+fn join_proc(tarparams: &TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
+    if tarparams.traversal_list.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Name the archives to join onto the end of the archive"));
+    }
+
+    if tarparams.spanning {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Archives can't be joined across multiple volumes"));
+    }
+
+    if tarparams.traversal_list.contains(&tarparams.outfile) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot join an archive onto itself"));
+    }
+
+    let mut tarball = open_archive_sink(1, tarparams, tarresult)?;
+
+    if let Some(tape) = tarball.downcast_tapedevice() {
+        position_tape(tape, tarparams)?;
+    }
+
+    start_volume(tarball.deref_mut(), tarparams, tarresult)?;
+
+    for name in tarparams.traversal_list.iter() {
+        let copied = tar::join::copy_members(decompress::decompress_source(open_source(name)?)?, tarball.deref_mut())?;
+
+        if !copied.terminated {
+            warn!(path:? = name; "Archive has no end-of-archive marker, and may have been cut short");
+        }
+
+        info!(path:? = name; "Joined {} members", copied.members);
+
+        tarresult.status.add_bytes(copied.bytes);
+        tarresult.current_volume().bytes += copied.bytes;
+    }
+
+    close_tarball(tarball, tarparams, tarresult)
+}
+
+/// Rewrite the archives named on the command line into a new archive, as
+/// `--recode` does.
+///
+/// Members are read from each archive in turn, decompressing them if need
+/// be, and written out in the format, compression, and blocking asked for,
+/// without ever touching the filesystem. Members which can't be written in the
+/// new format are reported and left out, and fail the operation once the rest
+/// have been recoded.
+fn recode_proc(tarparams: &TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
+    if tarparams.traversal_list.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Name the archives to recode"));
+    }
+
+    if tarparams.spanning {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Archives can't be recoded across multiple volumes"));
+    }
+
+    if tarparams.traversal_list.contains(&tarparams.outfile) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot recode an archive onto itself"));
+    }
+
+    let mut tarball = open_archive_sink(1, tarparams, tarresult)?;
+    let mut failed = 0;
+
+    if let Some(tape) = tarball.downcast_tapedevice() {
+        position_tape(tape, tarparams)?;
+    }
+
+    start_volume(tarball.deref_mut(), tarparams, tarresult)?;
+
+    for name in tarparams.traversal_list.iter() {
+        let recoded = tar::recode::recode_members(decompress::decompress_source(open_source(name)?)?, tarball.deref_mut(), tarparams.format)?;
+
+        if !recoded.terminated {
+            warn!(path:? = name; "Archive has no end-of-archive marker, and may have been cut short");
+        }
+
+        info!(path:? = name; "Recoded {} members", recoded.members);
+
+        failed += recoded.failed;
+        tarresult.status.add_bytes(recoded.bytes);
+        tarresult.current_volume().bytes += recoded.bytes;
+    }
+
+    close_tarball(tarball, tarparams, tarresult)?;
+
+    match failed {
+        0 => Ok(()),
+        failed => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} members could not be written in the new format", failed)))
+    }
+}
+
+/// List the members of the archive, as `tar -t` does.
+///
+/// If any names were given, only those members, and anything beneath them,
+/// are listed. Names which match nothing in the archive are reported, and
+/// fail the operation once the listing is complete.
+fn list_proc(tarparams: &TarParameter) -> io::Result<()> {
+    if tarparams.catalog_partition {
+        return list_catalog_proc(tarparams);
+    }
+
+    let mut reader = VolumeReader::open(tarparams)?;
+    let mut lister = tar::list::Lister::new(tarparams.verbosity > 0);
+    let names = listed_names(tarparams);
+    let mut found = vec![false; names.len()];
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    while let Some(entry) = reader.next_entry()? {
+        //Members continued from the previous volume were listed there.
+        if reader.continued().is_some() || !match_listed_name(&names, &mut found, &entry.header.path) {
+            continue;
+        }
+
+        writeln!(out, "{}", lister.format(&entry.header))?;
+
+        if tarparams.show_pax && tarparams.verbosity > 0 {
+            for (key, value) in entry.unknown_attributes.iter() {
+                writeln!(out, "  {}={}", tar::list::quote_name(key), tar::list::quote_name(value))?;
+            }
+        }
+    }
+
+    if !reader.is_terminated() {
+        warn!("Archive has no end-of-archive marker, and may have been cut short");
+    }
+
+    report_unlisted_names(&names, &found)
+}
+
+/// List the members of a partitioned tape from it's catalog partition,
+/// without reading the archive itself.
+///
+/// The catalog only records each member's name, type, and size, so this only
+/// produces the short listing.
+fn list_catalog_proc(tarparams: &TarParameter) -> io::Result<()> {
+    if tarparams.verbosity > 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "-v cannot be combined with --catalog-partition when listing, as the catalog does not record ownership, permissions, or times"));
+    }
+
+    let mut source = open_source(&tarparams.outfile)?;
+    let catalog = match source.downcast_tapedevice() {
+        Some(tape) => tar::catalog::read_catalog_partition(tape)?,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "--catalog-partition can only be listed from a tape"))
+    };
+    let names = listed_names(tarparams);
+    let mut found = vec![false; names.len()];
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    for entry in catalog.entries() {
+        if match_listed_name(&names, &mut found, &entry.path) {
+            writeln!(out, "{}", tar::list::format_name(&entry.path, entry.file_type))?;
+        }
+    }
+
+    report_unlisted_names(&names, &found)
+}
+
+/// The member names given to list, without trailing slashes.
+fn listed_names(tarparams: &TarParameter) -> Vec<path::PathBuf> {
+    tarparams.traversal_list.iter().map(|name| path::PathBuf::from(name.trim_end_matches('/'))).collect()
+}
+
+/// Check whether a member should be listed, marking every name it matched as
+/// found. Everything is listed when no names were given.
+fn match_listed_name(names: &[path::PathBuf], found: &mut [bool], member: &path::Path) -> bool {
+    if names.is_empty() {
+        return true;
+    }
+
+    let mut matched = false;
+
+    for (name, found) in names.iter().zip(found.iter_mut()) {
+        if member.starts_with(name) {
+            *found = true;
+            matched = true;
+        }
+    }
+
+    matched
+}
+
+/// Report any names to list which matched nothing, failing if there were any.
+fn report_unlisted_names(names: &[path::PathBuf], found: &[bool]) -> io::Result<()> {
+    let missing : Vec<&path::PathBuf> = names.iter().zip(found.iter()).filter(|(_, found)| !**found).map(|(name, _)| name).collect();
+
+    for name in missing.iter() {
+        error!(path:? = name; "Not found in archive");
+    }
+
+    match missing.len() {
+        0 => Ok(()),
+        missing => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} of the given names were not found in the archive", missing)))
+    }
+}
+
+/// Dump the raw headers of the first few members of the archive.
+///
+/// If any names were given, only members with those names, or beneath them,
+/// are dumped and counted.
+fn dump_header_proc(tarparams: &TarParameter) -> io::Result<()> {
+    let mut dumper = tar::dump::HeaderDumper::new(open_archive_source(tarparams)?);
+    let names : Vec<path::PathBuf> = tarparams.traversal_list.iter().map(|name| path::PathBuf::from(name.trim_end_matches('/'))).collect();
+    let mut dumped = 0;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+
+    while tarparams.dump_count == 0 || dumped < tarparams.dump_count {
+        let member = match dumper.next_member()? {
+            Some(member) => member,
+            None => break
+        };
+
+        if !names.is_empty() && !names.iter().any(|name| member.path.starts_with(name)) {
+            continue;
+        }
+
+        if dumped > 0 {
+            writeln!(out)?;
+        }
+
+        write!(out, "{}", member)?;
+        dumped += 1;
+    }
+
+    if dumped == 0 && !names.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "None of the given names were found in the archive"));
+    }
+
+    Ok(())
+}
+
+/// Compare the members of an archive with the filesystem, as `tar -d` does.
+///
+/// Differences are reported in GNU tar's style, and also as JSON if asked
+/// for. Members which can't be compared are reported as errors. Either way,
+/// the rest of the archive is still compared, and the operation as a whole
+/// fails afterwards.
+///
+/// With `--verify-restore`, the comparison also goes into a restore report,
+/// which notes files the archive doesn't account for as well.
+fn compare_proc(tarparams: &TarParameter) -> io::Result<()> {
+    let mut reader = tar::reader::TarReader::new(open_archive_source(tarparams)?);
+    let options = tar::compare::CompareOptions {
+        content: match tarparams.verify_restore {
+            Some(_) => Some(tar::compare::ContentDigest::Sha256),
+            None => tarparams.compare_content
+        }
+    };
+    let mut report = match tarparams.verify_restore {
+        Some(_) => Some(tar::verify::RestoreReport::new(&tarparams.outfile, &env::current_dir()?)),
+        None => None
+    };
+    let mut json : Option<Box<dyn Write>> = match tarparams.diff_json.as_ref().map(|s| s.as_str()) {
+        Some("-") => Some(Box::new(io::stdout())),
+        Some(path) => Some(Box::new(io::BufWriter::new(std::fs::File::create(path)?))),
+        None => None
+    };
+    let mut differing = 0;
+
+    while let Some(entry) = reader.next_entry()? {
+        if tarparams.verbosity > 0 {
+            println!("{}", tar::list::quote_name(&entry.header.path.to_string_lossy()));
+        }
+
+        //The archive's root directory, as archived from `.`, is restored to
+        //no path at all.
+        let disk_path = match tar::restored_path(&entry.header.path, tarparams.unc_restore, tarparams.path_style) {
+            ref disk_path if disk_path.as_os_str().is_empty() => path::PathBuf::from("."),
+            disk_path => disk_path
+        };
+        let differences = match tar::compare::compare_member(&entry.header, &mut reader, &disk_path, &options) {
+            Ok(differences) => differences,
+            Err(e) => {
+                error!(path:? = entry.header.path; "Could not compare member: {}", e);
+
+                if let Some(ref mut report) = report {
+                    report.add_failure(&entry.header, &e);
+                }
+
+                differing += 1;
+                continue;
+            }
+        };
+
+        if !differences.is_empty() {
+            differing += 1;
+        }
+
+        if let Some(ref mut report) = report {
+            report.add_member(&entry.header, differences.clone());
+        }
+
+        for difference in differences {
+            let record = tar::compare::DifferenceRecord { path: entry.header.path.clone(), difference: difference };
+
+            println!("{}", record);
+
+            if let Some(ref mut json) = json {
+                record.write_json(json)?;
+            }
+        }
+    }
+
+    if !reader.is_terminated() {
+        warn!("Archive has no end-of-archive marker, and may have been cut short");
+
+        if let Some(ref mut report) = report {
+            report.add_truncation();
+        }
+    }
+
+    if let Some(ref mut json) = json {
+        json.flush()?;
+    }
+
+    if let (Some(mut report), Some(report_path)) = (report, tarparams.verify_restore.as_ref()) {
+        //Neither the archive nor the report came out of the archive, if
+        //either happens to live in the restored tree.
+        report.find_unexpected(&[path::PathBuf::from(&tarparams.outfile), path::PathBuf::from(report_path)])?;
+
+        for path in report.unexpected() {
+            println!("{}: Not in archive", tar::list::quote_name(&path.to_string_lossy()));
+        }
+
+        match report_path.as_str() {
+            "-" => report.write(&mut io::stdout())?,
+            report_path => {
+                let mut out = io::BufWriter::new(std::fs::File::create(report_path)?);
+
+                report.write(&mut out)?;
+                out.flush()?;
+            }
+        }
+
+        if !report.is_clean() {
+            return Err(io::Error::new(io::ErrorKind::Other, format!("Restored tree does not match the archive: {} members differ, {} files unexpected", report.differing(), report.unexpected().len())));
+        }
+    }
+
+    match differing {
+        0 => Ok(()),
+        differing => Err(io::Error::new(io::ErrorKind::Other, format!("{} members differ from the filesystem", differing)))
+    }
+}
+
+/// Check that compression was only asked for where it can be done.
+///
+/// Compressed volumes can span, as each one ends on a complete compressed
+/// member, but a torn volume can't be trimmed back to a member boundary
+/// without decompressing it. Tape catalogs, LTFS, and stream manifests all
+/// record offsets within the uncompressed archive, which readers wouldn't be
+/// able to seek to.
+fn check_compression(tarparams: &TarParameter) -> io::Result<()> {
+    let codec = match tarparams.compression {
+        Some(codec) => codec,
+        None => return Ok(())
+    };
+
+    match tarparams.operation {
+        Some(TarOperation::Create) | Some(TarOperation::Recode) => {},
+        Some(TarOperation::Append) | Some(TarOperation::Update) | Some(TarOperation::Join) => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Cannot append to or update a compressed archive")),
+        //Compression is recognized by itself when reading.
+        _ => return Ok(())
+    }
+
+    if tarparams.trim_torn || tarparams.ltfs || tarparams.catalog_partition || !tarparams.streams.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "-z, -J, and --zstd cannot be combined with --trim-torn, --ltfs, --catalog-partition, or --stream"));
+    }
+
+    //Fail now, rather than once the archive is being written, if the codec
+    //isn't available.
+    codec.filter(&tarparams.perf_tuning).map(|_| ())
+}
+
+/// Redirect a new archive into a `.partial` file beside the one asked for, if
+/// `--atomic` was given.
+///
+/// Yields the name the archive is to be renamed to once it's complete. Only
+/// single-volume archives written to regular files can be created this way;
+/// devices can't be renamed, and appending to an archive already changes it in
+/// place.
+fn start_atomic(tarparams: &mut TarParameter) -> io::Result<Option<String>> {
+    if !tarparams.atomic {
+        return Ok(None);
+    }
+
+    match tarparams.operation {
+        Some(TarOperation::Create) => {},
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "--atomic can only be used to create new archives"))
+    }
+
+    if tarparams.spanning || tarparams.ltfs || tarparams.catalog_partition || !tarparams.streams.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--atomic cannot be combined with -M, --ltfs, --catalog-partition, or --stream"));
+    }
+
+    if fs::is_standard_stream(&tarparams.outfile) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--atomic cannot write to standard output"));
+    }
+
+    match std::fs::metadata(&tarparams.outfile) {
+        Ok(ref metadata) if !metadata.is_file() => return Err(io::Error::new(io::ErrorKind::InvalidInput, "--atomic can only write archives to regular files")),
+        Ok(_) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e)
+    }
+
+    if tarparams.check_retention {
+        check_file_retention(&tarparams.outfile)?;
+    }
+
+    let target = tarparams.outfile.clone();
+
+    tarparams.outfile = format!("{}.partial", target);
+
+    Ok(Some(target))
+}
+
+/// Rename a completed `--atomic` archive into place.
+fn commit_atomic(tarparams: &mut TarParameter, target: String) -> io::Result<()> {
+    std::fs::rename(&tarparams.outfile, &target)?;
+
+    info!("Renamed {} to {}", tarparams.outfile, target);
+    tarparams.outfile = target;
+
+    Ok(())
+}
+
+/// The volume selected for a backup by `start_rotation`, along with what's
+/// needed to record it's use.
+struct RotationUse {
+    selection: rotation::Selection,
+    usage: rotation::UsageCatalog,
+    day: i64,
+}
+
+/// Pick the volume to write to, if following a rotation, and have it loaded.
+///
+/// The load command loads the volume, if there is one. Otherwise the user is
+/// asked to load it, and we wait until they have.
+fn start_rotation(tarparams: &TarParameter) -> io::Result<Option<RotationUse>> {
+    if !tarparams.rotation.is_enabled() {
+        return Ok(None);
+    }
+
+    if !tarparams.streams.is_empty() {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--rotation cannot be combined with --stream"));
+    }
+
+    let day = rotation::day_number(time::SystemTime::now());
+    let (selection, usage) = tarparams.rotation.select(day)?;
+
+    info!(slot = selection.slot, volume = selection.volume.as_str(), set = selection.set.as_str(), first_use = selection.first_use; "Selected volume for tonight's media set");
+
+    if !tarparams.rotation.load(&selection)? {
+        eprintln!("Load volume {} from slot {} (media set {}) into {} and press enter when ready...", selection.volume, selection.slot, selection.set, tarparams.outfile);
+
+        let mut response = String::new();
+
+        if io::stdin().read_line(&mut response)? == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("Volume {} was never loaded", selection.volume)));
+        }
+    }
+
+    Ok(Some(RotationUse {
+        selection: selection,
+        usage: usage,
+        day: day
+    }))
+}
+
+/// Record that the volume selected by `start_rotation` has been written to,
+/// once the archive is complete.
+fn finish_rotation(tarparams: &TarParameter, rotation_use: Option<RotationUse>) -> io::Result<()> {
+    match rotation_use {
+        Some(RotationUse { selection, usage, day }) => {
+            tarparams.rotation.record_use(&selection, usage, day)?;
+
+            info!(volume = selection.volume.as_str(), set = selection.set.as_str(); "Recorded use of volume");
+            Ok(())
+        },
+        None => Ok(())
+    }
+}
+
+/// Note the members of the archive we're updating, if we are.
+///
+/// This has to happen before the archive is opened for writing, since tapes
+/// can't be opened twice. Archives which don't exist yet have no members.
+fn start_update(tarparams: &TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
+    match tarparams.operation {
+        Some(TarOperation::Update) => {},
+        _ => return Ok(())
+    }
+
+    let mut unchanged = tar::filter::SkipUnchanged::new();
+
+    match open_archive_source(tarparams) {
+        Ok(source) => {
+            let mut reader = tar::reader::TarReader::new(source);
+
+            while let Some(entry) = reader.next_entry()? {
+                unchanged.add_member(&entry.header);
+            }
+        },
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e)
+    }
+
+    info!("Archive already holds {} paths", unchanged.len());
+
+    tarresult.unchanged = Some(Arc::new(unchanged));
+
+    Ok(())
+}
+
+/// Snapshot the source directory, if the parameters ask for it.
+fn take_snapshot(tarparams: &TarParameter) -> io::Result<Option<snapshot::Snapshot>> {
+    match tarparams.snapshot.is_enabled() {
+        true => Ok(Some(snapshot::Snapshot::take(&tarparams.snapshot_source, &tarparams.snapshot)?)),
+        false => Ok(None)
+    }
+}
+
+/// Clean up after a snapshot once archival has finished.
+fn release_snapshot(snapshot: Option<snapshot::Snapshot>) -> io::Result<()> {
+    match snapshot {
+        Some(snapshot) => snapshot.release(),
+        None => Ok(())
+    }
+}
+
+/// Write the traversed files across several archives at once.
+///
+/// The archive named with `-f` is the first stream, followed by each given
+/// with `--stream`. Members are recorded in the manifest by stream, rather
+/// than by volume.
+fn streams_proc(receiver: &queue::BudgetedReceiver<tar::header::HeaderGenResult>, tarparams: &TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
+    if tarparams.spanning || tarparams.catalog_partition || tarparams.ltfs || tarparams.chunk_stats {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "--stream cannot be combined with -M, --catalog-partition, --ltfs, or --chunk-stats"));
+    }
+
+    let mut sinks : Vec<Box<fs::ArchivalSink<tar::recovery::RecoveryEntry>>> = Vec::new();
+
+    for device in Some(&tarparams.outfile).into_iter().chain(tarparams.streams.iter()) {
+        if tarparams.check_retention {
+            check_file_retention(device)?;
+        }
+
+        let mut sink = open_sink(device, &tarparams.perf_tuning, None, tarresult.status.cancel_token())?;
+
+        if let Some(tape) = sink.downcast_tapedevice() {
+            if let Some(policy) = tarparams.end_marks {
+                tape.set_filemark_policy(policy)?;
+            }
+
+            position_tape(tape, tarparams)?;
+        }
+
+        sinks.push(sink);
+    }
+
+    let results = multistream::write_streams(sinks, receiver, &tarparams.perf_tuning, tarresult.status.cancel_token())?;
+
+    for result in results.iter() {
+        tarresult.status.add_bytes(result.bytes);
+        tarresult.current_volume().bytes += result.bytes;
+        tarresult.current_volume().members += result.members.len();
+    }
+
+    tarresult.publish_volume();
+
+    if let Some(manifest) = tarresult.manifest.clone() {
+        let mut manifest = manifest.lock().unwrap();
+
+        for member in results.iter().flat_map(|result| result.members.iter()) {
+            manifest.write_stream_member(member.stream, member.offset, member.file_size, member.file_type, &member.path)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Write the traversed files onto a tape as an LTFS volume.
+///
+/// LTFS volumes are formatted in one go, so there is no support for spanning
+/// or resuming here: the tape either ends up with a complete volume or an
+/// unmountable one.
+fn ltfs_proc(receiver: &queue::BudgetedReceiver<tar::header::HeaderGenResult>, tarparams: &TarParameter, tarresult: &mut TarResult) -> io::Result<()> {
+    let mut tape = fs::open_tape(&tarparams.outfile, &tape::TapeOptions::default())?;
+    check_overwrite(tape.as_mut(), tarparams)?;
+
+    let mut options = ltfs::LtfsOptions::default();
+
+    if let Some(ref title) = tarparams.label_title {
+        options.volume_name = title.clone();
+    }
+
+    let mut writer = ltfs::LtfsWriter::format(tape.as_mut(), options)?;
+
+    while let Ok(entry) = receiver.recv() {
+        match writer.add_entry(&entry) {
+            Ok(true) => {
+                tarresult.current_volume().members += 1;
+                tarresult.publish_volume();
+            },
+            Ok(false) => warn!(path:? = entry.original_path; "Skipping file which LTFS cannot store"),
+            Err(e) => error!(path:? = entry.original_path; "Error archiving file: {}", e)
+        }
+    }
+
+    let bytes = writer.bytes_written();
+    writer.finish()?;
+
+    tarresult.current_volume().bytes = bytes;
+    tarresult.status.add_bytes(bytes);
+    tarresult.publish_volume();
+
+    Ok(())
+}
+
+/// Our command line, minus the program name and any `--jobs-file` option.
+fn job_base_args() -> Vec<String> {
+    let mut args = env::args().skip(1);
+    let mut base_args = Vec::new();
+
+    while let Some(arg) = args.next() {
+        if arg == "--jobs-file" {
+            args.next();
+        } else if !arg.starts_with("--jobs-file=") {
+            base_args.push(arg);
+        }
+    }
+
+    base_args
+}
+
+/// Run every job in a jobs file, one after the other, then summarize them.
+///
+/// Each job is parsed as if it's settings followed our own command line, so
+/// options given to us apply to every job unless the job overrides them. The
+/// exception is `parallel_io_limit`, as every job shares our I/O threads.
+/// Every job also shares our tape drives: a drive is opened by the first job
+/// writing to it and held open until the batch is done, so each job's archive
+/// follows the last one on tape, even on a rewinding device. Jobs that fail
+/// don't stop the rest of the batch from running, but cancelling a job does.
+fn jobs_proc(jobs_file: &str, parallel_io_pool: &rayon::ThreadPool) -> io::Result<()> {
+    let jobs = jobs::parse_jobs(&std::fs::read_to_string(jobs_file)?)?;
+    let start_dir = env::current_dir()?;
+    let drives = jobs::SharedDrives::new();
+    let mut outcomes = Vec::new();
+
+    for job in jobs.iter() {
+        let mut args = vec![env::args().next().unwrap_or_else(|| "rapidtar".to_string())];
+
+        args.extend(job_base_args());
+        args.extend(job.command_line());
+
+        let tarparams = match TarParameter::from_args(args) {
+            Ok(tarparams) => tarparams,
+            Err(_) => {
+                error!(job = job.name.as_str(); "Job has invalid settings");
+                outcomes.push((&job.name, None));
+                continue;
+            }
+        };
+
+        env::set_current_dir(&start_dir)?;
+        info!(job = job.name.as_str(); "Starting job");
+
+        match run_operation(tarparams, Some(&drives), parallel_io_pool) {
+            Ok(tarresult) => {
+                let cancelled = tarresult.status.is_cancelled();
+
+                outcomes.push((&job.name, Some((tarresult, time::Instant::now()))));
+
+                if cancelled {
+                    warn!(job = job.name.as_str(); "Job was cancelled, skipping the rest of the batch");
+                    break;
+                }
+            },
+            Err(e) => {
+                error!(job = job.name.as_str(); "Job failed: {}", e);
+                outcomes.push((&job.name, None));
+            }
+        }
+    }
+
+    let failures = outcomes.iter().filter(|(_, result)| result.is_none()).count();
+
+    for (name, result) in outcomes.iter() {
+        match result {
+            Some((tarresult, end_instant)) => {
+                let members : usize = tarresult.volumes.iter().map(|v| v.members).sum();
+
+                eprintln!("Job {}: {} members, {} in {}", name, members, tarresult.tarball_size(), units::HRDuration::from(end_instant.duration_since(tarresult.start_instant)));
+            },
+            None => eprintln!("Job {}: failed", name)
+        }
+    }
+
+    eprintln!("{} of {} jobs completed", outcomes.len() - failures, jobs.len());
+
+    match failures {
+        0 => Ok(()),
+        failures => Err(io::Error::new(io::ErrorKind::Other, format!("{} of {} jobs failed", failures, jobs.len())))
+    }
+}
+
+fn main() -> io::Result<()> {
+    //Here's some configuration!
+    let tarparams = TarParameter::from_proc_args();
+
+    logging::init(logging::level_for_verbosity(tarparams.verbosity)).expect("Could not install logger");
+
+    let parallel_io_pool = rayon::ThreadPoolBuilder::new().num_threads(tarparams.perf_tuning.parallel_io_limit).thread_name(|i| {
+        format!("I/O Thread {}", i)
+    }).build().unwrap();
+
+    match tarparams.jobs_file.clone() {
+        Some(jobs_file) => jobs_proc(&jobs_file, &parallel_io_pool),
+        None => run_operation(tarparams, None, &parallel_io_pool).map(|_| ())
+    }
+}
+
+/// Carry out the operation our parameters ask for.
+///
+/// Archives are written through `drives`, when given, rather than opening
+/// (and closing) tape drives ourselves.
+fn run_operation(mut tarparams: TarParameter, drives: Option<&jobs::SharedDrives<tar::recovery::RecoveryEntry>>, parallel_io_pool: &rayon::ThreadPool) -> io::Result<TarResult> {
+    let mut tarresult = TarResult::default();
+
+    tarresult.drives = drives.cloned();
+    
+    env::set_current_dir(tarparams.basepath.clone())?;
+    check_compression(&tarparams)?;
+    
+    let outcome = match tarparams.operation {
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, "You must specify one of the Acdtrux options.")),
+        Some(TarOperation::Create) if !tarparams.streams.is_empty() => {
+            let snapshot = take_snapshot(&tarparams)?;
+            start_analysis(&tarparams, &mut tarresult)?;
+
+            let receiver : queue::BudgetedReceiver<tar::header::HeaderGenResult> = read_traverse(parallel_io_pool, &tarparams, snapshot.as_ref(), &tarresult)?;
+
+            streams_proc(&receiver, &tarparams, &mut tarresult)?;
+            finish_analysis(&mut tarresult)?;
+            release_snapshot(snapshot)?;
+            report_totals(&tarparams, &mut tarresult)
+        },
+        Some(TarOperation::Create) if tarparams.ltfs => {
+            let rotation_use = start_rotation(&tarparams)?;
+            let snapshot = take_snapshot(&tarparams)?;
+            let receiver : queue::BudgetedReceiver<tar::header::HeaderGenResult> = read_traverse(parallel_io_pool, &tarparams, snapshot.as_ref(), &tarresult)?;
+
+            ltfs_proc(&receiver, &tarparams, &mut tarresult)?;
+            finish_rotation(&tarparams, rotation_use)?;
+            release_snapshot(snapshot)?;
+            report_totals(&tarparams, &mut tarresult)
+        },
+        Some(TarOperation::Create) | Some(TarOperation::Append) | Some(TarOperation::Update) => {
+            let atomic_target = start_atomic(&mut tarparams)?;
+            let rotation_use = start_rotation(&tarparams)?;
+            let mut completed = false;
+            let snapshot = take_snapshot(&tarparams)?;
+            start_analysis(&tarparams, &mut tarresult)?;
+            start_update(&tarparams, &mut tarresult)?;
+
+            let mut tarball = open_archive_sink(1, &tarparams, &tarresult)?;
+
+            if let Some(tape) = tarball.downcast_tapedevice() {
+                position_tape(tape, &tarparams)?;
+            }
+
+            let receiver : queue::BudgetedReceiver<tar::header::HeaderGenResult> = read_traverse(parallel_io_pool, &tarparams, snapshot.as_ref(), &tarresult)?;
+
+            start_volume(tarball.deref_mut(), &tarparams, &mut tarresult)?;
+
+            //Labels belong at the start of a volume, not partway through one
+            //we're appending to.
+            if !tarparams.is_appending() {
+                label_proc(tarball.deref_mut(), None, &mut tarparams, &mut tarresult)?;
+            }
+
+            while !tarresult.status.is_cancelled() {
+                let mut last_error_entry = None;
+
+                match serialize_proc(tarball.as_mut(), &receiver, &mut last_error_entry, &mut tarparams, &mut tarresult).err() {
+                    None => {
+                        match terminate_tarball(tarball.deref_mut(), &tarparams, &mut tarresult) {
+                            Ok(()) => {},
+                            Err(ref e) if e.kind() == io::ErrorKind::WriteZero && tarparams.spanning => {
+                                warn!("Ran out of space writing the end-of-archive marker, continuing onto the next volume");
+
+                                tarball = match recover_proc(tarball, false, &mut tarparams, &mut tarresult) {
+                                    Ok(tarball) => tarball,
+                                    Err(e) => {
+                                        error!("Got error when trying to open next volume: {}", e);
+                                        break;
+                                    }
+                                };
+
+                                tarball.flush()?;
+                                tarresult.status.add_bytes(tar::recovery::TERMINATOR_SIZE);
+                            },
+                            Err(ref e) if retry::is_position_lost(e) && tarresult.current_volume().start_block.is_some() => {
+                                warn!("Tape device lost it's position writing the end-of-archive marker, attempting to recover: {}", e);
+
+                                tarball = match recover_proc(tarball, true, &mut tarparams, &mut tarresult) {
+                                    Ok(tarball) => tarball,
+                                    Err(e) => {
+                                        error!("Got error when trying to recover tape position: {}", e);
+                                        break;
+                                    }
+                                };
+
+                                tarball.flush()?;
+                                tarresult.status.add_bytes(tar::recovery::TERMINATOR_SIZE);
+                            },
+                            Err(e) => return Err(e)
+                        }
+
+                        finish_tarball(tarball, &tarparams, &mut tarresult)?;
+                        completed = true;
+                        break;
+                    },
+                    Some(ref e) if e.kind() == io::ErrorKind::WriteZero => {
+                        if tarparams.spanning { 
+                            tarball = match recover_proc(tarball, false, &mut tarparams, &mut tarresult) {
+                                Ok(tarball) => tarball,
+                                Err(e) => {
+                                    error!("Got error when trying to open next volume: {}", e);
+                                    break;
+                                }
+                            }
+                        } else if tarparams.trim_torn {
+                            warn!(path:? = last_error_entry.unwrap().original_path; "Ran out of space archiving file, removing it from the end of the volume");
+
+                            if let Err(e) = trim_torn_proc(tarball, &tarparams, &mut tarresult) {
+                                error!("Could not end volume after removing torn file: {}", e);
+                            }
+
+                            break;
+                        } else {
+                            error!(path:? = last_error_entry.unwrap().original_path; "Ran out of space archiving file");
+                            break;
+                        }
+                    },
+                    Some(ref e) if retry::is_position_lost(e) && tarresult.current_volume().start_block.is_some() => {
+                        warn!("Tape device lost it's position, attempting to recover: {}", e);
+
+                        tarball = match recover_proc(tarball, true, &mut tarparams, &mut tarresult) {
+                            Ok(tarball) => tarball,
+                            Err(e) => {
+                                error!("Got error when trying to recover tape position: {}", e);
+                                break;
+                            }
+                        }
+                    },
+                    Some(ref e) if cancel::is_cancellation(e) => break,
+                    Some(e) => error!(path:? = last_error_entry.unwrap().original_path; "Error archiving file: {}", e)
+                }
+            }
+
+            match atomic_target {
+                Some(target) if completed => commit_atomic(&mut tarparams, target)?,
+                Some(target) => error!("Archive was not completed, leaving it in {} rather than {}", tarparams.outfile, target),
+                None => {}
+            }
+
+            if completed {
+                finish_rotation(&tarparams, rotation_use)?;
+            }
+
+            finish_analysis(&mut tarresult)?;
+            release_snapshot(snapshot)?;
+            report_totals(&tarparams, &mut tarresult)
+        },
+        Some(TarOperation::Join) => {
+            join_proc(&tarparams, &mut tarresult)?;
+            report_totals(&tarparams, &mut tarresult)
+        },
+        Some(TarOperation::Recode) => {
+            recode_proc(&tarparams, &mut tarresult)?;
+            report_totals(&tarparams, &mut tarresult)
+        },
+        Some(TarOperation::Extract) if tarparams.to_command.is_some() => to_command_proc(&tarparams),
+        Some(TarOperation::List) => list_proc(&tarparams),
+        Some(TarOperation::Compare) => compare_proc(&tarparams),
+        Some(TarOperation::DumpHeader) => dump_header_proc(&tarparams),
+        Some(TarOperation::Extract) => extract_proc(&tarparams)
+    };
+
+    outcome.map(|()| tarresult)
 }